@@ -15,7 +15,7 @@ mod node_addr;
 mod relay_url;
 
 #[cfg(feature = "key")]
-pub use self::key::{KeyParsingError, NodeId, PublicKey, SecretKey, Signature};
+pub use self::key::{KeyParsingError, NodeId, Pkcs8Error, PublicKey, SecretKey, Signature};
 #[cfg(feature = "key")]
 pub use self::node_addr::NodeAddr;
 #[cfg(feature = "relay")]
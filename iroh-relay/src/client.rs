@@ -23,6 +23,8 @@ use url::Url;
 
 pub use self::conn::{ConnSendError, ReceivedMessage, SendMessage};
 #[cfg(not(wasm_browser))]
+pub use self::tls::ClientTlsConfig;
+#[cfg(not(wasm_browser))]
 use crate::dns::DnsResolver;
 use crate::{
     http::{Protocol, RELAY_PATH},
@@ -31,6 +33,8 @@ use crate::{
 
 pub(crate) mod conn;
 #[cfg(not(wasm_browser))]
+mod socks;
+#[cfg(not(wasm_browser))]
 pub(crate) mod streams;
 #[cfg(not(wasm_browser))]
 mod tls;
@@ -54,6 +58,9 @@ pub struct ClientBuilder {
     insecure_skip_cert_verify: bool,
     /// HTTP Proxy
     proxy_url: Option<Url>,
+    /// How to verify the TLS certificates of relay servers.
+    #[cfg(not(wasm_browser))]
+    tls_config: ClientTlsConfig,
     /// The secret key of this client.
     secret_key: SecretKey,
     /// The DNS resolver to use.
@@ -82,6 +89,8 @@ impl ClientBuilder {
             insecure_skip_cert_verify: false,
 
             proxy_url: None,
+            #[cfg(not(wasm_browser))]
+            tls_config: Default::default(),
             secret_key,
             #[cfg(not(wasm_browser))]
             dns_resolver,
@@ -125,7 +134,20 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets how to verify the TLS certificates of relay servers.
+    ///
+    /// By default the certificate authorities trusted by Mozilla are trusted.
+    #[cfg(not(wasm_browser))]
+    pub fn tls_config(mut self, config: ClientTlsConfig) -> Self {
+        self.tls_config = config;
+        self
+    }
+
     /// Set an explicit proxy url to proxy all HTTP(S) traffic through.
+    ///
+    /// Supports HTTP CONNECT proxies with the `http` and `https` schemes, and SOCKS5 proxies
+    /// with the `socks5` and `socks5h` schemes.  With `socks5h` the proxy resolves the relay
+    /// server's domain name.  Credentials in the URL are used to authenticate to the proxy.
     pub fn proxy_url(mut self, url: Url) -> Self {
         self.proxy_url.replace(url);
         self
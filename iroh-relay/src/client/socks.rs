@@ -0,0 +1,226 @@
+//! Minimal SOCKS5 client, to reach relay servers through a SOCKS5 proxy.
+//!
+//! Only supports the `CONNECT` command, with either no authentication or username/password
+//! authentication.  See [RFC 1928] and [RFC 1929].
+//!
+//! [RFC 1928]: https://datatracker.ietf.org/doc/html/rfc1928
+//! [RFC 1929]: https://datatracker.ietf.org/doc/html/rfc1929
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// The default port of SOCKS proxies.
+pub(super) const DEFAULT_PORT: u16 = 1080;
+
+/// The address the proxy should connect to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Target<'a> {
+    /// An address resolved by the client.
+    Addr(SocketAddr),
+    /// A domain name, resolved by the proxy.
+    Domain(&'a str, u16),
+}
+
+/// Asks the SOCKS5 proxy at the other end of `io` to connect to `target`.
+///
+/// Authenticates with `auth`, a username and password, if given and required by the proxy.
+/// Once this returns `io` is connected to the target.
+pub(super) async fn connect<S>(
+    io: &mut S,
+    target: Target<'_>,
+    auth: Option<(&str, &str)>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Greeting with the supported authentication methods.
+    let greeting: &[u8] = match auth {
+        Some(_) => &[VERSION, 2, AUTH_NONE, AUTH_PASSWORD],
+        None => &[VERSION, 1, AUTH_NONE],
+    };
+    io.write_all(greeting).await?;
+    let mut reply = [0u8; 2];
+    io.read_exact(&mut reply).await?;
+    ensure!(reply[0] == VERSION, "unexpected SOCKS version {}", reply[0]);
+    match (reply[1], auth) {
+        (AUTH_NONE, _) => {}
+        (AUTH_PASSWORD, Some((username, password))) => {
+            ensure!(
+                username.len() <= 255 && password.len() <= 255,
+                "SOCKS username or password too long"
+            );
+            let mut req = Vec::with_capacity(3 + username.len() + password.len());
+            req.push(PASSWORD_VERSION);
+            req.push(username.len() as u8);
+            req.extend_from_slice(username.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+            io.write_all(&req).await?;
+            let mut reply = [0u8; 2];
+            io.read_exact(&mut reply).await?;
+            ensure!(reply[1] == 0x00, "SOCKS authentication failed");
+        }
+        (AUTH_NO_ACCEPTABLE, _) | (AUTH_PASSWORD, None) => {
+            bail!("SOCKS proxy requires unsupported authentication");
+        }
+        (method, _) => bail!("SOCKS proxy chose unknown authentication method {method}"),
+    }
+
+    // Connect request.
+    let mut req = vec![VERSION, CMD_CONNECT, 0x00];
+    let port = match target {
+        Target::Addr(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    req.push(ATYP_IPV4);
+                    req.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    req.push(ATYP_IPV6);
+                    req.extend_from_slice(&ip.octets());
+                }
+            }
+            addr.port()
+        }
+        Target::Domain(domain, port) => {
+            ensure!(domain.len() <= 255, "domain name too long for SOCKS");
+            req.push(ATYP_DOMAIN);
+            req.push(domain.len() as u8);
+            req.extend_from_slice(domain.as_bytes());
+            port
+        }
+    };
+    req.extend_from_slice(&port.to_be_bytes());
+    io.write_all(&req).await?;
+
+    // The reply contains the address the proxy bound to, which is not needed.
+    let mut reply = [0u8; 4];
+    io.read_exact(&mut reply).await?;
+    ensure!(reply[0] == VERSION, "unexpected SOCKS version {}", reply[0]);
+    if reply[1] != 0x00 {
+        bail!("SOCKS proxy failed to connect: {}", reply_error(reply[1]));
+    }
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => io.read_u8().await? as usize,
+        atyp => bail!("unknown SOCKS address type {atyp}"),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    io.read_exact(&mut bound)
+        .await
+        .context("failed to read SOCKS bound address")?;
+    Ok(())
+}
+
+/// Describes the reply codes of RFC 1928.
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Runs the server side of a SOCKS5 handshake, returning the connect request.
+    async fn serve(
+        io: &mut tokio::io::DuplexStream,
+        auth: Option<(&str, &str)>,
+    ) -> Result<Vec<u8>> {
+        let mut greeting = [0u8; 2];
+        io.read_exact(&mut greeting).await?;
+        let mut methods = vec![0u8; greeting[1] as usize];
+        io.read_exact(&mut methods).await?;
+        match auth {
+            Some((username, password)) => {
+                assert!(methods.contains(&AUTH_PASSWORD));
+                io.write_all(&[VERSION, AUTH_PASSWORD]).await?;
+                let mut req = vec![0u8; 3 + username.len() + password.len()];
+                io.read_exact(&mut req).await?;
+                let mut expected = vec![PASSWORD_VERSION, username.len() as u8];
+                expected.extend_from_slice(username.as_bytes());
+                expected.push(password.len() as u8);
+                expected.extend_from_slice(password.as_bytes());
+                let status = if req == expected { 0x00 } else { 0x01 };
+                io.write_all(&[PASSWORD_VERSION, status]).await?;
+            }
+            None => io.write_all(&[VERSION, AUTH_NONE]).await?,
+        }
+        let mut req = vec![0u8; 64];
+        let n = io.read(&mut req).await?;
+        req.truncate(n);
+        io.write_all(&[VERSION, 0x00, 0x00, ATYP_IPV4, 127, 0, 0, 1, 0x04, 0x38])
+            .await?;
+        Ok(req)
+    }
+
+    #[tokio::test]
+    async fn test_connect_addr() -> Result<()> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move { serve(&mut server, None).await });
+        let target = Target::Addr((Ipv4Addr::new(10, 0, 0, 1), 443).into());
+        connect(&mut client, target, None).await?;
+        let req = server.await??;
+        assert_eq!(
+            req,
+            [
+                VERSION,
+                CMD_CONNECT,
+                0x00,
+                ATYP_IPV4,
+                10,
+                0,
+                0,
+                1,
+                0x01,
+                0xbb
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_domain_with_auth() -> Result<()> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let auth = Some(("user", "secret"));
+        let server = tokio::spawn(async move { serve(&mut server, auth).await });
+        let target = Target::Domain("relay.example", 443);
+        connect(&mut client, target, auth).await?;
+        let req = server.await??;
+        let mut expected = vec![VERSION, CMD_CONNECT, 0x00, ATYP_DOMAIN, 13];
+        expected.extend_from_slice(b"relay.example");
+        expected.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(req, expected);
+
+        // wrong credentials are rejected
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move { serve(&mut server, auth).await });
+        let res = connect(&mut client, target, Some(("user", "Secret"))).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+}
@@ -14,7 +14,7 @@
 
 // Based on tailscale/derp/derphttp/derphttp_client.go
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use bytes::Bytes;
 use data_encoding::BASE64URL;
 use http_body_util::Empty;
@@ -30,10 +30,127 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{error, info_span, Instrument};
 
 use super::{
-    streams::{downcast_upgrade, MaybeTlsStream, ProxyStream},
+    socks,
+    streams::{downcast_upgrade, MaybeTlsStream, MaybeTlsStreamChained, ProxyStream},
     *,
 };
-use crate::defaults::timeouts::*;
+use crate::{defaults::timeouts::*, http::MESH_KEY_HEADER};
+#[cfg(feature = "server")]
+use crate::{protos::relay::RelayCodec, server::MeshKey};
+
+/// How a relay client verifies the TLS certificates of relay servers.
+///
+/// The configuration only applies to relay servers.  The certificates of HTTPS proxies are
+/// always verified against the certificate authorities from [`webpki_roots`].
+#[derive(Debug, Clone, Default)]
+pub enum ClientTlsConfig {
+    /// Trusts the certificate authorities trusted by Mozilla, from [`webpki_roots`].
+    #[default]
+    WebPki,
+    /// Trusts only these certificate authorities.
+    ///
+    /// Useful for self-hosted relay servers with certificates issued by a private CA.
+    Roots(Arc<rustls::RootCertStore>),
+    /// Trusts only these server certificates.
+    ///
+    /// The certificate presented by the server must be exactly one of these, it is not
+    /// verified against any certificate authority.
+    Pinned(Arc<Vec<rustls::pki_types::CertificateDer<'static>>>),
+    /// Uses this TLS client configuration as is.
+    Custom(Arc<rustls::ClientConfig>),
+}
+
+impl ClientTlsConfig {
+    /// Builds the [`rustls::ClientConfig`] for this configuration.
+    ///
+    /// The returned configuration has no ALPN protocols set.
+    pub fn client_config(&self) -> Arc<rustls::ClientConfig> {
+        let builder = rustls::client::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .expect("protocols supported by ring");
+        let mut config = match self {
+            Self::WebPki => {
+                let roots = rustls::RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+                builder.with_root_certificates(roots).with_no_client_auth()
+            }
+            Self::Roots(roots) => builder
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth(),
+            Self::Pinned(certs) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                    certs: certs.clone(),
+                }))
+                .with_no_client_auth(),
+            Self::Custom(config) => return config.clone(),
+        };
+        config.resumption = Resumption::default();
+        Arc::new(config)
+    }
+}
+
+/// Accepts only the pinned server certificates.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    certs: Arc<Vec<rustls::pki_types::CertificateDer<'static>>>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer,
+        _intermediates: &[rustls::pki_types::CertificateDer],
+        _server_name: &rustls::pki_types::ServerName,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if self.certs.iter().any(|cert| cert == end_entity) {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MaybeTlsStreamBuilder {
@@ -41,6 +158,7 @@ pub struct MaybeTlsStreamBuilder {
     dns_resolver: DnsResolver,
     proxy_url: Option<Url>,
     prefer_ipv6: bool,
+    tls_config: ClientTlsConfig,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_cert_verify: bool,
 }
@@ -52,11 +170,17 @@ impl MaybeTlsStreamBuilder {
             dns_resolver,
             proxy_url: None,
             prefer_ipv6: false,
+            tls_config: Default::default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: false,
         }
     }
 
+    pub fn tls_config(mut self, config: ClientTlsConfig) -> Self {
+        self.tls_config = config;
+        self
+    }
+
     pub fn proxy_url(mut self, proxy_url: Option<Url>) -> Self {
         self.proxy_url = proxy_url;
         self
@@ -73,28 +197,24 @@ impl MaybeTlsStreamBuilder {
         self
     }
 
-    pub async fn connect(self) -> Result<MaybeTlsStream<ProxyStream>> {
-        let roots = rustls::RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-        };
-        let mut config = rustls::client::ClientConfig::builder_with_provider(Arc::new(
-            rustls::crypto::ring::default_provider(),
-        ))
-        .with_safe_default_protocol_versions()
-        .expect("protocols supported by ring")
-        .with_root_certificates(roots)
-        .with_no_client_auth();
+    /// Returns the TLS connector verifying certificates as configured by `config`.
+    fn tls_connector(&self, config: &ClientTlsConfig) -> tokio_rustls::TlsConnector {
+        #[allow(unused_mut)]
+        let mut config = config.client_config();
         #[cfg(any(test, feature = "test-utils"))]
         if self.insecure_skip_cert_verify {
             warn!("Insecure config: SSL certificates from relay servers not verified");
-            config
+            Arc::make_mut(&mut config)
                 .dangerous()
                 .set_certificate_verifier(Arc::new(NoCertVerifier));
         }
-        config.resumption = Resumption::default();
-        let tls_connector: tokio_rustls::TlsConnector = Arc::new(config).into();
+        config.into()
+    }
+
+    pub async fn connect(self) -> Result<MaybeTlsStream<ProxyStream>> {
+        let tls_connector = self.tls_connector(&self.tls_config);
 
-        let tcp_stream = self.dial_url(&tls_connector).await?;
+        let tcp_stream = self.dial_url().await?;
 
         let local_addr = tcp_stream
             .local_addr()
@@ -133,9 +253,9 @@ impl MaybeTlsStreamBuilder {
             .and_then(|s| rustls::pki_types::ServerName::try_from(s).ok())
     }
 
-    async fn dial_url(&self, tls_connector: &tokio_rustls::TlsConnector) -> Result<ProxyStream> {
+    async fn dial_url(&self) -> Result<ProxyStream> {
         if let Some(ref proxy) = self.proxy_url {
-            let stream = self.dial_url_proxy(proxy.clone(), tls_connector).await?;
+            let stream = self.dial_url_proxy(proxy.clone()).await?;
             Ok(ProxyStream::Proxied(stream))
         } else {
             let stream = self.dial_url_direct().await?;
@@ -170,19 +290,33 @@ impl MaybeTlsStreamBuilder {
     async fn dial_url_proxy(
         &self,
         proxy_url: Url,
-        tls_connector: &tokio_rustls::TlsConnector,
     ) -> Result<util::Chain<std::io::Cursor<Bytes>, MaybeTlsStream<tokio::net::TcpStream>>> {
         use hyper_util::rt::TokioIo;
         use tokio::net::TcpStream;
         debug!(%self.url, %proxy_url, "dial url via proxy");
 
-        // Resolve proxy DNS
-        let proxy_ip = self
-            .dns_resolver
-            .resolve_host(&proxy_url, self.prefer_ipv6, DNS_TIMEOUT)
-            .await?;
+        // Resolve proxy DNS.  URLs with a non-special scheme, like `socks5`, keep IP
+        // addresses as an opaque host, so these need to be parsed here.
+        let proxy_ip = match proxy_url.host_str().and_then(|host| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .ok()
+        }) {
+            Some(ip) => ip,
+            None => {
+                self.dns_resolver
+                    .resolve_host(&proxy_url, self.prefer_ipv6, DNS_TIMEOUT)
+                    .await?
+            }
+        };
 
         let proxy_port = url_port(&proxy_url).ok_or_else(|| anyhow!("Missing proxy url port"))?;
+        let proxy_scheme = proxy_url.scheme();
+        ensure!(
+            matches!(proxy_scheme, "http" | "https" | "socks5" | "socks5h"),
+            "unsupported proxy scheme: {proxy_scheme}"
+        );
         let proxy_addr = SocketAddr::new(proxy_ip, proxy_port);
 
         debug!(%proxy_addr, "connecting to proxy");
@@ -196,12 +330,19 @@ impl MaybeTlsStreamBuilder {
 
         tcp_stream.set_nodelay(true)?;
 
+        if matches!(proxy_scheme, "socks5" | "socks5h") {
+            return self.dial_url_socks(&proxy_url, tcp_stream).await;
+        }
+
         // Setup TLS if necessary
-        let io = if proxy_url.scheme() == "http" {
+        let io = if proxy_scheme == "http" {
             MaybeTlsStream::Raw(tcp_stream)
         } else {
             let hostname = proxy_url.host_str().context("No hostname in proxy URL")?;
             let hostname = rustls::pki_types::ServerName::try_from(hostname.to_string())?;
+            // The relay's TLS configuration is not meant for the proxy, e.g. a pinned relay
+            // certificate would never match the proxy's.
+            let tls_connector = self.tls_connector(&ClientTlsConfig::WebPki);
             let tls_stream = tls_connector.connect(hostname, tcp_stream).await?;
             MaybeTlsStream::Tls(tls_stream)
         };
@@ -262,6 +403,45 @@ impl MaybeTlsStreamBuilder {
 
         Ok(res)
     }
+
+    /// Connects to the url through the SOCKS5 proxy connected to by `tcp_stream`.
+    async fn dial_url_socks(
+        &self,
+        proxy_url: &Url,
+        mut tcp_stream: tokio::net::TcpStream,
+    ) -> Result<util::Chain<std::io::Cursor<Bytes>, MaybeTlsStream<tokio::net::TcpStream>>> {
+        let port = url_port(&self.url).ok_or_else(|| anyhow!("invalid target port"))?;
+        let dst_ip;
+        let target = match self.url.host() {
+            Some(url::Host::Domain(domain)) if proxy_url.scheme() == "socks5h" => {
+                socks::Target::Domain(domain, port)
+            }
+            Some(_) => {
+                dst_ip = self
+                    .dns_resolver
+                    .resolve_host(&self.url, self.prefer_ipv6, DNS_TIMEOUT)
+                    .await?;
+                socks::Target::Addr(SocketAddr::new(dst_ip, port))
+            }
+            None => bail!("Missing proxy host"),
+        };
+        let auth = (!proxy_url.username().is_empty()).then(|| {
+            (
+                proxy_url.username(),
+                proxy_url.password().unwrap_or_default(),
+            )
+        });
+        debug!(?target, "Sending SOCKS connect request");
+        time::timeout(
+            DIAL_NODE_TIMEOUT,
+            socks::connect(&mut tcp_stream, target, auth),
+        )
+        .await
+        .context("Timeout connecting through SOCKS proxy")??;
+
+        let io = MaybeTlsStream::Raw(tcp_stream);
+        Ok(util::chain(std::io::Cursor::new(Bytes::new()), io))
+    }
 }
 
 impl ClientBuilder {
@@ -270,11 +450,41 @@ impl ClientBuilder {
     ///
     /// [`HTTP_UPGRADE_PROTOCOL`]: crate::http::HTTP_UPGRADE_PROTOCOL
     pub(super) async fn connect_relay(&self) -> Result<(Conn, SocketAddr)> {
+        let (conn, local_addr) = self.upgrade_relay(None).await?;
+        let conn = Conn::new_relay(conn, self.key_cache.clone(), &self.secret_key).await?;
+
+        Ok((conn, local_addr))
+    }
+
+    /// Connects to a relay server as a peer of its mesh.
+    ///
+    /// Like [`ClientBuilder::connect_relay`], but authenticates the connection with the
+    /// [`MeshKey`] and returns the framed connection to exchange mesh frames on.
+    #[cfg(feature = "server")]
+    pub(crate) async fn connect_mesh(
+        &self,
+        key: &MeshKey,
+    ) -> Result<tokio_util::codec::Framed<MaybeTlsStreamChained, RelayCodec>> {
+        let (conn, _) = self.upgrade_relay(Some(&key.to_string())).await?;
+        match Conn::new_relay(conn, self.key_cache.clone(), &self.secret_key).await? {
+            Conn::Relay { conn } => Ok(conn),
+            _ => unreachable!("created a relay connection"),
+        }
+    }
+
+    /// Connects to the relay server and upgrades the connection to the relay protocol.
+    ///
+    /// The hex encoded `mesh_key` is sent to connect as a peer of the server's mesh.
+    async fn upgrade_relay(
+        &self,
+        mesh_key: Option<&str>,
+    ) -> Result<(MaybeTlsStreamChained, SocketAddr)> {
         #[allow(unused_mut)]
         let mut builder =
             MaybeTlsStreamBuilder::new(self.url.clone().into(), self.dns_resolver.clone())
                 .prefer_ipv6(self.prefer_ipv6())
-                .proxy_url(self.proxy_url.clone());
+                .proxy_url(self.proxy_url.clone())
+                .tls_config(self.tls_config.clone());
 
         #[cfg(any(test, feature = "test-utils"))]
         if self.insecure_skip_cert_verify {
@@ -283,7 +493,7 @@ impl ClientBuilder {
 
         let stream = builder.connect().await?;
         let local_addr = stream.as_ref().local_addr()?;
-        let response = self.http_upgrade_relay(stream).await?;
+        let response = self.http_upgrade_relay(stream, mesh_key).await?;
 
         if response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
             bail!(
@@ -301,8 +511,6 @@ impl ClientBuilder {
         debug!("connection upgraded");
         let conn = downcast_upgrade(upgraded)?;
 
-        let conn = Conn::new_relay(conn, self.key_cache.clone(), &self.secret_key).await?;
-
         Ok((conn, local_addr))
     }
 
@@ -324,7 +532,8 @@ impl ClientBuilder {
         #[allow(unused_mut)]
         let mut builder = MaybeTlsStreamBuilder::new(dial_url.clone(), self.dns_resolver.clone())
             .prefer_ipv6(self.prefer_ipv6())
-            .proxy_url(self.proxy_url.clone());
+            .proxy_url(self.proxy_url.clone())
+            .tls_config(self.tls_config.clone());
 
         #[cfg(any(test, feature = "test-utils"))]
         if self.insecure_skip_cert_verify {
@@ -352,7 +561,13 @@ impl ClientBuilder {
     }
 
     /// Sends the HTTP upgrade request to the relay server.
-    async fn http_upgrade_relay<T>(&self, io: T) -> Result<hyper::Response<Incoming>>
+    ///
+    /// Includes the mesh key, if any, to connect as a peer of the server's mesh.
+    async fn http_upgrade_relay<T>(
+        &self,
+        io: T,
+        mesh_key: Option<&str>,
+    ) -> Result<hyper::Response<Incoming>>
     where
         T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     {
@@ -375,14 +590,17 @@ impl ClientBuilder {
             .instrument(info_span!("http-driver")),
         );
         debug!("Sending upgrade request");
-        let req = Request::builder()
+        let mut req = Request::builder()
             .uri(RELAY_PATH)
             .header(UPGRADE, Protocol::Relay.upgrade_header())
             // https://datatracker.ietf.org/doc/html/rfc2616#section-14.23
             // > A client MUST include a Host header field in all HTTP/1.1 request messages.
             // This header value helps reverse proxies identify how to forward requests.
-            .header(HOST, host_header_value)
-            .body(http_body_util::Empty::<hyper::body::Bytes>::new())?;
+            .header(HOST, host_header_value);
+        if let Some(key) = mesh_key {
+            req = req.header(MESH_KEY_HEADER, key);
+        }
+        let req = req.body(http_body_util::Empty::<hyper::body::Bytes>::new())?;
         request_sender.send_request(req).await.map_err(From::from)
     }
 
@@ -422,6 +640,7 @@ fn url_port(url: &Url) -> Option<u16> {
     match url.scheme() {
         "http" | "ws" => Some(80),
         "https" | "wss" => Some(443),
+        "socks5" | "socks5h" => Some(socks::DEFAULT_PORT),
         _ => None,
     }
 }
@@ -4,6 +4,8 @@ pub(crate) const HTTP_UPGRADE_PROTOCOL: &str = "iroh derp http";
 pub(crate) const WEBSOCKET_UPGRADE_PROTOCOL: &str = "websocket";
 #[cfg(feature = "server")] // only used in the server for now
 pub(crate) const SUPPORTED_WEBSOCKET_VERSION: &str = "13";
+/// The HTTP header carrying the mesh key, connecting to a relay server as a peer of its mesh.
+pub(crate) const MESH_KEY_HEADER: &str = "X-Iroh-Relay-Mesh-Key";
 
 /// The HTTP path under which the relay accepts relaying connections
 /// (over websockets and a custom upgrade protocol).
@@ -9,10 +9,10 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::{anyhow, bail, Context as _, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
 use clap::Parser;
 use http::StatusCode;
-use iroh_base::NodeId;
+use iroh_base::{NodeId, RelayUrl};
 use iroh_relay::{
     defaults::{
         DEFAULT_HTTPS_PORT, DEFAULT_HTTP_PORT, DEFAULT_METRICS_PORT, DEFAULT_RELAY_QUIC_PORT,
@@ -33,6 +33,10 @@ const DEV_MODE_HTTP_PORT: u16 = 3340;
 const X_IROH_NODE_ID: &str = "X-Iroh-NodeId";
 /// Environment variable to read a bearer token for HTTP auth requests from.
 const ENV_HTTP_BEARER_TOKEN: &str = "IROH_RELAY_HTTP_BEARER_TOKEN";
+/// Environment variable to read the mesh key from.
+const ENV_MESH_KEY: &str = "IROH_RELAY_MESH_KEY";
+/// Environment variable to read the diagnostics endpoint token from.
+const ENV_DIAGNOSTICS_TOKEN: &str = "IROH_RELAY_DIAGNOSTICS_TOKEN";
 
 /// A relay server for iroh.
 #[derive(Parser, Debug, Clone)]
@@ -147,6 +151,14 @@ struct Config {
     ///
     /// Defaults to using the `http_bind_addr` with the port set to [`DEFAULT_STUN_PORT`].
     stun_bind_addr: Option<SocketAddr>,
+    /// Whether to serve STUN on the UDP port of the address the relay is served on.
+    ///
+    /// This is the HTTPS bind address if TLS is enabled, otherwise the `http_bind_addr`.
+    /// When set, `stun_bind_addr` is ignored.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    stun_share_relay_addr: bool,
     /// Whether to allow QUIC connections for QUIC address discovery
     ///
     /// If no `tls` is set, this will error.
@@ -175,6 +187,39 @@ struct Config {
     /// This controls which nodes are allowed to relay connections, other endpoints, like STUN are not controlled by this.
     #[serde(default)]
     access: AccessConfig,
+    /// Meshing with other relay servers.
+    ///
+    /// Disabled if not present.
+    mesh: Option<MeshConfig>,
+    /// The authenticated diagnostics endpoint of the relay server.
+    ///
+    /// Disabled if not present.
+    diagnostics: Option<DiagnosticsConfig>,
+}
+
+/// Configuration for the diagnostics endpoint, served at `/diagnostics`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct DiagnosticsConfig {
+    /// The bearer token requests must present in their `Authorization` header.
+    ///
+    /// The token can also be set via the `IROH_RELAY_DIAGNOSTICS_TOKEN` environment
+    /// variable, which takes precedence over the config.
+    token: Option<String>,
+}
+
+/// Configuration for meshing with other relay servers.
+///
+/// Every relay server of the mesh must list all the other servers as its peers and use the
+/// same key.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct MeshConfig {
+    /// The hex-encoded 32 byte key shared by all servers of the mesh.
+    ///
+    /// The key can also be set via the `IROH_RELAY_MESH_KEY` environment variable, which
+    /// takes precedence over the config.
+    key: Option<String>,
+    /// The URLs of the other relay servers of the mesh.
+    peers: Vec<RelayUrl>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
@@ -331,12 +376,15 @@ impl Default for Config {
             tls: None,
             enable_stun: cfg_defaults::enable_stun(),
             stun_bind_addr: None,
+            stun_share_relay_addr: false,
             enable_quic_addr_discovery: cfg_defaults::enable_quic_addr_discovery(),
             limits: None,
             enable_metrics: cfg_defaults::enable_metrics(),
             metrics_bind_addr: None,
             key_cache_capacity: Default::default(),
             access: AccessConfig::Everyone,
+            mesh: None,
+            diagnostics: None,
         }
     }
 }
@@ -651,6 +699,30 @@ async fn maybe_load_tls(
     }))
 }
 
+/// Convert the TOML-loaded mesh config to the [`relay::MeshConfig`] format.
+fn build_mesh_config(cfg: MeshConfig) -> Result<relay::MeshConfig> {
+    let key = std::env::var(ENV_MESH_KEY)
+        .ok()
+        .or(cfg.key)
+        .with_context(|| format!("mesh key missing, set it in the config or {ENV_MESH_KEY}"))?;
+    let key = key.parse().context("invalid mesh key")?;
+    Ok(relay::MeshConfig {
+        key,
+        peers: cfg.peers,
+    })
+}
+
+fn build_diagnostics_config(cfg: DiagnosticsConfig) -> Result<relay::DiagnosticsConfig> {
+    let token = std::env::var(ENV_DIAGNOSTICS_TOKEN)
+        .ok()
+        .or(cfg.token)
+        .with_context(|| {
+            format!("diagnostics token missing, set it in the config or {ENV_DIAGNOSTICS_TOKEN}")
+        })?;
+    ensure!(!token.is_empty(), "diagnostics token must not be empty");
+    Ok(relay::DiagnosticsConfig { token })
+}
+
 /// Convert the TOML-loaded config to the [`relay::RelayConfig`] format.
 async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::Error>> {
     // Don't bind to https, even if tls configuration is available.
@@ -710,10 +782,17 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
         limits,
         key_cache_capacity: cfg.key_cache_capacity,
         access: cfg.access.clone().into(),
+        mesh: cfg.mesh.clone().map(build_mesh_config).transpose()?,
+        diagnostics: cfg
+            .diagnostics
+            .clone()
+            .map(build_diagnostics_config)
+            .transpose()?,
     };
 
     let stun_config = relay::StunConfig {
         bind_addr: cfg.stun_bind_addr(),
+        share_relay_addr: cfg.stun_share_relay_addr,
     };
     Ok(relay::ServerConfig {
         relay: Some(relay_config),
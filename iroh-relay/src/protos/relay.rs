@@ -55,10 +55,12 @@ pub(crate) const PER_CLIENT_SEND_QUEUE_DEPTH: usize = 512; //32;
 ///  - version 2: received packets have src addrs in FrameType::RecvPacket at beginning.
 ///
 /// NOTE: we are technically running a modified version of the protocol.
-/// `FrameType::PeerPresent`, `FrameType::WatchConn`, `FrameType::ClosePeer`, have been removed.
+/// `FrameType::WatchConn`, `FrameType::ClosePeer`, have been removed.
 /// The server will error on that connection if a client sends one of these frames.
+/// `FrameType::PeerPresent` and `FrameType::ForwardPacket` are only used between meshed
+/// relay servers.
 /// We have split with the DERP protocol significantly starting with our relay protocol 3
-/// `FrameType::PeerPresent`, `FrameType::WatchConn`, `FrameType::ClosePeer`, `FrameType::ServerKey`, and `FrameType::ServerInfo` have been removed.
+/// `FrameType::WatchConn`, `FrameType::ClosePeer`, `FrameType::ServerKey`, and `FrameType::ServerInfo` have been removed.
 /// The server will error on that connection if a client sends one of these frames.
 /// This materially affects the handshake protocol, and so relay nodes on version 3 will be unable to communicate
 /// with nodes running earlier protocol versions.
@@ -92,8 +94,18 @@ pub(crate) enum FrameType {
     ///
     /// 32B pub key of peer that's gone
     PeerGone = 8,
-    /// Frames 9-11 concern meshing, which we have eliminated from our version of the protocol.
-    /// Messages with these frames will be ignored.
+    /// Sent from a server to a meshed relay server to signal that a client is connected.
+    ///
+    /// Only sent on mesh connections, where `FrameType::PeerGone` signals that the client
+    /// disconnected again.
+    ///
+    /// 32B pub key of the connected client
+    PeerPresent = 9,
+    /// Sent between meshed relay servers to forward a packet to a client connected to the
+    /// other server.
+    ///
+    /// 32B src pub key + 32B dst pub key + packet bytes
+    ForwardPacket = 10,
     /// 8 byte ping payload, to be echoed back in FrameType::Pong
     Ping = 12,
     /// 8 byte payload, the contents of ping being replied to
@@ -250,6 +262,14 @@ pub(crate) enum Frame {
     NodeGone {
         node_id: PublicKey,
     },
+    NodePresent {
+        node_id: PublicKey,
+    },
+    ForwardPacket {
+        src_key: PublicKey,
+        dst_key: PublicKey,
+        packet: Bytes,
+    },
     Ping {
         data: [u8; 8],
     },
@@ -274,6 +294,8 @@ impl Frame {
             Frame::KeepAlive => FrameType::KeepAlive,
             Frame::NotePreferred { .. } => FrameType::NotePreferred,
             Frame::NodeGone { .. } => FrameType::PeerGone,
+            Frame::NodePresent { .. } => FrameType::PeerPresent,
+            Frame::ForwardPacket { .. } => FrameType::ForwardPacket,
             Frame::Ping { .. } => FrameType::Ping,
             Frame::Pong { .. } => FrameType::Pong,
             Frame::Health { .. } => FrameType::Health,
@@ -298,6 +320,8 @@ impl Frame {
             Frame::KeepAlive => 0,
             Frame::NotePreferred { .. } => 1,
             Frame::NodeGone { .. } => PublicKey::LENGTH,
+            Frame::NodePresent { .. } => PublicKey::LENGTH,
+            Frame::ForwardPacket { packet, .. } => PublicKey::LENGTH * 2 + packet.len(),
             Frame::Ping { .. } => 8,
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
@@ -365,6 +389,18 @@ impl Frame {
             Frame::NodeGone { node_id: peer } => {
                 dst.put(peer.as_ref());
             }
+            Frame::NodePresent { node_id } => {
+                dst.put(node_id.as_ref());
+            }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            } => {
+                dst.put(src_key.as_ref());
+                dst.put(dst_key.as_ref());
+                dst.put(packet.as_ref());
+            }
             Frame::Ping { data } => {
                 dst.put(&data[..]);
             }
@@ -462,6 +498,35 @@ impl Frame {
                 let peer = cache.key_from_slice(&content[..32])?;
                 Self::NodeGone { node_id: peer }
             }
+            FrameType::PeerPresent => {
+                anyhow::ensure!(
+                    content.len() == PublicKey::LENGTH,
+                    "invalid peer present frame length"
+                );
+                let node_id = cache.key_from_slice(&content[..32])?;
+                Self::NodePresent { node_id }
+            }
+            FrameType::ForwardPacket => {
+                ensure!(
+                    content.len() >= PublicKey::LENGTH * 2,
+                    "invalid forward packet frame length: {}",
+                    content.len()
+                );
+                let packet_len = content.len() - PublicKey::LENGTH * 2;
+                ensure!(
+                    packet_len <= MAX_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                );
+                let src_key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                let dst_key =
+                    cache.key_from_slice(&content[PublicKey::LENGTH..PublicKey::LENGTH * 2])?;
+                let packet = content.slice(PublicKey::LENGTH * 2..);
+                Self::ForwardPacket {
+                    src_key,
+                    dst_key,
+                    packet,
+                }
+            }
             FrameType::Ping => {
                 anyhow::ensure!(content.len() == 8, "invalid ping frame length");
                 let mut data = [0u8; 8];
@@ -689,6 +754,26 @@ mod tests {
                 a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
                 61",
             ),
+            (
+                Frame::NodePresent {
+                    node_id: client_key.public(),
+                },
+                "09 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61",
+            ),
+            (
+                Frame::ForwardPacket {
+                    src_key: client_key.public(),
+                    dst_key: client_key.public(),
+                    packet: "Hi!".into(),
+                },
+                "0a 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 48 69 21",
+            ),
             (
                 Frame::Ping { data: [42u8; 8] },
                 "0c 2a 2a 2a 2a 2a 2a 2a 2a",
@@ -787,6 +872,13 @@ mod proptests {
         let keep_alive = Just(Frame::KeepAlive);
         let note_preferred = any::<bool>().prop_map(|preferred| Frame::NotePreferred { preferred });
         let peer_gone = key().prop_map(|peer| Frame::NodeGone { node_id: peer });
+        let peer_present = key().prop_map(|node_id| Frame::NodePresent { node_id });
+        let forward_packet =
+            (key(), key(), data(64)).prop_map(|(src_key, dst_key, packet)| Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            });
         let ping = prop::array::uniform8(any::<u8>()).prop_map(|data| Frame::Ping { data });
         let pong = prop::array::uniform8(any::<u8>()).prop_map(|data| Frame::Pong { data });
         let health = data(0).prop_map(|problem| Frame::Health { problem });
@@ -802,6 +894,8 @@ mod proptests {
             keep_alive,
             note_preferred,
             peer_gone,
+            peer_present,
+            forward_packet,
             ping,
             pong,
             health,
@@ -817,8 +911,10 @@ mod proptests {
                 | FrameType::Ping
                 | FrameType::Pong
                 | FrameType::Restarting
-                | FrameType::PeerGone => true,
+                | FrameType::PeerGone
+                | FrameType::PeerPresent => true,
                 FrameType::ClientInfo
+                | FrameType::ForwardPacket
                 | FrameType::Health
                 | FrameType::SendPacket
                 | FrameType::RecvPacket
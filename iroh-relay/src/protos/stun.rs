@@ -2,14 +2,21 @@
 
 use std::net::SocketAddr;
 
-use stun_rs::{
-    attributes::stun::{Fingerprint, XorMappedAddress},
-    DecoderContextBuilder, MessageDecoderBuilder, MessageEncoderBuilder, StunMessageBuilder,
-};
 pub use stun_rs::{
     attributes::StunAttribute, error::StunDecodeError, methods, MessageClass, MessageDecoder,
-    TransactionId,
+    MessageMethod, TransactionId,
+};
+
+pub use self::message::{
+    attr_type, Attribute, ChangeRequest, ErrorCode, Message, MessageBuilder, RawAttribute,
+    Software, UnknownAttributes,
 };
+#[cfg(not(wasm_browser))]
+pub use self::server::{Server, ServerConfig};
+
+mod message;
+#[cfg(not(wasm_browser))]
+mod server;
 
 /// Errors that can occur when handling a STUN packet.
 #[derive(Debug, thiserror::Error)]
@@ -32,35 +39,25 @@ pub enum Error {
     /// STUN request had bogus fingerprint.
     #[error("invalid fingerprint")]
     InvalidFingerprint,
+    /// STUN message is too large to be encoded.
+    #[error("message too large")]
+    MessageTooLarge,
 }
 
 /// Generates a binding request STUN packet.
 pub fn request(tx: TransactionId) -> Vec<u8> {
-    let fp = Fingerprint::default();
-    let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::Request)
-        .with_transaction_id(tx)
-        .with_attribute(fp)
-        .build();
-
-    let encoder = MessageEncoderBuilder::default().build();
-    let mut buffer = vec![0u8; 150];
-    let size = encoder.encode(&mut buffer, &msg).expect("invalid encoding");
-    buffer.truncate(size);
-    buffer
+    MessageBuilder::new(methods::BINDING, MessageClass::Request, tx)
+        .fingerprint()
+        .build()
+        .expect("small message")
 }
 
 /// Generates a binding response.
 pub fn response(tx: TransactionId, addr: SocketAddr) -> Vec<u8> {
-    let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse)
-        .with_transaction_id(tx)
-        .with_attribute(XorMappedAddress::from(addr))
-        .build();
-
-    let encoder = MessageEncoderBuilder::default().build();
-    let mut buffer = vec![0u8; 150];
-    let size = encoder.encode(&mut buffer, &msg).expect("invalid encoding");
-    buffer.truncate(size);
-    buffer
+    MessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse, tx)
+        .xor_mapped_address(addr)
+        .build()
+        .expect("small message")
 }
 
 // Copied from stun_rs
@@ -76,75 +73,52 @@ pub fn is(b: &[u8]) -> bool {
 
 /// Parses a STUN binding request.
 pub fn parse_binding_request(b: &[u8]) -> Result<TransactionId, Error> {
-    let ctx = DecoderContextBuilder::default()
-        .with_validation() // ensure fingerprint is validated
-        .build();
-    let decoder = MessageDecoderBuilder::default().with_context(ctx).build();
-    let (msg, _) = decoder.decode(b).map_err(|_| Error::InvalidMessage)?;
+    decode_binding_request(b, true).map(|msg| msg.transaction_id())
+}
+
+/// Parses a STUN binding request without requiring a valid fingerprint.
+///
+/// Some STUN clients do not add the FINGERPRINT attribute to their requests, this accepts
+/// them as well.  A fingerprint which is present is not validated either.
+pub fn parse_binding_request_unverified(b: &[u8]) -> Result<TransactionId, Error> {
+    decode_binding_request(b, false).map(|msg| msg.transaction_id())
+}
 
-    let tx = *msg.transaction_id();
+/// Parses a STUN binding request, keeping all its attributes.
+fn decode_binding_request(b: &[u8], verify_fingerprint: bool) -> Result<Message, Error> {
+    let msg = if verify_fingerprint {
+        Message::parse(b)?
+    } else {
+        Message::parse_unverified(b)?
+    };
     if msg.method() != methods::BINDING {
         return Err(Error::NotBinding);
     }
 
     // TODO: Tailscale sets the software to tailscale, we should check if we want to do this too.
 
-    if msg
-        .attributes()
-        .last()
-        .map(|attr| !attr.is_fingerprint())
-        .unwrap_or_default()
-    {
+    if verify_fingerprint && !msg.has_fingerprint() {
         return Err(Error::NoFingerprint);
     }
 
-    Ok(tx)
+    Ok(msg)
 }
 
 /// Parses a successful binding response STUN packet.
 /// The IP address is extracted from the XOR-MAPPED-ADDRESS attribute.
 pub fn parse_response(b: &[u8]) -> Result<(TransactionId, SocketAddr), Error> {
-    let decoder = MessageDecoder::default();
-    let (msg, _) = decoder.decode(b).map_err(|_| Error::InvalidMessage)?;
-
-    let tx = *msg.transaction_id();
+    let msg = Message::parse_unverified(b)?;
     if msg.class() != MessageClass::SuccessResponse {
         return Err(Error::NotSuccessResponse);
     }
 
-    // Read through the attributes.
-    // The the addr+port reported by XOR-MAPPED-ADDRESS
-    // as the canonical value. If the attribute is not
-    // present but the STUN server responds with
-    // MAPPED-ADDRESS we fall back to it.
-
-    let mut addr = None;
-    let mut fallback_addr = None;
-    for attr in msg.attributes() {
-        match attr {
-            StunAttribute::XorMappedAddress(a) => {
-                let mut a = *a.socket_address();
-                a.set_ip(a.ip().to_canonical());
-                addr = Some(a);
-            }
-            StunAttribute::MappedAddress(a) => {
-                let mut a = *a.socket_address();
-                a.set_ip(a.ip().to_canonical());
-                fallback_addr = Some(a);
-            }
-            _ => {}
-        }
-    }
-
-    if let Some(addr) = addr {
-        return Ok((tx, addr));
-    }
-
-    if let Some(addr) = fallback_addr {
-        return Ok((tx, addr));
+    // The addr+port reported by XOR-MAPPED-ADDRESS is the canonical value.  If the
+    // attribute is not present but the STUN server responds with MAPPED-ADDRESS we fall
+    // back to it.
+    match msg.mapped_address()? {
+        Some(addr) => Ok((msg.transaction_id(), addr)),
+        None => Err(Error::MalformedAttrs),
     }
-
-    Err(Error::MalformedAttrs)
 }
 
 #[cfg(test)]
@@ -0,0 +1,700 @@
+//! STUN messages with arbitrary attributes.
+//!
+//! This is the STUN codec of the [`stun`](super) module, the binding request and response
+//! helpers there are built on it.  [`Message`] and [`MessageBuilder`] give access to all
+//! attributes of a message, including attributes defined outside of this crate via the
+//! [`Attribute`] trait.  Only the message types are shared with `stun_rs`, which does not
+//! allow decoding attributes it does not know about.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use stun_rs::{MessageClass, MessageMethod, MessageType, TransactionId};
+
+use super::{Error, COOKIE};
+
+/// Well-known STUN attribute types, see [RFC 8489 section 18.3].
+///
+/// [RFC 8489 section 18.3]: https://www.rfc-editor.org/rfc/rfc8489#section-18.3
+pub mod attr_type {
+    /// MAPPED-ADDRESS
+    pub const MAPPED_ADDRESS: u16 = 0x0001;
+    /// USERNAME
+    pub const USERNAME: u16 = 0x0006;
+    /// MESSAGE-INTEGRITY
+    pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+    /// ERROR-CODE
+    pub const ERROR_CODE: u16 = 0x0009;
+    /// UNKNOWN-ATTRIBUTES
+    pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+    /// REALM
+    pub const REALM: u16 = 0x0014;
+    /// NONCE
+    pub const NONCE: u16 = 0x0015;
+    /// XOR-MAPPED-ADDRESS
+    pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+    /// SOFTWARE
+    pub const SOFTWARE: u16 = 0x8022;
+    /// ALTERNATE-SERVER
+    pub const ALTERNATE_SERVER: u16 = 0x8023;
+    /// FINGERPRINT
+    pub const FINGERPRINT: u16 = 0x8028;
+    /// CHANGE-REQUEST, from [RFC 5780](https://www.rfc-editor.org/rfc/rfc5780#section-7.2)
+    pub const CHANGE_REQUEST: u16 = 0x0003;
+    /// RESPONSE-ORIGIN, from [RFC 5780](https://www.rfc-editor.org/rfc/rfc5780#section-7.3)
+    pub const RESPONSE_ORIGIN: u16 = 0x802B;
+    /// OTHER-ADDRESS, from [RFC 5780](https://www.rfc-editor.org/rfc/rfc5780#section-7.4)
+    pub const OTHER_ADDRESS: u16 = 0x802C;
+
+    /// Reports whether an attribute type is comprehension-required.
+    ///
+    /// A message with a comprehension-required attribute the receiver does not understand
+    /// must not be processed.
+    pub fn is_comprehension_required(attr_type: u16) -> bool {
+        attr_type < 0x8000
+    }
+}
+
+/// The attribute types understood by this module.
+const KNOWN_ATTRS: [u16; 6] = [
+    attr_type::MAPPED_ADDRESS,
+    attr_type::ERROR_CODE,
+    attr_type::UNKNOWN_ATTRIBUTES,
+    attr_type::XOR_MAPPED_ADDRESS,
+    attr_type::SOFTWARE,
+    attr_type::FINGERPRINT,
+];
+
+const HEADER_LEN: usize = stun_rs::MESSAGE_HEADER_SIZE;
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+const FINGERPRINT_CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// A STUN attribute which can be encoded to and decoded from its value.
+///
+/// Implement this for user-defined attributes to add them using
+/// [`MessageBuilder::attribute`] and read them using [`Message::get`].
+pub trait Attribute: Sized {
+    /// The attribute type.
+    const TYPE: u16;
+
+    /// Encodes the value of the attribute, without padding.
+    fn encode_value(&self) -> Vec<u8>;
+
+    /// Decodes the attribute from its value.
+    fn decode_value(value: &[u8]) -> Result<Self, Error>;
+}
+
+/// The SOFTWARE attribute, describing the software sending the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Software(pub String);
+
+impl Attribute for Software {
+    const TYPE: u16 = attr_type::SOFTWARE;
+
+    fn encode_value(&self) -> Vec<u8> {
+        self.0.as_bytes().to_vec()
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self, Error> {
+        let software = std::str::from_utf8(value).map_err(|_| Error::MalformedAttrs)?;
+        Ok(Self(software.to_string()))
+    }
+}
+
+/// The ERROR-CODE attribute of error responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorCode {
+    /// The error code, in the range 300 to 699.
+    pub code: u16,
+    /// A human readable description of the error.
+    pub reason: String,
+}
+
+impl ErrorCode {
+    /// The client should contact an alternate server.
+    pub const TRY_ALTERNATE: u16 = 300;
+    /// The request was malformed.
+    pub const BAD_REQUEST: u16 = 400;
+    /// The request did not contain the correct credentials.
+    pub const UNAUTHORIZED: u16 = 401;
+    /// The server did not understand a comprehension-required attribute.
+    pub const UNKNOWN_ATTRIBUTE: u16 = 420;
+    /// The NONCE used by the client is no longer valid.
+    pub const STALE_NONCE: u16 = 438;
+    /// The server has suffered a temporary error.
+    pub const SERVER_ERROR: u16 = 500;
+
+    /// Creates a new error code attribute.
+    pub fn new(code: u16, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Attribute for ErrorCode {
+    const TYPE: u16 = attr_type::ERROR_CODE;
+
+    fn encode_value(&self) -> Vec<u8> {
+        let mut value = vec![
+            0,
+            0,
+            (self.code / 100) as u8 & 0x07,
+            (self.code % 100) as u8,
+        ];
+        value.extend_from_slice(self.reason.as_bytes());
+        value
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self, Error> {
+        if value.len() < 4 {
+            return Err(Error::MalformedAttrs);
+        }
+        let class = u16::from(value[2] & 0x07);
+        let number = u16::from(value[3]);
+        if !(3..=6).contains(&class) || number > 99 {
+            return Err(Error::MalformedAttrs);
+        }
+        let reason = std::str::from_utf8(&value[4..]).map_err(|_| Error::MalformedAttrs)?;
+        Ok(Self::new(class * 100 + number, reason))
+    }
+}
+
+/// The UNKNOWN-ATTRIBUTES attribute, listing the attributes a request was rejected for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownAttributes(pub Vec<u16>);
+
+impl Attribute for UnknownAttributes {
+    const TYPE: u16 = attr_type::UNKNOWN_ATTRIBUTES;
+
+    fn encode_value(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|t| t.to_be_bytes()).collect()
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self, Error> {
+        if value.len() % 2 != 0 {
+            return Err(Error::MalformedAttrs);
+        }
+        let types = value
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        Ok(Self(types))
+    }
+}
+
+/// The CHANGE-REQUEST attribute, asking the server to respond from another address.
+///
+/// Only servers supporting [RFC 5780] understand this attribute, they advertise their
+/// alternate address in the OTHER-ADDRESS attribute, see [`Message::other_address`].
+///
+/// [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780#section-7.2
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeRequest {
+    /// Respond from the alternate IP address.
+    pub change_ip: bool,
+    /// Respond from the alternate port.
+    pub change_port: bool,
+}
+
+impl ChangeRequest {
+    const CHANGE_IP: u8 = 0x04;
+    const CHANGE_PORT: u8 = 0x02;
+}
+
+impl Attribute for ChangeRequest {
+    const TYPE: u16 = attr_type::CHANGE_REQUEST;
+
+    fn encode_value(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.change_ip {
+            flags |= Self::CHANGE_IP;
+        }
+        if self.change_port {
+            flags |= Self::CHANGE_PORT;
+        }
+        vec![0, 0, 0, flags]
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self, Error> {
+        let value = <[u8; 4]>::try_from(value).map_err(|_| Error::MalformedAttrs)?;
+        Ok(Self {
+            change_ip: value[3] & Self::CHANGE_IP != 0,
+            change_port: value[3] & Self::CHANGE_PORT != 0,
+        })
+    }
+}
+
+/// An attribute of a message, as found on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawAttribute {
+    /// The attribute type.
+    pub attr_type: u16,
+    /// The attribute value, without padding.
+    pub value: Vec<u8>,
+}
+
+/// A parsed STUN message.
+///
+/// All attributes are kept, use [`Message::get`] to decode them.
+#[derive(Debug, Clone)]
+pub struct Message {
+    msg_type: MessageType,
+    transaction_id: TransactionId,
+    attributes: Vec<RawAttribute>,
+}
+
+impl Message {
+    /// Parses a STUN message.
+    ///
+    /// When the message carries a FINGERPRINT attribute it must be the last attribute and
+    /// be valid.  Bytes beyond the length given in the message header are ignored.
+    pub fn parse(b: &[u8]) -> Result<Self, Error> {
+        Self::decode(b, true)
+    }
+
+    /// Parses a STUN message without validating its FINGERPRINT attribute.
+    pub(super) fn parse_unverified(b: &[u8]) -> Result<Self, Error> {
+        Self::decode(b, false)
+    }
+
+    fn decode(b: &[u8], verify_fingerprint: bool) -> Result<Self, Error> {
+        if !super::is(b) {
+            return Err(Error::InvalidMessage);
+        }
+        let len = usize::from(u16::from_be_bytes([b[2], b[3]]));
+        if len % 4 != 0 || b.len() < HEADER_LEN + len {
+            return Err(Error::InvalidMessage);
+        }
+        let b = &b[..HEADER_LEN + len];
+        let msg_type = MessageType::from(u16::from_be_bytes([b[0], b[1]]));
+        let transaction_id = TransactionId::from(<[u8; 12]>::try_from(&b[8..20]).expect("len"));
+
+        let mut attributes = Vec::new();
+        let mut pos = HEADER_LEN;
+        while pos < b.len() {
+            if b.len() - pos < 4 {
+                return Err(Error::InvalidMessage);
+            }
+            let attr_type = u16::from_be_bytes([b[pos], b[pos + 1]]);
+            let attr_len = usize::from(u16::from_be_bytes([b[pos + 2], b[pos + 3]]));
+            let start = pos + 4;
+            if b.len() - start < attr_len {
+                return Err(Error::InvalidMessage);
+            }
+            let value = &b[start..start + attr_len];
+            if verify_fingerprint && attr_type == attr_type::FINGERPRINT {
+                if start + attr_len != b.len() || attr_len != 4 {
+                    return Err(Error::InvalidFingerprint);
+                }
+                if value != fingerprint(&b[..pos]).to_be_bytes() {
+                    return Err(Error::InvalidFingerprint);
+                }
+            }
+            attributes.push(RawAttribute {
+                attr_type,
+                value: value.to_vec(),
+            });
+            pos = start + padded(attr_len);
+        }
+
+        Ok(Self {
+            msg_type,
+            transaction_id,
+            attributes,
+        })
+    }
+
+    /// The class of the message.
+    pub fn class(&self) -> MessageClass {
+        self.msg_type.class()
+    }
+
+    /// The method of the message.
+    pub fn method(&self) -> MessageMethod {
+        self.msg_type.method()
+    }
+
+    /// The transaction ID of the message.
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    /// All attributes of the message, in the order they appear in.
+    pub fn attributes(&self) -> &[RawAttribute] {
+        &self.attributes
+    }
+
+    /// The value of the first attribute of the given type.
+    pub fn raw(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|attr| attr.attr_type == attr_type)
+            .map(|attr| attr.value.as_slice())
+    }
+
+    /// Decodes the first attribute of type `A::TYPE`.
+    ///
+    /// Returns `Ok(None)` if the message has no such attribute.
+    pub fn get<A: Attribute>(&self) -> Result<Option<A>, Error> {
+        self.raw(A::TYPE).map(A::decode_value).transpose()
+    }
+
+    /// Reports whether the message carries a (valid) FINGERPRINT attribute.
+    pub fn has_fingerprint(&self) -> bool {
+        self.raw(attr_type::FINGERPRINT).is_some()
+    }
+
+    /// The address from the XOR-MAPPED-ADDRESS attribute.
+    ///
+    /// Falls back to the MAPPED-ADDRESS attribute if there is no XOR-MAPPED-ADDRESS.
+    pub fn mapped_address(&self) -> Result<Option<SocketAddr>, Error> {
+        if let Some(value) = self.raw(attr_type::XOR_MAPPED_ADDRESS) {
+            let xored = decode_address(value)?;
+            let addr = xor_address(xored, &self.transaction_id);
+            return Ok(Some(canonical(addr)));
+        }
+        self.raw(attr_type::MAPPED_ADDRESS)
+            .map(|value| decode_address(value).map(canonical))
+            .transpose()
+    }
+
+    /// The address from the OTHER-ADDRESS attribute.
+    ///
+    /// Servers supporting [RFC 5780] send this in binding responses, it is the address
+    /// they respond from when asked to change their IP address and port using a
+    /// [`ChangeRequest`].
+    ///
+    /// [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780#section-7.4
+    pub fn other_address(&self) -> Result<Option<SocketAddr>, Error> {
+        self.raw(attr_type::OTHER_ADDRESS)
+            .map(|value| decode_address(value).map(canonical))
+            .transpose()
+    }
+
+    /// The comprehension-required attributes of the message which are not understood.
+    ///
+    /// Attributes are understood if they are handled by this module, or if their type is
+    /// contained in `known`.  A request with such attributes should be rejected with an
+    /// [`ErrorCode::UNKNOWN_ATTRIBUTE`] error response listing them in an
+    /// [`UnknownAttributes`] attribute.
+    pub fn unknown_attributes(&self, known: &[u16]) -> Vec<u16> {
+        let mut unknown: Vec<u16> = Vec::new();
+        for attr in &self.attributes {
+            let t = attr.attr_type;
+            if attr_type::is_comprehension_required(t)
+                && !KNOWN_ATTRS.contains(&t)
+                && !known.contains(&t)
+                && !unknown.contains(&t)
+            {
+                unknown.push(t);
+            }
+        }
+        unknown
+    }
+}
+
+/// Builds STUN messages with arbitrary attributes.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    msg_type: MessageType,
+    transaction_id: TransactionId,
+    attributes: Vec<RawAttribute>,
+    fingerprint: bool,
+}
+
+impl MessageBuilder {
+    /// Creates a builder for a message without attributes.
+    pub fn new(method: MessageMethod, class: MessageClass, transaction_id: TransactionId) -> Self {
+        Self {
+            msg_type: MessageType::new(method, class),
+            transaction_id,
+            attributes: Vec::new(),
+            fingerprint: false,
+        }
+    }
+
+    /// Adds an attribute.
+    pub fn attribute<A: Attribute>(self, attr: &A) -> Self {
+        self.raw_attribute(A::TYPE, attr.encode_value())
+    }
+
+    /// Adds an attribute from its type and value.
+    pub fn raw_attribute(mut self, attr_type: u16, value: impl Into<Vec<u8>>) -> Self {
+        self.attributes.push(RawAttribute {
+            attr_type,
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Adds a XOR-MAPPED-ADDRESS attribute.
+    pub fn xor_mapped_address(self, addr: SocketAddr) -> Self {
+        let value = encode_address(xor_address(addr, &self.transaction_id));
+        self.raw_attribute(attr_type::XOR_MAPPED_ADDRESS, value)
+    }
+
+    /// Adds a MAPPED-ADDRESS attribute.
+    pub fn mapped_address(self, addr: SocketAddr) -> Self {
+        self.raw_attribute(attr_type::MAPPED_ADDRESS, encode_address(addr))
+    }
+
+    /// Adds an OTHER-ADDRESS attribute.
+    pub fn other_address(self, addr: SocketAddr) -> Self {
+        self.raw_attribute(attr_type::OTHER_ADDRESS, encode_address(addr))
+    }
+
+    /// Ends the message with a FINGERPRINT attribute.
+    pub fn fingerprint(mut self) -> Self {
+        self.fingerprint = true;
+        self
+    }
+
+    /// Encodes the message.
+    ///
+    /// Fails if an attribute value or the message is too large to encode.
+    pub fn build(self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + 64);
+        buf.extend_from_slice(&self.msg_type.as_u16().to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&COOKIE);
+        buf.extend_from_slice(self.transaction_id.as_bytes());
+        for attr in &self.attributes {
+            let len = u16::try_from(attr.value.len()).map_err(|_| Error::MessageTooLarge)?;
+            buf.extend_from_slice(&attr.attr_type.to_be_bytes());
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(&attr.value);
+            buf.resize(HEADER_LEN + padded(buf.len() - HEADER_LEN), 0);
+        }
+        let len = buf.len() - HEADER_LEN;
+        if self.fingerprint {
+            set_len(&mut buf, len + 8)?;
+            let crc = fingerprint(&buf);
+            buf.extend_from_slice(&attr_type::FINGERPRINT.to_be_bytes());
+            buf.extend_from_slice(&4u16.to_be_bytes());
+            buf.extend_from_slice(&crc.to_be_bytes());
+        } else {
+            set_len(&mut buf, len)?;
+        }
+        Ok(buf)
+    }
+}
+
+/// Rounds an attribute length up to the 4 byte boundary.
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+fn set_len(buf: &mut [u8], len: usize) -> Result<(), Error> {
+    let len = u16::try_from(len).map_err(|_| Error::MessageTooLarge)?;
+    buf[2..4].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+/// Computes the FINGERPRINT value of the message preceding the attribute.
+fn fingerprint(b: &[u8]) -> u32 {
+    FINGERPRINT_CRC.checksum(b) ^ FINGERPRINT_XOR
+}
+
+fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut value = vec![0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(0x01);
+            value.extend_from_slice(&addr.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value.push(0x02);
+            value.extend_from_slice(&addr.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+    }
+    value
+}
+
+fn decode_address(value: &[u8]) -> Result<SocketAddr, Error> {
+    if value.len() < 4 {
+        return Err(Error::MalformedAttrs);
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]);
+    let ip: IpAddr = match (value[1], &value[4..]) {
+        (0x01, ip) => {
+            Ipv4Addr::from(<[u8; 4]>::try_from(ip).map_err(|_| Error::MalformedAttrs)?).into()
+        }
+        (0x02, ip) => {
+            Ipv6Addr::from(<[u8; 16]>::try_from(ip).map_err(|_| Error::MalformedAttrs)?).into()
+        }
+        _ => return Err(Error::MalformedAttrs),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Converts IPv4-mapped IPv6 addresses to IPv4.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Applies the XOR-MAPPED-ADDRESS obfuscation, which is its own inverse.
+fn xor_address(addr: SocketAddr, tx: &TransactionId) -> SocketAddr {
+    let port = addr.port() ^ u16::from_be_bytes([COOKIE[0], COOKIE[1]]);
+    let ip: IpAddr = match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            octets.iter_mut().zip(COOKIE).for_each(|(o, c)| *o ^= c);
+            octets.into()
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            let key = COOKIE.iter().chain(tx.as_bytes());
+            octets.iter_mut().zip(key).for_each(|(o, c)| *o ^= c);
+            octets.into()
+        }
+    };
+    SocketAddr::new(ip, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use stun_rs::{
+        attributes::stun::Fingerprint, methods, MessageEncoderBuilder, StunMessageBuilder,
+    };
+
+    use super::*;
+    use crate::protos::stun::{parse_binding_request, parse_response, request};
+
+    /// A user-defined attribute.
+    #[derive(Debug, PartialEq)]
+    struct Priority(u32);
+
+    impl Attribute for Priority {
+        const TYPE: u16 = 0x0024;
+
+        fn encode_value(&self) -> Vec<u8> {
+            self.0.to_be_bytes().to_vec()
+        }
+
+        fn decode_value(value: &[u8]) -> Result<Self, Error> {
+            let value = <[u8; 4]>::try_from(value).map_err(|_| Error::MalformedAttrs)?;
+            Ok(Self(u32::from_be_bytes(value)))
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let tx = TransactionId::from([7; 12]);
+        let addr: SocketAddr = "[2001:db8::1]:4242".parse().unwrap();
+        let pkt = MessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse, tx)
+            .attribute(&Software("iroh".into()))
+            .attribute(&Priority(12345))
+            .xor_mapped_address(addr)
+            .fingerprint()
+            .build()
+            .unwrap();
+
+        // The existing helpers understand the message.
+        assert_eq!(parse_response(&pkt).unwrap(), (tx, addr));
+
+        let msg = Message::parse(&pkt).unwrap();
+        assert_eq!(msg.class(), MessageClass::SuccessResponse);
+        assert_eq!(msg.method(), methods::BINDING);
+        assert_eq!(msg.transaction_id(), tx);
+        assert_eq!(
+            msg.get::<Software>().unwrap(),
+            Some(Software("iroh".into()))
+        );
+        assert_eq!(msg.get::<Priority>().unwrap(), Some(Priority(12345)));
+        assert_eq!(msg.get::<ErrorCode>().unwrap(), None);
+        assert_eq!(msg.mapped_address().unwrap(), Some(addr));
+        assert!(msg.has_fingerprint());
+        assert_eq!(msg.unknown_attributes(&[]), vec![Priority::TYPE]);
+        assert!(msg.unknown_attributes(&[Priority::TYPE]).is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let tx = TransactionId::default();
+        // Fingerprints created by stun_rs are validated.
+        let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::Request)
+            .with_transaction_id(tx)
+            .with_attribute(Fingerprint::default())
+            .build();
+        let mut buffer = vec![0u8; 150];
+        let size = MessageEncoderBuilder::default()
+            .build()
+            .encode(&mut buffer, &msg)
+            .unwrap();
+        let msg = Message::parse(&buffer[..size]).unwrap();
+        assert!(msg.has_fingerprint());
+        assert!(Message::parse(&request(tx)).unwrap().has_fingerprint());
+        let pkt = MessageBuilder::new(methods::BINDING, MessageClass::Request, tx)
+            .attribute(&Software("iroh".into()))
+            .fingerprint()
+            .build()
+            .unwrap();
+        assert_eq!(parse_binding_request(&pkt).unwrap(), tx);
+
+        let mut corrupted = pkt.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(matches!(
+            Message::parse(&corrupted),
+            Err(Error::InvalidFingerprint)
+        ));
+
+        let pkt = MessageBuilder::new(methods::BINDING, MessageClass::Request, tx)
+            .build()
+            .unwrap();
+        assert!(!Message::parse(&pkt).unwrap().has_fingerprint());
+    }
+
+    #[test]
+    fn test_error_response() {
+        let tx = TransactionId::default();
+        let pkt = MessageBuilder::new(methods::BINDING, MessageClass::ErrorResponse, tx)
+            .attribute(&ErrorCode::new(
+                ErrorCode::UNKNOWN_ATTRIBUTE,
+                "Unknown Attribute",
+            ))
+            .attribute(&UnknownAttributes(vec![0x0024, 0x0025]))
+            .build()
+            .unwrap();
+        let msg = Message::parse(&pkt).unwrap();
+        assert_eq!(msg.class(), MessageClass::ErrorResponse);
+        let error = msg.get::<ErrorCode>().unwrap().unwrap();
+        assert_eq!(error.code, 420);
+        assert_eq!(error.reason, "Unknown Attribute");
+        assert_eq!(
+            msg.get::<UnknownAttributes>().unwrap(),
+            Some(UnknownAttributes(vec![0x0024, 0x0025]))
+        );
+        assert!(msg.unknown_attributes(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_change_request() {
+        let tx = TransactionId::default();
+        let change = ChangeRequest {
+            change_ip: true,
+            change_port: false,
+        };
+        let pkt = MessageBuilder::new(methods::BINDING, MessageClass::Request, tx)
+            .attribute(&change)
+            .fingerprint()
+            .build()
+            .unwrap();
+        let msg = Message::parse(&pkt).unwrap();
+        assert_eq!(msg.get::<ChangeRequest>().unwrap(), Some(change));
+        assert_eq!(msg.raw(attr_type::CHANGE_REQUEST), Some(&[0, 0, 0, 4][..]));
+        // Servers without RFC 5780 support have to reject the request.
+        assert_eq!(msg.unknown_attributes(&[]), vec![attr_type::CHANGE_REQUEST]);
+
+        let other: SocketAddr = "192.0.2.2:3479".parse().unwrap();
+        let pkt = MessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse, tx)
+            .xor_mapped_address("198.51.100.1:1234".parse().unwrap())
+            .other_address(other)
+            .build()
+            .unwrap();
+        let msg = Message::parse(&pkt).unwrap();
+        assert_eq!(msg.other_address().unwrap(), Some(other));
+    }
+}
@@ -0,0 +1,292 @@
+//! A standalone STUN binding server.
+
+use std::{io, net::SocketAddr};
+
+#[cfg(feature = "server")]
+use iroh_metrics::inc;
+use tokio::net::UdpSocket;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use super::{
+    decode_binding_request, is, methods, response, ErrorCode, MessageBuilder, MessageClass,
+    TransactionId, UnknownAttributes,
+};
+#[cfg(feature = "server")]
+use crate::server::StunMetrics;
+
+/// Configuration for a STUN [`Server`].
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    verify_fingerprint: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            verify_fingerprint: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Sets whether binding requests must carry a valid FINGERPRINT attribute.
+    ///
+    /// Requests without a valid fingerprint are ignored when enabled, which is the default.
+    pub fn verify_fingerprint(mut self, verify: bool) -> Self {
+        self.verify_fingerprint = verify;
+        self
+    }
+}
+
+/// A STUN server answering binding requests over UDP.
+///
+/// Each binding request is answered with the source address of the request in the
+/// XOR-MAPPED-ADDRESS attribute, nothing else is served.  Requests with
+/// comprehension-required attributes the server does not understand are rejected with an
+/// error response.  This is enough to act as a probe target for net reports.
+///
+/// The server runs in a tokio task, which is stopped when the server is dropped or when
+/// receiving from the socket fails.
+#[derive(Debug)]
+pub struct Server {
+    local_addr: SocketAddr,
+    handle: AbortOnDropHandle<io::Result<()>>,
+}
+
+impl Server {
+    /// Binds a UDP socket to `addr` and runs a STUN server on it.
+    pub async fn bind(addr: SocketAddr, config: ServerConfig) -> io::Result<Self> {
+        let sock = UdpSocket::bind(addr).await?;
+        Self::spawn(sock, config)
+    }
+
+    /// Runs a STUN server on an already bound socket.
+    pub fn spawn(sock: UdpSocket, config: ServerConfig) -> io::Result<Self> {
+        let local_addr = sock.local_addr()?;
+        let span = info_span!("stun-server", %local_addr);
+        let task = tokio::spawn(
+            async move {
+                let res = serve(sock, config).await;
+                if let Err(err) = &res {
+                    warn!("STUN server stopped: {err:#}");
+                }
+                res
+            }
+            .instrument(span),
+        );
+        Ok(Self {
+            local_addr,
+            handle: AbortOnDropHandle::new(task),
+        })
+    }
+
+    /// The local address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Waits for the server to stop by itself.
+    ///
+    /// Returns the receive error which stopped the server.  Panics if the server task
+    /// panicked.
+    pub async fn stopped(self) -> io::Result<()> {
+        match self.handle.await {
+            Ok(res) => res,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Stops the server and waits for its task to finish.
+    pub async fn shutdown(self) {
+        self.handle.abort();
+        self.handle.await.ok();
+    }
+}
+
+/// Answers binding requests until the socket fails.
+///
+/// Errors which only concern a single datagram, like an ICMP error for an earlier
+/// response reported as a connection reset on some platforms, are skipped.  Any other
+/// receive error is returned.
+///
+/// When the `server` feature is enabled the requests are recorded in the [`StunMetrics`].
+async fn serve(sock: UdpSocket, config: ServerConfig) -> io::Result<()> {
+    info!("running STUN server");
+    let mut buffer = vec![0u8; 64 << 10];
+    loop {
+        let (n, src_addr) = match sock.recv_from(&mut buffer).await {
+            Ok(res) => res,
+            Err(err) if is_transient(&err) => {
+                debug!("failed to recv: {err:#}");
+                continue;
+            }
+            Err(err) => {
+                #[cfg(feature = "server")]
+                inc!(StunMetrics, failures);
+                return Err(err);
+            }
+        };
+        #[cfg(feature = "server")]
+        inc!(StunMetrics, requests);
+        let pkt = &buffer[..n];
+        if !is(pkt) {
+            debug!(%src_addr, "ignoring non stun packet");
+            #[cfg(feature = "server")]
+            inc!(StunMetrics, bad_requests);
+            continue;
+        }
+        let msg = match decode_binding_request(pkt, config.verify_fingerprint) {
+            Ok(msg) => msg,
+            Err(err) => {
+                debug!(%src_addr, "invalid binding request: {err}");
+                #[cfg(feature = "server")]
+                inc!(StunMetrics, bad_requests);
+                continue;
+            }
+        };
+        let txid = msg.transaction_id();
+        trace!(%src_addr, %txid, "received binding request");
+        let unknown = msg.unknown_attributes(&[]);
+        let response = if unknown.is_empty() {
+            response(txid, src_addr)
+        } else {
+            debug!(%src_addr, %txid, ?unknown, "rejecting unknown attributes");
+            unknown_attributes_response(txid, unknown)
+        };
+        match sock.send_to(&response, src_addr).await {
+            Ok(len) if len == response.len() => {
+                trace!(%src_addr, %txid, "sent {len} bytes");
+                #[cfg(feature = "server")]
+                match src_addr {
+                    SocketAddr::V4(_) => inc!(StunMetrics, ipv4_success),
+                    SocketAddr::V6(_) => inc!(StunMetrics, ipv6_success),
+                }
+            }
+            Ok(len) => {
+                warn!(
+                    %src_addr,
+                    %txid,
+                    "failed to write response, {len}/{} bytes sent",
+                    response.len()
+                );
+            }
+            Err(err) => {
+                warn!(%src_addr, %txid, "failed to write response: {err:#}");
+                #[cfg(feature = "server")]
+                inc!(StunMetrics, failures);
+            }
+        }
+    }
+}
+
+/// Reports whether a receive error only affects a single datagram.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Generates the error response rejecting a request with unknown attributes.
+fn unknown_attributes_response(tx: TransactionId, unknown: Vec<u16>) -> Vec<u8> {
+    MessageBuilder::new(methods::BINDING, MessageClass::ErrorResponse, tx)
+        .attribute(&ErrorCode::new(
+            ErrorCode::UNKNOWN_ATTRIBUTE,
+            "Unknown Attribute",
+        ))
+        .attribute(&UnknownAttributes(unknown))
+        .fingerprint()
+        .build()
+        .expect("small message")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use stun_rs::{MessageEncoderBuilder, StunMessageBuilder};
+
+    use super::*;
+    use crate::protos::stun::{parse_response, request, Message};
+
+    async fn roundtrip(server: &Server, pkt: &[u8]) -> Option<(TransactionId, SocketAddr)> {
+        let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        sock.send_to(pkt, server.local_addr()).await.unwrap();
+        let mut buf = vec![0u8; 1500];
+        let recv = tokio::time::timeout(
+            std::time::Duration::from_millis(500),
+            sock.recv_from(&mut buf),
+        );
+        let (n, _) = recv.await.ok()?.unwrap();
+        let (txid, addr) = parse_response(&buf[..n]).unwrap();
+        assert_eq!(addr, sock.local_addr().unwrap());
+        Some((txid, addr))
+    }
+
+    /// A binding request without the FINGERPRINT attribute.
+    fn request_without_fingerprint(tx: TransactionId) -> Vec<u8> {
+        let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::Request)
+            .with_transaction_id(tx)
+            .build();
+        let encoder = MessageEncoderBuilder::default().build();
+        let mut buffer = vec![0u8; 150];
+        let size = encoder.encode(&mut buffer, &msg).unwrap();
+        buffer.truncate(size);
+        buffer
+    }
+
+    #[tokio::test]
+    async fn test_stun_server() {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let server = Server::bind(addr, ServerConfig::default()).await.unwrap();
+
+        let tx = TransactionId::default();
+        let (got_tx, _) = roundtrip(&server, &request(tx)).await.unwrap();
+        assert_eq!(got_tx, tx);
+
+        let mut corrupted = request(tx);
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(roundtrip(&server, &corrupted).await.is_none());
+        assert!(roundtrip(&server, b"not a stun packet").await.is_none());
+
+        let pkt = MessageBuilder::new(methods::BINDING, MessageClass::Request, tx)
+            .raw_attribute(0x0024, 7u32.to_be_bytes())
+            .fingerprint()
+            .build()
+            .unwrap();
+        let sock = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        sock.send_to(&pkt, server.local_addr()).await.unwrap();
+        let mut buf = vec![0u8; 1500];
+        let (n, _) = sock.recv_from(&mut buf).await.unwrap();
+        let msg = Message::parse(&buf[..n]).unwrap();
+        assert_eq!(msg.class(), MessageClass::ErrorResponse);
+        assert_eq!(
+            msg.get::<ErrorCode>().unwrap().map(|e| e.code),
+            Some(ErrorCode::UNKNOWN_ATTRIBUTE)
+        );
+        assert_eq!(
+            msg.get::<UnknownAttributes>().unwrap(),
+            Some(UnknownAttributes(vec![0x0024]))
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_stun_server_unverified() {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let config = ServerConfig::default().verify_fingerprint(false);
+        let server = Server::bind(addr, config).await.unwrap();
+
+        let tx = TransactionId::default();
+        let (got_tx, _) = roundtrip(&server, &request_without_fingerprint(tx))
+            .await
+            .unwrap();
+        assert_eq!(got_tx, tx);
+    }
+}
@@ -53,6 +53,17 @@ impl RelayMap {
         self.nodes.is_empty()
     }
 
+    /// Inserts a node, replacing and returning the node with the same URL if there was one.
+    pub fn insert(&mut self, node: impl Into<Arc<RelayNode>>) -> Option<Arc<RelayNode>> {
+        let node = node.into();
+        Arc::make_mut(&mut self.nodes).insert(node.url.clone(), node)
+    }
+
+    /// Removes and returns the node with the given URL.
+    pub fn remove(&mut self, url: &RelayUrl) -> Option<Arc<RelayNode>> {
+        Arc::make_mut(&mut self.nodes).remove(url)
+    }
+
     /// Creates a new [`RelayMap`] with a single relay server configured.
     ///
     /// Allows to set a custom STUN port and different IP addresses for IPv4 and IPv6.
@@ -27,14 +27,13 @@ use hyper::body::Incoming;
 use iroh_base::NodeId;
 #[cfg(feature = "test-utils")]
 use iroh_base::RelayUrl;
-use iroh_metrics::inc;
 use n0_future::{future::Boxed, StreamExt};
 use tokio::{
     net::{TcpListener, UdpSocket},
     task::JoinSet,
 };
 use tokio_util::task::AbortOnDropHandle;
-use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument};
+use tracing::{debug, error, info, info_span, instrument, Instrument};
 
 use crate::{
     defaults::DEFAULT_KEY_CACHE_CAPACITY,
@@ -45,7 +44,9 @@ use crate::{
 
 mod client;
 mod clients;
+mod diagnostics;
 mod http_server;
+mod mesh;
 mod metrics;
 pub(crate) mod resolver;
 pub(crate) mod streams;
@@ -53,7 +54,9 @@ pub(crate) mod streams;
 pub mod testing;
 
 pub use self::{
-    metrics::{Metrics, StunMetrics},
+    diagnostics::{ClientDisconnect, DiagnosticsConfig, DIAGNOSTICS_PATH},
+    mesh::{MeshConfig, MeshKey},
+    metrics::{ClientMetrics, Metrics, StunMetrics},
     resolver::{ReloadingResolver, DEFAULT_CERT_RELOAD_INTERVAL},
 };
 
@@ -123,6 +126,16 @@ pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     pub key_cache_capacity: Option<usize>,
     /// Access configuration.
     pub access: AccessConfig,
+    /// Meshing with other relay servers.
+    ///
+    /// When meshed, clients connected to this server can reach clients connected to any
+    /// other server in the mesh.
+    pub mesh: Option<MeshConfig>,
+    /// The authenticated diagnostics endpoint at [`DIAGNOSTICS_PATH`].
+    ///
+    /// Reports the connected clients, their traffic and why recent clients disconnected.
+    /// Disabled if not present.
+    pub diagnostics: Option<DiagnosticsConfig>,
 }
 
 /// Controls which nodes are allowed to use the relay.
@@ -163,7 +176,19 @@ pub struct StunConfig {
     /// The socket address on which the STUN server should bind.
     ///
     /// Normally you'd chose port `3478`, see [`crate::defaults::DEFAULT_STUN_PORT`].
+    ///
+    /// Ignored if [`StunConfig::share_relay_addr`] is set.
     pub bind_addr: SocketAddr,
+    /// Whether to serve STUN on the UDP port of the socket address the relay server is
+    /// served on.
+    ///
+    /// This allows a single public address to serve both relayed connections and STUN
+    /// probes, in which case the [`RelayNode::stun_port`] is the port of the relay URL.
+    /// This is either [`RelayConfig::http_bind_addr`] or [`TlsConfig::https_bind_addr`] when
+    /// TLS is enabled.  Requires the relay server to be configured.
+    ///
+    /// [`RelayNode::stun_port`]: crate::RelayNode::stun_port
+    pub share_relay_addr: bool,
 }
 
 /// Configuration for the QUIC server.
@@ -298,25 +323,6 @@ impl Server {
             );
         }
 
-        // Start the STUN server.
-        let stun_addr = match config.stun {
-            Some(stun) => {
-                debug!("Starting STUN server");
-                match UdpSocket::bind(stun.bind_addr).await {
-                    Ok(sock) => {
-                        let addr = sock.local_addr()?;
-                        info!("STUN server listening on {addr}");
-                        tasks.spawn(
-                            server_stun_listener(sock).instrument(info_span!("stun-server", %addr)),
-                        );
-                        Some(addr)
-                    }
-                    Err(err) => bail!("failed to bind STUN listener: {err:#?}"),
-                }
-            }
-            None => None,
-        };
-
         // Start the Relay server, but first clone the certs out.
         let certificates = config.relay.as_ref().and_then(|relay| {
             relay.tls.as_ref().and_then(|tls| match tls.cert {
@@ -354,6 +360,8 @@ impl Server {
                     .headers(headers)
                     .key_cache_capacity(key_cache_capacity)
                     .access(relay_config.access)
+                    .mesh_key(relay_config.mesh.as_ref().map(|mesh| mesh.key.clone()))
+                    .diagnostics(relay_config.diagnostics)
                     .request_handler(Method::GET, "/", Box::new(root_handler))
                     .request_handler(Method::GET, "/index.html", Box::new(root_handler))
                     .request_handler(Method::GET, RELAY_PROBE_PATH, Box::new(probe_handler))
@@ -421,6 +429,19 @@ impl Server {
                     }
                 };
                 let relay_server = builder.spawn().await?;
+                if let Some(mesh) = relay_config.mesh {
+                    for peer in mesh.peers {
+                        let span = info_span!("mesh-client", %peer);
+                        tasks.spawn(
+                            mesh::run_client(
+                                peer,
+                                mesh.key.clone(),
+                                relay_server.clients().clone(),
+                            )
+                            .instrument(span),
+                        );
+                    }
+                }
                 (Some(relay_server), http_addr)
             }
             None => (None, None),
@@ -428,6 +449,30 @@ impl Server {
         // If http_addr is Some then relay_server is serving HTTPS.  If http_addr is None
         // relay_server is serving HTTP, including the /generate_204 service.
         let relay_addr = relay_server.as_ref().map(|srv| srv.addr());
+
+        // Start the STUN server.
+        let stun_addr = match config.stun {
+            Some(stun) => {
+                debug!("Starting STUN server");
+                let bind_addr = if stun.share_relay_addr {
+                    relay_addr.context("sharing the relay address requires a relay server")?
+                } else {
+                    stun.bind_addr
+                };
+                match UdpSocket::bind(bind_addr).await {
+                    Ok(sock) => {
+                        let server = protos::stun::Server::spawn(sock, Default::default())?;
+                        let addr = server.local_addr();
+                        info!("STUN server listening on {addr}");
+                        tasks.spawn(async move { server.stopped().await.map_err(Into::into) });
+                        Some(addr)
+                    }
+                    Err(err) => bail!("failed to bind STUN listener: {err:#?}"),
+                }
+            }
+            None => None,
+        };
+
         let relay_handle = relay_server.as_ref().map(|srv| srv.handle());
         let task = tokio::spawn(relay_supervisor(tasks, relay_server, quic_server));
 
@@ -486,6 +531,27 @@ impl Server {
         self.stun_addr
     }
 
+    /// Returns the traffic of each client currently connected to the relay server.
+    ///
+    /// Empty if the server does not run a relay server.
+    pub fn client_metrics(&self) -> Vec<ClientMetrics> {
+        self.relay_handle
+            .as_ref()
+            .map(|handle| handle.client_metrics())
+            .unwrap_or_default()
+    }
+
+    /// Returns the clients which most recently disconnected from the relay server, oldest
+    /// first, with the reason their connection ended.
+    ///
+    /// Empty if the server does not run a relay server.
+    pub fn recent_disconnects(&self) -> Vec<ClientDisconnect> {
+        self.relay_handle
+            .as_ref()
+            .map(|handle| handle.recent_disconnects())
+            .unwrap_or_default()
+    }
+
     /// The certificates chain if configured with manual TLS certificates.
     pub fn certificates(&self) -> Option<Vec<rustls::pki_types::CertificateDer<'static>>> {
         self.certificates.clone()
@@ -579,86 +645,6 @@ async fn relay_supervisor(
     ret
 }
 
-/// Runs a STUN server.
-///
-/// When the future is dropped, the server stops.
-async fn server_stun_listener(sock: UdpSocket) -> Result<()> {
-    info!(addr = ?sock.local_addr().ok(), "running STUN server");
-    let sock = Arc::new(sock);
-    let mut buffer = vec![0u8; 64 << 10];
-    let mut tasks = JoinSet::new();
-    loop {
-        tokio::select! {
-            biased;
-
-            Some(res) = tasks.join_next() => {
-                if let Err(err) = res {
-                    if err.is_panic() {
-                        panic!("task panicked: {:#?}", err);
-                    }
-                }
-            }
-            res = sock.recv_from(&mut buffer) => {
-                match res {
-                    Ok((n, src_addr)) => {
-                        inc!(StunMetrics, requests);
-                        let pkt = &buffer[..n];
-                        if !protos::stun::is(pkt) {
-                            debug!(%src_addr, "STUN: ignoring non stun packet");
-                            inc!(StunMetrics, bad_requests);
-                            continue;
-                        }
-                        let pkt = pkt.to_vec();
-                        tasks.spawn(handle_stun_request(src_addr, pkt, sock.clone()));
-                    }
-                    Err(err) => {
-                        inc!(StunMetrics, failures);
-                        warn!("failed to recv: {err:#}");
-                    }
-                }
-            }
-        }
-    }
-}
-
-/// Handles a single STUN request, doing all logging required.
-async fn handle_stun_request(src_addr: SocketAddr, pkt: Vec<u8>, sock: Arc<UdpSocket>) {
-    let (txid, response) = match protos::stun::parse_binding_request(&pkt) {
-        Ok(txid) => {
-            debug!(%src_addr, %txid, "STUN: received binding request");
-            (txid, protos::stun::response(txid, src_addr))
-        }
-        Err(err) => {
-            inc!(StunMetrics, bad_requests);
-            warn!(%src_addr, "STUN: invalid binding request: {:?}", err);
-            return;
-        }
-    };
-
-    match sock.send_to(&response, src_addr).await {
-        Ok(len) => {
-            if len != response.len() {
-                warn!(
-                    %src_addr,
-                    %txid,
-                    "failed to write response, {len}/{} bytes sent",
-                    response.len()
-                );
-            } else {
-                match src_addr {
-                    SocketAddr::V4(_) => inc!(StunMetrics, ipv4_success),
-                    SocketAddr::V6(_) => inc!(StunMetrics, ipv6_success),
-                }
-            }
-            trace!(%src_addr, %txid, "sent {len} bytes");
-        }
-        Err(err) => {
-            inc!(StunMetrics, failures);
-            warn!(%src_addr, %txid, "failed to write response: {err:#}");
-        }
-    }
-}
-
 fn root_handler(
     _r: Request<Incoming>,
     response: ResponseBuilder,
@@ -829,6 +815,8 @@ mod tests {
                 limits: Default::default(),
                 key_cache_capacity: Some(1024),
                 access: AccessConfig::Everyone,
+                mesh: None,
+                diagnostics: None,
             }),
             quic: None,
             stun: None,
@@ -884,6 +872,8 @@ mod tests {
                 limits: Default::default(),
                 key_cache_capacity: Some(1024),
                 access: AccessConfig::Everyone,
+                mesh: None,
+                diagnostics: None,
             }),
             stun: None,
             quic: None,
@@ -931,6 +921,77 @@ mod tests {
         assert!(body.is_empty());
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_diagnostics() -> TestResult<()> {
+        let server = spawn_local_relay().await?;
+        let url = format!("http://{}{DIAGNOSTICS_PATH}", server.http_addr().unwrap());
+        let response = reqwest::get(&url).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig::<(), ()> {
+                http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                access: AccessConfig::Everyone,
+                mesh: None,
+                diagnostics: Some(DiagnosticsConfig {
+                    token: "secret".to_string(),
+                }),
+            }),
+            quic: None,
+            stun: None,
+            metrics_addr: None,
+        })
+        .await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        let url = format!("{relay_url}{}", &DIAGNOSTICS_PATH[1..]);
+        let http = reqwest::Client::new();
+
+        let response = http.get(&url).send().await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = http.get(&url).bearer_auth("wrong").send().await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_id = secret_key.public();
+        let mut client = ClientBuilder::new(relay_url.clone(), secret_key, dns_resolver())
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        client.next().await.context("stream finished")??;
+
+        let response = http.get(&url).bearer_auth("secret").send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.text().await?;
+        assert!(body.contains(&format!("client node={node_id} ")));
+
+        client.close().await?;
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.recent_disconnects().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let disconnect = &server.recent_disconnects()[0];
+        assert_eq!(disconnect.node_id(), node_id);
+        assert!(server.client_metrics().is_empty());
+
+        let body = http
+            .get(&url)
+            .bearer_auth("secret")
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert!(body.contains("# connected clients: 0"));
+        assert!(body.contains(&format!("disconnect node={node_id} ")));
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_relay_client_legacy_route() {
@@ -1001,6 +1062,129 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_mesh() -> TestResult<()> {
+        let key = MeshKey::generate();
+        let mesh_relay = |peers| {
+            Server::spawn(ServerConfig::<(), ()> {
+                relay: Some(RelayConfig::<(), ()> {
+                    http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                    tls: None,
+                    limits: Default::default(),
+                    key_cache_capacity: Some(1024),
+                    access: AccessConfig::Everyone,
+                    mesh: Some(MeshConfig {
+                        key: key.clone(),
+                        peers,
+                    }),
+                    diagnostics: None,
+                }),
+                quic: None,
+                stun: None,
+                metrics_addr: None,
+            })
+        };
+        // Server A connects to server B, so clients of A can reach clients of B.
+        let server_b = mesh_relay(vec![]).await?;
+        let url_b: RelayUrl = format!("http://{}", server_b.http_addr().unwrap()).parse()?;
+        let server_a = mesh_relay(vec![url_b.clone()]).await?;
+        let url_a: RelayUrl = format!("http://{}", server_a.http_addr().unwrap()).parse()?;
+
+        let resolver = dns_resolver();
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let mut client_a = ClientBuilder::new(url_a, a_secret_key, resolver.clone())
+            .connect()
+            .await?;
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_b = ClientBuilder::new(url_b.clone(), b_secret_key, resolver.clone())
+            .connect()
+            .await?;
+
+        let msg = Bytes::from("hello, b");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        else {
+            panic!("client_b received unexpected message {res:?}");
+        };
+        assert_eq!(remote_node_id, a_key);
+        assert_eq!(data, msg);
+
+        // Connecting with the wrong key is rejected.
+        let builder = ClientBuilder::new(
+            url_b,
+            SecretKey::generate(rand::thread_rng()),
+            resolver.clone(),
+        );
+        assert!(builder.connect_mesh(&MeshKey::generate()).await.is_err());
+        Ok(())
+    }
+
+    /// Runs a SOCKS5 proxy without authentication, accepting a single IPv4 connection.
+    async fn spawn_socks5_proxy() -> Result<(SocketAddr, AbortOnDropHandle<Result<()>>)> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await?;
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await?;
+            let mut req = [0u8; 10];
+            stream.read_exact(&mut req).await?;
+            assert_eq!(req[..4], [5, 1, 0, 1]);
+            let ip = Ipv4Addr::new(req[4], req[5], req[6], req[7]);
+            let port = u16::from_be_bytes([req[8], req[9]]);
+            let mut target = tokio::net::TcpStream::connect((ip, port)).await?;
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+            Ok(())
+        });
+        Ok((addr, AbortOnDropHandle::new(task)))
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_client_socks5_proxy() -> TestResult<()> {
+        let server = spawn_local_relay().await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        let (proxy_addr, _proxy) = spawn_socks5_proxy().await?;
+        let proxy_url: url::Url = format!("socks5://{proxy_addr}").parse()?;
+
+        let resolver = dns_resolver();
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let mut client_a = ClientBuilder::new(relay_url.clone(), a_secret_key, resolver.clone())
+            .proxy_url(proxy_url)
+            .connect()
+            .await?;
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_b = ClientBuilder::new(relay_url, b_secret_key, resolver)
+            .connect()
+            .await?;
+
+        let msg = Bytes::from("hello, b");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        else {
+            panic!("client_b received unexpected message {res:?}");
+        };
+        assert_eq!(remote_node_id, a_key);
+        assert_eq!(data, msg);
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_relay_clients_both_websockets() -> TestResult<()> {
@@ -1126,6 +1310,7 @@ mod tests {
             relay: None,
             stun: Some(StunConfig {
                 bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                share_relay_addr: false,
             }),
             quic: None,
             metrics_addr: None,
@@ -1151,6 +1336,61 @@ mod tests {
         assert_eq!(response_addr, socket.local_addr().unwrap());
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_stun_share_relay_addr() -> TestResult<()> {
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig::<(), ()> {
+                http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                access: AccessConfig::Everyone,
+                mesh: None,
+                diagnostics: None,
+            }),
+            stun: Some(StunConfig {
+                bind_addr: (Ipv4Addr::LOCALHOST, 1).into(),
+                share_relay_addr: true,
+            }),
+            quic: None,
+            metrics_addr: None,
+        })
+        .await?;
+        let stun_addr = server.stun_addr().unwrap();
+        assert_eq!(stun_addr, server.http_addr().unwrap());
+
+        let txid = protos::stun::TransactionId::default();
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket
+            .send_to(&protos::stun::request(txid), stun_addr)
+            .await?;
+        let mut buf = vec![0u8; 64000];
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        assert_eq!(addr, stun_addr);
+        let (txid_back, response_addr) = protos::stun::parse_response(&buf[..len])?;
+        assert_eq!(txid, txid_back);
+        assert_eq!(response_addr, socket.local_addr()?);
+
+        // The relay is still served on the same address.
+        let response = reqwest::get(format!("http://{stun_addr}")).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Sharing the relay address needs a relay server.
+        let res = Server::spawn(ServerConfig::<(), ()> {
+            relay: None,
+            stun: Some(StunConfig {
+                bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                share_relay_addr: true,
+            }),
+            quic: None,
+            metrics_addr: None,
+        })
+        .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_relay_access_control() -> Result<()> {
@@ -1175,6 +1415,8 @@ mod tests {
                     }
                     .boxed()
                 })),
+                mesh: None,
+                diagnostics: None,
             }),
             quic: None,
             stun: None,
@@ -1,8 +1,16 @@
 //! The server-side representation of an ongoing client relaying connection.
 
 use std::{
-    collections::HashSet, future::Future, num::NonZeroU32, pin::Pin, sync::Arc, task::Poll,
-    time::Duration,
+    collections::HashSet,
+    future::Future,
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context, Result};
@@ -24,7 +32,12 @@ use crate::{
         disco,
         relay::{write_frame, Frame, PING_INTERVAL},
     },
-    server::{clients::Clients, metrics::Metrics, streams::RelayedStream, ClientRateLimit},
+    server::{
+        clients::Clients,
+        metrics::{ClientMetrics, Metrics},
+        streams::RelayedStream,
+        ClientRateLimit,
+    },
     PingTracker,
 };
 
@@ -67,6 +80,26 @@ pub(super) struct Client {
     disco_send_queue: mpsc::Sender<Packet>,
     /// Channel to notify the client that a previous sender has disconnected.
     peer_gone: mpsc::Sender<NodeId>,
+    /// Traffic of this connection.
+    stats: Arc<ClientStats>,
+    /// When the connection was registered.
+    connected_at: Instant,
+}
+
+/// Counters for the traffic of a single client connection, see [`ClientMetrics`].
+#[derive(Debug, Default)]
+struct ClientStats {
+    bytes_sent: AtomicU64,
+    bytes_recv: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_recv: AtomicU64,
+    packets_dropped: AtomicU64,
+}
+
+impl ClientStats {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
 }
 
 impl Client {
@@ -99,6 +132,7 @@ impl Client {
 
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
+        let stats = Arc::new(ClientStats::default());
 
         let actor = Actor {
             stream,
@@ -111,6 +145,7 @@ impl Client {
             clients: clients.clone(),
             client_counter: ClientCounter::default(),
             ping_tracker: PingTracker::default(),
+            stats: stats.clone(),
         };
 
         // start io loop
@@ -129,6 +164,8 @@ impl Client {
             send_queue: send_queue_s,
             disco_send_queue: disco_send_queue_s,
             peer_gone: peer_gone_s,
+            stats,
+            connected_at: Instant::now(),
         }
     }
 
@@ -173,6 +210,25 @@ impl Client {
     pub(super) fn try_send_peer_gone(&self, key: NodeId) -> Result<(), TrySendError<NodeId>> {
         self.peer_gone.try_send(key)
     }
+
+    /// Records a packet for the client dropped before reaching its queue.
+    pub(super) fn record_dropped(&self) {
+        ClientStats::add(&self.stats.packets_dropped, 1);
+    }
+
+    /// Returns the traffic of this connection so far.
+    pub(super) fn metrics(&self) -> ClientMetrics {
+        let stats = &self.stats;
+        ClientMetrics {
+            node_id: self.node_id,
+            connected_for: self.connected_at.elapsed(),
+            bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+            bytes_recv: stats.bytes_recv.load(Ordering::Relaxed),
+            packets_sent: stats.packets_sent.load(Ordering::Relaxed),
+            packets_recv: stats.packets_recv.load(Ordering::Relaxed),
+            packets_dropped: stats.packets_dropped.load(Ordering::Relaxed),
+        }
+    }
 }
 
 /// Manages all the reads and writes to this client. It periodically sends a `KEEP_ALIVE`
@@ -213,6 +269,27 @@ struct Actor {
     /// Statistics about the connected clients
     client_counter: ClientCounter,
     ping_tracker: PingTracker,
+    /// Traffic of this connection, shared with the [`Client`].
+    stats: Arc<ClientStats>,
+}
+
+/// Why the [`Actor`] stopped serving a client, other than an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    /// The server shut down the connection.
+    Shutdown,
+    /// The client did not answer a ping in time.
+    PongTimeout,
+}
+
+impl Exit {
+    /// The disconnect reason reported by the diagnostics endpoint.
+    fn reason(self) -> &'static str {
+        match self {
+            Self::Shutdown => "closed by server",
+            Self::PongTimeout => "ping timed out",
+        }
+    }
 }
 
 impl Actor {
@@ -224,20 +301,23 @@ impl Actor {
         if self.client_counter.update(self.node_id) {
             inc!(Metrics, unique_client_keys);
         }
-        match self.run_inner(done).await {
+        let reason = match self.run_inner(done).await {
             Err(e) => {
                 warn!("actor errored {e:#?}, exiting");
+                format!("{e:#}")
             }
-            Ok(()) => {
-                debug!("actor finished, exiting");
+            Ok(exit) => {
+                debug!(?exit, "actor finished, exiting");
+                exit.reason().to_string()
             }
-        }
+        };
 
-        self.clients.unregister(self.connection_id, self.node_id);
+        self.clients
+            .unregister(self.connection_id, self.node_id, reason);
         inc!(Metrics, disconnects);
     }
 
-    async fn run_inner(&mut self, done: CancellationToken) -> Result<()> {
+    async fn run_inner(&mut self, done: CancellationToken) -> Result<Exit> {
         // Add some jitter to ping pong interactions, to avoid all pings being sent at the same time
         let next_interval = || {
             let random_secs = rand::rngs::OsRng.gen_range(1..=5);
@@ -257,7 +337,7 @@ impl Actor {
                     trace!("actor loop cancelled, exiting");
                     // final flush
                     self.stream.flush().await.context("flush")?;
-                    break;
+                    return Ok(Exit::Shutdown);
                 }
                 maybe_frame = self.stream.next() => {
                     self.handle_frame(maybe_frame).await.context("handle read")?;
@@ -282,7 +362,7 @@ impl Actor {
                 }
                 _ = self.ping_tracker.timeout() => {
                     trace!("pong timed out");
-                    break;
+                    return Ok(Exit::PongTimeout);
                 }
                 _ = ping_interval.tick() => {
                     trace!("keep alive ping");
@@ -295,7 +375,6 @@ impl Actor {
 
             self.stream.flush().await.context("tick flush")?;
         }
-        Ok(())
     }
 
     /// Writes the given frame to the connection.
@@ -315,6 +394,7 @@ impl Actor {
 
         if let Ok(len) = content.len().try_into() {
             inc_by!(Metrics, bytes_sent, len);
+            ClientStats::add(&self.stats.bytes_sent, len);
         }
         self.write_frame(Frame::RecvPacket { src_key, content })
             .await
@@ -325,10 +405,12 @@ impl Actor {
         match self.send_raw(packet).await {
             Ok(()) => {
                 inc!(Metrics, send_packets_sent);
+                ClientStats::add(&self.stats.packets_sent, 1);
                 Ok(())
             }
             Err(err) => {
                 inc!(Metrics, send_packets_dropped);
+                ClientStats::add(&self.stats.packets_dropped, 1);
                 Err(err)
             }
         }
@@ -339,10 +421,12 @@ impl Actor {
         match self.send_raw(packet).await {
             Ok(()) => {
                 inc!(Metrics, disco_packets_sent);
+                ClientStats::add(&self.stats.packets_sent, 1);
                 Ok(())
             }
             Err(err) => {
                 inc!(Metrics, disco_packets_dropped);
+                ClientStats::add(&self.stats.packets_dropped, 1);
                 Err(err)
             }
         }
@@ -361,6 +445,8 @@ impl Actor {
                 let packet_len = packet.len();
                 self.handle_frame_send_packet(dst_key, packet)?;
                 inc_by!(Metrics, bytes_recv, packet_len as u64);
+                ClientStats::add(&self.stats.bytes_recv, packet_len as u64);
+                ClientStats::add(&self.stats.packets_recv, 1);
             }
             Frame::Ping { data } => {
                 inc!(Metrics, got_ping);
@@ -639,6 +725,7 @@ mod tests {
             clients: clients.clone(),
             client_counter: ClientCounter::default(),
             ping_tracker: PingTracker::default(),
+            stats: Default::default(),
         };
 
         let done = CancellationToken::new();
@@ -2,11 +2,12 @@
 // Based on tailscale/derp/derp_server.go
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet, VecDeque},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    time::SystemTime,
 };
 
 use anyhow::{bail, Result};
@@ -14,11 +15,18 @@ use bytes::Bytes;
 use dashmap::DashMap;
 use iroh_base::NodeId;
 use iroh_metrics::inc;
-use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, error::TrySendError};
 use tracing::{debug, trace};
 
-use super::client::{Client, Config};
-use crate::server::metrics::Metrics;
+use super::{
+    client::{Client, Config},
+    diagnostics::{ClientDisconnect, RECENT_DISCONNECTS_CAPACITY},
+    mesh::MESH_QUEUE_DEPTH,
+};
+use crate::{
+    protos::{disco, relay::Frame},
+    server::metrics::{ClientMetrics, Metrics},
+};
 
 /// Manages the connections to all currently connected clients.
 #[derive(Debug, Default, Clone)]
@@ -32,6 +40,16 @@ struct Inner {
     sent_to: DashMap<NodeId, HashSet<NodeId>>,
     /// Connection ID Counter
     next_connection_id: AtomicU64,
+    /// The connections to the peers of the mesh, by mesh connection ID.
+    mesh_peers: DashMap<u64, mpsc::Sender<Frame>>,
+    /// The mesh connection to forward packets for clients of other servers to.
+    mesh_routes: DashMap<NodeId, u64>,
+    /// The peers of the mesh to notify about clients connecting and disconnecting.
+    ///
+    /// Locked while updating `clients`, so the peers see the changes in order.
+    mesh_watchers: Mutex<HashMap<u64, mpsc::Sender<Frame>>>,
+    /// The most recently disconnected clients, oldest first.
+    recent_disconnects: Mutex<VecDeque<ClientDisconnect>>,
 }
 
 impl Clients {
@@ -39,6 +57,7 @@ impl Clients {
         let keys: Vec<_> = self.0.clients.iter().map(|x| *x.key()).collect();
         trace!("shutting down {} clients", keys.len());
         let clients = keys.into_iter().filter_map(|k| self.0.clients.remove(&k));
+        self.0.mesh_watchers.lock().expect("poisoned").clear();
 
         n0_future::join_all(clients.map(|(_, client)| async move { client.shutdown().await }))
             .await;
@@ -51,15 +70,51 @@ impl Clients {
         trace!(remote_node = node_id.fmt_short(), "registering client");
 
         let client = Client::new(client_config, connection_id, self);
-        if let Some(old_client) = self.0.clients.insert(node_id, client) {
+        let old_client = {
+            let mut watchers = self.0.mesh_watchers.lock().expect("poisoned");
+            let old_client = self.0.clients.insert(node_id, client);
+            if old_client.is_none() {
+                notify_watchers(&mut watchers, Frame::NodePresent { node_id });
+            }
+            old_client
+        };
+        if let Some(old_client) = old_client {
             debug!(
                 remote_node = node_id.fmt_short(),
                 "multiple connections found, pruning old connection",
             );
+            self.record_disconnect(old_client.metrics(), "replaced by a new connection".into());
             old_client.shutdown().await;
         }
     }
 
+    /// Returns the traffic of each connected client.
+    pub(super) fn metrics(&self) -> Vec<ClientMetrics> {
+        self.0
+            .clients
+            .iter()
+            .map(|client| client.value().metrics())
+            .collect()
+    }
+
+    /// Returns the most recently disconnected clients, oldest first.
+    pub(super) fn recent_disconnects(&self) -> Vec<ClientDisconnect> {
+        let disconnects = self.0.recent_disconnects.lock().expect("poisoned");
+        disconnects.iter().cloned().collect()
+    }
+
+    fn record_disconnect(&self, metrics: ClientMetrics, reason: String) {
+        let mut disconnects = self.0.recent_disconnects.lock().expect("poisoned");
+        if disconnects.len() == RECENT_DISCONNECTS_CAPACITY {
+            disconnects.pop_front();
+        }
+        disconnects.push_back(ClientDisconnect {
+            at: SystemTime::now(),
+            reason,
+            metrics,
+        });
+    }
+
     fn get_connection_id(&self) -> u64 {
         self.0.next_connection_id.fetch_add(1, Ordering::Relaxed)
     }
@@ -68,19 +123,28 @@ impl Clients {
     /// to each client that peers has sent data to, to let them know that
     /// peer is gone from the network.
     ///
-    /// Must be passed a matching connection_id.
-    pub(super) fn unregister(&self, connection_id: u64, node_id: NodeId) {
+    /// Must be passed a matching connection_id.  The `reason` is kept for the diagnostics
+    /// endpoint.
+    pub(super) fn unregister(&self, connection_id: u64, node_id: NodeId, reason: String) {
         trace!(
             node_id = node_id.fmt_short(),
             connection_id,
             "unregistering client"
         );
 
-        if let Some((_, client)) = self
-            .0
-            .clients
-            .remove_if(&node_id, |_, c| c.connection_id() == connection_id)
-        {
+        let removed = {
+            let mut watchers = self.0.mesh_watchers.lock().expect("poisoned");
+            let removed = self
+                .0
+                .clients
+                .remove_if(&node_id, |_, c| c.connection_id() == connection_id);
+            if removed.is_some() {
+                notify_watchers(&mut watchers, Frame::NodeGone { node_id });
+            }
+            removed
+        };
+        if let Some((_, client)) = removed {
+            self.record_disconnect(client.metrics(), reason);
             if let Some((_, sent_to)) = self.0.sent_to.remove(&node_id) {
                 for key in sent_to {
                     match client.try_send_peer_gone(key) {
@@ -104,12 +168,59 @@ impl Clients {
     }
 
     /// Attempt to send a packet to client with [`NodeId`] `dst`.
+    ///
+    /// Packets for clients connected to a peer of the mesh are forwarded to that peer.
     pub(super) fn send_packet(&self, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
         let Some(client) = self.0.clients.get(&dst) else {
+            if self.forward(dst, data, src) {
+                return Ok(());
+            }
             debug!(dst = dst.fmt_short(), "no connected client, dropped packet");
             inc!(Metrics, send_packets_dropped);
             return Ok(());
         };
+        self.deliver(&client, dst, data, src)
+    }
+
+    /// Attempt to send a disco packet to client with [`NodeId`] `dst`.
+    ///
+    /// Packets for clients connected to a peer of the mesh are forwarded to that peer.
+    pub(super) fn send_disco_packet(&self, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
+        let Some(client) = self.0.clients.get(&dst) else {
+            if self.forward(dst, data, src) {
+                return Ok(());
+            }
+            debug!(
+                dst = dst.fmt_short(),
+                "no connected client, dropped disco packet"
+            );
+            inc!(Metrics, disco_packets_dropped);
+            return Ok(());
+        };
+        self.deliver_disco(&client, dst, data, src)
+    }
+
+    /// Delivers a packet forwarded by a peer of the mesh to the client with [`NodeId`] `dst`.
+    ///
+    /// Unlike [`Clients::send_packet`] this never forwards the packet again.
+    pub(super) fn send_forwarded_packet(
+        &self,
+        dst: NodeId,
+        data: Bytes,
+        src: NodeId,
+    ) -> Result<()> {
+        let Some(client) = self.0.clients.get(&dst) else {
+            inc!(Metrics, mesh_packets_dropped);
+            bail!("no connected client");
+        };
+        if disco::looks_like_disco_wrapper(&data) {
+            self.deliver_disco(&client, dst, data, src)
+        } else {
+            self.deliver(&client, dst, data, src)
+        }
+    }
+
+    fn deliver(&self, client: &Client, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
         match client.try_send_packet(src, data) {
             Ok(_) => {
                 // Record sent_to relationship
@@ -121,6 +232,7 @@ impl Clients {
                     dst = dst.fmt_short(),
                     "client too busy to receive packet, dropping packet"
                 );
+                client.record_dropped();
                 bail!("failed to send message: full");
             }
             Err(TrySendError::Closed(_)) => {
@@ -134,16 +246,7 @@ impl Clients {
         }
     }
 
-    /// Attempt to send a disco packet to client with [`NodeId`] `dst`.
-    pub(super) fn send_disco_packet(&self, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
-        let Some(client) = self.0.clients.get(&dst) else {
-            debug!(
-                dst = dst.fmt_short(),
-                "no connected client, dropped disco packet"
-            );
-            inc!(Metrics, disco_packets_dropped);
-            return Ok(());
-        };
+    fn deliver_disco(&self, client: &Client, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
         match client.try_send_disco_packet(src, data) {
             Ok(_) => {
                 // Record sent_to relationship
@@ -155,6 +258,7 @@ impl Clients {
                     dst = dst.fmt_short(),
                     "client too busy to receive disco packet, dropping packet"
                 );
+                client.record_dropped();
                 bail!("failed to send message: full");
             }
             Err(TrySendError::Closed(_)) => {
@@ -167,6 +271,99 @@ impl Clients {
             }
         }
     }
+
+    /// Forwards a packet to the peer of the mesh the client with [`NodeId`] `dst` is
+    /// connected to.
+    ///
+    /// Returns `false` if the client is not connected to any peer.
+    fn forward(&self, dst: NodeId, packet: Bytes, src: NodeId) -> bool {
+        let Some(peer_id) = self.0.mesh_routes.get(&dst).map(|r| *r.value()) else {
+            return false;
+        };
+        let Some(peer) = self.0.mesh_peers.get(&peer_id) else {
+            return false;
+        };
+        let frame = Frame::ForwardPacket {
+            src_key: src,
+            dst_key: dst,
+            packet,
+        };
+        match peer.try_send(frame) {
+            Ok(_) => {
+                trace!(dst = dst.fmt_short(), peer_id, "forwarded packet");
+                inc!(Metrics, mesh_packets_forwarded);
+                self.0.sent_to.entry(src).or_default().insert(dst);
+            }
+            Err(_) => {
+                debug!(
+                    dst = dst.fmt_short(),
+                    peer_id, "mesh peer too busy, dropping packet"
+                );
+                inc!(Metrics, mesh_packets_dropped);
+            }
+        }
+        true
+    }
+
+    /// Adds a connection to a peer of the mesh, returning its mesh connection ID.
+    pub(super) fn add_mesh_peer(&self, sender: mpsc::Sender<Frame>) -> u64 {
+        let peer_id = self.get_connection_id();
+        self.0.mesh_peers.insert(peer_id, sender);
+        peer_id
+    }
+
+    /// Removes a connection to a peer of the mesh, with all routes through it.
+    pub(super) fn remove_mesh_peer(&self, peer_id: u64) {
+        self.0.mesh_peers.remove(&peer_id);
+        self.0.mesh_routes.retain(|_, id| *id != peer_id);
+    }
+
+    /// Records that the client `node_id` is connected to the peer `peer_id`.
+    pub(super) fn add_mesh_route(&self, node_id: NodeId, peer_id: u64) {
+        self.0.mesh_routes.insert(node_id, peer_id);
+    }
+
+    /// Records that the client `node_id` is no longer connected to the peer `peer_id`.
+    pub(super) fn remove_mesh_route(&self, node_id: NodeId, peer_id: u64) {
+        self.0
+            .mesh_routes
+            .remove_if(&node_id, |_, id| *id == peer_id);
+    }
+
+    /// Subscribes a peer of the mesh to the clients connecting to and disconnecting from this
+    /// server.
+    ///
+    /// The returned receiver starts with a [`Frame::NodePresent`] for every currently
+    /// connected client.  It is closed if the peer falls behind.
+    pub(super) fn add_mesh_watcher(&self) -> (u64, mpsc::Receiver<Frame>) {
+        let id = self.get_connection_id();
+        let mut watchers = self.0.mesh_watchers.lock().expect("poisoned");
+        let (sender, receiver) = mpsc::channel(MESH_QUEUE_DEPTH + self.0.clients.len());
+        for client in self.0.clients.iter() {
+            let frame = Frame::NodePresent {
+                node_id: *client.key(),
+            };
+            sender.try_send(frame).expect("sized for all clients");
+        }
+        watchers.insert(id, sender);
+        (id, receiver)
+    }
+
+    /// Unsubscribes a peer of the mesh.
+    pub(super) fn remove_mesh_watcher(&self, id: u64) {
+        self.0.mesh_watchers.lock().expect("poisoned").remove(&id);
+    }
+}
+
+/// Sends `frame` to all mesh watchers, dropping those which fell behind.
+fn notify_watchers(watchers: &mut HashMap<u64, mpsc::Sender<Frame>>, frame: Frame) {
+    watchers.retain(|id, sender| match sender.try_send(frame.clone()) {
+        Ok(_) => true,
+        Err(_) => {
+            debug!(id, "mesh peer fell behind, dropping it");
+            false
+        }
+    });
 }
 
 #[cfg(test)]
@@ -255,4 +452,94 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_client_metrics() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng()).public();
+        let b_key = SecretKey::generate(rand::thread_rng()).public();
+
+        let (builder_a, mut a_rw) = test_client_builder(a_key);
+
+        let clients = Clients::default();
+        clients.register(builder_a).await;
+        assert_eq!(clients.metrics()[0].packets_sent, 0);
+
+        let data = b"hello world!";
+        clients.send_packet(a_key, Bytes::from(&data[..]), b_key)?;
+        recv_frame(FrameType::RecvPacket, &mut a_rw).await?;
+        clients.send_disco_packet(a_key, Bytes::from(&data[..]), b_key)?;
+        recv_frame(FrameType::RecvPacket, &mut a_rw).await?;
+
+        // the counters are updated once the frame is written, which may be just after it
+        // was received
+        let c = clients.clone();
+        let metrics = tokio::time::timeout(Duration::from_secs(1), async move {
+            loop {
+                let metrics = c.metrics();
+                if metrics[0].packets_sent == 2 {
+                    break metrics;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].node_id, a_key);
+        assert_eq!(metrics[0].bytes_sent, 2 * data.len() as u64);
+        assert_eq!(metrics[0].packets_dropped, 0);
+
+        clients.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mesh() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng()).public();
+        let b_key = SecretKey::generate(rand::thread_rng()).public();
+        let c_key = SecretKey::generate(rand::thread_rng()).public();
+
+        let clients = Clients::default();
+        let (builder_a, _a_rw) = test_client_builder(a_key);
+        clients.register(builder_a).await;
+
+        // watchers learn about connected and newly connecting clients
+        let (watcher_id, mut updates) = clients.add_mesh_watcher();
+        assert_eq!(
+            updates.recv().await,
+            Some(Frame::NodePresent { node_id: a_key })
+        );
+        let (builder_b, _b_rw) = test_client_builder(b_key);
+        clients.register(builder_b).await;
+        assert_eq!(
+            updates.recv().await,
+            Some(Frame::NodePresent { node_id: b_key })
+        );
+        clients.remove_mesh_watcher(watcher_id);
+
+        // packets for clients of a peer are forwarded to it
+        let (sender, mut forwards) = mpsc::channel(10);
+        let peer_id = clients.add_mesh_peer(sender);
+        clients.add_mesh_route(c_key, peer_id);
+        let data = Bytes::from_static(b"hello world!");
+        clients.send_packet(c_key, data.clone(), a_key)?;
+        assert_eq!(
+            forwards.recv().await,
+            Some(Frame::ForwardPacket {
+                src_key: a_key,
+                dst_key: c_key,
+                packet: data.clone(),
+            })
+        );
+
+        // forwarded packets are never forwarded again
+        assert!(clients
+            .send_forwarded_packet(c_key, data.clone(), a_key)
+            .is_err());
+
+        clients.remove_mesh_peer(peer_id);
+        assert!(clients.0.mesh_routes.is_empty());
+
+        clients.shutdown().await;
+        Ok(())
+    }
 }
@@ -0,0 +1,128 @@
+//! Diagnostics endpoint of the relay server.
+//!
+//! When enabled using [`RelayConfig::diagnostics`], the relay HTTP server serves a plain
+//! text report at [`DIAGNOSTICS_PATH`] listing the connected clients with their traffic and
+//! the reasons the most recent clients disconnected.  The endpoint requires an
+//! `Authorization: Bearer <token>` header with the configured [`DiagnosticsConfig::token`].
+//!
+//! [`RelayConfig::diagnostics`]: super::RelayConfig::diagnostics
+
+use std::{fmt::Write, time::SystemTime};
+
+use http::{header::AUTHORIZATION, response::Builder as ResponseBuilder, StatusCode};
+use hyper::{Request, Response};
+use iroh_base::NodeId;
+use subtle::ConstantTimeEq;
+
+use super::{clients::Clients, metrics::ClientMetrics};
+
+/// The HTTP path of the diagnostics endpoint.
+pub const DIAGNOSTICS_PATH: &str = "/diagnostics";
+
+/// The number of disconnected clients remembered for the diagnostics report.
+pub(super) const RECENT_DISCONNECTS_CAPACITY: usize = 64;
+
+type BytesBody = http_body_util::Full<hyper::body::Bytes>;
+
+/// Configuration for the diagnostics endpoint.
+#[derive(derive_more::Debug, Clone)]
+pub struct DiagnosticsConfig {
+    /// The bearer token requests to the diagnostics endpoint must present.
+    #[debug("[REDACTED]")]
+    pub token: String,
+}
+
+/// A client which disconnected from the relay server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientDisconnect {
+    /// When the client disconnected.
+    pub at: SystemTime,
+    /// Why the connection ended.
+    pub reason: String,
+    /// The traffic of the connection up to the disconnect.
+    pub metrics: ClientMetrics,
+}
+
+impl ClientDisconnect {
+    /// The node ID of the client.
+    pub fn node_id(&self) -> NodeId {
+        self.metrics.node_id
+    }
+}
+
+impl DiagnosticsConfig {
+    /// Checks the bearer token of a request, in constant time.
+    fn is_authorized<B>(&self, req: &Request<B>) -> bool {
+        let Some(token) = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        else {
+            return false;
+        };
+        token.ct_eq(self.token.as_bytes()).into()
+    }
+}
+
+/// Serves the diagnostics report to authorized requests.
+pub(super) fn handle<B>(
+    config: &DiagnosticsConfig,
+    clients: &Clients,
+    req: &Request<B>,
+    response: ResponseBuilder,
+) -> Result<Response<BytesBody>, http::Error> {
+    if !req.headers().contains_key(AUTHORIZATION) {
+        return response
+            .status(StatusCode::UNAUTHORIZED)
+            .header(http::header::WWW_AUTHENTICATE, "Bearer")
+            .body(BytesBody::default());
+    }
+    if !config.is_authorized(req) {
+        return response
+            .status(StatusCode::FORBIDDEN)
+            .body(BytesBody::default());
+    }
+    response
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(report(&clients.metrics(), &clients.recent_disconnects()).into())
+}
+
+/// Renders the diagnostics report.
+///
+/// Every line is one record of whitespace separated `key=value` fields, the disconnect
+/// reason, which may contain spaces, is always the last field.
+fn report(connected: &[ClientMetrics], disconnects: &[ClientDisconnect]) -> String {
+    let now = SystemTime::now();
+    let mut out = String::new();
+    writeln!(out, "# connected clients: {}", connected.len()).ok();
+    for client in connected {
+        writeln!(out, "client node={} {}", client.node_id, traffic(client)).ok();
+    }
+    writeln!(out, "# recent disconnects: {}", disconnects.len()).ok();
+    for disconnect in disconnects {
+        let ago = now.duration_since(disconnect.at).unwrap_or_default();
+        writeln!(
+            out,
+            "disconnect node={} ago={}s {} reason={}",
+            disconnect.node_id(),
+            ago.as_secs(),
+            traffic(&disconnect.metrics),
+            disconnect.reason,
+        )
+        .ok();
+    }
+    out
+}
+
+fn traffic(metrics: &ClientMetrics) -> String {
+    format!(
+        "connected_for={}s bytes_sent={} bytes_recv={} packets_sent={} packets_recv={} packets_dropped={}",
+        metrics.connected_for.as_secs(),
+        metrics.bytes_sent,
+        metrics.bytes_recv,
+        metrics.packets_sent,
+        metrics.packets_recv,
+        metrics.packets_dropped,
+    )
+}
@@ -20,10 +20,14 @@ use tokio_rustls_acme::AcmeAcceptor;
 use tokio_util::{codec::Framed, sync::CancellationToken, task::AbortOnDropHandle};
 use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument};
 
-use super::{clients::Clients, AccessConfig};
+use super::{
+    clients::Clients,
+    diagnostics::{self, ClientDisconnect, DiagnosticsConfig, DIAGNOSTICS_PATH},
+    mesh, AccessConfig, ClientMetrics, MeshKey,
+};
 use crate::{
     defaults::{timeouts::SERVER_WRITE_TIMEOUT, DEFAULT_KEY_CACHE_CAPACITY},
-    http::{Protocol, LEGACY_RELAY_PATH, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION},
+    http::{Protocol, LEGACY_RELAY_PATH, MESH_KEY_HEADER, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION},
     protos::relay::{
         recv_client_key, Frame, RelayCodec, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
     },
@@ -91,6 +95,7 @@ pub(super) struct Server {
     addr: SocketAddr,
     http_server_task: AbortOnDropHandle<()>,
     cancel_server_loop: CancellationToken,
+    clients: Clients,
 }
 
 impl Server {
@@ -101,6 +106,7 @@ impl Server {
     pub(super) fn handle(&self) -> ServerHandle {
         ServerHandle {
             cancel_token: self.cancel_server_loop.clone(),
+            clients: self.clients.clone(),
         }
     }
 
@@ -122,6 +128,11 @@ impl Server {
     pub(super) fn addr(&self) -> SocketAddr {
         self.addr
     }
+
+    /// Returns the clients connected to this server.
+    pub(super) fn clients(&self) -> &Clients {
+        &self.clients
+    }
 }
 
 /// A handle for the [`Server`].
@@ -130,6 +141,7 @@ impl Server {
 #[derive(Debug, Clone)]
 pub(super) struct ServerHandle {
     cancel_token: CancellationToken,
+    clients: Clients,
 }
 
 impl ServerHandle {
@@ -137,6 +149,16 @@ impl ServerHandle {
     pub(super) fn shutdown(&self) {
         self.cancel_token.cancel()
     }
+
+    /// Returns the traffic of each connected client.
+    pub(super) fn client_metrics(&self) -> Vec<ClientMetrics> {
+        self.clients.metrics()
+    }
+
+    /// Returns the most recently disconnected clients, oldest first.
+    pub(super) fn recent_disconnects(&self) -> Vec<ClientDisconnect> {
+        self.clients.recent_disconnects()
+    }
 }
 
 /// Configuration to use for the TLS connection
@@ -176,6 +198,10 @@ pub(super) struct ServerBuilder {
     key_cache_capacity: usize,
     /// Access config for nodes.
     access: AccessConfig,
+    /// The key connections from peers of the mesh need to present.
+    mesh_key: Option<MeshKey>,
+    /// Configuration of the diagnostics endpoint, disabled if `None`.
+    diagnostics: Option<DiagnosticsConfig>,
 }
 
 impl ServerBuilder {
@@ -189,6 +215,8 @@ impl ServerBuilder {
             client_rx_ratelimit: None,
             key_cache_capacity: DEFAULT_KEY_CACHE_CAPACITY,
             access: AccessConfig::Everyone,
+            mesh_key: None,
+            diagnostics: None,
         }
     }
 
@@ -198,6 +226,18 @@ impl ServerBuilder {
         self
     }
 
+    /// Accepts connections from peers of the mesh presenting this key.
+    pub(super) fn mesh_key(mut self, key: Option<MeshKey>) -> Self {
+        self.mesh_key = key;
+        self
+    }
+
+    /// Serves the diagnostics endpoint, if configured.
+    pub(super) fn diagnostics(mut self, config: Option<DiagnosticsConfig>) -> Self {
+        self.diagnostics = config;
+        self
+    }
+
     /// Serves all requests content using TLS.
     pub(super) fn tls_config(mut self, config: Option<TlsConfig>) -> Self {
         self.tls_config = config;
@@ -248,8 +288,11 @@ impl ServerBuilder {
             self.client_rx_ratelimit,
             KeyCache::new(self.key_cache_capacity),
             self.access,
+            self.mesh_key,
+            self.diagnostics,
         );
 
+        let clients = service.0.clients.clone();
         let addr = self.addr;
         let tls_config = self.tls_config;
 
@@ -310,6 +353,7 @@ impl ServerBuilder {
             addr,
             http_server_task: AbortOnDropHandle::new(task),
             cancel_server_loop: cancel_token,
+            clients,
         })
     }
 }
@@ -327,6 +371,8 @@ struct Inner {
     rate_limit: Option<ClientRateLimit>,
     key_cache: KeyCache,
     access: AccessConfig,
+    mesh_key: Option<MeshKey>,
+    diagnostics: Option<DiagnosticsConfig>,
 }
 
 impl RelayService {
@@ -385,7 +431,23 @@ impl RelayService {
                     None
                 };
 
-                debug!(?protocol, "upgrading connection");
+                // Connections presenting the mesh key come from peers of the mesh.
+                let mesh = match req.headers().get(MESH_KEY_HEADER) {
+                    None => false,
+                    Some(value) => {
+                        let key = value.to_str().ok().and_then(|v| v.parse::<MeshKey>().ok());
+                        if key.is_none() || key != this.0.mesh_key {
+                            warn!("invalid mesh key");
+                            return Ok(builder
+                                .status(StatusCode::FORBIDDEN)
+                                .body(body_empty())
+                                .expect("valid body"));
+                        }
+                        true
+                    }
+                };
+
+                debug!(?protocol, mesh, "upgrading connection");
 
                 // Setup a future that will eventually receive the upgraded
                 // connection and talk a new protocol, and spawn the future
@@ -398,8 +460,10 @@ impl RelayService {
                     async move {
                         match hyper::upgrade::on(&mut req).await {
                             Ok(upgraded) => {
-                                if let Err(err) =
-                                    this.0.relay_connection_handler(protocol, upgraded).await
+                                if let Err(err) = this
+                                    .0
+                                    .relay_connection_handler(protocol, mesh, upgraded)
+                                    .await
                                 {
                                     warn!(
                                         ?protocol,
@@ -452,6 +516,18 @@ impl Service<Request<Incoming>> for RelayService {
         }
         // Otherwise handle the relay connection as normal.
 
+        if let Some(ref config) = self.0.diagnostics {
+            if matches!(
+                (req.method(), req.uri().path()),
+                (&hyper::Method::GET, DIAGNOSTICS_PATH)
+            ) {
+                let res =
+                    diagnostics::handle(config, &self.0.clients, &req, self.0.default_response())
+                        .map_err(Into::into);
+                return Box::pin(async move { res });
+            }
+        }
+
         // Check all other possible endpoints.
         let uri = req.uri().clone();
         if let Some(res) = self.0.handlers.get(&(req.method().clone(), uri.path())) {
@@ -491,7 +567,12 @@ impl Inner {
     /// This handler runs while doing the connection upgrade handshake.  Once the connection
     /// is upgraded it sends the stream to the relay server which takes it over.  After
     /// having sent off the connection this handler returns.
-    async fn relay_connection_handler(&self, protocol: Protocol, upgraded: Upgraded) -> Result<()> {
+    async fn relay_connection_handler(
+        &self,
+        protocol: Protocol,
+        mesh: bool,
+        upgraded: Upgraded,
+    ) -> Result<()> {
         debug!(?protocol, "relay_connection upgraded");
         let (io, read_buf) = downcast_upgrade(upgraded)?;
         ensure!(
@@ -500,7 +581,7 @@ impl Inner {
             read_buf
         );
 
-        self.accept(protocol, io).await
+        self.accept(protocol, mesh, io).await
     }
 
     /// Adds a new connection to the server and serves it.
@@ -511,9 +592,11 @@ impl Inner {
     ///
     /// The provided [`AsyncRead`] and [`AsyncWrite`] must be already connected to the connection.
     ///
+    /// Connections from peers of the mesh, with `mesh` set, are served until they close.
+    ///
     /// [`AsyncRead`]: tokio::io::AsyncRead
     /// [`AsyncWrite`]: tokio::io::AsyncWrite
-    async fn accept(&self, protocol: Protocol, io: MaybeTlsStream) -> Result<()> {
+    async fn accept(&self, protocol: Protocol, mesh: bool, io: MaybeTlsStream) -> Result<()> {
         trace!(?protocol, "accept: start");
         let mut io = match protocol {
             Protocol::Relay => {
@@ -536,6 +619,17 @@ impl Inner {
             .await
             .context("unable to receive client information")?;
 
+        if mesh {
+            ensure!(
+                info.version == PROTOCOL_VERSION,
+                "unexpected mesh peer version {}, expected {}",
+                info.version,
+                PROTOCOL_VERSION
+            );
+            debug!(peer = client_key.fmt_short(), "serving mesh peer");
+            return mesh::serve_peer(io, self.clients.clone()).await;
+        }
+
         trace!("accept: checking access: {:?}", self.access);
         if !self.access.is_allowed(client_key).await {
             io.send(Frame::Health {
@@ -591,6 +685,8 @@ impl RelayService {
         rate_limit: Option<ClientRateLimit>,
         key_cache: KeyCache,
         access: AccessConfig,
+        mesh_key: Option<MeshKey>,
+        diagnostics: Option<DiagnosticsConfig>,
     ) -> Self {
         Self(Arc::new(Inner {
             handlers,
@@ -600,6 +696,8 @@ impl RelayService {
             rate_limit,
             key_cache,
             access,
+            mesh_key,
+            diagnostics,
         }))
     }
 
@@ -725,12 +823,17 @@ mod tests {
         client::{
             conn::{Conn, ReceivedMessage, SendMessage},
             streams::MaybeTlsStreamChained,
-            Client, ClientBuilder,
+            Client, ClientBuilder, ClientTlsConfig,
         },
         dns::DnsResolver,
     };
 
     pub(crate) fn make_tls_config() -> TlsConfig {
+        make_tls_config_with_cert().0
+    }
+
+    /// Creates a TLS config with a self-signed certificate, returning the certificate too.
+    fn make_tls_config_with_cert() -> (TlsConfig, rustls::pki_types::CertificateDer<'static>) {
         let subject_alt_names = vec!["localhost".to_string()];
 
         let cert = rcgen::generate_simple_self_signed(subject_alt_names).unwrap();
@@ -742,16 +845,17 @@ mod tests {
         .with_safe_default_protocol_versions()
         .expect("protocols supported by ring")
         .with_no_client_auth()
-        .with_single_cert(vec![(rustls_certificate)], rustls_key.into())
+        .with_single_cert(vec![rustls_certificate.clone()], rustls_key.into())
         .expect("cert is right");
 
         let config = Arc::new(config);
         let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
 
-        TlsConfig {
+        let tls_config = TlsConfig {
             config,
             acceptor: TlsAcceptor::Manual(acceptor),
-        }
+        };
+        (tls_config, rustls_certificate)
     }
 
     #[tokio::test]
@@ -825,6 +929,40 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_https_pinned_cert() -> Result<()> {
+        let (tls_config, cert) = make_tls_config_with_cert();
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(tls_config))
+            .spawn()
+            .await?;
+        let url: Url = format!("https://localhost:{}", server.addr().port()).parse()?;
+        let connect = |tls_config| {
+            let builder = ClientBuilder::new(
+                url.clone(),
+                SecretKey::generate(rand::thread_rng()),
+                DnsResolver::new(),
+            )
+            .tls_config(tls_config);
+            async move { builder.connect().await }
+        };
+
+        // The self-signed certificate is not trusted by default.
+        assert!(connect(ClientTlsConfig::WebPki).await.is_err());
+
+        // Only the pinned certificate is trusted.
+        let other_cert = make_tls_config_with_cert().1;
+        let pinned = ClientTlsConfig::Pinned(Arc::new(vec![other_cert]));
+        assert!(connect(pinned).await.is_err());
+        let pinned = ClientTlsConfig::Pinned(Arc::new(vec![cert]));
+        let mut client = connect(pinned).await?;
+
+        client.close().await?;
+        server.shutdown();
+        Ok(())
+    }
+
     async fn create_test_client(key: SecretKey, server_url: Url) -> Result<(PublicKey, Client)> {
         let public_key = key.public();
         let client =
@@ -952,6 +1090,8 @@ mod tests {
             None,
             KeyCache::test(),
             AccessConfig::Everyone,
+            None,
+            None,
         );
 
         info!("Create client A and connect it to the server.");
@@ -960,7 +1100,7 @@ mod tests {
         let (client_a, rw_a) = tokio::io::duplex(10);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_a))
+            s.0.accept(Protocol::Relay, false, MaybeTlsStream::Test(rw_a))
                 .await
         });
         let mut client_a = make_test_client(client_a, &key_a).await?;
@@ -972,7 +1112,7 @@ mod tests {
         let (client_b, rw_b) = tokio::io::duplex(10);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_b))
+            s.0.accept(Protocol::Relay, false, MaybeTlsStream::Test(rw_b))
                 .await
         });
         let mut client_b = make_test_client(client_b, &key_b).await?;
@@ -1039,6 +1179,8 @@ mod tests {
             None,
             KeyCache::test(),
             AccessConfig::Everyone,
+            None,
+            None,
         );
 
         info!("Create client A and connect it to the server.");
@@ -1047,7 +1189,7 @@ mod tests {
         let (client_a, rw_a) = tokio::io::duplex(10);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_a))
+            s.0.accept(Protocol::Relay, false, MaybeTlsStream::Test(rw_a))
                 .await
         });
         let mut client_a = make_test_client(client_a, &key_a).await?;
@@ -1059,7 +1201,7 @@ mod tests {
         let (client_b, rw_b) = tokio::io::duplex(10);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_b))
+            s.0.accept(Protocol::Relay, false, MaybeTlsStream::Test(rw_b))
                 .await
         });
         let mut client_b = make_test_client(client_b, &key_b).await?;
@@ -1105,7 +1247,7 @@ mod tests {
         let (new_client_b, new_rw_b) = tokio::io::duplex(10);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(new_rw_b))
+            s.0.accept(Protocol::Relay, false, MaybeTlsStream::Test(new_rw_b))
                 .await
         });
         let mut new_client_b = make_test_client(new_client_b, &key_b).await?;
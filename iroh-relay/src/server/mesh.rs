@@ -0,0 +1,242 @@
+//! Meshing of relay servers.
+//!
+//! A client only receives packets from the relay servers it is connected to.  Relay servers
+//! in a mesh forward packets for clients connected to another server of the mesh, so two
+//! nodes homed on different servers can reach each other without either of them connecting
+//! to the other's home relay.
+//!
+//! Each server connects to all its peers using the relay protocol, authenticated using the
+//! [`MeshKey`] shared by the mesh.  On this connection the peer announces the clients
+//! connected to it with `FrameType::PeerPresent` and `FrameType::PeerGone`, and the server
+//! forwards the packets for these clients to the peer using `FrameType::ForwardPacket`.
+//!
+//! Forwarded packets are only delivered to clients connected to the receiving server, they
+//! are never forwarded again.  So outdated routes can not make packets loop in the mesh.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use data_encoding::HEXLOWER;
+use iroh_base::{RelayUrl, SecretKey};
+use iroh_metrics::inc;
+use n0_future::{
+    time::{self, Duration},
+    StreamExt,
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use super::{clients::Clients, metrics::Metrics, streams::RelayedStream};
+use crate::{
+    client::ClientBuilder,
+    defaults::timeouts::SERVER_WRITE_TIMEOUT,
+    dns::DnsResolver,
+    protos::relay::{write_frame, Frame, PER_CLIENT_SEND_QUEUE_DEPTH},
+};
+
+/// The number of frames queued for a mesh connection before frames are dropped.
+pub(super) const MESH_QUEUE_DEPTH: usize = PER_CLIENT_SEND_QUEUE_DEPTH;
+
+/// The delay before reconnecting to a peer, doubled for every failed attempt.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum delay before reconnecting to a peer.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// The key shared by all relay servers in a mesh.
+///
+/// Only connections presenting this key are treated as coming from a peer of the mesh.
+#[derive(Clone)]
+pub struct MeshKey([u8; 32]);
+
+impl MeshKey {
+    /// Generates a new random key.
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    /// Creates a key from its bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the bytes of the key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+/// Compares in constant time, to not leak the key to peers guessing it.
+impl PartialEq for MeshKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Eq for MeshKey {}
+
+impl fmt::Debug for MeshKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MeshKey(..)")
+    }
+}
+
+/// Formats the key as hex, which is parsed by [`MeshKey::from_str`].
+impl fmt::Display for MeshKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", HEXLOWER.encode(&self.0))
+    }
+}
+
+impl FromStr for MeshKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = HEXLOWER
+            .decode(s.trim().to_ascii_lowercase().as_bytes())
+            .context("invalid hex")?;
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("mesh key must be 32 bytes"))?;
+        Ok(Self(bytes))
+    }
+}
+
+/// Configuration for meshing a relay server with other relay servers.
+///
+/// Every server of the mesh needs to list all the other servers as its peers: a server only
+/// learns about the clients of the servers it connects to.
+#[derive(Debug, Clone)]
+pub struct MeshConfig {
+    /// The key shared by all relay servers in the mesh.
+    pub key: MeshKey,
+    /// The URLs of the other relay servers in the mesh.
+    pub peers: Vec<RelayUrl>,
+}
+
+/// Serves a connection from a peer of the mesh.
+///
+/// Announces the clients connected to this server to the peer and delivers the packets it
+/// forwards.  Returns once the connection is closed.
+pub(super) async fn serve_peer(mut io: RelayedStream, clients: Clients) -> Result<()> {
+    let (watcher_id, mut updates) = clients.add_mesh_watcher();
+    let res = async {
+        loop {
+            tokio::select! {
+                update = updates.recv() => {
+                    // The watcher is removed once the server shuts down or the peer falls
+                    // too far behind, the peer reconnects to get all clients again.
+                    let Some(frame) = update else {
+                        bail!("mesh peer stopped being updated");
+                    };
+                    write_frame(&mut io, frame, Some(SERVER_WRITE_TIMEOUT)).await?;
+                }
+                frame = io.next() => {
+                    let Some(frame) = frame else {
+                        return Ok(());
+                    };
+                    match frame? {
+                        Frame::ForwardPacket { src_key, dst_key, packet } => {
+                            inc!(Metrics, mesh_packets_recv);
+                            if let Err(err) = clients.send_forwarded_packet(dst_key, packet, src_key) {
+                                debug!(dst = dst_key.fmt_short(), "dropped forwarded packet: {err:#}");
+                            }
+                        }
+                        Frame::Ping { data } => {
+                            write_frame(&mut io, Frame::Pong { data }, Some(SERVER_WRITE_TIMEOUT)).await?;
+                        }
+                        _ => {
+                            inc!(Metrics, unknown_frames);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    clients.remove_mesh_watcher(watcher_id);
+    res
+}
+
+/// Connects to a peer of the mesh, reconnecting whenever the connection is lost.
+///
+/// Runs until the server shuts down.
+pub(super) async fn run_client(peer: RelayUrl, key: MeshKey, clients: Clients) -> Result<()> {
+    // The peer only uses the node ID to identify the connection.
+    let secret_key = SecretKey::generate(rand::rngs::OsRng);
+    let builder = ClientBuilder::new(peer, secret_key, DnsResolver::new());
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        match builder.connect_mesh(&key).await {
+            Ok(conn) => {
+                info!("connected to mesh peer");
+                inc!(Metrics, mesh_connects);
+                delay = MIN_RECONNECT_DELAY;
+                if let Err(err) = run_connection(conn, &clients).await {
+                    warn!("mesh connection lost: {err:#}");
+                }
+            }
+            Err(err) => {
+                warn!("failed to connect to mesh peer: {err:#}");
+            }
+        }
+        time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Handles a connection to a peer, keeping the routes to its clients up to date.
+async fn run_connection(
+    mut conn: impl n0_future::Stream<Item = Result<Frame>>
+        + n0_future::Sink<Frame, Error = std::io::Error>
+        + Unpin,
+    clients: &Clients,
+) -> Result<()> {
+    let (sender, mut forwards) = mpsc::channel(MESH_QUEUE_DEPTH);
+    let peer_id = clients.add_mesh_peer(sender);
+    let res = async {
+        loop {
+            tokio::select! {
+                Some(frame) = forwards.recv() => {
+                    write_frame(&mut conn, frame, Some(SERVER_WRITE_TIMEOUT)).await?;
+                }
+                frame = conn.next() => {
+                    let Some(frame) = frame else {
+                        bail!("connection closed");
+                    };
+                    match frame? {
+                        Frame::NodePresent { node_id } => clients.add_mesh_route(node_id, peer_id),
+                        Frame::NodeGone { node_id } => clients.remove_mesh_route(node_id, peer_id),
+                        Frame::Ping { data } => {
+                            write_frame(&mut conn, Frame::Pong { data }, Some(SERVER_WRITE_TIMEOUT)).await?;
+                        }
+                        Frame::Health { problem } => {
+                            bail!("peer reported a problem: {}", String::from_utf8_lossy(&problem));
+                        }
+                        frame => {
+                            debug!(typ = ?frame.typ(), "ignoring frame from mesh peer");
+                        }
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    clients.remove_mesh_peer(peer_id);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mesh_key_roundtrip() {
+        let key = MeshKey::generate();
+        let parsed: MeshKey = key.to_string().parse().unwrap();
+        assert_eq!(parsed, key);
+        assert_eq!(format!("{key:?}"), "MeshKey(..)");
+        assert!("abcd".parse::<MeshKey>().is_err());
+    }
+}
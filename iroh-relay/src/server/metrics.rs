@@ -1,3 +1,6 @@
+use std::time::Duration;
+
+use iroh_base::NodeId;
 use iroh_metrics::{
     core::{Counter, Metric},
     struct_iterable::Iterable,
@@ -62,6 +65,18 @@ pub struct Metrics {
     pub websocket_accepts: Counter,
     /// Number of accepted 'iroh derp http' connection upgrades
     pub relay_accepts: Counter,
+
+    /*
+     * Metrics about meshing
+     */
+    /// Number of packets forwarded to a peer of the mesh
+    pub mesh_packets_forwarded: Counter,
+    /// Number of packets dropped on the way through the mesh
+    pub mesh_packets_dropped: Counter,
+    /// Number of packets received from a peer of the mesh
+    pub mesh_packets_recv: Counter,
+    /// Number of connections made to peers of the mesh
+    pub mesh_connects: Counter,
     // TODO: enable when we can have multiple connections for one node id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -113,6 +128,18 @@ impl Default for Metrics {
 
             websocket_accepts: Counter::new("Number of accepted websocket connections"),
             relay_accepts: Counter::new("Number of accepted 'iroh derp http' connection upgrades"),
+
+            /*
+             * Metrics about meshing
+             */
+            mesh_packets_forwarded: Counter::new(
+                "Number of packets forwarded to a peer of the mesh.",
+            ),
+            mesh_packets_dropped: Counter::new(
+                "Number of packets dropped on the way through the mesh.",
+            ),
+            mesh_packets_recv: Counter::new("Number of packets received from a peer of the mesh."),
+            mesh_connects: Counter::new("Number of connections made to peers of the mesh."),
             // TODO: enable when we can have multiple connections for one node id
             // pub duplicate_client_keys: Counter::new("Number of duplicate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
@@ -167,3 +194,28 @@ impl Metric for StunMetrics {
         "stun"
     }
 }
+
+/// Traffic of a single client currently connected to the relay server.
+///
+/// Unlike the [`Metrics`], which are aggregated over all clients, these are only kept while
+/// the client is connected.  See [`Server::client_metrics`].
+///
+/// [`Server::client_metrics`]: crate::server::Server::client_metrics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientMetrics {
+    /// The node ID of the client.
+    pub node_id: NodeId,
+    /// How long the client has been connected.
+    pub connected_for: Duration,
+    /// Bytes relayed to the client.
+    pub bytes_sent: u64,
+    /// Bytes received from the client to relay to others.
+    pub bytes_recv: u64,
+    /// Packets, including disco packets, relayed to the client.
+    pub packets_sent: u64,
+    /// Packets, including disco packets, received from the client to relay to others.
+    pub packets_recv: u64,
+    /// Packets for the client which were dropped, because its queue was full or writing
+    /// to it failed.
+    pub packets_dropped: u64,
+}
@@ -11,6 +11,7 @@ use super::{
 pub fn stun_config() -> StunConfig {
     StunConfig {
         bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+        share_relay_addr: false,
     }
 }
 
@@ -70,6 +71,8 @@ pub fn relay_config() -> RelayConfig<()> {
         limits: Default::default(),
         key_cache_capacity: Some(1024),
         access: AccessConfig::Everyone,
+        mesh: None,
+        diagnostics: None,
     }
 }
 
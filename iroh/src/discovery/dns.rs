@@ -28,11 +28,18 @@ const DNS_STAGGERING_MS: &[u64] = &[200, 300];
 /// If a TXT record contains multiple character strings, they are concatenated first.
 /// The supported attributes are:
 /// * `relay=<url>`: The URL of the home relay server of the node
+/// * `addr=<ip:port>`: A direct address of the node, one record per address
+/// * `user-data=<data>`: The [`UserData`] of the node
 ///
 /// The DNS resolver defaults to using the nameservers configured on the host system, but can be changed
 /// with [`crate::endpoint::Builder::dns_resolver`].
 ///
+/// To publish the records of the local node, use a [`PkarrPublisher`] pointed at the pkarr
+/// relay of a DNS server serving the origin domain, like `iroh-dns-server`.
+///
 /// [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
+/// [`UserData`]: crate::discovery::UserData
+/// [`PkarrPublisher`]: crate::discovery::pkarr::PkarrPublisher
 #[derive(Debug)]
 pub struct DnsDiscovery {
     origin_domain: String,
@@ -18,15 +18,20 @@ use std::{
     net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
     pin::Pin,
     sync::Arc,
-    task::Poll,
+    task::{ready, Poll},
 };
 
 use anyhow::{bail, Context, Result};
 use data_encoding::BASE32_DNSSEC;
 use ed25519_dalek::{pkcs8::DecodePublicKey, VerifyingKey};
 use iroh_base::{NodeAddr, NodeId, RelayUrl, SecretKey};
-use iroh_relay::RelayMap;
-use n0_future::{time::Duration, Stream};
+use iroh_metrics::inc;
+use iroh_relay::{RelayMap, RelayNode};
+use n0_future::{
+    boxed::BoxFuture,
+    time::{self, Duration, Sleep},
+    Stream,
+};
 use pin_project::pin_project;
 use tracing::{debug, instrument, trace, warn};
 use url::Url;
@@ -34,27 +39,35 @@ use url::Url;
 #[cfg(wasm_browser)]
 use crate::discovery::pkarr::PkarrResolver;
 #[cfg(not(wasm_browser))]
-use crate::{discovery::dns::DnsDiscovery, dns::DnsResolver};
+use crate::{discovery::dns::DnsDiscovery, dns::DnsResolver, magicsock::RecvLimits};
 use crate::{
     discovery::{
         pkarr::PkarrPublisher, ConcurrentDiscovery, Discovery, DiscoveryItem, DiscoverySubscribers,
         DiscoveryTask, Lagged, UserData,
     },
-    magicsock::{self, Handle, NodeIdMappedAddr},
+    magicsock::{
+        self, Handle, NetReportSchedule, NodeIdMappedAddr, RelayKeepalive, RelayReconnect,
+        SendPacing, SendRateLimit,
+    },
+    metrics::MagicsockMetrics,
+    net_report::ProbeLimits,
     tls,
     watchable::Watcher,
     RelayProtocol,
 };
 
+mod accept_policy;
+mod connection_limits;
+mod connection_pool;
 mod rtt_actor;
 
-// Missing still: SendDatagram and ConnectionClose::frame_type's Type.
+// Missing still: ConnectionClose::frame_type's Type.
 pub use quinn::{
     AcceptBi, AcceptUni, AckFrequencyConfig, ApplicationClose, Chunk, ClosedStream,
     ConnectionClose, ConnectionError, ConnectionStats, MtuDiscoveryConfig, OpenBi, OpenUni,
     ReadDatagram, ReadError, ReadExactError, ReadToEndError, RecvStream, ResetError, RetryError,
-    SendDatagramError, SendStream, ServerConfig, StoppedError, StreamId, TransportConfig, VarInt,
-    WeakConnectionHandle, WriteError,
+    SendDatagram, SendDatagramError, SendStream, ServerConfig, StoppedError, StreamId,
+    TransportConfig, VarInt, WeakConnectionHandle, WriteError,
 };
 pub use quinn_proto::{
     congestion::{Controller, ControllerFactory},
@@ -66,9 +79,31 @@ pub use quinn_proto::{
 };
 
 use self::rtt_actor::RttMessage;
+pub use self::{
+    accept_policy::{AcceptPolicy, ACCEPT_DENIED_CODE},
+    connection_limits::{AddressValidation, ConnectionLimits},
+    connection_pool::ConnectionPool,
+    rtt_actor::ConnectionEvent,
+};
+#[cfg(not(wasm_browser))]
+pub use super::magicsock::{PortMappingConfig, PortMappingEvent, PortMappingStatus};
+#[cfg(all(not(wasm_browser), any(test, feature = "test-utils")))]
+pub use quinn::udp::{EcnCodepoint, RecvMeta};
+
+#[cfg(not(wasm_browser))]
+pub use super::magicsock::TurnServer;
 pub use super::magicsock::{
-    ConnectionType, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType, RemoteInfo, Source,
+    AddrFilter, AddrPolicy, ConnectionType, ConnectionTypeInfo, ControlMsg, DirectAddr,
+    DirectAddrInfo, DirectAddrType, DiscoConfig, DiscoPathInfo, DiscoTrust, HolepunchEvent,
+    PacketFilter, PathQuality, PathTraffic, PingSource, PinnedRelays, RelayEvent, RelayProbe,
+    RelaySelector, RelayUrlInfo, RemoteInfo, Source, TrustedNodes,
 };
+pub use crate::net_report::{
+    Connectivity, Nat64Prefix, NatFiltering, NatMapping, PortAllocation, PreferredRelayReason,
+    PublicAddr, ReportChange,
+};
+#[cfg(not(wasm_browser))]
+pub use crate::net_report::{StunOverflowPolicy, StunServer, StunServerParseError, StunServers};
 
 /// The delay to fall back to discovery when direct addresses fail.
 ///
@@ -77,6 +112,14 @@ pub use super::magicsock::{
 /// is still no connection the configured [`Discovery`] will be used however.
 const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 
+/// How long endpoints without relay servers try to reach a node on its direct addresses.
+///
+/// See [`Builder::direct_only`].
+const DIRECT_ONLY_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The shortest period of [`Connection::stats_stream`].
+const MIN_STATS_PERIOD: Duration = Duration::from_millis(1);
+
 /// Maximum amount of TLS tickets we will cache (by default) for 0-RTT connection
 /// establishment.
 ///
@@ -87,6 +130,14 @@ const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 /// I think 150KB is an acceptable default upper limit for such a cache.
 const MAX_TLS_TICKETS: usize = 8 * 32;
 
+/// The largest UDP payload size MTU discovery searches up to by default, as in quinn.
+#[cfg(not(wasm_browser))]
+const DEFAULT_MTU_UPPER_BOUND: u16 = 1452;
+
+/// The smallest UDP payload size allowed by QUIC.
+#[cfg(not(wasm_browser))]
+const MIN_UDP_PAYLOAD_SIZE: u16 = 1200;
+
 type DiscoveryBuilder = Box<dyn FnOnce(&SecretKey) -> Option<Box<dyn Discovery>> + Send + Sync>;
 
 /// Defines the mode of path selection for all traffic flowing through
@@ -101,6 +152,46 @@ pub enum PathSelection {
     RelayOnly,
 }
 
+/// The congestion controller used by QUIC connections.
+///
+/// Loss-based controllers like Cubic and NewReno back off on every lost packet, which
+/// throttles connections on lossy or jittery paths, e.g. via a relay server.  BBR instead
+/// estimates the bottleneck bandwidth and the round trip time of the path.
+///
+/// See [`Builder::congestion_control`].  For individual connections apply it to the
+/// transport config passed to [`ConnectOptions::with_transport_config`] or
+/// [`Incoming::accept_with_transport_config`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CongestionControl {
+    /// CUBIC, see [RFC 8312](https://www.rfc-editor.org/rfc/rfc8312).  This is the default.
+    #[default]
+    Cubic,
+    /// NewReno, see [RFC 6582](https://www.rfc-editor.org/rfc/rfc6582).
+    NewReno,
+    /// BBR, experimental.
+    Bbr,
+}
+
+impl CongestionControl {
+    /// Sets this congestion controller, with its default configuration, on the transport config.
+    pub fn apply(self, transport_config: &mut TransportConfig) {
+        use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+
+        match self {
+            Self::Cubic => {
+                transport_config.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            Self::NewReno => {
+                transport_config.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            Self::Bbr => {
+                transport_config.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+    }
+}
+
 /// Builder for [`Endpoint`].
 ///
 /// By default the endpoint will generate a new random [`SecretKey`], which will result in a
@@ -111,18 +202,54 @@ pub enum PathSelection {
 pub struct Builder {
     secret_key: Option<SecretKey>,
     relay_mode: RelayMode,
+    direct_only: bool,
     relay_protocol: iroh_relay::http::Protocol,
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: quinn::TransportConfig,
+    max_udp_payload_size: Option<u16>,
+    congestion_control: Option<CongestionControl>,
+    keep_alive_interval: Option<Option<Duration>>,
+    max_idle_timeout: Option<Option<Duration>>,
+    handshake_timeout: Option<Duration>,
     keylog: bool,
     #[debug(skip)]
     discovery: Vec<DiscoveryBuilder>,
     discovery_user_data: Option<UserData>,
     proxy_url: Option<Url>,
+    #[cfg(not(wasm_browser))]
+    relay_tls_config: iroh_relay::client::ClientTlsConfig,
     /// List of known nodes. See [`Builder::known_nodes`].
     node_map: Option<Vec<NodeAddr>>,
     #[cfg(not(wasm_browser))]
     dns_resolver: Option<DnsResolver>,
+    #[cfg(not(wasm_browser))]
+    packet_filter: Option<Arc<dyn PacketFilter>>,
+    #[cfg(not(wasm_browser))]
+    recv_limits: RecvLimits,
+    #[cfg(not(wasm_browser))]
+    stun_servers: StunServers,
+    #[cfg(not(wasm_browser))]
+    stun_overflow_policy: StunOverflowPolicy,
+    #[cfg(not(wasm_browser))]
+    turn_server: Option<TurnServer>,
+    #[cfg(not(wasm_browser))]
+    port_mapping: PortMappingConfig,
+    send_rate_limit: Option<SendRateLimit>,
+    send_pacing: Option<SendPacing>,
+    disco_config: DiscoConfig,
+    relay_reconnect: RelayReconnect,
+    relay_keepalive: RelayKeepalive,
+    relay_standby: usize,
+    net_report_schedule: NetReportSchedule,
+    net_report_limits: ProbeLimits,
+    relay_selector: Option<Arc<dyn RelaySelector>>,
+    port_prediction: Option<usize>,
+    addr_filter: Option<Arc<dyn AddrFilter>>,
+    disco_trust: Option<Arc<dyn DiscoTrust>>,
+    accept_policy: Option<Arc<dyn AcceptPolicy>>,
+    connection_limits: Option<ConnectionLimits>,
+    address_validation: AddressValidation,
+    recv_packet_budget: Option<usize>,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_relay_cert_verify: bool,
     addr_v4: Option<SocketAddrV4>,
@@ -139,16 +266,52 @@ impl Default for Builder {
         Self {
             secret_key: Default::default(),
             relay_mode: default_relay_mode(),
+            direct_only: false,
             relay_protocol: iroh_relay::http::Protocol::default(),
             alpn_protocols: Default::default(),
             transport_config,
+            max_udp_payload_size: None,
+            congestion_control: None,
+            keep_alive_interval: None,
+            max_idle_timeout: None,
+            handshake_timeout: None,
             keylog: Default::default(),
             discovery: Default::default(),
             discovery_user_data: Default::default(),
             proxy_url: None,
+            #[cfg(not(wasm_browser))]
+            relay_tls_config: Default::default(),
             node_map: None,
             #[cfg(not(wasm_browser))]
             dns_resolver: None,
+            #[cfg(not(wasm_browser))]
+            packet_filter: None,
+            #[cfg(not(wasm_browser))]
+            recv_limits: Default::default(),
+            #[cfg(not(wasm_browser))]
+            stun_servers: Default::default(),
+            #[cfg(not(wasm_browser))]
+            stun_overflow_policy: Default::default(),
+            #[cfg(not(wasm_browser))]
+            turn_server: None,
+            #[cfg(not(wasm_browser))]
+            port_mapping: Default::default(),
+            send_rate_limit: None,
+            send_pacing: None,
+            disco_config: Default::default(),
+            relay_reconnect: Default::default(),
+            relay_keepalive: Default::default(),
+            relay_standby: 0,
+            net_report_schedule: Default::default(),
+            net_report_limits: Default::default(),
+            relay_selector: None,
+            port_prediction: None,
+            addr_filter: None,
+            disco_trust: None,
+            accept_policy: None,
+            connection_limits: None,
+            address_validation: Default::default(),
+            recv_packet_budget: None,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
             addr_v4: None,
@@ -168,15 +331,66 @@ impl Builder {
 
     /// Binds the magic endpoint.
     pub async fn bind(self) -> Result<Endpoint> {
-        let relay_map = self.relay_mode.relay_map();
+        let relay_map = match self.direct_only {
+            true => RelayMap::empty(),
+            false => self.relay_mode.relay_map(),
+        };
+        let node_map = match self.direct_only {
+            true => self.node_map.map(|nodes| {
+                nodes
+                    .into_iter()
+                    .map(|node| NodeAddr {
+                        relay_url: None,
+                        ..node
+                    })
+                    .collect()
+            }),
+            false => self.node_map,
+        };
         let secret_key = self
             .secret_key
             .unwrap_or_else(|| SecretKey::generate(rand::rngs::OsRng));
+        let mut transport_config = self.transport_config;
+        let mtu_upper_bound = self.max_udp_payload_size;
+        // Datagrams through the TURN server are wrapped in Send indications, which have to
+        // fit into the MTU of the path to the server as well.
+        #[cfg(not(wasm_browser))]
+        let mtu_upper_bound = match self.turn_server {
+            Some(_) => Some(
+                mtu_upper_bound
+                    .unwrap_or(DEFAULT_MTU_UPPER_BOUND)
+                    .saturating_sub(magicsock::TURN_SEND_OVERHEAD)
+                    .max(MIN_UDP_PAYLOAD_SIZE),
+            ),
+            None => mtu_upper_bound,
+        };
+        if let Some(size) = mtu_upper_bound {
+            let mut mtu_discovery_config = MtuDiscoveryConfig::default();
+            mtu_discovery_config.upper_bound(size);
+            transport_config.mtu_discovery_config(Some(mtu_discovery_config));
+        }
+        if let Some(congestion_control) = self.congestion_control {
+            congestion_control.apply(&mut transport_config);
+        }
+        if let Some(interval) = self.keep_alive_interval {
+            transport_config.keep_alive_interval(interval);
+        }
+        if let Some(timeout) = self.max_idle_timeout {
+            let timeout = timeout
+                .map(quinn::IdleTimeout::try_from)
+                .transpose()
+                .context("max idle timeout too large")?;
+            transport_config.max_idle_timeout(timeout);
+        }
         let static_config = StaticConfig {
-            transport_config: Arc::new(self.transport_config),
+            transport_config: Arc::new(transport_config),
             tls_auth: self.tls_auth,
             keylog: self.keylog,
             secret_key: secret_key.clone(),
+            accept_policy: self.accept_policy,
+            connection_limits: self.connection_limits,
+            address_validation: self.address_validation,
+            handshake_timeout: self.handshake_timeout,
         };
         #[cfg(not(wasm_browser))]
         let dns_resolver = self.dns_resolver.unwrap_or_default();
@@ -198,13 +412,42 @@ impl Builder {
             secret_key,
             relay_map,
             relay_protocol: self.relay_protocol,
-            node_map: self.node_map,
+            node_map,
+            direct_only: self.direct_only,
             discovery,
             discovery_user_data: self.discovery_user_data,
             proxy_url: self.proxy_url,
             #[cfg(not(wasm_browser))]
+            relay_tls_config: self.relay_tls_config,
+            #[cfg(not(wasm_browser))]
             dns_resolver,
             server_config,
+            max_udp_payload_size: self.max_udp_payload_size,
+            #[cfg(not(wasm_browser))]
+            packet_filter: self.packet_filter,
+            send_rate_limit: self.send_rate_limit,
+            send_pacing: self.send_pacing,
+            disco_config: self.disco_config,
+            relay_reconnect: self.relay_reconnect,
+            relay_keepalive: self.relay_keepalive,
+            relay_standby: self.relay_standby,
+            net_report_schedule: self.net_report_schedule,
+            net_report_limits: self.net_report_limits,
+            relay_selector: self.relay_selector,
+            port_prediction: self.port_prediction,
+            addr_filter: self.addr_filter,
+            disco_trust: self.disco_trust,
+            recv_packet_budget: self.recv_packet_budget,
+            #[cfg(not(wasm_browser))]
+            recv_limits: self.recv_limits,
+            #[cfg(not(wasm_browser))]
+            stun_servers: self.stun_servers,
+            #[cfg(not(wasm_browser))]
+            stun_overflow_policy: self.stun_overflow_policy,
+            #[cfg(not(wasm_browser))]
+            turn_server: self.turn_server,
+            #[cfg(not(wasm_browser))]
+            port_mapping: self.port_mapping,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
             #[cfg(any(test, feature = "test-utils"))]
@@ -221,6 +464,11 @@ impl Builder {
     /// If the port specified is already in use, it will fallback to choosing a random port.
     ///
     /// By default will use `0.0.0.0:0` to bind to.
+    ///
+    /// Binding to the address of a specific local interface restricts direct traffic to
+    /// that interface, which can be used on multi-homed hosts or with VPNs to control which
+    /// network iroh uses.  Binding to a named interface, e.g. using `SO_BINDTODEVICE`, is
+    /// not supported.
     pub fn bind_addr_v4(mut self, addr: SocketAddrV4) -> Self {
         self.addr_v4.replace(addr);
         self
@@ -232,6 +480,11 @@ impl Builder {
     /// If the port specified is already in use, it will fallback to choosing a random port.
     ///
     /// By default will use `[::]:0` to bind to.
+    ///
+    /// Binding to the address of a specific local interface restricts direct traffic to
+    /// that interface, which can be used on multi-homed hosts or with VPNs to control which
+    /// network iroh uses.  Binding to a named interface, e.g. using `SO_BINDTODEVICE`, is
+    /// not supported.
     pub fn bind_addr_v6(mut self, addr: SocketAddrV6) -> Self {
         self.addr_v6.replace(addr);
         self
@@ -283,6 +536,24 @@ impl Builder {
         self
     }
 
+    /// Forbids the use of relay servers, only direct paths are used.
+    ///
+    /// No connections to relay servers are opened, neither to the ones configured with
+    /// [`Builder::relay_mode`], which is ignored, nor to the relay servers of other nodes.
+    /// Holepunching over the direct addresses of nodes still works.
+    ///
+    /// Connection attempts fail immediately with a [`NoDirectPathError`] if no direct
+    /// addresses of the remote node are known.  If none of the known addresses can be
+    /// reached, [`Endpoint::connect`] fails with a [`NoDirectPathError`] once the handshake
+    /// did not complete within 5 seconds, or the [`Builder::handshake_timeout`] if shorter.
+    ///
+    /// This is useful for deployments in local networks and to measure how often direct
+    /// connections can be established.
+    pub fn direct_only(mut self, direct_only: bool) -> Self {
+        self.direct_only = direct_only;
+        self
+    }
+
     /// Sets the protocol to use for relay connections.
     ///
     /// Options are either [`RelayProtocol::Websocket`] or [`RelayProtocol::Relay`].
@@ -475,6 +746,174 @@ impl Builder {
         self
     }
 
+    /// Sets the maximum UDP payload size this endpoint sends and accepts.
+    ///
+    /// This is the MTU of the network path minus the IP and UDP headers, e.g. 1472 bytes for
+    /// IPv4 over Ethernet.  By default payloads of up to 1472 bytes are accepted and MTU
+    /// discovery searches up to 1452 bytes, which works with both IPv4 and IPv6 over
+    /// Ethernet.  Raising this allows using jumbo frames on networks which support them,
+    /// lowering it avoids futile MTU probes on constrained links such as VPNs.
+    ///
+    /// Packets always start out at 1200 bytes, the minimum allowed by QUIC, and are only
+    /// grown once MTU discovery confirms the path supports them.  Values below 1200 or above
+    /// 65527 will make [`Builder::bind`] fail.
+    ///
+    /// This replaces any [`MtuDiscoveryConfig`] set on the [`Builder::transport_config`].
+    pub fn max_udp_payload_size(mut self, size: u16) -> Self {
+        self.max_udp_payload_size = Some(size);
+        self
+    }
+
+    /// Sets the congestion controller of the QUIC connections.
+    ///
+    /// By default [`CongestionControl::Cubic`] is used.  This replaces the congestion
+    /// controller set on the [`Builder::transport_config`].
+    pub fn congestion_control(mut self, congestion_control: CongestionControl) -> Self {
+        self.congestion_control = Some(congestion_control);
+        self
+    }
+
+    /// Sets how often keep-alive packets are sent on idle QUIC connections.
+    ///
+    /// Keep-alives stop connections from running into the [`Builder::max_idle_timeout`], and
+    /// keep the NAT mappings of their paths open.  By default they are sent every second,
+    /// `None` disables them.  This replaces the interval set on the
+    /// [`Builder::transport_config`].  For individual connections set it on the transport
+    /// config passed to [`ConnectOptions::with_transport_config`] or
+    /// [`Incoming::accept_with_transport_config`].
+    pub fn keep_alive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// Sets after how long without any traffic a QUIC connection is closed.
+    ///
+    /// The connection uses the lower of the timeouts of both peers.  By default it is 30
+    /// seconds, `None` disables the timeout, which lets connections to vanished peers stay
+    /// open forever.  Timeouts above 2^62 milliseconds will make [`Builder::bind`] fail.
+    /// This replaces the timeout set on the [`Builder::transport_config`].
+    pub fn max_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.max_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long a handshake may take before the connection attempt is abandoned.
+    ///
+    /// Incomplete handshakes are otherwise only dropped after the [`Builder::max_idle_timeout`]
+    /// passed without any packets from the remote, which a slow or hostile peer can prevent.
+    /// Connections whose handshake did not complete in time fail with
+    /// [`ConnectionError::TimedOut`] and are counted in the `connection_handshake_timeout`
+    /// metric.  The timeout starts when [`Endpoint::connect`] is called or an incoming
+    /// connection is returned from [`Endpoint::accept`], and only runs while the
+    /// [`Connecting`] or [`IncomingFuture`] is awaited.  It includes the time the
+    /// [`AcceptPolicy`] takes to decide about an incoming connection.  Disabled by default.
+    ///
+    /// This only covers QUIC handshakes.  Direct paths whose holepunching pings are never
+    /// answered use no resources beyond the pings themselves, which are abandoned after the
+    /// [`DiscoConfig`] ping timeout, and have no separate setting.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the rate at which data is sent to each remote node.
+    ///
+    /// Every remote node gets a token bucket which allows sending up to `burst` bytes at
+    /// once and refills at `bytes_per_second`.  Packets exceeding the limit are delayed
+    /// until the bucket refilled, up to a queue of 128 KiB per node.  Once this queue is
+    /// full packets are dropped, which the congestion controller of the connection treats
+    /// as packet loss.  E.g. a node serving many others can use this to stop a single one
+    /// from using all of its upload bandwidth.
+    ///
+    /// The limit applies to all connections to a node together, on both the direct and
+    /// relay paths.  Holepunching traffic is not limited.
+    ///
+    /// The `bytes_per_second` must not be zero and the `burst` must be at least 1200 bytes,
+    /// the size of the smallest QUIC datagram, otherwise [`Builder::bind`] will fail.
+    pub fn send_rate_limit_per_node(mut self, bytes_per_second: u64, burst: u64) -> Self {
+        self.send_rate_limit = Some(SendRateLimit {
+            bytes_per_second,
+            burst,
+        });
+        self
+    }
+
+    /// Paces the packets sent to each remote node over direct paths.
+    ///
+    /// QUIC sends everything its congestion window allows at once, which can overflow the
+    /// small buffers of consumer routers and cause burst loss.  With pacing only `quantum`
+    /// packets are sent back to back, the remainder are spread over the round trip time of
+    /// the path.  Pacing applies to all connections to a node together, on top of the
+    /// pacing each connection does itself.
+    ///
+    /// Packets of at most `bypass_size` bytes are never delayed, so small latency sensitive
+    /// messages are not held up behind bulk transfers, even though they may overtake
+    /// delayed packets.  Relayed packets are not paced.
+    ///
+    /// The `quantum` must be at least 1, otherwise [`Builder::bind`] will fail.  Pacing is
+    /// disabled by default.
+    pub fn send_pacing(mut self, quantum: usize, bypass_size: usize) -> Self {
+        self.send_pacing = Some(SendPacing {
+            quantum,
+            bypass_size,
+        });
+        self
+    }
+
+    /// Sets how often the direct paths to remote nodes are pinged to keep them alive.
+    ///
+    /// While a connection to a node is in use its direct path is pinged, which keeps the
+    /// NAT mappings on the path open and confirms the path still works.  A longer interval
+    /// saves battery on mobile devices, at the risk of NAT mappings expiring more often.
+    /// Pings are sent at most every 2 seconds.
+    ///
+    /// Must not be zero and smaller than the [`Builder::path_idle_timeout`], otherwise
+    /// [`Builder::bind`] will fail.  Defaults to 5 seconds.
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.disco_config.interval = interval;
+        self
+    }
+
+    /// Sets how long a direct path is used on its own without being confirmed.
+    ///
+    /// A direct path is confirmed by the replies to the keepalive pings, and by any data
+    /// received on it.  Once not confirmed for this long data is sent over the relay as
+    /// well, until the path is confirmed again.  A shorter timeout recovers sooner from a
+    /// broken path, a longer one sends less redundant traffic over the relay while the
+    /// path is idle.
+    ///
+    /// Must be larger than the [`Builder::keepalive_interval`], otherwise
+    /// [`Builder::bind`] will fail.  Defaults to 6.5 seconds.
+    pub fn path_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.disco_config.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the timing of the DISCO pings finding and maintaining direct paths.
+    ///
+    /// This replaces the [`Builder::keepalive_interval`] and [`Builder::path_idle_timeout`]
+    /// set before, see [`DiscoConfig`] for all settings.
+    ///
+    /// The keepalive interval must not be zero and smaller than the path idle timeout, and
+    /// the ping interval and timeout must not be zero, otherwise [`Builder::bind`] will fail.
+    pub fn disco_config(mut self, config: DiscoConfig) -> Self {
+        self.disco_config = config;
+        self
+    }
+
+    /// Sets the number of datagrams received before yielding to other tasks.
+    ///
+    /// Under heavy inbound traffic the endpoint could keep receiving without ever giving
+    /// other tasks running on the same thread a chance to run.  After receiving `budget`
+    /// datagrams it yields instead, and continues receiving once rescheduled.  A smaller
+    /// budget keeps other tasks more responsive at the cost of some receive throughput.
+    ///
+    /// Defaults to 1024 datagrams, a budget of `0` is treated as `1`.
+    pub fn recv_packet_budget(mut self, budget: usize) -> Self {
+        self.recv_packet_budget = Some(budget);
+        self
+    }
+
     /// Optionally sets a custom DNS resolver to use for this endpoint.
     ///
     /// The DNS resolver is used to resolve relay hostnames, and node addresses if
@@ -490,134 +929,529 @@ impl Builder {
         self
     }
 
-    /// Sets an explicit proxy url to proxy all HTTP(S) traffic through.
-    pub fn proxy_url(mut self, url: Url) -> Self {
-        self.proxy_url.replace(url);
+    /// Sets a [`PacketFilter`] for datagrams received directly over UDP.
+    ///
+    /// The filter is consulted for every received UDP datagram before iroh processes it,
+    /// allowing applications to drop traffic from unwanted sources.  Datagrams received via
+    /// a relay server are not filtered.
+    ///
+    /// Note that dropping datagrams from a node's direct addresses prevents holepunching to
+    /// that node, connections to it will then only use the relay.
+    #[cfg(not(wasm_browser))]
+    pub fn packet_filter(mut self, filter: impl PacketFilter) -> Self {
+        self.packet_filter = Some(Arc::new(filter));
         self
     }
 
-    /// Sets the proxy url from the environment, in this order:
+    /// Sets the maximum number of datagrams received from a UDP socket at once.
     ///
-    /// - `HTTP_PROXY`
-    /// - `http_proxy`
-    /// - `HTTPS_PROXY`
-    /// - `https_proxy`
-    pub fn proxy_from_env(mut self) -> Self {
-        self.proxy_url = proxy_url_from_env();
+    /// Datagrams are received in batches, with a single system call where the platform
+    /// supports it.  The batch size can only be lowered, it is capped at the platform's
+    /// maximum of [`quinn_udp::BATCH_SIZE`] datagrams.
+    ///
+    /// This does not reduce memory usage, the receive buffers are always allocated for the
+    /// maximum batch size.  Use [`Builder::max_gro_segments`] to shrink them instead.
+    #[cfg(not(wasm_browser))]
+    pub fn recv_batch_size(mut self, batch_size: usize) -> Self {
+        self.recv_limits.batch_size = Some(batch_size);
         self
     }
 
-    /// Enables saving the TLS pre-master key for connections.
+    /// Sets the maximum number of GRO segments received into a single buffer.
     ///
-    /// This key should normally remain secret but can be useful to debug networking issues
-    /// by decrypting captured traffic.
+    /// With Generic Receive Offload the kernel coalesces up to 64 datagrams into one,
+    /// which requires a receive buffer of 64 times the maximum UDP payload size for each
+    /// datagram of a batch.  Lowering this shrinks the receive buffer accordingly, which
+    /// is useful for memory constrained devices.  Coalesced datagrams which do not fit are
+    /// then copied out of a separate buffer, which costs some throughput.
     ///
-    /// If *keylog* is `true` then setting the `SSLKEYLOGFILE` environment variable to a
-    /// filename will result in this file being used to log the TLS pre-master keys.
-    pub fn keylog(mut self, keylog: bool) -> Self {
-        self.keylog = keylog;
+    /// The number of segments can only be lowered, values larger than the socket supports
+    /// have no effect.
+    #[cfg(not(wasm_browser))]
+    pub fn max_gro_segments(mut self, segments: usize) -> Self {
+        self.recv_limits.gro_segments = Some(segments);
         self
     }
 
-    /// Skip verification of SSL certificates from relay servers
+    /// Sets custom STUN servers used to discover the endpoint's public addresses.
     ///
-    /// May only be used in tests.
-    #[cfg(any(test, feature = "test-utils"))]
-    pub fn insecure_skip_relay_cert_verify(mut self, skip_verify: bool) -> Self {
-        self.insecure_skip_relay_cert_verify = skip_verify;
+    /// By default only the relay servers of the [`RelayMode`] are probed using STUN.  The
+    /// servers configured here are probed in addition, per address family, or instead of
+    /// the relay servers when [`StunServers::replace_relays`] is set.  This allows
+    /// deployments whose relay servers do not serve STUN, or which use no relay servers at
+    /// all, to still learn their public addresses.
+    ///
+    /// Custom STUN servers are never picked as the home relay.
+    #[cfg(not(wasm_browser))]
+    pub fn stun_servers(mut self, stun_servers: StunServers) -> Self {
+        self.stun_servers = stun_servers;
         self
     }
 
-    /// This implies we only use the relay to communicate
-    /// and do not attempt to do any hole punching.
-    #[cfg(any(test, feature = "test-utils"))]
-    pub fn path_selection(mut self, path_selection: PathSelection) -> Self {
-        self.path_selection = path_selection;
+    /// Sets which STUN packets to drop if net reports do not keep up with them.
+    ///
+    /// Received STUN packets are queued for the net report, which may fall behind on a busy
+    /// endpoint.  Once the queue is full either the newly received or the oldest queued
+    /// packets are dropped.  Defaults to [`StunOverflowPolicy::DropNewest`].
+    #[cfg(not(wasm_browser))]
+    pub fn stun_overflow_policy(mut self, policy: StunOverflowPolicy) -> Self {
+        self.stun_overflow_policy = policy;
         self
     }
-}
 
-/// Configuration for a [`quinn::Endpoint`] that cannot be changed at runtime.
-#[derive(Debug)]
-struct StaticConfig {
-    tls_auth: tls::Authentication,
-    secret_key: SecretKey,
-    transport_config: Arc<quinn::TransportConfig>,
-    keylog: bool,
-}
+    /// Sets a TURN server to allocate a relayed address on.
+    ///
+    /// When both nodes are behind NATs which map each destination to a different port,
+    /// holepunching fails and connections stay on the relay server.  A TURN server close to
+    /// the endpoint can provide a faster path: the relayed address allocated on it is
+    /// advertised as a [`DirectAddrType::Turn`] direct address, and used like any other
+    /// direct path if its latency is lower than the relay's.
+    ///
+    /// Only datagrams from the addresses of nodes this endpoint holepunches to are
+    /// forwarded by the TURN server.  Datagrams sent through the TURN server carry up to 51
+    /// bytes of framing, so MTU discovery searches up to 51 bytes less than the
+    /// [`Builder::max_udp_payload_size`] when a TURN server is set.  By default no TURN
+    /// server is used.
+    #[cfg(not(wasm_browser))]
+    pub fn turn_server(mut self, server: TurnServer) -> Self {
+        self.turn_server = Some(server);
+        self
+    }
 
-impl StaticConfig {
-    /// Create a [`quinn::ServerConfig`] with the specified ALPN protocols.
-    fn create_server_config(&self, alpn_protocols: Vec<Vec<u8>>) -> Result<ServerConfig> {
-        let quic_server_config =
-            self.tls_auth
-                .make_server_config(&self.secret_key, alpn_protocols, self.keylog)?;
-        let mut server_config = ServerConfig::with_crypto(Arc::new(quic_server_config));
-        server_config.transport_config(self.transport_config.clone());
+    /// Sets the protocols used to request port mappings from the NAT devices.
+    ///
+    /// The endpoint asks the gateway to forward its UDP port, so that nodes behind other
+    /// NATs can holepunch to it.  The gateway is discovered via SSDP for UPnP IGD, or
+    /// addressed directly for PCP and NAT-PMP.  The mapping is refreshed before it expires
+    /// and released when the endpoint is closed.  Many home routers only support UPnP.
+    ///
+    /// By default all protocols are enabled, e.g. UPnP can be disabled while keeping PCP
+    /// and NAT-PMP.  Disabling all of them disables port mapping, see
+    /// [`Builder::disable_port_mapping`].
+    #[cfg(not(wasm_browser))]
+    pub fn port_mapping(mut self, config: PortMappingConfig) -> Self {
+        self.port_mapping = config;
+        self
+    }
 
-        Ok(server_config)
+    /// Disables port mapping.
+    ///
+    /// The gateway is neither probed for the port mapping protocols nor asked for a
+    /// mapping, and net reports skip the port mapping probe.  Direct connections then
+    /// rely on holepunching alone.
+    #[cfg(not(wasm_browser))]
+    pub fn disable_port_mapping(mut self) -> Self {
+        self.port_mapping = PortMappingConfig::disabled();
+        self
     }
-}
 
-/// Controls an iroh node, establishing connections with other nodes.
-///
-/// This is the main API interface to create connections to, and accept connections from
-/// other iroh nodes.  The connections are peer-to-peer and encrypted, a Relay server is
-/// used to make the connections reliable.  See the [crate docs] for a more detailed
-/// overview of iroh.
-///
-/// It is recommended to only create a single instance per application.  This ensures all
-/// the connections made share the same peer-to-peer connections to other iroh nodes,
-/// while still remaining independent connections.  This will result in more optimal network
-/// behaviour.
-///
-/// The endpoint is created using the [`Builder`], which can be created using
-/// [`Endpoint::builder`].
-///
-/// Once an endpoint exists, new connections are typically created using the
-/// [`Endpoint::connect`] and [`Endpoint::accept`] methods.  Once established, the
-/// [`Connection`] gives access to most [QUIC] features.  Individual streams to send data to
-/// the peer are created using the [`Connection::open_bi`], [`Connection::accept_bi`],
-/// [`Connection::open_uni`] and [`Connection::open_bi`] functions.
-///
-/// Note that due to the light-weight properties of streams a stream will only be accepted
-/// once the initiating peer has sent some data on it.
-///
-/// [QUIC]: https://quicwg.org
-#[derive(Clone, Debug)]
-pub struct Endpoint {
-    /// Handle to the magicsocket/actor
-    msock: Handle,
-    /// Handle to the actor that resets the quinn RTT estimator
-    rtt_actor: Arc<rtt_actor::RttHandle>,
-    /// Configuration structs for quinn, holds the transport config, certificate setup, secret key etc.
-    static_config: Arc<StaticConfig>,
-    /// Cache for TLS session keys we receive.
-    session_store: Arc<dyn rustls::client::ClientSessionStore>,
-}
+    /// Sets how often net reports are run.
+    ///
+    /// Net reports probe the relay servers to find the home relay and discover the
+    /// endpoint's public addresses.  Besides the triggers configured with
+    /// [`Builder::net_report_on_network_change`] and
+    /// [`Builder::net_report_after_holepunch_failures`] they are run periodically, by default
+    /// every 20 to 26 seconds so the NAT mappings are refreshed before they commonly expire.
+    /// Setting `None` disables the periodic reports, which saves battery on mobile devices.
+    /// A report can always be requested using [`Endpoint::run_net_report`].
+    ///
+    /// Must not be zero, otherwise [`Builder::bind`] will fail.
+    pub fn net_report_interval(mut self, interval: Option<Duration>) -> Self {
+        self.net_report_schedule.interval = interval.map(|interval| interval..=interval);
+        self
+    }
 
-impl Endpoint {
-    // The ordering of public methods is reflected directly in the documentation.  This is
-    // roughly ordered by what is most commonly needed by users, but grouped in similar
-    // items.
+    /// Sets how often a full net report is run.
+    ///
+    /// Most net reports are incremental, only probing the relay servers which were the
+    /// fastest in the previous report.  A full report probes all relay servers.
+    ///
+    /// Defaults to 5 minutes.
+    pub fn net_report_full_interval(mut self, interval: Duration) -> Self {
+        self.net_report_schedule.full_interval = interval;
+        self
+    }
 
-    // # Methods relating to construction.
+    /// Sets whether a net report is run when the network changes.
+    ///
+    /// Enabled by default.
+    pub fn net_report_on_network_change(mut self, enable: bool) -> Self {
+        self.net_report_schedule.on_network_change = enable;
+        self
+    }
 
-    /// Returns the builder for an [`Endpoint`], with a production configuration.
-    pub fn builder() -> Builder {
-        Builder::default()
+    /// Sets whether the net report run after a minor network change is a fast one.
+    ///
+    /// A fast report only probes the home relay to confirm the public addresses and reuses
+    /// the other results of the previous report, which takes a single round trip to the
+    /// home relay instead of seconds.  If it finds any changes the next report is a full
+    /// one.  Major network changes, like switching networks, always run a normal report.
+    ///
+    /// Enabled by default.
+    pub fn net_report_fast_on_minor_change(mut self, enable: bool) -> Self {
+        self.net_report_schedule.fast_on_minor_change = enable;
+        self
     }
 
-    /// Creates a quinn endpoint backed by a magicsock.
+    /// Sets after how many failed hole punching pings a net report is run.
     ///
-    /// This is for internal use, the public interface is the [`Builder`] obtained from
-    /// [Self::builder]. See the methods on the builder for documentation of the parameters.
-    #[instrument("ep", skip_all, fields(me = %static_config.secret_key.public().fmt_short()))]
-    async fn bind(static_config: StaticConfig, msock_opts: magicsock::Options) -> Result<Self> {
+    /// A hole punching ping failed if no reply arrived and no direct path to the node is
+    /// known.  Repeated failures can indicate that the endpoint's public addresses changed
+    /// without the network change being detected.  The failures are counted since the last
+    /// net report.
+    ///
+    /// Must be at least 1, otherwise [`Builder::bind`] will fail.  Defaults to `None`, i.e.
+    /// hole punching failures do not trigger net reports.
+    pub fn net_report_after_holepunch_failures(mut self, failures: Option<usize>) -> Self {
+        self.net_report_schedule.holepunch_failures = failures;
+        self
+    }
+
+    /// Sets the maximum amount of time a single net report probe may take.
+    ///
+    /// A probe taking longer is considered failed.  Raise this on high-latency links, e.g.
+    /// satellite connections, where probes would otherwise never succeed.
+    ///
+    /// Must not be zero, otherwise [`Builder::bind`] will fail.  Defaults to 3 seconds.
+    pub fn net_report_probe_timeout(mut self, timeout: Duration) -> Self {
+        self.net_report_limits.probe_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum amount of time a net report may take.
+    ///
+    /// Probes still running after three fifths of this time are aborted and the report is
+    /// finished with the results gathered so far.
+    ///
+    /// Must not be zero, otherwise [`Builder::bind`] will fail.  Defaults to 5 seconds.
+    pub fn net_report_timeout(mut self, timeout: Duration) -> Self {
+        self.net_report_limits.report_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of net report probes running at the same time.
+    ///
+    /// By default all probes of a report are started at once, or as soon as their delay in
+    /// the probe plan has passed.  On constrained devices limiting this spreads out the
+    /// load, at the cost of slower reports.
+    ///
+    /// Must be at least 1, otherwise [`Builder::bind`] will fail.  Defaults to `None`, i.e.
+    /// unlimited.
+    pub fn net_report_max_concurrent_probes(mut self, max: Option<usize>) -> Self {
+        self.net_report_limits.max_concurrent_probes = max;
+        self
+    }
+
+    /// Sets how the home relay is selected from net reports.
+    ///
+    /// By default the relay with the lowest recent latency is the home relay, see
+    /// [`RelaySelector`] for the details.  [`PinnedRelays`] restricts the home relay to a
+    /// set of relays.
+    pub fn relay_selector(mut self, selector: impl RelaySelector) -> Self {
+        self.relay_selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Sets the number of predicted ports to hole punch through a symmetric NAT.
+    ///
+    /// Holepunching usually fails behind a NAT which gives every destination its own public
+    /// port, see [`NatMapping::EndpointDependent`].  Many such NATs allocate the ports
+    /// sequentially however, see [`PortAllocation::Sequential`].  With port prediction
+    /// enabled, the call-me-maybe messages sent to remote nodes contain the next `count`
+    /// ports the NAT is likely to allocate, and the remote node sends pings to each of
+    /// them.  How often this succeeds is recorded in the `port_prediction_sent` and
+    /// `port_prediction_hit` metrics.
+    ///
+    /// Must be between 1 and 16, otherwise [`Builder::bind`] will fail.  Defaults to `None`,
+    /// i.e. no port prediction.
+    pub fn port_prediction(mut self, count: Option<usize>) -> Self {
+        self.port_prediction = count;
+        self
+    }
+
+    /// Sets which direct addresses are advertised to other nodes.
+    ///
+    /// By default all direct addresses are advertised.  [`AddrPolicy`] can e.g. exclude
+    /// private and link-local addresses, see [`AddrFilter`] for the details.
+    pub fn addr_filter(mut self, filter: impl AddrFilter) -> Self {
+        self.addr_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Sets a hook deciding whether to answer the holepunching pings of unknown nodes.
+    ///
+    /// By default pings from all nodes are answered, revealing to anyone who knows our node
+    /// ID and addresses that we are online.  See [`DiscoTrust`] for the details.
+    pub fn disco_trust(mut self, trust: impl DiscoTrust) -> Self {
+        self.disco_trust = Some(Arc::new(trust));
+        self
+    }
+
+    /// Sets a policy deciding whether to accept incoming connections.
+    ///
+    /// By default all connections which complete the handshake are accepted.  The policy is
+    /// consulted with the authenticated node ID and the negotiated ALPN of each incoming
+    /// connection, see [`AcceptPolicy`] for the details.
+    pub fn accept_policy(mut self, policy: impl AcceptPolicy) -> Self {
+        self.accept_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Sets limits on the incoming connections.
+    ///
+    /// Connections exceeding the limits are refused before their handshake, protecting
+    /// publicly reachable nodes from floods of connection attempts.  By default there are
+    /// no limits, see [`ConnectionLimits`] for the details.
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = Some(limits);
+        self
+    }
+
+    /// Sets when incoming connections have to validate their address using a retry packet.
+    ///
+    /// Publicly reachable nodes can use this to resist address spoofing and amplification,
+    /// at the cost of an additional round trip for the validated connections.  Defaults to
+    /// [`AddressValidation::Never`].
+    pub fn address_validation(mut self, address_validation: AddressValidation) -> Self {
+        self.address_validation = address_validation;
+        self
+    }
+
+    /// Sets an explicit proxy url to proxy all HTTP(S) traffic through.
+    ///
+    /// Both HTTP CONNECT proxies, using the `http` or `https` scheme, and SOCKS5 proxies,
+    /// using the `socks5` or `socks5h` scheme, are supported.  Credentials in the URL are
+    /// used to authenticate to the proxy.
+    pub fn proxy_url(mut self, url: Url) -> Self {
+        self.proxy_url.replace(url);
+        self
+    }
+
+    /// Sets the proxy url from the environment, in this order:
+    ///
+    /// - `HTTP_PROXY`
+    /// - `http_proxy`
+    /// - `HTTPS_PROXY`
+    /// - `https_proxy`
+    /// - `ALL_PROXY`
+    /// - `all_proxy`
+    pub fn proxy_from_env(mut self) -> Self {
+        self.proxy_url = proxy_url_from_env();
+        self
+    }
+
+    /// Sets how the TLS certificates of relay servers are verified.
+    ///
+    /// By default the certificate authorities trusted by Mozilla are trusted.  Self-hosted
+    /// relay servers with certificates from a private CA can be used by trusting that CA, or
+    /// by pinning their certificates.
+    #[cfg(not(wasm_browser))]
+    pub fn relay_tls_config(mut self, config: iroh_relay::client::ClientTlsConfig) -> Self {
+        self.relay_tls_config = config;
+        self
+    }
+
+    /// Sets the delays between attempts to connect to a relay server.
+    ///
+    /// When connecting to a relay server fails it is retried after `initial`, the delay
+    /// doubles with each further failed attempt up to `max`.  A lost connection is
+    /// reconnected right away.  See [`Endpoint::relay_events`] to follow the attempts.
+    ///
+    /// `initial` must not be zero nor larger than `max`, otherwise [`Builder::bind`] will
+    /// fail.  Defaults to 10 milliseconds and 16 seconds.
+    pub fn relay_reconnect_delay(mut self, initial: Duration, max: Duration) -> Self {
+        self.relay_reconnect.initial_delay = initial;
+        self.relay_reconnect.max_delay = max;
+        self
+    }
+
+    /// Sets whether the delays between attempts to connect to a relay server are randomised.
+    ///
+    /// The jitter spreads out the reconnects of many nodes after a relay server restarts.
+    /// Enabled by default.
+    pub fn relay_reconnect_jitter(mut self, jitter: bool) -> Self {
+        self.relay_reconnect.jitter = jitter;
+        self
+    }
+
+    /// Sets after how many failed attempts in a row a relay server is reported unreachable.
+    ///
+    /// Once connecting to a relay server failed this many times in a row a
+    /// [`RelayEvent::Unreachable`] is emitted on [`Endpoint::relay_events`].  Connecting is
+    /// still retried afterwards.
+    ///
+    /// Must be at least 1, otherwise [`Builder::bind`] will fail.  Defaults to 10.
+    pub fn relay_reconnect_attempts(mut self, attempts: usize) -> Self {
+        self.relay_reconnect.max_attempts = attempts;
+        self
+    }
+
+    /// Sets how often relay servers are pinged to keep the connections alive.
+    ///
+    /// The pings keep the NAT mappings of the relay connections open and detect broken
+    /// connections.  A longer interval saves battery on mobile devices, at the risk of
+    /// noticing lost connections later.
+    ///
+    /// Must not be zero, otherwise [`Builder::bind`] will fail.  Defaults to 15 seconds.
+    pub fn relay_ping_interval(mut self, interval: Duration) -> Self {
+        self.relay_keepalive.ping_interval = interval;
+        self
+    }
+
+    /// Sets how long idle connections to relay servers are kept open.
+    ///
+    /// Connections to relay servers other than the home relay are opened to reach nodes
+    /// using those relay servers.  They are closed once nothing was sent over them for this
+    /// long, and opened again when needed.  The connection to the home relay is always kept
+    /// open, so that other nodes can reach this node.
+    ///
+    /// The timeout applies to all relay servers alike.  Unlike DERP regions, the entries of
+    /// the [`RelayMap`] are single relay servers, and which of them are connected to besides
+    /// the home relay depends on the remote nodes, so there is no per-region tuning to do.
+    ///
+    /// Must not be zero, otherwise [`Builder::bind`] will fail.  Defaults to 60 seconds.
+    pub fn relay_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.relay_keepalive.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the number of standby relay servers to stay connected to.
+    ///
+    /// Besides the home relay, the endpoint keeps connections open to this many of the
+    /// relay servers with the lowest latency.  When the connection to the home relay is
+    /// lost, a connected standby relay becomes the home relay straight away, instead of
+    /// waiting for the home relay to be reconnected or for the next net report.  This is
+    /// reported as a [`RelayEvent::Failover`].
+    ///
+    /// Defaults to `0`, i.e. no standby connections.
+    pub fn relay_standby(mut self, count: usize) -> Self {
+        self.relay_standby = count;
+        self
+    }
+
+    /// Enables saving the TLS pre-master key for connections.
+    ///
+    /// This key should normally remain secret but can be useful to debug networking issues
+    /// by decrypting captured traffic.
+    ///
+    /// If *keylog* is `true` then setting the `SSLKEYLOGFILE` environment variable to a
+    /// filename will result in this file being used to log the TLS pre-master keys.
+    pub fn keylog(mut self, keylog: bool) -> Self {
+        self.keylog = keylog;
+        self
+    }
+
+    /// Skip verification of SSL certificates from relay servers
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn insecure_skip_relay_cert_verify(mut self, skip_verify: bool) -> Self {
+        self.insecure_skip_relay_cert_verify = skip_verify;
+        self
+    }
+
+    /// This implies we only use the relay to communicate
+    /// and do not attempt to do any hole punching.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn path_selection(mut self, path_selection: PathSelection) -> Self {
+        self.path_selection = path_selection;
+        self
+    }
+}
+
+/// Configuration for a [`quinn::Endpoint`] that cannot be changed at runtime.
+#[derive(Debug)]
+struct StaticConfig {
+    tls_auth: tls::Authentication,
+    secret_key: SecretKey,
+    transport_config: Arc<quinn::TransportConfig>,
+    keylog: bool,
+    accept_policy: Option<Arc<dyn AcceptPolicy>>,
+    connection_limits: Option<ConnectionLimits>,
+    address_validation: AddressValidation,
+    handshake_timeout: Option<Duration>,
+}
+
+impl StaticConfig {
+    /// Create a [`quinn::ServerConfig`] with the specified ALPN protocols.
+    fn create_server_config(&self, alpn_protocols: Vec<Vec<u8>>) -> Result<ServerConfig> {
+        let quic_server_config =
+            self.tls_auth
+                .make_server_config(&self.secret_key, alpn_protocols, self.keylog)?;
+        let mut server_config = ServerConfig::with_crypto(Arc::new(quic_server_config));
+        server_config.transport_config(self.transport_config.clone());
+
+        Ok(server_config)
+    }
+}
+
+/// Controls an iroh node, establishing connections with other nodes.
+///
+/// This is the main API interface to create connections to, and accept connections from
+/// other iroh nodes.  The connections are peer-to-peer and encrypted, a Relay server is
+/// used to make the connections reliable.  See the [crate docs] for a more detailed
+/// overview of iroh.
+///
+/// It is recommended to only create a single instance per application.  This ensures all
+/// the connections made share the same peer-to-peer connections to other iroh nodes,
+/// while still remaining independent connections.  This will result in more optimal network
+/// behaviour.
+///
+/// The endpoint is created using the [`Builder`], which can be created using
+/// [`Endpoint::builder`].
+///
+/// Once an endpoint exists, new connections are typically created using the
+/// [`Endpoint::connect`] and [`Endpoint::accept`] methods.  Once established, the
+/// [`Connection`] gives access to most [QUIC] features.  Individual streams to send data to
+/// the peer are created using the [`Connection::open_bi`], [`Connection::accept_bi`],
+/// [`Connection::open_uni`] and [`Connection::open_bi`] functions.
+///
+/// Note that due to the light-weight properties of streams a stream will only be accepted
+/// once the initiating peer has sent some data on it.
+///
+/// [QUIC]: https://quicwg.org
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    /// Handle to the magicsocket/actor
+    msock: Handle,
+    /// Handle to the actor that resets the quinn RTT estimator
+    rtt_actor: Arc<rtt_actor::RttHandle>,
+    /// Configuration structs for quinn, holds the transport config, certificate setup, secret key etc.
+    static_config: Arc<StaticConfig>,
+    /// Cache for TLS session keys we receive.
+    session_store: Arc<dyn rustls::client::ClientSessionStore>,
+    /// The server config of the quinn endpoint, for accepting with a custom transport config.
+    server_config: Arc<std::sync::RwLock<ServerConfig>>,
+    /// Enforces the [`ConnectionLimits`], if any were configured.
+    connection_limiter: Option<Arc<connection_limits::ConnectionLimiter>>,
+}
+
+impl Endpoint {
+    // The ordering of public methods is reflected directly in the documentation.  This is
+    // roughly ordered by what is most commonly needed by users, but grouped in similar
+    // items.
+
+    // # Methods relating to construction.
+
+    /// Returns the builder for an [`Endpoint`], with a production configuration.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Creates a quinn endpoint backed by a magicsock.
+    ///
+    /// This is for internal use, the public interface is the [`Builder`] obtained from
+    /// [Self::builder]. See the methods on the builder for documentation of the parameters.
+    #[instrument("ep", skip_all, fields(me = %static_config.secret_key.public().fmt_short()))]
+    async fn bind(static_config: StaticConfig, msock_opts: magicsock::Options) -> Result<Self> {
+        let server_config = msock_opts.server_config.clone();
         let msock = magicsock::MagicSock::spawn(msock_opts).await?;
         trace!("created magicsock");
         debug!(version = env!("CARGO_PKG_VERSION"), "iroh Endpoint created");
 
+        let connection_limiter = static_config
+            .connection_limits
+            .map(|limits| Arc::new(connection_limits::ConnectionLimiter::new(limits)));
         let ep = Self {
             msock: msock.clone(),
             rtt_actor: Arc::new(rtt_actor::RttHandle::new()),
@@ -625,6 +1459,8 @@ impl Endpoint {
             session_store: Arc::new(rustls::client::ClientSessionMemoryCache::new(
                 MAX_TLS_TICKETS,
             )),
+            server_config: Arc::new(std::sync::RwLock::new(server_config)),
+            connection_limiter,
         };
         Ok(ep)
     }
@@ -635,6 +1471,7 @@ impl Endpoint {
     /// Note that this *overrides* the current list of ALPNs.
     pub fn set_alpns(&self, alpns: Vec<Vec<u8>>) -> Result<()> {
         let server_config = self.static_config.create_server_config(alpns)?;
+        *self.server_config.write().expect("poisoned") = server_config.clone();
         self.msock.endpoint().set_server_config(Some(server_config));
         Ok(())
     }
@@ -659,6 +1496,9 @@ impl Endpoint {
     ///
     /// If addresses or relay servers are neither provided nor can be discovered, the
     /// connection attempt will fail with an error.
+    /// If relay servers are disabled using [`Builder::direct_only`] and no direct addresses
+    /// are known, or none of them can be reached, the connection attempt fails with a
+    /// [`NoDirectPathError`].
     ///
     /// The `alpn`, or application-level protocol identifier, is also required. The remote
     /// endpoint must support this `alpn`, otherwise the connection attempt will fail with
@@ -669,9 +1509,12 @@ impl Endpoint {
         let connecting = self
             .connect_with_opts(node_addr, alpn, Default::default())
             .await?;
-        let conn = connecting
-            .await
-            .context("failed connecting to remote endpoint")?;
+        let conn = match connecting.await {
+            Err(ConnectionError::TimedOut) if self.msock.direct_only() => {
+                return Err(NoDirectPathError { node_id: remote }.into());
+            }
+            res => res.context("failed connecting to remote endpoint")?,
+        };
         debug!(
             me = %self.node_id().fmt_short(),
             remote = %remote.fmt_short(),
@@ -717,6 +1560,13 @@ impl Endpoint {
             );
         }
 
+        let node_addr = match self.msock.direct_only() {
+            true => NodeAddr {
+                relay_url: None,
+                ..node_addr
+            },
+            false => node_addr,
+        };
         if !node_addr.is_empty() {
             self.add_node_addr(node_addr.clone())?;
         }
@@ -728,15 +1578,18 @@ impl Endpoint {
         // address.  Start discovery for this node if it's enabled and we have no valid or
         // verified address information for this node.  Dropping the discovery cancels any
         // still running task.
-        let (mapped_addr, _discovery_drop_guard) = self
+        let mapping = self
             .get_mapping_addr_and_maybe_start_discovery(node_addr)
-            .await
-            .with_context(|| {
-                format!(
-                    "No addressing information for NodeId({}), unable to connect",
-                    node_id.fmt_short()
-                )
-            })?;
+            .await;
+        if self.msock.direct_only() && !(mapping.is_ok() && self.msock.has_send_address(node_id)) {
+            return Err(NoDirectPathError { node_id }.into());
+        }
+        let (mapped_addr, _discovery_drop_guard) = mapping.with_context(|| {
+            format!(
+                "No addressing information for NodeId({}), unable to connect",
+                node_id.fmt_short()
+            )
+        })?;
 
         let transport_config = options
             .transport_config
@@ -786,21 +1639,53 @@ impl Endpoint {
             ep: self.clone(),
             remote_node_id: Some(node_id),
             _discovery_drop_guard,
+            accept_policy: None,
+            authorizing: None,
+            handshake_timeout: self.connect_timeout(),
         })
     }
 
+    /// Returns the timer for a handshake starting now, see [`Builder::handshake_timeout`].
+    fn handshake_timeout(&self) -> Option<Pin<Box<Sleep>>> {
+        self.static_config
+            .handshake_timeout
+            .map(|timeout| Box::pin(time::sleep(timeout)))
+    }
+
+    /// Returns the timer for an outgoing handshake starting now.
+    ///
+    /// Without relay servers the handshake is bounded by [`DIRECT_ONLY_HANDSHAKE_TIMEOUT`],
+    /// so unreachable direct addresses fail quickly.
+    fn connect_timeout(&self) -> Option<Pin<Box<Sleep>>> {
+        let timeout = match self.msock.direct_only() {
+            true => Some(
+                self.static_config
+                    .handshake_timeout
+                    .map_or(DIRECT_ONLY_HANDSHAKE_TIMEOUT, |timeout| {
+                        timeout.min(DIRECT_ONLY_HANDSHAKE_TIMEOUT)
+                    }),
+            ),
+            false => self.static_config.handshake_timeout,
+        };
+        timeout.map(|timeout| Box::pin(time::sleep(timeout)))
+    }
+
     /// Accepts an incoming connection on the endpoint.
     ///
     /// Only connections with the ALPNs configured in [`Builder::alpns`] will be accepted.
     /// If multiple ALPNs have been configured the ALPN can be inspected before accepting
     /// the connection using [`Connecting::alpn`].
     ///
+    /// Incoming connections exceeding the [`ConnectionLimits`] are refused and those which
+    /// have to validate their address according to [`Builder::address_validation`] are sent
+    /// a retry packet, without being returned.
+    ///
     /// The returned future will yield `None` if the endpoint is closed by calling
     /// [`Endpoint::close`].
     pub fn accept(&self) -> Accept<'_> {
         Accept {
             inner: self.msock.endpoint().accept(),
-            ep: self.clone(),
+            ep: self,
         }
     }
 
@@ -863,6 +1748,29 @@ impl Endpoint {
         )
     }
 
+    /// Hints addresses on which the iroh node is known to be reachable.
+    ///
+    /// Like [`Endpoint::add_node_addr`] this stores the addresses for the node, but they
+    /// are tried before any other address while no direct path is confirmed yet.  E.g.
+    /// with the address of a node on the same LAN a connection starts on the LAN path
+    /// straight away, without waiting for holepunching or a discovery lookup.  If the
+    /// address does not work the node is reached on its other addresses and relay as
+    /// usual.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if we attempt to add our own [`NodeId`] to the node map or
+    /// if the direct addresses are a subset of ours.
+    pub fn add_peer_addr(
+        &self,
+        node_id: NodeId,
+        addrs: Vec<SocketAddr>,
+        relay_url: Option<RelayUrl>,
+    ) -> Result<()> {
+        let node_addr = NodeAddr::from_parts(node_id, relay_url, addrs);
+        self.add_node_addr_inner(node_addr, magicsock::Source::Hint)
+    }
+
     fn add_node_addr_inner(&self, node_addr: NodeAddr, source: magicsock::Source) -> Result<()> {
         // Connecting to ourselves is not supported.
         if node_addr.node_id == self.node_id() {
@@ -949,30 +1857,165 @@ impl Endpoint {
         self.msock.home_relay()
     }
 
-    /// Returns a [`Watcher`] for the direct addresses of this [`Endpoint`].
+    /// Returns a [`Watcher`] for the internet connectivity of this [`Endpoint`].
     ///
-    /// The direct addresses of the [`Endpoint`] are those that could be used by other
-    /// iroh nodes to establish direct connectivity, depending on the network
-    /// situation. The yielded lists of direct addresses contain both the locally-bound
-    /// addresses and the [`Endpoint`]'s publicly reachable addresses discovered through
-    /// mechanisms such as [STUN] and port mapping.  Hence usually only a subset of these
-    /// will be applicable to a certain remote iroh node.
+    /// The connectivity is determined by the periodic net reports, which probe the relay
+    /// servers.  When the relay servers cannot be reached over UDP, an HTTP request is used
+    /// to detect whether a captive portal, like the login page of a hotel network, is
+    /// intercepting traffic.  This allows applications to tell the user to log in, rather
+    /// than reporting that there is no internet connection.
     ///
-    /// The [`Endpoint`] continuously monitors the direct addresses for changes as its own
-    /// location in the network might change.  Whenever changes are detected this stream
-    /// will yield a new list of direct addresses.
+    /// The watcher stores `None` until the first net report finished.
+    pub fn connectivity(&self) -> Watcher<Option<Connectivity>> {
+        self.msock.connectivity()
+    }
+
+    /// Returns a [`Watcher`] for the net reports of this [`Endpoint`].
     ///
-    /// When issuing the first call to this method the first direct address discovery might
-    /// still be underway, in this case the [`Watcher`] might not be initialized with [`Some`]
-    /// value yet.  Once the first set of local direct addresses are discovered the [`Watcher`]
-    /// will always return [`Some`] set of direct addresses immediately, which are the most
-    /// recently discovered direct addresses.
+    /// The network is probed periodically and whenever a change of the network is
+    /// detected, producing a [`Report`] of e.g. the public addresses, whether UDP and IPv6
+    /// work and the latencies to the relay servers.  The watcher is updated with every new
+    /// report.  Use [`Report::changes_since`] on successive reports to react to relevant
+    /// changes only, like a lost IPv6 connection or a new public address.
     ///
-    /// # Examples
+    /// The watcher stores `None` until the first net report finished.
     ///
-    /// To get the first set of direct addresses use [`Watcher::initialized`]:
-    /// ```no_run
-    /// use futures_lite::StreamExt;
+    /// [`Report`]: crate::net_report::Report
+    /// [`Report::changes_since`]: crate::net_report::Report::changes_since
+    pub fn net_report_watcher(&self) -> Watcher<Option<Arc<crate::net_report::Report>>> {
+        self.msock.net_report()
+    }
+
+    /// Returns the public addresses of this endpoint observed by the last net report.
+    ///
+    /// Each address comes with the relay or STUN server which observed it.  Applications
+    /// can show these to users or pass them to external signalling systems.  Note that the
+    /// addresses may only be reachable from the observing server, see
+    /// [`Report::nat_mapping`].  Empty until the first net report finished.
+    ///
+    /// [`Report::nat_mapping`]: crate::net_report::Report::nat_mapping
+    pub fn public_addrs(&self) -> Vec<PublicAddr> {
+        self.msock
+            .net_report()
+            .get()
+            .ok()
+            .flatten()
+            .map(|report| report.public_addrs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the latencies to the relay servers measured recently, oldest first.
+    ///
+    /// The latencies to the relay servers are probed periodically.  The home relay, see
+    /// [`Endpoint::home_relay`], moves to another relay server once that is significantly
+    /// faster, so that small fluctuations in the latency do not make it flap.
+    ///
+    /// Only the most recent probes are kept.
+    pub fn relay_probes(&self) -> Vec<RelayProbe> {
+        self.msock.relay_probes()
+    }
+
+    /// Returns a stream of the events of the connections to relay servers.
+    ///
+    /// Events are emitted whenever a connection to a relay server is established or lost,
+    /// and for each failed attempt to (re)connect.  This can be used to show the health of
+    /// the relay connections.  How the attempts are retried is configured with
+    /// [`Builder::relay_reconnect_delay`] and related methods.  Failing over to a standby
+    /// relay, see [`Builder::relay_standby`], is reported as well.
+    ///
+    /// Only events emitted after subscribing are yielded.  If the stream is not processed
+    /// fast enough, [`Lagged`] is yielded, indicating that events were missed.
+    pub fn relay_events(&self) -> impl Stream<Item = Result<RelayEvent, Lagged>> {
+        self.msock.relay_events()
+    }
+
+    /// Returns a stream of the lifecycle events of the connections of this [`Endpoint`].
+    ///
+    /// Events are emitted when a connection is established, with the negotiated ALPN and
+    /// the initial path, whenever the path of a connection changes between the relay and
+    /// direct addresses, and when a connection was closed.
+    ///
+    /// Only events emitted after subscribing are yielded.  If the stream is not processed
+    /// fast enough, [`Lagged`] is yielded, indicating that events were missed.
+    pub fn connection_events(&self) -> impl Stream<Item = Result<ConnectionEvent, Lagged>> {
+        use n0_future::TryStreamExt;
+        use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+        let recv = self.rtt_actor.subscribe();
+        BroadcastStream::new(recv).map_err(|BroadcastStreamRecvError::Lagged(n)| Lagged(n))
+    }
+
+    /// Returns a stream of the holepunching events for the remote node.
+    ///
+    /// A holepunching attempt starts when data is sent to the node while no direct path is
+    /// confirmed.  The stream reports the candidate paths, the pings sent to them and the
+    /// first pong received, and finally whether a direct path was selected or the
+    /// connection stays on the relay.  This helps to understand why connections to some
+    /// nodes are never direct.
+    ///
+    /// Only events emitted after subscribing are yielded.  If the stream is not processed
+    /// fast enough, [`Lagged`] is yielded, indicating that events were missed.
+    pub fn holepunch_events(
+        &self,
+        node_id: NodeId,
+    ) -> impl Stream<Item = Result<HolepunchEvent, Lagged>> {
+        use n0_future::StreamExt;
+
+        self.msock
+            .holepunch_events()
+            .filter(move |event| event.as_ref().map_or(true, |ev| ev.node_id() == node_id))
+    }
+
+    /// Returns a [`Watcher`] for the status of the port mapping of this [`Endpoint`].
+    ///
+    /// The status holds the external address of the port mapping, if one is active, and
+    /// which port mapping protocols were found available on the gateway by the last net
+    /// report.  This helps to understand why direct connectivity works or not.  The port
+    /// mapper does not report which protocol created the mapping, nor when its lease
+    /// expires.
+    ///
+    /// See [`Builder::port_mapping`] for configuring the protocols used.
+    #[cfg(not(wasm_browser))]
+    pub fn port_mapping_status(&self) -> Watcher<PortMappingStatus> {
+        self.msock.port_mapping_status()
+    }
+
+    /// Returns a stream of the events of the port mapping of this [`Endpoint`].
+    ///
+    /// Events are emitted when a port mapping is acquired, when its external address
+    /// changes, and when it is lost.  Renewing the lease of an unchanged mapping emits no
+    /// event.
+    ///
+    /// Only events emitted after subscribing are yielded.  If the stream is not processed
+    /// fast enough, [`Lagged`] is yielded, indicating that events were missed.
+    #[cfg(not(wasm_browser))]
+    pub fn port_mapping_events(&self) -> impl Stream<Item = Result<PortMappingEvent, Lagged>> {
+        self.msock.port_mapping_events()
+    }
+
+    /// Returns a [`Watcher`] for the direct addresses of this [`Endpoint`].
+    ///
+    /// The direct addresses of the [`Endpoint`] are those that could be used by other
+    /// iroh nodes to establish direct connectivity, depending on the network
+    /// situation. The yielded lists of direct addresses contain both the locally-bound
+    /// addresses and the [`Endpoint`]'s publicly reachable addresses discovered through
+    /// mechanisms such as [STUN] and port mapping.  Hence usually only a subset of these
+    /// will be applicable to a certain remote iroh node.
+    ///
+    /// The [`Endpoint`] continuously monitors the direct addresses for changes as its own
+    /// location in the network might change.  Whenever changes are detected this stream
+    /// will yield a new list of direct addresses.
+    ///
+    /// When issuing the first call to this method the first direct address discovery might
+    /// still be underway, in this case the [`Watcher`] might not be initialized with [`Some`]
+    /// value yet.  Once the first set of local direct addresses are discovered the [`Watcher`]
+    /// will always return [`Some`] set of direct addresses immediately, which are the most
+    /// recently discovered direct addresses.
+    ///
+    /// # Examples
+    ///
+    /// To get the first set of direct addresses use [`Watcher::initialized`]:
+    /// ```no_run
+    /// use futures_lite::StreamExt;
     /// use iroh::Endpoint;
     ///
     /// # let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
@@ -987,6 +2030,28 @@ impl Endpoint {
         self.msock.direct_addresses()
     }
 
+    /// Adds a direct address to advertise to other nodes.
+    ///
+    /// Use this for addresses at which the endpoint is reachable but which it cannot
+    /// discover itself, e.g. a static public address whose port the operator forwards to
+    /// the endpoint, or an address learned via external signalling.  The address is
+    /// advertised as a [`DirectAddrType::Manual`] direct address until it is removed using
+    /// [`Endpoint::remove_direct_addr`].  It is subject to the [`Builder::addr_filter`].
+    ///
+    /// Returns `false` if the address was already added.
+    #[cfg(not(wasm_browser))]
+    pub fn add_direct_addr(&self, addr: SocketAddr) -> bool {
+        self.msock.add_direct_addr(addr)
+    }
+
+    /// Removes a direct address added using [`Endpoint::add_direct_addr`].
+    ///
+    /// Returns `false` if the address was not added.
+    #[cfg(not(wasm_browser))]
+    pub fn remove_direct_addr(&self, addr: SocketAddr) -> bool {
+        self.msock.remove_direct_addr(addr)
+    }
+
     /// Returns the local socket addresses on which the underlying sockets are bound.
     ///
     /// The [`Endpoint`] always binds on an IPv4 address and also tries to bind on an IPv6
@@ -1065,7 +2130,9 @@ impl Endpoint {
     /// This watcher allows observing a stream of [`ConnectionType`] items by calling
     /// [`Watcher::stream()`]. If the underlying connection to a remote node changes, it will
     /// yield a new item.  These connection changes are when the connection switches between
-    /// using the Relay server and a direct connection.
+    /// using the Relay server and a direct connection.  This also happens when the quality
+    /// of the direct path degrades too much, see [`DirectAddrInfo::quality`], in which case
+    /// the relay is used until a direct path of good quality is available again.
     ///
     /// Note that this does not guarantee each connection change is yielded in the stream.
     /// If the connection type changes several times before this stream is polled, only the
@@ -1088,6 +2155,19 @@ impl Endpoint {
         self.msock.conn_type(node_id)
     }
 
+    /// Returns a [`Watcher`] for the statistics of the DISCO traffic with the remote node.
+    ///
+    /// Besides the [`ConnectionType`] this reports when the last pong was received on each
+    /// network path, the measured round trip times and the number of holepunching attempts.
+    /// It is updated whenever pings are sent, pongs are received or pings time out.
+    ///
+    /// # Errors
+    ///
+    /// Will error if we do not have any address information for the given `node_id`.
+    pub fn conn_type_info(&self, node_id: NodeId) -> Result<Watcher<ConnectionTypeInfo>> {
+        self.msock.conn_type_info(node_id)
+    }
+
     /// Returns the DNS resolver used in this [`Endpoint`].
     ///
     /// See [`Builder::dns_resolver`].
@@ -1103,6 +2183,15 @@ impl Endpoint {
         self.msock.discovery()
     }
 
+    /// Returns the relay servers currently used by this endpoint.
+    ///
+    /// Initially these are the ones configured with [`Builder::relay_mode`], they can be
+    /// changed using [`Endpoint::set_relay_map`], [`Endpoint::insert_relay`] and
+    /// [`Endpoint::remove_relay`].
+    pub fn relay_map(&self) -> RelayMap {
+        self.msock.relay_map()
+    }
+
     // # Methods for less common state updates.
 
     /// Notifies the system of potential network changes.
@@ -1119,6 +2208,55 @@ impl Endpoint {
         self.msock.network_change().await;
     }
 
+    /// Runs a full net report.
+    ///
+    /// The report probes all relay servers, regardless of the schedule configured with
+    /// [`Builder::net_report_interval`] and related methods.  It completes in the
+    /// background, use [`Endpoint::net_report_watcher`] to get the result.
+    pub async fn run_net_report(&self) {
+        self.msock.run_net_report().await;
+    }
+
+    /// Rebinds the UDP sockets of this endpoint.
+    ///
+    /// The sockets are replaced by sockets bound to new ephemeral ports, unless a port was
+    /// set with [`Builder::bind_addr_v4`] or [`Builder::bind_addr_v6`], in which case they
+    /// are bound to it again.  After this the endpoint refreshes its direct addresses, port
+    /// mapping and TURN allocation, and re-establishes the paths to all nodes.
+    /// This can help to recover when holepunching keeps failing or the application
+    /// detected a network transition which iroh did not notice.
+    ///
+    /// Existing connections are kept, any packets in flight during the rebind may be lost.
+    pub async fn rebind(&self) {
+        self.msock.rebind("api").await;
+    }
+
+    /// Starts capturing the packets sent and received by this endpoint.
+    ///
+    /// The packets are written to `writer` in the pcapng format, which can be opened by
+    /// tools like Wireshark.  UDP datagrams are recorded with synthesised IP and UDP headers,
+    /// datagrams exchanged via a relay server on a separate interface prefixed by the remote
+    /// node ID.  Disco messages are annotated with their decrypted contents.
+    ///
+    /// Packets are written inline on the networking path, so `writer` should be buffered,
+    /// e.g. using a [`std::io::BufWriter`].  Calling this while a capture is running replaces
+    /// the running capture.  If writing fails the capture is stopped.
+    ///
+    /// The capture contains the metadata of all traffic of this endpoint, but QUIC packets
+    /// stay encrypted.
+    #[cfg(not(wasm_browser))]
+    pub fn start_packet_capture(&self, writer: impl std::io::Write + Send + 'static) -> Result<()> {
+        self.msock.start_packet_capture(Box::new(writer))
+    }
+
+    /// Stops a capture started by [`Endpoint::start_packet_capture`].
+    ///
+    /// This flushes the writer and drops it.  Does nothing if no capture is running.
+    #[cfg(not(wasm_browser))]
+    pub fn stop_packet_capture(&self) -> Result<()> {
+        self.msock.stop_packet_capture()
+    }
+
     // # Methods to update internal state.
 
     /// Sets the initial user-defined data to be published in discovery services for this node.
@@ -1132,6 +2270,38 @@ impl Endpoint {
         self.msock.set_user_data_for_discovery(user_data);
     }
 
+    /// Replaces the relay servers used by this endpoint.
+    ///
+    /// The home relay is picked again from the new relay servers.  Existing connections
+    /// keep working while this happens: connections to relay servers which are no longer
+    /// used are only closed once idle, and nodes keep being reachable over the relay
+    /// servers they are using.
+    ///
+    /// An empty [`RelayMap`] stops using a home relay.
+    ///
+    /// Has no effect if relay servers are disabled using [`Builder::direct_only`].
+    pub async fn set_relay_map(&self, relay_map: RelayMap) {
+        self.msock
+            .update_relay_map(|current| *current = relay_map)
+            .await;
+    }
+
+    /// Adds a relay server, or changes the one with the same URL.
+    ///
+    /// Returns the previous configuration of the relay server, if it was already used.  See
+    /// [`Endpoint::set_relay_map`] for how this affects existing connections.
+    pub async fn insert_relay(&self, node: RelayNode) -> Option<Arc<RelayNode>> {
+        self.msock.update_relay_map(|map| map.insert(node)).await
+    }
+
+    /// Removes a relay server.
+    ///
+    /// Returns the removed relay server, if it was used.  See [`Endpoint::set_relay_map`]
+    /// for how this affects existing connections.
+    pub async fn remove_relay(&self, url: &RelayUrl) -> Option<Arc<RelayNode>> {
+        self.msock.update_relay_map(|map| map.remove(url)).await
+    }
+
     // # Methods for terminating the endpoint.
 
     /// Closes the QUIC endpoint and the magic socket.
@@ -1167,12 +2337,21 @@ impl Endpoint {
     /// Be aware however that the underlying UDP sockets are only closed once all clones of
     /// the the respective [`Endpoint`] are dropped.
     pub async fn close(&self) {
+        self.close_with(0u32.into(), b"").await
+    }
+
+    /// Closes the QUIC endpoint and the magic socket, closing the open [`Connection`]s with
+    /// the given error code and reason.
+    ///
+    /// This works like [`Endpoint::close`], allowing the peers to tell an orderly shutdown
+    /// apart from other reasons for closing the connections.
+    pub async fn close_with(&self, error_code: VarInt, reason: &[u8]) {
         if self.is_closed() {
             return;
         }
 
         tracing::debug!("Connections closed");
-        self.msock.close().await;
+        self.msock.close(error_code, reason).await;
     }
 
     /// Check if this endpoint is still alive, or already closed.
@@ -1249,6 +2428,25 @@ impl Endpoint {
         self.msock.discovery_subscribers()
     }
 
+    /// Injects a datagram into the receive path, as if it was received on a UDP socket.
+    ///
+    /// The `payload` is described by `meta` like a received buffer: `meta.addr` is the
+    /// source and the payload may contain several datagrams spaced `meta.stride` apart.
+    /// Each datagram is classified like received ones, STUN and disco packets are processed
+    /// by the endpoint.  The returned metadata is what would be passed on to QUIC.  A `len`
+    /// of 0 means all datagrams were consumed or dropped.  QUIC packets are not delivered
+    /// to the connections.
+    ///
+    /// May only be used in tests.
+    ///
+    /// # Panics
+    ///
+    /// If `meta.len` is larger than the payload or `meta.stride` is zero.
+    #[cfg(all(not(wasm_browser), any(test, feature = "test-utils")))]
+    pub fn inject_udp_datagram(&self, meta: RecvMeta, payload: &mut [u8]) -> RecvMeta {
+        self.msock.inject_udp_datagram(meta, payload)
+    }
+
     #[cfg(test)]
     pub(crate) fn magic_sock(&self) -> Handle {
         self.msock.clone()
@@ -1259,6 +2457,23 @@ impl Endpoint {
     }
 }
 
+/// Error when connecting to a node without a known or reachable direct address.
+///
+/// Only returned by endpoints which may not use relay servers, see
+/// [`Builder::direct_only`].
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("No direct path to NodeId({}), relay servers are disabled", self.node_id.fmt_short())]
+pub struct NoDirectPathError {
+    node_id: NodeId,
+}
+
+impl NoDirectPathError {
+    /// Returns the [`NodeId`] of the node that could not be connected to.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+}
+
 /// Options for the [`Endpoint::connect_with_opts`] function.
 #[derive(Default, Debug, Clone)]
 pub struct ConnectOptions {
@@ -1288,21 +2503,43 @@ pub struct Accept<'a> {
     #[pin]
     #[debug("quinn::Accept")]
     inner: quinn::Accept<'a>,
-    ep: Endpoint,
+    ep: &'a Endpoint,
 }
 
 impl Future for Accept<'_> {
     type Output = Option<Incoming>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        match this.inner.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(inner)) => Poll::Ready(Some(Incoming {
+        let mut this = self.project();
+        loop {
+            let Some(inner) = ready!(this.inner.as_mut().poll(cx)) else {
+                return Poll::Ready(None);
+            };
+            let ep = *this.ep;
+            let open_connections = ep.msock.endpoint().open_connections();
+            if !inner.remote_address_validated()
+                && ep
+                    .static_config
+                    .address_validation
+                    .requires_retry(open_connections)
+            {
+                inner.retry().expect("not validated");
+                this.inner.set(ep.msock.endpoint().accept());
+                continue;
+            }
+            if let Some(limiter) = &ep.connection_limiter {
+                let recv_path = |addr| ep.msock.recv_path(addr);
+                if !limiter.admit(inner.remote_address(), open_connections, recv_path) {
+                    inner.refuse();
+                    this.inner.set(ep.msock.endpoint().accept());
+                    continue;
+                }
+            }
+            return Poll::Ready(Some(Incoming {
                 inner,
-                ep: this.ep.clone(),
-            })),
+                ep: ep.clone(),
+                handshake_timeout: ep.handshake_timeout(),
+            }));
         }
     }
 }
@@ -1313,6 +2550,7 @@ impl Future for Accept<'_> {
 pub struct Incoming {
     inner: quinn::Incoming,
     ep: Endpoint,
+    handshake_timeout: Option<Pin<Box<Sleep>>>,
 }
 
 impl Incoming {
@@ -1326,12 +2564,9 @@ impl Incoming {
     /// Thus it is common to simply log the errors here and accept them as something which
     /// can happen.
     pub fn accept(self) -> Result<Connecting, ConnectionError> {
-        self.inner.accept().map(|conn| Connecting {
-            inner: conn,
-            ep: self.ep,
-            remote_node_id: None,
-            _discovery_drop_guard: None,
-        })
+        self.inner
+            .accept()
+            .map(|conn| Connecting::incoming(conn, self.ep, self.handshake_timeout))
     }
 
     /// Accepts this incoming connection using a custom configuration.
@@ -1345,12 +2580,27 @@ impl Incoming {
     ) -> Result<Connecting, ConnectionError> {
         self.inner
             .accept_with(server_config)
-            .map(|conn| Connecting {
-                inner: conn,
-                ep: self.ep,
-                remote_node_id: None,
-                _discovery_drop_guard: None,
-            })
+            .map(|conn| Connecting::incoming(conn, self.ep, self.handshake_timeout))
+    }
+
+    /// Accepts this incoming connection using a custom transport configuration.
+    ///
+    /// This allows accepting connections with e.g. a different idle timeout or stream
+    /// limits than configured using [`Builder::transport_config`], the TLS configuration
+    /// and ALPNs of the endpoint are used unchanged.  The remote node ID and the ALPN are
+    /// only known after the handshake, so at this point only the [`Incoming::remote_address`]
+    /// can be used to choose the configuration.  Some limits can still be adjusted on the
+    /// established [`Connection`], e.g. using [`Connection::set_max_concurrent_bi_streams`].
+    ///
+    /// Custom transport configurations for outgoing connections are set using
+    /// [`ConnectOptions::with_transport_config`].
+    pub fn accept_with_transport_config(
+        self,
+        transport_config: Arc<TransportConfig>,
+    ) -> Result<Connecting, ConnectionError> {
+        let mut server_config = self.ep.server_config.read().expect("poisoned").clone();
+        server_config.transport_config(transport_config);
+        self.accept_with(Arc::new(server_config))
     }
 
     /// Rejects this incoming connection attempt.
@@ -1399,18 +2649,28 @@ impl IntoFuture for Incoming {
     fn into_future(self) -> Self::IntoFuture {
         IncomingFuture {
             inner: self.inner.into_future(),
+            accept_policy: self.ep.static_config.accept_policy.clone(),
+            authorizing: None,
             ep: self.ep,
+            handshake_timeout: self.handshake_timeout,
         }
     }
 }
 
 /// Adaptor to let [`Incoming`] be `await`ed like a [`Connecting`].
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 #[pin_project]
 pub struct IncomingFuture {
     #[pin]
     inner: quinn::IncomingFuture,
     ep: Endpoint,
+    /// The [`AcceptPolicy`] to consult once the handshake completed.
+    accept_policy: Option<Arc<dyn AcceptPolicy>>,
+    /// The consultation of the [`AcceptPolicy`] in progress.
+    #[debug("Option<BoxFuture>")]
+    authorizing: Option<BoxFuture<Result<Connection, ConnectionError>>>,
+    /// Fails the handshake once the [`Builder::handshake_timeout`] passed.
+    handshake_timeout: Option<Pin<Box<Sleep>>>,
 }
 
 impl Future for IncomingFuture {
@@ -1418,18 +2678,34 @@ impl Future for IncomingFuture {
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        match this.inner.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
-            Poll::Ready(Ok(inner)) => {
-                let conn = Connection {
-                    inner,
-                    tls_auth: this.ep.static_config.tls_auth,
-                };
+        if this.authorizing.is_none() {
+            let inner = match this.inner.poll(cx) {
+                Poll::Ready(Ok(inner)) => inner,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return poll_handshake_timeout(this.handshake_timeout, cx),
+            };
+            let conn = Connection {
+                inner,
+                tls_auth: this.ep.static_config.tls_auth,
+                rtt_tx: this.ep.rtt_actor.msg_tx.clone(),
+            };
+            let Some(policy) = this.accept_policy.take() else {
                 try_send_rtt_msg(&conn, this.ep, None);
-                Poll::Ready(Ok(conn))
+                return Poll::Ready(Ok(conn));
+            };
+            *this.authorizing = Some(Box::pin(accept_policy::authorize(policy, conn)));
+        }
+        let authorizing = this.authorizing.as_mut().expect("set above");
+        let res = match authorizing.as_mut().poll(cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => {
+                return poll_authorize_timeout(this.authorizing, this.handshake_timeout, cx)
             }
+        };
+        if let Ok(ref conn) = res {
+            try_send_rtt_msg(conn, this.ep, None);
         }
+        Poll::Ready(res)
     }
 }
 
@@ -1444,9 +2720,33 @@ pub struct Connecting {
     /// We run discovery as long as we haven't established a connection yet.
     #[debug("Option<DiscoveryTask>")]
     _discovery_drop_guard: Option<DiscoveryTask>,
+    /// The [`AcceptPolicy`] to consult once the handshake of an incoming connection completed.
+    accept_policy: Option<Arc<dyn AcceptPolicy>>,
+    /// The consultation of the [`AcceptPolicy`] in progress.
+    #[debug("Option<BoxFuture>")]
+    authorizing: Option<BoxFuture<Result<Connection, ConnectionError>>>,
+    /// Fails the handshake once the [`Builder::handshake_timeout`] passed.
+    handshake_timeout: Option<Pin<Box<Sleep>>>,
 }
 
 impl Connecting {
+    /// Creates the [`Connecting`] for an incoming connection.
+    fn incoming(
+        inner: quinn::Connecting,
+        ep: Endpoint,
+        handshake_timeout: Option<Pin<Box<Sleep>>>,
+    ) -> Self {
+        Self {
+            inner,
+            accept_policy: ep.static_config.accept_policy.clone(),
+            ep,
+            remote_node_id: None,
+            _discovery_drop_guard: None,
+            authorizing: None,
+            handshake_timeout,
+        }
+    }
+
     /// Converts this [`Connecting`] into a 0-RTT or 0.5-RTT connection at the cost of weakened
     /// security.
     ///
@@ -1479,7 +2779,9 @@ impl Connecting {
     /// ## Incoming
     ///
     /// For incoming connections, conversion to 0.5-RTT will always fully succeed. `into_0rtt` will
-    /// always return `Ok` and the [`ZeroRttAccepted`] will always resolve to true.
+    /// always return `Ok` and the [`ZeroRttAccepted`] will always resolve to true.  Unless an
+    /// [`AcceptPolicy`] is set, which can only be consulted after the handshake, then `into_0rtt`
+    /// always returns `Err`.
     ///
     /// ## Security
     ///
@@ -1493,11 +2795,15 @@ impl Connecting {
     /// You can use [`RecvStream::is_0rtt`] to check whether a stream has been opened in 0-RTT
     /// and thus whether parts of the stream are operating under this reduced security level.
     pub fn into_0rtt(self) -> Result<(Connection, ZeroRttAccepted), Self> {
+        if self.accept_policy.is_some() || self.authorizing.is_some() {
+            return Err(self);
+        }
         match self.inner.into_0rtt() {
             Ok((inner, zrtt_accepted)) => {
                 let conn = Connection {
                     inner,
                     tls_auth: self.ep.static_config.tls_auth,
+                    rtt_tx: self.ep.rtt_actor.msg_tx.clone(),
                 };
                 let zrtt_accepted = ZeroRttAccepted {
                     inner: zrtt_accepted,
@@ -1517,6 +2823,9 @@ impl Connecting {
                 ep: self.ep,
                 remote_node_id: self.remote_node_id,
                 _discovery_drop_guard: self._discovery_drop_guard,
+                accept_policy: None,
+                authorizing: None,
+                handshake_timeout: self.handshake_timeout,
             }),
         }
     }
@@ -1546,18 +2855,34 @@ impl Future for Connecting {
 
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        match this.inner.poll(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
-            Poll::Ready(Ok(inner)) => {
-                let conn = Connection {
-                    inner,
-                    tls_auth: this.ep.static_config.tls_auth,
-                };
+        if this.authorizing.is_none() {
+            let inner = match this.inner.poll(cx) {
+                Poll::Ready(Ok(inner)) => inner,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return poll_handshake_timeout(this.handshake_timeout, cx),
+            };
+            let conn = Connection {
+                inner,
+                tls_auth: this.ep.static_config.tls_auth,
+                rtt_tx: this.ep.rtt_actor.msg_tx.clone(),
+            };
+            let Some(policy) = this.accept_policy.take() else {
                 try_send_rtt_msg(&conn, this.ep, *this.remote_node_id);
-                Poll::Ready(Ok(conn))
+                return Poll::Ready(Ok(conn));
+            };
+            *this.authorizing = Some(Box::pin(accept_policy::authorize(policy, conn)));
+        }
+        let authorizing = this.authorizing.as_mut().expect("set above");
+        let res = match authorizing.as_mut().poll(cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => {
+                return poll_authorize_timeout(this.authorizing, this.handshake_timeout, cx)
             }
+        };
+        if let Ok(ref conn) = res {
+            try_send_rtt_msg(conn, this.ep, *this.remote_node_id);
         }
+        Poll::Ready(res)
     }
 }
 
@@ -1603,6 +2928,8 @@ impl Future for ZeroRttAccepted {
 pub struct Connection {
     inner: quinn::Connection,
     tls_auth: tls::Authentication,
+    /// Reports closing the connection for [`ConnectionEvent::Closed`].
+    rtt_tx: tokio::sync::mpsc::Sender<RttMessage>,
 }
 
 impl Connection {
@@ -1661,7 +2988,9 @@ impl Connection {
     /// [`ConnectionError::LocallyClosed`] and [`ConnectionError::ApplicationClosed`].
     #[inline]
     pub async fn closed(&self) -> ConnectionError {
-        self.inner.closed().await
+        let reason = self.inner.closed().await;
+        self.report_closed(reason.clone());
+        reason
     }
 
     /// If the connection is closed, the reason why.
@@ -1703,7 +3032,21 @@ impl Connection {
     /// [`close`]: Connection::close
     #[inline]
     pub fn close(&self, error_code: VarInt, reason: &[u8]) {
-        self.inner.close(error_code, reason)
+        self.inner.close(error_code, reason);
+        if let Some(reason) = self.inner.close_reason() {
+            self.report_closed(reason);
+        }
+    }
+
+    /// Informs the rtt-actor that the connection is closed, to emit [`ConnectionEvent::Closed`].
+    ///
+    /// If the actor is too busy the event is emitted once all handles are dropped instead.
+    fn report_closed(&self, reason: ConnectionError) {
+        let msg = RttMessage::Closed {
+            stable_id: self.inner.stable_id(),
+            reason,
+        };
+        self.rtt_tx.try_send(msg).ok();
     }
 
     /// Transmits `data` as an unreliable, unordered application datagram.
@@ -1716,20 +3059,18 @@ impl Connection {
         self.inner.send_datagram(data)
     }
 
-    // TODO: It seems `SendDatagram` is not yet exposed by quinn.  This has been fixed
-    //       upstream and will be in the next release.
-    // /// Transmits `data` as an unreliable, unordered application datagram
-    // ///
-    // /// Unlike [`send_datagram()`], this method will wait for buffer space during congestion
-    // /// conditions, which effectively prioritizes old datagrams over new datagrams.
-    // ///
-    // /// See [`send_datagram()`] for details.
-    // ///
-    // /// [`send_datagram()`]: Connection::send_datagram
-    // #[inline]
-    // pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
-    //     self.inner.send_datagram_wait(data)
-    // }
+    /// Transmits `data` as an unreliable, unordered application datagram
+    ///
+    /// Unlike [`send_datagram()`], this method will wait for buffer space during congestion
+    /// conditions, which effectively prioritizes old datagrams over new datagrams.
+    ///
+    /// See [`send_datagram()`] for details.
+    ///
+    /// [`send_datagram()`]: Connection::send_datagram
+    #[inline]
+    pub fn send_datagram_wait(&self, data: bytes::Bytes) -> SendDatagram<'_> {
+        self.inner.send_datagram_wait(data)
+    }
 
     /// Computes the maximum size of datagrams that may be passed to [`send_datagram`].
     ///
@@ -1771,6 +3112,30 @@ impl Connection {
         self.inner.stats()
     }
 
+    /// Returns a stream of the connection statistics, yielded every `period`.
+    ///
+    /// The first statistics are yielded immediately, the stream ends once the connection is
+    /// closed.  The stream holds a handle to the connection, keeping it open while it is
+    /// not dropped.  The network path of the connection is reported by
+    /// [`Endpoint::conn_type_info`].
+    ///
+    /// A `period` shorter than one millisecond, including [`Duration::ZERO`], is raised to
+    /// one millisecond.
+    pub fn stats_stream(&self, period: Duration) -> impl Stream<Item = ConnectionStats> {
+        let interval = n0_future::time::interval(period.max(MIN_STATS_PERIOD));
+        n0_future::stream::unfold(
+            (self.inner.clone(), interval),
+            |(conn, mut interval)| async move {
+                interval.tick().await;
+                if conn.close_reason().is_some() {
+                    return None;
+                }
+                let stats = conn.stats();
+                Some((stats, (conn, interval)))
+            },
+        )
+    }
+
     /// Current state of the congestion control algorithm, for debugging purposes.
     #[inline]
     pub fn congestion_state(&self) -> Box<dyn quinn_proto::congestion::Controller> {
@@ -1901,11 +3266,40 @@ impl Connection {
     }
 }
 
+/// Fails a handshake still in progress once its timeout passed.
+fn poll_handshake_timeout(
+    timeout: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut std::task::Context<'_>,
+) -> Poll<Result<Connection, ConnectionError>> {
+    let Some(timeout) = timeout else {
+        return Poll::Pending;
+    };
+    ready!(timeout.as_mut().poll(cx));
+    inc!(MagicsockMetrics, connection_handshake_timeout);
+    Poll::Ready(Err(ConnectionError::TimedOut))
+}
+
+/// Fails a connection whose [`AcceptPolicy`] did not decide before the handshake timeout.
+///
+/// Dropping the policy future drops the connection, which closes it.
+fn poll_authorize_timeout(
+    authorizing: &mut Option<BoxFuture<Result<Connection, ConnectionError>>>,
+    timeout: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut std::task::Context<'_>,
+) -> Poll<Result<Connection, ConnectionError>> {
+    let res = ready!(poll_handshake_timeout(timeout, cx));
+    *authorizing = None;
+    Poll::Ready(res)
+}
+
 /// Try send a message to the rtt-actor.
 ///
 /// If we can't notify the actor that will impact performance a little, but we can still
-/// function.
+/// function.  This also records the connection for the [`ConnectionLimits`].
 fn try_send_rtt_msg(conn: &Connection, magic_ep: &Endpoint, remote_node_id: Option<NodeId>) {
+    if let Some(limiter) = &magic_ep.connection_limiter {
+        limiter.register(&conn.inner);
+    }
     // If we can't notify the rtt-actor that's not great but not critical.
     let Some(node_id) = remote_node_id.or_else(|| conn.remote_node_id().ok()) else {
         warn!(?conn, "failed to get remote node id");
@@ -1917,8 +3311,12 @@ fn try_send_rtt_msg(conn: &Connection, magic_ep: &Endpoint, remote_node_id: Opti
     };
     let rtt_msg = RttMessage::NewConnection {
         connection: conn.inner.weak_handle(),
+        closed_check: conn.inner.weak_handle(),
+        conn_type: conn_type_changes.get().unwrap_or(ConnectionType::None),
         conn_type_changes: conn_type_changes.stream(),
         node_id,
+        alpn: conn.alpn(),
+        stable_id: conn.inner.stable_id(),
     };
     if let Err(err) = magic_ep.rtt_actor.msg_tx.try_send(rtt_msg) {
         warn!(?conn, "rtt-actor not reachable: {err:#}");
@@ -1931,6 +3329,8 @@ fn try_send_rtt_msg(conn: &Connection, magic_ep: &Endpoint, remote_node_id: Opti
 /// - `http_proxy`
 /// - `HTTPS_PROXY`
 /// - `https_proxy`
+/// - `ALL_PROXY`
+/// - `all_proxy`
 fn proxy_url_from_env() -> Option<Url> {
     if let Some(url) = std::env::var("HTTP_PROXY")
         .ok()
@@ -1960,6 +3360,18 @@ fn proxy_url_from_env() -> Option<Url> {
     {
         return Some(url);
     }
+    if let Some(url) = std::env::var("ALL_PROXY")
+        .ok()
+        .and_then(|s| s.parse::<Url>().ok())
+    {
+        return Some(url);
+    }
+    if let Some(url) = std::env::var("all_proxy")
+        .ok()
+        .and_then(|s| s.parse::<Url>().ok())
+    {
+        return Some(url);
+    }
 
     None
 }
@@ -2779,4 +4191,1544 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_max_udp_payload_size() -> testresult::TestResult {
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .max_udp_payload_size(1000)
+            .bind()
+            .await;
+        assert!(res.is_err());
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .max_udp_payload_size(9000)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .max_udp_payload_size(9000)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let msg = recv.read_to_end(100_000).await?;
+            send.write_all(&msg).await?;
+            send.finish()?;
+            conn.closed().await;
+            testresult::TestResult::Ok(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let msg = vec![42u8; 50_000];
+        send.write_all(&msg).await?;
+        send.finish()?;
+        let echo = recv.read_to_end(100_000).await?;
+        assert_eq!(echo, msg);
+        conn.close(0u32.into(), b"bye");
+        client.close().await;
+        server_task.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_packet_filter() -> testresult::TestResult {
+        #[derive(Debug, Clone)]
+        struct CountingFilter {
+            accept: bool,
+            count: Arc<std::sync::atomic::AtomicUsize>,
+        }
+
+        impl PacketFilter for CountingFilter {
+            fn accept(&self, _src: SocketAddr, _datagram: &[u8]) -> bool {
+                self.count
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.accept
+            }
+        }
+
+        async fn connect_with_filter(filter: CountingFilter) -> Result<()> {
+            let client = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .bind()
+                .await?;
+            let server = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .packet_filter(filter)
+                .bind()
+                .await?;
+            let server_addr = server.node_addr().await?;
+            let server_task = tokio::spawn(async move {
+                let incoming = server.accept().await.unwrap();
+                let conn = incoming.await?;
+                conn.closed().await;
+                anyhow::Ok(())
+            });
+            let res = tokio::time::timeout(
+                Duration::from_secs(2),
+                client.connect(server_addr, TEST_ALPN),
+            )
+            .await;
+            server_task.abort();
+            let conn = res??;
+            conn.close(0u32.into(), b"bye");
+            Ok(())
+        }
+
+        let accepting = CountingFilter {
+            accept: true,
+            count: Default::default(),
+        };
+        connect_with_filter(accepting.clone()).await?;
+        assert!(accepting.count.load(std::sync::atomic::Ordering::Relaxed) > 0);
+
+        let rejecting = CountingFilter {
+            accept: false,
+            count: Default::default(),
+        };
+        assert!(connect_with_filter(rejecting.clone()).await.is_err());
+        assert!(rejecting.count.load(std::sync::atomic::Ordering::Relaxed) > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_rate_limit_zero_burst() {
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .send_rate_limit_per_node(100 * 1024, 0)
+            .bind()
+            .await;
+        assert!(res.is_err());
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .send_rate_limit_per_node(0, 16 * 1024)
+            .bind()
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_port_prediction_limit() {
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .port_prediction(Some(17))
+            .bind()
+            .await;
+        assert!(res.is_err());
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .port_prediction(Some(16))
+            .bind()
+            .await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_pacing_zero_quantum() {
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .send_pacing(0, 0)
+            .bind()
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_rate_limit_per_node() -> testresult::TestResult {
+        const DATA_LEN: usize = 300 * 1024;
+        const RATE: u64 = 100 * 1024;
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .send_rate_limit_per_node(RATE, 16 * 1024)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            let mut recv = conn.accept_uni().await?;
+            let data = recv.read_to_end(DATA_LEN + 1).await?;
+            conn.close(0u32.into(), b"bye");
+            anyhow::Ok(data.len())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let start = Instant::now();
+        let mut send = conn.open_uni().await?;
+        send.write_all(&vec![0u8; DATA_LEN]).await?;
+        send.finish()?;
+        let received = tokio::time::timeout(Duration::from_secs(30), server_task).await???;
+        let elapsed = start.elapsed();
+        assert_eq!(received, DATA_LEN);
+
+        // Everything beyond the burst is sent at the limited rate.
+        let min_duration = Duration::from_secs_f64((DATA_LEN as f64 / RATE as f64) * 0.8);
+        assert!(elapsed > min_duration, "sent too fast: {elapsed:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_keepalive_config() -> testresult::TestResult {
+        // The idle timeout must leave room for a keepalive.
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .keepalive_interval(Duration::from_secs(10))
+            .bind()
+            .await;
+        assert!(res.is_err());
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .keepalive_interval(Duration::ZERO)
+            .bind()
+            .await;
+        assert!(res.is_err());
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .disco_config(DiscoConfig::default().ping_timeout(Duration::ZERO))
+            .bind()
+            .await;
+        assert!(res.is_err());
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .disco_config(DiscoConfig::default().replay_max_age(Duration::ZERO))
+            .bind()
+            .await;
+        assert!(res.is_err());
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .keepalive_interval(Duration::from_secs(2))
+            .path_idle_timeout(Duration::from_secs(3))
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_node_id = server_addr.node_id;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        // An idle connection keeps its direct path across several idle timeouts.
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        tokio::time::sleep(Duration::from_secs(7)).await;
+        assert!(matches!(
+            client.conn_type(server_node_id)?.get()?,
+            ConnectionType::Direct(_)
+        ));
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_update_relay_map() -> testresult::TestResult {
+        let (relay_map_1, relay_url_1, _guard_1) = run_relay_server().await?;
+        let (relay_map_2, relay_url_2, _guard_2) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map_1))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+
+        async fn home_relay_is(ep: &Endpoint, url: Option<&RelayUrl>) -> testresult::TestResult {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while ep.home_relay().get()?.as_ref() != url {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                anyhow::Ok(())
+            })
+            .await??;
+            Ok(())
+        }
+        home_relay_is(&ep, Some(&relay_url_1)).await?;
+
+        // Moving to another relay server changes the home relay.
+        let node_2 = relay_map_2.get_node(&relay_url_2).unwrap();
+        assert!(ep.insert_relay((**node_2).clone()).await.is_none());
+        assert!(ep.remove_relay(&relay_url_1).await.is_some());
+        assert!(ep.remove_relay(&relay_url_1).await.is_none());
+        assert_eq!(ep.relay_map(), relay_map_2);
+        home_relay_is(&ep, Some(&relay_url_2)).await?;
+
+        // Without any relay servers there is no home relay.
+        ep.set_relay_map(RelayMap::empty()).await;
+        home_relay_is(&ep, None).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_direct_only() -> testresult::TestResult {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map.clone()))
+            .insecure_skip_relay_cert_verify(true)
+            .direct_only(true)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        assert!(client.relay_map().is_empty());
+        let server_addr = server.node_addr().await?;
+        assert_eq!(server_addr.relay_url, Some(relay_url.clone()));
+
+        // Only knowing the relay server of the node fails immediately.
+        let relay_only = NodeAddr::new(server.node_id()).with_relay_url(relay_url);
+        let err = client.connect(relay_only, TEST_ALPN).await.unwrap_err();
+        let err = err
+            .downcast_ref::<NoDirectPathError>()
+            .expect("wrong error");
+        assert_eq!(err.node_id(), server.node_id());
+
+        // So do unreachable direct addresses, once the handshake timed out.
+        let unreachable =
+            NodeAddr::new(server.node_id()).with_direct_addresses(["127.0.0.1:1".parse().unwrap()]);
+        let start = Instant::now();
+        let err = client.connect(unreachable, TEST_ALPN).await.unwrap_err();
+        assert!(err.downcast_ref::<NoDirectPathError>().is_some(), "{err:#}");
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let info = client.remote_info(conn.remote_node_id()?).unwrap();
+        assert!(info.relay_url.is_none());
+        assert!(client.home_relay().get()?.is_none());
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connectivity() -> testresult::TestResult {
+        let (relay_map, _relay_url, _guard) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        let connectivity =
+            tokio::time::timeout(Duration::from_secs(10), ep.connectivity().initialized())
+                .await??;
+        assert_eq!(connectivity, Connectivity::Online);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_net_report_watcher() -> testresult::TestResult {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        let mut watcher = ep.net_report_watcher();
+        let first = tokio::time::timeout(Duration::from_secs(10), watcher.initialized()).await??;
+        assert!(first.udp);
+        assert_eq!(first.preferred_relay, Some(relay_url));
+        assert!(first
+            .changes_since(&Default::default())
+            .contains(&ReportChange::Udp(true)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_run_net_report() -> testresult::TestResult {
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .net_report_interval(Some(Duration::ZERO))
+            .bind()
+            .await;
+        assert!(res.is_err());
+
+        let (relay_map, _relay_url, _guard) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .net_report_interval(None)
+            .net_report_on_network_change(false)
+            .bind()
+            .await?;
+        let mut watcher = ep.net_report_watcher();
+        let first = tokio::time::timeout(Duration::from_secs(10), watcher.initialized()).await??;
+
+        // Without periodic reports only the explicit request runs a new one.
+        ep.run_net_report().await;
+        let second = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let report = watcher.updated().await?;
+                if let Some(report) = report.filter(|report| !Arc::ptr_eq(report, &first)) {
+                    return anyhow::Ok(report);
+                }
+            }
+        })
+        .await??;
+        assert!(second.udp);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_path_traffic() -> testresult::TestResult {
+        let (relay_map, _relay_url, _guard) = run_relay_server().await?;
+        async fn echo(
+            client: &Endpoint,
+            server: Endpoint,
+            addr: NodeAddr,
+        ) -> anyhow::Result<RemoteInfo> {
+            let server_task = tokio::spawn(async move {
+                let conn = server.accept().await.context("no incoming")?.await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                let data = recv.read_to_end(1024 * 1024).await?;
+                send.write_all(&data).await?;
+                send.finish()?;
+                conn.closed().await;
+                anyhow::Ok(())
+            });
+            let conn = client.connect(addr, TEST_ALPN).await?;
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(&[7u8; 64 * 1024]).await?;
+            send.finish()?;
+            let data = recv.read_to_end(1024 * 1024).await?;
+            assert_eq!(data.len(), 64 * 1024);
+            let info = client
+                .remote_info(conn.remote_node_id()?)
+                .context("no remote info")?;
+            conn.close(0u32.into(), b"bye");
+            server_task.await??;
+            Ok(info)
+        }
+
+        // Relayed traffic.
+        let endpoint = |path_selection| {
+            Endpoint::builder()
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+                .insecure_skip_relay_cert_verify(true)
+                .path_selection(path_selection)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .bind()
+        };
+        let client = endpoint(PathSelection::RelayOnly).await?;
+        let server = endpoint(PathSelection::RelayOnly).await?;
+        let addr = NodeAddr::new(server.node_id())
+            .with_relay_url(server.home_relay().initialized().await?);
+        let traffic = echo(&client, server, addr).await?.traffic;
+        info!(?traffic, "relayed");
+        assert!(traffic.relay_sent >= 64 * 1024);
+        assert!(traffic.relay_recv >= 64 * 1024);
+        assert_eq!(traffic.direct(), 0);
+        assert_eq!(traffic.direct_fraction(), Some(0.0));
+
+        // Direct traffic.
+        let client = Endpoint::builder().direct_only(true).bind().await?;
+        let server = Endpoint::builder()
+            .direct_only(true)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let addr = server.node_addr().await?;
+        let traffic = echo(&client, server, addr).await?.traffic;
+        info!(?traffic, "direct");
+        assert!(traffic.direct_sent >= 64 * 1024);
+        assert!(traffic.direct_recv >= 64 * 1024);
+        assert_eq!(traffic.relay(), 0);
+        assert_eq!(traffic.direct_fraction(), Some(1.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_events() -> testresult::TestResult {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .relay_reconnect_delay(Duration::from_millis(10), Duration::from_millis(50))
+            .relay_reconnect_jitter(false)
+            .relay_reconnect_attempts(3)
+            .bind()
+            .await?;
+        let mut events = std::pin::pin!(ep.relay_events());
+        async fn next_event(
+            events: &mut (impl Stream<Item = Result<RelayEvent, Lagged>> + Unpin),
+        ) -> anyhow::Result<RelayEvent> {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.next())
+                .await?
+                .context("stream ended")??;
+            Ok(event)
+        }
+        assert_eq!(
+            next_event(&mut events).await?,
+            RelayEvent::Connected {
+                url: relay_url.clone()
+            }
+        );
+
+        // Reconnecting to a relay server which is gone fails until it is reported unreachable.
+        let dead_url: RelayUrl = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            format!("http://{}", listener.local_addr()?).parse()?
+        };
+        let remote = SecretKey::generate(rand::thread_rng()).public();
+        let connect = tokio::spawn({
+            let ep = ep.clone();
+            let addr = NodeAddr::new(remote).with_relay_url(dead_url.clone());
+            async move { ep.connect(addr, TEST_ALPN).await }
+        });
+        for attempt in 1..=3 {
+            let event = next_event(&mut events).await?;
+            if attempt == 3 {
+                assert!(
+                    matches!(event, RelayEvent::Unreachable { ref url, attempts: 3, .. } if *url == dead_url),
+                    "unexpected event {event:?}"
+                );
+            }
+            let event = match attempt {
+                3 => next_event(&mut events).await?,
+                _ => event,
+            };
+            let RelayEvent::Reconnecting {
+                url,
+                attempt: n,
+                delay,
+                ..
+            } = event
+            else {
+                panic!("unexpected event {event:?}");
+            };
+            assert_eq!(url, dead_url);
+            assert_eq!(n, attempt);
+            assert!(delay <= Duration::from_millis(50));
+        }
+        connect.abort();
+
+        // Invalid delays are rejected.
+        let res = Endpoint::builder()
+            .relay_reconnect_delay(Duration::from_secs(2), Duration::from_secs(1))
+            .bind()
+            .await;
+        assert!(res.is_err());
+        let res = Endpoint::builder()
+            .relay_reconnect_delay(Duration::ZERO, Duration::from_secs(1))
+            .bind()
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_keepalive_config() -> testresult::TestResult {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .relay_ping_interval(Duration::from_secs(60))
+            .relay_idle_timeout(Duration::from_secs(5))
+            .bind()
+            .await?;
+        ep.close().await;
+
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .relay_ping_interval(Duration::ZERO)
+            .bind()
+            .await;
+        assert!(res.is_err());
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .relay_idle_timeout(Duration::ZERO)
+            .bind()
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_addr_filter() -> testresult::TestResult {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let addrs = ep.direct_addresses().initialized().await?;
+        let ip = addrs.first().expect("direct addr").addr.ip();
+
+        let filtered = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .addr_filter(AddrPolicy::default().only([ip]))
+            .bind()
+            .await?;
+        let addrs = filtered.direct_addresses().initialized().await?;
+        assert!(addrs.iter().all(|addr| addr.addr.ip() == ip));
+        let node_addr = filtered.node_addr().await?;
+        assert!(node_addr
+            .direct_addresses
+            .iter()
+            .all(|addr| addr.ip() == ip));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_manual_direct_addr() -> testresult::TestResult {
+        async fn wait_for_addr(ep: &Endpoint, addr: SocketAddr, present: bool) -> Result<()> {
+            let mut addrs = ep.direct_addresses().stream();
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while let Some(addrs) = addrs.next().await {
+                    let found = addrs.is_some_and(|addrs| {
+                        addrs.contains(&DirectAddr {
+                            addr,
+                            typ: DirectAddrType::Manual,
+                        })
+                    });
+                    if found == present {
+                        return Ok(());
+                    }
+                }
+                anyhow::bail!("direct addresses watcher closed")
+            })
+            .await?
+        }
+
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let addr: SocketAddr = "203.0.113.1:4433".parse()?;
+        assert!(ep.add_direct_addr(addr));
+        assert!(!ep.add_direct_addr(addr));
+        wait_for_addr(&ep, addr, true).await?;
+
+        assert!(ep.remove_direct_addr(addr));
+        assert!(!ep.remove_direct_addr(addr));
+        wait_for_addr(&ep, addr, false).await?;
+        ep.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_disable_port_mapping() -> testresult::TestResult {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .disable_port_mapping()
+            .bind()
+            .await?;
+        let addrs = ep.direct_addresses().initialized().await?;
+        assert!(!addrs.is_empty());
+        assert!(addrs
+            .iter()
+            .all(|addr| addr.typ != DirectAddrType::Portmapped));
+        assert_eq!(
+            ep.port_mapping_status().get()?,
+            PortMappingStatus::default()
+        );
+        ep.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_disco_trust() -> testresult::TestResult {
+        async fn connect_with_trust(trust: impl Fn(NodeId) -> TrustedNodes) -> Result<()> {
+            let client = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .bind()
+                .await?;
+            let server = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .alpns(vec![TEST_ALPN.to_vec()])
+                .disco_trust(trust(client.node_id()))
+                .bind()
+                .await?;
+            let server_addr = server.node_addr().await?;
+            let server_task = tokio::spawn(async move {
+                let incoming = server.accept().await.unwrap();
+                let conn = incoming.await?;
+                conn.closed().await;
+                anyhow::Ok(())
+            });
+            let res = tokio::time::timeout(
+                Duration::from_secs(2),
+                client.connect(server_addr, TEST_ALPN),
+            )
+            .await;
+            server_task.abort();
+            let conn = res??;
+            conn.close(0u32.into(), b"bye");
+            Ok(())
+        }
+
+        connect_with_trust(|client| TrustedNodes::new([client])).await?;
+        // Without a relay the server only learns the client's address from its pings.
+        assert!(connect_with_trust(|_| TrustedNodes::default())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connection_events() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let mut events = client.connection_events();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        let event = events.next().await.unwrap()?;
+        let ConnectionEvent::Established {
+            node_id,
+            alpn,
+            conn_type: _,
+        } = event
+        else {
+            panic!("unexpected event: {event:?}");
+        };
+        assert_eq!(node_id, server_addr.node_id);
+        assert_eq!(alpn.as_deref(), Some(TEST_ALPN));
+
+        // Closing is reported right away, while the connection is still held.
+        conn.close(0u32.into(), b"bye");
+        let closed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let ConnectionEvent::Closed {
+                    node_id,
+                    alpn,
+                    reason,
+                } = events.next().await.unwrap()?
+                {
+                    return anyhow::Ok((node_id, alpn, reason));
+                }
+            }
+        })
+        .await??;
+        assert_eq!(
+            closed,
+            (
+                server_addr.node_id,
+                Some(TEST_ALPN.to_vec()),
+                Some(ConnectionError::LocallyClosed)
+            )
+        );
+        server_task.await??;
+        drop(conn);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_accept_with_transport_config() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let mut transport_config = TransportConfig::default();
+            transport_config.max_concurrent_uni_streams(0u32.into());
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming
+                .accept_with_transport_config(Arc::new(transport_config))?
+                .await?;
+            assert_eq!(conn.alpn().as_deref(), Some(TEST_ALPN));
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        // The server does not allow any unidirectional streams.
+        let res = tokio::time::timeout(Duration::from_millis(500), conn.open_uni()).await;
+        assert!(res.is_err());
+        assert!(conn.open_bi().await.is_ok());
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_congestion_control_bbr() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .congestion_control(CongestionControl::Bbr)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let mut transport_config = TransportConfig::default();
+            CongestionControl::NewReno.apply(&mut transport_config);
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming
+                .accept_with_transport_config(Arc::new(transport_config))?
+                .await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(1024 * 1024).await?;
+            send.write_all(&data).await?;
+            send.finish()?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let data = vec![7u8; 256 * 1024];
+        send.write_all(&data).await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(1024 * 1024).await?, data);
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_max_idle_timeout() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .keep_alive_interval(None)
+            .max_idle_timeout(Some(Duration::from_millis(300)))
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .keep_alive_interval(None)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            anyhow::Ok(conn.closed().await)
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let err = tokio::time::timeout(Duration::from_secs(5), conn.closed()).await?;
+        assert_eq!(err, ConnectionError::TimedOut);
+        server_task.await??;
+
+        let res = Endpoint::builder()
+            .max_idle_timeout(Some(Duration::MAX))
+            .bind()
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_accept_policy() -> testresult::TestResult {
+        let allowed = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let denied = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .accept_policy(TrustedNodes::new([allowed.node_id()]))
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                let incoming = server.accept().await.unwrap();
+                let res = incoming.await;
+                if let Ok(ref conn) = res {
+                    conn.closed().await;
+                }
+                results.push(res.map(|conn| conn.remote_node_id().unwrap()));
+            }
+            results
+        });
+
+        let conn = denied.connect(server_addr.clone(), TEST_ALPN).await?;
+        let err = conn.closed().await;
+        let ConnectionError::ApplicationClosed(close) = err else {
+            panic!("unexpected close: {err:?}");
+        };
+        assert_eq!(close.error_code, ACCEPT_DENIED_CODE);
+
+        let conn = allowed.connect(server_addr, TEST_ALPN).await?;
+        conn.close(0u32.into(), b"bye");
+        let results = server_task.await?;
+        assert!(matches!(results[0], Err(ConnectionError::LocallyClosed)));
+        assert_eq!(results[1].as_ref().ok(), Some(&allowed.node_id()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connection_limits() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .connection_limits(ConnectionLimits::default().max_connections_per_node(1))
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let (accepted_tx, accepted_rx) = tokio::sync::oneshot::channel();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            accepted_tx.send(()).ok();
+            tokio::select! {
+                incoming = server.accept() => panic!("limit not enforced: {incoming:?}"),
+                _ = conn.closed() => {}
+            }
+            testresult::TestResult::Ok(())
+        });
+
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        accepted_rx.await?;
+        let err = client
+            .connect(server_addr, TEST_ALPN)
+            .await
+            .expect_err("limit exceeded");
+        let Some(ConnectionError::ConnectionClosed(close)) = err.downcast_ref() else {
+            panic!("unexpected error: {err:#}");
+        };
+        assert_eq!(close.error_code, TransportErrorCode::CONNECTION_REFUSED);
+
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connection_limits_per_source() -> testresult::TestResult {
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .connection_limits(ConnectionLimits::default().max_connections_per_source(1))
+            .bind()
+            .await?;
+        // Only use the IPv4 addresses, so both clients connect from the same source.
+        let mut server_addr = server.node_addr().await?;
+        server_addr.direct_addresses.retain(|addr| addr.is_ipv4());
+        let (accepted_tx, accepted_rx) = tokio::sync::oneshot::channel();
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            accepted_tx.send(()).ok();
+            tokio::select! {
+                incoming = server.accept() => panic!("limit not enforced: {incoming:?}"),
+                _ = conn.closed() => {}
+            }
+            testresult::TestResult::Ok(())
+        });
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+        accepted_rx.await?;
+
+        // A different node from the same IP address is refused.
+        let other = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let err = other
+            .connect(server_addr, TEST_ALPN)
+            .await
+            .expect_err("limit exceeded");
+        let Some(ConnectionError::ConnectionClosed(close)) = err.downcast_ref() else {
+            panic!("unexpected error: {err:#}");
+        };
+        assert_eq!(close.error_code, TransportErrorCode::CONNECTION_REFUSED);
+
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connection_limits_per_source_relayed() -> testresult::TestResult {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let endpoint = || {
+            Endpoint::builder()
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+                .insecure_skip_relay_cert_verify(true)
+        };
+        let server = endpoint()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .connection_limits(ConnectionLimits::default().max_connections_per_source(1))
+            .bind()
+            .await?;
+        let server_addr = NodeAddr::new(server.node_id()).with_relay_url(relay_url);
+        let server_task = tokio::spawn(async move {
+            let mut conns = Vec::new();
+            for _ in 0..2 {
+                let incoming = server.accept().await.unwrap();
+                conns.push(incoming.await?);
+            }
+            testresult::TestResult::Ok(conns)
+        });
+
+        // Connections via the same relay server do not share a limit.
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let client = endpoint().bind().await?;
+            let conn = client.connect(server_addr.clone(), TEST_ALPN).await?;
+            conns.push((client, conn));
+        }
+        assert_eq!(server_task.await??.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_address_validation() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .address_validation(AddressValidation::Always)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            assert!(incoming.remote_address_validated());
+            let conn = incoming.await?;
+            conn.closed().await;
+            testresult::TestResult::Ok(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_handshake_timeout() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .handshake_timeout(Duration::from_millis(500))
+            .bind()
+            .await?;
+        // The server never accepts the connection, so the handshake does not progress.
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+
+        let connecting = client
+            .connect_with_opts(server_addr, TEST_ALPN, Default::default())
+            .await?;
+        let res = tokio::time::timeout(Duration::from_secs(5), connecting).await?;
+        assert!(matches!(res, Err(ConnectionError::TimedOut)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_handshake_timeout_accept_policy() -> testresult::TestResult {
+        /// A policy which never decides.
+        #[derive(Debug)]
+        struct Undecided;
+
+        impl AcceptPolicy for Undecided {
+            fn authorize(&self, _node_id: NodeId, _alpn: &[u8]) -> BoxFuture<bool> {
+                Box::pin(std::future::pending())
+            }
+        }
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .handshake_timeout(Duration::from_millis(500))
+            .accept_policy(Undecided)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), incoming).await
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let res = server_task.await??;
+        assert!(matches!(res, Err(ConnectionError::TimedOut)));
+        // Dropping the undecided connection closed it.
+        let err = tokio::time::timeout(Duration::from_secs(5), conn.closed()).await?;
+        assert!(matches!(err, ConnectionError::ApplicationClosed(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_datagrams() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            let datagram = conn.read_datagram().await?;
+            conn.send_datagram_wait(datagram).await?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let max_size = conn.max_datagram_size().expect("datagrams supported");
+        assert!(conn.datagram_send_buffer_space() >= max_size);
+        let too_large = bytes::Bytes::from(vec![1u8; max_size + 1]);
+        assert!(matches!(
+            conn.send_datagram(too_large),
+            Err(SendDatagramError::TooLarge)
+        ));
+        let datagram = bytes::Bytes::from(vec![1u8; max_size]);
+        conn.send_datagram(datagram.clone())?;
+        assert_eq!(conn.read_datagram().await?, datagram);
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_close_with() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            anyhow::Ok(conn.closed().await)
+        });
+
+        let _conn = client.connect(server_addr, TEST_ALPN).await?;
+        client.close_with(42u32.into(), b"shutdown").await;
+        assert!(client.is_closed());
+        let err = tokio::time::timeout(Duration::from_secs(2), server_task).await???;
+        let ConnectionError::ApplicationClosed(close) = err else {
+            panic!("unexpected close: {err:?}");
+        };
+        assert_eq!(close.error_code, 42u32.into());
+        assert_eq!(&close.reason[..], b"shutdown");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_stats_stream() -> testresult::TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            let mut recv = conn.accept_uni().await?;
+            recv.read_to_end(100).await?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let mut stats = std::pin::pin!(conn.stats_stream(Duration::from_millis(10)));
+        let first = stats.next().await.unwrap();
+        let mut send = conn.open_uni().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+        send.stopped().await?;
+        let second = stats.next().await.unwrap();
+        assert!(second.udp_tx.bytes > first.udp_tx.bytes);
+
+        // A zero period does not panic.
+        let mut fast = std::pin::pin!(conn.stats_stream(Duration::ZERO));
+        assert!(fast.next().await.is_some());
+        assert!(fast.next().await.is_some());
+
+        conn.close(0u32.into(), b"bye");
+        assert!(stats.next().await.is_none());
+        server_task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_selector() -> testresult::TestResult {
+        let (relay_map_a, relay_url_a, _server_a) = run_relay_server().await?;
+        let (relay_map_b, relay_url_b, _server_b) = run_relay_server().await?;
+        let relay_map =
+            RelayMap::from_nodes(relay_map_a.nodes().chain(relay_map_b.nodes()).cloned())?;
+
+        // Both relays are equally close, the pinned one always wins.
+        for pinned in [relay_url_a, relay_url_b] {
+            let ep = Endpoint::builder()
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+                .insecure_skip_relay_cert_verify(true)
+                .relay_selector(PinnedRelays::new([pinned.clone()]))
+                .bind()
+                .await?;
+            let home = tokio::time::timeout(Duration::from_secs(10), ep.home_relay().initialized())
+                .await??;
+            assert_eq!(home, pinned);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_failover() -> testresult::TestResult {
+        let (relay_map_a, relay_url_a, server_a) = run_relay_server().await?;
+        let (relay_map_b, relay_url_b, server_b) = run_relay_server().await?;
+        let relay_map =
+            RelayMap::from_nodes(relay_map_a.nodes().chain(relay_map_b.nodes()).cloned())?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .relay_standby(1)
+            .bind()
+            .await?;
+        let mut events = std::pin::pin!(ep.relay_events());
+
+        // Both the home relay and the standby relay get connected.
+        let mut connected = BTreeSet::new();
+        while connected.len() < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.next())
+                .await?
+                .context("stream ended")??;
+            if let RelayEvent::Connected { url } = event {
+                connected.insert(url);
+            }
+        }
+        let home = ep.home_relay().initialized().await?;
+        let (standby, home_server) = if home == relay_url_a {
+            (relay_url_b, server_a)
+        } else {
+            (relay_url_a, server_b)
+        };
+
+        home_server.shutdown().await?;
+        let failover = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events.next().await {
+                    Some(Ok(RelayEvent::Failover { from, to })) => break Ok((from, to)),
+                    Some(_) => continue,
+                    None => break Err(anyhow::anyhow!("stream ended")),
+                }
+            }
+        })
+        .await??;
+        assert_eq!(failover, (home, standby.clone()));
+        assert_eq!(ep.home_relay().get()?, Some(standby));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_probes() -> testresult::TestResult {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+
+        let probe = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(probe) = ep.relay_probes().pop() {
+                    if probe.latencies.contains_key(&relay_url) {
+                        break probe;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+        assert_eq!(probe.home_relay, Some(relay_url));
+        assert!(probe.age < Duration::from_secs(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_pacing() -> testresult::TestResult {
+        const DATA_LEN: usize = 1024 * 1024;
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .send_pacing(4, 0)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .send_pacing(4, 0)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(DATA_LEN + 1).await?;
+            send.write_all(&data).await?;
+            send.finish()?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        // Paced transmits are all delivered, in both directions.
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&vec![7u8; DATA_LEN]).await?;
+        send.finish()?;
+        let echoed =
+            tokio::time::timeout(Duration::from_secs(30), recv.read_to_end(DATA_LEN + 1)).await??;
+        assert_eq!(echoed.len(), DATA_LEN);
+        assert!(echoed.iter().all(|b| *b == 7));
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        assert!(logs_contain("pacing transmit"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_recv_packet_budget() -> testresult::TestResult {
+        const DATA_LEN: usize = 1024 * 1024;
+
+        // The smallest budget yields after every receive, which must not stall the endpoint.
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .recv_packet_budget(1)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .recv_packet_budget(1)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let data = recv.read_to_end(DATA_LEN).await?;
+            send.write_all(&data).await?;
+            send.finish()?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&vec![1u8; DATA_LEN]).await?;
+        send.finish()?;
+        let data =
+            tokio::time::timeout(Duration::from_secs(30), recv.read_to_end(DATA_LEN)).await??;
+        assert_eq!(data.len(), DATA_LEN);
+        conn.close(0u32.into(), b"bye");
+        tokio::time::timeout(Duration::from_secs(10), server_task).await???;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_recv_limits() -> testresult::TestResult {
+        const DATA_LEN: usize = 1024 * 1024;
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .recv_batch_size(1)
+            .max_gro_segments(1)
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            let mut recv = conn.accept_uni().await?;
+            let data = recv.read_to_end(DATA_LEN).await?;
+            conn.close(0u32.into(), b"bye");
+            anyhow::Ok(data)
+        });
+
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        let data: Vec<u8> = (0..DATA_LEN).map(|i| i as u8).collect();
+        let mut send = conn.open_uni().await?;
+        send.write_all(&data).await?;
+        send.finish()?;
+        let received = tokio::time::timeout(Duration::from_secs(30), server_task).await???;
+        assert_eq!(received, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_packet_capture() -> testresult::TestResult {
+        #[derive(Debug, Clone, Default)]
+        struct SharedBuf(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let conn = incoming.await?;
+            conn.closed().await;
+            anyhow::Ok(())
+        });
+
+        let buf = SharedBuf::default();
+        client.start_packet_capture(buf.clone())?;
+        let conn = client.connect(server_addr, TEST_ALPN).await?;
+        client.stop_packet_capture()?;
+        let captured = buf.0.lock().unwrap().clone();
+
+        // Section header, then two interface descriptions and the captured packets.
+        assert_eq!(&captured[..4], &[0x0a, 0x0d, 0x0d, 0x0a]);
+        assert!(captured.len() > 200, "no packets captured");
+
+        // Nothing is written after stopping the capture.
+        conn.close(0u32.into(), b"bye");
+        server_task.await??;
+        assert_eq!(buf.0.lock().unwrap().len(), captured.len());
+
+        Ok(())
+    }
 }
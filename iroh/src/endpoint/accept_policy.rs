@@ -0,0 +1,71 @@
+//! Deciding whether to accept incoming connections once the remote node is known.
+
+use std::{fmt::Debug, sync::Arc};
+
+use iroh_base::NodeId;
+use n0_future::boxed::BoxFuture;
+use quinn::{ConnectionError, VarInt};
+use tracing::debug;
+
+use super::Connection;
+use crate::magicsock::TrustedNodes;
+
+/// The error code with which connections denied by the [`AcceptPolicy`] are closed.
+///
+/// The value spells "deny" in ASCII, to avoid clashing with the error codes of application
+/// protocols.
+pub const ACCEPT_DENIED_CODE: VarInt = VarInt::from_u32(0x6465_6e79);
+
+/// Decides whether to accept incoming connections, after the handshake.
+///
+/// The policy is consulted once the TLS handshake of an incoming connection completed, at
+/// which point the remote node ID was authenticated and the ALPN was negotiated.  Only
+/// connections it allows are returned from [`Connecting`] and [`IncomingFuture`], denied
+/// connections are closed with [`ACCEPT_DENIED_CODE`] and fail with
+/// [`ConnectionError::LocallyClosed`].  This also applies to the connections accepted by a
+/// [`Router`].  Outgoing connections are not checked.
+///
+/// Install a policy using [`Builder::accept_policy`].  [`TrustedNodes`] only accepts a
+/// fixed set of nodes.
+///
+/// The returned future may e.g. look up the node in a database.  Until it resolves the
+/// connection is not returned, so it should not take longer than a handshake.
+///
+/// [`Connecting`]: super::Connecting
+/// [`IncomingFuture`]: super::IncomingFuture
+/// [`Router`]: crate::protocol::Router
+/// [`Builder::accept_policy`]: super::Builder::accept_policy
+pub trait AcceptPolicy: Debug + Send + Sync + 'static {
+    /// Returns whether a connection from `node_id` using the `alpn` protocol is accepted.
+    fn authorize(&self, node_id: NodeId, alpn: &[u8]) -> BoxFuture<bool>;
+}
+
+impl AcceptPolicy for TrustedNodes {
+    fn authorize(&self, node_id: NodeId, _alpn: &[u8]) -> BoxFuture<bool> {
+        let allowed = self.contains(&node_id);
+        Box::pin(async move { allowed })
+    }
+}
+
+/// Returns the connection if the policy allows it, closes it otherwise.
+pub(super) async fn authorize(
+    policy: Arc<dyn AcceptPolicy>,
+    conn: Connection,
+) -> Result<Connection, ConnectionError> {
+    let allowed = match conn.remote_node_id() {
+        Ok(node_id) => {
+            let alpn = conn.alpn().unwrap_or_default();
+            policy.authorize(node_id, &alpn).await
+        }
+        Err(err) => {
+            debug!("denying connection without node id: {err:#}");
+            false
+        }
+    };
+    if allowed {
+        Ok(conn)
+    } else {
+        conn.close(ACCEPT_DENIED_CODE, b"denied");
+        Err(ConnectionError::LocallyClosed)
+    }
+}
@@ -0,0 +1,338 @@
+//! Limits on incoming connections, enforced before their handshake.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+};
+
+use n0_future::time::{Duration, Instant};
+use quinn::WeakConnectionHandle;
+use tracing::debug;
+
+use crate::disco::SendAddr;
+
+/// The default prefix length grouping IPv6 addresses for the per-source limit.
+const DEFAULT_IPV6_PREFIX_LEN: u8 = 64;
+
+/// Limits on the incoming connections accepted by an [`Endpoint`].
+///
+/// Incoming connections exceeding a limit are refused before the server starts its part of
+/// the handshake, so a flood of connection attempts does not cost any cryptographic work.
+/// The limits are checked as [`Endpoint::accept`] takes the incoming connections from the
+/// queue, those exceeding them are never returned and the remote sees the connection attempt
+/// fail with [`ConnectionError::ConnectionClosed`].
+///
+/// By default no limits are enforced.  Use [`Builder::connection_limits`] to configure an
+/// endpoint.
+///
+/// [`Endpoint`]: super::Endpoint
+/// [`Endpoint::accept`]: super::Endpoint::accept
+/// [`ConnectionError::ConnectionClosed`]: super::ConnectionError::ConnectionClosed
+/// [`Builder::connection_limits`]: super::Builder::connection_limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// The maximum number of open connections.
+    max_connections: Option<usize>,
+    /// The maximum number of established connections with a single remote node.
+    max_connections_per_node: Option<usize>,
+    /// The maximum number of established connections from a single source.
+    max_connections_per_source: Option<usize>,
+    /// The prefix length grouping IPv6 source addresses.
+    ipv6_prefix_len: Option<u8>,
+    /// The maximum number of handshakes started per second.
+    max_handshakes_per_second: Option<usize>,
+}
+
+impl ConnectionLimits {
+    /// Sets the maximum number of open connections of the endpoint.
+    ///
+    /// This counts all connections, incoming and outgoing, including those still in their
+    /// handshake.  Once reached, incoming connections are refused until connections are closed.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of established connections with a single remote node.
+    ///
+    /// Each remote node is seen at its own QUIC address, no matter over which IP addresses
+    /// or relay servers its packets arrive.  Use
+    /// [`ConnectionLimits::max_connections_per_source`] to limit the connections per IP
+    /// address.
+    ///
+    /// Both incoming and outgoing connections are counted.  Connections still in their
+    /// handshake are not, use [`ConnectionLimits::max_handshakes_per_second`] to limit
+    /// those.
+    pub fn max_connections_per_node(mut self, max: usize) -> Self {
+        self.max_connections_per_node = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of established connections from a single source.
+    ///
+    /// The source is the IP address the packets of a connection currently arrive from,
+    /// IPv6 addresses are grouped by their prefix, see [`ConnectionLimits::ipv6_prefix_len`].
+    /// Unlike [`ConnectionLimits::max_connections_per_node`] this also limits a single host
+    /// using many node IDs.  Since connections can change their path, the source of each
+    /// connection is looked up whenever a new connection is checked.
+    ///
+    /// Connections via a relay server are not limited: the relay's address says nothing
+    /// about the remote, and many unrelated nodes share it.  They are only counted once they
+    /// switch to a direct path, the per-node limit still applies to them.
+    ///
+    /// Both incoming and outgoing connections are counted, connections still in their
+    /// handshake are not.
+    pub fn max_connections_per_source(mut self, max: usize) -> Self {
+        self.max_connections_per_source = Some(max);
+        self
+    }
+
+    /// Sets the prefix length by which IPv6 addresses are grouped into a single source.
+    ///
+    /// Hosts are usually assigned a whole IPv6 subnet, so limiting individual addresses
+    /// would not limit them.  Defaults to `64`, values larger than `128` are treated as
+    /// `128`.
+    pub fn ipv6_prefix_len(mut self, len: u8) -> Self {
+        self.ipv6_prefix_len = Some(len.min(128));
+        self
+    }
+
+    /// Returns the source a connection arriving on `path` is counted under.
+    ///
+    /// Relayed connections have no source, they are not limited.
+    fn source(&self, path: &SendAddr) -> Option<IpAddr> {
+        let SendAddr::Udp(addr) = path else {
+            return None;
+        };
+        match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => Some(ip.into()),
+            IpAddr::V6(ip) => {
+                let len = self.ipv6_prefix_len.unwrap_or(DEFAULT_IPV6_PREFIX_LEN);
+                let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+                Some(Ipv6Addr::from(u128::from(ip) & mask).into())
+            }
+        }
+    }
+
+    /// Sets the maximum number of incoming handshakes started per second.
+    ///
+    /// The rate is measured over a sliding window of one second.
+    pub fn max_handshakes_per_second(mut self, max: usize) -> Self {
+        self.max_handshakes_per_second = Some(max);
+        self
+    }
+}
+
+/// When incoming connections have to validate their address before their handshake.
+///
+/// QUIC servers can answer the first packet of a connection with a retry packet containing
+/// a token, which the client has to echo back to continue.  This proves that the client
+/// receives packets at its address, so spoofed source addresses can not be used to make the
+/// server handshake on behalf of, or send its larger handshake packets to, someone else.
+/// It costs the client an additional round trip.
+///
+/// Use [`Builder::address_validation`] to configure an endpoint.
+///
+/// [`Builder::address_validation`]: super::Builder::address_validation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AddressValidation {
+    /// Never sends retry packets.
+    ///
+    /// Packets from addresses unknown to the endpoint are already dropped before reaching
+    /// QUIC, so handshakes can only be started via a relay server or from addresses a node
+    /// sent an authenticated DISCO message from.
+    #[default]
+    Never,
+    /// Sends retry packets once the endpoint has this many open connections.
+    UnderLoad(usize),
+    /// Always sends retry packets.
+    Always,
+}
+
+impl AddressValidation {
+    /// Returns whether an incoming connection has to validate its address first.
+    pub(super) fn requires_retry(&self, open_connections: usize) -> bool {
+        match self {
+            Self::Never => false,
+            Self::UnderLoad(threshold) => open_connections >= *threshold,
+            Self::Always => true,
+        }
+    }
+}
+
+/// Enforces the [`ConnectionLimits`] of an endpoint.
+#[derive(Debug)]
+pub(super) struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    /// The established connections, by their remote address.
+    connections: HashMap<SocketAddr, Vec<WeakConnectionHandle>>,
+    /// When the handshakes of the last second were started.
+    handshakes: VecDeque<Instant>,
+}
+
+impl ConnectionLimiter {
+    pub(super) fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns whether an incoming connection from `remote` may start its handshake.
+    ///
+    /// `open_connections` is the current number of open connections of the endpoint, and
+    /// `recv_path` returns the network path the packets from a remote address currently
+    /// arrive on.
+    pub(super) fn admit(
+        &self,
+        remote: SocketAddr,
+        open_connections: usize,
+        recv_path: impl Fn(SocketAddr) -> Option<SendAddr>,
+    ) -> bool {
+        if let Some(max) = self.limits.max_connections {
+            if open_connections >= max {
+                debug!(%remote, "refusing connection: {open_connections} open connections");
+                return false;
+            }
+        }
+        let mut state = self.state.lock().expect("poisoned");
+        if self.limits.max_connections_per_node.is_some()
+            || self.limits.max_connections_per_source.is_some()
+        {
+            state.connections.retain(|_, conns| {
+                conns.retain(|conn| conn.is_alive());
+                !conns.is_empty()
+            });
+        }
+        if let Some(max) = self.limits.max_connections_per_node {
+            let count = state
+                .connections
+                .get(&remote)
+                .map_or(0, |conns| conns.len());
+            if count >= max {
+                debug!(%remote, "refusing connection: {count} connections with node");
+                return false;
+            }
+        }
+        if let Some(max) = self.limits.max_connections_per_source {
+            let source = |addr| recv_path(addr).and_then(|path| self.limits.source(&path));
+            if let Some(ip) = source(remote) {
+                let count: usize = state
+                    .connections
+                    .iter()
+                    .filter(|(addr, _)| source(**addr) == Some(ip))
+                    .map(|(_, conns)| conns.len())
+                    .sum();
+                if count >= max {
+                    debug!(%remote, %ip, "refusing connection: {count} connections from source");
+                    return false;
+                }
+            }
+        }
+        if let Some(max) = self.limits.max_handshakes_per_second {
+            let now = Instant::now();
+            while state
+                .handshakes
+                .front()
+                .is_some_and(|start| now.duration_since(*start) >= Duration::from_secs(1))
+            {
+                state.handshakes.pop_front();
+            }
+            if state.handshakes.len() >= max {
+                debug!(%remote, "refusing connection: handshake rate exceeded");
+                return false;
+            }
+            state.handshakes.push_back(now);
+        }
+        true
+    }
+
+    /// Records an established connection, counting towards the per-node and per-source limits.
+    pub(super) fn register(&self, conn: &quinn::Connection) {
+        if self.limits.max_connections_per_node.is_none()
+            && self.limits.max_connections_per_source.is_none()
+        {
+            return;
+        }
+        let mut state = self.state.lock().expect("poisoned");
+        state
+            .connections
+            .entry(conn.remote_address())
+            .or_default()
+            .push(conn.weak_handle());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::RelayUrl;
+
+    use super::*;
+
+    fn no_path(_remote: SocketAddr) -> Option<SendAddr> {
+        None
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits::default().max_connections(2));
+        let remote: SocketAddr = "[::1]:1".parse().unwrap();
+        assert!(limiter.admit(remote, 0, no_path));
+        assert!(limiter.admit(remote, 1, no_path));
+        assert!(!limiter.admit(remote, 2, no_path));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_handshakes_per_second() {
+        let limiter =
+            ConnectionLimiter::new(ConnectionLimits::default().max_handshakes_per_second(2));
+        let remote: SocketAddr = "[::1]:1".parse().unwrap();
+        assert!(limiter.admit(remote, 0, no_path));
+        assert!(limiter.admit(remote, 0, no_path));
+        assert!(!limiter.admit(remote, 0, no_path));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(limiter.admit(remote, 0, no_path));
+    }
+
+    #[test]
+    fn test_source() {
+        let limits = ConnectionLimits::default();
+        let udp = |addr: &str| SendAddr::Udp(addr.parse().unwrap());
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        assert_eq!(limits.source(&udp("192.0.2.1:1")), ip("192.0.2.1"));
+        assert_eq!(limits.source(&udp("[::ffff:192.0.2.1]:1")), ip("192.0.2.1"));
+        assert_eq!(
+            limits.source(&udp("[2001:db8:1:2:3::4]:1")),
+            ip("2001:db8:1:2::")
+        );
+        assert_eq!(
+            limits
+                .ipv6_prefix_len(48)
+                .source(&udp("[2001:db8:1:2:3::4]:1")),
+            ip("2001:db8:1::")
+        );
+        assert_eq!(
+            limits
+                .ipv6_prefix_len(0)
+                .source(&udp("[2001:db8:1:2:3::4]:1")),
+            ip("::")
+        );
+        assert_eq!(
+            limits
+                .ipv6_prefix_len(255)
+                .source(&udp("[2001:db8:1:2:3::4]:1")),
+            ip("2001:db8:1:2:3::4")
+        );
+        // Relayed connections are not limited.
+        let url: RelayUrl = "https://relay.example".parse().unwrap();
+        assert_eq!(limits.source(&SendAddr::Relay(url)), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! Reusing outgoing connections to the same node and ALPN.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::Result;
+use iroh_base::{NodeAddr, NodeId};
+use n0_future::{
+    task::{self, AbortOnDropHandle},
+    time::{self, Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+use super::{Connection, Endpoint};
+
+type Connections = Mutex<HashMap<(NodeId, Vec<u8>), Arc<PoolEntry>>>;
+
+/// A pool of outgoing connections, reusing open connections to the same node and ALPN.
+///
+/// [`ConnectionPool::connect`] returns the pooled connection to the node using the ALPN, and
+/// only dials a new connection if there is none or it was closed.  Concurrent calls for the
+/// same node and ALPN share a single dial.
+///
+/// Connections which were not returned from [`ConnectionPool::connect`] for the idle timeout
+/// are removed from the pool.  The pool does not close them, they close once all their
+/// [`Connection`] handles are dropped, so connections still used by the application stay
+/// open.
+///
+/// The pool can be cloned cheaply, the clones share the connections.
+#[derive(Debug, Clone)]
+pub struct ConnectionPool {
+    endpoint: Endpoint,
+    connections: Arc<Connections>,
+    _prune_task: Arc<AbortOnDropHandle<()>>,
+}
+
+#[derive(Debug)]
+struct PoolEntry {
+    conn: OnceCell<Connection>,
+    last_used: Mutex<Instant>,
+}
+
+impl Default for PoolEntry {
+    fn default() -> Self {
+        Self {
+            conn: OnceCell::new(),
+            last_used: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl PoolEntry {
+    /// Returns whether the entry can be removed from the pool.
+    fn is_expired(self: &Arc<Self>, idle_timeout: Duration) -> bool {
+        // Connections are being dialed or returned for this entry.
+        if Arc::strong_count(self) > 1 {
+            return false;
+        }
+        let idle = self.last_used.lock().expect("poisoned").elapsed() >= idle_timeout;
+        self.conn
+            .get()
+            .map_or(true, |conn| idle || conn.close_reason().is_some())
+    }
+}
+
+impl ConnectionPool {
+    /// Creates a pool for outgoing connections of the endpoint.
+    ///
+    /// Connections are removed from the pool once they were not used for `idle_timeout`.
+    pub fn new(endpoint: Endpoint, idle_timeout: Duration) -> Self {
+        let connections = Arc::new(Connections::default());
+        let prune_task = task::spawn(prune(Arc::downgrade(&connections), idle_timeout));
+        Self {
+            endpoint,
+            connections,
+            _prune_task: Arc::new(AbortOnDropHandle::new(prune_task)),
+        }
+    }
+
+    /// Returns the endpoint the connections are made from.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Returns an open connection to the node using the ALPN.
+    ///
+    /// An open connection in the pool is reused, otherwise a new connection is dialed using
+    /// [`Endpoint::connect`].  If a dial for the same node and ALPN is already in progress,
+    /// its connection is returned once established.  If that dial fails, the waiting calls
+    /// dial again one after another.
+    pub async fn connect(&self, node_addr: impl Into<NodeAddr>, alpn: &[u8]) -> Result<Connection> {
+        let node_addr = node_addr.into();
+        let entry = {
+            let mut connections = self.connections.lock().expect("poisoned");
+            let entry = connections
+                .entry((node_addr.node_id, alpn.to_vec()))
+                .or_default();
+            if entry
+                .conn
+                .get()
+                .is_some_and(|conn| conn.close_reason().is_some())
+            {
+                *entry = Default::default();
+            }
+            entry.clone()
+        };
+        let conn = entry
+            .conn
+            .get_or_try_init(|| self.endpoint.connect(node_addr, alpn))
+            .await?;
+        *entry.last_used.lock().expect("poisoned") = Instant::now();
+        Ok(conn.clone())
+    }
+
+    /// Returns the number of connections in the pool, including those being dialed.
+    pub fn len(&self) -> usize {
+        self.connections.lock().expect("poisoned").len()
+    }
+
+    /// Returns whether there are no connections in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Removes closed and idle connections from the pool, until it is dropped.
+async fn prune(connections: Weak<Connections>, idle_timeout: Duration) {
+    let mut interval = time::interval(idle_timeout.max(Duration::from_millis(100)) / 2);
+    loop {
+        interval.tick().await;
+        let Some(connections) = connections.upgrade() else {
+            break;
+        };
+        connections
+            .lock()
+            .expect("poisoned")
+            .retain(|_, entry| !entry.is_expired(idle_timeout));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use n0_future::join_all;
+    use testresult::TestResult;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::RelayMode;
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test";
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connection_pool() -> TestResult {
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let server = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let server_addr = server.node_addr().await?;
+        let server_task = task::spawn({
+            let server = server.clone();
+            async move {
+                let mut accepted = 0;
+                while let Some(incoming) = server.accept().await {
+                    let Ok(conn) = incoming.await else {
+                        continue;
+                    };
+                    accepted += 1;
+                    task::spawn(async move { conn.closed().await });
+                }
+                accepted
+            }
+        });
+
+        let pool = ConnectionPool::new(client.clone(), Duration::from_millis(500));
+        let conns = join_all((0..4).map(|_| pool.connect(server_addr.clone(), TEST_ALPN))).await;
+        let ids = conns
+            .into_iter()
+            .map(|conn| conn.map(|conn| conn.stable_id()))
+            .collect::<Result<Vec<_>>>()?;
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        assert_eq!(pool.len(), 1);
+
+        // A closed connection is replaced.
+        let conn = pool.connect(server_addr.clone(), TEST_ALPN).await?;
+        assert_eq!(conn.stable_id(), ids[0]);
+        conn.close(0u32.into(), b"bye");
+        let conn = pool.connect(server_addr, TEST_ALPN).await?;
+        assert_ne!(conn.stable_id(), ids[0]);
+        drop(conn);
+
+        // Idle connections are removed.
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(pool.is_empty());
+
+        drop(pool);
+        client.close().await;
+        server.close().await;
+        assert_eq!(server_task.await?, 2);
+        Ok(())
+    }
+}
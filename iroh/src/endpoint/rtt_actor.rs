@@ -1,4 +1,6 @@
 //! Actor which coordinates the congestion controller for the magic socket
+//!
+//! As it monitors all connections it also emits the [`ConnectionEvent`]s.
 
 use std::{pin::Pin, task::Poll};
 
@@ -6,24 +8,94 @@ use iroh_base::NodeId;
 use iroh_metrics::inc;
 use n0_future::{
     task::{self, AbortOnDropHandle},
+    time::{self, Duration},
     MergeUnbounded, Stream, StreamExt,
 };
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use tracing::{debug, info_span, Instrument};
 
+use super::ConnectionError;
 use crate::{magicsock::ConnectionType, metrics::MagicsockMetrics, watchable::WatcherStream};
 
+/// How many [`ConnectionEvent`]s are kept for subscribers which are not keeping up.
+const CONNECTION_EVENTS_CAPACITY: usize = 256;
+
+/// How often the monitored connections are checked for having been closed.
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An event of the lifecycle of a connection.
+///
+/// See [`Endpoint::connection_events`].
+///
+/// [`Endpoint::connection_events`]: crate::Endpoint::connection_events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectionEvent {
+    /// A connection was established, either by connecting or by accepting it.
+    Established {
+        /// The remote node.
+        node_id: NodeId,
+        /// The negotiated ALPN protocol.
+        ///
+        /// This is `None` for 0-RTT connections whose handshake did not complete yet.
+        alpn: Option<Vec<u8>>,
+        /// The path to the remote node when the connection was established.
+        conn_type: ConnectionType,
+    },
+    /// The path to the remote node changed.
+    ///
+    /// This covers upgrades from the relay to a direct path, downgrades back to the relay
+    /// and changes of the remote node's direct address.  It is emitted for each connection
+    /// to the node.
+    ///
+    /// The QUIC connection itself never migrates, the endpoint switches the path below it.
+    /// A direct address is only switched to once it answered a DISCO ping sent to it, so
+    /// QUIC packets from an address alone never move a connection to it.
+    PathChanged {
+        /// The remote node.
+        node_id: NodeId,
+        /// The previous path.
+        previous: ConnectionType,
+        /// The new path.
+        conn_type: ConnectionType,
+    },
+    /// A connection was closed.
+    ///
+    /// This is emitted as soon as the connection is closed using [`Connection::close`], or
+    /// when [`Connection::closed`] returns, with the close reason.  Connections closed in
+    /// any other way, e.g. by the remote node or by timing out, are only noticed once all
+    /// their handles are dropped and the connection finished draining.  This can be
+    /// delayed by a few seconds and carries no close reason.  A closed connection of which
+    /// the application keeps a handle without calling [`Connection::closed`] is never
+    /// reported.
+    ///
+    /// [`Connection::close`]: crate::endpoint::Connection::close
+    /// [`Connection::closed`]: crate::endpoint::Connection::closed
+    Closed {
+        /// The remote node.
+        node_id: NodeId,
+        /// The negotiated ALPN protocol.
+        alpn: Option<Vec<u8>>,
+        /// Why the connection was closed, if known.
+        reason: Option<ConnectionError>,
+    },
+}
+
 #[derive(Debug)]
 pub(super) struct RttHandle {
     // We should and some point use this to propagate panics and errors.
     pub(super) _handle: AbortOnDropHandle<()>,
     pub(super) msg_tx: mpsc::Sender<RttMessage>,
+    events: broadcast::Sender<ConnectionEvent>,
 }
 
 impl RttHandle {
     pub(super) fn new() -> Self {
+        let events = broadcast::Sender::new(CONNECTION_EVENTS_CAPACITY);
         let mut actor = RttActor {
             connection_events: Default::default(),
+            connections: Default::default(),
+            events: events.clone(),
         };
         let (msg_tx, msg_rx) = mpsc::channel(16);
         let handle = task::spawn(
@@ -35,8 +107,13 @@ impl RttHandle {
         Self {
             _handle: AbortOnDropHandle::new(handle),
             msg_tx,
+            events,
         }
     }
+
+    pub(super) fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
 }
 
 /// Messages to send to the [`RttActor`].
@@ -46,10 +123,25 @@ pub(super) enum RttMessage {
     NewConnection {
         /// The connection.
         connection: quinn::WeakConnectionHandle,
+        /// Another handle to the connection, to detect when it is closed.
+        closed_check: quinn::WeakConnectionHandle,
+        /// The current path of the connection.
+        conn_type: ConnectionType,
         /// Path changes for this connection from the magic socket.
         conn_type_changes: WatcherStream<ConnectionType>,
         /// For reporting-only, the Node ID of this connection.
         node_id: NodeId,
+        /// For reporting-only, the ALPN of this connection.
+        alpn: Option<Vec<u8>>,
+        /// The [`quinn::Connection::stable_id`], to match [`RttMessage::Closed`].
+        stable_id: usize,
+    },
+    /// Informs the [`RttActor`] that a connection was closed.
+    Closed {
+        /// The [`quinn::Connection::stable_id`] of the connection.
+        stable_id: usize,
+        /// Why the connection was closed.
+        reason: ConnectionError,
     },
 }
 
@@ -62,6 +154,18 @@ struct RttActor {
     /// Stream of connection type changes.
     #[debug("MergeUnbounded<WatcherStream<ConnectionType>>")]
     connection_events: MergeUnbounded<MappedStream>,
+    /// The connections not yet closed.
+    connections: Vec<OpenConnection>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+/// A connection which was not closed yet.
+#[derive(Debug)]
+struct OpenConnection {
+    connection: quinn::WeakConnectionHandle,
+    stable_id: usize,
+    node_id: NodeId,
+    alpn: Option<Vec<u8>>,
 }
 
 #[derive(Debug)]
@@ -73,6 +177,9 @@ struct MappedStream {
     /// This an indiciator of whether this connection was direct before.
     /// This helps establish metrics on number of connections that became direct.
     was_direct_before: bool,
+    /// The current path of the connection, to report [`ConnectionEvent::PathChanged`].
+    conn_type: ConnectionType,
+    events: broadcast::Sender<ConnectionEvent>,
 }
 
 impl Stream for MappedStream {
@@ -84,6 +191,12 @@ impl Stream for MappedStream {
     /// resetting.  Even when switching to mixed we should reset the state as e.g. switching
     /// from direct to mixed back to direct should be a rare exception and is a bug if this
     /// happens commonly.
+    ///
+    /// This also restarts path MTU discovery, so every path a connection moves to, be it
+    /// IPv4, IPv6 or a relay, probes for its own MTU starting from
+    /// [`quinn::TransportConfig::initial_mtu`] instead of inheriting the MTU of the previous
+    /// path.  Quinn does not allow seeding a connection with an MTU discovered earlier, so
+    /// moving back to a path also means discovering its MTU again.
     fn poll_next(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -94,13 +207,23 @@ impl Stream for MappedStream {
                     debug!(
                         node_id = %self.node_id.fmt_short(),
                         new_type = ?new_conn_type,
-                        "Congestion controller state reset",
+                        "Congestion controller and MTU discovery state reset",
                     );
                     if !self.was_direct_before && matches!(new_conn_type, ConnectionType::Direct(_))
                     {
                         self.was_direct_before = true;
                         inc!(MagicsockMetrics, connection_became_direct);
                     }
+                    if new_conn_type != self.conn_type {
+                        let previous =
+                            std::mem::replace(&mut self.conn_type, new_conn_type.clone());
+                        let event = ConnectionEvent::PathChanged {
+                            node_id: self.node_id,
+                            previous,
+                            conn_type: new_conn_type.clone(),
+                        };
+                        self.events.send(event).ok();
+                    }
                 }
                 Poll::Ready(Some(new_conn_type))
             }
@@ -115,6 +238,7 @@ impl RttActor {
     ///
     /// The main loop will finish when the sender is dropped.
     async fn run(&mut self, mut msg_rx: mpsc::Receiver<RttMessage>) {
+        let mut closed_check = time::interval(CLOSED_CHECK_INTERVAL);
         loop {
             tokio::select! {
                 biased;
@@ -125,6 +249,9 @@ impl RttActor {
                     }
                 }
                 _item = self.connection_events.next(), if !self.connection_events.is_empty() => {}
+                _ = closed_check.tick(), if !self.connections.is_empty() => {
+                    self.check_closed();
+                }
             }
         }
         debug!("rtt-actor finished");
@@ -135,10 +262,44 @@ impl RttActor {
         match msg {
             RttMessage::NewConnection {
                 connection,
+                closed_check,
+                conn_type,
                 conn_type_changes,
                 node_id,
+                alpn,
+                stable_id,
             } => {
-                self.handle_new_connection(connection, conn_type_changes, node_id);
+                self.events
+                    .send(ConnectionEvent::Established {
+                        node_id,
+                        alpn: alpn.clone(),
+                        conn_type: conn_type.clone(),
+                    })
+                    .ok();
+                self.connections.push(OpenConnection {
+                    connection: closed_check,
+                    stable_id,
+                    node_id,
+                    alpn,
+                });
+                self.handle_new_connection(connection, conn_type, conn_type_changes, node_id);
+            }
+            RttMessage::Closed { stable_id, reason } => {
+                // Connections are only reported once, later messages find nothing.
+                let Some(i) = self
+                    .connections
+                    .iter()
+                    .position(|conn| conn.stable_id == stable_id)
+                else {
+                    return;
+                };
+                let conn = self.connections.swap_remove(i);
+                let event = ConnectionEvent::Closed {
+                    node_id: conn.node_id,
+                    alpn: conn.alpn,
+                    reason: Some(reason),
+                };
+                self.events.send(event).ok();
             }
         }
     }
@@ -147,6 +308,7 @@ impl RttActor {
     fn handle_new_connection(
         &mut self,
         connection: quinn::WeakConnectionHandle,
+        conn_type: ConnectionType,
         conn_type_changes: WatcherStream<ConnectionType>,
         node_id: NodeId,
     ) {
@@ -155,7 +317,26 @@ impl RttActor {
             connection,
             node_id,
             was_direct_before: false,
+            conn_type,
+            events: self.events.clone(),
         });
         inc!(MagicsockMetrics, connection_handshake_success);
     }
+
+    /// Reports the connections which were closed since the last check.
+    fn check_closed(&mut self) {
+        let events = &self.events;
+        self.connections.retain(|conn| {
+            if conn.connection.is_alive() {
+                return true;
+            }
+            let event = ConnectionEvent::Closed {
+                node_id: conn.node_id,
+                alpn: conn.alpn.clone(),
+                reason: None,
+            };
+            events.send(event).ok();
+            false
+        });
+    }
 }
@@ -16,10 +16,11 @@
 //! however, read any packets that come off the UDP sockets.
 
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     fmt::Display,
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    ops::RangeInclusive,
     pin::Pin,
     sync::{
         atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering},
@@ -28,7 +29,7 @@ use std::{
     task::{Context, Poll, Waker},
 };
 
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, ensure, Context as _, Result};
 use atomic_waker::AtomicWaker;
 use bytes::Bytes;
 use concurrent_queue::ConcurrentQueue;
@@ -40,7 +41,7 @@ use n0_future::{
     boxed::BoxStream,
     task::{self, JoinSet},
     time::{self, Duration, Instant},
-    FutureExt, StreamExt,
+    FutureExt, Stream, StreamExt, TryStreamExt,
 };
 use netwatch::{interfaces, netmon};
 #[cfg(not(wasm_browser))]
@@ -48,6 +49,7 @@ use netwatch::{ip::LocalAddresses, UdpSocket};
 use quinn::{AsyncUdpSocket, ServerConfig};
 use rand::{seq::SliceRandom, Rng, SeedableRng};
 use relay_actor::RelaySendItem;
+pub(crate) use relay_actor::{RelayKeepalive, RelayReconnect};
 use smallvec::{smallvec, SmallVec};
 use tokio::sync::{self, mpsc, Mutex};
 use tokio_util::sync::CancellationToken;
@@ -57,9 +59,12 @@ use tracing::{
 };
 use url::Url;
 
+#[cfg(not(wasm_browser))]
+pub(crate) use self::udp_conn::RecvLimits;
 #[cfg(not(wasm_browser))]
 use self::udp_conn::UdpConn;
 use self::{
+    disco_replay::ReplayWindows,
     metrics::Metrics as MagicsockMetrics,
     node_map::{NodeMap, PingAction, PingRole, SendPing},
     relay_actor::{RelayActor, RelayActorMessage, RelayRecvDatagram},
@@ -73,23 +78,50 @@ use crate::net_report::{IpMappedAddr, QuicConfig};
 use crate::{
     defaults::timeouts::NET_REPORT_TIMEOUT,
     disco::{self, CallMeMaybe, SendAddr},
-    discovery::{Discovery, DiscoveryItem, DiscoverySubscribers, NodeData, UserData},
+    discovery::{Discovery, DiscoveryItem, DiscoverySubscribers, Lagged, NodeData, UserData},
     key::{public_ed_box, secret_ed_box, DecryptionError, SharedSecret},
     net_report::{self, IpMappedAddresses},
     watchable::{Watchable, Watcher},
 };
 
+mod addr_filter;
+#[cfg(not(wasm_browser))]
+mod capture;
+mod disco_pool;
+mod disco_replay;
+mod disco_trust;
 mod metrics;
 mod node_map;
+mod pacer;
+#[cfg(not(wasm_browser))]
+mod port_mapping;
+mod rate_limiter;
 mod relay_actor;
+mod relay_selector;
+#[cfg(not(wasm_browser))]
+mod turn;
 #[cfg(not(wasm_browser))]
 mod udp_conn;
 
-pub use node_map::Source;
+pub use addr_filter::{AddrFilter, AddrPolicy};
+pub use disco_trust::{DiscoTrust, PingSource, TrustedNodes};
+pub use node_map::{DiscoConfig, HolepunchEvent, Source};
+pub(crate) use pacer::SendPacing;
+#[cfg(not(wasm_browser))]
+pub use port_mapping::{PortMappingConfig, PortMappingEvent, PortMappingStatus};
+pub(crate) use rate_limiter::SendRateLimit;
+pub use relay_selector::{PinnedRelays, RelaySelector};
+#[cfg(not(wasm_browser))]
+pub use turn::TurnServer;
+#[cfg(not(wasm_browser))]
+pub(crate) use turn::SEND_OVERHEAD as TURN_SEND_OVERHEAD;
 
 pub use self::{
     metrics::Metrics,
-    node_map::{ConnectionType, ControlMsg, DirectAddrInfo, RemoteInfo},
+    node_map::{
+        ConnectionType, ConnectionTypeInfo, ControlMsg, DirectAddrInfo, DiscoPathInfo, PathQuality,
+        PathTraffic, RelayUrlInfo, RemoteInfo,
+    },
 };
 
 /// How long we consider a STUN-derived endpoint valid for. UDP NAT mappings typically
@@ -98,6 +130,24 @@ const ENDPOINTS_FRESH_ENOUGH_DURATION: Duration = Duration::from_secs(27);
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
+/// How many relay probes are kept, see [`MagicSock::relay_probes`].
+const RELAY_PROBE_HISTORY: usize = 32;
+
+/// How many [`RelayEvent`]s are kept for subscribers which are not keeping up.
+const RELAY_EVENTS_CAPACITY: usize = 64;
+
+/// The maximum number of predicted addresses added to a call-me-maybe message.
+const MAX_PREDICTED_PORTS: usize = 16;
+
+/// The default number of datagrams received before yielding, see [`RecvBudget`].
+const DEFAULT_RECV_PACKET_BUDGET: usize = 1024;
+
+/// The range the interval between periodic net reports is picked from by default.
+///
+/// Just under 30s, a common UDP NAT timeout on Linux, etc.
+const DEFAULT_NET_REPORT_INTERVAL: RangeInclusive<Duration> =
+    Duration::from_secs(20)..=Duration::from_secs(26);
+
 /// Contains options for `MagicSock::listen`.
 #[derive(derive_more::Debug)]
 pub(crate) struct Options {
@@ -122,6 +172,9 @@ pub(crate) struct Options {
     /// An optional [`NodeMap`], to restore information about nodes.
     pub(crate) node_map: Option<Vec<NodeAddr>>,
 
+    /// Whether relay servers are forbidden, including the relay servers of other nodes.
+    pub(crate) direct_only: bool,
+
     /// Optional node discovery mechanism.
     pub(crate) discovery: Option<Box<dyn Discovery>>,
 
@@ -138,9 +191,85 @@ pub(crate) struct Options {
     /// Proxy configuration.
     pub(crate) proxy_url: Option<Url>,
 
+    /// How to verify the TLS certificates of relay servers.
+    #[cfg(not(wasm_browser))]
+    pub(crate) relay_tls_config: iroh_relay::client::ClientTlsConfig,
+
     /// ServerConfig for the internal QUIC endpoint
     pub(crate) server_config: ServerConfig,
 
+    /// The maximum UDP payload size accepted from peers.
+    ///
+    /// If set to `None` quinn's default of 1472 bytes is used, which fits in an Ethernet
+    /// frame.
+    pub(crate) max_udp_payload_size: Option<u16>,
+
+    /// Optional filter for datagrams received on the UDP sockets.
+    #[cfg(not(wasm_browser))]
+    pub(crate) packet_filter: Option<Arc<dyn PacketFilter>>,
+
+    /// Optional limit of the rate at which QUIC datagrams are sent to each node.
+    pub(crate) send_rate_limit: Option<SendRateLimit>,
+
+    /// Optional pacing of the QUIC datagrams sent to each node on the direct paths.
+    pub(crate) send_pacing: Option<SendPacing>,
+
+    /// Timing of the DISCO pings on the direct paths to nodes.
+    pub(crate) disco_config: DiscoConfig,
+
+    /// How connecting to relay servers is retried.
+    pub(crate) relay_reconnect: RelayReconnect,
+
+    /// Timing of the traffic keeping the relay connections alive.
+    pub(crate) relay_keepalive: RelayKeepalive,
+
+    /// The number of relay servers besides the home relay to stay connected to.
+    pub(crate) relay_standby: usize,
+
+    /// The number of datagrams received before yielding to other tasks.
+    ///
+    /// If set to `None` [`DEFAULT_RECV_PACKET_BUDGET`] is used.
+    pub(crate) recv_packet_budget: Option<usize>,
+
+    /// Limits on the number of datagrams received from the UDP sockets at once.
+    #[cfg(not(wasm_browser))]
+    pub(crate) recv_limits: RecvLimits,
+
+    /// Custom STUN servers probed by net reports.
+    #[cfg(not(wasm_browser))]
+    pub(crate) stun_servers: net_report::StunServers,
+
+    /// Which STUN packets to drop if net reports do not keep up with them.
+    #[cfg(not(wasm_browser))]
+    pub(crate) stun_overflow_policy: net_report::StunOverflowPolicy,
+
+    /// When net reports are run.
+    pub(crate) net_report_schedule: NetReportSchedule,
+
+    /// The timeouts and concurrency limit of net report probes.
+    pub(crate) net_report_limits: net_report::ProbeLimits,
+
+    /// Overrides the selection of the home relay from net reports.
+    pub(crate) relay_selector: Option<Arc<dyn RelaySelector>>,
+
+    /// The number of predicted addresses added to call-me-maybe messages behind a NAT with
+    /// sequential port allocation, `None` disables port prediction.
+    pub(crate) port_prediction: Option<usize>,
+
+    /// Filters the direct addresses advertised to other nodes.
+    pub(crate) addr_filter: Option<Arc<dyn AddrFilter>>,
+
+    /// Decides whether to answer the DISCO pings of unknown nodes.
+    pub(crate) disco_trust: Option<Arc<dyn DiscoTrust>>,
+
+    /// Optional TURN server to allocate a relayed address on.
+    #[cfg(not(wasm_browser))]
+    pub(crate) turn_server: Option<TurnServer>,
+
+    /// The protocols used to request port mappings from the NAT devices.
+    #[cfg(not(wasm_browser))]
+    pub(crate) port_mapping: PortMappingConfig,
+
     /// Skip verification of SSL certificates from relay servers
     ///
     /// May only be used in tests.
@@ -186,6 +315,11 @@ pub(crate) struct MagicSock {
     me: String,
     /// Proxy
     proxy_url: Option<Url>,
+    /// Whether relay servers are forbidden.
+    direct_only: bool,
+    /// How to verify the TLS certificates of relay servers.
+    #[cfg(not(wasm_browser))]
+    relay_tls_config: iroh_relay::client::ClientTlsConfig,
     /// Queue to receive datagrams from relays for [`AsyncUdpSocket::poll_recv`].
     ///
     /// Relay datagrams received by relays are put into this queue and consumed by
@@ -196,11 +330,31 @@ pub(crate) struct MagicSock {
     relay_datagram_send_channel: RelayDatagramSendChannelSender,
     /// Counter for ordering of [`MagicSock::poll_recv`] polling order.
     poll_recv_counter: AtomicUsize,
+    /// Limits the number of datagrams received without yielding.
+    recv_budget: RecvBudget,
 
     /// The DNS resolver to be used in this magicsock.
     #[cfg(not(wasm_browser))]
     dns_resolver: DnsResolver,
 
+    /// Filter consulted for every datagram received on the UDP sockets.
+    #[cfg(not(wasm_browser))]
+    packet_filter: Option<Arc<dyn PacketFilter>>,
+
+    /// The TURN client, if a TURN server is configured.
+    #[cfg(not(wasm_browser))]
+    turn: Option<Arc<turn::TurnClient>>,
+    /// The direct addresses added by the application, see [`DirectAddrType::Manual`].
+    #[cfg(not(wasm_browser))]
+    manual_direct_addrs: std::sync::Mutex<BTreeSet<SocketAddr>>,
+    /// Capture of the packets sent and received, if started.
+    #[cfg(not(wasm_browser))]
+    capture: capture::PacketCapture,
+    /// Limits the rate of QUIC datagrams sent to each node.
+    send_rate_limiter: Option<rate_limiter::RateLimiter>,
+    /// Paces the QUIC datagrams sent to each node on the direct paths.
+    pacer: Option<pacer::Pacer>,
+
     /// Key for this node.
     secret_key: SecretKey,
     /// Encryption key for this node.
@@ -216,11 +370,22 @@ pub(crate) struct MagicSock {
     closed: AtomicBool,
     /// If the last net_report report, reports IPv6 to be available.
     ipv6_reported: Arc<AtomicBool>,
+    /// The NAT64 prefix found by the last net report, used to reach IPv4 addresses from an
+    /// IPv6-only host.
+    nat64_prefix: RwLock<Option<net_report::Nat64Prefix>>,
 
     /// None (or zero nodes) means relay is disabled.
-    relay_map: RelayMap,
+    ///
+    /// Can be changed at runtime, see [`MagicSock::update_relay_map`].
+    relay_map: RwLock<RelayMap>,
     /// Nearest relay node ID; 0 means none/unknown.
     my_relay: Watchable<Option<RelayUrl>>,
+    /// The internet connectivity found by the last net report.
+    connectivity: Watchable<Option<net_report::Connectivity>>,
+    /// The last net report.
+    net_report: Watchable<Option<Arc<net_report::Report>>>,
+    /// The relay latencies measured by the most recent net reports, oldest first.
+    relay_probes: std::sync::Mutex<VecDeque<(Instant, RelayProbe)>>,
     /// Tracks the networkmap node entity for each node discovery key.
     node_map: NodeMap,
     /// Tracks the mapped IP addresses
@@ -229,9 +394,15 @@ pub(crate) struct MagicSock {
     net_reporter: net_report::Addr,
     /// The state for an active DiscoKey.
     disco_secrets: DiscoSecrets,
+    /// The nonces of the recently received DISCO messages, to drop replays.
+    disco_replay: ReplayWindows,
 
     /// UDP disco (ping) queue
     udp_disco_sender: mpsc::Sender<(SocketAddr, PublicKey, disco::Message)>,
+    /// The holepunching pings to send later, see [`DiscoConfig::probe_spacing`].
+    paced_ping_sender: mpsc::Sender<(Instant, SendPing)>,
+    /// Workers handling the received DISCO messages.
+    disco_pool: disco_pool::DiscoPool,
 
     /// Optional discovery service
     discovery: Option<Box<dyn Discovery>>,
@@ -246,6 +417,19 @@ pub(crate) struct MagicSock {
     /// completes
     pending_call_me_maybes: std::sync::Mutex<HashMap<PublicKey, RelayUrl>>,
 
+    /// The number of predicted addresses to add to call-me-maybe messages, if enabled.
+    port_prediction: Option<usize>,
+    /// The predicted addresses sent in the last call-me-maybe, not yet confirmed by a pong.
+    predicted_addrs: std::sync::Mutex<BTreeSet<SocketAddr>>,
+
+    /// Filters the direct addresses advertised to other nodes.
+    addr_filter: Option<Arc<dyn AddrFilter>>,
+    /// Decides whether to answer the DISCO pings of unknown nodes.
+    disco_trust: Option<Arc<dyn DiscoTrust>>,
+    /// The status and events of the port mapping.
+    #[cfg(not(wasm_browser))]
+    port_mapping: port_mapping::PortMappingState,
+
     /// Indicates the direct addr update state.
     direct_addr_update_state: DirectAddrUpdateState,
 
@@ -257,6 +441,15 @@ pub(crate) struct MagicSock {
 
     /// Broadcast channel for listening to discovery updates.
     discovery_subscribers: DiscoverySubscribers,
+
+    /// How connecting to relay servers is retried.
+    relay_reconnect: RelayReconnect,
+    /// Timing of the traffic keeping the relay connections alive.
+    relay_keepalive: RelayKeepalive,
+    /// The number of standby relay servers to stay connected to, to fail over to.
+    relay_standby: usize,
+    /// Broadcast channel for the [`RelayEvent`]s of the relay connections.
+    relay_events: sync::broadcast::Sender<RelayEvent>,
 }
 
 /// Sockets and related state, grouped together so we can cfg them out for browsers.
@@ -291,6 +484,17 @@ impl MagicSock {
         self.proxy_url.as_ref()
     }
 
+    /// Returns `true` if relay servers may not be used.
+    pub(crate) fn direct_only(&self) -> bool {
+        self.direct_only
+    }
+
+    /// Returns how to verify the TLS certificates of relay servers.
+    #[cfg(not(wasm_browser))]
+    pub(crate) fn relay_tls_config(&self) -> &iroh_relay::client::ClientTlsConfig {
+        &self.relay_tls_config
+    }
+
     /// Sets the relay node with the best latency.
     ///
     /// If we are not connected to any relay nodes, set this to `None`.
@@ -357,6 +561,45 @@ impl MagicSock {
         self.my_relay.watch()
     }
 
+    /// Watch for changes to the internet connectivity found by the net reports.
+    pub(crate) fn connectivity(&self) -> Watcher<Option<net_report::Connectivity>> {
+        self.connectivity.watch()
+    }
+
+    /// Watch for new net reports.
+    pub(crate) fn net_report(&self) -> Watcher<Option<Arc<net_report::Report>>> {
+        self.net_report.watch()
+    }
+
+    /// Returns the relay latencies measured by the most recent net reports, oldest first.
+    pub(crate) fn relay_probes(&self) -> Vec<RelayProbe> {
+        let probes = self.relay_probes.lock().expect("poisoned");
+        probes
+            .iter()
+            .map(|(finished, probe)| RelayProbe {
+                age: finished.elapsed(),
+                ..probe.clone()
+            })
+            .collect()
+    }
+
+    /// Records the relay latencies of a net report.
+    fn record_relay_probe(&self, latencies: &net_report::RelayLatencies) {
+        let probe = RelayProbe {
+            age: Duration::ZERO,
+            latencies: latencies
+                .iter()
+                .map(|(url, latency)| (url.clone(), latency))
+                .collect(),
+            home_relay: self.my_relay(),
+        };
+        let mut probes = self.relay_probes.lock().expect("poisoned");
+        if probes.len() == RELAY_PROBE_HISTORY {
+            probes.pop_front();
+        }
+        probes.push_back((Instant::now(), probe));
+    }
+
     /// Returns a [`Watcher`] that reports the [`ConnectionType`] we have to the
     /// given `node_id`.
     ///
@@ -371,6 +614,29 @@ impl MagicSock {
         self.node_map.conn_type(node_id)
     }
 
+    /// Returns a [`Watcher`] for the [`ConnectionTypeInfo`] of the given `node_id`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is no address information known about the
+    /// given `node_id`.
+    pub(crate) fn conn_type_info(&self, node_id: NodeId) -> Result<Watcher<ConnectionTypeInfo>> {
+        self.node_map.conn_type_info(node_id)
+    }
+
+    /// Returns the network path the last datagram from the QUIC address `addr` arrived on.
+    ///
+    /// For nodes this is the real UDP address or the relay server they were last heard
+    /// from, rather than the [`NodeIdMappedAddr`] QUIC sees them at.
+    pub(crate) fn recv_path(&self, addr: SocketAddr) -> Option<SendAddr> {
+        match MappedAddr::from(addr) {
+            MappedAddr::NodeId(addr) => self.node_map.last_recv_path(addr),
+            #[cfg(not(wasm_browser))]
+            MappedAddr::Ip(addr) => self.ip_mapped_addrs.get_ip_addr(&addr).map(SendAddr::Udp),
+            MappedAddr::None(addr) => Some(SendAddr::Udp(addr)),
+        }
+    }
+
     /// Returns the socket address which can be used by the QUIC layer to dial this node.
     pub(crate) fn get_mapping_addr(&self, node_id: NodeId) -> Option<NodeIdMappedAddr> {
         self.node_map.get_quic_mapped_addr_for_node_key(node_id)
@@ -379,6 +645,11 @@ impl MagicSock {
     /// Add addresses for a node to the magic socket's addresbook.
     #[instrument(skip_all, fields(me = %self.me))]
     pub fn add_node_addr(&self, mut addr: NodeAddr, source: node_map::Source) -> Result<()> {
+        if self.direct_only {
+            if let Some(relay_url) = addr.relay_url.take() {
+                debug!(node_id=addr.node_id.fmt_short(), %relay_url, %source, "not adding relay for node, relays are disabled");
+            }
+        }
         let mut pruned = 0;
         for my_addr in self.direct_addrs.sockaddrs() {
             if addr.direct_addresses.remove(&my_addr) {
@@ -402,7 +673,10 @@ impl MagicSock {
     ///
     /// If the direct addresses have changed from the previous set, they are published to
     /// discovery.
-    pub(super) fn store_direct_addresses(&self, addrs: BTreeSet<DirectAddr>) {
+    pub(super) fn store_direct_addresses(&self, mut addrs: BTreeSet<DirectAddr>) {
+        if let Some(ref filter) = self.addr_filter {
+            addrs.retain(|addr| filter.advertise(addr));
+        }
         let updated = self.direct_addrs.update(addrs);
         if updated {
             self.node_map
@@ -411,6 +685,34 @@ impl MagicSock {
         }
     }
 
+    /// Adds a direct address to advertise, returns `false` if it was already added.
+    #[cfg(not(wasm_browser))]
+    pub(crate) fn add_direct_addr(&self, addr: SocketAddr) -> bool {
+        let added = self
+            .manual_direct_addrs
+            .lock()
+            .expect("poisoned")
+            .insert(addr);
+        if added {
+            self.re_stun("direct-addr-added");
+        }
+        added
+    }
+
+    /// Removes a direct address added before, returns `false` if it was not added.
+    #[cfg(not(wasm_browser))]
+    pub(crate) fn remove_direct_addr(&self, addr: SocketAddr) -> bool {
+        let removed = self
+            .manual_direct_addrs
+            .lock()
+            .expect("poisoned")
+            .remove(&addr);
+        if removed {
+            self.re_stun("direct-addr-removed");
+        }
+        removed
+    }
+
     /// Get a reference to the DNS resolver used in this [`MagicSock`].
     #[cfg(not(wasm_browser))]
     pub(crate) fn dns_resolver(&self) -> &DnsResolver {
@@ -432,6 +734,29 @@ impl MagicSock {
         }
     }
 
+    /// Returns the current [`RelayMap`].
+    pub(crate) fn relay_map(&self) -> RelayMap {
+        self.relay_map.read().expect("lock poisoned").clone()
+    }
+
+    /// Changes the [`RelayMap`] using `f`, returning its result.
+    ///
+    /// Afterwards the home relay is picked again from the new map.  Connections to relay
+    /// servers which were removed are not closed straight away, they are closed once
+    /// inactive like any other non-home relay connection.
+    pub(crate) async fn update_relay_map<T>(&self, f: impl FnOnce(&mut RelayMap) -> T) -> T {
+        if self.direct_only {
+            warn!("not updating relay map, relays are disabled");
+            return f(&mut RelayMap::empty());
+        }
+        let res = f(&mut self.relay_map.write().expect("lock poisoned"));
+        self.actor_sender
+            .send(ActorMessage::RelayMapChanged)
+            .await
+            .ok();
+        res
+    }
+
     /// Call to notify the system of potential network changes.
     pub(crate) async fn network_change(&self) {
         self.actor_sender
@@ -440,11 +765,100 @@ impl MagicSock {
             .ok();
     }
 
+    /// Requests a full net report to be run.
+    pub(crate) async fn run_net_report(&self) {
+        self.actor_sender
+            .send(ActorMessage::RunNetReport)
+            .await
+            .ok();
+    }
+
+    /// Requests the UDP sockets to be rebound.
+    ///
+    /// The `reason` is only used for logging.
+    pub(crate) async fn rebind(&self, reason: &'static str) {
+        self.actor_sender
+            .send(ActorMessage::Rebind { reason })
+            .await
+            .ok();
+    }
+
+    /// Starts capturing packets to `writer` in the pcapng format.
+    #[cfg(not(wasm_browser))]
+    pub(crate) fn start_packet_capture(
+        &self,
+        writer: Box<dyn std::io::Write + Send>,
+    ) -> Result<()> {
+        self.capture
+            .start(writer)
+            .context("failed to start packet capture")
+    }
+
+    /// Stops capturing packets.
+    #[cfg(not(wasm_browser))]
+    pub(crate) fn stop_packet_capture(&self) -> Result<()> {
+        self.capture
+            .stop()
+            .context("failed to flush packet capture")
+    }
+
+    /// Describes a datagram for the packet capture.
+    ///
+    /// Disco messages are decrypted using the shared secret with `peer`, or with the sender
+    /// of the message if no peer is given.  The latter only works for received messages.
+    #[cfg(not(wasm_browser))]
+    fn capture_comment(&self, datagram: &[u8], peer: Option<PublicKey>) -> Option<String> {
+        if stun::is(datagram) {
+            return Some("stun".to_string());
+        }
+        let (sender, sealed_box) = disco::source_and_box(datagram)?;
+        let peer = peer.unwrap_or(sender);
+        let comment = match self.disco_secrets.unseal_and_decode(
+            &self.secret_encryption_key,
+            peer,
+            sealed_box.to_vec(),
+        ) {
+            Ok(msg) => format!("disco {msg}"),
+            Err(err) => format!("disco: {err}"),
+        };
+        Some(comment)
+    }
+
     /// Returns a reference to the subscribers channel for discovery events.
     pub(crate) fn discovery_subscribers(&self) -> &DiscoverySubscribers {
         &self.discovery_subscribers
     }
 
+    /// Returns a stream of the [`RelayEvent`]s of the relay connections.
+    pub(crate) fn relay_events(&self) -> impl Stream<Item = Result<RelayEvent, Lagged>> {
+        use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+        let recv = self.relay_events.subscribe();
+        BroadcastStream::new(recv).map_err(|BroadcastStreamRecvError::Lagged(n)| Lagged(n))
+    }
+
+    /// Returns a stream of the [`HolepunchEvent`]s of all remote nodes.
+    pub(crate) fn holepunch_events(&self) -> impl Stream<Item = Result<HolepunchEvent, Lagged>> {
+        use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+        let recv = self.node_map.holepunch_events();
+        BroadcastStream::new(recv).map_err(|BroadcastStreamRecvError::Lagged(n)| Lagged(n))
+    }
+
+    /// Returns a [`Watcher`] for the [`PortMappingStatus`].
+    #[cfg(not(wasm_browser))]
+    pub(crate) fn port_mapping_status(&self) -> Watcher<PortMappingStatus> {
+        self.port_mapping.watch()
+    }
+
+    /// Returns a stream of the [`PortMappingEvent`]s.
+    #[cfg(not(wasm_browser))]
+    pub(crate) fn port_mapping_events(
+        &self,
+    ) -> impl Stream<Item = Result<PortMappingEvent, Lagged>> {
+        use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+        let recv = self.port_mapping.subscribe();
+        BroadcastStream::new(recv).map_err(|BroadcastStreamRecvError::Lagged(n)| Lagged(n))
+    }
+
     #[cfg(test)]
     async fn force_network_change(&self, is_major: bool) {
         self.actor_sender
@@ -462,9 +876,47 @@ impl MagicSock {
     }
 
     /// Implementation for AsyncUdpSocket::try_send
-    #[instrument(skip_all)]
     fn try_send(&self, transmit: &quinn_udp::Transmit) -> io::Result<()> {
-        inc_by!(MagicsockMetrics, send_data, transmit.contents.len() as _);
+        self.try_send_rate_limited(transmit, true)
+    }
+
+    /// Sends the transmits delayed by the send rate limit or pacing once they are ready.
+    ///
+    /// Runs until the actor tasks are aborted.
+    async fn send_delayed(&self, queue: &impl rate_limiter::DelayQueue) {
+        loop {
+            let (ready, next) = queue.take_ready(Instant::now());
+            for transmit in ready {
+                if let Err(err) = self.try_send_rate_limited(&transmit.as_transmit(), false) {
+                    trace!("failed to send delayed transmit: {err:#}");
+                }
+            }
+            match next {
+                Some(next) => {
+                    tokio::select! {
+                        _ = queue.queued() => (),
+                        _ = time::sleep_until(next) => (),
+                    }
+                }
+                None => queue.queued().await,
+            }
+        }
+    }
+
+    /// Sends a QUIC transmit, applying the send rate limit and pacing only if `rate_limit`
+    /// is set.
+    ///
+    /// Transmits delayed by the rate limit or pacing are sent later without applying either
+    /// again.
+    #[instrument(skip_all)]
+    fn try_send_rate_limited(
+        &self,
+        transmit: &quinn_udp::Transmit,
+        rate_limit: bool,
+    ) -> io::Result<()> {
+        if rate_limit {
+            inc_by!(MagicsockMetrics, send_data, transmit.contents.len() as _);
+        }
 
         if self.is_closed() {
             inc_by!(
@@ -503,7 +955,7 @@ impl MagicSock {
                     .node_map
                     .get_send_addrs(dest, self.ipv6_reported.load(Ordering::Relaxed))
                 {
-                    Some((node_id, udp_addr, relay_url, msgs)) => {
+                    Some((node_id, udp_addrs, relay_url, msgs)) => {
                         let mut pings_sent = false;
                         // If we have pings to send, we *have* to send them out first.
                         if !msgs.is_empty() {
@@ -516,21 +968,71 @@ impl MagicSock {
                             pings_sent = true;
                         }
 
+                        if let Some(limiter) =
+                            self.send_rate_limiter.as_ref().filter(|_| rate_limit)
+                        {
+                            match limiter.check(node_id, &transmit) {
+                                rate_limiter::Limited::Send => (),
+                                rate_limiter::Limited::Queued => {
+                                    trace!(
+                                        node = %node_id.fmt_short(),
+                                        len = %transmit.contents.len(),
+                                        "send rate limit exceeded, delaying transmit",
+                                    );
+                                    inc!(MagicsockMetrics, send_rate_limited);
+                                    return Ok(());
+                                }
+                                rate_limiter::Limited::Dropped => {
+                                    // Like any other lost packet, QUIC will detect this.
+                                    trace!(
+                                        node = %node_id.fmt_short(),
+                                        len = %transmit.contents.len(),
+                                        "send rate limit queue full, dropping transmit",
+                                    );
+                                    inc!(MagicsockMetrics, send_rate_limited_dropped);
+                                    return Ok(());
+                                }
+                            }
+                        }
+
+                        if let Some(pacer) = self.pacer.as_ref().filter(|_| rate_limit) {
+                            let rtt = udp_addrs
+                                .first()
+                                .and_then(|(addr, _)| self.node_map.udp_path_rtt(dest, *addr));
+                            if let Some(rtt) = rtt {
+                                if pacer.check(node_id, &transmit, rtt) == pacer::Paced::Queued {
+                                    trace!(
+                                        node = %node_id.fmt_short(),
+                                        len = %transmit.contents.len(),
+                                        "pacing transmit",
+                                    );
+                                    inc!(MagicsockMetrics, send_paced);
+                                    return Ok(());
+                                }
+                            }
+                        }
+
                         let mut udp_sent = false;
                         let mut udp_error: Option<io::Error> = None;
+                        let mut udp_bytes = 0;
                         let mut relay_sent = false;
                         let mut relay_error = None;
 
-                        // send udp
+                        // send udp, to multiple addresses while racing candidate paths
+                        #[cfg(not(wasm_browser))]
+                        let src_ip = transmit.src_ip;
                         #[cfg(not(wasm_browser))]
-                        if let Some(addr) = udp_addr {
+                        for &(addr, local_ip) in &udp_addrs {
                             // rewrite target address
                             transmit.destination = addr;
+                            // pin the source address to the one the node sent to
+                            transmit.src_ip = local_ip.or(src_ip);
                             match self.try_send_udp(addr, &transmit) {
                                 Ok(()) => {
                                     trace!(node = %node_id.fmt_short(), dst = %addr,
                                    "sent transmit over UDP");
                                     udp_sent = true;
+                                    udp_bytes += transmit.contents.len();
                                 }
                                 Err(err) => {
                                     // No need to print "WouldBlock" errors to the console
@@ -548,8 +1050,10 @@ impl MagicSock {
 
                         // send relay
                         if let Some(ref relay_url) = relay_url {
-                            match self.try_send_relay(relay_url, node_id, split_packets(&transmit))
-                            {
+                            // QUIC datagrams are never sent with priority, so those of a
+                            // connection are not reordered.
+                            let contents = split_packets(&transmit);
+                            match self.try_send_relay(relay_url, node_id, contents, false) {
                                 Ok(()) => {
                                     relay_sent = true;
                                 }
@@ -575,9 +1079,15 @@ impl MagicSock {
                             return Err(io::Error::new(io::ErrorKind::WouldBlock, "pending"));
                         } else {
                             if relay_sent || udp_sent {
+                                let relay_bytes = if relay_sent {
+                                    transmit.contents.len()
+                                } else {
+                                    0
+                                };
+                                self.node_map.record_sent(dest, udp_bytes, relay_bytes);
                                 trace!(
                                     node = %node_id.fmt_short(),
-                                    send_udp = ?udp_addr,
+                                    send_udp = ?udp_addrs,
                                     send_relay = ?relay_url,
                                     "sent transmit",
                                 );
@@ -673,25 +1183,44 @@ impl MagicSock {
         }
     }
 
+    /// Queues datagrams to be sent over a relay server.
+    ///
+    /// Datagrams with `priority` are sent ahead of other datagrams queued for the relay
+    /// server.
     fn try_send_relay(
         &self,
         url: &RelayUrl,
         node: NodeId,
         contents: RelayContents,
+        priority: bool,
     ) -> io::Result<()> {
         trace!(
             node = %node.fmt_short(),
             relay_url = %url,
+            priority,
             count = contents.len(),
             len = contents.iter().map(|c| c.len()).sum::<usize>(),
             "send relay",
         );
+        #[cfg(not(wasm_browser))]
+        if self.capture.is_enabled() {
+            for datagram in &contents {
+                let comment = self.capture_comment(datagram, Some(node));
+                self.capture.record_relay(
+                    capture::Direction::Outbound,
+                    url,
+                    node,
+                    datagram,
+                    comment.as_deref(),
+                );
+            }
+        }
         let msg = RelaySendItem {
             remote_node: node,
             url: url.clone(),
             datagrams: contents,
         };
-        match self.relay_datagram_send_channel.try_send(msg) {
+        match self.relay_datagram_send_channel.try_send(msg, priority) {
             Ok(_) => {
                 trace!(node = %node.fmt_short(), relay_url = %url,
                        "send relay: message queued");
@@ -718,8 +1247,40 @@ impl MagicSock {
 
     #[cfg(not(wasm_browser))]
     fn try_send_udp(&self, addr: SocketAddr, transmit: &quinn_udp::Transmit) -> io::Result<()> {
+        let nat64_transmit;
+        let (addr, transmit) = match self.nat64_addr(addr) {
+            Some(nat64_addr) => {
+                nat64_transmit = quinn_udp::Transmit {
+                    destination: nat64_addr,
+                    src_ip: None,
+                    ..*transmit
+                };
+                (nat64_addr, &nat64_transmit)
+            }
+            None => (addr, transmit),
+        };
+        if let Some(turn) = self.turn.as_ref() {
+            if let Some(peer) = turn.peer_addr(addr) {
+                return self.try_send_udp_turn(turn, peer, transmit);
+            }
+        }
         let conn = self.conn_for_addr(addr)?;
         conn.try_send(transmit)?;
+        // Disco messages are captured with their decoded contents by the caller.
+        if self.capture.is_enabled() && !disco::looks_like_disco_wrapper(transmit.contents) {
+            if let Ok(local_addr) = conn.local_addr() {
+                let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+                for datagram in transmit.contents.chunks(segment_size.max(1)) {
+                    self.capture.record_udp(
+                        capture::Direction::Outbound,
+                        local_addr,
+                        addr,
+                        datagram,
+                        None,
+                    );
+                }
+            }
+        }
         let total_bytes: u64 = transmit.contents.len() as u64;
         if addr.is_ipv6() {
             inc_by!(MagicsockMetrics, send_ipv6, total_bytes);
@@ -729,6 +1290,53 @@ impl MagicSock {
         Ok(())
     }
 
+    /// Sends the datagrams of a transmit to `peer` through the TURN server.
+    #[cfg(not(wasm_browser))]
+    fn try_send_udp_turn(
+        &self,
+        turn: &turn::TurnClient,
+        peer: SocketAddr,
+        transmit: &quinn_udp::Transmit,
+    ) -> io::Result<()> {
+        let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+        for datagram in transmit.contents.chunks(segment_size.max(1)) {
+            self.try_send_turn(turn.server_addr(), &turn.wrap(peer, datagram))?;
+        }
+        inc_by!(MagicsockMetrics, send_turn, transmit.contents.len() as u64);
+        Ok(())
+    }
+
+    /// Sends a packet to the TURN server.
+    #[cfg(not(wasm_browser))]
+    fn try_send_turn(&self, server: SocketAddr, packet: &[u8]) -> io::Result<()> {
+        let transmit = quinn_udp::Transmit {
+            destination: server,
+            ecn: None,
+            contents: packet,
+            segment_size: None,
+            src_ip: None,
+        };
+        self.conn_for_addr(server)?.try_send(&transmit)
+    }
+
+    /// Returns the address reaching an IPv4 `addr` through the NAT64 gateway, if needed.
+    ///
+    /// IPv4 addresses are only synthesized on IPv6-only hosts which found a NAT64 prefix,
+    /// addresses which are not globally routable can not be reached through it.
+    #[cfg(not(wasm_browser))]
+    fn nat64_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        let SocketAddr::V4(v4) = addr else {
+            return None;
+        };
+        let ip = v4.ip();
+        if ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() {
+            return None;
+        }
+        self.sockets.v6.as_ref()?;
+        let prefix = (*self.nat64_prefix.read().expect("poisoned"))?;
+        Some(prefix.synthesize_socket_addr(addr))
+    }
+
     #[cfg(not(wasm_browser))]
     fn conn_for_addr(&self, addr: SocketAddr) -> io::Result<&UdpConn> {
         let sock = match addr {
@@ -829,12 +1437,29 @@ impl MagicSock {
         self.poll_recv_relay(cx, bufs, metas)
     }
 
+    /// Processes a datagram as if it was received on a UDP socket.
+    ///
+    /// Returns the [`quinn_udp::RecvMeta`] as it would be passed on to Quinn.
+    #[cfg(all(not(wasm_browser), any(test, feature = "test-utils")))]
+    pub(crate) fn inject_udp_datagram(
+        &self,
+        meta: quinn_udp::RecvMeta,
+        payload: &mut [u8],
+    ) -> quinn_udp::RecvMeta {
+        assert!(meta.len <= payload.len(), "meta.len exceeds the payload");
+        assert!(meta.stride > 0, "meta.stride must not be zero");
+        let mut metas = [meta];
+        let mut bufs = [io::IoSliceMut::new(payload)];
+        self.process_udp_datagrams(meta.addr.is_ipv4(), &mut bufs, &mut metas);
+        metas[0]
+    }
+
     /// Process datagrams received from UDP sockets.
     ///
     /// All the `bufs` and `metas` should have initialized packets in them.
     ///
     /// This fixes up the datagrams to use the correct [`NodeIdMappedAddr`] and extracts DISCO
-    /// packets, processing them inside the magic socket.
+    /// packets, handing them to the DISCO workers.
     #[cfg(not(wasm_browser))]
     fn process_udp_datagrams(
         &self,
@@ -850,11 +1475,8 @@ impl MagicSock {
         // here so that Quinn would send on the right address.  But that would sometimes
         // result in the wrong address family and Windows trips up on that.
         //
-        // What should be done is that this dst_ip from the RecvMeta is stored in the
-        // NodeState/PathState.  Then on the send path it should be retrieved from the
-        // NodeState/PathSate together with the send address and substituted at send time.
-        // This is relevant for IPv6 link-local addresses where the OS otherwise does not
-        // know which intervace to send from.
+        // Instead the dst_ip from the RecvMeta is stored in the PathState when bound to the
+        // unspecified address, and substituted as the source address at send time.
         #[cfg(not(windows))]
         let dst_ip = self.normalized_local_addr().ok().map(|addr| addr.ip());
         // Reasoning for this here:
@@ -862,11 +1484,46 @@ impl MagicSock {
         #[cfg(windows)]
         let dst_ip = None;
 
+        // When bound to the unspecified address on a multi-homed host the routing table
+        // picks the source address of sent datagrams, which need not be the address the
+        // remote sent to.  Record the address datagrams were received on so that sends on
+        // the path can be pinned to it.
+        let local_addr = if from_ipv4 {
+            Some(self.local_addr().0)
+        } else {
+            self.local_addr().1
+        };
+        let pin_src_ip = local_addr.is_some_and(|addr| addr.ip().is_unspecified());
+
         let mut quic_packets_total = 0;
 
+        // Datagrams from IPv4 nodes reached through NAT64 are attributed to their IPv4
+        // address, as known to the node map.
+        let nat64_prefix = match from_ipv4 {
+            true => None,
+            false => *self.nat64_prefix.read().expect("poisoned"),
+        };
+
+        // The local address to record captured datagrams with, if capturing.
+        let capture_local_addr = local_addr.filter(|_| self.capture.is_enabled());
+
         for (meta, buf) in metas.iter_mut().zip(bufs.iter_mut()) {
+            if let Some(ref turn) = self.turn {
+                if meta.addr == turn.server_addr() {
+                    if !turn.unwrap_datagrams(meta, buf) {
+                        meta.len = 0;
+                        continue;
+                    }
+                    inc_by!(MagicsockMetrics, recv_turn, meta.len as _);
+                }
+            }
+            let local_ip = meta.dst_ip.filter(|_| pin_src_ip);
+            let src = nat64_prefix
+                .and_then(|prefix| prefix.extract_socket_addr(meta.addr))
+                .unwrap_or(meta.addr);
             let mut buf_contains_quic_datagrams = false;
             let mut quic_datagram_count = 0;
+            let mut quic_datagram_bytes = 0;
             if meta.len > meta.stride {
                 trace!(%meta.len, %meta.stride, "GRO datagram received");
                 inc!(MagicsockMetrics, recv_gro_datagrams);
@@ -883,10 +1540,30 @@ impl MagicSock {
                     );
                 }
 
+                if let Some(local_addr) = capture_local_addr {
+                    let comment = self.capture_comment(datagram, None);
+                    self.capture.record_udp(
+                        capture::Direction::Inbound,
+                        local_addr,
+                        meta.addr,
+                        datagram,
+                        comment.as_deref(),
+                    );
+                }
+
                 // Detect DISCO and STUN datagrams and process them.  Overwrite the first
                 // byte of those packets with zero to make Quinn ignore the packet.  This
                 // relies on quinn::EndpointConfig::grease_quic_bit being set to `false`,
-                // which we do in Endpoint::bind.
+                // which we do in Endpoint::bind.  Datagrams rejected by the packet filter
+                // are dropped the same way.
+                if let Some(ref filter) = self.packet_filter {
+                    if !filter.accept(meta.addr, datagram) {
+                        trace!(src = %meta.addr, len = %datagram.len(), "UDP recv: filtered");
+                        inc!(MagicsockMetrics, recv_filtered);
+                        datagram[0] = 0u8;
+                        continue;
+                    }
+                }
                 if stun::is(datagram) {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: stun packet");
                     let packet2 = Bytes::copy_from_slice(datagram);
@@ -894,11 +1571,20 @@ impl MagicSock {
                     datagram[0] = 0u8;
                 } else if let Some((sender, sealed_box)) = disco::source_and_box(datagram) {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: disco packet");
-                    self.handle_disco_message(
+                    let job = disco_pool::DiscoJob {
                         sender,
-                        sealed_box,
-                        DiscoMessageSource::Udp(meta.addr),
-                    );
+                        sealed_box: sealed_box.to_vec(),
+                        src: DiscoMessageSource::Udp(src),
+                        local_ip,
+                    };
+                    if self.node_map.has_udp_path(src) {
+                        self.disco_pool.submit(job);
+                    } else {
+                        // QUIC datagrams from an unknown path are dropped, a ping from a
+                        // new path is handled right away so the path exists for the QUIC
+                        // datagrams following it in this batch.
+                        self.handle_disco_job(job);
+                    }
                     datagram[0] = 0u8;
                 } else {
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: quic packet");
@@ -907,14 +1593,21 @@ impl MagicSock {
                     } else {
                         inc_by!(MagicsockMetrics, recv_data_ipv6, datagram.len() as _);
                     }
+                    if meta.ecn == Some(quinn_udp::EcnCodepoint::Ce) {
+                        inc!(MagicsockMetrics, recv_ecn_ce);
+                    }
                     quic_datagram_count += 1;
+                    quic_datagram_bytes += datagram.len();
                     buf_contains_quic_datagrams = true;
                 };
             }
 
             if buf_contains_quic_datagrams {
                 // Update the NodeMap and remap RecvMeta to the NodeIdMappedAddr.
-                match self.node_map.receive_udp(meta.addr) {
+                match self
+                    .node_map
+                    .receive_udp(src, local_ip, quic_datagram_bytes)
+                {
                     None => {
                         // Check if this address is mapped to an IpMappedAddr
                         if let Some(ip_mapped_addr) =
@@ -963,6 +1656,19 @@ impl MagicSock {
 
         if quic_packets_total > 0 {
             inc_by!(MagicsockMetrics, recv_datagrams, quic_packets_total as _);
+            if from_ipv4 {
+                inc_by!(
+                    MagicsockMetrics,
+                    recv_datagrams_ipv4,
+                    quic_packets_total as _
+                );
+            } else {
+                inc_by!(
+                    MagicsockMetrics,
+                    recv_datagrams_ipv6,
+                    quic_packets_total as _
+                );
+            }
             trace!("UDP recv: {} packets", quic_packets_total);
         }
     }
@@ -1045,12 +1751,24 @@ impl MagicSock {
             return None;
         }
 
+        #[cfg(not(wasm_browser))]
+        if self.capture.is_enabled() {
+            let comment = self.capture_comment(&dm.buf, None);
+            self.capture.record_relay(
+                capture::Direction::Inbound,
+                &dm.url,
+                dm.src,
+                &dm.buf,
+                comment.as_deref(),
+            );
+        }
+
         if self.handle_relay_disco_message(&dm.buf, &dm.url, dm.src) {
             // DISCO messages are handled internally in the MagicSock, do not pass to Quinn.
             return None;
         }
 
-        let quic_mapped_addr = self.node_map.receive_relay(&dm.url, dm.src);
+        let quic_mapped_addr = self.node_map.receive_relay(&dm.url, dm.src, dm.buf.len());
 
         // Normalize local_ip
         #[cfg(not(any(windows, wasm_browser)))]
@@ -1060,6 +1778,8 @@ impl MagicSock {
         #[cfg(any(windows, wasm_browser))]
         let dst_ip = None;
 
+        // The relay carries datagrams over a reliable stream which does not forward the ECN
+        // bits, so there is no codepoint to report.
         let meta = quinn_udp::RecvMeta {
             len: dm.buf.len(),
             stride: dm.buf.len(),
@@ -1082,23 +1802,49 @@ impl MagicSock {
                     // TODO: return here?
                     warn!("Received relay disco message from connection for {}, but with message from {}", relay_node_src.fmt_short(), source.fmt_short());
                 }
-                self.handle_disco_message(
-                    source,
-                    sealed_box,
-                    DiscoMessageSource::Relay {
+                self.disco_pool.submit(disco_pool::DiscoJob {
+                    sender: source,
+                    sealed_box: sealed_box.to_vec(),
+                    src: DiscoMessageSource::Relay {
                         url: url.clone(),
                         key: relay_node_src,
                     },
-                );
+                    local_ip: None,
+                });
                 true
             }
             None => false,
         }
     }
 
+    /// Handles a discovery message taken from the [`disco_pool::DiscoPool`].
+    fn handle_disco_job(&self, job: disco_pool::DiscoJob) {
+        let disco_pool::DiscoJob {
+            sender,
+            sealed_box,
+            src,
+            local_ip,
+        } = job;
+        let udp_addr = match src {
+            DiscoMessageSource::Udp(addr) => Some(addr),
+            DiscoMessageSource::Relay { .. } => None,
+        };
+        self.handle_disco_message(sender, sealed_box, src);
+        if let (Some(addr), Some(local_ip)) = (udp_addr, local_ip) {
+            // Pings from the node create the path when handled, so this is only
+            // recorded after handling the DISCO message.
+            self.node_map.set_udp_local_ip(addr, local_ip);
+        }
+    }
+
     /// Handles a discovery message.
     #[instrument("disco_in", skip_all, fields(node = %sender.fmt_short(), %src))]
-    fn handle_disco_message(&self, sender: PublicKey, sealed_box: &[u8], src: DiscoMessageSource) {
+    fn handle_disco_message(
+        &self,
+        sender: PublicKey,
+        sealed_box: Vec<u8>,
+        src: DiscoMessageSource,
+    ) {
         trace!("handle_disco_message start");
         if self.is_closed() {
             return;
@@ -1106,10 +1852,11 @@ impl MagicSock {
 
         // We're now reasonably sure we're expecting communication from
         // this node, do the heavy crypto lifting to see what they want.
+        let nonce = disco_replay::nonce(&sealed_box);
         let dm = match self.disco_secrets.unseal_and_decode(
             &self.secret_encryption_key,
             sender,
-            sealed_box.to_vec(),
+            sealed_box,
         ) {
             Ok(dm) => dm,
             Err(DiscoBoxError::Open(err)) => {
@@ -1130,6 +1877,15 @@ impl MagicSock {
             }
         };
 
+        // The box was opened, so it has a nonce.
+        if let Some(nonce) = nonce {
+            if !self.disco_replay.check(sender, nonce) {
+                debug!(node = %sender.fmt_short(), "dropping replayed disco message");
+                inc!(MagicsockMetrics, recv_disco_replayed);
+                return;
+            }
+        }
+
         if src.is_relay() {
             inc!(MagicsockMetrics, recv_disco_relay);
         } else {
@@ -1146,43 +1902,86 @@ impl MagicSock {
             }
             disco::Message::Pong(pong) => {
                 inc!(MagicsockMetrics, recv_disco_pong);
+                if let SendAddr::Udp(observed) = &pong.ping_observed_addr {
+                    if self
+                        .predicted_addrs
+                        .lock()
+                        .expect("poisoned")
+                        .remove(observed)
+                    {
+                        debug!(%observed, node = %sender.fmt_short(), "predicted address confirmed");
+                        inc!(MagicsockMetrics, port_prediction_hit);
+                    }
+                }
                 self.node_map.handle_pong(sender, &src, pong);
             }
             disco::Message::CallMeMaybe(cm) => {
                 inc!(MagicsockMetrics, recv_disco_call_me_maybe);
-                match src {
-                    DiscoMessageSource::Relay { url, .. } => {
-                        event!(
-                            target: "iroh::_events::call-me-maybe::recv",
-                            Level::DEBUG,
-                            remote_node = sender.fmt_short(),
-                            via = ?url,
-                            their_addrs = ?cm.my_numbers,
-                        );
-                    }
-                    _ => {
-                        warn!("call-me-maybe packets should only come via relay");
-                        return;
-                    }
+                self.handle_call_me_maybe(cm, sender, src);
+            }
+        }
+        trace!("disco message handled");
+    }
+
+    /// Returns whether the DISCO messages of `sender` are answered, see [`DiscoTrust`].
+    fn is_disco_trusted(&self, sender: NodeId, src: &DiscoMessageSource) -> bool {
+        let Some(ref trust) = self.disco_trust else {
+            return true;
+        };
+        if self.node_map.is_disco_trusted(sender) {
+            return true;
+        }
+        let ping_src = match src {
+            DiscoMessageSource::Udp(addr) => PingSource::Udp(*addr),
+            DiscoMessageSource::Relay { url, .. } => PingSource::Relay(url.clone()),
+        };
+        trust.trust_ping(sender, &ping_src)
+    }
+
+    /// Handle a call-me-maybe message, pinging the addresses it contains.
+    fn handle_call_me_maybe(&self, cm: CallMeMaybe, sender: NodeId, src: DiscoMessageSource) {
+        match src {
+            DiscoMessageSource::Relay { ref url, .. } => {
+                event!(
+                    target: "iroh::_events::call-me-maybe::recv",
+                    Level::DEBUG,
+                    remote_node = sender.fmt_short(),
+                    via = ?url,
+                    their_addrs = ?cm.my_numbers,
+                );
+            }
+            _ => {
+                warn!("call-me-maybe packets should only come via relay");
+                return;
+            }
+        }
+        // The pings would reveal our addresses to the node, like answering its pings.
+        if !self.is_disco_trusted(sender, &src) {
+            debug!(%src, node = %sender.fmt_short(), "received call-me-maybe: untrusted node, skip");
+            inc!(MagicsockMetrics, recv_disco_call_me_maybe_untrusted);
+            return;
+        }
+        let ping_actions = self.node_map.handle_call_me_maybe(sender, cm);
+        for action in ping_actions {
+            match action {
+                PingAction::SendCallMeMaybe { .. } => {
+                    warn!("Unexpected CallMeMaybe as response of handling a CallMeMaybe");
                 }
-                let ping_actions = self.node_map.handle_call_me_maybe(sender, cm);
-                for action in ping_actions {
-                    match action {
-                        PingAction::SendCallMeMaybe { .. } => {
-                            warn!("Unexpected CallMeMaybe as response of handling a CallMeMaybe");
-                        }
-                        PingAction::SendPing(ping) => {
-                            self.send_ping_queued(ping);
-                        }
-                    }
+                PingAction::SendPing(ping) => {
+                    self.send_ping_queued(ping);
                 }
             }
         }
-        trace!("disco message handled");
     }
 
     /// Handle a ping message.
     fn handle_ping(&self, dm: disco::Ping, sender: NodeId, src: DiscoMessageSource) {
+        if !self.is_disco_trusted(sender, &src) {
+            debug!(%src, node = %sender.fmt_short(), "received ping: untrusted node, skip");
+            inc!(MagicsockMetrics, recv_disco_ping_untrusted);
+            return;
+        }
+
         // Insert the ping into the node map, and return whether a ping with this tx_id was already
         // received.
         let addr: SendAddr = src.clone().into();
@@ -1240,12 +2039,17 @@ impl MagicSock {
     }
 
     fn send_ping_queued(&self, ping: SendPing) {
+        if !ping.delay.is_zero() {
+            self.schedule_ping(ping);
+            return;
+        }
         let SendPing {
             id,
             dst,
             dst_node,
             tx_id,
             purpose,
+            delay: _,
         } = ping;
         let msg = disco::Message::Ping(disco::Ping {
             tx_id,
@@ -1348,7 +2152,7 @@ impl MagicSock {
         debug!(node = %dst.fmt_short(), %url, %msg, "send disco message (relay)");
         let pkt = self.encode_disco_message(dst, &msg);
         inc!(MagicsockMetrics, send_disco_relay);
-        match self.try_send_relay(url, dst, smallvec![pkt]) {
+        match self.try_send_relay(url, dst, smallvec![pkt], true) {
             Ok(()) => {
                 if let disco::Message::CallMeMaybe(CallMeMaybe { ref my_numbers }) = msg {
                     event!(
@@ -1380,8 +2184,12 @@ impl MagicSock {
                     Ok(()) => return Poll::Ready(Ok(())),
                     Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
                         // This is the socket .try_send_disco_message_udp used.
-                        let sock = self.conn_for_addr(dst)?;
-                        match sock.as_socket_ref().poll_writable(cx) {
+                        let sock_addr = match self.turn {
+                            Some(ref turn) if turn.peer_addr(dst).is_some() => turn.server_addr(),
+                            _ => dst,
+                        };
+                        let sock = self.conn_for_addr(sock_addr)?;
+                        match sock.socket().poll_writable(cx) {
                             Poll::Ready(Ok(())) => continue,
                             Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
                             Poll::Pending => return Poll::Pending,
@@ -1408,6 +2216,11 @@ impl MagicSock {
                 "connection closed",
             ));
         }
+        if let (Some(turn), disco::Message::Ping(_)) = (&self.turn, msg) {
+            // Holepunching pings go to the addresses the node advertised, allow the node to
+            // reach us from those through the TURN server as well.
+            turn.add_permission(dst);
+        }
         let pkt = self.encode_disco_message(dst_node, msg);
         // TODO: These metrics will be wrong with the poll impl
         // Also - do we need it? I'd say the `sent_disco_udp` below is enough.
@@ -1417,12 +2230,23 @@ impl MagicSock {
             contents: &pkt,
             ecn: None,
             segment_size: None,
-            src_ip: None, // TODO
+            src_ip: self.node_map.udp_local_ip(dst),
         };
         let sent = self.try_send_udp(dst, &transmit);
         match sent {
             Ok(()) => {
                 trace!(%dst, node = %dst_node.fmt_short(), %msg, "sent disco message");
+                if self.capture.is_enabled() {
+                    if let Ok(local_addr) = self.conn_for_addr(dst).and_then(|c| c.local_addr()) {
+                        self.capture.record_udp(
+                            capture::Direction::Outbound,
+                            local_addr,
+                            dst,
+                            &pkt,
+                            Some(&format!("disco {msg}")),
+                        );
+                    }
+                }
                 inc!(MagicsockMetrics, sent_disco_udp);
                 disco_message_sent(msg);
                 Ok(())
@@ -1447,12 +2271,17 @@ impl MagicSock {
     }
 
     fn try_send_ping(&self, ping: SendPing) -> io::Result<()> {
+        if !ping.delay.is_zero() {
+            self.schedule_ping(ping);
+            return Ok(());
+        }
         let SendPing {
             id,
             dst,
             dst_node,
             tx_id,
             purpose,
+            delay: _,
         } = ping;
         let msg = disco::Message::Ping(disco::Ping {
             tx_id,
@@ -1466,8 +2295,64 @@ impl MagicSock {
         Ok(())
     }
 
+    /// Schedules a paced holepunching ping to be sent after its delay.
+    fn schedule_ping(&self, ping: SendPing) {
+        let id = ping.id;
+        let at = Instant::now() + ping.delay;
+        if self.paced_ping_sender.try_send((at, ping)).is_err() {
+            warn!("failed to schedule ping: queue full");
+            self.node_map.paced_probe_dropped(id);
+        }
+    }
+
+    /// Whether the [`AddrFilter`] allows advertising the address.
+    fn advertise_addr(&self, addr: SocketAddr, typ: DirectAddrType) -> bool {
+        self.addr_filter
+            .as_ref()
+            .map_or(true, |filter| filter.advertise(&DirectAddr { addr, typ }))
+    }
+
+    /// Builds the call-me-maybe message with our direct addresses.
+    ///
+    /// If port prediction is enabled and the last net report found a NAT with sequential
+    /// port allocation, the next public ports the NAT is likely to allocate are added.  The
+    /// remote node pings them while we ping it, one of the pings opening the mapping the
+    /// other side's pings are sent to.
+    fn call_me_maybe_message(&self) -> disco::CallMeMaybe {
+        let mut msg = self.direct_addrs.to_call_me_maybe_message();
+        let Some(count) = self.port_prediction else {
+            return msg;
+        };
+        let predicted: BTreeSet<SocketAddr> = self
+            .net_report
+            .get()
+            .and_then(|report| {
+                let ip = *report.global_v4?.ip();
+                let ports = report.port_allocation()?.predict_ports(count);
+                Some(
+                    ports
+                        .into_iter()
+                        .map(|port| SocketAddr::from((ip, port)))
+                        .filter(|addr| self.advertise_addr(*addr, DirectAddrType::Stun))
+                        .collect(),
+                )
+            })
+            .unwrap_or_default();
+        if !predicted.is_empty() {
+            debug!(addrs = ?predicted, "adding predicted addresses to call-me-maybe");
+            inc!(MagicsockMetrics, port_prediction_sent);
+            for addr in &predicted {
+                if !msg.my_numbers.contains(addr) {
+                    msg.my_numbers.push(*addr);
+                }
+            }
+        }
+        *self.predicted_addrs.lock().expect("poisoned") = predicted;
+        msg
+    }
+
     fn send_queued_call_me_maybes(&self) {
-        let msg = self.direct_addrs.to_call_me_maybe_message();
+        let msg = self.call_me_maybe_message();
         let msg = disco::Message::CallMeMaybe(msg);
         for (public_key, url) in self
             .pending_call_me_maybes
@@ -1489,7 +2374,7 @@ impl MagicSock {
     fn send_or_queue_call_me_maybe(&self, url: &RelayUrl, dst_node: NodeId) {
         match self.direct_addrs.fresh_enough() {
             Ok(()) => {
-                let msg = self.direct_addrs.to_call_me_maybe_message();
+                let msg = self.call_me_maybe_message();
                 let msg = disco::Message::CallMeMaybe(msg);
                 if !self.send_disco_message_relay(url, dst_node, msg) {
                     warn!(dstkey = %dst_node.fmt_short(), relayurl = %url,
@@ -1675,12 +2560,41 @@ impl Handle {
             relay_map,
             relay_protocol,
             node_map,
+            direct_only,
             discovery,
             discovery_user_data,
             #[cfg(not(wasm_browser))]
             dns_resolver,
             proxy_url,
+            #[cfg(not(wasm_browser))]
+            relay_tls_config,
             server_config,
+            max_udp_payload_size,
+            #[cfg(not(wasm_browser))]
+            packet_filter,
+            send_rate_limit,
+            send_pacing,
+            disco_config,
+            relay_reconnect,
+            relay_keepalive,
+            relay_standby,
+            recv_packet_budget,
+            #[cfg(not(wasm_browser))]
+            recv_limits,
+            #[cfg(not(wasm_browser))]
+            stun_servers,
+            #[cfg(not(wasm_browser))]
+            stun_overflow_policy,
+            net_report_schedule,
+            net_report_limits,
+            relay_selector,
+            port_prediction,
+            addr_filter,
+            disco_trust,
+            #[cfg(not(wasm_browser))]
+            turn_server,
+            #[cfg(not(wasm_browser))]
+            port_mapping,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
             #[cfg(any(test, feature = "test-utils"))]
@@ -1688,34 +2602,125 @@ impl Handle {
         } = opts;
 
         #[cfg(not(wasm_browser))]
-        let actor_sockets = ActorSocketState::bind(addr_v4, addr_v6)?;
+        let actor_sockets = ActorSocketState::bind(addr_v4, addr_v6, port_mapping)?;
 
         #[cfg(not(wasm_browser))]
-        let sockets = actor_sockets.msock_socket_state()?;
+        let sockets = actor_sockets.msock_socket_state(recv_limits)?;
 
         let ip_mapped_addrs = IpMappedAddresses::default();
 
         let net_reporter = net_report::Client::new(
             #[cfg(not(wasm_browser))]
-            Some(actor_sockets.port_mapper.clone()),
+            actor_sockets
+                .port_mapping_enabled
+                .then(|| actor_sockets.port_mapper.clone()),
             #[cfg(not(wasm_browser))]
             dns_resolver.clone(),
             #[cfg(not(wasm_browser))]
             Some(ip_mapped_addrs.clone()),
         )?;
+        #[cfg(not(wasm_browser))]
+        net_reporter.set_stun_overflow_policy(stun_overflow_policy);
 
         let (actor_sender, actor_receiver) = mpsc::channel(256);
         let (relay_actor_sender, relay_actor_receiver) = mpsc::channel(256);
         let (relay_datagram_send_tx, relay_datagram_send_rx) = relay_datagram_send_channel();
         let relay_datagram_recv_queue = Arc::new(RelayDatagramRecvQueue::new());
         let (udp_disco_sender, mut udp_disco_receiver) = mpsc::channel(256);
+        let (paced_ping_sender, mut paced_ping_receiver) = mpsc::channel(256);
+        let (disco_pool, disco_receivers) = disco_pool::DiscoPool::new(disco_pool::DISCO_WORKERS);
+        #[cfg(not(wasm_browser))]
+        let (turn, turn_runner) = turn_server.map(turn::TurnClient::new).unzip();
+
+        // A path must stay trusted across a missed keepalive, or it would be demoted to the
+        // relay between every two keepalives.
+        ensure!(
+            !disco_config.interval.is_zero(),
+            "the keepalive interval must not be zero"
+        );
+        ensure!(
+            disco_config.idle_timeout > disco_config.interval,
+            "the path idle timeout ({:?}) must be larger than the keepalive interval ({:?})",
+            disco_config.idle_timeout,
+            disco_config.interval,
+        );
+        ensure!(
+            !disco_config.ping_interval.is_zero() && !disco_config.ping_timeout.is_zero(),
+            "the disco ping interval and timeout must not be zero"
+        );
+        ensure!(
+            !disco_config.replay_max_age.is_zero(),
+            "the disco replay max age must not be zero"
+        );
+        ensure!(
+            (1..=node_map::MAX_PROBE_ROUNDS).contains(&disco_config.probe_rounds),
+            "the holepunching probe rounds must be between 1 and {}",
+            node_map::MAX_PROBE_ROUNDS
+        );
+        ensure!(
+            disco_config.probe_rounds == 1 || !disco_config.probe_spacing.is_zero(),
+            "multiple holepunching probe rounds need a non-zero probe spacing"
+        );
+        ensure!(
+            !relay_reconnect.initial_delay.is_zero(),
+            "the initial relay reconnect delay must not be zero"
+        );
+        ensure!(
+            relay_reconnect.initial_delay <= relay_reconnect.max_delay,
+            "the initial relay reconnect delay ({:?}) must not be larger than the maximum delay ({:?})",
+            relay_reconnect.initial_delay,
+            relay_reconnect.max_delay,
+        );
+        ensure!(
+            relay_reconnect.max_attempts > 0,
+            "the relay reconnect attempts must be at least 1"
+        );
+        ensure!(
+            !relay_keepalive.ping_interval.is_zero(),
+            "the relay ping interval must not be zero"
+        );
+        ensure!(
+            !relay_keepalive.idle_timeout.is_zero(),
+            "the relay idle timeout must not be zero"
+        );
+        ensure!(
+            !matches!(&net_report_schedule.interval, Some(interval) if interval.start().is_zero()),
+            "the net report interval must not be zero"
+        );
+        ensure!(
+            net_report_schedule.holepunch_failures != Some(0),
+            "the hole punching failures before a net report must be at least 1"
+        );
+        ensure!(
+            !net_report_limits.probe_timeout.is_zero()
+                && !net_report_limits.report_timeout.is_zero(),
+            "the net report timeouts must not be zero"
+        );
+        ensure!(
+            net_report_limits.max_concurrent_probes != Some(0),
+            "the concurrent net report probes must be at least 1"
+        );
+        ensure!(
+            matches!(port_prediction, None | Some(1..=MAX_PREDICTED_PORTS)),
+            "the predicted ports must be between 1 and {MAX_PREDICTED_PORTS}"
+        );
+        ensure!(
+            send_rate_limit.map_or(true, |limit| limit.bytes_per_second > 0
+                && limit.burst >= rate_limiter::MIN_BURST),
+            "the send rate limit must not be zero and its burst at least {} bytes",
+            rate_limiter::MIN_BURST
+        );
+        ensure!(
+            send_pacing.map_or(true, |pacing| pacing.quantum > 0),
+            "the send pacing quantum must be at least 1"
+        );
 
         // load the node data
         let node_map = node_map.unwrap_or_default();
         #[cfg(any(test, feature = "test-utils"))]
-        let node_map = NodeMap::load_from_vec(node_map, path_selection);
+        let node_map = NodeMap::load_from_vec(node_map, disco_config, path_selection);
         #[cfg(not(any(test, feature = "test-utils")))]
-        let node_map = NodeMap::load_from_vec(node_map);
+        let node_map = NodeMap::load_from_vec(node_map, disco_config);
 
         let secret_encryption_key = secret_ed_box(secret_key.secret());
 
@@ -1724,6 +2729,9 @@ impl Handle {
             secret_key,
             secret_encryption_key,
             proxy_url,
+            direct_only,
+            #[cfg(not(wasm_browser))]
+            relay_tls_config,
             #[cfg(not(wasm_browser))]
             sockets,
             closing: AtomicBool::new(false),
@@ -1731,25 +2739,56 @@ impl Handle {
             relay_datagram_recv_queue: relay_datagram_recv_queue.clone(),
             relay_datagram_send_channel: relay_datagram_send_tx,
             poll_recv_counter: AtomicUsize::new(0),
+            recv_budget: RecvBudget::new(recv_packet_budget.unwrap_or(DEFAULT_RECV_PACKET_BUDGET)),
             actor_sender: actor_sender.clone(),
             ipv6_reported: Arc::new(AtomicBool::new(false)),
-            relay_map,
+            nat64_prefix: Default::default(),
+            relay_map: RwLock::new(relay_map),
             my_relay: Default::default(),
+            connectivity: Default::default(),
+            net_report: Default::default(),
+            relay_probes: Default::default(),
             net_reporter: net_reporter.addr(),
             disco_secrets: DiscoSecrets::default(),
+            disco_replay: ReplayWindows::new(
+                disco_config.replay_window,
+                disco_config.replay_max_age,
+            ),
             node_map,
             ip_mapped_addrs,
             udp_disco_sender,
+            paced_ping_sender,
+            disco_pool,
             discovery,
             discovery_user_data: RwLock::new(discovery_user_data),
             direct_addrs: Default::default(),
             pending_call_me_maybes: Default::default(),
+            port_prediction,
+            predicted_addrs: Default::default(),
+            addr_filter,
+            disco_trust,
+            #[cfg(not(wasm_browser))]
+            port_mapping: Default::default(),
             direct_addr_update_state: DirectAddrUpdateState::new(),
             #[cfg(not(wasm_browser))]
             dns_resolver,
+            #[cfg(not(wasm_browser))]
+            packet_filter,
+            #[cfg(not(wasm_browser))]
+            turn,
+            #[cfg(not(wasm_browser))]
+            manual_direct_addrs: Default::default(),
+            #[cfg(not(wasm_browser))]
+            capture: Default::default(),
+            send_rate_limiter: send_rate_limit.map(rate_limiter::RateLimiter::new),
+            pacer: send_pacing.map(pacer::Pacer::new),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
             discovery_subscribers: DiscoverySubscribers::new(),
+            relay_reconnect,
+            relay_keepalive,
+            relay_standby,
+            relay_events: sync::broadcast::Sender::new(RELAY_EVENTS_CAPACITY),
         });
 
         let mut endpoint_config = quinn::EndpointConfig::default();
@@ -1759,6 +2798,11 @@ impl Handle {
         // through to quinn. We set the first byte of the packet to zero, which makes quinn ignore
         // the packet if grease_quic_bit is set to false.
         endpoint_config.grease_quic_bit(false);
+        if let Some(size) = max_udp_payload_size {
+            endpoint_config
+                .max_udp_payload_size(size)
+                .with_context(|| format!("invalid max UDP payload size: {size}"))?;
+        }
 
         let endpoint = quinn::Endpoint::new_with_abstract_socket(
             endpoint_config,
@@ -1783,6 +2827,83 @@ impl Handle {
             .instrument(info_span!("relay-actor")),
         );
 
+        // Sends the transmits delayed by the send rate limit.
+        if msock.send_rate_limiter.is_some() {
+            let msock = msock.clone();
+            actor_tasks.spawn(
+                async move {
+                    let limiter = msock.send_rate_limiter.as_ref().expect("checked");
+                    msock.send_delayed(limiter).await
+                }
+                .instrument(info_span!("send-rate-limiter")),
+            );
+        }
+
+        // Sends the transmits delayed by pacing.
+        if msock.pacer.is_some() {
+            let msock = msock.clone();
+            actor_tasks.spawn(
+                async move {
+                    let pacer = msock.pacer.as_ref().expect("checked");
+                    msock.send_delayed(pacer).await
+                }
+                .instrument(info_span!("send-pacer")),
+            );
+        }
+
+        #[cfg(not(wasm_browser))]
+        if let Some(turn_runner) = turn_runner {
+            let msock = msock.clone();
+            actor_tasks.spawn(turn_runner.run(msock).instrument(info_span!("turn-client")));
+        }
+
+        for (i, mut receiver) in disco_receivers.into_iter().enumerate() {
+            let msock = msock.clone();
+            actor_tasks.spawn(
+                async move {
+                    while let Some(job) = disco_pool::next_job(&mut receiver).await {
+                        msock.handle_disco_job(job);
+                    }
+                }
+                .instrument(info_span!("disco-worker", worker = i)),
+            );
+        }
+
+        let _ = actor_tasks.spawn({
+            let msock = msock.clone();
+            async move {
+                let mut scheduled = BTreeMap::new();
+                let mut seq = 0u64;
+                loop {
+                    let next = scheduled.keys().next().map(|(at, _)| *at);
+                    tokio::select! {
+                        item = paced_ping_receiver.recv() => {
+                            let Some((at, ping)) = item else {
+                                break;
+                            };
+                            // The sequence number keeps pings due at the same time apart.
+                            scheduled.insert((at, seq), ping);
+                            seq = seq.wrapping_add(1);
+                        }
+                        _ = time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                            let now = Instant::now();
+                            while let Some(entry) = scheduled.first_entry() {
+                                if entry.key().0 > now {
+                                    break;
+                                }
+                                let mut ping: SendPing = entry.remove();
+                                if msock.node_map.paced_probe_due(ping.id, &ping.dst) {
+                                    ping.delay = Duration::ZERO;
+                                    msock.send_ping_queued(ping);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            .instrument(info_span!("paced-pings"))
+        });
+
         #[cfg(not(wasm_browser))]
         let _ = actor_tasks.spawn({
             let msock = msock.clone();
@@ -1798,16 +2919,10 @@ impl Handle {
         let network_monitor = netmon::Monitor::new().await?;
         let qad_endpoint = endpoint.clone();
 
-        // create a client config for the endpoint to use for QUIC address discovery
-        let root_store =
-            rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        let client_config = rustls::client::ClientConfig::builder_with_provider(Arc::new(
-            rustls::crypto::ring::default_provider(),
-        ))
-        .with_safe_default_protocol_versions()
-        .expect("ring supports these")
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+        // QUIC address discovery connects to the relay servers, so verifies their
+        // certificates like the relay client does.
+        #[cfg(not(wasm_browser))]
+        let client_config = (*msock.relay_tls_config().client_config()).clone();
 
         #[cfg(not(wasm_browser))]
         let quic_config = Some(QuicConfig {
@@ -1820,9 +2935,16 @@ impl Handle {
         let net_report_config = net_report::Options::default()
             .stun_v4(Some(actor_sockets.v4.clone()))
             .stun_v6(actor_sockets.v6.clone())
-            .quic_config(quic_config);
+            .quic_config(quic_config)
+            .relay_tls_config(msock.relay_tls_config().clone())
+            .stun_servers(stun_servers);
         #[cfg(wasm_browser)]
         let net_report_config = net_report::Options::default();
+        let net_report_config = net_report_config
+            .full_report_interval(net_report_schedule.full_interval)
+            .probe_timeout(net_report_limits.probe_timeout)
+            .report_timeout(net_report_limits.report_timeout)
+            .max_concurrent_probes(net_report_limits.max_concurrent_probes);
 
         actor_tasks.spawn({
             let msock = msock.clone();
@@ -1833,7 +2955,7 @@ impl Handle {
                     relay_actor_sender,
                     relay_actor_cancel_token,
                     msock,
-                    periodic_re_stun_timer: new_re_stun_timer(false),
+                    periodic_re_stun_timer: new_re_stun_timer(&net_report_schedule, false),
                     net_info_last: None,
                     #[cfg(not(wasm_browser))]
                     sockets: actor_sockets,
@@ -1841,6 +2963,13 @@ impl Handle {
                     net_reporter,
                     network_monitor,
                     net_report_config,
+                    net_report_schedule,
+                    relay_selector,
+                    net_report_full: false,
+                    net_report_fast: false,
+                    holepunch_failures: 0,
+                    #[cfg(not(wasm_browser))]
+                    keepalive_interval: disco_config.interval,
                 };
 
                 if let Err(err) = actor.run().await {
@@ -1869,11 +2998,13 @@ impl Handle {
     /// Only the first close does anything. Any later closes return nil.
     /// Polling the socket ([`AsyncUdpSocket::poll_recv`]) will return [`Poll::Pending`]
     /// indefinitely after this call.
+    ///
+    /// The open QUIC connections are closed with the given error code and reason.
     #[instrument(skip_all, fields(me = %self.msock.me))]
-    pub(crate) async fn close(&self) {
+    pub(crate) async fn close(&self, error_code: quinn::VarInt, reason: &[u8]) {
         trace!("magicsock closing...");
         // Initiate closing all connections, and refuse future connections.
-        self.endpoint.close(0u16.into(), b"");
+        self.endpoint.close(error_code, reason);
 
         // In the history of this code, this call had been
         // - removed: https://github.com/n0-computer/iroh/pull/1753
@@ -1933,19 +3064,28 @@ impl Handle {
 }
 
 #[derive(Debug, Default)]
-struct DiscoSecrets(std::sync::Mutex<HashMap<PublicKey, SharedSecret>>);
+struct DiscoSecrets(std::sync::Mutex<HashMap<PublicKey, Arc<SharedSecret>>>);
 
 impl DiscoSecrets {
+    /// Calls `cb` with the shared secret for `node_id`.
+    ///
+    /// The lock is not held while calling `cb`, so the DISCO workers can seal and open boxes
+    /// concurrently.
     fn get<F, T>(&self, secret: &crypto_box::SecretKey, node_id: PublicKey, cb: F) -> T
     where
-        F: FnOnce(&mut SharedSecret) -> T,
+        F: FnOnce(&SharedSecret) -> T,
     {
-        let mut inner = self.0.lock().expect("poisoned");
-        let x = inner.entry(node_id).or_insert_with(|| {
-            let public_key = public_ed_box(&node_id.public());
-            SharedSecret::new(secret, &public_key)
-        });
-        cb(x)
+        let shared = self
+            .0
+            .lock()
+            .expect("poisoned")
+            .entry(node_id)
+            .or_insert_with(|| {
+                let public_key = public_ed_box(&node_id.public());
+                Arc::new(SharedSecret::new(secret, &public_key))
+            })
+            .clone();
+        cb(&shared)
     }
 
     fn encode_and_seal(
@@ -1989,12 +3129,18 @@ fn relay_datagram_send_channel() -> (
     RelayDatagramSendChannelReceiver,
 ) {
     let (sender, receiver) = mpsc::channel(256);
+    let (prio_sender, prio_receiver) = mpsc::channel(64);
     let wakers = Arc::new(std::sync::Mutex::new(Vec::new()));
     let tx = RelayDatagramSendChannelSender {
         sender,
+        prio_sender,
         wakers: wakers.clone(),
     };
-    let rx = RelayDatagramSendChannelReceiver { receiver, wakers };
+    let rx = RelayDatagramSendChannelReceiver {
+        receiver,
+        prio_receiver,
+        wakers,
+    };
     (tx, rx)
 }
 
@@ -2005,14 +3151,27 @@ fn relay_datagram_send_channel() -> (
 #[derive(Debug, Clone)]
 struct RelayDatagramSendChannelSender {
     sender: mpsc::Sender<RelaySendItem>,
+    /// Channel for datagrams which are sent ahead of the ones in `sender`.
+    prio_sender: mpsc::Sender<RelaySendItem>,
     wakers: Arc<std::sync::Mutex<Vec<Waker>>>,
 }
 
 impl RelayDatagramSendChannelSender {
+    /// Queues the item, on the priority channel if `priority` is set.
+    ///
+    /// If the priority channel is full the item is queued as a normal item.
     fn try_send(
         &self,
         item: RelaySendItem,
+        priority: bool,
     ) -> Result<(), mpsc::error::TrySendError<RelaySendItem>> {
+        let item = match priority {
+            true => match self.prio_sender.try_send(item) {
+                Err(mpsc::error::TrySendError::Full(item)) => item,
+                res => return res,
+            },
+            false => item,
+        };
         self.sender.try_send(item)
     }
 
@@ -2044,15 +3203,92 @@ impl RelayDatagramSendChannelSender {
 #[derive(Debug)]
 struct RelayDatagramSendChannelReceiver {
     receiver: mpsc::Receiver<RelaySendItem>,
+    prio_receiver: mpsc::Receiver<RelaySendItem>,
     wakers: Arc<std::sync::Mutex<Vec<Waker>>>,
 }
 
 impl RelayDatagramSendChannelReceiver {
-    async fn recv(&mut self) -> Option<RelaySendItem> {
-        let item = self.receiver.recv().await;
-        let mut wakers = self.wakers.lock().expect("poisoned");
-        wakers.drain(..).for_each(Waker::wake);
-        item
+    /// Receives the next item, and whether it should be sent ahead of other items.
+    ///
+    /// Items sent with priority are received first.  Other items are only received if
+    /// `normal` is set.
+    async fn recv(&mut self, normal: bool) -> Option<(RelaySendItem, bool)> {
+        tokio::select! {
+            biased;
+            item = self.prio_receiver.recv() => item.map(|item| (item, true)),
+            item = self.receiver.recv(), if normal => {
+                let mut wakers = self.wakers.lock().expect("poisoned");
+                wakers.drain(..).for_each(Waker::wake);
+                item.map(|item| (item, false))
+            }
+        }
+    }
+}
+
+/// When the [`Actor`] runs net reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NetReportSchedule {
+    /// The range the interval between periodic reports is randomly picked from.
+    ///
+    /// If `None` no periodic reports are run.
+    pub(crate) interval: Option<RangeInclusive<Duration>>,
+    /// How often a full report is run instead of an incremental one.
+    pub(crate) full_interval: Duration,
+    /// Whether a report is run when the network changes.
+    pub(crate) on_network_change: bool,
+    /// Whether the report run after a minor network change is a fast one.
+    pub(crate) fast_on_minor_change: bool,
+    /// After this many failed hole punching pings a report is run.
+    pub(crate) holepunch_failures: Option<usize>,
+}
+
+impl Default for NetReportSchedule {
+    fn default() -> Self {
+        Self {
+            interval: Some(DEFAULT_NET_REPORT_INTERVAL),
+            full_interval: net_report::FULL_REPORT_INTERVAL,
+            on_network_change: true,
+            fast_on_minor_change: true,
+            holepunch_failures: None,
+        }
+    }
+}
+
+/// Limits the number of datagrams [`AsyncUdpSocket::poll_recv`] returns without yielding.
+///
+/// Quinn keeps receiving for as long as [`AsyncUdpSocket::poll_recv`] returns
+/// [`Poll::Ready`], under heavy inbound traffic this can monopolise the worker thread.  Once
+/// the budget is used up [`Poll::Pending`] is returned after waking the task, so it gets
+/// polled again after other tasks had a chance to run.
+#[derive(Debug)]
+struct RecvBudget {
+    budget: usize,
+    used: AtomicUsize,
+}
+
+impl RecvBudget {
+    fn new(budget: usize) -> Self {
+        Self {
+            budget: budget.max(1),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.used.load(Ordering::Relaxed) >= self.budget
+    }
+
+    /// Consumes the budget for the received datagrams, counting each GRO segment.
+    fn consume(&self, metas: &[quinn_udp::RecvMeta]) {
+        let datagrams: usize = metas
+            .iter()
+            .map(|meta| meta.len.div_ceil(meta.stride.max(1)).max(1))
+            .sum();
+        self.used.fetch_add(datagrams, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.used.store(0, Ordering::Relaxed);
     }
 }
 
@@ -2169,7 +3405,21 @@ impl AsyncUdpSocket for MagicSock {
         bufs: &mut [io::IoSliceMut<'_>],
         metas: &mut [quinn_udp::RecvMeta],
     ) -> Poll<io::Result<usize>> {
-        self.poll_recv(cx, bufs, metas)
+        if self.recv_budget.is_exhausted() {
+            // Yield so that under sustained load other tasks on this worker get to run.
+            trace!("recv packet budget exhausted, yielding");
+            inc!(MagicsockMetrics, recv_budget_exhausted);
+            self.recv_budget.reset();
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let res = self.poll_recv(cx, bufs, metas);
+        match res {
+            Poll::Ready(Ok(n)) => self.recv_budget.consume(&metas[..n]),
+            Poll::Ready(Err(_)) => (),
+            Poll::Pending => self.recv_budget.reset(),
+        }
+        res
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -2281,6 +3531,12 @@ enum ActorMessage {
     EndpointPingExpired(usize, stun_rs::TransactionId),
     NetReport(Result<Option<Arc<net_report::Report>>>, &'static str),
     NetworkChange,
+    Rebind {
+        reason: &'static str,
+    },
+    RelayMapChanged,
+    /// Run a full net report.
+    RunNetReport,
     #[cfg(test)]
     ForceNetworkChange(bool),
 }
@@ -2302,6 +3558,16 @@ struct Actor {
 
     /// Configuration for net report
     net_report_config: net_report::Options,
+    /// When net reports are run.
+    net_report_schedule: NetReportSchedule,
+    /// Overrides the selection of the home relay from net reports.
+    relay_selector: Option<Arc<dyn RelaySelector>>,
+    /// Whether the next net report is a full one, as explicitly requested.
+    net_report_full: bool,
+    /// Whether the next net report is a fast one, after a minor network change.
+    net_report_fast: bool,
+    /// The number of failed hole punching pings since the last net report.
+    holepunch_failures: usize,
 
     /// Whether IPv4 UDP is known to be unable to transmit
     /// at all. This could happen if the socket is in an invalid state
@@ -2312,6 +3578,10 @@ struct Actor {
     net_reporter: net_report::Client,
 
     network_monitor: netmon::Monitor,
+
+    /// How often the direct paths of the nodes in use are pinged.
+    #[cfg(not(wasm_browser))]
+    keepalive_interval: Duration,
 }
 
 /// Actor state that relies on sockets being available.
@@ -2321,35 +3591,53 @@ struct Actor {
 struct ActorSocketState {
     /// The NAT-PMP/PCP/UPnP prober/client, for requesting port mappings from NAT devices.
     port_mapper: portmapper::Client,
+    /// Whether any port mapping protocol is enabled, otherwise the gateway is never probed.
+    port_mapping_enabled: bool,
 
     // The underlying UDP sockets used to send/rcv packets.
     v4: Arc<UdpSocket>,
     v6: Option<Arc<UdpSocket>>,
+    /// The configured addresses of the sockets, their ports are pinned unless zero.
+    addr_v4: Option<SocketAddrV4>,
+    addr_v6: Option<SocketAddrV6>,
 }
 
 #[cfg(not(wasm_browser))]
 impl ActorSocketState {
-    fn bind(addr_v4: Option<SocketAddrV4>, addr_v6: Option<SocketAddrV6>) -> Result<Self> {
-        let port_mapper = portmapper::Client::default();
+    fn bind(
+        addr_v4: Option<SocketAddrV4>,
+        addr_v6: Option<SocketAddrV6>,
+        port_mapping: PortMappingConfig,
+    ) -> Result<Self> {
+        let port_mapping_enabled = port_mapping.is_enabled();
+        let port_mapper = portmapper::Client::new(port_mapping.into());
         let (v4, v6) = Self::bind_sockets(addr_v4, addr_v6)?;
 
         let this = Self {
             port_mapper,
+            port_mapping_enabled,
             v4,
             v6,
+            addr_v4,
+            addr_v6,
         };
+        this.update_port_mapping();
 
-        let port = this.port_v4();
+        Ok(this)
+    }
 
+    /// Tells the port mapper the local port to map, unless port mapping is disabled.
+    ///
+    /// Without a local port the port mapper never looks for a gateway.
+    fn update_port_mapping(&self) {
+        if !self.port_mapping_enabled {
+            return;
+        }
         // NOTE: we can end up with a zero port if `netwatch::UdpSocket::socket_addr` fails
-        match port.try_into() {
-            Ok(non_zero_port) => {
-                this.port_mapper.update_local_port(non_zero_port);
-            }
+        match self.port_v4().try_into() {
+            Ok(non_zero_port) => self.port_mapper.update_local_port(non_zero_port),
             Err(_zero_port) => debug!("Skipping port mapping with zero local port"),
         }
-
-        Ok(this)
     }
 
     /// Returns the ipv4 port, or 0 if `netwatch::UdpSocket::socket_addr` failed.
@@ -2379,15 +3667,15 @@ impl ActorSocketState {
         Ok((v4, v6))
     }
 
-    fn msock_socket_state(&self) -> Result<SocketState> {
+    fn msock_socket_state(&self, recv_limits: RecvLimits) -> Result<SocketState> {
         let ipv4_addr = self.v4.local_addr()?;
         let ipv6_addr = self.v6.as_ref().and_then(|c| c.local_addr().ok());
 
         let socket_state = SocketState {
             port: AtomicU16::new(self.port_v4()),
             local_addrs: std::sync::RwLock::new((ipv4_addr, ipv6_addr)),
-            v4: UdpConn::wrap(self.v4.clone()),
-            v6: self.v6.clone().map(UdpConn::wrap),
+            v4: UdpConn::wrap(self.v4.clone(), recv_limits),
+            v6: self.v6.clone().map(|v6| UdpConn::wrap(v6, recv_limits)),
         };
 
         Ok(socket_state)
@@ -2412,8 +3700,8 @@ impl Actor {
         // Let the the heartbeat only start a couple seconds later
         #[cfg(not(wasm_browser))]
         let mut direct_addr_heartbeat_timer = time::interval_at(
-            time::Instant::now() + HEARTBEAT_INTERVAL,
-            HEARTBEAT_INTERVAL,
+            time::Instant::now() + self.keepalive_interval,
+            self.keepalive_interval,
         );
         let mut direct_addr_update_receiver =
             self.msock.direct_addr_update_state.running.subscribe();
@@ -2431,6 +3719,11 @@ impl Actor {
         #[cfg_attr(wasm_browser, allow(unused_mut))]
         let mut portmap_watcher_closed = false;
         let mut link_change_closed = false;
+        let periodic_net_report = self.net_report_schedule.interval.is_some();
+        if !periodic_net_report {
+            // The first report is otherwise run by the first tick of the periodic timer.
+            self.msock.re_stun("startup");
+        }
         loop {
             inc!(Metrics, actor_tick_main);
             #[cfg(not(wasm_browser))]
@@ -2459,7 +3752,7 @@ impl Actor {
                         return Ok(());
                     }
                 }
-                tick = self.periodic_re_stun_timer.tick() => {
+                tick = self.periodic_re_stun_timer.tick(), if periodic_net_report => {
                     trace!("tick: re_stun {:?}", tick);
                     inc!(Metrics, actor_tick_re_stun);
                     self.msock.re_stun("periodic");
@@ -2479,6 +3772,7 @@ impl Actor {
                         inc!(Metrics, actor_tick_portmap_changed);
                         let new_external_address = *portmap_watcher.borrow();
                         debug!("external address updated: {new_external_address:?}");
+                        self.msock.port_mapping.set_external_addr(new_external_address);
                         self.msock.re_stun("portmap_updated");
                     }
                     #[cfg(wasm_browser)]
@@ -2548,24 +3842,137 @@ impl Actor {
         if is_major {
             #[cfg(not(wasm_browser))]
             {
-                if let Err(err) = self.sockets.v4.rebind() {
-                    warn!("failed to rebind Udp IPv4 socket: {:?}", err);
-                };
-                if let Some(ref socket) = self.sockets.v6 {
-                    if let Err(err) = socket.rebind() {
-                        warn!("failed to rebind Udp IPv6 socket: {:?}", err);
-                    };
-                }
+                self.rebind_sockets();
+                // A port mapping was obtained from the gateway of the previous network.
+                // Release it and request a new one, which will use the current gateway.
+                self.sockets.port_mapper.deactivate();
+                self.sockets.update_port_mapping();
                 self.msock.dns_resolver.clear_cache();
+                if let Some(ref turn) = self.msock.turn {
+                    turn.reset();
+                }
+            }
+            if self.net_report_schedule.on_network_change {
+                self.net_report_fast = false;
+                self.msock.re_stun("link-change-major");
             }
-            self.msock.re_stun("link-change-major");
             self.close_stale_relay_connections().await;
             self.reset_endpoint_states();
-        } else {
+        } else if self.net_report_schedule.on_network_change {
+            self.net_report_fast = self.net_report_schedule.fast_on_minor_change;
             self.msock.re_stun("link-change-minor");
         }
     }
 
+    /// Runs a net report once enough hole punching pings failed, if configured.
+    fn handle_holepunch_failure(&mut self) {
+        let Some(threshold) = self.net_report_schedule.holepunch_failures else {
+            return;
+        };
+        self.holepunch_failures += 1;
+        if self.holepunch_failures >= threshold {
+            self.holepunch_failures = 0;
+            self.msock.re_stun("holepunch-failures");
+        }
+    }
+
+    /// Replaces the UDP sockets by sockets bound to new ephemeral ports.
+    ///
+    /// Sockets on a port pinned by [`Options::addr_v4`] or [`Options::addr_v6`] are rebound
+    /// to the same port instead.  The port mapper and net reports switch to the new
+    /// sockets, the old ones are closed.
+    #[cfg(not(wasm_browser))]
+    async fn rebind_sockets_on_new_ports(&mut self) {
+        let pinned_v4 = self.sockets.addr_v4.is_some_and(|addr| addr.port() != 0);
+        let pinned_v6 = self.sockets.addr_v6.is_some_and(|addr| addr.port() != 0);
+        let mut replaced = Vec::new();
+        if pinned_v4 {
+            if let Err(err) = self.sockets.v4.rebind() {
+                warn!("failed to rebind Udp IPv4 socket: {:?}", err);
+            }
+        } else {
+            let ip = self
+                .sockets
+                .addr_v4
+                .map_or(Ipv4Addr::UNSPECIFIED, |addr| *addr.ip());
+            match bind_with_fallback(SocketAddr::from((ip, 0))) {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    replaced.push(self.msock.sockets.v4.replace(socket.clone()));
+                    self.sockets.v4 = socket;
+                }
+                Err(err) => warn!("failed to bind new Udp IPv4 socket: {err:#}"),
+            }
+        }
+        if let (Some(ref mut socket), Some(conn)) =
+            (&mut self.sockets.v6, self.msock.sockets.v6.as_ref())
+        {
+            if pinned_v6 {
+                if let Err(err) = socket.rebind() {
+                    warn!("failed to rebind Udp IPv6 socket: {:?}", err);
+                }
+            } else {
+                let ip = self
+                    .sockets
+                    .addr_v6
+                    .map_or(Ipv6Addr::UNSPECIFIED, |addr| *addr.ip());
+                match bind_with_fallback(SocketAddr::from((ip, 0))) {
+                    Ok(new_socket) => {
+                        let new_socket = Arc::new(new_socket);
+                        replaced.push(conn.replace(new_socket.clone()));
+                        *socket = new_socket;
+                    }
+                    Err(err) => warn!("failed to bind new Udp IPv6 socket: {err:#}"),
+                }
+            }
+        }
+        if replaced.is_empty() {
+            return;
+        }
+
+        let local_addr_v4 = self.sockets.v4.local_addr().ok();
+        let local_addr_v6 = self.sockets.v6.as_ref().and_then(|s| s.local_addr().ok());
+        {
+            let mut local_addrs = self.msock.sockets.local_addrs.write().expect("poisoned");
+            if let Some(addr) = local_addr_v4 {
+                local_addrs.0 = addr;
+            }
+            local_addrs.1 = local_addr_v6.or(local_addrs.1);
+        }
+        self.msock
+            .sockets
+            .port
+            .store(self.sockets.port_v4(), Ordering::Relaxed);
+        debug!(?local_addr_v4, ?local_addr_v6, "sockets bound to new ports");
+        self.net_report_config = self
+            .net_report_config
+            .clone()
+            .stun_v4(Some(self.sockets.v4.clone()))
+            .stun_v6(self.sockets.v6.clone());
+        // The port mapping and the TURN allocation were made for the previous local port.
+        self.sockets.port_mapper.deactivate();
+        self.sockets.update_port_mapping();
+        if let Some(ref turn) = self.msock.turn {
+            turn.reset();
+        }
+        for socket in replaced {
+            socket.close().await;
+        }
+    }
+
+    /// Rebinds the UDP sockets to their current local addresses.
+    #[cfg(not(wasm_browser))]
+    fn rebind_sockets(&self) {
+        if let Err(err) = self.sockets.v4.rebind() {
+            warn!("failed to rebind Udp IPv4 socket: {:?}", err);
+        };
+        if let Some(ref socket) = self.sockets.v6 {
+            if let Err(err) = socket.rebind() {
+                warn!("failed to rebind Udp IPv6 socket: {:?}", err);
+            };
+        }
+    }
+
     #[instrument(skip_all)]
     async fn handle_ping_actions(&mut self, msgs: Vec<PingAction>) {
         // TODO: This used to make sure that all ping actions are sent.  Though on the
@@ -2594,9 +4001,17 @@ impl Actor {
                 return true;
             }
             ActorMessage::EndpointPingExpired(id, txid) => {
-                self.msock.node_map.notify_ping_timeout(id, txid);
+                let timeout = self.msock.node_map.notify_ping_timeout(id, txid);
+                if let Some(ping) = timeout.retry {
+                    self.handle_ping_actions(vec![PingAction::SendPing(ping)])
+                        .await;
+                }
+                if timeout.holepunch_failed {
+                    self.handle_holepunch_failure();
+                }
             }
             ActorMessage::NetReport(report, why) => {
+                self.holepunch_failures = 0;
                 match report {
                     Ok(report) => {
                         self.handle_net_report_report(report).await;
@@ -2613,6 +4028,20 @@ impl Actor {
             ActorMessage::NetworkChange => {
                 self.network_monitor.network_change().await.ok();
             }
+            ActorMessage::Rebind { reason } => {
+                debug!(%reason, "rebinding sockets");
+                #[cfg(not(wasm_browser))]
+                self.rebind_sockets_on_new_ports().await;
+                self.msock.re_stun(reason);
+                self.reset_endpoint_states();
+            }
+            ActorMessage::RelayMapChanged => {
+                self.handle_relay_map_changed();
+            }
+            ActorMessage::RunNetReport => {
+                self.net_report_full = true;
+                self.msock.re_stun("api");
+            }
             #[cfg(test)]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major).await;
@@ -2696,6 +4125,23 @@ impl Actor {
             }
         }
 
+        // Then the relayed address of the TURN allocation.
+        #[cfg(not(wasm_browser))]
+        if let Some(relayed_addr) = self.msock.turn.as_ref().and_then(|t| t.relayed_addr()) {
+            addrs.entry(relayed_addr).or_insert(DirectAddrType::Turn);
+        }
+
+        // Then the addresses added by the application.
+        for addr in self
+            .msock
+            .manual_direct_addrs
+            .lock()
+            .expect("poisoned")
+            .iter()
+        {
+            addrs.entry(*addr).or_insert(DirectAddrType::Manual);
+        }
+
         let local_addr_v4 = self.sockets.v4.local_addr().ok();
         let local_addr_v6 = self.sockets.v6.as_ref().and_then(|c| c.local_addr().ok());
 
@@ -2785,7 +4231,7 @@ impl Actor {
             }
             #[cfg(not(wasm_browser))]
             {
-                self.periodic_re_stun_timer = new_re_stun_timer(true);
+                self.periodic_re_stun_timer = new_re_stun_timer(&self.net_report_schedule, true);
             }
         }
 
@@ -2830,7 +4276,12 @@ impl Actor {
             debug!("skipping net_report, socket is shutting down");
             return;
         }
-        if self.msock.relay_map.is_empty() {
+        let relay_map = self.msock.relay_map();
+        #[cfg(not(wasm_browser))]
+        let no_stun_servers = self.net_report_config.stun_servers.is_empty();
+        #[cfg(wasm_browser)]
+        let no_stun_servers = true;
+        if relay_map.is_empty() && no_stun_servers {
             debug!("skipping net_report, empty RelayMap");
             self.msg_sender
                 .send(ActorMessage::NetReport(Ok(None), why))
@@ -2839,15 +4290,20 @@ impl Actor {
             return;
         }
 
-        let relay_map = self.msock.relay_map.clone();
-        let opts = self.net_report_config.clone();
+        let opts = self
+            .net_report_config
+            .clone()
+            .full(std::mem::take(&mut self.net_report_full))
+            .fast(std::mem::take(&mut self.net_report_fast));
+        // Leave the report time to run into its own deadline and fail with that error.
+        let timeout = NET_REPORT_TIMEOUT.max(opts.report_timeout * 2);
 
         debug!("requesting net_report report");
         match self.net_reporter.get_report_channel(relay_map, opts).await {
             Ok(rx) => {
                 let msg_sender = self.msg_sender.clone();
                 task::spawn(async move {
-                    let report = time::timeout(NET_REPORT_TIMEOUT, rx).await;
+                    let report = time::timeout(timeout, rx).await;
                     let report: anyhow::Result<_> = match report {
                         Ok(Ok(Ok(report))) => Ok(Some(report)),
                         Ok(Ok(Err(err))) => Err(err),
@@ -2874,6 +4330,17 @@ impl Actor {
             self.msock
                 .ipv6_reported
                 .store(report.ipv6, Ordering::Relaxed);
+            let nat64_prefix = report.nat64_prefix.filter(|_| report.ipv6);
+            let old_prefix = std::mem::replace(
+                &mut *self.msock.nat64_prefix.write().expect("poisoned"),
+                nat64_prefix,
+            );
+            if old_prefix != nat64_prefix {
+                match nat64_prefix {
+                    Some(prefix) => info!(%prefix, "using NAT64 to reach IPv4 addresses"),
+                    None => debug!("no longer using NAT64"),
+                }
+            }
             let r = &report;
             trace!(
                 "setting no_v4_send {} -> {}",
@@ -2882,6 +4349,16 @@ impl Actor {
             );
             self.no_v4_send = !r.ipv4_can_send;
 
+            let connectivity = r.connectivity();
+            if let Ok(old) = self.msock.connectivity.set(Some(connectivity)) {
+                match connectivity {
+                    net_report::Connectivity::CaptivePortal => {
+                        warn!(?old, "captive portal detected")
+                    }
+                    _ => debug!(?old, %connectivity, "connectivity changed"),
+                }
+            }
+
             #[cfg(not(wasm_browser))]
             let have_port_map = self
                 .sockets
@@ -2891,6 +4368,10 @@ impl Actor {
                 .is_some();
             #[cfg(wasm_browser)]
             let have_port_map = false;
+            #[cfg(not(wasm_browser))]
+            if r.portmap_probe.is_some() {
+                self.msock.port_mapping.set_probe(r.portmap_probe.clone());
+            }
 
             let mut ni = NetInfo {
                 relay_latency: Default::default(),
@@ -2906,6 +4387,20 @@ impl Actor {
                 working_icmp_v6: r.icmpv6,
                 preferred_relay: r.preferred_relay.clone(),
             };
+            if let Some(ref selector) = self.relay_selector {
+                let current = self.msock.my_relay();
+                let relay_map = self.msock.relay_map();
+                match selector.select_relay(r, current.as_ref(), &relay_map) {
+                    Some(url) if relay_map.contains_node(&url) => {
+                        debug!(%url, "relay selector picked home relay");
+                        ni.preferred_relay = Some(url);
+                    }
+                    Some(url) => {
+                        debug!(%url, "relay selector picked unknown relay, ignoring");
+                    }
+                    None => (),
+                }
+            }
             for (rid, d) in r.relay_v4_latency.iter() {
                 ni.relay_latency
                     .insert(format!("{rid}-v4"), d.as_secs_f64());
@@ -2915,12 +4410,26 @@ impl Actor {
                     .insert(format!("{rid}-v6"), d.as_secs_f64());
             }
 
+            if ni
+                .preferred_relay
+                .as_ref()
+                .is_some_and(|url| !self.msock.relay_map().contains_node(url))
+            {
+                // The report was started before the relay server was removed.
+                ni.preferred_relay = None;
+            }
             if ni.preferred_relay.is_none() {
                 // Perhaps UDP is blocked. Pick a deterministic but arbitrary one.
                 ni.preferred_relay = self.pick_relay_fallback();
             }
 
             self.set_nearest_relay(ni.preferred_relay.clone());
+            if self.msock.relay_standby > 0 {
+                let urls = self.pick_standby_relays(&r.relay_latency);
+                self.send_relay_actor(RelayActorMessage::SetStandby { urls });
+            }
+            self.msock.record_relay_probe(&r.relay_latency);
+            self.msock.net_report.set(Some(report.clone())).ok();
 
             // TODO: set link type
             self.call_net_info_callback(ni).await;
@@ -2951,6 +4460,31 @@ impl Actor {
         }
     }
 
+    /// Returns the relay servers to keep standby connections to, best first.
+    ///
+    /// These are the relay servers with the lowest latency besides the home relay.  If the
+    /// latency of too few is known, the others are taken from the [`RelayMap`] in order.
+    fn pick_standby_relays(&self, latencies: &net_report::RelayLatencies) -> Vec<RelayUrl> {
+        let home = self.msock.my_relay();
+        let relay_map = self.msock.relay_map();
+        let mut by_latency: Vec<_> = latencies.iter().collect();
+        by_latency.sort_by_key(|(_, latency)| *latency);
+        let mut urls: Vec<RelayUrl> = Vec::new();
+        let candidates = by_latency
+            .into_iter()
+            .map(|(url, _)| url)
+            .chain(relay_map.urls());
+        for url in candidates {
+            if urls.len() == self.msock.relay_standby {
+                break;
+            }
+            if Some(url) != home.as_ref() && relay_map.contains_node(url) && !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
     /// Returns a deterministic relay node to connect to. This is only used if net_report
     /// couldn't find the nearest one, for instance, if UDP is blocked and thus STUN
     /// latency checks aren't working.
@@ -2972,11 +4506,27 @@ impl Actor {
             return my_relay;
         }
 
-        let ids = self.msock.relay_map.urls().collect::<Vec<_>>();
+        let relay_map = self.msock.relay_map();
+        let ids = relay_map.urls().collect::<Vec<_>>();
         let mut rng = rand::rngs::StdRng::seed_from_u64(0);
         ids.choose(&mut rng).map(|c| (*c).clone())
     }
 
+    /// Picks the home relay again after the [`RelayMap`] changed.
+    ///
+    /// If the home relay was removed it is cleared straight away, so it is no longer
+    /// published and the fallback does not stick to it.
+    fn handle_relay_map_changed(&mut self) {
+        if let Some(home) = self.msock.my_relay() {
+            if !self.msock.relay_map().contains_node(&home) {
+                info!(%home, "home relay removed from the relay map");
+                self.msock.set_my_relay(None);
+                self.msock.publish_my_addr();
+            }
+        }
+        self.msock.re_stun("relay-map-changed");
+    }
+
     /// Resets the preferred address for all nodes.
     /// This is called when connectivity changes enough that we no longer trust the old routes.
     #[instrument(skip_all, fields(me = %self.msock.me))]
@@ -3017,11 +4567,15 @@ impl Actor {
     }
 }
 
-fn new_re_stun_timer(initial_delay: bool) -> time::Interval {
-    // Pick a random duration between 20 and 26 seconds (just under 30s,
-    // a common UDP NAT timeout on Linux,etc)
+fn new_re_stun_timer(schedule: &NetReportSchedule, initial_delay: bool) -> time::Interval {
+    // Pick a random duration from the configured range, if periodic reports are disabled
+    // the timer is never polled.
+    let interval = schedule
+        .interval
+        .clone()
+        .unwrap_or(DEFAULT_NET_REPORT_INTERVAL);
     let mut rng = rand::thread_rng();
-    let d: Duration = rng.gen_range(Duration::from_secs(20)..=Duration::from_secs(26));
+    let d: Duration = rng.gen_range(interval);
     if initial_delay {
         debug!("scheduling periodic_stun to run in {}s", d.as_secs());
         time::interval_at(time::Instant::now() + d, d)
@@ -3135,7 +4689,7 @@ impl DiscoveredDirectAddrs {
 
 /// Split a transmit containing a GSO payload into individual packets.
 ///
-/// This allocates the data.
+/// This copies the data once, all packets are slices of this single allocation.
 ///
 /// If the transmit has a segment size it contains multiple GSO packets.  It will be split
 /// into multiple packets according to that segment size.  If it does not have a segment
@@ -3143,14 +4697,16 @@ impl DiscoveredDirectAddrs {
 // TODO: If quinn stayed on bytes this would probably be much cheaper, probably.  Need to
 // figure out where they allocate the Vec.
 fn split_packets(transmit: &quinn_udp::Transmit) -> RelayContents {
-    let mut res = SmallVec::with_capacity(1);
-    let contents = transmit.contents;
-    if let Some(segment_size) = transmit.segment_size {
-        for chunk in contents.chunks(segment_size) {
-            res.push(Bytes::from(chunk.to_vec()));
-        }
-    } else {
-        res.push(Bytes::from(contents.to_vec()));
+    let contents = Bytes::copy_from_slice(transmit.contents);
+    let Some(segment_size) = transmit.segment_size else {
+        return smallvec![contents];
+    };
+    let mut res = SmallVec::with_capacity(contents.len().div_ceil(segment_size));
+    let mut start = 0;
+    while start < contents.len() {
+        let end = (start + segment_size).min(contents.len());
+        res.push(contents.slice(start..end));
+        start = end;
     }
     res
 }
@@ -3252,6 +4808,98 @@ fn disco_message_sent(msg: &disco::Message) {
     }
 }
 
+/// Filter for datagrams received on the UDP sockets of an [`Endpoint`].
+///
+/// The filter is consulted for every datagram received directly over UDP, before it is
+/// classified as QUIC, STUN or DISCO packet.  Rejected datagrams are dropped silently, as if
+/// they were never received.  This can be used to implement simple firewall rules or to
+/// collect custom per-source statistics.
+///
+/// Datagrams received via a relay server are not passed to the filter.
+///
+/// The filter is called on the receive path for each datagram, so it should be cheap to
+/// evaluate and must not block.
+///
+/// [`Endpoint`]: crate::Endpoint
+pub trait PacketFilter: std::fmt::Debug + Send + Sync + 'static {
+    /// Returns whether the datagram received from `src` should be processed.
+    ///
+    /// The `datagram` is the entire UDP payload, its length is the datagram size.
+    fn accept(&self, src: SocketAddr, datagram: &[u8]) -> bool;
+}
+
+/// The latencies to the relay servers measured by one net report.
+///
+/// Net reports run periodically and whenever the network changes.  The home relay is only
+/// moved if another relay server is significantly faster, so it is not necessarily the
+/// fastest relay server of a probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayProbe {
+    /// Elapsed time since the probe finished.
+    pub age: Duration,
+    /// The latency to each relay server which responded to the probe.
+    pub latencies: BTreeMap<RelayUrl, Duration>,
+    /// The home relay chosen after the probe.
+    pub home_relay: Option<RelayUrl>,
+}
+
+/// An event of the connection to a relay server.
+///
+/// See [`Endpoint::relay_events`].
+///
+/// [`Endpoint::relay_events`]: crate::Endpoint::relay_events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RelayEvent {
+    /// The connection to the relay server was established.
+    Connected {
+        /// The relay server.
+        url: RelayUrl,
+    },
+    /// An established connection to the relay server was lost, it is reconnected right away.
+    Disconnected {
+        /// The relay server.
+        url: RelayUrl,
+        /// Why the connection was lost.
+        error: String,
+    },
+    /// Connecting to the relay server failed, it is retried after `delay`.
+    Reconnecting {
+        /// The relay server.
+        url: RelayUrl,
+        /// The number of failed attempts in a row.
+        attempt: usize,
+        /// The time until the next attempt.
+        delay: Duration,
+        /// Why the attempt failed.
+        error: String,
+    },
+    /// Connecting to the relay server failed the configured number of times in a row.
+    ///
+    /// Connecting is still retried, another [`RelayEvent::Connected`] follows once the relay
+    /// server is reachable again.
+    Unreachable {
+        /// The relay server.
+        url: RelayUrl,
+        /// The number of failed attempts in a row.
+        attempts: usize,
+        /// Why the last attempt failed.
+        error: String,
+    },
+    /// The connection to the home relay was lost and a connected standby relay became the
+    /// home relay.
+    ///
+    /// See [`Builder::relay_standby`].
+    ///
+    /// [`Builder::relay_standby`]: crate::endpoint::Builder::relay_standby
+    Failover {
+        /// The previous home relay.
+        from: RelayUrl,
+        /// The new home relay.
+        to: RelayUrl,
+    },
+}
+
 /// A *direct address* on which an iroh-node might be contactable.
 ///
 /// Direct addresses are UDP socket addresses on which an iroh node could potentially be
@@ -3268,8 +4916,10 @@ pub struct DirectAddr {
 /// The type of direct address.
 ///
 /// These are the various sources or origins from which an iroh node might have found a
-/// possible [`DirectAddr`].
+/// possible [`DirectAddr`].  More sources may be added, like [`DirectAddrType::Turn`] was,
+/// so matches need a wildcard arm.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
 pub enum DirectAddrType {
     /// Not yet determined..
     Unknown,
@@ -3292,6 +4942,18 @@ pub enum DirectAddrType {
     /// configure the router to forward this port to the iroh node.  This indicates a
     /// situation like this, which still uses STUN to discover the public address.
     Stun4LocalPort,
+    /// A relayed address allocated on a TURN server.
+    ///
+    /// Datagrams sent to this address are forwarded by the TURN server, which allows
+    /// reaching nodes behind NATs which defeat holepunching.
+    Turn,
+    /// An address added by the application.
+    ///
+    /// E.g. a static public address forwarded to the node by the operator, or an address
+    /// learned via external signalling.  See [`Endpoint::add_direct_addr`].
+    ///
+    /// [`Endpoint::add_direct_addr`]: crate::Endpoint::add_direct_addr
+    Manual,
 }
 
 impl Display for DirectAddrType {
@@ -3302,6 +4964,8 @@ impl Display for DirectAddrType {
             DirectAddrType::Stun => write!(f, "stun"),
             DirectAddrType::Portmapped => write!(f, "portmap"),
             DirectAddrType::Stun4LocalPort => write!(f, "stun4localport"),
+            DirectAddrType::Turn => write!(f, "turn"),
+            DirectAddrType::Manual => write!(f, "manual"),
         }
     }
 }
@@ -3412,10 +5076,32 @@ mod tests {
                 relay_map: RelayMap::empty(),
                 relay_protocol: iroh_relay::http::Protocol::default(),
                 node_map: None,
+                direct_only: false,
                 discovery: None,
                 proxy_url: None,
+                relay_tls_config: Default::default(),
                 dns_resolver: DnsResolver::new(),
                 server_config,
+                max_udp_payload_size: None,
+                packet_filter: None,
+                send_rate_limit: None,
+                send_pacing: None,
+                disco_config: Default::default(),
+                relay_reconnect: Default::default(),
+                relay_keepalive: Default::default(),
+                relay_standby: 0,
+                recv_packet_budget: None,
+                recv_limits: Default::default(),
+                stun_servers: Default::default(),
+                stun_overflow_policy: Default::default(),
+                net_report_schedule: Default::default(),
+                net_report_limits: Default::default(),
+                relay_selector: None,
+                port_prediction: None,
+                addr_filter: None,
+                disco_trust: None,
+                turn_server: None,
+                port_mapping: Default::default(),
                 #[cfg(any(test, feature = "test-utils"))]
                 insecure_skip_relay_cert_verify: false,
                 #[cfg(any(test, feature = "test-utils"))]
@@ -3745,6 +5431,65 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_two_devices_roundtrip_rebind() -> Result<()> {
+        let m1 = MagicStack::new(RelayMode::Disabled).await?;
+        let m2 = MagicStack::new(RelayMode::Disabled).await?;
+
+        let _guard = mesh_stacks(vec![m1.clone(), m2.clone()]).await?;
+
+        run_roundtrip(
+            m1.clone(),
+            m2.clone(),
+            b"hello m1",
+            ExpectedLoss::AlmostNone,
+        )
+        .await;
+
+        let (m1_v4, _) = m1.endpoint.bound_sockets();
+        let (m2_v4, _) = m2.endpoint.bound_sockets();
+        m1.endpoint.rebind().await;
+        m2.endpoint.rebind().await;
+
+        // The sockets are bound to new ephemeral ports.
+        time::timeout(Duration::from_secs(10), async {
+            while m1.endpoint.bound_sockets().0 == m1_v4 || m2.endpoint.bound_sockets().0 == m2_v4 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .context("sockets not rebound")?;
+
+        run_roundtrip(m1.clone(), m2.clone(), b"hello m1", ExpectedLoss::YeahSure).await;
+        run_roundtrip(m2.clone(), m1.clone(), b"hello m2", ExpectedLoss::YeahSure).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_rebind_pinned_port() -> Result<()> {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind_addr_v4(addr)
+            .bind()
+            .await?;
+        assert_eq!(ep.bound_sockets().0, SocketAddr::V4(addr));
+
+        ep.rebind().await;
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(ep.bound_sockets().0, SocketAddr::V4(addr));
+        // The socket is bound to the pinned port again.
+        assert!(std::net::UdpSocket::bind(addr).is_err());
+        ep.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_regression_network_change_rebind_wakes_connection_driver(
@@ -3953,6 +5698,9 @@ mod tests {
             split_packets(&mk_transmit(b"hello world", Some(1000))),
             mk_expected(["hello world"])
         );
+        // all packets share the same allocation
+        let parts = split_packets(&mk_transmit(b"helloworld", Some(5)));
+        assert_eq!(parts[1].as_ptr(), parts[0][5..].as_ptr());
     }
 
     #[tokio::test]
@@ -4025,11 +5773,33 @@ mod tests {
             relay_map: RelayMap::empty(),
             relay_protocol: iroh_relay::http::Protocol::default(),
             node_map: None,
+            direct_only: false,
             discovery: None,
             discovery_user_data: None,
             dns_resolver,
             proxy_url: None,
+            relay_tls_config: Default::default(),
             server_config,
+            max_udp_payload_size: None,
+            packet_filter: None,
+            send_rate_limit: None,
+            send_pacing: None,
+            disco_config: Default::default(),
+            relay_reconnect: Default::default(),
+            relay_keepalive: Default::default(),
+            relay_standby: 0,
+            recv_packet_budget: None,
+            recv_limits: Default::default(),
+            stun_servers: Default::default(),
+            stun_overflow_policy: Default::default(),
+            net_report_schedule: Default::default(),
+            net_report_limits: Default::default(),
+            relay_selector: None,
+            port_prediction: None,
+            addr_filter: None,
+            disco_trust: None,
+            turn_server: None,
+            port_mapping: Default::default(),
             insecure_skip_relay_cert_verify: true,
             path_selection: PathSelection::default(),
         };
@@ -4091,6 +5861,209 @@ mod tests {
         Ok(connection)
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_inject_udp_datagram() -> Result<()> {
+        let secret_key = SecretKey::from_bytes(&[1u8; 32]);
+        let msock = magicsock_ep(secret_key.clone(), tls::Authentication::RawPublicKey).await?;
+        let src: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let meta_for = |payload: &[u8]| quinn_udp::RecvMeta {
+            addr: src,
+            len: payload.len(),
+            stride: payload.len(),
+            ecn: None,
+            dst_ip: None,
+        };
+
+        // STUN packets are consumed.
+        let mut stun_packet = stun::request(stun::TransactionId::default());
+        let meta = msock.inject_udp_datagram(meta_for(&stun_packet), &mut stun_packet);
+        assert_eq!(meta.len, 0);
+
+        // QUIC packets from an address without node state are dropped.
+        let mut quic_packet = vec![0x40; 32];
+        let meta = msock.inject_udp_datagram(meta_for(&quic_packet), &mut quic_packet);
+        assert_eq!(meta.len, 0);
+
+        // Disco pings are consumed and add the sender to the node map.
+        let peer_key = SecretKey::from_bytes(&[2u8; 32]);
+        let peer = peer_key.public();
+        let ping = disco::Message::Ping(disco::Ping {
+            tx_id: stun::TransactionId::default(),
+            node_key: peer,
+        });
+        let mut disco_packet = DiscoSecrets::default()
+            .encode_and_seal(
+                &secret_ed_box(peer_key.secret()),
+                peer,
+                secret_key.public(),
+                &ping,
+            )
+            .to_vec();
+        assert!(msock.remote_info(peer).is_none());
+        let meta = msock.inject_udp_datagram(meta_for(&disco_packet), &mut disco_packet);
+        assert_eq!(meta.len, 0);
+        assert!(msock.remote_info(peer).is_some());
+
+        // Once the sender is known its QUIC packets are passed on, to the mapped address.
+        let meta = msock.inject_udp_datagram(meta_for(&quic_packet), &mut quic_packet);
+        assert_eq!(meta.len, quic_packet.len());
+        assert_ne!(meta.addr, src);
+
+        msock.close(0u16.into(), b"").await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_disco_trust_relay_datagram() -> Result<()> {
+        let untrusted = SecretKey::from_bytes(&[2u8; 32]).public();
+        let trusted = SecretKey::from_bytes(&[3u8; 32]).public();
+        let msock = Handle::new(Options {
+            disco_trust: Some(Arc::new(TrustedNodes::new([trusted]))),
+            ..Default::default()
+        })
+        .await?;
+        let url: RelayUrl = "https://relay.example.com".parse()?;
+
+        for node in [untrusted, trusted] {
+            // A datagram via the relay adds the node to the node map, but does not trust it.
+            msock.node_map.receive_relay(&url, node, 100);
+            assert!(!msock.node_map.is_disco_trusted(node));
+
+            let ping = disco::Ping {
+                tx_id: stun::TransactionId::default(),
+                node_key: node,
+            };
+            let src = DiscoMessageSource::Relay {
+                url: url.clone(),
+                key: node,
+            };
+            msock.handle_ping(ping, node, src);
+        }
+        assert!(!msock.node_map.is_disco_trusted(untrusted));
+        assert!(msock.node_map.is_disco_trusted(trusted));
+
+        // Nodes added by the application are trusted.
+        msock.add_test_addr(NodeAddr::new(untrusted).with_relay_url(url));
+        assert!(msock.node_map.is_disco_trusted(untrusted));
+
+        msock.close(0u16.into(), b"").await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_disco_trust_call_me_maybe() -> Result<()> {
+        let untrusted = SecretKey::from_bytes(&[2u8; 32]).public();
+        let trusted = SecretKey::from_bytes(&[3u8; 32]).public();
+        let msock = Handle::new(Options {
+            disco_trust: Some(Arc::new(TrustedNodes::new([trusted]))),
+            ..Default::default()
+        })
+        .await?;
+        let url: RelayUrl = "https://relay.example.com".parse()?;
+
+        for (i, node) in [untrusted, trusted].into_iter().enumerate() {
+            // A relay datagram creates the node state, then its call-me-maybe arrives.
+            msock.node_map.receive_relay(&url, node, 100);
+            let addr: SocketAddr = format!("203.0.113.{}:1234", i + 1).parse()?;
+            let cm = CallMeMaybe {
+                my_numbers: vec![addr],
+            };
+            let src = DiscoMessageSource::Relay {
+                url: url.clone(),
+                key: node,
+            };
+            msock.handle_call_me_maybe(cm, node, src);
+            // Only the addresses of trusted nodes are added, and pinged.
+            assert_eq!(msock.node_map.has_udp_path(addr), node == trusted);
+        }
+
+        msock.close(0u16.into(), b"").await;
+        Ok(())
+    }
+
+    #[test]
+    fn test_recv_budget() {
+        let meta = |len, stride| quinn_udp::RecvMeta {
+            addr: "192.0.2.1:1234".parse().unwrap(),
+            len,
+            stride,
+            ecn: None,
+            dst_ip: None,
+        };
+        let budget = RecvBudget::new(4);
+        assert!(!budget.is_exhausted());
+
+        // GRO segments are counted individually, including a short last one.
+        budget.consume(&[meta(2500, 1000)]);
+        assert!(!budget.is_exhausted());
+        budget.consume(&[meta(1000, 1000)]);
+        assert!(budget.is_exhausted());
+
+        budget.reset();
+        assert!(!budget.is_exhausted());
+
+        // A zero budget still allows receiving once.
+        let budget = RecvBudget::new(0);
+        assert!(!budget.is_exhausted());
+        budget.consume(&[meta(0, 0)]);
+        assert!(budget.is_exhausted());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_pin_src_ip() -> Result<()> {
+        let secret_key = SecretKey::from_bytes(&[1u8; 32]);
+        let msock = magicsock_ep(secret_key.clone(), tls::Authentication::RawPublicKey).await?;
+        assert!(msock.local_addr().0.ip().is_unspecified());
+        let src: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let meta_for = |payload: &[u8], dst_ip: &str| quinn_udp::RecvMeta {
+            addr: src,
+            len: payload.len(),
+            stride: payload.len(),
+            ecn: None,
+            dst_ip: Some(dst_ip.parse().unwrap()),
+        };
+
+        // The address a ping was received on is recorded for the path it creates.
+        let peer_key = SecretKey::from_bytes(&[2u8; 32]);
+        let peer = peer_key.public();
+        let ping = disco::Message::Ping(disco::Ping {
+            tx_id: stun::TransactionId::default(),
+            node_key: peer,
+        });
+        let mut disco_packet = DiscoSecrets::default()
+            .encode_and_seal(
+                &secret_ed_box(peer_key.secret()),
+                peer,
+                secret_key.public(),
+                &ping,
+            )
+            .to_vec();
+        msock.inject_udp_datagram(meta_for(&disco_packet, "198.51.100.1"), &mut disco_packet);
+        assert_eq!(
+            msock.node_map.udp_local_ip(src),
+            Some("198.51.100.1".parse().unwrap())
+        );
+
+        // QUIC packets update it.
+        let mut quic_packet = vec![0x40; 32];
+        msock.inject_udp_datagram(meta_for(&quic_packet, "198.51.100.2"), &mut quic_packet);
+        assert_eq!(
+            msock.node_map.udp_local_ip(src),
+            Some("198.51.100.2".parse().unwrap())
+        );
+
+        // Addresses of the wrong family are never used as source address.
+        msock.inject_udp_datagram(meta_for(&quic_packet, "2001:db8::1"), &mut quic_packet);
+        assert_eq!(msock.node_map.udp_local_ip(src), None);
+
+        msock.close(0u16.into(), b"").await;
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_try_send_no_send_addr() {
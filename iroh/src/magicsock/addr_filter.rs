@@ -0,0 +1,142 @@
+//! Filtering of the direct addresses advertised to other nodes.
+
+use std::{
+    collections::BTreeSet,
+    fmt::Debug,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use super::DirectAddr;
+
+/// Decides which of our direct addresses are advertised to other nodes.
+///
+/// Direct addresses are advertised in the call-me-maybe messages sent when holepunching, to
+/// the discovery services and in the [`NodeAddr`] of the endpoint.  Some deployments must
+/// not leak their internal addressing to other nodes, a filter removes these addresses
+/// before they are advertised.  Filtered addresses are not reported by
+/// [`Endpoint::direct_addresses`] either.
+///
+/// Note that the public address of a NAT is still observed by the nodes we send pings to.
+///
+/// [`AddrPolicy`] covers the common policies.  Install a filter using
+/// [`Builder::addr_filter`].
+///
+/// [`NodeAddr`]: crate::NodeAddr
+/// [`Endpoint::direct_addresses`]: crate::Endpoint::direct_addresses
+/// [`Builder::addr_filter`]: crate::endpoint::Builder::addr_filter
+pub trait AddrFilter: Debug + Send + Sync + 'static {
+    /// Returns whether the direct address should be advertised.
+    fn advertise(&self, addr: &DirectAddr) -> bool;
+}
+
+/// An [`AddrFilter`] for the common policies.
+///
+/// The default policy advertises all addresses, the policies set are combined: an address
+/// is only advertised if no policy excludes it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddrPolicy {
+    exclude_private: bool,
+    exclude_link_local: bool,
+    only: Option<BTreeSet<IpAddr>>,
+}
+
+impl AddrPolicy {
+    /// Excludes private addresses.
+    ///
+    /// These are the IPv4 addresses of [RFC 1918] and the IPv6 unique local addresses of
+    /// [RFC 4193].
+    ///
+    /// [RFC 1918]: https://www.rfc-editor.org/rfc/rfc1918
+    /// [RFC 4193]: https://www.rfc-editor.org/rfc/rfc4193
+    pub fn exclude_private(mut self) -> Self {
+        self.exclude_private = true;
+        self
+    }
+
+    /// Excludes link-local addresses.
+    pub fn exclude_link_local(mut self) -> Self {
+        self.exclude_link_local = true;
+        self
+    }
+
+    /// Only advertises direct addresses with one of the given IP addresses.
+    ///
+    /// This allows advertising e.g. only the address of a port forwarding set up on the
+    /// router.  The addresses must still be discovered as direct addresses, a policy can
+    /// not add addresses.
+    pub fn only(mut self, ips: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.only = Some(ips.into_iter().map(|ip| ip.to_canonical()).collect());
+        self
+    }
+}
+
+impl AddrFilter for AddrPolicy {
+    fn advertise(&self, addr: &DirectAddr) -> bool {
+        let ip = addr.addr.ip().to_canonical();
+        if self.exclude_private && is_private(ip) {
+            return false;
+        }
+        if self.exclude_link_local && is_link_local(ip) {
+            return false;
+        }
+        if let Some(ref only) = self.only {
+            return only.contains(&ip);
+        }
+        true
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private(),
+        IpAddr::V6(ip) => is_unique_local(ip),
+    }
+}
+
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => netwatch::ip::is_unicast_link_local(ip),
+    }
+}
+
+/// Whether the address is in `fc00::/7`.
+const fn is_unique_local(ip: Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xfe00) == 0xfc00
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magicsock::DirectAddrType;
+
+    fn direct(addr: &str) -> DirectAddr {
+        DirectAddr {
+            addr: addr.parse().unwrap(),
+            typ: DirectAddrType::Local,
+        }
+    }
+
+    #[test]
+    fn test_addr_policy() {
+        let all = AddrPolicy::default();
+        assert!(all.advertise(&direct("192.168.1.2:1234")));
+
+        let policy = AddrPolicy::default().exclude_private();
+        assert!(!policy.advertise(&direct("10.0.0.1:1234")));
+        assert!(!policy.advertise(&direct("172.16.3.4:1234")));
+        assert!(!policy.advertise(&direct("[fd00::1]:1234")));
+        assert!(policy.advertise(&direct("203.0.113.1:1234")));
+        assert!(policy.advertise(&direct("169.254.1.1:1234")));
+
+        let policy = policy.exclude_link_local();
+        assert!(!policy.advertise(&direct("169.254.1.1:1234")));
+        assert!(!policy.advertise(&direct("[fe80::1]:1234")));
+        assert!(policy.advertise(&direct("[2001:db8::1]:1234")));
+
+        let policy = AddrPolicy::default().only(["203.0.113.1".parse().unwrap()]);
+        assert!(policy.advertise(&direct("203.0.113.1:1234")));
+        assert!(policy.advertise(&direct("[::ffff:203.0.113.1]:1234")));
+        assert!(!policy.advertise(&direct("203.0.113.2:1234")));
+    }
+}
@@ -0,0 +1,434 @@
+//! Packet capture of the traffic of the magic socket in the pcapng format.
+//!
+//! UDP datagrams are written with synthesised IP and UDP headers on a raw IP interface so
+//! that tools like Wireshark can dissect the QUIC packets.  Relay datagrams have no IP
+//! headers, they are written on a separate interface using [`LINKTYPE_USER0`] with the
+//! 32-byte remote node ID prepended to the datagram.
+//!
+//! See <https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html> for the format.
+
+use std::{
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use iroh_base::{NodeId, RelayUrl};
+use tracing::warn;
+
+/// Link type for raw IPv4 and IPv6 packets.
+const LINKTYPE_RAW: u16 = 101;
+
+/// Link type reserved for private use, used for relay datagrams.
+const LINKTYPE_USER0: u16 = 147;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Interface ID of the UDP interface, in the order the interfaces are written.
+const INTERFACE_UDP: u32 = 0;
+/// Interface ID of the relay interface.
+const INTERFACE_RELAY: u32 = 1;
+
+/// Direction of a captured packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    /// The value of the direction bits of the `epb_flags` option.
+    fn epb_flags(self) -> u32 {
+        match self {
+            Self::Inbound => 1,
+            Self::Outbound => 2,
+        }
+    }
+}
+
+/// Captures packets to a pcapng writer, which can be started and stopped at any time.
+#[derive(Debug, Default)]
+pub(super) struct PacketCapture {
+    /// Whether a capture is running, allows skipping the lock on the hot path.
+    enabled: AtomicBool,
+    writer: std::sync::Mutex<Option<PcapngWriter>>,
+}
+
+impl PacketCapture {
+    /// Starts a new capture to `writer`, replacing any running capture.
+    pub(super) fn start(&self, writer: Box<dyn Write + Send>) -> io::Result<()> {
+        let writer = PcapngWriter::new(writer)?;
+        let mut guard = self.writer.lock().expect("poisoned");
+        if let Some(mut previous) = guard.replace(writer) {
+            previous.flush().ok();
+        }
+        self.enabled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stops the running capture, if any, flushing the writer.
+    pub(super) fn stop(&self) -> io::Result<()> {
+        let mut guard = self.writer.lock().expect("poisoned");
+        self.enabled.store(false, Ordering::Relaxed);
+        match guard.take() {
+            Some(mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a capture is running.
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Records a UDP datagram exchanged between `local` and `remote`.
+    pub(super) fn record_udp(
+        &self,
+        direction: Direction,
+        local: SocketAddr,
+        remote: SocketAddr,
+        datagram: &[u8],
+        comment: Option<&str>,
+    ) {
+        let (src, dst) = match direction {
+            Direction::Inbound => (remote, local),
+            Direction::Outbound => (local, remote),
+        };
+        let packet = ip_udp_packet(src, dst, datagram);
+        self.write_packet(INTERFACE_UDP, direction, &packet, comment);
+    }
+
+    /// Records a datagram exchanged with `node` via the relay server at `url`.
+    pub(super) fn record_relay(
+        &self,
+        direction: Direction,
+        url: &RelayUrl,
+        node: NodeId,
+        datagram: &[u8],
+        comment: Option<&str>,
+    ) {
+        let mut packet = Vec::with_capacity(NodeId::LENGTH + datagram.len());
+        packet.extend_from_slice(node.as_bytes());
+        packet.extend_from_slice(datagram);
+        let comment = match comment {
+            Some(comment) => format!("relay {url} node {}: {comment}", node.fmt_short()),
+            None => format!("relay {url} node {}", node.fmt_short()),
+        };
+        self.write_packet(INTERFACE_RELAY, direction, &packet, Some(&comment));
+    }
+
+    fn write_packet(
+        &self,
+        interface: u32,
+        direction: Direction,
+        packet: &[u8],
+        comment: Option<&str>,
+    ) {
+        let mut guard = self.writer.lock().expect("poisoned");
+        let Some(writer) = guard.as_mut() else {
+            return;
+        };
+        if let Err(err) = writer.write_packet(interface, direction, packet, comment) {
+            warn!("Failed to write packet capture, stopping capture: {err:#}");
+            self.enabled.store(false, Ordering::Relaxed);
+            *guard = None;
+        }
+    }
+}
+
+/// Writes the pcapng blocks.
+#[derive(derive_more::Debug)]
+struct PcapngWriter {
+    #[debug("Box<dyn Write>")]
+    writer: Box<dyn Write + Send>,
+}
+
+impl PcapngWriter {
+    /// Creates the writer, writing the section header and interface descriptions.
+    fn new(writer: Box<dyn Write + Send>) -> io::Result<Self> {
+        let mut this = Self { writer };
+
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // major version
+        body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        body.extend_from_slice(&(-1i64).to_le_bytes()); // section length, unspecified
+        this.write_block(BLOCK_SECTION_HEADER, &body)?;
+
+        this.write_interface(LINKTYPE_RAW, "udp")?;
+        this.write_interface(LINKTYPE_USER0, "relay")?;
+        Ok(this)
+    }
+
+    fn write_interface(&mut self, link_type: u16, name: &str) -> io::Result<()> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&link_type.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        body.extend_from_slice(&0u32.to_le_bytes()); // snap length, unlimited
+        push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+        push_option(&mut body, OPT_END, &[]);
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION, &body)
+    }
+
+    fn write_packet(
+        &mut self,
+        interface: u32,
+        direction: Direction,
+        packet: &[u8],
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        // Timestamps use the default resolution of microseconds.
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let len = u32::try_from(packet.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too large"))?;
+
+        let mut body = Vec::with_capacity(packet.len() + 64);
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&len.to_le_bytes()); // captured length
+        body.extend_from_slice(&len.to_le_bytes()); // original length
+        body.extend_from_slice(packet);
+        pad(&mut body);
+        push_option(
+            &mut body,
+            OPT_EPB_FLAGS,
+            &direction.epb_flags().to_le_bytes(),
+        );
+        if let Some(comment) = comment {
+            push_option(&mut body, OPT_COMMENT, comment.as_bytes());
+        }
+        push_option(&mut body, OPT_END, &[]);
+        self.write_block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    /// Writes a block, `body` must already be padded to 32 bits.
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(body.len() % 4, 0, "unpadded block body");
+        let total_len = u32::try_from(body.len() + 12)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "block too large"))?;
+        self.writer.write_all(&block_type.to_le_bytes())?;
+        self.writer.write_all(&total_len.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&total_len.to_le_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Appends an option, padding its value to 32 bits.
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    // Values longer than the option length field allows are truncated.
+    let value = &value[..value.len().min(u16::MAX as usize & !3)];
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Builds an IP packet with a UDP header around `payload`.
+///
+/// If the address families differ, e.g. because of an unspecified bind address, the
+/// packet uses the family of the `dst` address.
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + payload.len()).min(u16::MAX as usize) as u16;
+    let mut udp = Vec::with_capacity(8 + payload.len());
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&0u16.to_be_bytes()); // checksum, not computed
+    udp.extend_from_slice(payload);
+
+    match to_canonical(dst.ip()) {
+        IpAddr::V4(dst_ip) => {
+            let src_ip = match to_canonical(src.ip()) {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+            let total_len = (20 + udp.len()).min(u16::MAX as usize) as u16;
+            let mut packet = Vec::with_capacity(20 + udp.len());
+            packet.push(0x45); // version 4, header length 5 words
+            packet.push(0); // DSCP and ECN
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0]); // identification, don't fragment
+            packet.push(64); // TTL
+            packet.push(17); // protocol UDP
+            packet.extend_from_slice(&[0, 0]); // checksum, filled below
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&udp);
+            packet
+        }
+        IpAddr::V6(dst_ip) => {
+            let src_ip = match src.ip() {
+                IpAddr::V6(ip) => ip,
+                IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+            };
+            let mut packet = Vec::with_capacity(40 + udp.len());
+            packet.extend_from_slice(&[0x60, 0, 0, 0]); // version 6, no traffic class or flow
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.push(17); // next header UDP
+            packet.push(64); // hop limit
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            packet.extend_from_slice(&udp);
+            packet
+        }
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses to IPv4 addresses.
+fn to_canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        },
+        ip => ip,
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    /// A writer to inspect what was captured.
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Splits a capture into `(block_type, body)` pairs, checking the block framing.
+    fn blocks(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            let trailer = u32::from_le_bytes(rest[len - 4..len].try_into().unwrap()) as usize;
+            assert_eq!(len, trailer);
+            blocks.push((block_type, &rest[8..len - 4]));
+            rest = &rest[len..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_capture() {
+        let capture = PacketCapture::default();
+        assert!(!capture.is_enabled());
+
+        let buf = SharedBuf::default();
+        capture.start(Box::new(buf.clone())).unwrap();
+        assert!(capture.is_enabled());
+
+        let local: SocketAddr = "192.168.1.2:1234".parse().unwrap();
+        let remote: SocketAddr = "[::ffff:10.0.0.1]:5678".parse().unwrap();
+        capture.record_udp(Direction::Inbound, local, remote, b"hello", None);
+
+        let url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let node = SecretKey::generate(rand::thread_rng()).public();
+        capture.record_relay(Direction::Outbound, &url, node, b"world", Some("disco"));
+
+        capture.stop().unwrap();
+        assert!(!capture.is_enabled());
+        // Not recorded after stopping.
+        capture.record_udp(Direction::Inbound, local, remote, b"hello", None);
+
+        let data = buf.0.lock().unwrap();
+        let blocks = blocks(&data);
+        let types: Vec<_> = blocks.iter().map(|(t, _)| *t).collect();
+        assert_eq!(
+            types,
+            vec![
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_ENHANCED_PACKET,
+            ]
+        );
+
+        // UDP packet: IPv4 header from the remote, mapped, address to the local one.
+        let udp = blocks[3].1;
+        assert_eq!(&udp[0..4], &INTERFACE_UDP.to_le_bytes());
+        let len = u32::from_le_bytes(udp[12..16].try_into().unwrap()) as usize;
+        assert_eq!(len, 20 + 8 + 5);
+        let packet = &udp[20..20 + len];
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        assert_eq!(&packet[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&packet[16..20], &[192, 168, 1, 2]);
+        assert_eq!(&packet[20..22], &5678u16.to_be_bytes());
+        assert_eq!(&packet[22..24], &1234u16.to_be_bytes());
+        assert_eq!(&packet[28..], b"hello");
+
+        // Relay packet: node ID followed by the datagram, with a comment.
+        let relay = blocks[4].1;
+        assert_eq!(&relay[0..4], &INTERFACE_RELAY.to_le_bytes());
+        let len = u32::from_le_bytes(relay[12..16].try_into().unwrap()) as usize;
+        let packet = &relay[20..20 + len];
+        assert_eq!(&packet[..32], node.as_bytes());
+        assert_eq!(&packet[32..], b"world");
+        let comment = format!("relay {url} node {}: disco", node.fmt_short());
+        assert!(relay
+            .windows(comment.len())
+            .any(|window| window == comment.as_bytes()));
+    }
+
+    #[test]
+    fn test_ip_udp_packet_v6() {
+        let src: SocketAddr = "[2001:db8::1]:1".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:2".parse().unwrap();
+        let packet = ip_udp_packet(src, dst, b"hi");
+        assert_eq!(packet.len(), 40 + 8 + 2);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(&packet[4..6], &10u16.to_be_bytes());
+        assert_eq!(packet[6], 17);
+        assert_eq!(&packet[48..], b"hi");
+    }
+}
@@ -0,0 +1,142 @@
+//! A pool of workers handling received DISCO messages.
+//!
+//! Opening the sealed box of a DISCO message is expensive compared to forwarding a QUIC
+//! datagram.  Received DISCO messages are therefore handed to a small pool of workers
+//! instead of being handled while receiving, so a burst of them does not delay the data
+//! packets received alongside them.
+//!
+//! All messages from the same sender are handled by the same worker, in the order they
+//! were received.  DISCO messages from UDP addresses which are not yet a path of any node
+//! are not handed to the pool: the QUIC datagrams following a first ping on a new path
+//! would be dropped if it was handled after them, so they are handled while receiving.
+
+use std::net::IpAddr;
+
+use iroh_base::PublicKey;
+use iroh_metrics::{dec, inc};
+use tokio::sync::mpsc;
+use tracing::trace;
+
+use super::{metrics::Metrics as MagicsockMetrics, DiscoMessageSource};
+
+/// The number of workers handling DISCO messages.
+pub(super) const DISCO_WORKERS: usize = 4;
+
+/// The number of DISCO messages queued for each worker before new ones are dropped.
+const DISCO_QUEUE_CAPACITY: usize = 128;
+
+/// A received DISCO message waiting to be handled.
+#[derive(Debug)]
+pub(super) struct DiscoJob {
+    pub(super) sender: PublicKey,
+    pub(super) sealed_box: Vec<u8>,
+    pub(super) src: DiscoMessageSource,
+    /// The local address the message was received on, if sends on the path should be
+    /// pinned to it.
+    pub(super) local_ip: Option<IpAddr>,
+}
+
+/// Hands received DISCO messages to the workers.
+#[derive(Debug)]
+pub(super) struct DiscoPool {
+    workers: Vec<mpsc::Sender<DiscoJob>>,
+}
+
+impl DiscoPool {
+    /// Creates the pool, returning the receivers the workers need to handle.
+    pub(super) fn new(workers: usize) -> (Self, Vec<mpsc::Receiver<DiscoJob>>) {
+        let (workers, receivers) = (0..workers.max(1))
+            .map(|_| mpsc::channel(DISCO_QUEUE_CAPACITY))
+            .unzip();
+        (Self { workers }, receivers)
+    }
+
+    /// Queues a DISCO message to be handled by the worker for its sender.
+    ///
+    /// Drops the message if the worker's queue is full.  DISCO messages are sent
+    /// periodically, so like any lost datagram they will be retried.
+    pub(super) fn submit(&self, job: DiscoJob) {
+        let worker = &self.workers[self.worker_index(&job.sender)];
+        match worker.try_send(job) {
+            Ok(()) => inc!(MagicsockMetrics, disco_queue_depth),
+            Err(mpsc::error::TrySendError::Full(job)) => {
+                trace!(node = %job.sender.fmt_short(), "disco queue full, dropping message");
+                inc!(MagicsockMetrics, recv_disco_queue_full);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                trace!("disco workers stopped, dropping message");
+            }
+        }
+    }
+
+    fn worker_index(&self, sender: &PublicKey) -> usize {
+        // Public keys are uniformly distributed, so a few bytes pick a worker well enough.
+        let bytes = sender.as_bytes();
+        let n = u64::from_le_bytes(bytes[..8].try_into().expect("32 byte key"));
+        (n % self.workers.len() as u64) as usize
+    }
+}
+
+/// Takes the next job from a worker's queue.
+pub(super) async fn next_job(receiver: &mut mpsc::Receiver<DiscoJob>) -> Option<DiscoJob> {
+    let job = receiver.recv().await?;
+    dec!(MagicsockMetrics, disco_queue_depth);
+    Some(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    fn job(sender: PublicKey, n: u8) -> DiscoJob {
+        DiscoJob {
+            sender,
+            sealed_box: vec![n],
+            src: DiscoMessageSource::Udp("127.0.0.1:1234".parse().unwrap()),
+            local_ip: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disco_pool() {
+        let (pool, mut receivers) = DiscoPool::new(DISCO_WORKERS);
+        assert_eq!(receivers.len(), DISCO_WORKERS);
+        let senders: Vec<_> = (0u8..16)
+            .map(|i| SecretKey::from_bytes(&[i; 32]).public())
+            .collect();
+
+        for n in 0..3 {
+            for sender in &senders {
+                pool.submit(job(*sender, n));
+            }
+        }
+
+        // Each sender's messages end up on a single worker, in order.
+        let mut seen = std::collections::HashMap::<PublicKey, Vec<u8>>::new();
+        let mut worker_of = std::collections::HashMap::new();
+        for (i, receiver) in receivers.iter_mut().enumerate() {
+            while let Ok(job) = receiver.try_recv() {
+                assert_eq!(*worker_of.entry(job.sender).or_insert(i), i);
+                seen.entry(job.sender).or_default().push(job.sealed_box[0]);
+            }
+        }
+        assert_eq!(seen.len(), senders.len());
+        for messages in seen.values() {
+            assert_eq!(messages, &[0, 1, 2]);
+        }
+
+        // A full queue drops messages instead of blocking.
+        let sender = senders[0];
+        for _ in 0..DISCO_QUEUE_CAPACITY + 1 {
+            pool.submit(job(sender, 0));
+        }
+        let receiver = &mut receivers[pool.worker_index(&sender)];
+        let mut queued = 0;
+        while receiver.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, DISCO_QUEUE_CAPACITY);
+    }
+}
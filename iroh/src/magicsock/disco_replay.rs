@@ -0,0 +1,128 @@
+//! Replay protection for the received DISCO messages.
+//!
+//! Every sealed DISCO box carries a random nonce.  The nonces of the recent messages of each
+//! sender are remembered, a message whose nonce was already seen is a replay of an earlier
+//! message.  Only authenticated messages are recorded, so nobody but the sender can fill
+//! its window.
+//!
+//! The window is bounded both in the number of nonces and in their age.  Replays of
+//! messages which dropped out of the window are not detected here, but pongs for pings
+//! which timed out are dropped by the node state anyway.
+
+use std::collections::{HashMap, VecDeque};
+
+use iroh_base::PublicKey;
+use n0_future::time::{Duration, Instant};
+
+use crate::key::NONCE_LEN;
+
+/// Number of senders above which the windows without recent messages are removed.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// The nonce of a sealed DISCO box.
+pub(super) type Nonce = [u8; NONCE_LEN];
+
+/// Returns the nonce of a sealed DISCO box, if it is long enough to have one.
+pub(super) fn nonce(sealed_box: &[u8]) -> Option<Nonce> {
+    let offset = sealed_box.len().checked_sub(NONCE_LEN)?;
+    sealed_box[offset..].try_into().ok()
+}
+
+/// The replay windows of all senders.
+#[derive(Debug)]
+pub(super) struct ReplayWindows {
+    /// The maximum number of nonces remembered per sender, 0 disables the protection.
+    size: usize,
+    /// How long a nonce is remembered.
+    max_age: Duration,
+    windows: std::sync::Mutex<HashMap<PublicKey, VecDeque<(Nonce, Instant)>>>,
+}
+
+impl ReplayWindows {
+    pub(super) fn new(size: usize, max_age: Duration) -> Self {
+        Self {
+            size,
+            max_age,
+            windows: Default::default(),
+        }
+    }
+
+    /// Records the nonce of an authenticated message from `sender`.
+    ///
+    /// Returns `false` if the nonce is already in the window, i.e. the message is a replay.
+    pub(super) fn check(&self, sender: PublicKey, nonce: Nonce) -> bool {
+        self.check_at(sender, nonce, Instant::now())
+    }
+
+    fn check_at(&self, sender: PublicKey, nonce: Nonce, now: Instant) -> bool {
+        if self.size == 0 {
+            return true;
+        }
+        let mut windows = self.windows.lock().expect("poisoned");
+        if windows.len() >= PRUNE_THRESHOLD && !windows.contains_key(&sender) {
+            windows.retain(|_, window| {
+                window
+                    .back()
+                    .is_some_and(|(_, seen)| now.duration_since(*seen) < self.max_age)
+            });
+        }
+        let window = windows.entry(sender).or_default();
+        while window
+            .front()
+            .is_some_and(|(_, seen)| now.duration_since(*seen) >= self.max_age)
+        {
+            window.pop_front();
+        }
+        if window.iter().any(|(seen, _)| *seen == nonce) {
+            return false;
+        }
+        if window.len() >= self.size {
+            window.pop_front();
+        }
+        window.push_back((nonce, now));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let sender = SecretKey::generate(rand::thread_rng()).public();
+        let other = SecretKey::generate(rand::thread_rng()).public();
+        let windows = ReplayWindows::new(2, Duration::from_secs(10));
+        let now = Instant::now();
+
+        assert!(windows.check_at(sender, [1; NONCE_LEN], now));
+        assert!(!windows.check_at(sender, [1; NONCE_LEN], now));
+        // The windows are per sender.
+        assert!(windows.check_at(other, [1; NONCE_LEN], now));
+
+        // Only the most recent nonces are kept.
+        assert!(windows.check_at(sender, [2; NONCE_LEN], now));
+        assert!(windows.check_at(sender, [3; NONCE_LEN], now));
+        assert!(windows.check_at(sender, [1; NONCE_LEN], now));
+
+        // Old nonces are forgotten.
+        let later = now + Duration::from_secs(10);
+        assert!(!windows.check_at(other, [1; NONCE_LEN], now));
+        assert!(windows.check_at(other, [1; NONCE_LEN], later));
+
+        // A window of size 0 disables the protection.
+        let windows = ReplayWindows::new(0, Duration::from_secs(10));
+        assert!(windows.check_at(sender, [1; NONCE_LEN], now));
+        assert!(windows.check_at(sender, [1; NONCE_LEN], now));
+    }
+
+    #[test]
+    fn test_nonce() {
+        assert_eq!(nonce(&[0; NONCE_LEN - 1]), None);
+        let mut sealed_box = vec![0; 4];
+        sealed_box.extend_from_slice(&[7; NONCE_LEN]);
+        assert_eq!(nonce(&sealed_box), Some([7; NONCE_LEN]));
+    }
+}
@@ -0,0 +1,91 @@
+//! Deciding whether to answer the DISCO messages of unknown nodes.
+
+use std::{collections::BTreeSet, fmt::Debug, net::SocketAddr};
+
+use iroh_base::{NodeId, RelayUrl};
+
+/// Where a DISCO ping was received from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PingSource {
+    /// The ping was received directly over UDP from this address.
+    Udp(SocketAddr),
+    /// The ping was received via this relay server.
+    Relay(RelayUrl),
+}
+
+/// Decides whether to answer the DISCO pings of nodes the endpoint does not know yet.
+///
+/// Endpoints answer the pings sent to them while holepunching with a pong, which confirms
+/// to the sender that the endpoint is reachable at the pinged address.  By default pings
+/// from any node are answered, so a node which learned our node ID and addresses can probe
+/// whether we are online.  Likewise a call-me-maybe makes the endpoint ping the addresses
+/// the sender chose.  A trust hook lets the application refuse to answer nodes it does not
+/// expect, their pings and call-me-maybes are then dropped as if they never arrived.
+///
+/// The hook is only consulted for nodes which are not trusted yet: nodes we connected to or
+/// added addressing information for, and nodes whose pings were accepted before, are always
+/// answered.  Nodes which merely sent us datagrams via a relay server are not trusted.  Note
+/// that this does not prevent an unknown node from connecting via the relay server, use the
+/// QUIC handshake to restrict connections.
+///
+/// Install a hook using [`Builder::disco_trust`].  [`TrustedNodes`] only answers a fixed
+/// set of nodes.
+///
+/// The hook is called for each ping and call-me-maybe from an unknown node, so it should be
+/// cheap to evaluate and must not block.
+///
+/// [`Builder::disco_trust`]: crate::endpoint::Builder::disco_trust
+pub trait DiscoTrust: Debug + Send + Sync + 'static {
+    /// Returns whether the ping or call-me-maybe from the unknown node `node_id` should be
+    /// answered.
+    fn trust_ping(&self, node_id: NodeId, src: &PingSource) -> bool;
+}
+
+/// A [`DiscoTrust`] only answering the pings of a fixed set of nodes.
+///
+/// It is also an [`AcceptPolicy`] only accepting connections from these nodes.
+///
+/// [`AcceptPolicy`]: crate::endpoint::AcceptPolicy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedNodes {
+    nodes: BTreeSet<NodeId>,
+}
+
+impl TrustedNodes {
+    /// Creates a hook only answering the given nodes.
+    pub fn new(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        Self {
+            nodes: nodes.into_iter().collect(),
+        }
+    }
+
+    /// Returns whether the node is trusted.
+    pub fn contains(&self, node_id: &NodeId) -> bool {
+        self.nodes.contains(node_id)
+    }
+}
+
+impl DiscoTrust for TrustedNodes {
+    fn trust_ping(&self, node_id: NodeId, _src: &PingSource) -> bool {
+        self.nodes.contains(&node_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_trusted_nodes() {
+        let trusted = SecretKey::generate(rand::thread_rng()).public();
+        let other = SecretKey::generate(rand::thread_rng()).public();
+        let src = PingSource::Udp("203.0.113.1:1234".parse().unwrap());
+
+        let hook = TrustedNodes::new([trusted]);
+        assert!(hook.trust_ping(trusted, &src));
+        assert!(!hook.trust_ping(other, &src));
+        assert!(!TrustedNodes::default().trust_ping(trusted, &src));
+    }
+}
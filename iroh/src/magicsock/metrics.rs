@@ -1,5 +1,5 @@
 use iroh_metrics::{
-    core::{Counter, Metric},
+    core::{Counter, Gauge, Metric},
     struct_iterable::Iterable,
 };
 
@@ -25,8 +25,36 @@ pub struct Metrics {
     pub recv_data_ipv6: Counter,
     /// Number of QUIC datagrams received.
     pub recv_datagrams: Counter,
+    /// Number of QUIC datagrams received on the IPv4 socket.
+    pub recv_datagrams_ipv4: Counter,
+    /// Number of QUIC datagrams received on the IPv6 socket.
+    pub recv_datagrams_ipv6: Counter,
     /// Number of datagrams received using GRO
     pub recv_gro_datagrams: Counter,
+    /// Number of transmits sent as a single GSO batch
+    pub send_gso_datagrams: Counter,
+    /// Number of GSO transmits that had to be split into individual datagrams
+    pub send_gso_fallback: Counter,
+    /// Number of QUIC datagrams received with the ECN Congestion Experienced codepoint
+    pub recv_ecn_ce: Counter,
+    /// Number of UDP datagrams dropped by the packet filter
+    pub recv_filtered: Counter,
+    /// Number of transient errors when receiving from the UDP sockets
+    pub recv_errors_transient: Counter,
+    /// Number of fatal errors when receiving from the UDP sockets, each triggers a rebind
+    pub recv_errors_fatal: Counter,
+    /// Number of QUIC transmits delayed because they exceeded the per-node send rate limit
+    pub send_rate_limited: Counter,
+    /// Number of QUIC transmits dropped because the per-node send rate limit queue was full
+    pub send_rate_limited_dropped: Counter,
+    /// Number of QUIC transmits delayed by pacing
+    pub send_paced: Counter,
+    /// Number of times receiving yielded to other tasks because the packet budget was used up
+    pub recv_budget_exhausted: Counter,
+    /// Number of bytes sent to nodes through the TURN server
+    pub send_turn: Counter,
+    /// Number of bytes received from nodes through the TURN server
+    pub recv_turn: Counter,
 
     // Disco packets
     pub send_disco_udp: Counter,
@@ -42,12 +70,30 @@ pub struct Metrics {
     pub recv_disco_udp: Counter,
     pub recv_disco_relay: Counter,
     pub recv_disco_ping: Counter,
+    /// Number of received DISCO pings from unknown nodes not answered by the trust hook
+    pub recv_disco_ping_untrusted: Counter,
     pub recv_disco_pong: Counter,
     pub recv_disco_call_me_maybe: Counter,
     pub recv_disco_call_me_maybe_bad_disco: Counter,
+    /// Number of received DISCO call-me-maybes from unknown nodes ignored by the trust hook
+    pub recv_disco_call_me_maybe_untrusted: Counter,
+    /// Number of received DISCO messages dropped because they were already seen
+    pub recv_disco_replayed: Counter,
+    /// Number of received DISCO pongs dropped because their ping is unknown or timed out
+    pub recv_disco_stale: Counter,
+    /// Number of received DISCO messages waiting to be handled by the DISCO workers
+    pub disco_queue_depth: Gauge,
+    /// Number of received DISCO messages dropped because the queue of their worker was full
+    pub recv_disco_queue_full: Counter,
 
     // How many times our relay home node DI has changed from non-zero to a different non-zero.
     pub relay_home_change: Counter,
+    /// Number of times the home relay was lost and replaced by a standby relay.
+    pub relay_home_failover: Counter,
+    /// Number of call-me-maybe messages sent with predicted addresses of a symmetric NAT.
+    pub port_prediction_sent: Counter,
+    /// Number of predicted addresses confirmed by a pong from a remote node.
+    pub port_prediction_hit: Counter,
 
     /*
      * Connection Metrics
@@ -77,6 +123,8 @@ pub struct Metrics {
 
     /// Number of connections with a successful handshake.
     pub connection_handshake_success: Counter,
+    /// Number of connections whose handshake did not complete within the handshake timeout.
+    pub connection_handshake_timeout: Counter,
     /// Number of connections with a successful handshake that became direct.
     pub connection_became_direct: Counter,
 }
@@ -103,7 +151,21 @@ impl Default for Metrics {
             recv_data_ipv4: Counter::new("recv_data_ipv4"),
             recv_data_ipv6: Counter::new("recv_data_ipv6"),
             recv_datagrams: Counter::new("recv_datagrams"),
+            recv_datagrams_ipv4: Counter::new("recv_datagrams_ipv4"),
+            recv_datagrams_ipv6: Counter::new("recv_datagrams_ipv6"),
             recv_gro_datagrams: Counter::new("recv_gro_packets"),
+            send_gso_datagrams: Counter::new("send_gso_packets"),
+            send_gso_fallback: Counter::new("send_gso_fallback"),
+            recv_ecn_ce: Counter::new("recv_ecn_ce"),
+            recv_filtered: Counter::new("recv_filtered"),
+            recv_errors_transient: Counter::new("recv_errors_transient"),
+            recv_errors_fatal: Counter::new("recv_errors_fatal"),
+            send_rate_limited: Counter::new("send_rate_limited"),
+            send_rate_limited_dropped: Counter::new("send_rate_limited_dropped"),
+            send_paced: Counter::new("send_paced"),
+            recv_budget_exhausted: Counter::new("recv_budget_exhausted"),
+            send_turn: Counter::new("send_turn"),
+            recv_turn: Counter::new("recv_turn"),
 
             // Disco packets
             send_disco_udp: Counter::new("disco_send_udp"),
@@ -119,12 +181,23 @@ impl Default for Metrics {
             recv_disco_udp: Counter::new("disco_recv_udp"),
             recv_disco_relay: Counter::new("disco_recv_relay"),
             recv_disco_ping: Counter::new("disco_recv_ping"),
+            recv_disco_ping_untrusted: Counter::new("disco_recv_ping_untrusted"),
             recv_disco_pong: Counter::new("disco_recv_pong"),
             recv_disco_call_me_maybe: Counter::new("disco_recv_callmemaybe"),
             recv_disco_call_me_maybe_bad_disco: Counter::new("disco_recv_callmemaybe_bad_disco"),
+            recv_disco_call_me_maybe_untrusted: Counter::new("disco_recv_callmemaybe_untrusted"),
+            recv_disco_replayed: Counter::new("disco_recv_replayed"),
+            recv_disco_stale: Counter::new("disco_recv_stale"),
+            disco_queue_depth: Gauge::new("disco_queue_depth"),
+            recv_disco_queue_full: Counter::new("disco_recv_queue_full"),
 
             // How many times our relay home node DI has changed from non-zero to a different non-zero.
             relay_home_change: Counter::new("relay_home_change"),
+            relay_home_failover: Counter::new(
+                "number of times the home relay was replaced by a standby relay",
+            ),
+            port_prediction_sent: Counter::new("port_prediction_sent"),
+            port_prediction_hit: Counter::new("port_prediction_hit"),
 
             num_direct_conns_added: Counter::new(
                 "number of direct connections to a peer we have added",
@@ -148,6 +221,7 @@ impl Default for Metrics {
             nodes_contacted_directly: Counter::new("nodes_contacted_directly"),
 
             connection_handshake_success: Counter::new("connection_handshake_success"),
+            connection_handshake_timeout: Counter::new("connection_handshake_timeout"),
             connection_became_direct: Counter::new("connection_became_direct"),
         }
     }
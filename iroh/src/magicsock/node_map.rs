@@ -7,17 +7,21 @@ use std::{
 
 use iroh_base::{NodeAddr, NodeId, PublicKey, RelayUrl};
 use iroh_metrics::inc;
-use n0_future::time::Instant;
+use n0_future::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 use stun_rs::TransactionId;
+use tokio::sync::broadcast;
 use tracing::{debug, info, instrument, trace, warn};
 
 use self::{
     best_addr::ClearReason,
+    holepunch::HolepunchEvents,
     node_state::{NodeState, Options, PingHandled},
 };
 use super::{
     metrics::Metrics as MagicsockMetrics, ActorMessage, DiscoMessageSource, NodeIdMappedAddr,
+    HEARTBEAT_INTERVAL,
 };
 #[cfg(any(test, feature = "test-utils"))]
 use crate::endpoint::PathSelection;
@@ -27,17 +31,33 @@ use crate::{
 };
 
 mod best_addr;
+mod holepunch;
 mod node_state;
+mod path_quality;
 mod path_state;
 mod udp_paths;
 
-pub use node_state::{ConnectionType, ControlMsg, DirectAddrInfo, RemoteInfo};
-pub(super) use node_state::{DiscoPingPurpose, PingAction, PingRole, SendPing};
+pub use holepunch::HolepunchEvent;
+pub use node_state::{
+    ConnectionType, ConnectionTypeInfo, ControlMsg, DirectAddrInfo, DiscoPathInfo, PathTraffic,
+    RelayUrlInfo, RemoteInfo,
+};
+pub(super) use node_state::{DiscoPingPurpose, PingAction, PingRole, PingTimeout, SendPing};
+pub use path_quality::PathQuality;
 
 /// Number of nodes that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`NodeMap::prune_inactive`].
 const MAX_INACTIVE_NODES: usize = 30;
 
+/// Default number of nonces remembered per node to detect replayed DISCO messages.
+const DEFAULT_REPLAY_WINDOW: usize = 128;
+
+/// Maximum number of rounds of holepunching pings, see [`DiscoConfig::probe_rounds`].
+pub(super) const MAX_PROBE_ROUNDS: u32 = 8;
+
+/// Default time the nonces of received DISCO messages are remembered.
+const DEFAULT_REPLAY_MAX_AGE: Duration = Duration::from_secs(60);
+
 /// Map of the [`NodeState`] information for all the known nodes.
 ///
 /// The nodes can be looked up by:
@@ -67,10 +87,176 @@ pub(super) struct NodeMapInner {
     by_quic_mapped_addr: HashMap<NodeIdMappedAddr, usize>,
     by_id: HashMap<usize, NodeState>,
     next_id: usize,
+    disco_config: DiscoConfig,
+    holepunch_events: HolepunchEvents,
     #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
 }
 
+/// Timing of the DISCO pings finding and maintaining the direct paths to nodes.
+///
+/// The defaults suit most internet connections.  On a LAN shorter intervals and timeouts
+/// find and recover paths faster, while high latency links such as satellite connections
+/// need longer timeouts so their pongs are not considered lost.
+///
+/// Use [`Builder::disco_config`] to configure an endpoint.
+///
+/// [`Builder::disco_config`]: crate::endpoint::Builder::disco_config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoConfig {
+    /// How often the direct path of a node in use is pinged.
+    pub(crate) interval: Duration,
+    /// How long a direct path is used on its own after it was last confirmed.
+    ///
+    /// Afterwards data is also sent over the relay, until the path is confirmed again.
+    pub(crate) idle_timeout: Duration,
+    /// The minimum time between two pings to the same path.
+    pub(crate) ping_interval: Duration,
+    /// How long to wait for the pong of a ping.
+    pub(crate) ping_timeout: Duration,
+    /// How often a lost holepunching ping is resent before holepunching is failed.
+    pub(crate) ping_retries: u32,
+    /// How many nonces of received messages are remembered per node to detect replays.
+    pub(crate) replay_window: usize,
+    /// How long the nonces of received messages are remembered.
+    pub(crate) replay_max_age: Duration,
+    /// The time between two holepunching pings to candidate paths.
+    pub(crate) probe_spacing: Duration,
+    /// The maximum random delay added to each holepunching ping.
+    pub(crate) probe_jitter: Duration,
+    /// How many rounds of holepunching pings are sent to the candidate paths.
+    pub(crate) probe_rounds: u32,
+}
+
+impl Default for DiscoConfig {
+    fn default() -> Self {
+        Self {
+            interval: HEARTBEAT_INTERVAL,
+            idle_timeout: best_addr::TRUST_UDP_ADDR_DURATION,
+            ping_interval: path_state::DISCO_PING_INTERVAL,
+            ping_timeout: node_state::PING_TIMEOUT_DURATION,
+            ping_retries: 0,
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            replay_max_age: DEFAULT_REPLAY_MAX_AGE,
+            probe_spacing: Duration::ZERO,
+            probe_jitter: Duration::ZERO,
+            probe_rounds: 1,
+        }
+    }
+}
+
+impl DiscoConfig {
+    /// Sets how often the direct paths to remote nodes in use are pinged.
+    ///
+    /// See [`Builder::keepalive_interval`].  Defaults to 5 seconds.
+    ///
+    /// [`Builder::keepalive_interval`]: crate::endpoint::Builder::keepalive_interval
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how long a direct path is trusted on its own without being confirmed.
+    ///
+    /// See [`Builder::path_idle_timeout`].  Defaults to 6.5 seconds.
+    ///
+    /// [`Builder::path_idle_timeout`]: crate::endpoint::Builder::path_idle_timeout
+    pub fn path_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the minimum time between two pings to the same path.
+    ///
+    /// Call-me-maybe messages from the remote node reset this, a holepunching attempt
+    /// always pings all candidate paths.  Defaults to 5 seconds.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Sets how long to wait for the pong of a ping.
+    ///
+    /// A path whose pong did not arrive within this time, and which showed no other sign of
+    /// life, is no longer used.  Must be larger than the round trip time of the direct
+    /// paths.  Defaults to 5 seconds.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Sets how often a lost holepunching ping is resent.
+    ///
+    /// On lossy links a single lost ping can fail a holepunching attempt.  Each retry is
+    /// sent once the previous ping timed out, only then holepunching counts as failed.
+    /// Defaults to 0.
+    pub fn ping_retries(mut self, retries: u32) -> Self {
+        self.ping_retries = retries;
+        self
+    }
+
+    /// Sets how many received messages are remembered per node to detect replays.
+    ///
+    /// A received message which was already seen within the window is a replay and dropped,
+    /// these are counted in the `disco_recv_replayed` metric.  Nodes sending many DISCO
+    /// messages, e.g. while holepunching to many candidate paths, need a larger window.  A
+    /// window of 0 disables the replay protection.  Defaults to 128.
+    ///
+    /// DISCO messages carry no timestamp, only a random nonce, so the protection only covers
+    /// the window: a message replayed after [`Self::replay_max_age`], or after the sender
+    /// sent this many newer messages, is accepted again.  Such a replay is at worst a stale
+    /// ping, pong or call-me-maybe, pongs for pings which already timed out are ignored.
+    pub fn replay_window(mut self, size: usize) -> Self {
+        self.replay_window = size;
+        self
+    }
+
+    /// Sets how long received messages are remembered to detect replays.
+    ///
+    /// Replays of older messages are not detected, see [`Self::replay_window`].  Must not be
+    /// zero, otherwise [`Builder::bind`] will fail.  Defaults to 1 minute.
+    ///
+    /// [`Builder::bind`]: crate::endpoint::Builder::bind
+    pub fn replay_max_age(mut self, max_age: Duration) -> Self {
+        self.replay_max_age = max_age;
+        self
+    }
+
+    /// Sets the time between two holepunching pings to the candidate paths of a node.
+    ///
+    /// Some NATs rate limit or drop pings sent to many addresses at once as a port scan,
+    /// spacing them out improves holepunching through strict carrier-grade NATs.  Defaults
+    /// to 0, sending all pings at once.
+    pub fn probe_spacing(mut self, spacing: Duration) -> Self {
+        self.probe_spacing = spacing;
+        self
+    }
+
+    /// Sets the maximum random delay added to each holepunching ping.
+    ///
+    /// Defaults to 0.
+    pub fn probe_jitter(mut self, jitter: Duration) -> Self {
+        self.probe_jitter = jitter;
+        self
+    }
+
+    /// Sets how many rounds of holepunching pings are sent to the candidate paths.
+    ///
+    /// Each round pings all candidate paths again, spacing the pings twice as far apart as
+    /// the previous round.  Rounds after a direct path was found are not sent.  Must be
+    /// between 1 and 8 and rounds after the first need a non-zero [`Self::probe_spacing`].
+    /// Defaults to 1.
+    pub fn probe_rounds(mut self, rounds: u32) -> Self {
+        self.probe_rounds = rounds;
+        self
+    }
+
+    /// Returns how many received messages are remembered per node to detect replays.
+    pub fn replay_window_size(&self) -> usize {
+        self.replay_window
+    }
+}
+
 /// Identifier to look up a [`NodeState`] in the [`NodeMap`].
 ///
 /// You can look up entries in [`NodeMap`] with various keys, depending on the context you
@@ -112,6 +298,13 @@ pub enum Source {
     Relay,
     /// Application layer added the address directly.
     App,
+    /// Application layer hinted the address as known to work, see
+    /// [`Endpoint::add_peer_addr`].
+    ///
+    /// Hinted addresses are tried first when no direct path is confirmed yet.
+    ///
+    /// [`Endpoint::add_peer_addr`]: crate::Endpoint::add_peer_addr
+    Hint,
     /// The address was discovered by a discovery service.
     #[strum(serialize = "{name}")]
     Discovery {
@@ -129,14 +322,22 @@ pub enum Source {
 impl NodeMap {
     #[cfg(not(any(test, feature = "test-utils")))]
     /// Create a new [`NodeMap`] from a list of [`NodeAddr`]s.
-    pub(super) fn load_from_vec(nodes: Vec<NodeAddr>) -> Self {
-        Self::from_inner(NodeMapInner::load_from_vec(nodes))
+    pub(super) fn load_from_vec(nodes: Vec<NodeAddr>, disco_config: DiscoConfig) -> Self {
+        Self::from_inner(NodeMapInner::load_from_vec(nodes, disco_config))
     }
 
     #[cfg(any(test, feature = "test-utils"))]
     /// Create a new [`NodeMap`] from a list of [`NodeAddr`]s.
-    pub(super) fn load_from_vec(nodes: Vec<NodeAddr>, path_selection: PathSelection) -> Self {
-        Self::from_inner(NodeMapInner::load_from_vec(nodes, path_selection))
+    pub(super) fn load_from_vec(
+        nodes: Vec<NodeAddr>,
+        disco_config: DiscoConfig,
+        path_selection: PathSelection,
+    ) -> Self {
+        Self::from_inner(NodeMapInner::load_from_vec(
+            nodes,
+            disco_config,
+            path_selection,
+        ))
     }
 
     fn from_inner(inner: NodeMapInner) -> Self {
@@ -158,19 +359,107 @@ impl NodeMap {
         self.inner.lock().expect("poisoned").node_count()
     }
 
+    /// Returns whether the DISCO pings of the node are answered without asking the
+    /// [`DiscoTrust`] hook.
+    ///
+    /// Nodes are trusted once the application added addressing information for them, or
+    /// once one of their pings was answered.  Nodes only known because they sent us
+    /// datagrams are not.
+    ///
+    /// [`DiscoTrust`]: super::DiscoTrust
+    pub(super) fn is_disco_trusted(&self, node_id: NodeId) -> bool {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeId(node_id))
+            .is_some_and(|node_state| node_state.is_disco_trusted())
+    }
+
+    /// Marks the node we believe to be at `udp_addr` as recently used, having received `len`
+    /// payload bytes from it.
+    ///
+    /// If `local_ip` is given it is recorded as the source address to send to `udp_addr`
+    /// from, see [`NodeMap::udp_local_ip`].
     #[cfg(not(wasm_browser))]
     pub(super) fn receive_udp(
         &self,
         udp_addr: SocketAddr,
+        local_ip: Option<IpAddr>,
+        len: usize,
     ) -> Option<(PublicKey, NodeIdMappedAddr)> {
-        self.inner.lock().expect("poisoned").receive_udp(udp_addr)
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .receive_udp(udp_addr, local_ip, len)
     }
 
-    pub(super) fn receive_relay(&self, relay_url: &RelayUrl, src: NodeId) -> NodeIdMappedAddr {
+    /// Returns whether `udp_addr` is a known path of a node.
+    pub(super) fn has_udp_path(&self, udp_addr: SocketAddr) -> bool {
         self.inner
             .lock()
             .expect("poisoned")
-            .receive_relay(relay_url, src)
+            .get(NodeStateKey::IpPort(udp_addr.into()))
+            .is_some()
+    }
+
+    /// Records the local IP address a datagram from `udp_addr` was received on.
+    ///
+    /// Only known paths are updated, this is used for datagrams which do not go through
+    /// [`NodeMap::receive_udp`] such as DISCO messages.
+    #[cfg(not(wasm_browser))]
+    pub(super) fn set_udp_local_ip(&self, udp_addr: SocketAddr, local_ip: IpAddr) {
+        let mut inner = self.inner.lock().expect("poisoned");
+        let ip_port: IpPort = udp_addr.into();
+        if let Some(node_state) = inner.get_mut(NodeStateKey::IpPort(ip_port)) {
+            node_state.set_udp_local_ip(ip_port, local_ip);
+        }
+    }
+
+    /// The local IP address to send datagrams to `udp_addr` from.
+    ///
+    /// This is the address the last datagram from `udp_addr` was received on, if the
+    /// socket is bound to the unspecified address.  Sending from it makes sure replies are
+    /// sent from the address the remote sent to, rather than the one picked by the routing
+    /// table.
+    #[cfg(not(wasm_browser))]
+    pub(super) fn udp_local_ip(&self, udp_addr: SocketAddr) -> Option<IpAddr> {
+        let inner = self.inner.lock().expect("poisoned");
+        inner
+            .get(NodeStateKey::IpPort(udp_addr.into()))
+            .and_then(|node_state| node_state.udp_local_ip(udp_addr))
+    }
+
+    pub(super) fn receive_relay(
+        &self,
+        relay_url: &RelayUrl,
+        src: NodeId,
+        len: usize,
+    ) -> NodeIdMappedAddr {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .receive_relay(relay_url, src, len)
+    }
+
+    /// The path the last payload datagram from the node at `addr` was received on.
+    pub(super) fn last_recv_path(&self, addr: NodeIdMappedAddr) -> Option<SendAddr> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeIdMappedAddr(addr))
+            .and_then(|ep| ep.last_recv_path().cloned())
+    }
+
+    /// Records the payload bytes sent to the node at `addr` over direct paths and the relay.
+    pub(super) fn record_sent(&self, addr: NodeIdMappedAddr, direct: usize, relay: usize) {
+        if let Some(ep) = self
+            .inner
+            .lock()
+            .expect("poisoned")
+            .get_mut(NodeStateKey::NodeIdMappedAddr(addr))
+        {
+            ep.record_sent(direct, relay);
+        }
     }
 
     pub(super) fn notify_ping_sent(
@@ -191,17 +480,41 @@ impl NodeMap {
         }
     }
 
-    pub(super) fn notify_ping_timeout(&self, id: usize, tx_id: stun_rs::TransactionId) {
+    /// Returns whether the paced holepunching ping to the node should still be sent.
+    pub(super) fn paced_probe_due(&self, id: usize, dst: &SendAddr) -> bool {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get_mut(NodeStateKey::Idx(id))
+            .is_some_and(|ep| ep.paced_probe_due(dst))
+    }
+
+    /// Records that the paced holepunching ping to the node could not be scheduled.
+    pub(super) fn paced_probe_dropped(&self, id: usize) {
         if let Some(ep) = self
             .inner
             .lock()
             .expect("poisoned")
             .get_mut(NodeStateKey::Idx(id))
         {
-            ep.ping_timeout(tx_id);
+            ep.paced_probe_dropped();
         }
     }
 
+    /// Returns `true` if the expired ping was a failed hole punching attempt.
+    pub(super) fn notify_ping_timeout(
+        &self,
+        id: usize,
+        tx_id: stun_rs::TransactionId,
+    ) -> PingTimeout {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get_mut(NodeStateKey::Idx(id))
+            .map(|ep| ep.ping_timeout(tx_id))
+            .unwrap_or_default()
+    }
+
     pub(super) fn get_quic_mapped_addr_for_node_key(
         &self,
         node_key: NodeId,
@@ -253,7 +566,7 @@ impl NodeMap {
         have_ipv6: bool,
     ) -> Option<(
         PublicKey,
-        Option<SocketAddr>,
+        SmallVec<[(SocketAddr, Option<IpAddr>); 1]>,
         Option<RelayUrl>,
         Vec<PingAction>,
     )> {
@@ -261,8 +574,23 @@ impl NodeMap {
         let ep = inner.get_mut(NodeStateKey::NodeIdMappedAddr(addr))?;
         let public_key = *ep.public_key();
         trace!(dest = %addr, node_id = %public_key.fmt_short(), "dst mapped to NodeId");
-        let (udp_addr, relay_url, msgs) = ep.get_send_addrs(have_ipv6);
-        Some((public_key, udp_addr, relay_url, msgs))
+        let (udp_addrs, relay_url, msgs) = ep.get_send_addrs(have_ipv6);
+        let udp_addrs = udp_addrs
+            .into_iter()
+            .map(|addr| (addr, ep.udp_local_ip(addr)))
+            .collect();
+        Some((public_key, udp_addrs, relay_url, msgs))
+    }
+
+    /// Returns the smoothed RTT of the direct path to `udp_addr` of the node, if known.
+    pub(super) fn udp_path_rtt(
+        &self,
+        node: NodeIdMappedAddr,
+        udp_addr: SocketAddr,
+    ) -> Option<Duration> {
+        let inner = self.inner.lock().expect("poisoned");
+        let ep = inner.get(NodeStateKey::NodeIdMappedAddr(node))?;
+        ep.udp_path_rtt(udp_addr)
     }
 
     pub(super) fn notify_shutdown(&self) {
@@ -310,6 +638,36 @@ impl NodeMap {
         self.inner.lock().expect("poisoned").conn_type(node_id)
     }
 
+    /// Returns a [`Watcher`] for the [`ConnectionTypeInfo`] of the node.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is not an entry in the [`NodeMap`] for
+    /// the `node_id`
+    pub(super) fn conn_type_info(
+        &self,
+        node_id: NodeId,
+    ) -> anyhow::Result<Watcher<ConnectionTypeInfo>> {
+        match self
+            .inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeId(node_id))
+        {
+            Some(ep) => Ok(ep.conn_type_info()),
+            None => anyhow::bail!("No endpoint for {node_id:?} found"),
+        }
+    }
+
+    /// Subscribes to the [`HolepunchEvent`]s of all nodes.
+    pub(super) fn holepunch_events(&self) -> broadcast::Receiver<HolepunchEvent> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .holepunch_events
+            .subscribe()
+    }
+
     /// Get the [`RemoteInfo`]s for the node identified by [`NodeId`].
     pub(super) fn remote_info(&self, node_id: NodeId) -> Option<RemoteInfo> {
         self.inner.lock().expect("poisoned").remote_info(node_id)
@@ -331,8 +689,11 @@ impl NodeMap {
 impl NodeMapInner {
     #[cfg(not(any(test, feature = "test-utils")))]
     /// Create a new [`NodeMap`] from a list of [`NodeAddr`]s.
-    fn load_from_vec(nodes: Vec<NodeAddr>) -> Self {
-        let mut me = Self::default();
+    fn load_from_vec(nodes: Vec<NodeAddr>, disco_config: DiscoConfig) -> Self {
+        let mut me = Self {
+            disco_config,
+            ..Default::default()
+        };
         for node_addr in nodes {
             me.add_node_addr(node_addr, Source::Saved);
         }
@@ -341,8 +702,13 @@ impl NodeMapInner {
 
     #[cfg(any(test, feature = "test-utils"))]
     /// Create a new [`NodeMap`] from a list of [`NodeAddr`]s.
-    fn load_from_vec(nodes: Vec<NodeAddr>, path_selection: PathSelection) -> Self {
+    fn load_from_vec(
+        nodes: Vec<NodeAddr>,
+        disco_config: DiscoConfig,
+        path_selection: PathSelection,
+    ) -> Self {
         let mut me = Self {
+            disco_config,
             path_selection,
             ..Default::default()
         };
@@ -358,6 +724,7 @@ impl NodeMapInner {
         let source0 = source.clone();
         let node_id = node_addr.node_id;
         let relay_url = node_addr.relay_url.clone();
+        let disco_config = self.disco_config;
         #[cfg(any(test, feature = "test-utils"))]
         let path_selection = self.path_selection;
         let node_state = self.get_or_insert_with(NodeStateKey::NodeId(node_id), || Options {
@@ -365,6 +732,7 @@ impl NodeMapInner {
             relay_url,
             active: false,
             source,
+            disco_config,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection,
         });
@@ -373,6 +741,7 @@ impl NodeMapInner {
             &node_addr.direct_addresses,
             source0,
         );
+        node_state.set_disco_trusted();
         let id = node_state.id();
         for addr in node_addr.direct_addresses() {
             self.set_node_state_for_ip_port(*addr, id);
@@ -440,18 +809,24 @@ impl NodeMapInner {
 
     /// Marks the node we believe to be at `ipp` as recently used.
     #[cfg(not(wasm_browser))]
-    fn receive_udp(&mut self, udp_addr: SocketAddr) -> Option<(NodeId, NodeIdMappedAddr)> {
+    fn receive_udp(
+        &mut self,
+        udp_addr: SocketAddr,
+        local_ip: Option<IpAddr>,
+        len: usize,
+    ) -> Option<(NodeId, NodeIdMappedAddr)> {
         let ip_port: IpPort = udp_addr.into();
         let Some(node_state) = self.get_mut(NodeStateKey::IpPort(ip_port)) else {
             trace!(src=%udp_addr, "receive_udp: no node_state found for addr, ignore");
             return None;
         };
-        node_state.receive_udp(ip_port, Instant::now());
+        node_state.receive_udp(ip_port, local_ip, len, Instant::now());
         Some((*node_state.public_key(), *node_state.quic_mapped_addr()))
     }
 
     #[instrument(skip_all, fields(src = %src.fmt_short()))]
-    fn receive_relay(&mut self, relay_url: &RelayUrl, src: NodeId) -> NodeIdMappedAddr {
+    fn receive_relay(&mut self, relay_url: &RelayUrl, src: NodeId, len: usize) -> NodeIdMappedAddr {
+        let disco_config = self.disco_config;
         #[cfg(any(test, feature = "test-utils"))]
         let path_selection = self.path_selection;
         let node_state = self.get_or_insert_with(NodeStateKey::NodeId(src), || {
@@ -461,11 +836,12 @@ impl NodeMapInner {
                 relay_url: Some(relay_url.clone()),
                 active: true,
                 source: Source::Relay,
+                disco_config,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection,
             }
         });
-        node_state.receive_relay(relay_url, src, Instant::now());
+        node_state.receive_relay(relay_url, src, len, Instant::now());
         *node_state.quic_mapped_addr()
     }
 
@@ -540,6 +916,7 @@ impl NodeMapInner {
     }
 
     fn handle_ping(&mut self, sender: NodeId, src: SendAddr, tx_id: TransactionId) -> PingHandled {
+        let disco_config = self.disco_config;
         #[cfg(any(test, feature = "test-utils"))]
         let path_selection = self.path_selection;
         let node_state = self.get_or_insert_with(NodeStateKey::NodeId(sender), || {
@@ -554,11 +931,14 @@ impl NodeMapInner {
                 relay_url: src.relay_url(),
                 active: true,
                 source,
+                disco_config,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection,
             }
         });
 
+        // Pings only get here if they are answered.
+        node_state.set_disco_trusted();
         let handled = node_state.handle_ping(src.clone(), tx_id);
         if let SendAddr::Udp(ref addr) = src {
             if matches!(handled.role, PingRole::NewPath) {
@@ -578,7 +958,7 @@ impl NodeMapInner {
         );
         let id = self.next_id;
         self.next_id = self.next_id.wrapping_add(1);
-        let node_state = NodeState::new(id, options);
+        let node_state = NodeState::new(id, options, self.holepunch_events.clone());
 
         // update indices
         self.by_quic_mapped_addr
@@ -757,7 +1137,11 @@ mod tests {
                 Some(addr)
             })
             .collect();
-        let loaded_node_map = NodeMap::load_from_vec(addrs.clone(), PathSelection::default());
+        let loaded_node_map = NodeMap::load_from_vec(
+            addrs.clone(),
+            DiscoConfig::default(),
+            PathSelection::default(),
+        );
 
         let mut loaded: Vec<NodeAddr> = loaded_node_map
             .list_remote_infos(Instant::now())
@@ -798,6 +1182,7 @@ mod tests {
                 source: Source::NamedApp {
                     name: "test".into(),
                 },
+                disco_config: DiscoConfig::default(),
                 path_selection: PathSelection::default(),
             })
             .id();
@@ -814,7 +1199,7 @@ mod tests {
             // add address
             node_map.add_test_addr(node_addr);
             // make it active
-            node_map.inner.lock().unwrap().receive_udp(addr);
+            node_map.inner.lock().unwrap().receive_udp(addr, None, 0);
         }
 
         info!("Adding offline/inactive addresses");
@@ -867,7 +1252,7 @@ mod tests {
             .inner
             .lock()
             .unwrap()
-            .receive_udp(addr)
+            .receive_udp(addr, None, 0)
             .expect("registered");
 
         for _ in 0..MAX_INACTIVE_NODES + 1 {
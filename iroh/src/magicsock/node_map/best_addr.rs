@@ -6,10 +6,20 @@ use n0_future::time::{Duration, Instant};
 use tracing::{debug, info};
 
 /// How long we trust a UDP address as the exclusive path (without using relay) without having heard a Pong reply.
-const TRUST_UDP_ADDR_DURATION: Duration = Duration::from_millis(6500);
+pub(super) const TRUST_UDP_ADDR_DURATION: Duration = Duration::from_millis(6500);
 
-#[derive(Debug, Default)]
-pub(super) struct BestAddr(Option<BestAddrInner>);
+#[derive(Debug)]
+pub(super) struct BestAddr {
+    inner: Option<BestAddrInner>,
+    /// How long the address is trusted after it was last confirmed.
+    trust_duration: Duration,
+}
+
+impl Default for BestAddr {
+    fn default() -> Self {
+        Self::new(TRUST_UDP_ADDR_DURATION)
+    }
+}
 
 #[derive(Debug)]
 struct BestAddrInner {
@@ -38,12 +48,12 @@ pub(super) enum Source {
 }
 
 impl Source {
-    fn trust_until(&self, from: Instant) -> Instant {
+    fn trust_until(&self, from: Instant, trust_duration: Duration) -> Instant {
         match self {
-            Source::ReceivedPong => from + TRUST_UDP_ADDR_DURATION,
+            Source::ReceivedPong => from + trust_duration,
             // TODO: Fix time
             Source::BestCandidate => from + Duration::from_secs(60 * 60),
-            Source::Udp => from + TRUST_UDP_ADDR_DURATION,
+            Source::Udp => from + trust_duration,
         }
     }
 }
@@ -61,9 +71,19 @@ pub enum ClearReason {
     Inactive,
     PongTimeout,
     MatchesOurLocalAddr,
+    Degraded,
 }
 
 impl BestAddr {
+    /// Creates an empty best address, trusting addresses for `trust_duration` after they
+    /// were confirmed.
+    pub fn new(trust_duration: Duration) -> Self {
+        Self {
+            inner: None,
+            trust_duration,
+        }
+    }
+
     #[cfg(test)]
     pub fn from_parts(
         addr: SocketAddr,
@@ -76,16 +96,19 @@ impl BestAddr {
             confirmed_at,
             trust_until: Some(trust_until),
         };
-        Self(Some(inner))
+        Self {
+            inner: Some(inner),
+            trust_duration: TRUST_UDP_ADDR_DURATION,
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_none()
+        self.inner.is_none()
     }
 
     /// Unconditionally clears the best address.
     pub fn clear(&mut self, reason: ClearReason, has_relay: bool) {
-        let old = self.0.take();
+        let old = self.inner.take();
         if let Some(old_addr) = old.as_ref().map(BestAddrInner::addr) {
             info!(?reason, ?has_relay, %old_addr, "clearing best_addr");
         }
@@ -99,7 +122,7 @@ impl BestAddr {
     }
 
     pub fn clear_trust(&mut self, why: &'static str) {
-        if let Some(state) = self.0.as_mut() {
+        if let Some(state) = self.inner.as_mut() {
             info!(
                 %why,
                 prev_trust_until = ?state.trust_until,
@@ -116,7 +139,7 @@ impl BestAddr {
         source: Source,
         confirmed_at: Instant,
     ) {
-        match self.0.as_mut() {
+        match self.inner.as_mut() {
             None => {
                 self.insert(addr, latency, source, confirmed_at);
             }
@@ -126,7 +149,7 @@ impl BestAddr {
                     self.insert(addr, latency, source, confirmed_at);
                 } else if state.addr.addr == addr {
                     state.confirmed_at = confirmed_at;
-                    state.trust_until = Some(source.trust_until(confirmed_at));
+                    state.trust_until = Some(source.trust_until(confirmed_at, self.trust_duration));
                 }
             }
         }
@@ -135,10 +158,10 @@ impl BestAddr {
     /// Reset the expiry, if the passed in addr matches the currently used one.
     #[cfg(not(wasm_browser))]
     pub fn reconfirm_if_used(&mut self, addr: SocketAddr, source: Source, confirmed_at: Instant) {
-        if let Some(state) = self.0.as_mut() {
+        if let Some(state) = self.inner.as_mut() {
             if state.addr.addr == addr {
                 state.confirmed_at = confirmed_at;
-                state.trust_until = Some(source.trust_until(confirmed_at));
+                state.trust_until = Some(source.trust_until(confirmed_at, self.trust_duration));
             }
         }
     }
@@ -150,10 +173,10 @@ impl BestAddr {
         source: Source,
         confirmed_at: Instant,
     ) {
-        let trust_until = source.trust_until(confirmed_at);
+        let trust_until = source.trust_until(confirmed_at, self.trust_duration);
 
         if self
-            .0
+            .inner
             .as_ref()
             .map(|prev| prev.addr.addr == addr)
             .unwrap_or_default()
@@ -177,11 +200,11 @@ impl BestAddr {
             trust_until: Some(trust_until),
             confirmed_at,
         };
-        self.0 = Some(inner);
+        self.inner = Some(inner);
     }
 
     pub fn state(&self, now: Instant) -> State {
-        match &self.0 {
+        match &self.inner {
             None => State::Empty,
             Some(state) => match state.trust_until {
                 Some(expiry) if now < expiry => State::Valid(&state.addr),
@@ -191,7 +214,7 @@ impl BestAddr {
     }
 
     pub fn addr(&self) -> Option<SocketAddr> {
-        self.0.as_ref().map(BestAddrInner::addr)
+        self.inner.as_ref().map(BestAddrInner::addr)
     }
 }
 
@@ -0,0 +1,125 @@
+//! Events of the holepunching attempts to remote nodes.
+
+use std::net::SocketAddr;
+
+use iroh_base::{NodeId, RelayUrl};
+use n0_future::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// How many [`HolepunchEvent`]s are kept for subscribers which are not keeping up.
+const HOLEPUNCH_EVENTS_CAPACITY: usize = 256;
+
+/// An event of a holepunching attempt to a remote node.
+///
+/// An attempt starts when data is sent to a node without a confirmed direct path, and ends
+/// once a direct path is confirmed or all pings to the candidate paths were lost.  See
+/// [`Endpoint::holepunch_events`].
+///
+/// [`Endpoint::holepunch_events`]: crate::Endpoint::holepunch_events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HolepunchEvent {
+    /// A holepunching attempt started.
+    Started {
+        /// The remote node.
+        node_id: NodeId,
+        /// The candidate direct addresses of the remote node.
+        candidates: Vec<SocketAddr>,
+    },
+    /// Pings were sent to candidate paths.
+    ///
+    /// Emitted for the pings sent right away, e.g. after receiving a call-me-maybe message
+    /// with new candidates from the remote node.  Pings delayed by the [`DiscoConfig`]
+    /// probe pacing are each reported when they are sent, in all rounds.
+    ///
+    /// [`DiscoConfig`]: crate::magicsock::DiscoConfig
+    PingsSent {
+        /// The remote node.
+        node_id: NodeId,
+        /// The addresses pinged.
+        addrs: Vec<SocketAddr>,
+    },
+    /// The first pong of the attempt was received on a direct path.
+    FirstPong {
+        /// The remote node.
+        node_id: NodeId,
+        /// The address of the remote node the pong was received from.
+        addr: SocketAddr,
+        /// The round trip time of the ping.
+        latency: Duration,
+    },
+    /// A direct path was selected, holepunching succeeded.
+    Direct {
+        /// The remote node.
+        node_id: NodeId,
+        /// The address of the remote node.
+        addr: SocketAddr,
+        /// Our address as observed by the remote node, if it was observed on a direct path.
+        observed_addr: Option<SocketAddr>,
+        /// The round trip time of the path.
+        latency: Duration,
+        /// The time since the attempt started.
+        elapsed: Duration,
+    },
+    /// All pings to the candidate paths were lost, the connection stays on the relay.
+    Relayed {
+        /// The remote node.
+        node_id: NodeId,
+        /// The relay server used for the node, if any.
+        relay_url: Option<RelayUrl>,
+        /// The time since the attempt started.
+        elapsed: Duration,
+    },
+}
+
+impl HolepunchEvent {
+    /// Returns the remote node of the attempt.
+    pub fn node_id(&self) -> NodeId {
+        match self {
+            Self::Started { node_id, .. }
+            | Self::PingsSent { node_id, .. }
+            | Self::FirstPong { node_id, .. }
+            | Self::Direct { node_id, .. }
+            | Self::Relayed { node_id, .. } => *node_id,
+        }
+    }
+}
+
+/// The channel broadcasting the [`HolepunchEvent`]s of all nodes.
+#[derive(Debug, Clone)]
+pub(super) struct HolepunchEvents(broadcast::Sender<HolepunchEvent>);
+
+impl Default for HolepunchEvents {
+    fn default() -> Self {
+        Self(broadcast::Sender::new(HOLEPUNCH_EVENTS_CAPACITY))
+    }
+}
+
+impl HolepunchEvents {
+    /// Sends an event, dropping it if there are no subscribers.
+    pub(super) fn send(&self, event: HolepunchEvent) {
+        self.0.send(event).ok();
+    }
+
+    pub(super) fn subscribe(&self) -> broadcast::Receiver<HolepunchEvent> {
+        self.0.subscribe()
+    }
+}
+
+/// A holepunching attempt in progress.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Attempt {
+    /// When the attempt started.
+    pub(super) started_at: Instant,
+    /// Whether a pong was received on a direct path.
+    pub(super) got_pong: bool,
+}
+
+impl Attempt {
+    pub(super) fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            got_pong: false,
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::{btree_map::Entry, BTreeSet, HashMap},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
     hash::Hash,
     net::{IpAddr, SocketAddr},
 };
@@ -12,21 +12,25 @@ use n0_future::{
     task::{self, AbortOnDropHandle},
     time::{self, Duration, Instant},
 };
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
 use tokio::sync::mpsc;
 use tracing::{debug, event, info, instrument, trace, warn, Level};
 
 use super::{
     best_addr::{self, ClearReason, Source as BestAddrSource},
+    holepunch::{Attempt, HolepunchEvent, HolepunchEvents},
+    path_quality::PathQuality,
     path_state::{summarize_node_paths, PathState},
     udp_paths::{NodeUdpPaths, UdpSendAddr},
-    IpPort, Source,
+    DiscoConfig, IpPort, Source,
 };
 #[cfg(any(test, feature = "test-utils"))]
 use crate::endpoint::PathSelection;
 use crate::{
     disco::{self, SendAddr},
-    magicsock::{ActorMessage, MagicsockMetrics, NodeIdMappedAddr, HEARTBEAT_INTERVAL},
+    magicsock::{ActorMessage, MagicsockMetrics, NodeIdMappedAddr},
     watchable::{Watchable, Watcher},
 };
 
@@ -39,7 +43,7 @@ pub(super) const MAX_INACTIVE_DIRECT_ADDRESSES: usize = 20;
 const LAST_ALIVE_PRUNE_DURATION: Duration = Duration::from_secs(120);
 
 /// How long we wait for a pong reply before assuming it's never coming.
-const PING_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
+pub(super) const PING_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
 
 /// The latency at or under which we don't try to upgrade to a better path.
 const GOOD_ENOUGH_LATENCY: Duration = Duration::from_millis(5);
@@ -70,6 +74,17 @@ pub(in crate::magicsock) struct SendPing {
     pub dst_node: NodeId,
     pub tx_id: stun::TransactionId,
     pub purpose: DiscoPingPurpose,
+    /// How long to wait before sending the ping, see [`DiscoConfig::probe_spacing`].
+    pub delay: Duration,
+}
+
+/// The outcome of a ping whose pong was not received in time.
+#[derive(Debug, Default)]
+pub(in crate::magicsock) struct PingTimeout {
+    /// Whether the ping was the last attempt to holepunch the path.
+    pub holepunch_failed: bool,
+    /// The ping resending the lost one, see [`DiscoConfig::ping_retries`].
+    pub retry: Option<SendPing>,
 }
 
 /// Indicating an [`NodeState`] has handled a ping.
@@ -124,12 +139,14 @@ pub(super) struct NodeState {
     ///
     /// Note that sending datagrams to a node does not mean the node receives them.
     last_used: Option<Instant>,
+    /// The path the last payload datagram from this node was received on.
+    last_recv_path: Option<SendAddr>,
     /// Last time we sent a call-me-maybe.
     ///
     /// When we do not have a direct connection and we try to send some data, we will try to
     /// do a full ping + call-me-maybe.  Usually each side only needs to send one
     /// call-me-maybe to the other for holes to be punched in both directions however.  So
-    /// we only try and send one per [`HEARTBEAT_INTERVAL`].  Each [`HEARTBEAT_INTERVAL`]
+    /// we only try and send one per [`DiscoConfig::keepalive_interval`].  Each interval
     /// the [`NodeState::stayin_alive`] function is called, which will trigger new
     /// call-me-maybe messages as backup.
     last_call_me_maybe: Option<Instant>,
@@ -139,6 +156,26 @@ pub(super) struct NodeState {
     ///
     /// Used for metric reporting.
     has_been_direct: bool,
+    /// The payload bytes exchanged with this node, by network path.
+    traffic: PathTraffic,
+    /// Timing of the DISCO pings to this node.
+    disco_config: DiscoConfig,
+    /// The holepunching attempt in progress, if any.
+    holepunch: Option<Attempt>,
+    /// Where the events of holepunching attempts are sent to.
+    holepunch_events: HolepunchEvents,
+    /// Number of holepunching pings scheduled to be sent later.
+    paced_probes: usize,
+    /// Number of holepunching attempts started.
+    holepunch_attempts: u64,
+    /// The statistics of the DISCO traffic with this node.
+    conn_type_info: Watchable<ConnectionTypeInfo>,
+    /// Whether the DISCO pings of this node are answered without asking the trust hook.
+    ///
+    /// See [`NodeMap::is_disco_trusted`].
+    ///
+    /// [`NodeMap::is_disco_trusted`]: super::NodeMap::is_disco_trusted
+    disco_trusted: bool,
     /// Configuration for what path selection to use
     #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
@@ -152,12 +189,13 @@ pub(super) struct Options {
     /// Is this endpoint currently active (sending data)?
     pub(super) active: bool,
     pub(super) source: super::Source,
+    pub(super) disco_config: DiscoConfig,
     #[cfg(any(test, feature = "test-utils"))]
     pub(super) path_selection: PathSelection,
 }
 
 impl NodeState {
-    pub(super) fn new(id: usize, options: Options) -> Self {
+    pub(super) fn new(id: usize, options: Options, holepunch_events: HolepunchEvents) -> Self {
         let quic_mapped_addr = NodeIdMappedAddr::generate();
 
         if options.relay_url.is_some() {
@@ -178,12 +216,21 @@ impl NodeState {
                     PathState::new(options.node_id, SendAddr::Relay(url), options.source, now),
                 )
             }),
-            udp_paths: NodeUdpPaths::new(),
+            udp_paths: NodeUdpPaths::new(options.disco_config.idle_timeout),
+            disco_config: options.disco_config,
+            holepunch: None,
+            holepunch_events,
+            paced_probes: 0,
+            holepunch_attempts: 0,
+            conn_type_info: Default::default(),
+            disco_trusted: false,
             sent_pings: HashMap::new(),
             last_used: options.active.then(Instant::now),
+            last_recv_path: None,
             last_call_me_maybe: None,
             conn_type: Watchable::new(ConnectionType::None),
             has_been_direct: false,
+            traffic: PathTraffic::default(),
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: options.path_selection,
         }
@@ -201,10 +248,45 @@ impl NodeState {
         self.id
     }
 
+    pub(super) fn is_disco_trusted(&self) -> bool {
+        self.disco_trusted
+    }
+
+    pub(super) fn set_disco_trusted(&mut self) {
+        self.disco_trusted = true;
+    }
+
     pub(super) fn conn_type(&self) -> Watcher<ConnectionType> {
         self.conn_type.watch()
     }
 
+    pub(super) fn conn_type_info(&self) -> Watcher<ConnectionTypeInfo> {
+        self.conn_type_info.watch()
+    }
+
+    /// Updates the [`ConnectionTypeInfo`] from the current path states.
+    fn update_conn_type_info(&self) {
+        let path_info = |state: &PathState| DiscoPathInfo {
+            last_pong: state.recent_pong.as_ref().map(|pong| pong.pong_at),
+            quality: state.quality.quality(),
+        };
+        let info = ConnectionTypeInfo {
+            conn_type: self.conn_type.get(),
+            direct: self
+                .udp_paths
+                .paths
+                .iter()
+                .map(|(ipp, state)| (SocketAddr::from(*ipp), path_info(state)))
+                .collect(),
+            relay: self
+                .relay_url
+                .as_ref()
+                .map(|(url, state)| (url.clone(), path_info(state))),
+            holepunch_attempts: self.holepunch_attempts,
+        };
+        self.conn_type_info.set(info).ok();
+    }
+
     /// Returns info about this node.
     pub(super) fn info(&self, now: Instant) -> RemoteInfo {
         let conn_type = self.conn_type.get();
@@ -255,6 +337,7 @@ impl NodeState {
                     .iter()
                     .map(|(source, instant)| (source.clone(), now.duration_since(*instant)))
                     .collect(),
+                quality: path_state.quality.quality(),
             })
             .collect();
 
@@ -265,6 +348,7 @@ impl NodeState {
             conn_type,
             latency,
             last_used: self.last_used.map(|instant| now.duration_since(instant)),
+            traffic: self.traffic,
         }
     }
 
@@ -327,6 +411,7 @@ impl NodeState {
                 conn_type = ?typ,
             );
             info!(%typ, "new connection type");
+            self.update_conn_type_info();
 
             // Update some metrics
             match (prev_typ, typ) {
@@ -426,17 +511,25 @@ impl NodeState {
     }
 
     /// Cleanup the expired ping for the passed in txid.
+    ///
+    /// Returns `true` if the ping was a hole punching ping and no direct path to the node
+    /// is known afterwards.
     #[instrument("disco", skip_all, fields(node = %self.node_id.fmt_short()))]
-    pub(super) fn ping_timeout(&mut self, txid: stun::TransactionId) {
+    pub(super) fn ping_timeout(&mut self, txid: stun::TransactionId) -> PingTimeout {
+        let mut timeout = PingTimeout::default();
         if let Some(sp) = self.sent_pings.remove(&txid) {
             debug!(tx = %HEXLOWER.encode(&txid), addr = %sp.to, "pong not received in timeout");
             match sp.to {
                 SendAddr::Udp(addr) => {
                     if let Some(path_state) = self.udp_paths.paths.get_mut(&addr.into()) {
                         path_state.last_ping = None;
+                        path_state.quality.record_lost();
+                        let degraded = path_state.quality.is_degraded();
                         let consider_alive = path_state
                             .last_alive()
-                            .map(|last_alive| last_alive.elapsed() <= PING_TIMEOUT_DURATION)
+                            .map(|last_alive| {
+                                last_alive.elapsed() <= self.disco_config.ping_timeout
+                            })
                             .unwrap_or(false);
                         if !consider_alive {
                             // If there was no sign of life from this path during the time
@@ -450,6 +543,9 @@ impl NodeState {
                                 self.relay_url().is_some(),
                             )
                         }
+                        if degraded {
+                            self.clear_degraded_best_addr(addr);
+                        }
                     } else {
                         // If we have no state for the best addr it should have been cleared
                         // anyway.
@@ -465,11 +561,41 @@ impl NodeState {
                         if home_relay == url {
                             // lost connectivity via relay
                             relay_state.last_ping = None;
+                            relay_state.quality.record_lost();
                         }
                     }
                 }
             }
+            let holepunching = sp.purpose == DiscoPingPurpose::Discovery
+                && matches!(sp.to, SendAddr::Udp(_))
+                && self.udp_paths.best_addr.is_empty();
+            if holepunching {
+                timeout.retry = self.retry_ping(&sp.to);
+                timeout.holepunch_failed = timeout.retry.is_none();
+                if timeout.holepunch_failed {
+                    self.holepunch_failed();
+                }
+            }
+            self.update_conn_type_info();
+        }
+        timeout
+    }
+
+    /// Resends a lost holepunching ping, if the path has retries left.
+    ///
+    /// See [`DiscoConfig::ping_retries`].
+    fn retry_ping(&mut self, to: &SendAddr) -> Option<SendPing> {
+        let SendAddr::Udp(addr) = to else {
+            return None;
+        };
+        let path_state = self.udp_paths.paths.get_mut(&(*addr).into())?;
+        if path_state.ping_retries >= self.disco_config.ping_retries {
+            path_state.ping_retries = 0;
+            return None;
         }
+        path_state.ping_retries += 1;
+        debug!(%addr, retry = path_state.ping_retries, "resending lost ping");
+        self.start_ping(to.clone(), DiscoPingPurpose::Discovery)
     }
 
     #[must_use = "pings must be handled"]
@@ -502,9 +628,78 @@ impl NodeState {
             dst_node: self.node_id,
             tx_id,
             purpose,
+            delay: Duration::ZERO,
         })
     }
 
+    /// Spreads the holepunching pings over time.
+    ///
+    /// Each ping is sent the probe spacing after the previous one, delayed by a random
+    /// jitter.  Further rounds ping the same paths again, each round spacing its pings twice
+    /// as far apart as the previous one.
+    fn pace_probes(&mut self, pings: Vec<SendPing>) -> Vec<SendPing> {
+        let DiscoConfig {
+            probe_spacing,
+            probe_jitter,
+            probe_rounds,
+            ..
+        } = self.disco_config;
+        if probe_spacing.is_zero() && probe_jitter.is_zero() && probe_rounds <= 1 {
+            return pings;
+        }
+        let dsts: Vec<_> = pings.iter().map(|ping| ping.dst.clone()).collect();
+        let mut rounds = vec![pings];
+        for _ in 1..probe_rounds {
+            rounds.push(
+                dsts.iter()
+                    .filter_map(|dst| self.start_ping(dst.clone(), DiscoPingPurpose::Discovery))
+                    .collect(),
+            );
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut offset = Duration::ZERO;
+        let mut paced = Vec::with_capacity(dsts.len() * rounds.len());
+        for (round, pings) in rounds.into_iter().enumerate() {
+            let spacing = probe_spacing * 2u32.pow(round as u32);
+            if round > 0 {
+                offset += spacing;
+            }
+            for mut ping in pings {
+                ping.delay = offset + rng.gen_range(Duration::ZERO..=probe_jitter);
+                offset += spacing;
+                if !ping.delay.is_zero() {
+                    self.paced_probes += 1;
+                }
+                paced.push(ping);
+            }
+        }
+        paced
+    }
+
+    /// Returns whether a paced holepunching ping to `dst` should still be sent now it is due.
+    ///
+    /// Once the attempt selected a direct path the remaining pings are dropped.  Pings which
+    /// are still sent are reported in a [`HolepunchEvent::PingsSent`].
+    pub(super) fn paced_probe_due(&mut self, dst: &SendAddr) -> bool {
+        self.paced_probes = self.paced_probes.saturating_sub(1);
+        if self.holepunch.is_none() {
+            return false;
+        }
+        if let SendAddr::Udp(addr) = dst {
+            self.holepunch_events.send(HolepunchEvent::PingsSent {
+                node_id: self.node_id,
+                addrs: vec![*addr],
+            });
+        }
+        true
+    }
+
+    /// Records that a paced holepunching ping could not be scheduled.
+    pub(super) fn paced_probe_dropped(&mut self) {
+        self.paced_probes = self.paced_probes.saturating_sub(1);
+    }
+
     /// Record the fact that a ping has been sent out.
     pub(super) fn ping_sent(
         &mut self,
@@ -540,8 +735,9 @@ impl NodeState {
         }
 
         let id = self.id;
+        let ping_timeout = self.disco_config.ping_timeout;
         let _expiry_task = AbortOnDropHandle::new(task::spawn(async move {
-            time::sleep(PING_TIMEOUT_DURATION).await;
+            time::sleep(ping_timeout).await;
             sender
                 .send(ActorMessage::EndpointPingExpired(id, tx_id))
                 .await
@@ -576,7 +772,7 @@ impl NodeState {
             SendCallMeMaybe::IfNoRecent => {
                 let had_recent_call_me_maybe = self
                     .last_call_me_maybe
-                    .map(|when| when.elapsed() < HEARTBEAT_INTERVAL)
+                    .map(|when| when.elapsed() < self.disco_config.interval)
                     .unwrap_or(false);
                 if had_recent_call_me_maybe {
                     trace!("skipping call-me-maybe, still recent");
@@ -617,7 +813,7 @@ impl NodeState {
         let mut ping_msgs = Vec::with_capacity(self.udp_paths.paths.len() + 1);
 
         if let Some((url, state)) = self.relay_url.as_ref() {
-            if state.needs_ping(&now) {
+            if state.needs_ping(&now, self.disco_config.ping_interval) {
                 debug!(%url, "relay path needs ping");
                 if let Some(msg) =
                     self.start_ping(SendAddr::Relay(url.clone()), DiscoPingPurpose::Discovery)
@@ -635,19 +831,38 @@ impl NodeState {
 
         self.prune_direct_addresses();
         let mut ping_dsts = String::from("[");
+        let mut udp_pings = Vec::new();
         self.udp_paths
             .paths
             .iter()
-            .filter_map(|(ipp, state)| state.needs_ping(&now).then_some(*ipp))
+            .filter_map(|(ipp, state)| {
+                state
+                    .needs_ping(&now, self.disco_config.ping_interval)
+                    .then_some(*ipp)
+            })
             .filter_map(|ipp| {
                 self.start_ping(SendAddr::Udp(ipp.into()), DiscoPingPurpose::Discovery)
             })
             .for_each(|msg| {
                 use std::fmt::Write;
                 write!(&mut ping_dsts, " {} ", msg.dst).ok();
-                ping_msgs.push(PingAction::SendPing(msg));
+                udp_pings.push(msg);
             });
         ping_dsts.push(']');
+        if !udp_pings.is_empty() && self.udp_paths.best_addr.is_empty() {
+            udp_pings = self.pace_probes(udp_pings);
+            // Paced pings are reported once they are due, see `paced_probe_due`.
+            let sent_now = udp_pings
+                .iter()
+                .filter(|ping| ping.delay.is_zero())
+                .filter_map(|ping| match ping.dst {
+                    SendAddr::Udp(addr) => Some(addr),
+                    SendAddr::Relay(_) => None,
+                })
+                .collect();
+            self.holepunch_pings_sent(sent_now, now);
+        }
+        ping_msgs.extend(udp_pings.into_iter().map(PingAction::SendPing));
         debug!(
             %ping_dsts,
             dst = %self.node_id.fmt_short(),
@@ -655,6 +870,7 @@ impl NodeState {
             "sending pings to node",
         );
         self.last_full_ping.replace(now);
+        self.update_conn_type_info();
         ping_msgs
     }
 
@@ -710,12 +926,14 @@ impl NodeState {
     #[instrument(skip_all, fields(node = %self.node_id.fmt_short()))]
     pub(super) fn reset(&mut self) {
         self.last_full_ping = None;
+        self.holepunch = None;
         self.udp_paths
             .best_addr
             .clear(ClearReason::Reset, self.relay_url.is_some());
 
         for es in self.udp_paths.paths.values_mut() {
             es.last_ping = None;
+            es.local_ip = None;
         }
     }
 
@@ -737,7 +955,11 @@ impl NodeState {
 
         let role = match path {
             SendAddr::Udp(addr) => match self.udp_paths.paths.entry(addr.into()) {
-                Entry::Occupied(mut occupied) => occupied.get_mut().handle_ping(tx_id, now),
+                Entry::Occupied(mut occupied) => {
+                    occupied
+                        .get_mut()
+                        .handle_ping(tx_id, now, self.disco_config.interval)
+                }
                 Entry::Vacant(vacant) => {
                     info!(%addr, "new direct addr for node");
                     vacant.insert(PathState::with_ping(
@@ -746,6 +968,7 @@ impl NodeState {
                         tx_id,
                         Source::Udp,
                         now,
+                        self.disco_config.interval,
                     ));
                     PingRole::NewPath
                 }
@@ -764,11 +987,14 @@ impl NodeState {
                                 tx_id,
                                 Source::Relay,
                                 now,
+                                self.disco_config.interval,
                             ),
                         ));
                         PingRole::NewPath
                     }
-                    Some((_home_url, state)) => state.handle_ping(tx_id, now),
+                    Some((_home_url, state)) => {
+                        state.handle_ping(tx_id, now, self.disco_config.interval)
+                    }
                     None => {
                         info!(%url, "new relay addr for node");
                         self.relay_url = Some((
@@ -779,6 +1005,7 @@ impl NodeState {
                                 tx_id,
                                 Source::Relay,
                                 now,
+                                self.disco_config.interval,
                             ),
                         ));
                         PingRole::NewPath
@@ -902,6 +1129,7 @@ impl NodeState {
                 // did send this ping but it has timed-out by the time we receive this pong
                 // so we removed the state already.
                 debug!(tx = %HEXLOWER.encode(&m.tx_id), "received unknown pong (did it timeout?)");
+                inc!(MagicsockMetrics, recv_disco_stale);
                 None
             }
             Some(sp) => {
@@ -966,23 +1194,166 @@ impl NodeState {
                     },
                 }
 
+                // The latency is that of the path the ping was sent on.
+                let degraded = match sp.to {
+                    SendAddr::Udp(to) => self.udp_paths.paths.get_mut(&to.into()),
+                    SendAddr::Relay(ref url) => self
+                        .relay_url
+                        .as_mut()
+                        .filter(|(home_url, _)| home_url == url)
+                        .map(|(_, state)| state),
+                }
+                .map(|state| {
+                    state.quality.record_pong(latency);
+                    state.quality.is_degraded()
+                })
+                .unwrap_or_default();
+
                 // Promote this pong response to our current best address if it's lower latency.
                 // TODO(bradfitz): decide how latency vs. preference order affects decision
                 if let SendAddr::Udp(to) = sp.to {
                     debug_assert!(!is_relay, "mismatching relay & udp");
-                    self.udp_paths.best_addr.insert_if_better_or_reconfirm(
-                        to,
-                        latency,
-                        best_addr::Source::ReceivedPong,
-                        now,
-                    );
+                    if degraded {
+                        self.clear_degraded_best_addr(to);
+                    } else {
+                        self.udp_paths.best_addr.insert_if_better_or_reconfirm(
+                            to,
+                            latency,
+                            best_addr::Source::ReceivedPong,
+                            now,
+                        );
+                    }
+                    self.holepunch_pong(to, latency, &m.ping_observed_addr, now);
                 }
+                self.update_conn_type_info();
 
                 node_map_insert
             }
         }
     }
 
+    /// Records holepunching pings sent to the UDP paths `addrs`, starting an attempt.
+    ///
+    /// The attempt is started even if all pings are paced and `addrs` is empty.
+    fn holepunch_pings_sent(&mut self, addrs: Vec<SocketAddr>, now: Instant) {
+        if self.holepunch.is_none() {
+            self.holepunch = Some(Attempt::new(now));
+            self.holepunch_attempts += 1;
+            self.holepunch_events.send(HolepunchEvent::Started {
+                node_id: self.node_id,
+                candidates: self
+                    .udp_paths
+                    .paths
+                    .keys()
+                    .map(|ipp| (*ipp).into())
+                    .collect(),
+            });
+        }
+        if !addrs.is_empty() {
+            self.holepunch_events.send(HolepunchEvent::PingsSent {
+                node_id: self.node_id,
+                addrs,
+            });
+        }
+    }
+
+    /// Records a pong received on the UDP path `addr` during a holepunching attempt.
+    ///
+    /// The attempt ends once a best address is selected.
+    fn holepunch_pong(
+        &mut self,
+        addr: SocketAddr,
+        latency: Duration,
+        observed_addr: &SendAddr,
+        now: Instant,
+    ) {
+        let Some(attempt) = self.holepunch.as_mut() else {
+            return;
+        };
+        if !attempt.got_pong {
+            attempt.got_pong = true;
+            self.holepunch_events.send(HolepunchEvent::FirstPong {
+                node_id: self.node_id,
+                addr,
+                latency,
+            });
+        }
+        let started_at = attempt.started_at;
+        let Some(best_addr) = self.udp_paths.best_addr.addr() else {
+            return;
+        };
+        let (observed_addr, latency) = if best_addr == addr {
+            let observed_addr = match observed_addr {
+                SendAddr::Udp(observed) => Some(*observed),
+                SendAddr::Relay(_) => None,
+            };
+            (observed_addr, latency)
+        } else {
+            let latency = self
+                .udp_paths
+                .paths
+                .get(&best_addr.into())
+                .and_then(PathState::latency)
+                .unwrap_or(latency);
+            (None, latency)
+        };
+        self.holepunch = None;
+        self.holepunch_events.send(HolepunchEvent::Direct {
+            node_id: self.node_id,
+            addr: best_addr,
+            observed_addr,
+            latency,
+            elapsed: now.duration_since(started_at),
+        });
+    }
+
+    /// Ends the holepunching attempt once no holepunching pings are outstanding.
+    fn holepunch_failed(&mut self) {
+        let Some(attempt) = self.holepunch else {
+            return;
+        };
+        let outstanding = self.paced_probes > 0
+            || self.sent_pings.values().any(|sp| {
+                sp.purpose == DiscoPingPurpose::Discovery && matches!(sp.to, SendAddr::Udp(_))
+            });
+        if outstanding {
+            return;
+        }
+        self.holepunch = None;
+        self.holepunch_events.send(HolepunchEvent::Relayed {
+            node_id: self.node_id,
+            relay_url: self.relay_url(),
+            elapsed: attempt.started_at.elapsed(),
+        });
+    }
+
+    /// Stops using the UDP path `addr` as best address, because its quality degraded.
+    ///
+    /// The path is not used again until its quality recovered, in the meantime other
+    /// candidate paths and the relay are used.  The switch is visible to applications as a
+    /// change of the [`ConnectionType`].
+    fn clear_degraded_best_addr(&mut self, addr: SocketAddr) {
+        if self.udp_paths.best_addr.addr() != Some(addr) {
+            return;
+        }
+        let quality = self
+            .udp_paths
+            .paths
+            .get(&addr.into())
+            .map(|state| state.quality.quality());
+        event!(
+            target: "iroh::_events::path::degraded",
+            Level::DEBUG,
+            remote_node = %self.node_id.fmt_short(),
+            path = ?addr,
+            ?quality,
+        );
+        info!(%addr, ?quality, "path quality degraded, switching path");
+        self.udp_paths
+            .best_addr
+            .clear(ClearReason::Degraded, self.relay_url.is_some());
+    }
+
     /// Handles a DISCO CallMeMaybe discovery message.
     ///
     /// The contract for use of this message is that the node has already pinged to us via
@@ -1054,21 +1425,49 @@ impl NodeState {
         self.send_pings(now)
     }
 
-    /// Marks this node as having received a UDP payload message.
+    /// Marks this node as having received `len` bytes of UDP payload messages.
     #[cfg(not(wasm_browser))]
-    pub(super) fn receive_udp(&mut self, addr: IpPort, now: Instant) {
+    pub(super) fn receive_udp(
+        &mut self,
+        addr: IpPort,
+        local_ip: Option<IpAddr>,
+        len: usize,
+        now: Instant,
+    ) {
         let Some(state) = self.udp_paths.paths.get_mut(&addr) else {
             debug_assert!(false, "node map inconsistency by_ip_port <-> direct addr");
             return;
         };
         state.last_payload_msg = Some(now);
+        self.traffic.direct_recv += len as u64;
+        if local_ip.is_some() {
+            state.local_ip = local_ip;
+        }
         self.last_used = Some(now);
+        self.last_recv_path = Some(SendAddr::Udp(addr.into()));
         self.udp_paths
             .best_addr
             .reconfirm_if_used(addr.into(), BestAddrSource::Udp, now);
     }
 
-    pub(super) fn receive_relay(&mut self, url: &RelayUrl, src: NodeId, now: Instant) {
+    /// Records the local IP address a datagram on the UDP path `addr` was received on.
+    pub(super) fn set_udp_local_ip(&mut self, addr: IpPort, local_ip: IpAddr) {
+        if let Some(state) = self.udp_paths.paths.get_mut(&addr) {
+            state.local_ip = Some(local_ip);
+        }
+    }
+
+    /// The local IP address to send from on the UDP path `addr`, if one was recorded.
+    pub(super) fn udp_local_ip(&self, addr: SocketAddr) -> Option<IpAddr> {
+        self.udp_paths
+            .paths
+            .get(&addr.into())
+            .and_then(|state| state.local_ip)
+            .filter(|ip| ip.is_ipv4() == addr.is_ipv4())
+    }
+
+    pub(super) fn receive_relay(&mut self, url: &RelayUrl, src: NodeId, len: usize, now: Instant) {
+        self.traffic.relay_recv += len as u64;
         match self.relay_url.as_mut() {
             Some((current_home, state)) if current_home == url => {
                 // We received on the expected url. update state.
@@ -1090,6 +1489,18 @@ impl NodeState {
             }
         }
         self.last_used = Some(now);
+        self.last_recv_path = Some(SendAddr::Relay(url.clone()));
+    }
+
+    /// The path the last payload datagram from this node was received on, if any.
+    pub(super) fn last_recv_path(&self) -> Option<&SendAddr> {
+        self.last_recv_path.as_ref()
+    }
+
+    /// Records the payload bytes sent to this node over direct paths and the relay.
+    pub(super) fn record_sent(&mut self, direct: usize, relay: usize) {
+        self.traffic.direct_sent += direct as u64;
+        self.traffic.relay_sent += relay as u64;
     }
 
     pub(super) fn last_ping(&self, addr: &SendAddr) -> Option<Instant> {
@@ -1165,7 +1576,7 @@ impl NodeState {
     pub(crate) fn get_send_addrs(
         &mut self,
         have_ipv6: bool,
-    ) -> (Option<SocketAddr>, Option<RelayUrl>, Vec<PingAction>) {
+    ) -> (SmallVec<[SocketAddr; 1]>, Option<RelayUrl>, Vec<PingAction>) {
         let now = Instant::now();
         let prev = self.last_used.replace(now);
         if prev.is_none() {
@@ -1173,6 +1584,11 @@ impl NodeState {
             inc!(MagicsockMetrics, nodes_contacted);
         }
         let (udp_addr, relay_url) = self.addr_for_send(&now, have_ipv6);
+        // While racing candidate paths data is sent to all the candidates started so far.
+        let udp_addrs: SmallVec<[SocketAddr; 1]> = udp_addr
+            .into_iter()
+            .chain(self.udp_paths.racing_addrs(now))
+            .collect();
         let mut ping_msgs = Vec::new();
 
         if self.want_call_me_maybe(&now) {
@@ -1180,13 +1596,21 @@ impl NodeState {
         }
 
         trace!(
-            ?udp_addr,
+            ?udp_addrs,
             ?relay_url,
             pings = %ping_msgs.len(),
             "found send address",
         );
 
-        (udp_addr, relay_url, ping_msgs)
+        (udp_addrs, relay_url, ping_msgs)
+    }
+
+    /// Returns the smoothed RTT of the direct path to `addr`, if known.
+    pub(super) fn udp_path_rtt(&self, addr: SocketAddr) -> Option<Duration> {
+        self.udp_paths
+            .paths
+            .get(&addr.into())
+            .and_then(|state| state.quality.rtt())
     }
 
     /// Get the direct addresses for this endpoint.
@@ -1223,7 +1647,7 @@ impl From<RemoteInfo> for NodeAddr {
 /// Whether to send a call-me-maybe message after sending pings to all known paths.
 ///
 /// `IfNoRecent` will only send a call-me-maybe if no previous one was sent in the last
-/// [`HEARTBEAT_INTERVAL`].
+/// [`DiscoConfig::keepalive_interval`].
 #[derive(Debug)]
 enum SendCallMeMaybe {
     Always,
@@ -1245,7 +1669,6 @@ pub(super) struct PongReply {
 pub(super) struct SentPing {
     pub(super) to: SendAddr,
     pub(super) at: Instant,
-    #[allow(dead_code)]
     pub(super) purpose: DiscoPingPurpose,
     pub(super) _expiry_task: AbortOnDropHandle<()>,
 }
@@ -1328,6 +1751,8 @@ pub struct DirectAddrInfo {
     /// The [`Duration`] will always indicate the most recent time the source
     /// recorded this address.
     pub sources: HashMap<Source, Duration>,
+    /// The estimated quality of this network path.
+    pub quality: PathQuality,
 }
 
 /// Information about the network path to a remote node via a relay server.
@@ -1339,6 +1764,8 @@ pub struct RelayUrlInfo {
     pub last_alive: Option<Duration>,
     /// Latency to the remote node over this relayed network path.
     pub latency: Option<Duration>,
+    /// The estimated quality of this relayed network path.
+    pub quality: PathQuality,
 }
 
 impl From<(RelayUrl, PathState)> for RelayUrlInfo {
@@ -1347,10 +1774,49 @@ impl From<(RelayUrl, PathState)> for RelayUrlInfo {
             relay_url: value.0,
             last_alive: value.1.last_alive().map(|i| i.elapsed()),
             latency: value.1.latency(),
+            quality: value.1.quality.quality(),
         }
     }
 }
 
+/// The payload bytes exchanged with a remote node, by network path.
+///
+/// Only QUIC datagrams are counted, DISCO and STUN messages are not.  The counters cover all
+/// connections with the node since it became known to this endpoint.  Datagrams sent on
+/// several paths at once, e.g. while a direct path is being confirmed, are counted for
+/// each of them.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PathTraffic {
+    /// Bytes sent to the node over direct UDP paths.
+    pub direct_sent: u64,
+    /// Bytes received from the node over direct UDP paths.
+    pub direct_recv: u64,
+    /// Bytes sent to the node via a relay server.
+    pub relay_sent: u64,
+    /// Bytes received from the node via a relay server.
+    pub relay_recv: u64,
+}
+
+impl PathTraffic {
+    /// Total bytes exchanged over direct UDP paths.
+    pub fn direct(&self) -> u64 {
+        self.direct_sent + self.direct_recv
+    }
+
+    /// Total bytes exchanged via relay servers.
+    pub fn relay(&self) -> u64 {
+        self.relay_sent + self.relay_recv
+    }
+
+    /// The fraction of the bytes exchanged over direct UDP paths.
+    ///
+    /// Returns `None` if no bytes were exchanged yet.
+    pub fn direct_fraction(&self) -> Option<f64> {
+        let total = self.direct() + self.relay();
+        (total > 0).then(|| self.direct() as f64 / total as f64)
+    }
+}
+
 impl From<RelayUrlInfo> for RelayUrl {
     fn from(value: RelayUrlInfo) -> Self {
         value.relay_url
@@ -1386,6 +1852,8 @@ pub struct RemoteInfo {
     /// from the remote node. Note that sending to the remote node does not imply
     /// the remote node received anything.
     pub last_used: Option<Duration>,
+    /// The payload bytes exchanged with the node over direct paths and via relays.
+    pub traffic: PathTraffic,
 }
 
 impl RemoteInfo {
@@ -1449,9 +1917,40 @@ pub enum ConnectionType {
     None,
 }
 
+/// Statistics of the DISCO traffic with a remote node.
+///
+/// The DISCO pings sent to find and maintain the network paths to a node tell which paths
+/// work and how well.  See [`Endpoint::conn_type_info`].
+///
+/// [`Endpoint::conn_type_info`]: crate::endpoint::Endpoint::conn_type_info
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionTypeInfo {
+    /// The type of connection we have to the node.
+    pub conn_type: ConnectionType,
+    /// The statistics of the direct paths to the node.
+    pub direct: BTreeMap<SocketAddr, DiscoPathInfo>,
+    /// The statistics of the path via the relay server of the node, if any.
+    pub relay: Option<(RelayUrl, DiscoPathInfo)>,
+    /// The number of holepunching attempts to the node.
+    ///
+    /// An attempt starts whenever a direct path is needed and none is confirmed.
+    pub holepunch_attempts: u64,
+}
+
+/// Statistics of the DISCO traffic on a network path to a remote node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiscoPathInfo {
+    /// When the most recent pong on this path was received.
+    ///
+    /// This is cleared when a later ping on the path was not answered.
+    pub last_pong: Option<Instant>,
+    /// The quality of the path, including its measured round trip time.
+    pub quality: PathQuality,
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeMap, net::Ipv4Addr};
+    use std::net::Ipv4Addr;
 
     use best_addr::BestAddr;
     use iroh_base::SecretKey;
@@ -1519,9 +2018,18 @@ mod tests {
                     ),
                     sent_pings: HashMap::new(),
                     last_used: Some(now),
+                    last_recv_path: None,
                     last_call_me_maybe: None,
                     conn_type: Watchable::new(ConnectionType::Direct(ip_port.into())),
                     has_been_direct: true,
+                    traffic: PathTraffic::default(),
+                    disco_config: DiscoConfig::default(),
+                    holepunch: None,
+                    holepunch_events: Default::default(),
+                    paced_probes: 0,
+                    holepunch_attempts: 0,
+                    conn_type_info: Default::default(),
+                    disco_trusted: false,
                     #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
                 },
@@ -1538,12 +2046,21 @@ mod tests {
                 node_id: key.public(),
                 last_full_ping: None,
                 relay_url: relay_and_state(key.public(), send_addr.clone()),
-                udp_paths: NodeUdpPaths::new(),
+                udp_paths: NodeUdpPaths::default(),
                 sent_pings: HashMap::new(),
                 last_used: Some(now),
+                last_recv_path: None,
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
                 has_been_direct: false,
+                traffic: PathTraffic::default(),
+                disco_config: DiscoConfig::default(),
+                holepunch: None,
+                holepunch_events: Default::default(),
+                paced_probes: 0,
+                holepunch_attempts: 0,
+                conn_type_info: Default::default(),
+                disco_trusted: false,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
             }
@@ -1567,12 +2084,21 @@ mod tests {
                         now,
                     ),
                 )),
-                udp_paths: NodeUdpPaths::new(),
+                udp_paths: NodeUdpPaths::default(),
                 sent_pings: HashMap::new(),
                 last_used: Some(now),
+                last_recv_path: None,
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
                 has_been_direct: false,
+                traffic: PathTraffic::default(),
+                disco_config: DiscoConfig::default(),
+                holepunch: None,
+                holepunch_events: Default::default(),
+                paced_probes: 0,
+                holepunch_attempts: 0,
+                conn_type_info: Default::default(),
+                disco_trusted: false,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
             }
@@ -1609,12 +2135,21 @@ mod tests {
                     ),
                     sent_pings: HashMap::new(),
                     last_used: Some(now),
+                    last_recv_path: None,
                     last_call_me_maybe: None,
                     conn_type: Watchable::new(ConnectionType::Mixed(
                         socket_addr,
                         send_addr.clone(),
                     )),
                     has_been_direct: false,
+                    traffic: PathTraffic::default(),
+                    disco_config: DiscoConfig::default(),
+                    holepunch: None,
+                    holepunch_events: Default::default(),
+                    paced_probes: 0,
+                    holepunch_attempts: 0,
+                    conn_type_info: Default::default(),
+                    disco_trusted: false,
                     #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
                 },
@@ -1633,10 +2168,12 @@ mod tests {
                     last_payload: None,
                     last_alive: Some(elapsed),
                     sources: HashMap::new(),
+                    quality: PathQuality::default(),
                 }]),
                 conn_type: ConnectionType::Direct(a_socket_addr),
                 latency: Some(latency),
                 last_used: Some(elapsed),
+                traffic: PathTraffic::default(),
             },
             RemoteInfo {
                 node_id: b_endpoint.node_id,
@@ -1644,11 +2181,13 @@ mod tests {
                     relay_url: b_endpoint.relay_url.as_ref().unwrap().0.clone(),
                     last_alive: None,
                     latency: Some(latency),
+                    quality: PathQuality::default(),
                 }),
                 addrs: Vec::new(),
                 conn_type: ConnectionType::Relay(send_addr.clone()),
                 latency: Some(latency),
                 last_used: Some(elapsed),
+                traffic: PathTraffic::default(),
             },
             RemoteInfo {
                 node_id: c_endpoint.node_id,
@@ -1656,11 +2195,13 @@ mod tests {
                     relay_url: c_endpoint.relay_url.as_ref().unwrap().0.clone(),
                     last_alive: None,
                     latency: None,
+                    quality: PathQuality::default(),
                 }),
                 addrs: Vec::new(),
                 conn_type: ConnectionType::Relay(send_addr.clone()),
                 latency: None,
                 last_used: Some(elapsed),
+                traffic: PathTraffic::default(),
             },
             RemoteInfo {
                 node_id: d_endpoint.node_id,
@@ -1668,6 +2209,7 @@ mod tests {
                     relay_url: d_endpoint.relay_url.as_ref().unwrap().0.clone(),
                     last_alive: None,
                     latency: Some(latency),
+                    quality: PathQuality::default(),
                 }),
                 addrs: Vec::from([DirectAddrInfo {
                     addr: d_socket_addr,
@@ -1676,10 +2218,12 @@ mod tests {
                     last_payload: None,
                     last_alive: Some(elapsed),
                     sources: HashMap::new(),
+                    quality: PathQuality::default(),
                 }]),
                 conn_type: ConnectionType::Mixed(d_socket_addr, send_addr.clone()),
                 latency: Some(Duration::from_millis(50)),
                 last_used: Some(elapsed),
+                traffic: PathTraffic::default(),
             },
         ]);
 
@@ -1707,6 +2251,8 @@ mod tests {
                 (d_endpoint.id, d_endpoint),
             ]),
             next_id: 5,
+            disco_config: DiscoConfig::default(),
+            holepunch_events: Default::default(),
             path_selection: PathSelection::default(),
         });
         let mut got = node_map.list_remote_infos(later);
@@ -1737,9 +2283,10 @@ mod tests {
             source: crate::magicsock::Source::NamedApp {
                 name: "test".into(),
             },
+            disco_config: DiscoConfig::default(),
             path_selection: PathSelection::default(),
         };
-        let mut ep = NodeState::new(0, opts);
+        let mut ep = NodeState::new(0, opts, Default::default());
 
         let my_numbers_count: u16 = (MAX_INACTIVE_DIRECT_ADDRESSES + 5).try_into().unwrap();
         let my_numbers = (0u16..my_numbers_count)
@@ -1753,4 +2300,264 @@ mod tests {
         // number of pings as direct addresses in the call-me-maybe.
         assert_eq!(ping_messages.len(), my_numbers_count as usize);
     }
+
+    #[tokio::test]
+    async fn test_ping_retries() {
+        let key = SecretKey::generate(rand::thread_rng());
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            disco_config: DiscoConfig::default().ping_retries(1),
+            path_selection: PathSelection::default(),
+        };
+        let mut ep = NodeState::new(0, opts, Default::default());
+        let (sender, _receiver) = mpsc::channel(8);
+
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let call_me_maybe = disco::CallMeMaybe {
+            my_numbers: vec![addr],
+        };
+        let mut pings = ep.handle_call_me_maybe(call_me_maybe);
+        assert_eq!(pings.len(), 1);
+        let Some(PingAction::SendPing(ping)) = pings.pop() else {
+            panic!("expected a ping");
+        };
+
+        // The first lost ping is resent.
+        ep.ping_sent(ping.dst, ping.tx_id, ping.purpose, sender.clone());
+        let timeout = ep.ping_timeout(ping.tx_id);
+        assert!(!timeout.holepunch_failed);
+        let retry = timeout.retry.expect("retry");
+        assert_eq!(retry.dst, SendAddr::Udp(addr));
+
+        // Once the retries are used up holepunching failed.
+        ep.ping_sent(retry.dst, retry.tx_id, retry.purpose, sender);
+        let timeout = ep.ping_timeout(retry.tx_id);
+        assert!(timeout.holepunch_failed);
+        assert!(timeout.retry.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_holepunch_events() {
+        let key = SecretKey::generate(rand::thread_rng());
+        let opts = || Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            disco_config: DiscoConfig::default(),
+            path_selection: PathSelection::default(),
+        };
+        let events = HolepunchEvents::default();
+        let mut recv = events.subscribe();
+        let (sender, _receiver) = mpsc::channel(8);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let observed_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 2000);
+
+        // A pong selects the direct path.
+        let mut ep = NodeState::new(0, opts(), events.clone());
+        let mut pings = ep.handle_call_me_maybe(disco::CallMeMaybe {
+            my_numbers: vec![addr],
+        });
+        let Some(PingAction::SendPing(ping)) = pings.pop() else {
+            panic!("expected a ping");
+        };
+        ep.ping_sent(ping.dst, ping.tx_id, ping.purpose, sender.clone());
+        let pong = disco::Pong {
+            tx_id: ping.tx_id,
+            ping_observed_addr: SendAddr::Udp(observed_addr),
+        };
+        ep.handle_pong(&pong, SendAddr::Udp(addr));
+
+        assert_eq!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::Started {
+                node_id: key.public(),
+                candidates: vec![addr],
+            }
+        );
+        assert_eq!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::PingsSent {
+                node_id: key.public(),
+                addrs: vec![addr],
+            }
+        );
+        assert!(matches!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::FirstPong { addr: a, .. } if a == addr
+        ));
+        assert!(matches!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::Direct { addr: a, observed_addr: o, .. }
+                if a == addr && o == Some(observed_addr)
+        ));
+        assert!(recv.try_recv().is_err());
+
+        // Losing all pings falls back to the relay.
+        let mut ep = NodeState::new(1, opts(), events);
+        let mut pings = ep.handle_call_me_maybe(disco::CallMeMaybe {
+            my_numbers: vec![addr],
+        });
+        let Some(PingAction::SendPing(ping)) = pings.pop() else {
+            panic!("expected a ping");
+        };
+        ep.ping_sent(ping.dst, ping.tx_id, ping.purpose, sender);
+        assert!(ep.ping_timeout(ping.tx_id).holepunch_failed);
+
+        assert!(matches!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::Started { .. }
+        ));
+        assert!(matches!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::PingsSent { .. }
+        ));
+        assert!(matches!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::Relayed {
+                relay_url: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_pace_probes() {
+        let key = SecretKey::generate(rand::thread_rng());
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            disco_config: DiscoConfig::default()
+                .probe_spacing(Duration::from_millis(10))
+                .probe_rounds(2),
+            path_selection: PathSelection::default(),
+        };
+        let mut ep = NodeState::new(0, opts, Default::default());
+
+        let addrs = [
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000),
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1001),
+        ];
+        let pings = ep.handle_call_me_maybe(disco::CallMeMaybe {
+            my_numbers: addrs.to_vec(),
+        });
+        let pings: Vec<_> = pings
+            .into_iter()
+            .map(|action| match action {
+                PingAction::SendPing(ping) => (ping.dst, ping.delay),
+                PingAction::SendCallMeMaybe { .. } => panic!("expected a ping"),
+            })
+            .collect();
+        let ms = Duration::from_millis;
+        assert_eq!(
+            pings,
+            vec![
+                (SendAddr::Udp(addrs[0]), ms(0)),
+                (SendAddr::Udp(addrs[1]), ms(10)),
+                // The second round is spaced twice as far apart.
+                (SendAddr::Udp(addrs[0]), ms(40)),
+                (SendAddr::Udp(addrs[1]), ms(60)),
+            ]
+        );
+        assert_eq!(ep.paced_probes, 3);
+
+        // Paced pings are only sent while holepunching, and reported when they are sent.
+        let mut recv = ep.holepunch_events.subscribe();
+        assert!(ep.paced_probe_due(&SendAddr::Udp(addrs[1])));
+        assert_eq!(
+            recv.try_recv().unwrap(),
+            HolepunchEvent::PingsSent {
+                node_id: key.public(),
+                addrs: vec![addrs[1]],
+            }
+        );
+        ep.reset();
+        assert!(!ep.paced_probe_due(&SendAddr::Udp(addrs[0])));
+        assert_eq!(ep.paced_probes, 1);
+        ep.paced_probe_dropped();
+        assert_eq!(ep.paced_probes, 0);
+    }
+
+    #[test]
+    fn test_local_ip_cleared() {
+        let key = SecretKey::generate(rand::thread_rng());
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            disco_config: DiscoConfig::default(),
+            path_selection: PathSelection::default(),
+        };
+        let mut ep = NodeState::new(0, opts, Default::default());
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let local_ip = Ipv4Addr::new(192, 168, 1, 2).into();
+        ep.handle_call_me_maybe(disco::CallMeMaybe {
+            my_numbers: vec![addr],
+        });
+
+        // The local address may no longer exist after the network changed.
+        ep.set_udp_local_ip(addr.into(), local_ip);
+        assert_eq!(ep.udp_local_ip(addr), Some(local_ip));
+        ep.note_connectivity_change();
+        assert_eq!(ep.udp_local_ip(addr), None);
+
+        ep.set_udp_local_ip(addr.into(), local_ip);
+        ep.reset();
+        assert_eq!(ep.udp_local_ip(addr), None);
+    }
+
+    #[tokio::test]
+    async fn test_conn_type_info() {
+        let key = SecretKey::generate(rand::thread_rng());
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: None,
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            disco_config: DiscoConfig::default(),
+            path_selection: PathSelection::default(),
+        };
+        let mut ep = NodeState::new(0, opts, Default::default());
+        let (sender, _receiver) = mpsc::channel(8);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let info = ep.conn_type_info();
+
+        let mut pings = ep.handle_call_me_maybe(disco::CallMeMaybe {
+            my_numbers: vec![addr],
+        });
+        let Some(PingAction::SendPing(ping)) = pings.pop() else {
+            panic!("expected a ping");
+        };
+        let stats = info.get().unwrap();
+        assert_eq!(stats.holepunch_attempts, 1);
+        assert_eq!(stats.direct[&addr].last_pong, None);
+        assert_eq!(stats.relay, None);
+
+        ep.ping_sent(ping.dst, ping.tx_id, ping.purpose, sender);
+        let pong = disco::Pong {
+            tx_id: ping.tx_id,
+            ping_observed_addr: SendAddr::Udp(addr),
+        };
+        ep.handle_pong(&pong, SendAddr::Udp(addr));
+        let stats = info.get().unwrap();
+        let path = stats.direct[&addr];
+        assert!(path.last_pong.is_some());
+        assert!(path.quality.rtt.is_some());
+    }
 }
@@ -0,0 +1,168 @@
+//! Quality estimation for a single network path, based on DISCO pings.
+
+use std::collections::VecDeque;
+
+use n0_future::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// The number of most recent pings the loss is estimated from.
+const LOSS_WINDOW: usize = 16;
+
+/// The minimum number of pings before a path can be considered degraded.
+const MIN_DEGRADED_SAMPLES: usize = 4;
+
+/// The fraction of lost pings above which a path is considered degraded.
+const DEGRADED_LOSS: f32 = 0.5;
+
+/// The increase of the smoothed RTT over the minimum recent RTT above which a path is
+/// considered degraded, if it also more than tripled.
+const DEGRADED_RTT_INCREASE: Duration = Duration::from_millis(200);
+
+/// Estimated quality of a network path to a remote node.
+///
+/// The estimates are based on the DISCO pings sent on the path, which are sent about every
+/// few seconds while a connection to the node is in use.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PathQuality {
+    /// The smoothed round trip time.
+    pub rtt: Option<Duration>,
+    /// The variation of the round trip time, i.e. the jitter.
+    pub jitter: Option<Duration>,
+    /// The number of recent pings for which the loss is reported.
+    pub pings: u32,
+    /// The number of the recent pings which were not answered in time.
+    pub lost: u32,
+}
+
+impl PathQuality {
+    /// The fraction of recent pings which were lost, `0.0` if no pings were sent.
+    pub fn loss(&self) -> f32 {
+        if self.pings == 0 {
+            0.0
+        } else {
+            self.lost as f32 / self.pings as f32
+        }
+    }
+}
+
+/// Keeps the estimates for [`PathQuality`] up to date.
+///
+/// The RTT and its variation are smoothed like TCP does, as per [RFC 6298].
+///
+/// [RFC 6298]: https://www.rfc-editor.org/rfc/rfc6298#section-2
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct QualityEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// For each recent ping the RTT, or `None` if it was lost.
+    outcomes: VecDeque<Option<Duration>>,
+}
+
+impl QualityEstimator {
+    /// Records a ping answered after `rtt`.
+    pub(super) fn record_pong(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.record_outcome(Some(rtt));
+    }
+
+    /// Records a ping which was not answered in time.
+    pub(super) fn record_lost(&mut self) {
+        self.record_outcome(None);
+    }
+
+    fn record_outcome(&mut self, rtt: Option<Duration>) {
+        if self.outcomes.len() == LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(rtt);
+    }
+
+    /// The smoothed round trip time.
+    pub(super) fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub(super) fn quality(&self) -> PathQuality {
+        PathQuality {
+            rtt: self.srtt,
+            jitter: self.srtt.map(|_| self.rttvar),
+            pings: self.outcomes.len() as u32,
+            lost: self.outcomes.iter().filter(|rtt| rtt.is_none()).count() as u32,
+        }
+    }
+
+    /// Whether the path became too lossy or too slow to keep using it.
+    pub(super) fn is_degraded(&self) -> bool {
+        let quality = self.quality();
+        if self.outcomes.len() >= MIN_DEGRADED_SAMPLES && quality.loss() > DEGRADED_LOSS {
+            return true;
+        }
+        let min_rtt = self.outcomes.iter().flatten().min();
+        match (self.srtt, min_rtt) {
+            (Some(srtt), Some(min_rtt)) => {
+                srtt > *min_rtt * 3 && srtt - *min_rtt > DEGRADED_RTT_INCREASE
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality_estimator() {
+        let mut estimator = QualityEstimator::default();
+        assert_eq!(estimator.quality(), PathQuality::default());
+
+        estimator.record_pong(Duration::from_millis(100));
+        let quality = estimator.quality();
+        assert_eq!(quality.rtt, Some(Duration::from_millis(100)));
+        assert_eq!(quality.jitter, Some(Duration::from_millis(50)));
+
+        estimator.record_pong(Duration::from_millis(180));
+        let quality = estimator.quality();
+        assert_eq!(quality.rtt, Some(Duration::from_millis(110)));
+        assert_eq!(
+            quality.jitter,
+            Some(Duration::from_millis(57) + Duration::from_micros(500))
+        );
+        assert!(!estimator.is_degraded());
+
+        // Losing most pings degrades the path.
+        estimator.record_lost();
+        estimator.record_lost();
+        assert_eq!(estimator.quality().loss(), 0.5);
+        assert!(!estimator.is_degraded());
+        estimator.record_lost();
+        assert!(estimator.is_degraded());
+
+        // Only the recent pings count.
+        for _ in 0..LOSS_WINDOW {
+            estimator.record_pong(Duration::from_millis(100));
+        }
+        assert_eq!(estimator.quality().lost, 0);
+        assert_eq!(estimator.quality().pings, LOSS_WINDOW as u32);
+        assert!(!estimator.is_degraded());
+
+        // A large increase of the RTT degrades the path, until it is the new normal.
+        for _ in 0..LOSS_WINDOW / 2 {
+            estimator.record_pong(Duration::from_millis(500));
+        }
+        assert!(estimator.is_degraded());
+        for _ in 0..LOSS_WINDOW {
+            estimator.record_pong(Duration::from_millis(500));
+        }
+        assert!(!estimator.is_degraded());
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::{
     collections::{BTreeMap, HashMap},
-    net::SocketAddr,
+    net::IpAddr,
 };
 
 use iroh_base::NodeId;
@@ -12,15 +12,16 @@ use tracing::{debug, event, Level};
 
 use super::{
     node_state::{ControlMsg, PongReply, SESSION_ACTIVE_TIMEOUT},
+    path_quality::QualityEstimator,
     IpPort, PingRole, Source,
 };
-use crate::{disco::SendAddr, magicsock::HEARTBEAT_INTERVAL};
+use crate::disco::SendAddr;
 
 /// The minimum time between pings to an endpoint.
 ///
 /// Except in the case of CallMeMaybe frames resetting the counter, as the first pings
 /// likely didn't through the firewall.
-const DISCO_PING_INTERVAL: Duration = Duration::from_secs(5);
+pub(super) const DISCO_PING_INTERVAL: Duration = Duration::from_secs(5);
 
 /// State about a particular path to another [`NodeState`].
 ///
@@ -59,6 +60,15 @@ pub(super) struct PathState {
     /// We keep track of only the latest [`Instant`] for each [`Source`], keeping the size of
     /// the map of sources down to one entry per type of source.
     pub(super) sources: HashMap<Source, Instant>,
+    /// The local IP address datagrams on this path were last received on.
+    ///
+    /// Only recorded when the socket is bound to the unspecified address, in which case it
+    /// is used as the source address when sending on this path.
+    pub(super) local_ip: Option<IpAddr>,
+    /// Estimates of the quality of this path, from the pings sent on it.
+    pub(super) quality: QualityEstimator,
+    /// The number of lost holepunching pings resent since the last pong.
+    pub(super) ping_retries: u32,
 }
 
 impl PathState {
@@ -74,13 +84,9 @@ impl PathState {
             recent_pong: None,
             last_payload_msg: None,
             sources,
-        }
-    }
-
-    pub(super) fn udp_addr(&self) -> Option<SocketAddr> {
-        match self.path {
-            SendAddr::Udp(addr) => Some(addr),
-            SendAddr::Relay(_) => None,
+            local_ip: None,
+            quality: Default::default(),
+            ping_retries: 0,
         }
     }
 
@@ -101,6 +107,9 @@ impl PathState {
             recent_pong: None,
             last_payload_msg: Some(now),
             sources,
+            local_ip: None,
+            quality: Default::default(),
+            ping_retries: 0,
         }
     }
 
@@ -110,9 +119,10 @@ impl PathState {
         tx_id: stun::TransactionId,
         source: Source,
         now: Instant,
+        heartbeat_interval: Duration,
     ) -> Self {
         let mut new = PathState::new(node_id, path, source, now);
-        new.handle_ping(tx_id, now);
+        new.handle_ping(tx_id, now, heartbeat_interval);
         new
     }
 
@@ -129,6 +139,7 @@ impl PathState {
             }
         }
         self.recent_pong = Some(r);
+        self.ping_retries = 0;
     }
 
     #[cfg(test)]
@@ -142,6 +153,9 @@ impl PathState {
             recent_pong: Some(r),
             last_payload_msg: None,
             sources: HashMap::new(),
+            local_ip: None,
+            quality: Default::default(),
+            ping_retries: 0,
         }
     }
 
@@ -222,7 +236,8 @@ impl PathState {
         self.recent_pong.as_ref().map(|p| p.latency)
     }
 
-    pub(super) fn needs_ping(&self, now: &Instant) -> bool {
+    /// Whether the path needs a ping, the last one being older than `ping_interval`.
+    pub(super) fn needs_ping(&self, now: &Instant, ping_interval: Duration) -> bool {
         match self.last_ping {
             None => true,
             Some(last_ping) => {
@@ -234,17 +249,26 @@ impl PathState {
                 // if !needs_ping {
                 //     debug!("ping is too new: {}ms", elapsed.as_millis());
                 // }
-                elapsed > DISCO_PING_INTERVAL
+                elapsed > ping_interval
             }
         }
     }
 
-    pub(super) fn handle_ping(&mut self, tx_id: stun::TransactionId, now: Instant) -> PingRole {
+    /// Records a received ping.
+    ///
+    /// Pings arriving within one and a half `heartbeat_interval` of the previous one are
+    /// considered heartbeats.
+    pub(super) fn handle_ping(
+        &mut self,
+        tx_id: stun::TransactionId,
+        now: Instant,
+        heartbeat_interval: Duration,
+    ) -> PingRole {
         if Some(&tx_id) == self.last_got_ping.as_ref().map(|(_t, tx_id)| tx_id) {
             PingRole::Duplicate
         } else {
             let prev = self.last_got_ping.replace((now, tx_id));
-            let heartbeat_deadline = HEARTBEAT_INTERVAL + (HEARTBEAT_INTERVAL / 2);
+            let heartbeat_deadline = heartbeat_interval + (heartbeat_interval / 2);
             match prev {
                 Some((prev_time, _tx)) if now.duration_since(prev_time) <= heartbeat_deadline => {
                     PingRole::LikelyHeartbeat
@@ -276,11 +300,17 @@ impl PathState {
         self.sources.insert(source, now);
     }
 
+    /// Whether the application hinted this path, see [`Source::Hint`].
+    pub(super) fn is_hinted(&self) -> bool {
+        self.sources.contains_key(&Source::Hint)
+    }
+
     pub(super) fn clear(&mut self) {
         self.last_ping = None;
         self.last_got_ping = None;
         self.call_me_maybe_time = None;
         self.recent_pong = None;
+        self.local_ip = None;
     }
 
     fn summary(&self, mut w: impl std::fmt::Write) -> std::fmt::Result {
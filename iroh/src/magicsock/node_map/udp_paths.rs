@@ -8,8 +8,7 @@
 use std::{collections::BTreeMap, net::SocketAddr};
 
 use n0_future::time::{Duration, Instant};
-use rand::seq::IteratorRandom;
-use tracing::warn;
+use tracing::{debug, warn};
 
 use super::{
     best_addr::{self, BestAddr},
@@ -19,6 +18,22 @@ use super::{
 };
 use crate::disco::SendAddr;
 
+/// The delay between starting to send on successive candidate paths.
+///
+/// This is the "Connection Attempt Delay" recommended by [RFC 8305 section 5].
+///
+/// [RFC 8305 section 5]: https://www.rfc-editor.org/rfc/rfc8305#section-5
+const CANDIDATE_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The maximum number of candidate paths data is sent on at the same time.
+const MAX_RACED_CANDIDATES: usize = 2;
+
+/// How long data is sent on several candidate paths.
+///
+/// Afterwards data is only sent on the first candidate again, the holepunching pings keep
+/// probing all the paths.
+const CANDIDATE_RACE_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// The address on which to send datagrams over UDP.
 ///
 /// The [`MagicSock`] sends packets to zero or one UDP address, depending on the known paths
@@ -74,13 +89,18 @@ pub(super) struct NodeUdpPaths {
     pub(super) paths: BTreeMap<IpPort, PathState>,
     /// Best UDP path currently selected.
     pub(super) best_addr: BestAddr,
-    /// If we had to choose a path because we had no `best_addr` it is stored here.
-    chosen_candidate: Option<IpPort>,
+    /// The candidate paths being raced while we have no `best_addr`.
+    race: Option<CandidateRace>,
 }
 
 impl NodeUdpPaths {
-    pub(super) fn new() -> Self {
-        Default::default()
+    /// Creates the paths, trusting a selected path for `trust_duration` after it was
+    /// confirmed.
+    pub(super) fn new(trust_duration: Duration) -> Self {
+        Self {
+            best_addr: BestAddr::new(trust_duration),
+            ..Default::default()
+        }
     }
 
     #[cfg(test)]
@@ -88,7 +108,7 @@ impl NodeUdpPaths {
         Self {
             paths,
             best_addr,
-            chosen_candidate: None,
+            race: None,
         }
     }
 
@@ -100,40 +120,42 @@ impl NodeUdpPaths {
     pub(super) fn send_addr(&mut self, now: Instant, have_ipv6: bool) -> UdpSendAddr {
         self.assign_best_addr_from_candidates_if_empty();
         match self.best_addr.state(now) {
-            best_addr::State::Valid(addr) => UdpSendAddr::Valid(addr.addr),
-            best_addr::State::Outdated(addr) => UdpSendAddr::Outdated(addr.addr),
+            best_addr::State::Valid(addr) => {
+                self.race = None;
+                UdpSendAddr::Valid(addr.addr)
+            }
+            best_addr::State::Outdated(addr) => {
+                self.race = None;
+                UdpSendAddr::Outdated(addr.addr)
+            }
             best_addr::State::Empty => {
                 // No direct connection has been used before.  If we know of any possible
-                // candidate addresses, randomly try to use one.  This path is most
-                // effective when folks use a NodeAddr with exactly one direct address which
-                // they know to work, effectively like using a traditional socket or QUIC
-                // endpoint.
-                let addr = self
-                    .chosen_candidate
-                    .and_then(|ipp| self.paths.get(&ipp))
-                    .and_then(|path| path.udp_addr())
-                    .filter(|addr| addr.is_ipv4() || have_ipv6)
-                    .or_else(|| {
-                        // Look for a new candidate in all the known paths.  This may look
-                        // like a RNG use on the hot-path but this is normally invoked at
-                        // most most once at startup.
-                        let addr = self
-                            .paths
-                            .values()
-                            .filter_map(|path| path.udp_addr())
-                            .filter(|addr| addr.is_ipv4() || have_ipv6)
-                            .choose(&mut rand::thread_rng());
-                        self.chosen_candidate = addr.map(IpPort::from);
-                        addr
-                    });
-                match addr {
-                    Some(addr) => UdpSendAddr::Unconfirmed(addr),
+                // candidate addresses, race them.  This path is most effective when folks
+                // use a NodeAddr with exactly one direct address which they know to work,
+                // effectively like using a traditional socket or QUIC endpoint.
+                let race = self.race.get_or_insert_with(|| CandidateRace::new(now));
+                race.update(&self.paths, have_ipv6);
+                match race.candidates.first() {
+                    Some(ipp) => UdpSendAddr::Unconfirmed((*ipp).into()),
                     None => UdpSendAddr::None,
                 }
             }
         }
     }
 
+    /// Returns the candidate addresses to send on in addition to [`UdpSendAddr::Unconfirmed`].
+    ///
+    /// While no path is confirmed the candidates are raced: data is sent to an additional
+    /// candidate every [`CANDIDATE_ATTEMPT_DELAY`], up to [`MAX_RACED_CANDIDATES`].  This
+    /// stops once a candidate is confirmed by a pong, or after [`CANDIDATE_RACE_TIMEOUT`].
+    /// Must be called after [`NodeUdpPaths::send_addr`].
+    pub(super) fn racing_addrs(&self, now: Instant) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.race
+            .iter()
+            .flat_map(move |race| race.started(now).iter().skip(1))
+            .map(|ipp| (*ipp).into())
+    }
+
     /// Fixup best_addr from candidates.
     ///
     /// If somehow we end up in a state where we failed to set a best_addr, while we do have
@@ -153,10 +175,12 @@ impl NodeUdpPaths {
                 .unwrap_or(MAX_LATENCY);
             match state.recent_pong {
                 // This pong is better if it has a lower latency, or if it has the same
-                // latency but on an IPv6 path.
+                // latency but on an IPv6 path.  Degraded paths are not used until they
+                // recover.
                 Some(ref pong)
-                    if pong.latency < best_latency
-                        || (pong.latency == best_latency && ipp.ip().is_ipv6()) =>
+                    if !state.quality.is_degraded()
+                        && (pong.latency < best_latency
+                            || (pong.latency == best_latency && ipp.ip().is_ipv6())) =>
                 {
                     Some(pong)
                 }
@@ -178,3 +202,223 @@ impl NodeUdpPaths {
         }
     }
 }
+
+/// Candidate paths data is sent on with staggered starts.
+///
+/// This borrows the address ordering and the attempt delay of [RFC 8305] Happy Eyeballs,
+/// but does not pick a winner itself: the same data is sent on all started candidates, and
+/// which path gets used is decided by the holepunching pongs.
+///
+/// The candidates are ordered by interleaving the address families, starting with IPv6.
+/// Paths hinted by the application, see [`Source::Hint`], are moved before all others.  The
+/// first candidate is used straight away, every [`CANDIDATE_ATTEMPT_DELAY`] the next one is
+/// used in addition, up to [`MAX_RACED_CANDIDATES`].  Candidates further down the list are
+/// only reached by the holepunching pings.
+///
+/// The race ends as soon as any path is confirmed by a pong, at which point the path with
+/// the lowest latency becomes the `best_addr`.  Without any pong data is only sent on the
+/// first candidate again once [`CANDIDATE_RACE_TIMEOUT`] has passed, even if that path is
+/// the one not working, until a pong confirms some path.  So a blackholed IPv6 path only
+/// delays IPv4 by [`CANDIDATE_ATTEMPT_DELAY`] while the race lasts.
+///
+/// [RFC 8305]: https://www.rfc-editor.org/rfc/rfc8305
+/// [`Source::Hint`]: crate::magicsock::Source::Hint
+#[derive(Debug)]
+struct CandidateRace {
+    started_at: Instant,
+    /// The candidates, in the order they are started.
+    candidates: Vec<IpPort>,
+}
+
+impl CandidateRace {
+    fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Updates the candidates from the known paths.
+    ///
+    /// Candidates which are no longer usable are removed, new candidates are added at the
+    /// end while keeping the families interleaved.  Hinted candidates are moved to the
+    /// front.
+    fn update(&mut self, paths: &BTreeMap<IpPort, PathState>, have_ipv6: bool) {
+        let usable = |ipp: &IpPort| paths.contains_key(ipp) && (ipp.ip().is_ipv4() || have_ipv6);
+        self.candidates.retain(usable);
+        let new_candidates = paths
+            .keys()
+            .filter(|ipp| usable(ipp) && !self.candidates.contains(ipp));
+        let (mut new_v6, mut new_v4): (Vec<IpPort>, Vec<IpPort>) =
+            new_candidates.partition(|ipp| ipp.ip().is_ipv6());
+        if !new_v6.is_empty() || !new_v4.is_empty() {
+            debug!(
+                v6 = new_v6.len(),
+                v4 = new_v4.len(),
+                "adding candidates to race"
+            );
+            new_v6.reverse();
+            new_v4.reverse();
+            let mut next_v6 = self
+                .candidates
+                .last()
+                .map_or(true, |ipp| ipp.ip().is_ipv4());
+            while !new_v6.is_empty() || !new_v4.is_empty() {
+                let next = match next_v6 {
+                    true => new_v6.pop().or_else(|| new_v4.pop()),
+                    false => new_v4.pop().or_else(|| new_v6.pop()),
+                };
+                self.candidates.extend(next);
+                next_v6 = !next_v6;
+            }
+        }
+        self.candidates
+            .sort_by_key(|ipp| !paths.get(ipp).is_some_and(PathState::is_hinted));
+    }
+
+    /// Returns the candidates which have been started by `now`.
+    fn started(&self, now: Instant) -> &[IpPort] {
+        let elapsed = now.saturating_duration_since(self.started_at);
+        let count = if elapsed >= CANDIDATE_RACE_TIMEOUT {
+            1
+        } else {
+            let count = 1 + (elapsed.as_millis() / CANDIDATE_ATTEMPT_DELAY.as_millis()) as usize;
+            count.min(MAX_RACED_CANDIDATES)
+        };
+        &self.candidates[..count.min(self.candidates.len())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+    use crate::magicsock::node_map::Source;
+
+    fn paths(addrs: &[&str]) -> BTreeMap<IpPort, PathState> {
+        let node_id = SecretKey::from_bytes(&[1u8; 32]).public();
+        addrs
+            .iter()
+            .map(|addr| {
+                let addr: SocketAddr = addr.parse().unwrap();
+                let state =
+                    PathState::new(node_id, SendAddr::Udp(addr), Source::App, Instant::now());
+                (addr.into(), state)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_candidate_race() {
+        let paths = paths(&[
+            "192.0.2.1:1",
+            "192.0.2.2:1",
+            "192.0.2.3:1",
+            "[2001:db8::1]:1",
+        ]);
+        let now = Instant::now();
+        let mut udp_paths = NodeUdpPaths::from_parts(paths, BestAddr::default());
+
+        // IPv6 is started first, followed by interleaving the families.
+        let UdpSendAddr::Unconfirmed(addr) = udp_paths.send_addr(now, true) else {
+            panic!("expected unconfirmed send addr");
+        };
+        assert_eq!(addr, "[2001:db8::1]:1".parse().unwrap());
+        assert_eq!(udp_paths.racing_addrs(now).count(), 0);
+
+        // After the attempt delay the next candidate is added.
+        let later = now + CANDIDATE_ATTEMPT_DELAY;
+        udp_paths.send_addr(later, true);
+        let racing: Vec<_> = udp_paths.racing_addrs(later).collect();
+        assert_eq!(racing, vec!["192.0.2.1:1".parse().unwrap()]);
+
+        // Without IPv6 only IPv4 candidates are raced.
+        let UdpSendAddr::Unconfirmed(addr) = udp_paths.send_addr(now, false) else {
+            panic!("expected unconfirmed send addr");
+        };
+        assert_eq!(addr, "192.0.2.1:1".parse().unwrap());
+
+        // The race ends once a path is confirmed.
+        udp_paths.best_addr.insert_if_better_or_reconfirm(
+            "192.0.2.2:1".parse().unwrap(),
+            Duration::from_millis(10),
+            best_addr::Source::ReceivedPong,
+            now,
+        );
+        let UdpSendAddr::Valid(addr) = udp_paths.send_addr(now, true) else {
+            panic!("expected valid send addr");
+        };
+        assert_eq!(addr, "192.0.2.2:1".parse().unwrap());
+        assert_eq!(udp_paths.racing_addrs(later).count(), 0);
+    }
+
+    #[test]
+    fn test_candidate_race_duplication() {
+        let paths = paths(&[
+            "192.0.2.1:1",
+            "192.0.2.2:1",
+            "192.0.2.3:1",
+            "[2001:db8::1]:1",
+            "[2001:db8::2]:1",
+        ]);
+        let now = Instant::now();
+        let mut udp_paths = NodeUdpPaths::from_parts(paths, BestAddr::default());
+
+        // Count the copies sent of a datagram sent every 10ms for two seconds.
+        let mut copies = 0;
+        for i in 0..200 {
+            let at = now + Duration::from_millis(10 * i);
+            udp_paths.send_addr(at, true);
+            let sent = 1 + udp_paths.racing_addrs(at).count();
+            assert!(sent <= MAX_RACED_CANDIDATES);
+            copies += sent;
+        }
+        // Only datagrams between the attempt delay and the race timeout are duplicated.
+        let duplicated = (CANDIDATE_RACE_TIMEOUT - CANDIDATE_ATTEMPT_DELAY).as_millis() / 10;
+        assert_eq!(copies, 200 + duplicated as usize);
+
+        // The first candidate is still used after the race timed out.
+        let later = now + CANDIDATE_RACE_TIMEOUT;
+        let UdpSendAddr::Unconfirmed(addr) = udp_paths.send_addr(later, true) else {
+            panic!("expected unconfirmed send addr");
+        };
+        assert_eq!(addr, "[2001:db8::1]:1".parse().unwrap());
+    }
+
+    #[test]
+    fn test_candidate_race_hinted() {
+        let mut paths = paths(&["192.0.2.1:1", "192.0.2.2:1", "[2001:db8::1]:1"]);
+        let hinted: SocketAddr = "192.0.2.2:1".parse().unwrap();
+        paths
+            .get_mut(&hinted.into())
+            .unwrap()
+            .add_source(Source::Hint, Instant::now());
+        let now = Instant::now();
+        let mut udp_paths = NodeUdpPaths::from_parts(paths, BestAddr::default());
+
+        // The hinted path is started first.
+        let UdpSendAddr::Unconfirmed(addr) = udp_paths.send_addr(now, true) else {
+            panic!("expected unconfirmed send addr");
+        };
+        assert_eq!(addr, hinted);
+        let later = now + CANDIDATE_ATTEMPT_DELAY;
+        udp_paths.send_addr(later, true);
+        let racing: Vec<_> = udp_paths.racing_addrs(later).collect();
+        assert_eq!(racing, vec!["[2001:db8::1]:1".parse().unwrap()]);
+
+        // A path hinted while racing moves ahead of the paths not hinted.
+        let second_hint: SocketAddr = "192.0.2.1:1".parse().unwrap();
+        udp_paths
+            .paths
+            .get_mut(&second_hint.into())
+            .unwrap()
+            .add_source(Source::Hint, later);
+        let UdpSendAddr::Unconfirmed(addr) = udp_paths.send_addr(later, true) else {
+            panic!("expected unconfirmed send addr");
+        };
+        assert_eq!(addr, hinted);
+        let racing: Vec<_> = udp_paths.racing_addrs(later).collect();
+        assert_eq!(racing, vec![second_hint]);
+    }
+}
@@ -0,0 +1,302 @@
+//! Pacing of outgoing QUIC datagrams on the direct paths.
+//!
+//! QUIC sends whatever its congestion window allows in bursts, which consumer routers with
+//! small buffers tend to drop the tail of.  Quinn has a pacer of its own, but it only
+//! applies to a single connection.  This spreads the packets sent to a node over the RTT of
+//! the path instead, allowing only a quantum of packets to be sent back to back.
+//!
+//! The pacing rate is derived from the number of packets QUIC tried to send to the node
+//! during the previous RTT, which approximates the congestion windows of its connections.
+//! The rate is a multiple of this, so pacing does not hold back the growth of the
+//! congestion windows.
+
+use std::collections::{HashMap, VecDeque};
+
+use iroh_base::NodeId;
+use n0_future::time::{Duration, Instant};
+use tokio::sync::{futures::Notified, Notify};
+
+use super::rate_limiter::{DelayQueue, QueuedTransmit};
+
+/// Number of node states above which idle ones are removed.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Maximum number of transmits queued for a node.
+///
+/// Once full transmits are sent straight away, this only happens if the pacing rate lags
+/// far behind.
+const MAX_QUEUED_TRANSMITS: usize = 1024;
+
+/// Multiple of the previous RTT's packets used as the pacing rate.
+const PACING_GAIN: f64 = 2.0;
+
+/// The smallest RTT used to compute the pacing rate.
+const MIN_RTT: Duration = Duration::from_millis(1);
+
+/// Parameters of the pacing used for every node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SendPacing {
+    /// The number of packets which can be sent back to back.
+    pub(crate) quantum: usize,
+    /// Transmits of at most this many bytes are never delayed.
+    pub(crate) bypass_size: usize,
+}
+
+/// What to do with a transmit, see [`Pacer::check`].
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Paced {
+    /// The transmit should be sent straight away.
+    Send,
+    /// The transmit was queued to be sent later.
+    Queued,
+}
+
+/// Paces the transmits sent to each node.
+///
+/// Each node gets a bucket of up to [`SendPacing::quantum`] packets, refilled at the pacing
+/// rate.  Like for the [`RateLimiter`] a transmit is allowed as long as the bucket is not
+/// empty, so GSO batches larger than the quantum are not held back forever.
+///
+/// [`RateLimiter`]: super::rate_limiter::RateLimiter
+#[derive(Debug)]
+pub(super) struct Pacer {
+    pacing: SendPacing,
+    nodes: std::sync::Mutex<HashMap<NodeId, NodeState>>,
+    /// Notified when a transmit is queued.
+    queued: Notify,
+}
+
+impl Pacer {
+    pub(super) fn new(pacing: SendPacing) -> Self {
+        Self {
+            pacing,
+            nodes: Default::default(),
+            queued: Notify::new(),
+        }
+    }
+
+    /// Checks whether `transmit` to `node`, over a path with the given `rtt`, must be delayed.
+    pub(super) fn check(
+        &self,
+        node: NodeId,
+        transmit: &quinn_udp::Transmit,
+        rtt: Duration,
+    ) -> Paced {
+        self.check_at(node, transmit, rtt, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        node: NodeId,
+        transmit: &quinn_udp::Transmit,
+        rtt: Duration,
+        now: Instant,
+    ) -> Paced {
+        let mut nodes = self.nodes.lock().expect("poisoned");
+        if nodes.len() >= PRUNE_THRESHOLD && !nodes.contains_key(&node) {
+            nodes.retain(|_, state| !state.is_idle(now));
+        }
+        let state = nodes
+            .entry(node)
+            .or_insert_with(|| NodeState::new(&self.pacing, now));
+        let packets = packet_count(transmit);
+        state.update(&self.pacing, rtt.max(MIN_RTT), packets, now);
+
+        // Small transmits skip the queue, at the cost of overtaking queued ones.
+        let bypass = transmit.contents.len() <= self.pacing.bypass_size;
+        if bypass
+            || (state.queue.is_empty() && state.tokens > 0.0)
+            || state.queue.len() >= MAX_QUEUED_TRANSMITS
+        {
+            state.tokens -= packets as f64;
+            return Paced::Send;
+        }
+        state.queue.push_back(QueuedTransmit::from(transmit));
+        drop(nodes);
+        self.queued.notify_one();
+        Paced::Queued
+    }
+}
+
+impl DelayQueue for Pacer {
+    fn take_ready(&self, now: Instant) -> (Vec<QueuedTransmit>, Option<Instant>) {
+        let mut ready = Vec::new();
+        let mut next = None;
+        let mut nodes = self.nodes.lock().expect("poisoned");
+        for state in nodes.values_mut() {
+            if state.queue.is_empty() {
+                continue;
+            }
+            state.refill(&self.pacing, now);
+            while state.tokens > 0.0 {
+                let Some(transmit) = state.queue.pop_front() else {
+                    break;
+                };
+                state.tokens -= packet_count(&transmit.as_transmit()) as f64;
+                ready.push(transmit);
+            }
+            if !state.queue.is_empty() {
+                let ready_at = state.ready_at(now);
+                next = Some(next.map_or(ready_at, |next: Instant| next.min(ready_at)));
+            }
+        }
+        (ready, next)
+    }
+
+    fn queued(&self) -> Notified<'_> {
+        self.queued.notified()
+    }
+}
+
+/// The number of datagrams in a transmit.
+fn packet_count(transmit: &quinn_udp::Transmit) -> usize {
+    match transmit.segment_size {
+        Some(segment_size) if segment_size > 0 => transmit.contents.len().div_ceil(segment_size),
+        _ => 1,
+    }
+}
+
+#[derive(Debug)]
+struct NodeState {
+    /// The packets in the bucket, negative if the bucket was overdrawn.
+    tokens: f64,
+    updated: Instant,
+    /// The latest RTT of the path to the node.
+    rtt: Duration,
+    /// The pacing rate, in packets per second.
+    rate: f64,
+    /// When the current RTT long window started.
+    window_start: Instant,
+    /// The number of packets QUIC tried to send in the current window.
+    window_packets: usize,
+    /// The number of packets QUIC tried to send in the previous window.
+    prev_window_packets: usize,
+    queue: VecDeque<QueuedTransmit>,
+}
+
+impl NodeState {
+    fn new(pacing: &SendPacing, now: Instant) -> Self {
+        Self {
+            tokens: pacing.quantum as f64,
+            updated: now,
+            rtt: MIN_RTT,
+            rate: 0.0,
+            window_start: now,
+            window_packets: 0,
+            prev_window_packets: 0,
+            queue: Default::default(),
+        }
+    }
+
+    /// Accounts for `packets` about to be sent and updates the pacing rate.
+    fn update(&mut self, pacing: &SendPacing, rtt: Duration, packets: usize, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= self.rtt {
+            // After being idle for a while the previous window says nothing about now.
+            self.prev_window_packets = if elapsed < self.rtt * 2 {
+                self.window_packets
+            } else {
+                0
+            };
+            self.window_packets = 0;
+            self.window_start = now;
+        }
+        self.window_packets += packets;
+        self.refill(pacing, now);
+        self.rtt = rtt;
+        // Without a previous window at least a quantum is spread over the RTT.
+        let window = self.prev_window_packets.max(pacing.quantum).max(1);
+        self.rate = PACING_GAIN * window as f64 / rtt.as_secs_f64();
+    }
+
+    fn refill(&mut self, pacing: &SendPacing, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(pacing.quantum as f64);
+        self.updated = now;
+    }
+
+    /// When the bucket will have tokens again, after [`NodeState::refill`].
+    fn ready_at(&self, now: Instant) -> Instant {
+        let missing = 1.0 - self.tokens.min(0.0);
+        now + Duration::from_secs_f64(missing / self.rate.max(1.0))
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.queue.is_empty() && now.saturating_duration_since(self.window_start) >= self.rtt * 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    fn transmit(contents: &[u8], segment_size: Option<usize>) -> quinn_udp::Transmit<'_> {
+        quinn_udp::Transmit {
+            destination: "127.0.0.1:1234".parse().unwrap(),
+            ecn: None,
+            contents,
+            segment_size,
+            src_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_pacer() {
+        let pacer = Pacer::new(SendPacing {
+            quantum: 4,
+            bypass_size: 100,
+        });
+        let node = SecretKey::from_bytes(&[1u8; 32]).public();
+        let rtt = Duration::from_millis(100);
+        let start = Instant::now();
+        let packet = [0u8; 1000];
+
+        // A quantum of packets is sent back to back.
+        for _ in 0..4 {
+            assert_eq!(
+                pacer.check_at(node, &transmit(&packet, None), rtt, start),
+                Paced::Send
+            );
+        }
+        assert_eq!(
+            pacer.check_at(node, &transmit(&packet, None), rtt, start),
+            Paced::Queued
+        );
+
+        // Small transmits are never delayed.
+        assert_eq!(
+            pacer.check_at(node, &transmit(&packet[..100], None), rtt, start),
+            Paced::Send
+        );
+
+        // Without a previous window a quantum is spread over half the RTT.
+        let (ready, next) = pacer.take_ready(start);
+        assert!(ready.is_empty());
+        let next = next.unwrap();
+        assert!(next > start + Duration::from_millis(12));
+        assert!(next < start + Duration::from_millis(40));
+        let (ready, next) = pacer.take_ready(next);
+        assert_eq!(ready.len(), 1);
+        assert!(next.is_none());
+
+        // The next window is paced based on the packets sent in this one, GSO batches count
+        // as all their packets.
+        let later = start + rtt;
+        let batch = [0u8; 10_000];
+        assert_eq!(
+            pacer.check_at(node, &transmit(&batch, Some(1000)), rtt, later),
+            Paced::Send
+        );
+        assert_eq!(
+            pacer.check_at(node, &transmit(&packet, None), rtt, later),
+            Paced::Queued
+        );
+        let (_, next) = pacer.take_ready(later);
+        let next = next.unwrap();
+        // 6 packets in the previous window give a rate of 12 packets per RTT.
+        assert!(next > later + Duration::from_millis(50));
+        assert!(next < later + Duration::from_millis(70));
+    }
+}
@@ -0,0 +1,237 @@
+//! Status and events of the port mapping of the endpoint's UDP port.
+
+use std::net::SocketAddrV4;
+
+use tokio::sync::broadcast;
+
+use crate::watchable::{Watchable, Watcher};
+
+/// How many [`PortMappingEvent`]s are kept for subscribers which are not keeping up.
+const PORT_MAPPING_EVENTS_CAPACITY: usize = 16;
+
+/// The protocols used to request port mappings from the NAT devices.
+///
+/// All protocols are enabled by default.  See [`Builder::port_mapping`].
+///
+/// [`Builder::port_mapping`]: crate::endpoint::Builder::port_mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMappingConfig {
+    upnp: bool,
+    pcp: bool,
+    nat_pmp: bool,
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        Self {
+            upnp: true,
+            pcp: true,
+            nat_pmp: true,
+        }
+    }
+}
+
+impl PortMappingConfig {
+    /// A configuration with all protocols disabled, which disables port mapping.
+    pub fn disabled() -> Self {
+        Self {
+            upnp: false,
+            pcp: false,
+            nat_pmp: false,
+        }
+    }
+
+    /// Sets whether port mappings are requested using UPnP IGD.
+    pub fn upnp(mut self, enable: bool) -> Self {
+        self.upnp = enable;
+        self
+    }
+
+    /// Sets whether port mappings are requested using PCP.
+    pub fn pcp(mut self, enable: bool) -> Self {
+        self.pcp = enable;
+        self
+    }
+
+    /// Sets whether port mappings are requested using NAT-PMP.
+    pub fn nat_pmp(mut self, enable: bool) -> Self {
+        self.nat_pmp = enable;
+        self
+    }
+
+    /// Whether any protocol is enabled.
+    pub(super) fn is_enabled(&self) -> bool {
+        self.upnp || self.pcp || self.nat_pmp
+    }
+}
+
+impl From<PortMappingConfig> for portmapper::Config {
+    fn from(config: PortMappingConfig) -> Self {
+        Self {
+            enable_upnp: config.upnp,
+            enable_pcp: config.pcp,
+            enable_nat_pmp: config.nat_pmp,
+        }
+    }
+}
+
+/// The status of the port mapping of the endpoint's UDP port.
+///
+/// See [`Endpoint::port_mapping_status`].
+///
+/// [`Endpoint::port_mapping_status`]: crate::Endpoint::port_mapping_status
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortMappingStatus {
+    /// The external address of the active port mapping, if any.
+    ///
+    /// This address is advertised as a [`DirectAddrType::Portmapped`] direct address.
+    ///
+    /// [`DirectAddrType::Portmapped`]: crate::endpoint::DirectAddrType::Portmapped
+    pub external_addr: Option<SocketAddrV4>,
+    /// The port mapping protocols found available on the gateway by the last net report.
+    pub probe: Option<portmapper::ProbeOutput>,
+}
+
+/// An event of the port mapping of the endpoint's UDP port.
+///
+/// See [`Endpoint::port_mapping_events`].
+///
+/// [`Endpoint::port_mapping_events`]: crate::Endpoint::port_mapping_events
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PortMappingEvent {
+    /// A port mapping was acquired.
+    Acquired {
+        /// The external address of the mapping.
+        external_addr: SocketAddrV4,
+    },
+    /// The port mapping moved to another external address, e.g. after the gateway rebooted.
+    Changed {
+        /// The previous external address.
+        previous: SocketAddrV4,
+        /// The new external address.
+        external_addr: SocketAddrV4,
+    },
+    /// The port mapping was lost, it could not be renewed.
+    Lost {
+        /// The external address of the lost mapping.
+        previous: SocketAddrV4,
+    },
+}
+
+impl PortMappingEvent {
+    /// Returns the event for a change of the external address, if it changed.
+    fn from_change(previous: Option<SocketAddrV4>, current: Option<SocketAddrV4>) -> Option<Self> {
+        match (previous, current) {
+            (None, Some(external_addr)) => Some(Self::Acquired { external_addr }),
+            (Some(previous), Some(external_addr)) if previous != external_addr => {
+                Some(Self::Changed {
+                    previous,
+                    external_addr,
+                })
+            }
+            (Some(previous), None) => Some(Self::Lost { previous }),
+            _ => None,
+        }
+    }
+}
+
+/// Tracks the [`PortMappingStatus`] and broadcasts its [`PortMappingEvent`]s.
+#[derive(Debug)]
+pub(super) struct PortMappingState {
+    status: Watchable<PortMappingStatus>,
+    events: broadcast::Sender<PortMappingEvent>,
+}
+
+impl Default for PortMappingState {
+    fn default() -> Self {
+        Self {
+            status: Default::default(),
+            events: broadcast::Sender::new(PORT_MAPPING_EVENTS_CAPACITY),
+        }
+    }
+}
+
+impl PortMappingState {
+    pub(super) fn watch(&self) -> Watcher<PortMappingStatus> {
+        self.status.watch()
+    }
+
+    pub(super) fn subscribe(&self) -> broadcast::Receiver<PortMappingEvent> {
+        self.events.subscribe()
+    }
+
+    /// Records the external address reported by the port mapper.
+    pub(super) fn set_external_addr(&self, external_addr: Option<SocketAddrV4>) {
+        let mut status = self.status.get();
+        let event = PortMappingEvent::from_change(status.external_addr, external_addr);
+        status.external_addr = external_addr;
+        self.status.set(status).ok();
+        if let Some(event) = event {
+            self.events.send(event).ok();
+        }
+    }
+
+    /// Records the result of the port mapping probe of a net report.
+    pub(super) fn set_probe(&self, probe: Option<portmapper::ProbeOutput>) {
+        let mut status = self.status.get();
+        status.probe = probe;
+        self.status.set(status).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_mapping_config() {
+        let config = PortMappingConfig::default().pcp(false).nat_pmp(false);
+        assert!(config.is_enabled());
+        let portmapper::Config {
+            enable_upnp,
+            enable_pcp,
+            enable_nat_pmp,
+        } = config.into();
+        assert_eq!(
+            (enable_upnp, enable_pcp, enable_nat_pmp),
+            (true, false, false)
+        );
+        let disabled = PortMappingConfig::default()
+            .upnp(false)
+            .pcp(false)
+            .nat_pmp(false);
+        assert_eq!(disabled, PortMappingConfig::disabled());
+        assert!(!disabled.is_enabled());
+    }
+
+    #[test]
+    fn test_port_mapping_events() {
+        let state = PortMappingState::default();
+        let mut events = state.subscribe();
+        let a: SocketAddrV4 = "203.0.113.1:1234".parse().unwrap();
+        let b: SocketAddrV4 = "203.0.113.2:1234".parse().unwrap();
+
+        state.set_external_addr(Some(a));
+        state.set_external_addr(Some(a));
+        state.set_external_addr(Some(b));
+        state.set_external_addr(None);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            PortMappingEvent::Acquired { external_addr: a }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            PortMappingEvent::Changed {
+                previous: a,
+                external_addr: b
+            }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            PortMappingEvent::Lost { previous: b }
+        );
+        assert!(events.try_recv().is_err());
+        assert_eq!(state.watch().get().unwrap().external_addr, None);
+    }
+}
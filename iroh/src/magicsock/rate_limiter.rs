@@ -0,0 +1,289 @@
+//! Per-node rate limiting of outgoing QUIC datagrams.
+//!
+//! Each node gets a token bucket.  Transmits exceeding the rate are delayed in a queue for
+//! the node instead of being dropped straight away, dropping only happens once the queue
+//! is full.  Dropping every transmit over the limit would make QUIC see heavy packet loss,
+//! which besides reducing the congestion window also triggers MTU black hole detection.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+};
+
+use bytes::Bytes;
+use iroh_base::NodeId;
+use n0_future::time::Instant;
+use tokio::sync::{futures::Notified, Notify};
+
+/// Number of buckets above which idle buckets are removed.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Maximum number of bytes queued for a node before transmits are dropped.
+const MAX_QUEUED_BYTES: usize = 128 * 1024;
+
+/// The smallest allowed burst, a QUIC datagram of the minimum size.
+///
+/// A smaller bucket would never fill up enough to send a full sized datagram without
+/// waiting for it to refill first.
+pub(crate) const MIN_BURST: u64 = 1200;
+
+/// Parameters of the token bucket used for every node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SendRateLimit {
+    /// The sustained rate in bytes per second.
+    pub(crate) bytes_per_second: u64,
+    /// The number of bytes which can be sent in a burst above the sustained rate.
+    pub(crate) burst: u64,
+}
+
+/// What to do with a transmit, see [`RateLimiter::check`].
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Limited {
+    /// The transmit is within the rate limit and should be sent.
+    Send,
+    /// The transmit was queued to be sent later.
+    Queued,
+    /// The queue for the node is full, the transmit should be dropped.
+    Dropped,
+}
+
+/// A transmit delayed by the rate limit or pacing.
+#[derive(Debug)]
+pub(super) struct QueuedTransmit {
+    pub(super) destination: SocketAddr,
+    pub(super) ecn: Option<quinn_udp::EcnCodepoint>,
+    pub(super) contents: Bytes,
+    pub(super) segment_size: Option<usize>,
+    pub(super) src_ip: Option<IpAddr>,
+}
+
+impl From<&quinn_udp::Transmit<'_>> for QueuedTransmit {
+    fn from(transmit: &quinn_udp::Transmit<'_>) -> Self {
+        Self {
+            destination: transmit.destination,
+            ecn: transmit.ecn,
+            contents: Bytes::copy_from_slice(transmit.contents),
+            segment_size: transmit.segment_size,
+            src_ip: transmit.src_ip,
+        }
+    }
+}
+
+impl QueuedTransmit {
+    pub(super) fn as_transmit(&self) -> quinn_udp::Transmit<'_> {
+        quinn_udp::Transmit {
+            destination: self.destination,
+            ecn: self.ecn,
+            contents: &self.contents,
+            segment_size: self.segment_size,
+            src_ip: self.src_ip,
+        }
+    }
+}
+
+/// Transmits delayed until they can be sent, flushed by [`MagicSock::send_delayed`].
+///
+/// [`MagicSock::send_delayed`]: super::MagicSock::send_delayed
+pub(super) trait DelayQueue {
+    /// Removes the queued transmits which can be sent now.
+    ///
+    /// Returns the transmits to send and when the next queued transmit can be sent.
+    fn take_ready(&self, now: Instant) -> (Vec<QueuedTransmit>, Option<Instant>);
+
+    /// Waits until a transmit is queued.
+    fn queued(&self) -> Notified<'_>;
+}
+
+/// Limits the rate of outgoing transmits using a token bucket for each node.
+///
+/// A transmit is allowed as long as the bucket is not empty, it may overdraw the bucket.
+/// This allows transmits larger than the burst, e.g. GSO batches.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    limit: SendRateLimit,
+    nodes: std::sync::Mutex<HashMap<NodeId, NodeState>>,
+    /// Notified when a transmit is queued.
+    queued: Notify,
+}
+
+impl RateLimiter {
+    pub(super) fn new(limit: SendRateLimit) -> Self {
+        Self {
+            limit,
+            nodes: Default::default(),
+            queued: Notify::new(),
+        }
+    }
+
+    /// Checks whether `transmit` to `node` is within the rate limit.
+    pub(super) fn check(&self, node: NodeId, transmit: &quinn_udp::Transmit) -> Limited {
+        self.check_at(node, transmit, Instant::now())
+    }
+
+    fn check_at(&self, node: NodeId, transmit: &quinn_udp::Transmit, now: Instant) -> Limited {
+        let mut nodes = self.nodes.lock().expect("poisoned");
+        if nodes.len() >= PRUNE_THRESHOLD && !nodes.contains_key(&node) {
+            // An idle node with a full bucket behaves the same as a new one.
+            nodes.retain(|_, state| !state.is_idle(&self.limit, now));
+        }
+        let state = nodes
+            .entry(node)
+            .or_insert_with(|| NodeState::new(&self.limit, now));
+        state.refill(&self.limit, now);
+
+        // Transmits must be sent in order, only send straight away if nothing is queued.
+        if state.queue.is_empty() && state.tokens > 0.0 {
+            state.tokens -= transmit.contents.len() as f64;
+            return Limited::Send;
+        }
+        if state.queued_bytes + transmit.contents.len() > MAX_QUEUED_BYTES {
+            return Limited::Dropped;
+        }
+        state.queued_bytes += transmit.contents.len();
+        state.queue.push_back(QueuedTransmit::from(transmit));
+        drop(nodes);
+        self.queued.notify_one();
+        Limited::Queued
+    }
+}
+
+impl DelayQueue for RateLimiter {
+    fn take_ready(&self, now: Instant) -> (Vec<QueuedTransmit>, Option<Instant>) {
+        let mut ready = Vec::new();
+        let mut next = None;
+        let mut nodes = self.nodes.lock().expect("poisoned");
+        for state in nodes.values_mut() {
+            if state.queue.is_empty() {
+                continue;
+            }
+            state.refill(&self.limit, now);
+            while state.tokens > 0.0 {
+                let Some(transmit) = state.queue.pop_front() else {
+                    break;
+                };
+                state.tokens -= transmit.contents.len() as f64;
+                state.queued_bytes -= transmit.contents.len();
+                ready.push(transmit);
+            }
+            if !state.queue.is_empty() {
+                let ready_at = state.ready_at(&self.limit, now);
+                next = Some(next.map_or(ready_at, |next: Instant| next.min(ready_at)));
+            }
+        }
+        (ready, next)
+    }
+
+    fn queued(&self) -> Notified<'_> {
+        self.queued.notified()
+    }
+}
+
+#[derive(Debug)]
+struct NodeState {
+    /// The tokens in the bucket, negative if the bucket was overdrawn.
+    tokens: f64,
+    updated: Instant,
+    queue: VecDeque<QueuedTransmit>,
+    queued_bytes: usize,
+}
+
+impl NodeState {
+    fn new(limit: &SendRateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            updated: now,
+            queue: Default::default(),
+            queued_bytes: 0,
+        }
+    }
+
+    fn refill(&mut self, limit: &SendRateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.bytes_per_second as f64).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// When the bucket will have tokens again, after [`NodeState::refill`].
+    fn ready_at(&self, limit: &SendRateLimit, now: Instant) -> Instant {
+        // Waiting for at least one token makes sure the bucket is not empty by then.
+        let missing = 1.0 - self.tokens.min(0.0);
+        now + std::time::Duration::from_secs_f64(missing / limit.bytes_per_second.max(1) as f64)
+    }
+
+    fn is_idle(&self, limit: &SendRateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.queue.is_empty()
+            && self.tokens + elapsed * limit.bytes_per_second as f64 >= limit.burst as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    fn transmit(contents: &[u8]) -> quinn_udp::Transmit<'_> {
+        quinn_udp::Transmit {
+            destination: "127.0.0.1:1234".parse().unwrap(),
+            ecn: None,
+            contents,
+            segment_size: None,
+            src_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(SendRateLimit {
+            bytes_per_second: 1000,
+            burst: 1500,
+        });
+        let node_a = SecretKey::from_bytes(&[1u8; 32]).public();
+        let node_b = SecretKey::from_bytes(&[2u8; 32]).public();
+        let start = Instant::now();
+        let packet = [0u8; 1000];
+
+        // The burst is available straight away, and may be overdrawn.
+        assert_eq!(
+            limiter.check_at(node_a, &transmit(&packet), start),
+            Limited::Send
+        );
+        assert_eq!(
+            limiter.check_at(node_a, &transmit(&packet), start),
+            Limited::Send
+        );
+        assert_eq!(
+            limiter.check_at(node_a, &transmit(&packet), start),
+            Limited::Queued
+        );
+
+        // Nodes are limited independently.
+        assert_eq!(
+            limiter.check_at(node_b, &transmit(&packet), start),
+            Limited::Send
+        );
+
+        // The bucket is at -500, the queued transmit can be sent once it refilled.
+        let (ready, next) = limiter.take_ready(start);
+        assert!(ready.is_empty());
+        let next = next.unwrap();
+        assert!(next > start + Duration::from_millis(500));
+        assert!(next < start + Duration::from_millis(510));
+        let (ready, next) = limiter.take_ready(next);
+        assert_eq!(ready.len(), 1);
+        assert!(next.is_none());
+
+        // Once the queue is full transmits are dropped.
+        let mut dropped = false;
+        for _ in 0..(MAX_QUEUED_BYTES / packet.len() + 1) {
+            if limiter.check_at(node_a, &transmit(&packet), start) == Limited::Dropped {
+                dropped = true;
+            }
+        }
+        assert!(dropped);
+    }
+}
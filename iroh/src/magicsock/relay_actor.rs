@@ -6,7 +6,9 @@
 //! - The [`RelayActor`] manages all connections to relay servers.
 //!   - It starts a new [`ActiveRelayActor`] for each relay server needed.
 //!   - The [`ActiveRelayActor`] will exit when unused.
-//!     - Unless it is for the home relay, this one never exits.
+//!     - Unless it is for the home relay or a standby relay, these never exit.
+//!   - When the connection to the home relay is lost, a connected standby relay becomes
+//!     the home relay.
 //!   - Each [`ActiveRelayActor`] uses a relay [`Client`].
 //!     - The relay [`Client`] is a `Stream` and `Sink` directly connected to the
 //!       `TcpStream` connected to the relay server.
@@ -44,7 +46,7 @@ use iroh_base::{NodeId, PublicKey, RelayUrl, SecretKey};
 use iroh_metrics::{inc, inc_by};
 use iroh_relay::{
     self as relay,
-    client::{Client, ReceivedMessage, SendMessage},
+    client::{Client, ClientSink, ReceivedMessage, SendMessage},
     PingTracker, MAX_PACKET_SIZE,
 };
 use n0_future::{
@@ -52,20 +54,24 @@ use n0_future::{
     time::{self, Duration, Instant, MissedTickBehavior},
     FuturesUnorderedBounded, SinkExt, StreamExt,
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, event, info_span, instrument, trace, warn, Instrument, Level};
+use tracing::{debug, error, event, info, info_span, instrument, trace, warn, Instrument, Level};
 use url::Url;
 
 use super::RelayDatagramSendChannelReceiver;
 #[cfg(not(wasm_browser))]
 use crate::dns::DnsResolver;
 use crate::{
-    magicsock::{MagicSock, Metrics as MagicsockMetrics, RelayContents, RelayDatagramRecvQueue},
+    magicsock::{
+        MagicSock, Metrics as MagicsockMetrics, RelayContents, RelayDatagramRecvQueue, RelayEvent,
+    },
     util::MaybeFuture,
 };
 
 /// How long a non-home relay connection needs to be idle (last written to) before we close it.
+///
+/// This is the default, see [`RelayKeepalive::idle_timeout`].
 const RELAY_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
 
 /// Maximum size a datagram payload is allowed to be.
@@ -74,7 +80,7 @@ const MAX_PAYLOAD_SIZE: usize = MAX_PACKET_SIZE - PublicKey::LENGTH;
 /// Interval in which we ping the relay server to ensure the connection is alive.
 ///
 /// The default QUIC max_idle_timeout is 30s, so setting that to half this time gives some
-/// chance of recovering.
+/// chance of recovering.  This is the default, see [`RelayKeepalive::ping_interval`].
 const PING_INTERVAL: Duration = Duration::from_secs(15);
 
 /// Number of datagrams which can be sent to the relay server in one batch.
@@ -90,6 +96,13 @@ const SEND_DATAGRAM_BATCH_SIZE: usize = 20;
 /// handshake.
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Maximum time to spend sending queued datagrams when the [`ActiveRelayActor`] shuts down.
+///
+/// Endpoints close their connections right before shutting down the relay actors, so the
+/// queue usually holds the final CONNECTION_CLOSE frames.  This bounds how long an
+/// unresponsive relay server can delay shutdown.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Time after which the [`ActiveRelayActor`] will drop undeliverable datagrams.
 ///
 /// When the [`ActiveRelayActor`] is not connected it can not deliver datagrams.  However it
@@ -99,6 +112,62 @@ const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 /// This value is set to 3 times the QUIC initial Probe Timeout (PTO).
 const UNDELIVERABLE_DATAGRAM_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// How an [`ActiveRelayActor`] retries connecting to its relay server.
+#[derive(Debug, Clone)]
+pub(crate) struct RelayReconnect {
+    /// The delay before the first retry, doubled for each further retry.
+    pub(crate) initial_delay: Duration,
+    /// The maximum delay between retries.
+    pub(crate) max_delay: Duration,
+    /// Whether random jitter is added to the delays.
+    pub(crate) jitter: bool,
+    /// After this many failed attempts in a row a [`RelayEvent::Unreachable`] is emitted.
+    pub(crate) max_attempts: usize,
+}
+
+impl Default for RelayReconnect {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(16),
+            jitter: true,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl RelayReconnect {
+    fn build_backoff(&self) -> impl Backoff {
+        let builder = ExponentialBuilder::new()
+            .with_min_delay(self.initial_delay)
+            .with_max_delay(self.max_delay)
+            .without_max_times();
+        match self.jitter {
+            true => builder.with_jitter().build(),
+            false => builder.build(),
+        }
+    }
+}
+
+/// Timing of the traffic keeping the relay connections alive.
+#[derive(Debug, Clone)]
+pub(crate) struct RelayKeepalive {
+    /// How often the relay server is pinged.
+    pub(crate) ping_interval: Duration,
+    /// How long a connection to a relay server which is not the home relay is kept open
+    /// while no datagrams are sent.
+    pub(crate) idle_timeout: Duration,
+}
+
+impl Default for RelayKeepalive {
+    fn default() -> Self {
+        Self {
+            ping_interval: PING_INTERVAL,
+            idle_timeout: RELAY_INACTIVE_CLEANUP_TIME,
+        }
+    }
+}
+
 /// An actor which handles the connection to a single relay server.
 ///
 /// It is responsible for maintaining the connection to the relay server and handling all
@@ -138,10 +207,19 @@ struct ActiveRelayActor {
     relay_datagrams_recv: Arc<RelayDatagramRecvQueue>,
     /// Channel on which we queue packets to send to the relay.
     relay_datagrams_send: mpsc::Receiver<RelaySendItem>,
+    /// Channel on which we queue packets to send to the relay ahead of the packets in
+    /// `relay_datagrams_send`.
+    relay_prio_datagrams_send: mpsc::Receiver<RelaySendItem>,
 
     // Other actor state.
     /// The relay server for this actor.
     url: RelayUrl,
+    /// How to retry connecting to the relay server.
+    reconnect: RelayReconnect,
+    /// How to keep the connection to the relay server alive.
+    keepalive: RelayKeepalive,
+    /// Channel on which [`RelayEvent`]s are emitted.
+    events: broadcast::Sender<RelayEvent>,
     /// Builder which can repeatedly build a relay client.
     relay_client_builder: relay::client::ClientBuilder,
     /// Whether or not this is the home relay server.
@@ -149,6 +227,11 @@ struct ActiveRelayActor {
     /// The home relay server needs to maintain it's connection to the relay server, even if
     /// the relay actor is otherwise idle.
     is_home_relay: bool,
+    /// Whether or not this is a standby relay server.
+    ///
+    /// Standby relay servers maintain their connection like the home relay, so they can take
+    /// over if the home relay is lost.
+    is_standby_relay: bool,
     /// When this expires the actor has been idle and should shut down.
     ///
     /// Unless it is managing the home relay connection.  Inactivity is only tracked on the
@@ -172,6 +255,8 @@ enum ActiveRelayMessage {
     CheckConnection(Vec<IpAddr>),
     /// Sets this relay as the home relay, or not.
     SetHomeRelay(bool),
+    /// Sets this relay as a standby relay, or not.
+    SetStandbyRelay(bool),
     #[cfg(test)]
     GetLocalAddr(oneshot::Sender<Option<SocketAddr>>),
     #[cfg(test)]
@@ -187,6 +272,8 @@ enum ActiveRelayMessage {
 enum ActiveRelayPrioMessage {
     /// Returns whether or not this relay can reach the NodeId.
     HasNodeRoute(NodeId, oneshot::Sender<bool>),
+    /// Returns whether the connection to the relay server is established.
+    IsConnected(oneshot::Sender<bool>),
 }
 
 /// Configuration needed to start an [`ActiveRelayActor`].
@@ -196,8 +283,12 @@ struct ActiveRelayActorOptions {
     prio_inbox_: mpsc::Receiver<ActiveRelayPrioMessage>,
     inbox: mpsc::Receiver<ActiveRelayMessage>,
     relay_datagrams_send: mpsc::Receiver<RelaySendItem>,
+    relay_prio_datagrams_send: mpsc::Receiver<RelaySendItem>,
     relay_datagrams_recv: Arc<RelayDatagramRecvQueue>,
     connection_opts: RelayConnectionOptions,
+    reconnect: RelayReconnect,
+    keepalive: RelayKeepalive,
+    events: broadcast::Sender<RelayEvent>,
     stop_token: CancellationToken,
 }
 
@@ -208,6 +299,8 @@ struct RelayConnectionOptions {
     #[cfg(not(wasm_browser))]
     dns_resolver: DnsResolver,
     proxy_url: Option<Url>,
+    #[cfg(not(wasm_browser))]
+    tls_config: relay::client::ClientTlsConfig,
     prefer_ipv6: Arc<AtomicBool>,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_cert_verify: bool,
@@ -232,20 +325,30 @@ impl ActiveRelayActor {
             prio_inbox_: prio_inbox,
             inbox,
             relay_datagrams_send,
+            relay_prio_datagrams_send,
             relay_datagrams_recv,
             connection_opts,
+            reconnect,
+            keepalive,
+            events,
             stop_token,
         } = opts;
         let relay_client_builder = Self::create_relay_builder(url.clone(), connection_opts);
+        let inactive_timeout = Box::pin(time::sleep(keepalive.idle_timeout));
         ActiveRelayActor {
             prio_inbox,
             inbox,
             relay_datagrams_recv,
             relay_datagrams_send,
+            relay_prio_datagrams_send,
             url,
+            reconnect,
+            keepalive,
+            events,
             relay_client_builder,
             is_home_relay: false,
-            inactive_timeout: Box::pin(time::sleep(RELAY_INACTIVE_CLEANUP_TIME)),
+            is_standby_relay: false,
+            inactive_timeout,
             stop_token,
         }
     }
@@ -259,6 +362,8 @@ impl ActiveRelayActor {
             #[cfg(not(wasm_browser))]
             dns_resolver,
             proxy_url,
+            #[cfg(not(wasm_browser))]
+            tls_config,
             prefer_ipv6,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify,
@@ -276,6 +381,8 @@ impl ActiveRelayActor {
         if let Some(proxy_url) = proxy_url {
             builder = builder.proxy_url(proxy_url);
         }
+        #[cfg(not(wasm_browser))]
+        let builder = builder.tls_config(tls_config);
         #[cfg(any(test, feature = "test-utils"))]
         let builder = builder.insecure_skip_cert_verify(insecure_skip_cert_verify);
         builder
@@ -287,22 +394,43 @@ impl ActiveRelayActor {
     async fn run(mut self) -> Result<()> {
         inc!(MagicsockMetrics, num_relay_conns_added);
 
-        let mut backoff = Self::build_backoff();
+        let mut backoff = self.reconnect.build_backoff();
+        let mut attempts = 0;
 
         while let Err(err) = self.run_once().await {
             warn!("{err}");
+            let error = err.to_string();
             match err {
                 RelayConnectionError::Connecting(_) | RelayConnectionError::Handshake(_) => {
                     // If dialing failed, or if the relay connection failed before we received a pong,
                     // we wait an exponentially increasing time until we attempt to reconnect again.
+                    attempts += 1;
+                    if attempts == self.reconnect.max_attempts {
+                        self.emit(RelayEvent::Unreachable {
+                            url: self.url.clone(),
+                            attempts,
+                            error: error.clone(),
+                        });
+                    }
                     let delay = backoff.next().context("Retries exceeded")?;
                     debug!("Retry in {delay:?}");
+                    self.emit(RelayEvent::Reconnecting {
+                        url: self.url.clone(),
+                        attempt: attempts,
+                        delay,
+                        error,
+                    });
                     time::sleep(delay).await;
                 }
                 RelayConnectionError::Established(_) => {
                     // If the relay connection remained established long enough so that we received a pong
                     // from the relay server, we reset the backoff and attempt to reconnect immediately.
-                    backoff = Self::build_backoff();
+                    backoff = self.reconnect.build_backoff();
+                    attempts = 0;
+                    self.emit(RelayEvent::Disconnected {
+                        url: self.url.clone(),
+                        error,
+                    });
                 }
             }
         }
@@ -311,13 +439,9 @@ impl ActiveRelayActor {
         Ok(())
     }
 
-    fn build_backoff() -> impl Backoff {
-        ExponentialBuilder::new()
-            .with_min_delay(Duration::from_millis(10))
-            .with_max_delay(Duration::from_secs(16))
-            .with_jitter()
-            .without_max_times()
-            .build()
+    fn emit(&self, event: RelayEvent) {
+        // Sending fails if there are no subscribers, which is fine.
+        self.events.send(event).ok();
     }
 
     /// Attempt to connect to the relay, and run the connected actor loop.
@@ -339,7 +463,7 @@ impl ActiveRelayActor {
     fn reset_inactive_timeout(&mut self) {
         self.inactive_timeout
             .as_mut()
-            .reset(Instant::now() + RELAY_INACTIVE_CLEANUP_TIME);
+            .reset(Instant::now() + self.keepalive.idle_timeout);
     }
 
     fn set_home_relay(&mut self, is_home: bool) {
@@ -389,6 +513,9 @@ impl ActiveRelayActor {
                         ActiveRelayPrioMessage::HasNodeRoute(_peer, sender) => {
                             sender.send(false).ok();
                         }
+                        ActiveRelayPrioMessage::IsConnected(sender) => {
+                            sender.send(false).ok();
+                        }
                     }
                 }
                 res = &mut dialing_fut => {
@@ -410,6 +537,9 @@ impl ActiveRelayActor {
                         ActiveRelayMessage::SetHomeRelay(is_home) => {
                             self.set_home_relay(is_home);
                         }
+                        ActiveRelayMessage::SetStandbyRelay(is_standby) => {
+                            self.is_standby_relay = is_standby;
+                        }
                         ActiveRelayMessage::CheckConnection(_local_ips) => {}
                         #[cfg(test)]
                         ActiveRelayMessage::GetLocalAddr(sender) => {
@@ -424,15 +554,17 @@ impl ActiveRelayActor {
                 _ = send_datagram_flush.tick() => {
                     self.reset_inactive_timeout();
                     let mut logged = false;
-                    while self.relay_datagrams_send.try_recv().is_ok() {
+                    while self.relay_prio_datagrams_send.try_recv().is_ok()
+                        || self.relay_datagrams_send.try_recv().is_ok()
+                    {
                         if !logged {
                             debug!(?UNDELIVERABLE_DATAGRAM_TIMEOUT, "Dropping datagrams to send.");
                             logged = true;
                         }
                     }
                 }
-                _ = &mut self.inactive_timeout, if !self.is_home_relay => {
-                    debug!(?RELAY_INACTIVE_CLEANUP_TIME, "Inactive, exiting.");
+                _ = &mut self.inactive_timeout, if !self.is_home_relay && !self.is_standby_relay => {
+                    debug!(idle_timeout = ?self.keepalive.idle_timeout, "Inactive, exiting.");
                     break None;
                 }
             }
@@ -486,10 +618,11 @@ impl ActiveRelayActor {
 
         // A buffer to pass through multiple datagrams at once as an optimisation.
         let mut send_datagrams_buf = Vec::with_capacity(SEND_DATAGRAM_BATCH_SIZE);
+        let mut send_prio_datagrams_buf = Vec::with_capacity(SEND_DATAGRAM_BATCH_SIZE);
 
         // Regularly send pings so we know the connection is healthy.
         // The first ping will be sent immediately.
-        let mut ping_interval = time::interval(PING_INTERVAL);
+        let mut ping_interval = time::interval(self.keepalive.ping_interval);
         ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         let res = loop {
@@ -502,6 +635,7 @@ impl ActiveRelayActor {
                 biased;
                 _ = self.stop_token.cancelled() => {
                     debug!("Shutdown.");
+                    self.drain_datagrams(&mut client_sink).await;
                     break Ok(());
                 }
                 msg = self.prio_inbox.recv() => {
@@ -514,6 +648,9 @@ impl ActiveRelayActor {
                             let has_peer = state.nodes_present.contains(&peer);
                             sender.send(has_peer).ok();
                         }
+                        ActiveRelayPrioMessage::IsConnected(sender) => {
+                            sender.send(state.established).ok();
+                        }
                     }
                 }
                 _ = state.ping_tracker.timeout() => {
@@ -533,6 +670,9 @@ impl ActiveRelayActor {
                         ActiveRelayMessage::SetHomeRelay(is_home) => {
                             self.set_home_relay(is_home);
                         }
+                        ActiveRelayMessage::SetStandbyRelay(is_standby) => {
+                            self.is_standby_relay = is_standby;
+                        }
                         ActiveRelayMessage::CheckConnection(local_ips) => {
                             match client_stream.local_addr() {
                                 Some(addr) if local_ips.contains(&addr.ip()) => {
@@ -558,6 +698,25 @@ impl ActiveRelayActor {
                         }
                     }
                 }
+                // Priority datagrams, like disco messages, are sent ahead of the others.
+                count = self.relay_prio_datagrams_send.recv_many(
+                    &mut send_prio_datagrams_buf,
+                    SEND_DATAGRAM_BATCH_SIZE,
+                ) => {
+                    if count == 0 {
+                        warn!("Priority datagram inbox closed, shutdown");
+                        break Ok(());
+                    };
+                    self.reset_inactive_timeout();
+                    let dgrams = std::mem::replace(
+                        &mut send_prio_datagrams_buf,
+                        Vec::with_capacity(SEND_DATAGRAM_BATCH_SIZE),
+                    );
+                    let packet_iter = packetize_send_items(dgrams).map(Ok);
+                    let mut packet_stream = n0_future::stream::iter(packet_iter);
+                    let fut = client_sink.send_all(&mut packet_stream);
+                    self.run_sending(fut, &mut state, &mut client_stream).await?;
+                }
                 count = self.relay_datagrams_send.recv_many(
                     &mut send_datagrams_buf,
                     SEND_DATAGRAM_BATCH_SIZE,
@@ -573,17 +732,7 @@ impl ActiveRelayActor {
                         &mut send_datagrams_buf,
                         Vec::with_capacity(SEND_DATAGRAM_BATCH_SIZE),
                     );
-                    let packet_iter = dgrams.into_iter().flat_map(|datagrams| {
-                        PacketizeIter::<_, MAX_PAYLOAD_SIZE>::new(
-                            datagrams.remote_node,
-                            datagrams.datagrams.clone(),
-                        )
-                        .map(|p| {
-                            inc_by!(MagicsockMetrics, send_relay, p.payload.len() as _);
-                            SendMessage::SendPacket(p.node_id, p.payload)
-                        })
-                        .map(Ok)
-                    });
+                    let packet_iter = packetize_send_items(dgrams).map(Ok);
                     let mut packet_stream = n0_future::stream::iter(packet_iter);
                     let fut = client_sink.send_all(&mut packet_stream);
                     self.run_sending(fut, &mut state, &mut client_stream).await?;
@@ -601,8 +750,8 @@ impl ActiveRelayActor {
                         Err(err) => break Err(anyhow!("Client stream read error: {err:#}")),
                     }
                 }
-                _ = &mut self.inactive_timeout, if !self.is_home_relay => {
-                    debug!("Inactive for {RELAY_INACTIVE_CLEANUP_TIME:?}, exiting.");
+                _ = &mut self.inactive_timeout, if !self.is_home_relay && !self.is_standby_relay => {
+                    debug!("Inactive for {:?}, exiting.", self.keepalive.idle_timeout);
                     break Ok(());
                 }
             }
@@ -617,6 +766,32 @@ impl ActiveRelayActor {
         res.map_err(|err| state.map_err(err))
     }
 
+    /// Sends the datagrams still queued for this relay server before shutting down.
+    ///
+    /// Only datagrams already in the queue are sent, and no longer than
+    /// [`SHUTDOWN_DRAIN_TIMEOUT`].  Failures are only logged since the connection is
+    /// closed right after anyway.
+    async fn drain_datagrams(&mut self, client_sink: &mut ClientSink) {
+        let mut dgrams = Vec::new();
+        while let Ok(item) = self.relay_prio_datagrams_send.try_recv() {
+            dgrams.push(item);
+        }
+        while let Ok(item) = self.relay_datagrams_send.try_recv() {
+            dgrams.push(item);
+        }
+        if dgrams.is_empty() {
+            return;
+        }
+        let count = dgrams.len();
+        let mut packet_stream = n0_future::stream::iter(packetize_send_items(dgrams).map(Ok));
+        let fut = client_sink.send_all(&mut packet_stream);
+        match time::timeout(SHUTDOWN_DRAIN_TIMEOUT, fut).await {
+            Ok(Ok(())) => debug!(count, "Sent queued datagrams on shutdown"),
+            Ok(Err(err)) => debug!("Failed to send queued datagrams on shutdown: {err:#}"),
+            Err(_) => debug!("Timeout sending queued datagrams on shutdown"),
+        }
+    }
+
     fn handle_relay_msg(&mut self, msg: ReceivedMessage, state: &mut ConnectedRelayState) {
         match msg {
             ReceivedMessage::ReceivedPacket {
@@ -661,7 +836,12 @@ impl ActiveRelayActor {
                     }
                 }
                 state.ping_tracker.pong_received(data);
-                state.established = true;
+                if !state.established {
+                    state.established = true;
+                    self.emit(RelayEvent::Connected {
+                        url: self.url.clone(),
+                    });
+                }
             }
             ReceivedMessage::Health { problem } => {
                 let problem = problem.as_deref().unwrap_or("unknown");
@@ -690,7 +870,7 @@ impl ActiveRelayActor {
         state: &mut ConnectedRelayState,
         client_stream: &mut iroh_relay::client::ClientStream,
     ) -> Result<(), RelayConnectionError> {
-        // we use the same time as for our ping interval
+        // we use the same time as for our default ping interval
         let send_timeout = PING_INTERVAL;
 
         let mut timeout = pin!(time::sleep(send_timeout));
@@ -714,6 +894,9 @@ impl ActiveRelayActor {
                             let has_peer = state.nodes_present.contains(&peer);
                             sender.send(has_peer).ok();
                         }
+                        ActiveRelayPrioMessage::IsConnected(sender) => {
+                            sender.send(state.established).ok();
+                        }
                     }
                 }
                 res = &mut sending_fut => {
@@ -735,8 +918,8 @@ impl ActiveRelayActor {
                         Err(err) => break Err(anyhow!("Client stream read error: {err:#}")),
                     }
                 }
-                _ = &mut self.inactive_timeout, if !self.is_home_relay => {
-                    debug!("Inactive for {RELAY_INACTIVE_CLEANUP_TIME:?}, exiting.");
+                _ = &mut self.inactive_timeout, if !self.is_home_relay && !self.is_standby_relay => {
+                    debug!("Inactive for {:?}, exiting.", self.keepalive.idle_timeout);
                     break Ok(());
                 }
             }
@@ -782,7 +965,13 @@ impl ConnectedRelayState {
 
 pub(super) enum RelayActorMessage {
     MaybeCloseRelaysOnRebind(Vec<IpAddr>),
-    SetHome { url: RelayUrl },
+    SetHome {
+        url: RelayUrl,
+    },
+    /// Sets the relays to keep standby connections to, best first.
+    SetStandby {
+        urls: Vec<RelayUrl>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -810,6 +999,13 @@ pub(super) struct RelayActor {
     active_relays: BTreeMap<RelayUrl, ActiveRelayHandle>,
     /// The tasks for the [`ActiveRelayActor`]s in `active_relays` above.
     active_relay_tasks: JoinSet<()>,
+    /// The relays to keep standby connections to, best first.
+    ///
+    /// When the connection to the home relay is lost the first connected one becomes the
+    /// home relay.
+    standby_relays: Vec<RelayUrl>,
+    /// The relays which currently have an established connection.
+    connected_relays: BTreeSet<RelayUrl>,
     cancel_token: CancellationToken,
     protocol: iroh_relay::http::Protocol,
 }
@@ -826,6 +1022,8 @@ impl RelayActor {
             relay_datagram_recv_queue,
             active_relays: Default::default(),
             active_relay_tasks: JoinSet::new(),
+            standby_relays: Vec::new(),
+            connected_relays: BTreeSet::new(),
             cancel_token,
             protocol,
         }
@@ -843,6 +1041,8 @@ impl RelayActor {
         // When this future is present, it is sending pending datagrams to an
         // ActiveRelayActor.  We can not process further datagrams during this time.
         let mut datagram_send_fut = std::pin::pin!(MaybeFuture::none());
+        // Tracks which relays are connected, to know where to fail over to.
+        let mut relay_events = self.msock.relay_events.subscribe();
 
         loop {
             tokio::select! {
@@ -872,14 +1072,30 @@ impl RelayActor {
                     let cancel_token = self.cancel_token.child_token();
                     cancel_token.run_until_cancelled(self.handle_msg(msg)).await;
                 }
-                // Only poll for new datagrams if we are not blocked on sending them.
-                item = datagram_send_channel.recv(), if datagram_send_fut.is_none() => {
-                    let Some(item) = item else {
+                res = relay_events.recv() => {
+                    match res {
+                        Ok(event) => self.handle_relay_event(event).await,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!(skipped, "missed relay events, resyncing relay state");
+                            self.resync_connected_relays().await;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            debug!("Relay events closed, shutting down.");
+                            break;
+                        }
+                    }
+                }
+                // Only poll for new normal datagrams if we are not blocked on sending them.
+                // Priority datagrams are never blocked.
+                item = datagram_send_channel.recv(datagram_send_fut.is_none()) => {
+                    let Some((item, priority)) = item else {
                         debug!("Datagram send channel dropped, shutting down.");
                         break;
                     };
                     let token = self.cancel_token.child_token();
-                    if let Some(Some(fut)) = token.run_until_cancelled(
+                    if priority {
+                        token.run_until_cancelled(self.send_prio_datagram(item)).await;
+                    } else if let Some(Some(fut)) = token.run_until_cancelled(
                         self.try_send_datagram(item)
                     ).await {
                         datagram_send_fut.as_mut().set_future(fut);
@@ -909,9 +1125,86 @@ impl RelayActor {
             RelayActorMessage::MaybeCloseRelaysOnRebind(ifs) => {
                 self.maybe_close_relays_on_rebind(&ifs).await;
             }
+            RelayActorMessage::SetStandby { urls } => {
+                self.set_standby_relays(urls).await;
+            }
+        }
+    }
+
+    /// Tracks the connected relays and fails over when the home relay is lost.
+    async fn handle_relay_event(&mut self, event: RelayEvent) {
+        match event {
+            RelayEvent::Connected { url } => {
+                self.connected_relays.insert(url);
+            }
+            RelayEvent::Disconnected { url, .. }
+            | RelayEvent::Reconnecting { url, .. }
+            | RelayEvent::Unreachable { url, .. } => {
+                self.connected_relays.remove(&url);
+                if self.msock.my_relay().as_ref() == Some(&url) {
+                    self.failover(url).await;
+                }
+            }
+            RelayEvent::Failover { .. } => (),
+        }
+    }
+
+    /// Queries the [`ActiveRelayActor`]s for the connected relays, after missing events.
+    ///
+    /// Fails over if the home relay turns out to be no longer connected.
+    async fn resync_connected_relays(&mut self) {
+        let check_futs = self.active_relays.iter().map(|(url, handle)| async move {
+            let (tx, rx) = oneshot::channel();
+            handle
+                .prio_inbox_addr
+                .send(ActiveRelayPrioMessage::IsConnected(tx))
+                .await
+                .ok();
+            rx.await.unwrap_or(false).then(|| url.clone())
+        });
+        self.connected_relays = n0_future::join_all(check_futs)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        if let Some(home) = self.msock.my_relay() {
+            if !self.connected_relays.contains(&home) {
+                self.failover(home).await;
+            }
         }
     }
 
+    /// Makes the first connected standby relay the home relay, replacing `from`.
+    ///
+    /// The previous home relay becomes a standby relay, so it keeps reconnecting.
+    async fn failover(&mut self, from: RelayUrl) {
+        let Some(to) = self
+            .standby_relays
+            .iter()
+            .find(|url| self.connected_relays.contains(*url))
+            .cloned()
+        else {
+            if !self.standby_relays.is_empty() {
+                debug!(%from, "home relay lost, no standby relay connected");
+            }
+            return;
+        };
+        info!(%from, %to, "home relay lost, failing over to standby relay");
+        inc!(MagicsockMetrics, relay_home_failover);
+        self.msock.set_my_relay(Some(to.clone()));
+        self.msock.publish_my_addr();
+
+        let mut standby = std::mem::take(&mut self.standby_relays);
+        standby.retain(|url| url != &to);
+        standby.push(from.clone());
+        self.set_home_relay(to.clone()).await;
+        self.set_standby_relays(standby).await;
+        self.msock
+            .relay_events
+            .send(RelayEvent::Failover { from, to })
+            .ok();
+    }
+
     /// Sends datagrams to the correct [`ActiveRelayActor`], or returns a future.
     ///
     /// If the datagram can not be sent immediately, because the destination channel is
@@ -940,6 +1233,20 @@ impl RelayActor {
         }
     }
 
+    /// Sends priority datagrams to the correct [`ActiveRelayActor`].
+    ///
+    /// This never waits for the [`ActiveRelayActor`], if its queue is full the datagrams
+    /// are dropped.
+    async fn send_prio_datagram(&mut self, item: RelaySendItem) {
+        let url = item.url.clone();
+        let handle = self
+            .active_relay_handle_for_node(&item.url, &item.remote_node)
+            .await;
+        if let Err(err) = handle.prio_datagrams_send_queue.try_send(item) {
+            warn!(?url, "Dropped priority datagram(s): {err}");
+        }
+    }
+
     async fn set_home_relay(&mut self, home_url: RelayUrl) {
         let home_url_ref = &home_url;
         n0_future::join_all(self.active_relays.iter().map(|(url, handle)| async move {
@@ -955,6 +1262,31 @@ impl RelayActor {
         self.active_relay_handle(home_url);
     }
 
+    /// Sets the relays to keep standby connections to, the home relay is skipped.
+    async fn set_standby_relays(&mut self, mut urls: Vec<RelayUrl>) {
+        let home_url = self.msock.my_relay();
+        urls.retain(|url| Some(url) != home_url.as_ref());
+        if urls == self.standby_relays {
+            return;
+        }
+        let urls_ref = &urls;
+        n0_future::join_all(self.active_relays.iter().map(|(url, handle)| async move {
+            let is_standby = urls_ref.contains(url);
+            handle
+                .inbox_addr
+                .send(ActiveRelayMessage::SetStandbyRelay(is_standby))
+                .await
+                .ok()
+        }))
+        .await;
+        debug!(?urls, "standby relays");
+        self.standby_relays = urls;
+        // Ensure we have ActiveRelayActors for all standby relays.
+        for url in self.standby_relays.clone() {
+            self.active_relay_handle(url);
+        }
+    }
+
     /// Returns the handle for the [`ActiveRelayActor`] to reach `remote_node`.
     ///
     /// The node is expected to be reachable on `url`, but if no [`ActiveRelayActor`] for
@@ -1013,6 +1345,14 @@ impl RelayActor {
                         error!("Home relay not set, send to new actor failed: {err:#}.");
                     }
                 }
+                if self.standby_relays.contains(&url) {
+                    if let Err(err) = handle
+                        .inbox_addr
+                        .try_send(ActiveRelayMessage::SetStandbyRelay(true))
+                    {
+                        error!("Standby relay not set, send to new actor failed: {err:#}.");
+                    }
+                }
                 self.active_relays.insert(url, handle.clone());
                 self.log_active_relay();
                 handle
@@ -1028,6 +1368,8 @@ impl RelayActor {
             #[cfg(not(wasm_browser))]
             dns_resolver: self.msock.dns_resolver.clone(),
             proxy_url: self.msock.proxy_url().cloned(),
+            #[cfg(not(wasm_browser))]
+            tls_config: self.msock.relay_tls_config().clone(),
             prefer_ipv6: self.msock.ipv6_reported.clone(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: self.msock.insecure_skip_relay_cert_verify,
@@ -1036,6 +1378,7 @@ impl RelayActor {
 
         // TODO: Replace 64 with PER_CLIENT_SEND_QUEUE_DEPTH once that's unused
         let (send_datagram_tx, send_datagram_rx) = mpsc::channel(64);
+        let (send_prio_datagram_tx, send_prio_datagram_rx) = mpsc::channel(32);
         let (prio_inbox_tx, prio_inbox_rx) = mpsc::channel(32);
         let (inbox_tx, inbox_rx) = mpsc::channel(64);
         let span = info_span!("active-relay", %url);
@@ -1044,8 +1387,12 @@ impl RelayActor {
             prio_inbox_: prio_inbox_rx,
             inbox: inbox_rx,
             relay_datagrams_send: send_datagram_rx,
+            relay_prio_datagrams_send: send_prio_datagram_rx,
             relay_datagrams_recv: self.relay_datagram_recv_queue.clone(),
             connection_opts,
+            reconnect: self.msock.relay_reconnect.clone(),
+            keepalive: self.msock.relay_keepalive.clone(),
+            events: self.msock.relay_events.clone(),
             stop_token: self.cancel_token.child_token(),
         };
         let actor = ActiveRelayActor::new(opts);
@@ -1062,6 +1409,7 @@ impl RelayActor {
             prio_inbox_addr: prio_inbox_tx,
             inbox_addr: inbox_tx,
             datagrams_send_queue: send_datagram_tx,
+            prio_datagrams_send_queue: send_prio_datagram_tx,
         };
         self.log_active_relay();
         handle
@@ -1088,11 +1436,17 @@ impl RelayActor {
     fn reap_active_relays(&mut self) {
         self.active_relays
             .retain(|_url, handle| !handle.inbox_addr.is_closed());
+        let active_relays = &self.active_relays;
+        self.connected_relays
+            .retain(|url| active_relays.contains_key(url));
 
-        // Make sure home relay exists
+        // Make sure home relay and standby relays exist
         if let Some(ref url) = self.msock.my_relay() {
             self.active_relay_handle(url.clone());
         }
+        for url in self.standby_relays.clone() {
+            self.active_relay_handle(url);
+        }
         self.log_active_relay();
     }
 
@@ -1132,6 +1486,17 @@ struct ActiveRelayHandle {
     prio_inbox_addr: mpsc::Sender<ActiveRelayPrioMessage>,
     inbox_addr: mpsc::Sender<ActiveRelayMessage>,
     datagrams_send_queue: mpsc::Sender<RelaySendItem>,
+    prio_datagrams_send_queue: mpsc::Sender<RelaySendItem>,
+}
+
+/// Packs the datagrams of [`RelaySendItem`]s into [`SendMessage::SendPacket`] frames.
+fn packetize_send_items(items: Vec<RelaySendItem>) -> impl Iterator<Item = SendMessage> {
+    items.into_iter().flat_map(|item| {
+        PacketizeIter::<_, MAX_PAYLOAD_SIZE>::new(item.remote_node, item.datagrams).map(|p| {
+            inc_by!(MagicsockMetrics, send_relay, p.payload.len() as _);
+            SendMessage::SendPacket(p.node_id, p.payload)
+        })
+    })
 }
 
 /// A packet to send over the relay.
@@ -1318,7 +1683,36 @@ mod tests {
         prio_inbox_rx: mpsc::Receiver<ActiveRelayPrioMessage>,
         inbox_rx: mpsc::Receiver<ActiveRelayMessage>,
         relay_datagrams_send: mpsc::Receiver<RelaySendItem>,
+        relay_prio_datagrams_send: mpsc::Receiver<RelaySendItem>,
+        relay_datagrams_recv: Arc<RelayDatagramRecvQueue>,
+        span: tracing::Span,
+    ) -> AbortOnDropHandle<anyhow::Result<()>> {
+        start_active_relay_actor_with_keepalive(
+            secret_key,
+            stop_token,
+            url,
+            prio_inbox_rx,
+            inbox_rx,
+            relay_datagrams_send,
+            relay_prio_datagrams_send,
+            relay_datagrams_recv,
+            Default::default(),
+            span,
+        )
+    }
+
+    /// Starts a new [`ActiveRelayActor`] with custom [`RelayKeepalive`] timings.
+    #[allow(clippy::too_many_arguments)]
+    fn start_active_relay_actor_with_keepalive(
+        secret_key: SecretKey,
+        stop_token: CancellationToken,
+        url: RelayUrl,
+        prio_inbox_rx: mpsc::Receiver<ActiveRelayPrioMessage>,
+        inbox_rx: mpsc::Receiver<ActiveRelayMessage>,
+        relay_datagrams_send: mpsc::Receiver<RelaySendItem>,
+        relay_prio_datagrams_send: mpsc::Receiver<RelaySendItem>,
         relay_datagrams_recv: Arc<RelayDatagramRecvQueue>,
+        keepalive: RelayKeepalive,
         span: tracing::Span,
     ) -> AbortOnDropHandle<anyhow::Result<()>> {
         let opts = ActiveRelayActorOptions {
@@ -1326,15 +1720,20 @@ mod tests {
             prio_inbox_: prio_inbox_rx,
             inbox: inbox_rx,
             relay_datagrams_send,
+            relay_prio_datagrams_send,
             relay_datagrams_recv,
             connection_opts: RelayConnectionOptions {
                 secret_key,
                 dns_resolver: DnsResolver::new(),
                 proxy_url: None,
+                tls_config: Default::default(),
                 prefer_ipv6: Arc::new(AtomicBool::new(true)),
                 insecure_skip_cert_verify: true,
                 protocol: iroh_relay::http::Protocol::default(),
             },
+            reconnect: Default::default(),
+            keepalive,
+            events: broadcast::Sender::new(16),
             stop_token,
         };
         let task = tokio::spawn(ActiveRelayActor::new(opts).run().instrument(span));
@@ -1350,6 +1749,7 @@ mod tests {
         let secret_key = SecretKey::from_bytes(&[8u8; 32]);
         let recv_datagram_queue = Arc::new(RelayDatagramRecvQueue::new());
         let (send_datagram_tx, send_datagram_rx) = mpsc::channel(16);
+        let (prio_send_datagram_tx, prio_send_datagram_rx) = mpsc::channel(16);
         let (prio_inbox_tx, prio_inbox_rx) = mpsc::channel(8);
         let (inbox_tx, inbox_rx) = mpsc::channel(16);
         let cancel_token = CancellationToken::new();
@@ -1360,6 +1760,7 @@ mod tests {
             prio_inbox_rx,
             inbox_rx,
             send_datagram_rx,
+            prio_send_datagram_rx,
             recv_datagram_queue.clone(),
             info_span!("echo-node"),
         );
@@ -1388,6 +1789,7 @@ mod tests {
             // move the inboxes here so it is not dropped, as this stops the actor.
             let _prio_inbox_tx = prio_inbox_tx;
             let _inbox_tx = inbox_tx;
+            let _prio_send_datagram_tx = prio_send_datagram_tx;
             tokio::select! {
                 biased;
                 _ = actor_task => (),
@@ -1443,6 +1845,7 @@ mod tests {
         let secret_key = SecretKey::from_bytes(&[1u8; 32]);
         let datagram_recv_queue = Arc::new(RelayDatagramRecvQueue::new());
         let (send_datagram_tx, send_datagram_rx) = mpsc::channel(16);
+        let (_prio_send_datagram_tx, prio_send_datagram_rx) = mpsc::channel(16);
         let (_prio_inbox_tx, prio_inbox_rx) = mpsc::channel(8);
         let (inbox_tx, inbox_rx) = mpsc::channel(16);
         let cancel_token = CancellationToken::new();
@@ -1453,6 +1856,7 @@ mod tests {
             prio_inbox_rx,
             inbox_rx,
             send_datagram_rx,
+            prio_send_datagram_rx,
             datagram_recv_queue.clone(),
             info_span!("actor-under-test"),
         );
@@ -1529,6 +1933,7 @@ mod tests {
         let secret_key = SecretKey::from_bytes(&[1u8; 32]);
         let datagram_recv_queue = Arc::new(RelayDatagramRecvQueue::new());
         let (_send_datagram_tx, send_datagram_rx) = mpsc::channel(16);
+        let (_prio_send_datagram_tx, prio_send_datagram_rx) = mpsc::channel(16);
         let (_prio_inbox_tx, prio_inbox_rx) = mpsc::channel(8);
         let (inbox_tx, inbox_rx) = mpsc::channel(16);
         let cancel_token = CancellationToken::new();
@@ -1539,6 +1944,7 @@ mod tests {
             prio_inbox_rx,
             inbox_rx,
             send_datagram_rx,
+            prio_send_datagram_rx,
             datagram_recv_queue.clone(),
             info_span!("actor-under-test"),
         );
@@ -1590,6 +1996,220 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_active_relay_idle_timeout() -> TestResult {
+        let (_relay_map, relay_url, _server) = test_utils::run_relay_server().await?;
+
+        let secret_key = SecretKey::from_bytes(&[1u8; 32]);
+        let (_send_datagram_tx, send_datagram_rx) = mpsc::channel(16);
+        let (_prio_send_datagram_tx, prio_send_datagram_rx) = mpsc::channel(16);
+        let (_prio_inbox_tx, prio_inbox_rx) = mpsc::channel(8);
+        let (inbox_tx, inbox_rx) = mpsc::channel(16);
+        let cancel_token = CancellationToken::new();
+        let keepalive = RelayKeepalive {
+            ping_interval: Duration::from_millis(100),
+            idle_timeout: Duration::from_millis(500),
+        };
+        let task = start_active_relay_actor_with_keepalive(
+            secret_key,
+            cancel_token.clone(),
+            relay_url,
+            prio_inbox_rx,
+            inbox_rx,
+            send_datagram_rx,
+            prio_send_datagram_rx,
+            Arc::new(RelayDatagramRecvQueue::new()),
+            keepalive,
+            info_span!("actor-under-test"),
+        );
+        let _guard = cancel_token.drop_guard();
+        wait_relay_connected(&inbox_tx).await?;
+
+        // The pings do not keep the idle connection open.
+        tokio::time::timeout(Duration::from_secs(5), task).await???;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_active_relay_is_connected() -> TestResult {
+        let (_relay_map, relay_url, _server) = test_utils::run_relay_server().await?;
+
+        let secret_key = SecretKey::from_bytes(&[1u8; 32]);
+        let datagram_recv_queue = Arc::new(RelayDatagramRecvQueue::new());
+        let (_send_datagram_tx, send_datagram_rx) = mpsc::channel(16);
+        let (_prio_send_datagram_tx, prio_send_datagram_rx) = mpsc::channel(16);
+        let (prio_inbox_tx, prio_inbox_rx) = mpsc::channel(8);
+        let (inbox_tx, inbox_rx) = mpsc::channel(16);
+        let cancel_token = CancellationToken::new();
+        let task = start_active_relay_actor(
+            secret_key,
+            cancel_token.clone(),
+            relay_url,
+            prio_inbox_rx,
+            inbox_rx,
+            send_datagram_rx,
+            prio_send_datagram_rx,
+            datagram_recv_queue,
+            info_span!("actor-under-test"),
+        );
+
+        // The relay actor resyncs its state using this after missing relay events.
+        wait_relay_connected(&inbox_tx).await?;
+        let (tx, rx) = oneshot::channel();
+        prio_inbox_tx
+            .send(ActiveRelayPrioMessage::IsConnected(tx))
+            .await?;
+        assert!(rx.await?);
+
+        cancel_token.cancel();
+        task.await??;
+        Ok(())
+    }
+
+    /// Waits until the [`ActiveRelayActor`] is connected to the relay server.
+    async fn wait_relay_connected(inbox_tx: &mpsc::Sender<ActiveRelayMessage>) -> Result<()> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let (tx, rx) = oneshot::channel();
+                inbox_tx.send(ActiveRelayMessage::PingServer(tx)).await.ok();
+                if tokio::time::timeout(Duration::from_millis(200), rx)
+                    .await
+                    .map(|resp| resp.is_ok())
+                    .unwrap_or_default()
+                {
+                    break;
+                }
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_active_relay_drain_on_shutdown() -> TestResult {
+        let (_relay_map, relay_url, _server) = test_utils::run_relay_server().await?;
+
+        // The peer receiving the datagrams.
+        let peer_key = SecretKey::from_bytes(&[8u8; 32]);
+        let peer_recv_queue = Arc::new(RelayDatagramRecvQueue::new());
+        let (_peer_send_tx, peer_send_rx) = mpsc::channel(16);
+        let (_peer_prio_send_tx, peer_prio_send_rx) = mpsc::channel(16);
+        let (_peer_prio_inbox_tx, peer_prio_inbox_rx) = mpsc::channel(8);
+        let (peer_inbox_tx, peer_inbox_rx) = mpsc::channel(16);
+        let peer_cancel_token = CancellationToken::new();
+        let _peer_task = start_active_relay_actor(
+            peer_key.clone(),
+            peer_cancel_token.clone(),
+            relay_url.clone(),
+            peer_prio_inbox_rx,
+            peer_inbox_rx,
+            peer_send_rx,
+            peer_prio_send_rx,
+            peer_recv_queue.clone(),
+            info_span!("peer"),
+        );
+        let _peer_guard = peer_cancel_token.drop_guard();
+
+        let secret_key = SecretKey::from_bytes(&[1u8; 32]);
+        let (send_datagram_tx, send_datagram_rx) = mpsc::channel(16);
+        let (_prio_send_datagram_tx, prio_send_datagram_rx) = mpsc::channel(16);
+        let (_prio_inbox_tx, prio_inbox_rx) = mpsc::channel(8);
+        let (inbox_tx, inbox_rx) = mpsc::channel(16);
+        let cancel_token = CancellationToken::new();
+        let task = start_active_relay_actor(
+            secret_key,
+            cancel_token.clone(),
+            relay_url.clone(),
+            prio_inbox_rx,
+            inbox_rx,
+            send_datagram_rx,
+            prio_send_datagram_rx,
+            Arc::new(RelayDatagramRecvQueue::new()),
+            info_span!("actor-under-test"),
+        );
+
+        wait_relay_connected(&peer_inbox_tx).await?;
+        wait_relay_connected(&inbox_tx).await?;
+
+        // Queue a datagram and shut down straight away, it should still be delivered.
+        send_datagram_tx
+            .send(RelaySendItem {
+                remote_node: peer_key.public(),
+                url: relay_url,
+                datagrams: smallvec![Bytes::from_static(b"goodbye")],
+            })
+            .await?;
+        cancel_token.cancel();
+        task.await??;
+
+        let RelayRecvDatagram { buf, .. } = tokio::time::timeout(
+            Duration::from_secs(5),
+            future::poll_fn(|cx| peer_recv_queue.poll_recv(cx)),
+        )
+        .await??;
+        assert_eq!(buf.as_ref(), b"goodbye");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_active_relay_prio_datagrams() -> TestResult {
+        let (_relay_map, relay_url, _server) = test_utils::run_relay_server().await?;
+        let (peer_node, _echo_node_task) = start_echo_node(relay_url.clone());
+
+        let secret_key = SecretKey::from_bytes(&[1u8; 32]);
+        let datagram_recv_queue = Arc::new(RelayDatagramRecvQueue::new());
+        let (send_datagram_tx, send_datagram_rx) = mpsc::channel(16);
+        let (prio_send_datagram_tx, prio_send_datagram_rx) = mpsc::channel(16);
+        let (_prio_inbox_tx, prio_inbox_rx) = mpsc::channel(8);
+        let (_inbox_tx, inbox_rx) = mpsc::channel(16);
+        let cancel_token = CancellationToken::new();
+        let _task = start_active_relay_actor(
+            secret_key,
+            cancel_token.clone(),
+            relay_url.clone(),
+            prio_inbox_rx,
+            inbox_rx,
+            send_datagram_rx,
+            prio_send_datagram_rx,
+            datagram_recv_queue.clone(),
+            info_span!("actor-under-test"),
+        );
+        let _guard = cancel_token.drop_guard();
+
+        let item = |data: &'static [u8]| RelaySendItem {
+            remote_node: peer_node,
+            url: relay_url.clone(),
+            datagrams: smallvec![Bytes::from_static(data)],
+        };
+        send_recv_echo(item(b"hello"), &send_datagram_tx, &datagram_recv_queue).await?;
+
+        // Queued at the same time, the priority datagram is sent first.
+        for _ in 0..8 {
+            send_datagram_tx.try_send(item(b"bulk"))?;
+        }
+        prio_send_datagram_tx.try_send(item(b"prio"))?;
+        let buf = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let RelayRecvDatagram { buf, .. } =
+                    future::poll_fn(|cx| datagram_recv_queue.poll_recv(cx)).await?;
+                // Skip echos of retried hellos.
+                if buf.as_ref() != b"hello" {
+                    break anyhow::Ok(buf);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(buf.as_ref(), b"prio");
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_ping_tracker() {
         tokio::time::pause();
@@ -0,0 +1,114 @@
+//! Selection of the home relay from net reports.
+
+use std::fmt::Debug;
+
+use iroh_base::RelayUrl;
+use iroh_relay::RelayMap;
+
+use crate::net_report::Report;
+
+/// Selects the home relay from the results of a net report.
+///
+/// By default the home relay is the [`Report::preferred_relay`]: the relay with the lowest
+/// recent latency, where the current home relay is kept unless another relay is
+/// considerably faster.  Implement this trait to use a different policy, e.g. to pin the
+/// home relay to a region, to prefer relays in some jurisdictions or to weight relays by
+/// their jitter rather than their latency.  Selectors needing the history of reports can
+/// keep it themselves, they are called for every report.
+///
+/// Install a selector using [`Builder::relay_selector`].
+///
+/// [`Builder::relay_selector`]: crate::endpoint::Builder::relay_selector
+pub trait RelaySelector: Debug + Send + Sync + 'static {
+    /// Selects the home relay after a net report finished.
+    ///
+    /// *current* is the current home relay, if any.  The returned relay must be part of
+    /// *relay_map*, otherwise it is ignored.  Returning `None` falls back to the default
+    /// selection, the [`Report::preferred_relay`].
+    fn select_relay(
+        &self,
+        report: &Report,
+        current: Option<&RelayUrl>,
+        relay_map: &RelayMap,
+    ) -> Option<RelayUrl>;
+}
+
+/// A [`RelaySelector`] only using relays from a fixed set.
+///
+/// The relay with the lowest latency among the set is selected as home relay, keeping the
+/// current home relay while it is part of the set and reachable.  If none of the relays
+/// in the set is reachable the default selection is used.
+#[derive(Debug, Clone)]
+pub struct PinnedRelays {
+    urls: Vec<RelayUrl>,
+}
+
+impl PinnedRelays {
+    /// Creates a selector only using the given relays.
+    pub fn new(urls: impl IntoIterator<Item = RelayUrl>) -> Self {
+        Self {
+            urls: urls.into_iter().collect(),
+        }
+    }
+}
+
+impl RelaySelector for PinnedRelays {
+    fn select_relay(
+        &self,
+        report: &Report,
+        current: Option<&RelayUrl>,
+        _relay_map: &RelayMap,
+    ) -> Option<RelayUrl> {
+        if let Some(current) = current {
+            if self.urls.contains(current) && report.relay_latency.get(current).is_some() {
+                return Some(current.clone());
+            }
+        }
+        report
+            .relay_latency
+            .iter()
+            .filter(|(url, _)| self.urls.contains(url))
+            .min_by_key(|(_, latency)| *latency)
+            .map(|(url, _)| url.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_pinned_relays() {
+        let url = |n: u8| -> RelayUrl { format!("https://relay-{n}.example/").parse().unwrap() };
+        let mut report = Report::default();
+        report
+            .relay_latency
+            .update_relay(url(1), Duration::from_millis(10));
+        report
+            .relay_latency
+            .update_relay(url(2), Duration::from_millis(50));
+        report
+            .relay_latency
+            .update_relay(url(3), Duration::from_millis(30));
+        let relay_map = RelayMap::empty();
+
+        let selector = PinnedRelays::new([url(2), url(3)]);
+        assert_eq!(
+            selector.select_relay(&report, None, &relay_map),
+            Some(url(3))
+        );
+        assert_eq!(
+            selector.select_relay(&report, Some(&url(2)), &relay_map),
+            Some(url(2))
+        );
+        assert_eq!(
+            selector.select_relay(&report, Some(&url(1)), &relay_map),
+            Some(url(3))
+        );
+
+        let selector = PinnedRelays::new([url(4)]);
+        assert_eq!(selector.select_relay(&report, None, &relay_map), None);
+    }
+}
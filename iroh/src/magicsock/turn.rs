@@ -0,0 +1,1304 @@
+//! TURN client, providing a relayed address as an additional direct address.
+//!
+//! When both nodes are behind NATs which map every destination to a different port,
+//! holepunching fails and traffic has to go through the relay server.  A TURN server
+//! ([RFC 5766]) nearby can provide a lower latency path: the client allocates a relayed
+//! address on the server, which is advertised to other nodes like any other direct
+//! address.  Datagrams nodes send to the relayed address are forwarded to us by the server
+//! in Data indications, we reply to them through the server using Send indications.
+//!
+//! The TURN server only forwards datagrams from IP addresses we created a permission
+//! for.  Permissions are created for the IP addresses we send holepunching pings to, which
+//! are the addresses the remote node advertised, if they are of the same address family as
+//! the relayed address.  Permissions of addresses no longer pinged expire.
+//!
+//! Datagrams from peers are passed on as received from a mapped address for each peer, so
+//! only the path through the TURN server uses it, not a direct path to the same address.
+//!
+//! [RFC 5766]: https://www.rfc-editor.org/rfc/rfc5766
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Result};
+use iroh_relay::protos::stun;
+use n0_future::time::{self, Duration, Instant};
+use stun_rs::{
+    attributes::{
+        stun::{MessageIntegrity, Nonce, Realm, UserName},
+        turn::{Data, LifeTime, RequestedAddressFamily, RequestedTrasport, XorPeerAddress},
+    },
+    methods, AddressFamily, Algorithm, AlgorithmId, HMACKey, MessageClass, MessageDecoder,
+    MessageEncoderBuilder, MessageMethod, StunAttribute, StunMessageBuilder, TransactionId,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace, warn};
+
+use super::MagicSock;
+use crate::{net_report::IpMappedAddr, watchable::Watchable};
+
+/// The allocation lifetime requested from the server.
+const ALLOCATION_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// The lifetime of a permission, fixed by RFC 5766.
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// How often permissions are refreshed, before they expire.
+const PERMISSION_REFRESH_INTERVAL: Duration = Duration::from_secs(4 * 60);
+
+/// The initial retransmission timeout of requests, doubled for each retransmission.
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+
+/// How often a request is sent before giving up.
+const MAX_ATTEMPTS: u32 = 7;
+
+/// How often a request is sent again with a new nonce after the server found it stale.
+///
+/// A server which keeps reporting the fresh nonce as stale is misbehaving, the request then
+/// fails like one rejected with any other error.
+const MAX_STALE_NONCE_RETRIES: u32 = 2;
+
+/// How long to wait before trying to allocate again after failing.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// The number of messages queued for the TURN actor.
+const QUEUE_SIZE: usize = 64;
+
+/// The error code of a request lacking valid credentials.
+const ERROR_UNAUTHORIZED: u16 = 401;
+
+/// The error code of a request using an expired nonce.
+const ERROR_STALE_NONCE: u16 = 438;
+
+/// The error code of an allocate request when there already is an allocation.
+const ERROR_ALLOCATION_MISMATCH: u16 = 437;
+
+/// The largest number of bytes a Send indication adds to a datagram.
+///
+/// The STUN header, an XOR-PEER-ADDRESS attribute with an IPv6 address and the header and
+/// padding of the DATA attribute.
+pub(crate) const SEND_OVERHEAD: u16 = 20 + 24 + 4 + 3;
+
+/// A TURN server to allocate a relayed address on.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TurnServer {
+    addr: SocketAddr,
+    username: String,
+    password: String,
+}
+
+impl TurnServer {
+    /// Creates a TURN server using the long-term credentials `username` and `password`.
+    pub fn new(addr: SocketAddr, username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            addr,
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Returns the address of the TURN server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the username used to authenticate.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+impl fmt::Debug for TurnServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TurnServer")
+            .field("addr", &self.addr)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The TURN client of the magicsock.
+///
+/// Shared between the magicsock, which forwards datagrams of the TURN server to it, and
+/// the [`TurnActor`] managing the allocation.
+#[derive(Debug)]
+pub(super) struct TurnClient {
+    server: TurnServer,
+    /// The relayed address of the allocation, if there is one.
+    relayed_addr: Watchable<Option<SocketAddr>>,
+    /// The IP addresses to keep permissions for, with when they were last pinged.
+    ///
+    /// The [`TurnActor`] refreshes the permissions every [`PERMISSION_REFRESH_INTERVAL`],
+    /// dropping those which were not pinged for the [`PERMISSION_LIFETIME`].
+    permissions: RwLock<HashMap<IpAddr, Instant>>,
+    /// The peers whose datagrams arrived through the allocation.
+    peers: RwLock<Peers>,
+    sender: mpsc::Sender<ActorMessage>,
+}
+
+/// The peers reached through the TURN server.
+///
+/// The datagrams of a peer are passed on as received from a mapped address, so the node
+/// map treats the path through the TURN server separately from a direct path to the same
+/// address.  Only datagrams sent to the mapped address go through the TURN server.
+#[derive(Debug, Default)]
+struct Peers {
+    by_addr: HashMap<SocketAddr, IpMappedAddr>,
+    by_mapped_addr: HashMap<IpMappedAddr, Peer>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    addr: SocketAddr,
+    /// When the last datagram of the peer arrived.
+    last_seen: Instant,
+}
+
+impl Peers {
+    /// Returns the mapped address of the peer at `addr`, marking it as seen.
+    fn seen(&mut self, addr: SocketAddr, now: Instant) -> SocketAddr {
+        let mapped_addr = *self
+            .by_addr
+            .entry(addr)
+            .or_insert_with(IpMappedAddr::generate);
+        self.by_mapped_addr
+            .entry(mapped_addr)
+            .and_modify(|peer| peer.last_seen = now)
+            .or_insert(Peer {
+                addr,
+                last_seen: now,
+            });
+        mapped_addr.private_socket_addr()
+    }
+
+    /// Removes the peers which were not seen for the [`PERMISSION_LIFETIME`], or whose
+    /// permission was dropped.
+    fn prune(&mut self, permissions: &HashMap<IpAddr, Instant>, now: Instant) {
+        self.by_mapped_addr.retain(|_, peer| {
+            now.duration_since(peer.last_seen) < PERMISSION_LIFETIME
+                && permissions.contains_key(&peer.addr.ip())
+        });
+        let by_mapped_addr = &self.by_mapped_addr;
+        self.by_addr
+            .retain(|_, mapped_addr| by_mapped_addr.contains_key(mapped_addr));
+    }
+
+    fn clear(&mut self) {
+        self.by_addr.clear();
+        self.by_mapped_addr.clear();
+    }
+}
+
+/// Messages to the [`TurnActor`].
+#[derive(Debug)]
+enum ActorMessage {
+    /// A STUN response from the TURN server.
+    Response(Vec<u8>),
+    /// Datagrams from this new IP address should be forwarded.
+    Permission(IpAddr),
+    /// The network changed, the allocation needs to be replaced.
+    Reset,
+}
+
+impl TurnClient {
+    /// Creates the client and the actor which needs to be run for it.
+    pub(super) fn new(server: TurnServer) -> (Arc<Self>, ActorRunner) {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let client = Arc::new(Self {
+            server,
+            relayed_addr: Default::default(),
+            permissions: Default::default(),
+            peers: Default::default(),
+            sender,
+        });
+        (client.clone(), ActorRunner { client, receiver })
+    }
+
+    /// Returns the address of the TURN server.
+    pub(super) fn server_addr(&self) -> SocketAddr {
+        self.server.addr
+    }
+
+    /// Returns the relayed address of the allocation, if there is one.
+    pub(super) fn relayed_addr(&self) -> Option<SocketAddr> {
+        self.relayed_addr.get()
+    }
+
+    /// Asks for datagrams from the IP address of `addr` to be forwarded to us.
+    ///
+    /// `addr` is an address we send a holepunching ping to, either a direct address or the
+    /// mapped address of a peer.  The relayed address has the address family of the
+    /// server, permissions can only be created for IP addresses of the same family.
+    /// Sending pings keeps the permission alive, the actor refreshes it on the server on a
+    /// timer.
+    pub(super) fn add_permission(&self, addr: SocketAddr) {
+        let ip = self.peer_addr(addr).unwrap_or(addr).ip();
+        if ip.is_ipv4() != self.server.addr.is_ipv4() {
+            return;
+        }
+        let new = self
+            .permissions
+            .write()
+            .expect("poisoned")
+            .insert(ip, Instant::now())
+            .is_none();
+        if new {
+            self.sender.try_send(ActorMessage::Permission(ip)).ok();
+        }
+    }
+
+    /// Replaces the allocation, after the network changed.
+    pub(super) fn reset(&self) {
+        self.sender.try_send(ActorMessage::Reset).ok();
+    }
+
+    /// Returns the address of the peer if `addr` is the mapped address of a peer.
+    ///
+    /// Datagrams to mapped addresses need to be sent through the TURN server.
+    pub(super) fn peer_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        let SocketAddr::V6(addr) = addr else {
+            return None;
+        };
+        let mapped_addr = IpMappedAddr::try_from(*addr.ip()).ok()?;
+        self.peers
+            .read()
+            .expect("poisoned")
+            .by_mapped_addr
+            .get(&mapped_addr)
+            .map(|peer| peer.addr)
+    }
+
+    /// Wraps a datagram to `peer` into a Send indication for the TURN server.
+    pub(super) fn wrap(&self, peer: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+        send_indication(peer, datagram)
+    }
+
+    /// Unwraps the datagrams received from the TURN server in place.
+    ///
+    /// Responses to requests are handed to the actor.  The data of Data indications is
+    /// moved to the start of `buf`, and `meta` updated to describe the datagrams as received
+    /// from the mapped address of the peer.  Returns `false` if no datagrams remain.
+    ///
+    /// Coalesced datagrams can only be passed on together, so only the datagrams from the
+    /// same peer with the same length as the first are kept, the others are dropped.
+    pub(super) fn unwrap_datagrams(&self, meta: &mut quinn_udp::RecvMeta, buf: &mut [u8]) -> bool {
+        let mut peer = None;
+        let mut stride = 0;
+        let mut len = 0;
+        let mut offset = 0;
+        while offset < meta.len {
+            let end = (offset + meta.stride).min(meta.len);
+            let datagram = &buf[offset..end];
+            offset = end;
+            if !stun::is(datagram) {
+                trace!(len = datagram.len(), "TURN: ignoring non-STUN datagram");
+                continue;
+            }
+            let Some((from, data)) = parse_data_indication(datagram) else {
+                self.sender
+                    .try_send(ActorMessage::Response(datagram.to_vec()))
+                    .ok();
+                continue;
+            };
+            match peer {
+                None => {
+                    peer = Some(from);
+                    stride = data.len();
+                }
+                Some(peer) if peer == from && data.len() <= stride && len % stride == 0 => {}
+                Some(_) => {
+                    trace!(%from, "TURN: dropping coalesced datagram");
+                    continue;
+                }
+            }
+            buf[len..len + data.len()].copy_from_slice(&data);
+            len += data.len();
+        }
+        let Some(peer) = peer else {
+            return false;
+        };
+        meta.addr = self
+            .peers
+            .write()
+            .expect("poisoned")
+            .seen(peer, Instant::now());
+        // The datagrams were sent to the TURN server, not to one of our local addresses.
+        meta.dst_ip = None;
+        meta.len = len;
+        meta.stride = stride;
+        len > 0
+    }
+}
+
+/// Runs the [`TurnActor`], created with the [`TurnClient`].
+#[derive(Debug)]
+pub(super) struct ActorRunner {
+    client: Arc<TurnClient>,
+    receiver: mpsc::Receiver<ActorMessage>,
+}
+
+impl ActorRunner {
+    pub(super) async fn run(self, msock: Arc<MagicSock>) {
+        let actor = TurnActor {
+            client: self.client,
+            msock,
+            auth: None,
+            allocated: false,
+            pending: HashMap::new(),
+            refresh_at: None,
+            permissions_refresh_at: None,
+            allocate_at: Some(Instant::now()),
+        };
+        actor.run(self.receiver).await
+    }
+}
+
+/// The realm and nonce the server asked us to authenticate with.
+#[derive(Debug, Clone)]
+struct Auth {
+    realm: String,
+    nonce: String,
+}
+
+/// The kind of a request sent to the TURN server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    /// Allocates a relayed address of the address family.
+    Allocate(AddressFamily),
+    Refresh {
+        lifetime: Duration,
+    },
+    CreatePermission(BTreeSet<IpAddr>),
+}
+
+/// A request waiting for its response.
+#[derive(Debug)]
+struct Pending {
+    request: Request,
+    /// Whether the request was sent with credentials.
+    authenticated: bool,
+    /// How often the request was sent again because of a stale nonce.
+    stale_nonce_retries: u32,
+    packet: Vec<u8>,
+    attempts: u32,
+    retransmit_at: Instant,
+}
+
+/// Manages the allocation on the TURN server.
+#[derive(Debug)]
+struct TurnActor {
+    client: Arc<TurnClient>,
+    msock: Arc<MagicSock>,
+    auth: Option<Auth>,
+    allocated: bool,
+    pending: HashMap<TransactionId, Pending>,
+    refresh_at: Option<Instant>,
+    permissions_refresh_at: Option<Instant>,
+    allocate_at: Option<Instant>,
+}
+
+impl TurnActor {
+    async fn run(mut self, mut receiver: mpsc::Receiver<ActorMessage>) {
+        debug!(server = %self.client.server.addr, "TURN client started");
+        loop {
+            let deadline = self.next_deadline();
+            let timer = async {
+                match deadline {
+                    Some(deadline) => time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                msg = receiver.recv() => {
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    match msg {
+                        ActorMessage::Response(packet) => self.handle_response(&packet),
+                        ActorMessage::Permission(ip) => self.handle_permission(ip),
+                        ActorMessage::Reset => {
+                            debug!("TURN: network changed, allocating again");
+                            self.reset(Instant::now());
+                        }
+                    }
+                }
+                _ = timer => self.handle_timers(),
+            }
+        }
+        debug!("TURN client stopped");
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| pending.retransmit_at)
+            .chain(self.allocate_at)
+            .chain(self.refresh_at)
+            .chain(self.permissions_refresh_at)
+            .min()
+    }
+
+    fn handle_timers(&mut self) {
+        let now = Instant::now();
+        if self.allocate_at.is_some_and(|at| at <= now) {
+            self.allocate_at = None;
+            let family = match self.client.server.addr {
+                SocketAddr::V4(_) => AddressFamily::IPv4,
+                SocketAddr::V6(_) => AddressFamily::IPv6,
+            };
+            self.send_request(Request::Allocate(family), now);
+        }
+        if self.refresh_at.is_some_and(|at| at <= now) {
+            self.refresh_at = None;
+            self.send_request(
+                Request::Refresh {
+                    lifetime: ALLOCATION_LIFETIME,
+                },
+                now,
+            );
+        }
+        if self.permissions_refresh_at.is_some_and(|at| at <= now) {
+            self.permissions_refresh_at = Some(now + PERMISSION_REFRESH_INTERVAL);
+            let ips = {
+                let mut permissions = self.client.permissions.write().expect("poisoned");
+                permissions.retain(|_, pinged| now.duration_since(*pinged) < PERMISSION_LIFETIME);
+                self.client
+                    .peers
+                    .write()
+                    .expect("poisoned")
+                    .prune(&permissions, now);
+                permissions.keys().copied().collect::<BTreeSet<_>>()
+            };
+            if !ips.is_empty() {
+                self.send_request(Request::CreatePermission(ips), now);
+            }
+        }
+
+        let mut failed = Vec::new();
+        for (tx, pending) in self.pending.iter_mut() {
+            if pending.retransmit_at > now {
+                continue;
+            }
+            if pending.attempts >= MAX_ATTEMPTS {
+                failed.push(*tx);
+                continue;
+            }
+            trace!(request = ?pending.request, attempt = pending.attempts, "TURN: retransmitting");
+            send(&self.msock, self.client.server.addr, &pending.packet);
+            pending.retransmit_at = now + INITIAL_RTO * 2u32.pow(pending.attempts);
+            pending.attempts += 1;
+        }
+        for tx in failed {
+            let pending = self.pending.remove(&tx).expect("just found");
+            warn!(request = ?pending.request, "TURN server did not respond");
+            if matches!(
+                pending.request,
+                Request::Allocate(_) | Request::Refresh { .. }
+            ) {
+                self.reset(now + RETRY_DELAY);
+            }
+        }
+    }
+
+    fn handle_permission(&mut self, ip: IpAddr) {
+        if self.allocated {
+            debug!(%ip, "TURN: creating permission");
+            self.send_request(
+                Request::CreatePermission(BTreeSet::from([ip])),
+                Instant::now(),
+            );
+        }
+    }
+
+    fn handle_response(&mut self, packet: &[u8]) {
+        let response = match parse_response(packet) {
+            Ok(response) => response,
+            Err(err) => {
+                debug!("TURN: invalid response: {err:#}");
+                return;
+            }
+        };
+        let Some(pending) = self.pending.remove(&response.tx) else {
+            trace!("TURN: response to unknown request");
+            return;
+        };
+        let now = Instant::now();
+        match response.result {
+            Ok(success) => self.handle_success(pending.request, success, now),
+            Err(error) => self.handle_error(pending, error, now),
+        }
+    }
+
+    fn handle_success(&mut self, request: Request, success: Success, now: Instant) {
+        match request {
+            Request::Allocate(_) => {
+                let Some(relayed_addr) = success.relayed_addr else {
+                    warn!("TURN server allocated no relayed address");
+                    self.reset(now + RETRY_DELAY);
+                    return;
+                };
+                let lifetime = success.lifetime.unwrap_or(ALLOCATION_LIFETIME);
+                info!(%relayed_addr, ?lifetime, "TURN allocation created");
+                self.allocated = true;
+                self.refresh_at = Some(now + lifetime / 2);
+                self.permissions_refresh_at = Some(now + PERMISSION_REFRESH_INTERVAL);
+                let ips: BTreeSet<_> = self
+                    .client
+                    .permissions
+                    .read()
+                    .expect("poisoned")
+                    .keys()
+                    .copied()
+                    .collect();
+                if !ips.is_empty() {
+                    self.send_request(Request::CreatePermission(ips), now);
+                }
+                if self.client.relayed_addr.set(Some(relayed_addr)).is_ok() {
+                    self.msock.re_stun("turn-allocated");
+                }
+            }
+            Request::Refresh { lifetime } if lifetime.is_zero() => {}
+            Request::Refresh { .. } => {
+                let lifetime = success.lifetime.unwrap_or(ALLOCATION_LIFETIME);
+                trace!(?lifetime, "TURN allocation refreshed");
+                self.refresh_at = Some(now + lifetime / 2);
+            }
+            Request::CreatePermission(ips) => {
+                trace!(?ips, "TURN permissions created");
+            }
+        }
+    }
+
+    fn handle_error(&mut self, pending: Pending, error: ErrorResponse, now: Instant) {
+        let Pending {
+            request,
+            authenticated,
+            stale_nonce_retries,
+            ..
+        } = pending;
+        match error.code {
+            ERROR_UNAUTHORIZED if !authenticated => {
+                self.update_auth(error);
+                self.send_request(request, now);
+            }
+            ERROR_STALE_NONCE if stale_nonce_retries < MAX_STALE_NONCE_RETRIES => {
+                self.update_auth(error);
+                self.send_request_retry(request, stale_nonce_retries + 1, now);
+            }
+            ERROR_UNAUTHORIZED => {
+                warn!(server = %self.client.server.addr, "TURN server rejected the credentials");
+                self.reset(now + RETRY_DELAY);
+            }
+            ERROR_ALLOCATION_MISMATCH if matches!(request, Request::Allocate(_)) => {
+                // A previous allocation from this address is still around, release it.
+                debug!("TURN: releasing stale allocation");
+                self.send_request(
+                    Request::Refresh {
+                        lifetime: Duration::ZERO,
+                    },
+                    now,
+                );
+                self.allocate_at = Some(now + INITIAL_RTO);
+            }
+            code => {
+                warn!(?request, code, reason = %error.reason, "TURN request failed");
+                match request {
+                    Request::Allocate(_) | Request::Refresh { .. } => self.reset(now + RETRY_DELAY),
+                    Request::CreatePermission(_) => {}
+                }
+            }
+        }
+    }
+
+    fn update_auth(&mut self, error: ErrorResponse) {
+        match (error.realm, error.nonce) {
+            (Some(realm), Some(nonce)) => self.auth = Some(Auth { realm, nonce }),
+            (None, Some(nonce)) => {
+                if let Some(ref mut auth) = self.auth {
+                    auth.nonce = nonce;
+                }
+            }
+            _ => debug!("TURN: error response without realm or nonce"),
+        }
+    }
+
+    /// Drops the allocation and schedules allocating a new one at `allocate_at`.
+    fn reset(&mut self, allocate_at: Instant) {
+        self.allocated = false;
+        self.pending.clear();
+        self.refresh_at = None;
+        self.permissions_refresh_at = None;
+        self.allocate_at = Some(allocate_at);
+        self.client.peers.write().expect("poisoned").clear();
+        if self.client.relayed_addr.set(None).is_ok() {
+            info!("TURN allocation lost");
+            self.msock.re_stun("turn-lost");
+        }
+    }
+
+    fn send_request(&mut self, request: Request, now: Instant) {
+        self.send_request_retry(request, 0, now);
+    }
+
+    /// Sends a request which was already sent `stale_nonce_retries` times with a stale nonce.
+    fn send_request_retry(&mut self, request: Request, stale_nonce_retries: u32, now: Instant) {
+        let tx = TransactionId::default();
+        let credentials = self.auth.as_ref().map(|auth| Credentials {
+            username: &self.client.server.username,
+            password: &self.client.server.password,
+            realm: &auth.realm,
+            nonce: &auth.nonce,
+        });
+        let packet = match encode_request(tx, &request, credentials) {
+            Ok(packet) => packet,
+            Err(err) => {
+                warn!("TURN: failed to encode request: {err:#}");
+                return;
+            }
+        };
+        trace!(?request, "TURN: sending request");
+        send(&self.msock, self.client.server.addr, &packet);
+        self.pending.insert(
+            tx,
+            Pending {
+                request,
+                authenticated: self.auth.is_some(),
+                stale_nonce_retries,
+                packet,
+                attempts: 1,
+                retransmit_at: now + INITIAL_RTO,
+            },
+        );
+    }
+}
+
+/// Sends a packet to the TURN server, dropping it if the socket is busy.
+fn send(msock: &MagicSock, server: SocketAddr, packet: &[u8]) {
+    if let Err(err) = msock.try_send_turn(server, packet) {
+        trace!("TURN: failed to send: {err:#}");
+    }
+}
+
+/// The long-term credentials to authenticate a request with.
+#[derive(Debug, Clone, Copy)]
+struct Credentials<'a> {
+    username: &'a str,
+    password: &'a str,
+    realm: &'a str,
+    nonce: &'a str,
+}
+
+/// Encodes a request to the TURN server.
+fn encode_request(
+    tx: TransactionId,
+    request: &Request,
+    credentials: Option<Credentials<'_>>,
+) -> Result<Vec<u8>> {
+    let method = match request {
+        Request::Allocate(_) => methods::ALLOCATE,
+        Request::Refresh { .. } => methods::REFRESH,
+        Request::CreatePermission(_) => methods::CREATE_PERMMISSION,
+    };
+    let mut msg = StunMessageBuilder::new(method, MessageClass::Request).with_transaction_id(tx);
+    match request {
+        Request::Allocate(family) => {
+            msg = msg
+                .with_attribute(RequestedTrasport::new(stun_rs::protocols::UDP))
+                .with_attribute(LifeTime::new(ALLOCATION_LIFETIME.as_secs() as u32));
+            // Servers allocate IPv4 relayed addresses unless asked otherwise.
+            if *family == AddressFamily::IPv6 {
+                msg = msg.with_attribute(RequestedAddressFamily::new(*family));
+            }
+        }
+        Request::Refresh { lifetime } => {
+            msg = msg.with_attribute(LifeTime::new(lifetime.as_secs() as u32));
+        }
+        Request::CreatePermission(ips) => {
+            for ip in ips {
+                msg = msg.with_attribute(XorPeerAddress::from(SocketAddr::new(*ip, 0)));
+            }
+        }
+    }
+    if let Some(credentials) = credentials {
+        let key = HMACKey::new_long_term(
+            credentials.username,
+            credentials.realm,
+            credentials.password,
+            Algorithm::from(AlgorithmId::MD5),
+        )
+        .map_err(|err| anyhow!("invalid credentials: {err:?}"))?;
+        msg = msg
+            .with_attribute(UserName::new(credentials.username).map_err(stun_error)?)
+            .with_attribute(Realm::new(credentials.realm).map_err(stun_error)?)
+            .with_attribute(Nonce::new(credentials.nonce).map_err(stun_error)?)
+            .with_attribute(MessageIntegrity::new(key));
+    }
+    encode(msg, 512)
+}
+
+/// Encodes a Send indication, wrapping a datagram to `peer`.
+fn send_indication(peer: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+    let msg = StunMessageBuilder::new(methods::SEND, MessageClass::Indication)
+        .with_attribute(XorPeerAddress::from(peer))
+        .with_attribute(Data::from(datagram));
+    encode(msg, datagram.len() + 64).expect("valid send indication")
+}
+
+fn encode(msg: StunMessageBuilder, capacity: usize) -> Result<Vec<u8>> {
+    let encoder = MessageEncoderBuilder::default().build();
+    let mut buffer = vec![0u8; capacity];
+    let size = encoder
+        .encode(&mut buffer, &msg.build())
+        .map_err(stun_error)?;
+    buffer.truncate(size);
+    Ok(buffer)
+}
+
+fn stun_error(err: impl fmt::Debug) -> anyhow::Error {
+    anyhow!("STUN encoding failed: {err:?}")
+}
+
+/// Parses a Data indication, returning the peer which sent the data and the data.
+fn parse_data_indication(packet: &[u8]) -> Option<(SocketAddr, Vec<u8>)> {
+    let (msg, _) = MessageDecoder::default().decode(packet).ok()?;
+    if msg.method() != methods::DATA || msg.class() != MessageClass::Indication {
+        return None;
+    }
+    let mut peer = None;
+    let mut data = None;
+    for attr in msg.attributes() {
+        match attr {
+            StunAttribute::XorPeerAddress(addr) => peer = Some(*addr.socket_address()),
+            StunAttribute::Data(d) => data = Some(d.as_bytes().to_vec()),
+            _ => {}
+        }
+    }
+    Some((peer?, data?))
+}
+
+/// A response from the TURN server.
+#[derive(Debug)]
+struct Response {
+    tx: TransactionId,
+    result: Result<Success, ErrorResponse>,
+}
+
+/// The attributes of a success response used by the client.
+#[derive(Debug, Default)]
+struct Success {
+    relayed_addr: Option<SocketAddr>,
+    lifetime: Option<Duration>,
+}
+
+/// The attributes of an error response used by the client.
+#[derive(Debug)]
+struct ErrorResponse {
+    code: u16,
+    reason: String,
+    realm: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Parses a success or error response to a request.
+fn parse_response(packet: &[u8]) -> Result<Response> {
+    let (msg, _) = MessageDecoder::default()
+        .decode(packet)
+        .map_err(|err| anyhow!("invalid STUN message: {err:?}"))?;
+    const TURN_METHODS: [MessageMethod; 3] = [
+        methods::ALLOCATE,
+        methods::REFRESH,
+        methods::CREATE_PERMMISSION,
+    ];
+    if !TURN_METHODS.contains(&msg.method()) {
+        bail!("unexpected method {:?}", msg.method());
+    }
+    let tx = *msg.transaction_id();
+    let result = match msg.class() {
+        MessageClass::SuccessResponse => {
+            let mut success = Success::default();
+            for attr in msg.attributes() {
+                match attr {
+                    StunAttribute::XorRelayedAddress(addr) => {
+                        success.relayed_addr = Some(*addr.socket_address())
+                    }
+                    StunAttribute::LifeTime(lifetime) => {
+                        success.lifetime = Some(Duration::from_secs(lifetime.as_u32().into()))
+                    }
+                    _ => {}
+                }
+            }
+            Ok(success)
+        }
+        MessageClass::ErrorResponse => {
+            let mut error = ErrorResponse {
+                code: 0,
+                reason: String::new(),
+                realm: None,
+                nonce: None,
+            };
+            for attr in msg.attributes() {
+                match attr {
+                    StunAttribute::ErrorCode(code) => {
+                        error.code = code.error_code().error_code();
+                        error.reason = code.error_code().reason().to_string();
+                    }
+                    StunAttribute::Realm(realm) => error.realm = Some(realm.as_str().to_string()),
+                    StunAttribute::Nonce(nonce) => error.nonce = Some(nonce.as_str().to_string()),
+                    _ => {}
+                }
+            }
+            Err(error)
+        }
+        class => bail!("unexpected class {class:?}"),
+    };
+    Ok(Response { tx, result })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::Context;
+    use n0_future::task::AbortOnDropHandle;
+    use stun_rs::{
+        attributes::turn::XorRelayedAddress, DecoderContextBuilder, MessageDecoderBuilder,
+    };
+    use testresult::TestResult;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::magicsock::{Handle, Options};
+
+    fn decode(packet: &[u8]) -> stun_rs::StunMessage {
+        MessageDecoder::default().decode(packet).unwrap().0
+    }
+
+    #[test]
+    fn test_encode_allocate() {
+        let tx = TransactionId::default();
+        let packet = encode_request(tx, &Request::Allocate(AddressFamily::IPv4), None).unwrap();
+        let msg = decode(&packet);
+        assert_eq!(msg.method(), methods::ALLOCATE);
+        assert_eq!(msg.class(), MessageClass::Request);
+        assert_eq!(*msg.transaction_id(), tx);
+        assert!(msg.get::<RequestedTrasport>().is_some());
+        assert!(msg.get::<RequestedAddressFamily>().is_none());
+        assert!(msg.get::<MessageIntegrity>().is_none());
+
+        let packet = encode_request(tx, &Request::Allocate(AddressFamily::IPv6), None).unwrap();
+        let msg = decode(&packet);
+        assert_eq!(
+            msg.get::<RequestedAddressFamily>()
+                .unwrap()
+                .as_requested_address_family()
+                .unwrap()
+                .family(),
+            AddressFamily::IPv6
+        );
+
+        let credentials = Credentials {
+            username: "user",
+            password: "pass",
+            realm: "example.org",
+            nonce: "abcd",
+        };
+        let packet = encode_request(
+            tx,
+            &Request::Allocate(AddressFamily::IPv4),
+            Some(credentials),
+        )
+        .unwrap();
+        let key = HMACKey::new_long_term(
+            "user",
+            "example.org",
+            "pass",
+            Algorithm::from(AlgorithmId::MD5),
+        )
+        .unwrap();
+        let ctx = DecoderContextBuilder::default()
+            .with_key(key)
+            .with_validation()
+            .build();
+        let decoder = MessageDecoderBuilder::default().with_context(ctx).build();
+        let (msg, _) = decoder.decode(&packet).unwrap();
+        assert_eq!(
+            msg.get::<UserName>()
+                .unwrap()
+                .as_user_name()
+                .unwrap()
+                .as_str(),
+            "user"
+        );
+        assert!(msg.get::<MessageIntegrity>().is_some());
+    }
+
+    #[test]
+    fn test_encode_create_permission() {
+        let ips = BTreeSet::from(["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()]);
+        let tx = TransactionId::default();
+        let packet = encode_request(tx, &Request::CreatePermission(ips.clone()), None).unwrap();
+        let msg = decode(&packet);
+        assert_eq!(msg.method(), methods::CREATE_PERMMISSION);
+        let peers: BTreeSet<IpAddr> = msg
+            .attributes()
+            .iter()
+            .filter_map(|attr| attr.as_xor_peer_address().ok())
+            .map(|addr| addr.socket_address().ip())
+            .collect();
+        assert_eq!(peers, ips);
+    }
+
+    #[test]
+    fn test_send_and_data_indications() {
+        let peer: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let datagram = b"hello turn";
+        let packet = send_indication(peer, datagram);
+        let msg = decode(&packet);
+        assert_eq!(msg.method(), methods::SEND);
+        assert_eq!(msg.class(), MessageClass::Indication);
+        // Only Data indications carry datagrams from peers.
+        assert!(parse_data_indication(&packet).is_none());
+
+        let msg = StunMessageBuilder::new(methods::DATA, MessageClass::Indication)
+            .with_attribute(XorPeerAddress::from(peer))
+            .with_attribute(Data::from(&datagram[..]));
+        let packet = encode(msg, 128).unwrap();
+        assert_eq!(
+            parse_data_indication(&packet),
+            Some((peer, datagram.to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let relayed: SocketAddr = "198.51.100.1:50000".parse().unwrap();
+        let tx = TransactionId::default();
+        let msg = StunMessageBuilder::new(methods::ALLOCATE, MessageClass::SuccessResponse)
+            .with_transaction_id(tx)
+            .with_attribute(XorRelayedAddress::from(relayed))
+            .with_attribute(LifeTime::new(600));
+        let response = parse_response(&encode(msg, 128).unwrap()).unwrap();
+        assert_eq!(response.tx, tx);
+        let success = response.result.unwrap();
+        assert_eq!(success.relayed_addr, Some(relayed));
+        assert_eq!(success.lifetime, Some(Duration::from_secs(600)));
+
+        let msg = StunMessageBuilder::new(methods::ALLOCATE, MessageClass::ErrorResponse)
+            .with_transaction_id(tx)
+            .with_attribute(stun_rs::attributes::stun::ErrorCode::new(
+                stun_rs::ErrorCode::new(401, "Unauthorized").unwrap(),
+            ))
+            .with_attribute(Realm::new("example.org").unwrap())
+            .with_attribute(Nonce::new("abcd").unwrap());
+        let response = parse_response(&encode(msg, 128).unwrap()).unwrap();
+        let error = response.result.unwrap_err();
+        assert_eq!(error.code, ERROR_UNAUTHORIZED);
+        assert_eq!(error.realm.as_deref(), Some("example.org"));
+        assert_eq!(error.nonce.as_deref(), Some("abcd"));
+
+        // Binding responses are handled by net reports.
+        let packet = stun::response(tx, relayed);
+        assert!(parse_response(&packet).is_err());
+    }
+
+    #[test]
+    fn test_unwrap_datagrams() {
+        let (client, _runner) = TurnClient::new(TurnServer::new(
+            "192.0.2.100:3478".parse().unwrap(),
+            "user",
+            "pass",
+        ));
+        let peer: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let data_indication = |data: &[u8]| {
+            let msg = StunMessageBuilder::new(methods::DATA, MessageClass::Indication)
+                .with_attribute(XorPeerAddress::from(peer))
+                .with_attribute(Data::from(data));
+            encode(msg, 128).unwrap()
+        };
+        let mut buf = data_indication(&[1; 8]);
+        let packet_len = buf.len();
+        buf.extend(data_indication(&[2; 8]));
+        let mut meta = quinn_udp::RecvMeta {
+            addr: client.server_addr(),
+            len: buf.len(),
+            stride: packet_len,
+            ecn: None,
+            dst_ip: None,
+        };
+        assert!(client.unwrap_datagrams(&mut meta, &mut buf));
+        assert_ne!(meta.addr, peer);
+        assert_eq!(meta.stride, 8);
+        assert_eq!(&buf[..meta.len], [[1; 8], [2; 8]].concat());
+        // Only the mapped address goes through the TURN server.
+        assert_eq!(client.peer_addr(meta.addr), Some(peer));
+        assert_eq!(client.peer_addr(peer), None);
+
+        // Peers are forgotten with their permission.
+        let mapped_addr = meta.addr;
+        let now = Instant::now();
+        client
+            .peers
+            .write()
+            .unwrap()
+            .prune(&HashMap::from([(peer.ip(), now)]), now);
+        assert_eq!(client.peer_addr(mapped_addr), Some(peer));
+        client.peers.write().unwrap().prune(&HashMap::new(), now);
+        assert_eq!(client.peer_addr(mapped_addr), None);
+    }
+
+    #[test]
+    fn test_permission_address_family() {
+        let (client, _runner) = TurnClient::new(TurnServer::new(
+            "192.0.2.100:3478".parse().unwrap(),
+            "user",
+            "pass",
+        ));
+        client.add_permission("192.0.2.1:4433".parse().unwrap());
+        client.add_permission("192.0.2.1:4434".parse().unwrap());
+        client.add_permission("[2001:db8::1]:4433".parse().unwrap());
+        let permissions = client.permissions.read().unwrap();
+        let ips: Vec<_> = permissions.keys().collect();
+        assert_eq!(ips, [&"192.0.2.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    /// A minimal TURN server, allocating a single relayed address on 127.0.0.1.
+    struct TestTurnServer {
+        addr: SocketAddr,
+        permissions: Arc<RwLock<BTreeSet<IpAddr>>>,
+        /// The number of requests received.
+        requests: Arc<AtomicUsize>,
+        _task: AbortOnDropHandle<()>,
+    }
+
+    impl TestTurnServer {
+        async fn spawn() -> Result<Self> {
+            Self::spawn_with(false).await
+        }
+
+        /// Spawns a server which, if `stale_nonce` is set, finds every nonce stale.
+        async fn spawn_with(stale_nonce: bool) -> Result<Self> {
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let addr = socket.local_addr()?;
+            let relayed_addr = relay.local_addr()?;
+            let permissions = Arc::new(RwLock::new(BTreeSet::new()));
+            let requests = Arc::new(AtomicUsize::new(0));
+            let task = tokio::spawn({
+                let permissions = permissions.clone();
+                let requests = requests.clone();
+                async move {
+                    let mut client = None;
+                    let mut buf = vec![0u8; 2048];
+                    let mut relay_buf = vec![0u8; 2048];
+                    loop {
+                        tokio::select! {
+                            res = socket.recv_from(&mut buf) => {
+                                let Ok((len, from)) = res else {
+                                    break;
+                                };
+                                let packet = &buf[..len];
+                                match Self::handle(packet, relayed_addr, &permissions, stale_nonce, &requests) {
+                                    Some(Reply::Response(response)) => {
+                                        client = Some(from);
+                                        socket.send_to(&response, from).await.ok();
+                                    }
+                                    Some(Reply::Relay(peer, data)) => {
+                                        relay.send_to(&data, peer).await.ok();
+                                    }
+                                    None => {}
+                                }
+                            }
+                            res = relay.recv_from(&mut relay_buf) => {
+                                let Ok((len, from)) = res else {
+                                    break;
+                                };
+                                let Some(client) = client else {
+                                    continue;
+                                };
+                                if !permissions.read().unwrap().contains(&from.ip()) {
+                                    continue;
+                                }
+                                let msg = StunMessageBuilder::new(methods::DATA, MessageClass::Indication)
+                                    .with_attribute(XorPeerAddress::from(from))
+                                    .with_attribute(Data::from(&relay_buf[..len]));
+                                let packet = encode(msg, len + 64).unwrap();
+                                socket.send_to(&packet, client).await.ok();
+                            }
+                        }
+                    }
+                }
+            });
+            Ok(Self {
+                addr,
+                permissions,
+                requests,
+                _task: AbortOnDropHandle::new(task),
+            })
+        }
+
+        /// Handles a packet from the client, asking for credentials first.
+        fn handle(
+            packet: &[u8],
+            relayed_addr: SocketAddr,
+            permissions: &RwLock<BTreeSet<IpAddr>>,
+            stale_nonce: bool,
+            requests: &AtomicUsize,
+        ) -> Option<Reply> {
+            let (msg, _) = MessageDecoder::default().decode(packet).ok()?;
+            if msg.method() == methods::SEND && msg.class() == MessageClass::Indication {
+                let peer = msg.get::<XorPeerAddress>()?.as_xor_peer_address().ok()?;
+                let data = msg.get::<Data>()?.as_data().ok()?;
+                return Some(Reply::Relay(
+                    *peer.socket_address(),
+                    data.as_bytes().to_vec(),
+                ));
+            }
+            if msg.class() != MessageClass::Request {
+                return None;
+            }
+            requests.fetch_add(1, Ordering::Relaxed);
+            let authenticated = msg.get::<MessageIntegrity>().is_some();
+            let response = if authenticated && stale_nonce {
+                StunMessageBuilder::new(msg.method(), MessageClass::ErrorResponse)
+                    .with_attribute(stun_rs::attributes::stun::ErrorCode::new(
+                        stun_rs::ErrorCode::new(ERROR_STALE_NONCE, "Stale Nonce").unwrap(),
+                    ))
+                    .with_attribute(Realm::new("example.org").unwrap())
+                    .with_attribute(Nonce::new("efgh").unwrap())
+            } else if !authenticated {
+                StunMessageBuilder::new(msg.method(), MessageClass::ErrorResponse)
+                    .with_attribute(stun_rs::attributes::stun::ErrorCode::new(
+                        stun_rs::ErrorCode::new(ERROR_UNAUTHORIZED, "Unauthorized").unwrap(),
+                    ))
+                    .with_attribute(Realm::new("example.org").unwrap())
+                    .with_attribute(Nonce::new("abcd").unwrap())
+            } else {
+                let response = StunMessageBuilder::new(msg.method(), MessageClass::SuccessResponse);
+                match msg.method() {
+                    methods::ALLOCATE => response
+                        .with_attribute(XorRelayedAddress::from(relayed_addr))
+                        .with_attribute(LifeTime::new(600)),
+                    methods::REFRESH => response.with_attribute(LifeTime::new(600)),
+                    methods::CREATE_PERMMISSION => {
+                        permissions.write().unwrap().extend(
+                            msg.attributes()
+                                .iter()
+                                .filter_map(|attr| attr.as_xor_peer_address().ok())
+                                .map(|addr| addr.socket_address().ip()),
+                        );
+                        response
+                    }
+                    _ => return None,
+                }
+            };
+            let response = response.with_transaction_id(*msg.transaction_id());
+            Some(Reply::Response(encode(response, 256).unwrap()))
+        }
+    }
+
+    /// What the [`TestTurnServer`] does with a packet from the client.
+    enum Reply {
+        /// Sends the response to the client.
+        Response(Vec<u8>),
+        /// Sends the data of a Send indication to the peer.
+        Relay(SocketAddr, Vec<u8>),
+    }
+
+    /// Polls `f` until it returns `Some`.
+    async fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> Result<T> {
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(value) = f() {
+                    return value;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("timeout"))
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_turn_server() -> TestResult {
+        let server = TestTurnServer::spawn().await?;
+        let msock = Handle::new(Options {
+            turn_server: Some(TurnServer::new(server.addr, "user", "pass")),
+            ..Default::default()
+        })
+        .await?;
+        let turn = msock.turn.clone().context("no TURN client")?;
+        let relayed_addr = wait_for(|| turn.relayed_addr()).await?;
+
+        // Datagrams are only forwarded once there is a permission.
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let peer_addr = peer.local_addr()?;
+        peer.send_to(b"before", relayed_addr).await?;
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(turn.peers.read().unwrap().by_addr.is_empty());
+
+        turn.add_permission(peer_addr);
+        wait_for(|| {
+            server
+                .permissions
+                .read()
+                .unwrap()
+                .contains(&peer_addr.ip())
+                .then_some(())
+        })
+        .await?;
+        assert_eq!(turn.permissions.read().unwrap().len(), 1);
+
+        let mapped_addr = wait_for(|| {
+            // Datagrams might be lost, even on loopback.
+            peer.try_send_to(b"after", relayed_addr).ok();
+            turn.peers.read().unwrap().by_addr.get(&peer_addr).copied()
+        })
+        .await?
+        .private_socket_addr();
+        assert_eq!(turn.peer_addr(mapped_addr), Some(peer_addr));
+
+        // Only datagrams to the mapped address are sent through the TURN server.
+        let transmit = quinn_udp::Transmit {
+            destination: mapped_addr,
+            ecn: None,
+            contents: b"reply",
+            segment_size: None,
+            src_ip: None,
+        };
+        msock.try_send_udp(mapped_addr, &transmit)?;
+        let mut buf = [0u8; 64];
+        let (len, from) = time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf)).await??;
+        assert_eq!(&buf[..len], b"reply");
+        assert_eq!(from, relayed_addr);
+
+        msock.close(0u16.into(), b"").await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_turn_server_stale_nonce() -> TestResult {
+        let server = TestTurnServer::spawn_with(true).await?;
+        let msock = Handle::new(Options {
+            turn_server: Some(TurnServer::new(server.addr, "user", "pass")),
+            ..Default::default()
+        })
+        .await?;
+
+        // The unauthenticated request, the authenticated one and the capped retries with a
+        // new nonce.  Then the client waits for the retry delay.
+        let expected = 2 + MAX_STALE_NONCE_RETRIES as usize;
+        wait_for(|| (server.requests.load(Ordering::Relaxed) >= expected).then_some(())).await?;
+        time::sleep(INITIAL_RTO * 2).await;
+        assert_eq!(server.requests.load(Ordering::Relaxed), expected);
+        assert!(msock.turn.as_ref().unwrap().relayed_addr().is_none());
+
+        msock.close(0u16.into(), b"").await;
+        Ok(())
+    }
+}
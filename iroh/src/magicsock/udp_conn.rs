@@ -1,29 +1,103 @@
 use std::{
     fmt::Debug,
+    future::Future,
     io,
     net::SocketAddr,
     pin::Pin,
-    sync::Arc,
-    task::{Context, Poll},
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
+    task::{ready, Context, Poll},
 };
 
+use iroh_metrics::inc;
+use n0_future::time::{self, Duration, Sleep};
 use netwatch::UdpSocket;
 use quinn::AsyncUdpSocket;
-use quinn_udp::Transmit;
+use quinn_udp::{RecvMeta, Transmit, BATCH_SIZE};
+use tracing::{trace, warn};
+
+use super::metrics::Metrics as MagicsockMetrics;
+
+/// The number of consecutive transient receive errors retried without delay.
+const IMMEDIATE_RECV_RETRIES: u32 = 8;
+/// The maximum delay before receiving again after consecutive transient errors.
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Limits on how many datagrams are received from a [`UdpConn`] at once.
+///
+/// Quinn allocates a receive buffer of the maximum UDP payload size, times the number of
+/// GRO segments, times [`BATCH_SIZE`].  Lowering the GRO segments shrinks this buffer at
+/// the cost of throughput.  The batch size does not, the buffers for a full batch are
+/// allocated regardless, it only limits how many of them are filled at once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RecvLimits {
+    /// The maximum number of datagrams received with a single system call.
+    ///
+    /// If set to `None`, or larger than [`BATCH_SIZE`], [`BATCH_SIZE`] is used.
+    pub(crate) batch_size: Option<usize>,
+    /// The maximum number of GRO segments per receive buffer.
+    ///
+    /// If set to `None`, or larger than the socket supports, the number of segments the
+    /// socket supports is used.
+    pub(crate) gro_segments: Option<usize>,
+}
 
 /// Wrapper struct to implement Quinn's [`AsyncUdpSocket`] for [`UdpSocket`].
+///
+/// The socket can be replaced, e.g. by one bound to a different port, the clones of a
+/// [`UdpConn`] and their pollers all switch to the new socket.
 #[derive(Debug, Clone)]
 pub(super) struct UdpConn {
-    inner: Arc<UdpSocket>,
+    inner: Arc<RwLock<Arc<UdpSocket>>>,
+    limits: RecvLimits,
+    /// Datagrams with more GRO segments than fit in Quinn's receive buffers.
+    gro_split: Arc<Mutex<GroSplit>>,
+    /// Delays receiving after repeated transient errors.
+    recv_backoff: Arc<Mutex<RecvBackoff>>,
+}
+
+/// The consecutive transient receive errors, and the delay before receiving again.
+#[derive(Debug, Default)]
+struct RecvBackoff {
+    errors: u32,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl RecvBackoff {
+    /// Returns the delay before receiving again after another transient error, if any.
+    ///
+    /// The first few errors are retried right away, after that the delay doubles with each
+    /// error, up to [`MAX_RECV_BACKOFF`].
+    fn on_error(&mut self) -> Option<Duration> {
+        self.errors = self.errors.saturating_add(1);
+        let exp = self.errors.checked_sub(IMMEDIATE_RECV_RETRIES + 1)?;
+        let delay = Duration::from_millis(1)
+            .checked_mul(1 << exp.min(16))
+            .unwrap_or(MAX_RECV_BACKOFF);
+        Some(delay.min(MAX_RECV_BACKOFF))
+    }
 }
 
 impl UdpConn {
-    pub(super) fn wrap(inner: Arc<UdpSocket>) -> Self {
-        Self { inner }
+    pub(super) fn wrap(inner: Arc<UdpSocket>, limits: RecvLimits) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(inner)),
+            limits,
+            gro_split: Default::default(),
+            recv_backoff: Default::default(),
+        }
+    }
+
+    /// Returns the current socket.
+    pub(super) fn socket(&self) -> RwLockReadGuard<'_, Arc<UdpSocket>> {
+        self.inner.read().expect("poisoned")
     }
 
-    pub(super) fn as_socket_ref(&self) -> &UdpSocket {
-        &self.inner
+    /// Replaces the socket, returning the previous one.
+    ///
+    /// The previous socket should be closed, which wakes up any tasks still waiting for it
+    /// so they poll the new socket.
+    pub(super) fn replace(&self, socket: Arc<UdpSocket>) -> Arc<UdpSocket> {
+        std::mem::replace(&mut *self.inner.write().expect("poisoned"), socket)
     }
 
     pub(super) fn create_io_poller(&self) -> Pin<Box<dyn quinn::UdpPoller>> {
@@ -31,6 +105,160 @@ impl UdpConn {
             io: self.inner.clone(),
         })
     }
+
+    /// Sends a transmit, coalescing its segments into a single GSO send when possible.
+    ///
+    /// Quinn only builds segmented transmits while [`AsyncUdpSocket::max_transmit_segments`]
+    /// reports more than one segment.  However the socket may turn off segmentation offload
+    /// at any time, e.g. when the network driver rejects a `UDP_SEGMENT` send with `EIO`,
+    /// or after a rebind onto an interface without GSO support.  Transmits already in the
+    /// pipeline at that point are split and sent one datagram at a time instead.
+    fn try_send_batched(&self, transmit: &Transmit<'_>) -> io::Result<()> {
+        let Some(segment_size) = transmit.segment_size else {
+            return self.socket().try_send_quinn(transmit);
+        };
+        if transmit.contents.len() <= segment_size {
+            return self.socket().try_send_quinn(transmit);
+        }
+        if self.socket().max_gso_segments() > 1 {
+            match self.socket().try_send_quinn(transmit) {
+                Ok(()) => {
+                    inc!(MagicsockMetrics, send_gso_datagrams);
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Err(err),
+                Err(err) if self.socket().max_gso_segments() > 1 => return Err(err),
+                Err(err) => {
+                    trace!("GSO send failed, falling back to individual datagrams: {err:#}");
+                }
+            }
+        }
+        inc!(MagicsockMetrics, send_gso_fallback);
+        self.try_send_segments(transmit, segment_size)
+    }
+
+    /// Receives datagrams from the socket, applying the [`RecvLimits`].
+    fn poll_recv_limited(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let batch_size = self.limits.batch_size.unwrap_or(BATCH_SIZE).max(1);
+        let n = bufs.len().min(batch_size);
+        let (bufs, meta) = (&mut bufs[..n], &mut meta[..n]);
+
+        let socket_gro_segments = self.socket().gro_segments();
+        let gro_segments = self.max_receive_segments();
+        if gro_segments >= socket_gro_segments {
+            return self.socket().poll_recv_quinn(cx, bufs, meta);
+        }
+
+        // The buffers are too small for the datagrams the kernel may coalesce, receive into
+        // a buffer which is large enough and split the segments over the buffers instead.
+        let mut split = self.gro_split.lock().expect("poisoned");
+        if split.is_empty() {
+            let buf_len = bufs.first().map_or(0, |buf| buf.len()) / gro_segments;
+            split.buf.resize(buf_len * socket_gro_segments, 0);
+            let GroSplit {
+                buf,
+                meta: split_meta,
+                offset,
+            } = &mut *split;
+            let mut split_bufs = [io::IoSliceMut::new(buf)];
+            let mut split_metas = [RecvMeta::default()];
+            match self
+                .socket()
+                .poll_recv_quinn(cx, &mut split_bufs, &mut split_metas)?
+            {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(0) => return Poll::Ready(Ok(0)),
+                Poll::Ready(_) => {
+                    *split_meta = split_metas[0];
+                    *offset = 0;
+                }
+            }
+        }
+        Poll::Ready(Ok(split.fill(bufs, meta)))
+    }
+
+    /// Sends each segment of a GSO transmit as an individual datagram.
+    ///
+    /// If the socket is not writable before the first segment went out the transmit is
+    /// reported as blocked, so quinn will retry it as a whole.  Once some segments are sent
+    /// any remaining ones are dropped on error: QUIC's loss recovery will take care of them
+    /// and retrying the entire transmit would duplicate the segments already sent.
+    fn try_send_segments(&self, transmit: &Transmit<'_>, segment_size: usize) -> io::Result<()> {
+        for (i, datagram) in split_segments(transmit, segment_size).enumerate() {
+            if let Err(err) = self.socket().try_send_quinn(&datagram) {
+                if i == 0 {
+                    return Err(err);
+                }
+                trace!(
+                    sent = i,
+                    "dropping remaining segments of GSO transmit: {err:#}"
+                );
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A received datagram whose GRO segments are split over multiple receive buffers.
+#[derive(Debug, Default)]
+struct GroSplit {
+    buf: Vec<u8>,
+    meta: RecvMeta,
+    /// The number of bytes already copied out of `buf`.
+    offset: usize,
+}
+
+impl GroSplit {
+    fn is_empty(&self) -> bool {
+        self.offset >= self.meta.len
+    }
+
+    /// Copies as many segments as fit into `bufs`, returning the number of buffers filled.
+    fn fill(&mut self, bufs: &mut [io::IoSliceMut<'_>], meta: &mut [RecvMeta]) -> usize {
+        let stride = self.meta.stride.max(1);
+        let mut n = 0;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()) {
+            if self.is_empty() {
+                break;
+            }
+            // Whole segments only, unless a single segment does not even fit.
+            let segments = (buf.len() / stride).max(1);
+            let len = (segments * stride)
+                .min(self.meta.len - self.offset)
+                .min(buf.len());
+            buf[..len].copy_from_slice(&self.buf[self.offset..self.offset + len]);
+            *meta = RecvMeta { len, ..self.meta };
+            self.offset += (segments * stride).min(self.meta.len - self.offset);
+            n += 1;
+        }
+        n
+    }
+}
+
+/// Splits a GSO transmit into one [`Transmit`] per segment.
+fn split_segments<'a>(
+    transmit: &Transmit<'a>,
+    segment_size: usize,
+) -> impl Iterator<Item = Transmit<'a>> + 'a {
+    let destination = transmit.destination;
+    let ecn = transmit.ecn;
+    let src_ip = transmit.src_ip;
+    transmit
+        .contents
+        .chunks(segment_size)
+        .map(move |contents| Transmit {
+            destination,
+            ecn,
+            contents,
+            segment_size: None,
+            src_ip,
+        })
 }
 
 impl AsyncUdpSocket for UdpConn {
@@ -39,43 +267,287 @@ impl AsyncUdpSocket for UdpConn {
     }
 
     fn try_send(&self, transmit: &Transmit<'_>) -> io::Result<()> {
-        self.inner.try_send_quinn(transmit)
+        self.try_send_batched(transmit)
     }
 
+    /// Receives datagrams, recovering from receive errors where possible.
+    ///
+    /// Quinn shuts down the endpoint on any error returned here, except for
+    /// [`io::ErrorKind::ConnectionReset`].  So transient errors are only counted, and
+    /// receiving is retried with an increasing delay while they persist.  Fatal errors
+    /// trigger a rebind of the socket, the error is only returned if the socket was closed
+    /// or the rebind fails.
     fn poll_recv(
         &self,
         cx: &mut Context,
         bufs: &mut [io::IoSliceMut<'_>],
-        meta: &mut [quinn_udp::RecvMeta],
+        meta: &mut [RecvMeta],
     ) -> Poll<io::Result<usize>> {
-        self.inner.poll_recv_quinn(cx, bufs, meta)
+        let mut backoff = self.recv_backoff.lock().expect("poisoned");
+        if let Some(timer) = backoff.timer.as_mut() {
+            ready!(timer.as_mut().poll(cx));
+            backoff.timer = None;
+        }
+        let socket = self.socket().clone();
+        let err = match self.poll_recv_limited(cx, bufs, meta) {
+            Poll::Ready(Err(err)) => err,
+            Poll::Ready(Ok(n)) => {
+                backoff.errors = 0;
+                return Poll::Ready(Ok(n));
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        if !Arc::ptr_eq(&socket, &self.socket()) {
+            // The socket was replaced while receiving, the error is from the previous one.
+            trace!("UDP recv error on replaced socket: {err:#}");
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        match RecvErrorKind::classify(&err) {
+            RecvErrorKind::Transient => {
+                inc!(MagicsockMetrics, recv_errors_transient);
+                trace!("transient UDP recv error: {err:#}");
+                if let Some(delay) = backoff.on_error() {
+                    let mut timer = Box::pin(time::sleep(delay));
+                    if timer.as_mut().poll(cx).is_pending() {
+                        backoff.timer = Some(timer);
+                        return Poll::Pending;
+                    }
+                }
+            }
+            RecvErrorKind::Fatal => {
+                inc!(MagicsockMetrics, recv_errors_fatal);
+                if socket.is_closed() {
+                    return Poll::Ready(Err(err));
+                }
+                warn!("fatal UDP recv error, rebinding socket: {err:#}");
+                if let Err(rebind_err) = socket.rebind() {
+                    warn!("failed to rebind socket: {rebind_err:#}");
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+        // Try again on the next poll instead of looping here, the error might persist.
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.inner.local_addr()
+        self.socket().local_addr()
     }
 
     fn may_fragment(&self) -> bool {
-        self.inner.may_fragment()
+        self.socket().may_fragment()
     }
 
     fn max_transmit_segments(&self) -> usize {
-        self.inner.max_gso_segments()
+        self.socket().max_gso_segments()
     }
 
     fn max_receive_segments(&self) -> usize {
-        self.inner.gro_segments()
+        let gro_segments = self.socket().gro_segments();
+        self.limits
+            .gro_segments
+            .map_or(gro_segments, |limit| limit.clamp(1, gro_segments))
+    }
+}
+
+/// Classification of the errors returned when receiving from a socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecvErrorKind {
+    /// The error only affects a single receive, the socket remains usable.
+    ///
+    /// E.g. an ICMP port unreachable message for an earlier send, reported as
+    /// `ECONNREFUSED` on Linux, an interrupted system call or a lack of buffer space.
+    Transient,
+    /// The socket is no longer usable, e.g. its file descriptor became invalid or it was
+    /// closed.
+    Fatal,
+}
+
+/// The OS errors after which a socket is no longer usable.
+#[cfg(unix)]
+const FATAL_OS_ERRORS: &[i32] = &[libc::EBADF, libc::ENOTSOCK, libc::ENOTCONN];
+/// The OS errors after which a socket is no longer usable.
+///
+/// These are `WSAEBADF`, `WSAENOTSOCK` and `WSAENOTCONN`.
+#[cfg(windows)]
+const FATAL_OS_ERRORS: &[i32] = &[10009, 10038, 10057];
+#[cfg(not(any(unix, windows)))]
+const FATAL_OS_ERRORS: &[i32] = &[];
+
+impl RecvErrorKind {
+    /// Classifies a receive error.
+    ///
+    /// Only the errors known to leave the socket unusable are fatal, rebinding for any
+    /// other error would needlessly interrupt the connections.
+    fn classify(err: &io::Error) -> Self {
+        match err.raw_os_error() {
+            Some(code) if FATAL_OS_ERRORS.contains(&code) => Self::Fatal,
+            _ if matches!(
+                err.kind(),
+                io::ErrorKind::NotConnected | io::ErrorKind::BrokenPipe
+            ) =>
+            {
+                Self::Fatal
+            }
+            _ => Self::Transient,
+        }
     }
 }
 
 /// Poller for when the socket is writable.
 #[derive(Debug)]
 struct IoPoller {
-    io: Arc<UdpSocket>,
+    io: Arc<RwLock<Arc<UdpSocket>>>,
 }
 
 impl quinn::UdpPoller for IoPoller {
     fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
-        self.io.poll_writable(cx)
+        self.io.read().expect("poisoned").poll_writable(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_segments() {
+        let transmit = Transmit {
+            destination: "127.0.0.1:1234".parse().unwrap(),
+            ecn: None,
+            contents: b"hello world",
+            segment_size: Some(5),
+            src_ip: None,
+        };
+        let parts: Vec<_> = split_segments(&transmit, 5).collect();
+        let contents: Vec<&[u8]> = parts.iter().map(|t| t.contents).collect();
+        assert_eq!(contents, vec![&b"hello"[..], b" worl", b"d"]);
+        assert!(parts.iter().all(|t| t.segment_size.is_none()));
+        assert!(parts.iter().all(|t| t.destination == transmit.destination));
+    }
+
+    #[test]
+    fn test_gro_split() {
+        let data: Vec<u8> = (0..25).collect();
+        let mut split = GroSplit {
+            buf: data.clone(),
+            meta: RecvMeta {
+                addr: "127.0.0.1:1234".parse().unwrap(),
+                len: data.len(),
+                stride: 10,
+                ecn: None,
+                dst_ip: None,
+            },
+            offset: 0,
+        };
+
+        // Only whole segments are copied into each buffer.
+        let mut storage = [[0u8; 25]; 2];
+        let [a, b] = &mut storage;
+        let mut bufs = [io::IoSliceMut::new(&mut a[..15]), io::IoSliceMut::new(b)];
+        let mut metas = [RecvMeta::default(); 2];
+        assert_eq!(split.fill(&mut bufs, &mut metas), 2);
+        assert_eq!(metas[0].len, 10);
+        assert_eq!(metas[1].len, 15);
+        assert!(metas.iter().all(|meta| meta.stride == 10));
+        assert_eq!(&storage[0][..10], &data[..10]);
+        assert_eq!(&storage[1][..15], &data[10..]);
+        assert!(split.is_empty());
+
+        // Segments which do not fit remain for the next call.
+        split.offset = 0;
+        let mut buf = [0u8; 10];
+        let mut bufs = [io::IoSliceMut::new(&mut buf)];
+        let mut metas = [RecvMeta::default()];
+        assert_eq!(split.fill(&mut bufs, &mut metas), 1);
+        assert_eq!(metas[0].len, 10);
+        assert!(!split.is_empty());
+        assert_eq!(split.fill(&mut bufs, &mut metas), 1);
+        assert_eq!(split.fill(&mut bufs, &mut metas), 1);
+        assert_eq!(metas[0].len, 5);
+        assert_eq!(&buf[..5], &data[20..]);
+        assert!(split.is_empty());
+    }
+
+    #[test]
+    fn test_recv_error_kind() {
+        let transient = [
+            io::ErrorKind::ConnectionRefused,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::Interrupted,
+            io::ErrorKind::InvalidInput,
+            io::ErrorKind::Other,
+        ];
+        for kind in transient {
+            assert_eq!(
+                RecvErrorKind::classify(&io::Error::from(kind)),
+                RecvErrorKind::Transient,
+                "{kind:?}"
+            );
+        }
+        let fatal = [io::ErrorKind::NotConnected, io::ErrorKind::BrokenPipe];
+        for kind in fatal {
+            assert_eq!(
+                RecvErrorKind::classify(&io::Error::from(kind)),
+                RecvErrorKind::Fatal,
+                "{kind:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_recv_backoff() {
+        let mut backoff = RecvBackoff::default();
+        for _ in 0..IMMEDIATE_RECV_RETRIES {
+            assert_eq!(backoff.on_error(), None);
+        }
+        assert_eq!(backoff.on_error(), Some(Duration::from_millis(1)));
+        assert_eq!(backoff.on_error(), Some(Duration::from_millis(2)));
+        for _ in 0..100 {
+            backoff.on_error();
+        }
+        assert_eq!(backoff.on_error(), Some(MAX_RECV_BACKOFF));
+    }
+
+    #[tokio::test]
+    async fn test_recv_closed_socket() {
+        let socket = Arc::new(UdpSocket::bind_local_v4(0).unwrap());
+        let conn = UdpConn::wrap(socket.clone(), RecvLimits::default());
+        socket.close().await;
+
+        let mut buf = [0u8; 1500];
+        let mut meta = [RecvMeta::default()];
+        let res = std::future::poll_fn(|cx| {
+            let mut bufs = [io::IoSliceMut::new(&mut buf)];
+            conn.poll_recv(cx, &mut bufs, &mut meta)
+        });
+        let err = time::timeout(Duration::from_secs(1), res)
+            .await
+            .expect("closed socket returns an error")
+            .expect_err("socket is closed");
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_recv_error_kind_os() {
+        // Running out of buffer space is temporary, the socket remains usable.
+        assert_eq!(
+            RecvErrorKind::classify(&io::Error::from_raw_os_error(libc::ENOBUFS)),
+            RecvErrorKind::Transient,
+        );
+        assert_eq!(
+            RecvErrorKind::classify(&io::Error::from_raw_os_error(libc::EHOSTUNREACH)),
+            RecvErrorKind::Transient,
+        );
+        for code in [libc::EBADF, libc::ENOTSOCK, libc::ENOTCONN] {
+            assert_eq!(
+                RecvErrorKind::classify(&io::Error::from_raw_os_error(code)),
+                RecvErrorKind::Fatal,
+                "{code}"
+            );
+        }
     }
 }
@@ -13,12 +13,16 @@
 use std::{
     collections::{BTreeMap, HashMap},
     fmt::{self, Debug},
-    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
-    sync::Arc,
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
+use concurrent_queue::ConcurrentQueue;
 use iroh_base::RelayUrl;
 #[cfg(feature = "metrics")]
 use iroh_metrics::inc;
@@ -31,7 +35,7 @@ use n0_future::{
 };
 #[cfg(not(wasm_browser))]
 use netwatch::UdpSocket;
-use tokio::sync::{self, mpsc, oneshot};
+use tokio::sync::{self, mpsc, oneshot, Notify};
 use tracing::{debug, error, info_span, trace, warn, Instrument};
 
 mod defaults;
@@ -39,6 +43,7 @@ mod defaults;
 mod dns;
 mod ip_mapped_addrs;
 mod metrics;
+mod nat64;
 #[cfg(not(wasm_browser))]
 mod ping;
 mod reportgen;
@@ -69,12 +74,19 @@ pub(crate) mod portmapper {
 
 pub(crate) use ip_mapped_addrs::{IpMappedAddr, IpMappedAddresses};
 pub use metrics::Metrics;
+pub use nat64::Nat64Prefix;
 pub use options::Options;
+#[cfg(not(wasm_browser))]
+pub use options::{StunServer, StunServerParseError, StunServers};
+pub(crate) use reportgen::ProbeLimits;
 pub use reportgen::QuicConfig;
 #[cfg(not(wasm_browser))]
 use reportgen::SocketState;
 
-const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+pub(crate) const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How old the previous report may be for a fast report to reuse its results.
+const FAST_REPORT_MAX_AGE: Duration = Duration::from_secs(60);
 
 /// The maximum latency of all nodes, if none are found yet.
 ///
@@ -85,10 +97,18 @@ const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
 /// default which will never be used.
 const DEFAULT_MAX_LATENCY: Duration = Duration::from_millis(100);
 
+/// The largest gap between observed public ports still considered sequential allocation.
+///
+/// Other traffic from behind the same NAT can allocate ports between our probes, so the
+/// observed ports are rarely adjacent.
+const MAX_SEQUENTIAL_PORT_GAP: u16 = 16;
+
 /// A net_report report.
 ///
-/// Can be obtained by calling [`Client::get_report`].
+/// Can be obtained by calling [`Client::get_report`].  New fields may be added to the
+/// report, it can not be constructed outside of this crate.
 #[derive(Default, Debug, PartialEq, Eq, Clone)]
+#[non_exhaustive]
 pub struct Report {
     /// A UDP STUN round trip completed.
     pub udp: bool,
@@ -116,23 +136,45 @@ pub struct Report {
     /// Whether the router supports communicating between two local devices through the NATted
     /// public IP address (on IPv4).
     pub hair_pinning: Option<bool>,
+    /// How the NAT filters incoming IPv4 packets, `None` if not checked.
+    ///
+    /// Only checked against a custom STUN server supporting [RFC 5780], see [`NatFiltering`].
+    /// The check only runs for full reports, other reports carry over its result.
+    ///
+    /// [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780#section-4.4
+    pub nat_filtering: Option<NatFiltering>,
     /// Probe indicating the presence of port mapping protocols on the LAN.
     pub portmap_probe: Option<portmapper::ProbeOutput>,
-    /// `None` for unknown
+    /// The relay server this node prefers to be reached at, `None` for unknown.
     pub preferred_relay: Option<RelayUrl>,
-    /// keyed by relay Url
+    /// Why the [`Report::preferred_relay`] was chosen, `None` if there is no preferred relay.
+    pub preferred_relay_reason: Option<PreferredRelayReason>,
+    /// The lowest latency to each relay server, over IPv4 or IPv6.
     pub relay_latency: RelayLatencies,
-    /// keyed by relay Url
+    /// The latency to each relay server over IPv4.
     pub relay_v4_latency: RelayLatencies,
-    /// keyed by relay Url
+    /// The latency to each relay server over IPv6.
     pub relay_v6_latency: RelayLatencies,
     /// ip:port of global IPv4
     pub global_v4: Option<SocketAddrV4>,
     /// `[ip]:port` of global IPv6
     pub global_v6: Option<SocketAddrV6>,
+    /// All public addresses observed by the relay and STUN servers, in the order they were
+    /// observed.
+    ///
+    /// [`Report::global_v4`] and [`Report::global_v6`] are the first ones of each address
+    /// family.  Behind a NAT whose mapping varies by destination each server can observe a
+    /// different address.
+    pub public_addrs: Vec<PublicAddr>,
     /// CaptivePortal is set when we think there's a captive portal that is
     /// intercepting HTTP traffic.
     pub captive_portal: Option<bool>,
+    /// The NAT64 prefix of the network, discovered using DNS64.
+    ///
+    /// Only looked for on IPv6-only hosts, IPv4 relay servers are then probed through the
+    /// NAT64 gateway.  The prefix is only looked up again once the addresses of the local
+    /// interfaces change.
+    pub nat64_prefix: Option<Nat64Prefix>,
 }
 
 impl fmt::Display for Report {
@@ -141,6 +183,273 @@ impl fmt::Display for Report {
     }
 }
 
+impl Report {
+    /// Returns the internet connectivity this report indicates.
+    pub fn connectivity(&self) -> Connectivity {
+        if self.captive_portal == Some(true) && !self.udp {
+            Connectivity::CaptivePortal
+        } else if self.udp || !self.relay_latency.is_empty() {
+            Connectivity::Online
+        } else {
+            Connectivity::Offline
+        }
+    }
+
+    /// Returns the connectivity changes from a `previous` report to this one.
+    ///
+    /// Only changes relevant to reaching this node are reported, changes to the latencies
+    /// of the relay servers are ignored.
+    pub fn changes_since(&self, previous: &Report) -> Vec<ReportChange> {
+        let mut changes = Vec::new();
+        if self.connectivity() != previous.connectivity() {
+            changes.push(ReportChange::Connectivity(self.connectivity()));
+        }
+        if self.udp != previous.udp {
+            changes.push(ReportChange::Udp(self.udp));
+        }
+        if self.ipv4 != previous.ipv4 {
+            changes.push(ReportChange::Ipv4(self.ipv4));
+        }
+        if self.ipv6 != previous.ipv6 {
+            changes.push(ReportChange::Ipv6(self.ipv6));
+        }
+        if self.global_v4 != previous.global_v4 {
+            changes.push(ReportChange::GlobalV4(self.global_v4));
+        }
+        if self.global_v6 != previous.global_v6 {
+            changes.push(ReportChange::GlobalV6(self.global_v6));
+        }
+        if self.nat_mapping() != previous.nat_mapping() {
+            changes.push(ReportChange::NatMapping(self.nat_mapping()));
+        }
+        if self.nat_filtering != previous.nat_filtering {
+            changes.push(ReportChange::NatFiltering(self.nat_filtering));
+        }
+        if self.preferred_relay != previous.preferred_relay {
+            changes.push(ReportChange::PreferredRelay(self.preferred_relay.clone()));
+        }
+        changes
+    }
+
+    /// Returns how the NAT maps the IPv4 address of this node, if it could be determined.
+    ///
+    /// This needs successful STUN probes to at least two relay or STUN servers with
+    /// different IP addresses.
+    pub fn nat_mapping(&self) -> Option<NatMapping> {
+        self.mapping_varies_by_dest_ip.map(|varies| match varies {
+            true => NatMapping::EndpointDependent,
+            false => NatMapping::EndpointIndependent,
+        })
+    }
+
+    /// How the NAT allocates public IPv4 ports.
+    ///
+    /// Only known for an [`NatMapping::EndpointDependent`] mapping, where every destination
+    /// is given its own public port, and needs at least two different ports of the same
+    /// IPv4 address in [`Report::public_addrs`].
+    pub fn port_allocation(&self) -> Option<PortAllocation> {
+        if self.nat_mapping() != Some(NatMapping::EndpointDependent) {
+            return None;
+        }
+        let global_v4 = self.global_v4?;
+        let mut ports: Vec<u16> = self
+            .public_addrs
+            .iter()
+            .filter(|public| public.addr.ip() == IpAddr::V4(*global_v4.ip()))
+            .map(|public| public.addr.port())
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        let gaps = ports.windows(2).map(|w| w[1] - w[0]);
+        let (min_gap, max_gap) = gaps.fold(None, |acc, gap| match acc {
+            None => Some((gap, gap)),
+            Some((min, max)) => Some((gap.min(min), gap.max(max))),
+        })?;
+        if max_gap > MAX_SEQUENTIAL_PORT_GAP {
+            return Some(PortAllocation::Random);
+        }
+        Some(PortAllocation::Sequential {
+            last_port: *ports.last().expect("at least two ports"),
+            delta: min_gap,
+        })
+    }
+}
+
+/// A change between two successive [`Report`]s, see [`Report::changes_since`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReportChange {
+    /// The internet connectivity changed.
+    Connectivity(Connectivity),
+    /// Whether UDP works changed.
+    Udp(bool),
+    /// Whether IPv4 works changed.
+    Ipv4(bool),
+    /// Whether IPv6 works changed.
+    Ipv6(bool),
+    /// The public IPv4 address changed.
+    GlobalV4(Option<SocketAddrV4>),
+    /// The public IPv6 address changed.
+    GlobalV6(Option<SocketAddrV6>),
+    /// The NAT mapping behaviour changed.
+    NatMapping(Option<NatMapping>),
+    /// The NAT filtering behaviour changed.
+    NatFiltering(Option<NatFiltering>),
+    /// The relay server with the lowest latency changed.
+    PreferredRelay(Option<RelayUrl>),
+}
+
+/// How a NAT maps local addresses to public addresses, as classified by [RFC 5780].
+///
+/// The mapping behaviour is found by comparing the public addresses reported by STUN
+/// servers at different IP addresses.  For the filtering behaviour see [`NatFiltering`].
+///
+/// [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780#section-4.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum NatMapping {
+    /// The same public address is used for all destinations.
+    ///
+    /// This is also the case when there is no NAT at all.  Holepunching works well with
+    /// such a mapping.
+    EndpointIndependent,
+    /// The public address depends on the destination, also known as a symmetric NAT.
+    ///
+    /// This is either an address-dependent or an address and port-dependent mapping.
+    /// Holepunching between two nodes behind such NATs usually fails, their connection
+    /// will stay relayed.
+    EndpointDependent,
+}
+
+/// Which incoming packets a NAT lets through to a mapping, as classified by [RFC 5780].
+///
+/// The filtering behaviour is found by asking a STUN server to respond from its alternate
+/// IP address and port, using the `CHANGE-REQUEST` attribute.  Relay servers do not
+/// support this, so it is only checked against the first IPv4 server of the custom
+/// [`StunServers`] and only if that server advertises an alternate address.  Otherwise
+/// [`Report::nat_filtering`] is `None`, i.e. unknown.
+///
+/// [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780#section-4.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum NatFiltering {
+    /// Packets from any address are let through, also known as a full cone NAT.
+    ///
+    /// This is also the case when there is no NAT or firewall at all.
+    EndpointIndependent,
+    /// Only packets from IP addresses the mapping sent to are let through.
+    AddressDependent,
+    /// Only packets from IP addresses and ports the mapping sent to are let through.
+    ///
+    /// Holepunching still works if the NAT mapping is endpoint independent, as both nodes
+    /// send to each other.
+    AddressAndPortDependent,
+}
+
+/// How a NAT with an [`NatMapping::EndpointDependent`] mapping allocates public ports.
+///
+/// See [`Report::port_allocation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortAllocation {
+    /// Each new mapping is given a port close above the previous one.
+    ///
+    /// The public port used when talking to a new destination can be predicted, which
+    /// allows holepunching through such a NAT.
+    Sequential {
+        /// The highest public port observed.
+        last_port: u16,
+        /// The smallest step observed between two allocated ports.
+        delta: u16,
+    },
+    /// Ports are allocated unpredictably.
+    Random,
+}
+
+impl PortAllocation {
+    /// Predicts the next `count` public ports of new mappings.
+    ///
+    /// Returns no ports for [`PortAllocation::Random`].
+    pub fn predict_ports(&self, count: usize) -> Vec<u16> {
+        match *self {
+            Self::Sequential { last_port, delta } => (1..=count)
+                .map_while(|i| {
+                    let offset = u16::try_from(i).ok()?.checked_mul(delta)?;
+                    last_port.checked_add(offset)
+                })
+                .collect(),
+            Self::Random => Vec::new(),
+        }
+    }
+}
+
+/// The internet connectivity as determined by a [`Report`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum Connectivity {
+    /// At least one relay or STUN server could be reached.
+    Online,
+    /// A captive portal is intercepting HTTP traffic.
+    ///
+    /// This is e.g. the login page of a hotel or airport network.  Connections will fail
+    /// until the user has logged in with the captive portal.
+    CaptivePortal,
+    /// None of the relay or STUN servers could be reached.
+    Offline,
+}
+
+/// Why a relay server was chosen as the [`Report::preferred_relay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PreferredRelayReason {
+    /// The relay server had the lowest latency measured in the recent reports.
+    LowestLatency {
+        /// The lowest recent latency to the relay server.
+        latency: Duration,
+    },
+    /// The previously preferred relay server was kept.
+    ///
+    /// Another relay server had a lower latency, but not low enough to switch.
+    KeptPrevious {
+        /// The latency to the previously preferred relay server in this report.
+        latency: Duration,
+        /// The lowest recent latency of the relay server which would have been preferred.
+        best_latency: Duration,
+    },
+}
+
+impl fmt::Display for PreferredRelayReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LowestLatency { latency } => write!(f, "lowest latency ({latency:?})"),
+            Self::KeptPrevious {
+                latency,
+                best_latency,
+            } => write!(
+                f,
+                "kept previous ({latency:?}, best other {best_latency:?} not fast enough)"
+            ),
+        }
+    }
+}
+
+/// A public address of this node, as observed by a relay or STUN server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PublicAddr {
+    /// The observed address.
+    pub addr: SocketAddr,
+    /// The server which observed the address.
+    ///
+    /// For custom STUN servers, see [`Options::stun_servers`], this is an `http` URL of their
+    /// host and STUN port.
+    pub observer: RelayUrl,
+    /// Whether the server is a relay server from the relay map, rather than a custom STUN
+    /// server.
+    pub is_relay: bool,
+}
+
+impl fmt::Display for PublicAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (observed by {})", self.addr, self.observer)
+    }
+}
+
 /// Latencies per relay node.
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct RelayLatencies(BTreeMap<RelayUrl, Duration>);
@@ -150,8 +459,13 @@ impl RelayLatencies {
         Default::default()
     }
 
+    /// Removes the latency of a relay.
+    fn remove(&mut self, url: &RelayUrl) {
+        self.0.remove(url);
+    }
+
     /// Updates a relay's latency, if it is faster than before.
-    fn update_relay(&mut self, url: RelayUrl, latency: Duration) {
+    pub(crate) fn update_relay(&mut self, url: RelayUrl, latency: Duration) {
         let val = self.0.entry(url).or_insert(latency);
         if latency < *val {
             *val = latency;
@@ -183,15 +497,18 @@ impl RelayLatencies {
         self.0.iter().map(|(k, v)| (k, *v))
     }
 
-    fn len(&self) -> usize {
+    /// Returns the number of relays with a latency.
+    pub fn len(&self) -> usize {
         self.0.len()
     }
 
-    fn is_empty(&self) -> bool {
+    /// Returns `true` if there are no latencies.
+    pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
 
-    fn get(&self, url: &RelayUrl) -> Option<Duration> {
+    /// Returns the latency of a relay, if it was reached.
+    pub fn get(&self, url: &RelayUrl) -> Option<Duration> {
         self.0.get(url).copied()
     }
 }
@@ -227,6 +544,8 @@ struct Reports {
     last: Option<Arc<Report>>,
     /// Time of last full (non-incremental) report.
     last_full: Instant,
+    /// Time of the most recent report.
+    last_at: Option<Instant>,
 }
 
 impl Default for Reports {
@@ -236,6 +555,7 @@ impl Default for Reports {
             prev: Default::default(),
             last: Default::default(),
             last_full: Instant::now(),
+            last_at: None,
         }
     }
 }
@@ -277,6 +597,16 @@ impl Client {
         self.addr.clone()
     }
 
+    /// Sets which STUN packets to drop if the actor does not keep up with them.
+    ///
+    /// See [`Addr::receive_stun_packet`].  Defaults to [`StunOverflowPolicy::DropNewest`].
+    pub fn set_stun_overflow_policy(&self, policy: StunOverflowPolicy) {
+        self.addr
+            .stun_packets
+            .drop_oldest
+            .store(policy == StunOverflowPolicy::DropOldest, Ordering::Relaxed);
+    }
+
     /// Runs a net_report, returning the report.
     ///
     /// It may not be called concurrently with itself, `&mut self` takes care of that.
@@ -346,7 +676,7 @@ impl Client {
         self.addr
             .send(Message::RunCheck {
                 relay_map,
-                opts,
+                opts: Box::new(opts),
                 response_tx: tx,
             })
             .await?;
@@ -375,7 +705,7 @@ pub(crate) enum Message {
         /// The map of relays we want to probe
         relay_map: RelayMap,
         /// Options for the report
-        opts: Options,
+        opts: Box<Options>,
         /// Channel to receive the response.
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     },
@@ -404,6 +734,54 @@ pub(crate) enum Message {
 #[derive(Debug, Clone)]
 pub struct Addr {
     sender: mpsc::Sender<Message>,
+    stun_packets: Arc<StunPacketQueue>,
+}
+
+/// Which STUN packets to drop if the net_report actor can not keep up with them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StunOverflowPolicy {
+    /// Drops newly received STUN packets until there is room in the queue again.
+    #[default]
+    DropNewest,
+    /// Drops the oldest queued STUN packet to make room for a newly received one.
+    DropOldest,
+}
+
+/// Queue of STUN packets received by [`Addr::receive_stun_packet`].
+#[derive(Debug)]
+struct StunPacketQueue {
+    packets: ConcurrentQueue<(Bytes, SocketAddr)>,
+    /// Whether to apply [`StunOverflowPolicy::DropOldest`].
+    drop_oldest: AtomicBool,
+    /// Notifies the actor of newly queued packets.
+    notify: Notify,
+}
+
+impl StunPacketQueue {
+    /// Capacity of the queue, matching the capacity of the actor's message channel.
+    const CAPACITY: usize = 32;
+
+    fn new() -> Self {
+        Self {
+            packets: ConcurrentQueue::bounded(Self::CAPACITY),
+            drop_oldest: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Queues a packet, returns the source address of the packet dropped if full.
+    fn push(&self, payload: Bytes, src: SocketAddr) -> Option<SocketAddr> {
+        let dropped = if self.drop_oldest.load(Ordering::Relaxed) {
+            // Only fails if the queue is closed, which it never is.
+            self.packets
+                .force_push((payload, src))
+                .map_or(Some(src), |dropped| dropped.map(|(_, addr)| addr))
+        } else {
+            self.packets.push((payload, src)).err().map(|_| src)
+        };
+        self.notify.notify_one();
+        dropped
+    }
 }
 
 impl Addr {
@@ -419,15 +797,13 @@ impl Addr {
     /// in-flight STUN probes.  The actor will simply ignore any stray STUN packets.
     ///
     /// There is an implicit queue here which may drop packets if the actor does not keep up
-    /// consuming them.
+    /// consuming them.  Which packets are dropped is determined by the
+    /// [`StunOverflowPolicy`] set using [`Client::set_stun_overflow_policy`].
     pub fn receive_stun_packet(&self, payload: Bytes, src: SocketAddr) {
-        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(Message::StunPacket {
-            payload,
-            from_addr: src,
-        }) {
+        if let Some(dropped_src) = self.stun_packets.push(payload, src) {
             #[cfg(feature = "metrics")]
             inc!(Metrics, stun_packets_dropped);
-            warn!("dropping stun packet from {}", src);
+            warn!("dropping stun packet from {}", dropped_src);
         }
     }
 
@@ -452,6 +828,8 @@ struct Actor {
     ///
     /// This allows creating new [`Addr`]s from the actor.
     sender: mpsc::Sender<Message>,
+    /// Queue of STUN packets received via [`Addr::receive_stun_packet`].
+    stun_packets: Arc<StunPacketQueue>,
     /// A collection of previously generated reports.
     ///
     /// Sometimes it is useful to look at past reports to decide what to do.
@@ -480,6 +858,10 @@ struct Actor {
     /// The [`IpMappedAddresses`] that allows you to do QAD in iroh
     #[cfg(not(wasm_browser))]
     ip_mapped_addrs: Option<IpMappedAddresses>,
+
+    /// The NAT64 prefix of the current link, shared by the reports.
+    #[cfg(not(wasm_browser))]
+    nat64_cache: nat64::Nat64Cache,
 }
 
 impl Actor {
@@ -497,6 +879,7 @@ impl Actor {
         Ok(Self {
             receiver,
             sender,
+            stun_packets: Arc::new(StunPacketQueue::new()),
             reports: Default::default(),
             #[cfg(not(wasm_browser))]
             port_mapper,
@@ -506,6 +889,8 @@ impl Actor {
             dns_resolver,
             #[cfg(not(wasm_browser))]
             ip_mapped_addrs,
+            #[cfg(not(wasm_browser))]
+            nat64_cache: Default::default(),
         })
     }
 
@@ -513,6 +898,7 @@ impl Actor {
     fn addr(&self) -> Addr {
         Addr {
             sender: self.sender.clone(),
+            stun_packets: self.stun_packets.clone(),
         }
     }
 
@@ -522,7 +908,23 @@ impl Actor {
     /// its clones) is dropped this will terminate.
     async fn run(&mut self) {
         debug!("net_report actor starting");
-        while let Some(msg) = self.receiver.recv().await {
+        loop {
+            let msg = tokio::select! {
+                // The InFlightStun registration of a probe is sent before its request, handle
+                // it before the STUN packets or its response could be dropped as unknown.
+                biased;
+
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = self.stun_packets.notify.notified() => {
+                    while let Ok((payload, from_addr)) = self.stun_packets.packets.pop() {
+                        self.handle_stun_packet(&payload, from_addr);
+                    }
+                    continue;
+                }
+            };
             trace!(?msg, "handling message");
             match msg {
                 Message::RunCheck {
@@ -530,7 +932,7 @@ impl Actor {
                     opts,
                     response_tx,
                 } => {
-                    self.handle_run_check(relay_map, opts, response_tx);
+                    self.handle_run_check(relay_map, *opts, response_tx);
                 }
                 Message::ReportReady { report } => {
                     self.handle_report_ready(*report);
@@ -560,14 +962,26 @@ impl Actor {
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     ) {
         let protocols = opts.to_protocols();
+        let full_report_interval = opts.full_report_interval;
+        let force_full = opts.full;
+        let want_fast = opts.fast;
+        let limits = reportgen::ProbeLimits {
+            probe_timeout: opts.probe_timeout,
+            report_timeout: opts.report_timeout,
+            max_concurrent_probes: opts.max_concurrent_probes,
+        };
         #[cfg(not(wasm_browser))]
         let socket_state = SocketState {
             port_mapper: self.port_mapper.clone(),
             stun_sock4: opts.stun_sock_v4,
             stun_sock6: opts.stun_sock_v6,
             quic_config: opts.quic_config,
+            stun_servers: opts.stun_servers,
             dns_resolver: self.dns_resolver.clone(),
             ip_mapped_addrs: self.ip_mapped_addrs.clone(),
+            nat64_prefix: None,
+            nat64_cache: self.nat64_cache.clone(),
+            relay_tls_config: opts.relay_tls_config,
         };
         trace!("Attempting probes for protocols {protocols:#?}");
         if self.current_report_run.is_some() {
@@ -581,8 +995,9 @@ impl Actor {
 
         let now = Instant::now();
 
-        let mut do_full = self.reports.next_full
-            || now.duration_since(self.reports.last_full) > FULL_REPORT_INTERVAL;
+        let mut do_full = force_full
+            || self.reports.next_full
+            || now.duration_since(self.reports.last_full) > full_report_interval;
 
         // If the last report had a captive portal and reported no UDP access,
         // it's possible that we didn't get a useful net_report due to the
@@ -602,25 +1017,59 @@ impl Actor {
         #[cfg(feature = "metrics")]
         inc!(Metrics, reports);
 
+        // A fast report needs a recent report with a preferred relay to reuse the results of.
+        let fast = !do_full
+            && want_fast
+            && !cfg!(wasm_browser)
+            && self
+                .reports
+                .last_at
+                .is_some_and(|last_at| now.duration_since(last_at) <= FAST_REPORT_MAX_AGE)
+            && self.reports.last.as_ref().is_some_and(|last| {
+                last.udp
+                    && last
+                        .preferred_relay
+                        .as_ref()
+                        .is_some_and(|url| relay_map.contains_node(url))
+            });
+        #[cfg(feature = "metrics")]
+        if fast {
+            inc!(Metrics, reports_fast);
+        }
+
         let actor = reportgen::Client::new(
             self.addr(),
             self.reports.last.clone(),
+            fast,
             relay_map,
             protocols,
+            limits,
             #[cfg(not(wasm_browser))]
             socket_state,
         );
 
         self.current_report_run = Some(ReportRun {
             _reportgen: actor,
+            fast,
             report_tx: response_tx,
         });
     }
 
     fn handle_report_ready(&mut self, report: Report) {
+        let previous = self.reports.last.clone();
         let report = self.finish_and_store_report(report);
         self.in_flight_stun_requests.clear();
-        if let Some(ReportRun { report_tx, .. }) = self.current_report_run.take() {
+        if let Some(ReportRun {
+            report_tx, fast, ..
+        }) = self.current_report_run.take()
+        {
+            if let Some(previous) = previous.filter(|_| fast) {
+                let changes = report.changes_since(&previous);
+                if !changes.is_empty() {
+                    debug!(?changes, "fast report found changes, next report is full");
+                    self.reports.next_full = true;
+                }
+            }
             report_tx.send(Ok(report)).ok();
         }
     }
@@ -763,12 +1212,20 @@ impl Actor {
                 && best_any > old_relay_cur_latency / 3 * 2
             {
                 r.preferred_relay = prev_relay;
+                r.preferred_relay_reason = Some(PreferredRelayReason::KeptPrevious {
+                    latency: old_relay_cur_latency,
+                    best_latency: best_any,
+                });
+            } else if r.preferred_relay.is_some() {
+                r.preferred_relay_reason =
+                    Some(PreferredRelayReason::LowestLatency { latency: best_any });
             }
         }
 
         let r = Arc::new(r);
         self.reports.prev.insert(now, r.clone());
         self.reports.last = Some(r.clone());
+        self.reports.last_at = Some(now);
 
         r
     }
@@ -779,6 +1236,8 @@ impl Actor {
 struct ReportRun {
     /// The handle of the [`reportgen`] actor, cancels the actor on drop.
     _reportgen: reportgen::Client,
+    /// Whether this is a fast report.
+    fast: bool,
     /// Where to send the completed report.
     report_tx: oneshot::Sender<Result<Arc<Report>>>,
 }
@@ -1088,6 +1547,8 @@ mod tests {
             );
             assert!(r.global_v4.is_some(), "expected globalV4 set");
             assert!(r.preferred_relay.is_some(),);
+            assert_eq!(r.public_addrs.len(), 1);
+            assert!(r.public_addrs[0].is_relay);
             cancel.cancel();
         }
 
@@ -1100,6 +1561,232 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_report_connectivity() {
+        let relay_url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let mut report = Report::default();
+        assert_eq!(report.connectivity(), Connectivity::Offline);
+
+        report.captive_portal = Some(false);
+        assert_eq!(report.connectivity(), Connectivity::Offline);
+
+        // UDP blocked, but the relay is reachable over HTTPS.
+        report
+            .relay_latency
+            .update_relay(relay_url, Duration::from_millis(20));
+        assert_eq!(report.connectivity(), Connectivity::Online);
+
+        report.captive_portal = Some(true);
+        assert_eq!(report.connectivity(), Connectivity::CaptivePortal);
+
+        report.udp = true;
+        assert_eq!(report.connectivity(), Connectivity::Online);
+    }
+
+    #[test]
+    fn test_report_changes_since() {
+        let relay_url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let mut previous = Report::default();
+        previous
+            .relay_latency
+            .update_relay(relay_url.clone(), Duration::from_millis(30));
+        let mut report = previous.clone();
+        report
+            .relay_latency
+            .update_relay(relay_url.clone(), Duration::from_millis(20));
+        assert!(report.changes_since(&previous).is_empty());
+
+        report.udp = true;
+        report.ipv6 = true;
+        report.global_v6 = Some("[2001:db8::1]:1234".parse().unwrap());
+        report.preferred_relay = Some(relay_url.clone());
+        assert_eq!(
+            report.changes_since(&previous),
+            vec![
+                ReportChange::Udp(true),
+                ReportChange::Ipv6(true),
+                ReportChange::GlobalV6(report.global_v6),
+                ReportChange::PreferredRelay(Some(relay_url)),
+            ]
+        );
+        assert_eq!(
+            Report::default().changes_since(&previous),
+            vec![ReportChange::Connectivity(Connectivity::Offline),]
+        );
+    }
+
+    #[test]
+    fn test_report_nat_mapping() {
+        let mut report = Report::default();
+        assert_eq!(report.nat_mapping(), None);
+        report.mapping_varies_by_dest_ip = Some(false);
+        assert_eq!(report.nat_mapping(), Some(NatMapping::EndpointIndependent));
+        report.mapping_varies_by_dest_ip = Some(true);
+        assert_eq!(report.nat_mapping(), Some(NatMapping::EndpointDependent));
+    }
+
+    #[test]
+    fn test_report_port_allocation() {
+        let observer: RelayUrl = "https://relay.example/".parse().unwrap();
+        let public = |port: u16| PublicAddr {
+            addr: SocketAddr::from(([203, 0, 113, 1], port)),
+            observer: observer.clone(),
+            is_relay: true,
+        };
+        let mut report = Report {
+            mapping_varies_by_dest_ip: Some(true),
+            global_v4: Some("203.0.113.1:40002".parse().unwrap()),
+            public_addrs: vec![public(40002), public(40000), public(40006)],
+            ..Default::default()
+        };
+        let allocation = report.port_allocation().unwrap();
+        assert_eq!(
+            allocation,
+            PortAllocation::Sequential {
+                last_port: 40006,
+                delta: 2
+            }
+        );
+        assert_eq!(allocation.predict_ports(3), vec![40008, 40010, 40012]);
+
+        let near_end = PortAllocation::Sequential {
+            last_port: u16::MAX - 3,
+            delta: 2,
+        };
+        assert_eq!(near_end.predict_ports(3), vec![u16::MAX - 1]);
+
+        report.public_addrs.push(public(50000));
+        assert_eq!(report.port_allocation(), Some(PortAllocation::Random));
+        assert!(PortAllocation::Random.predict_ports(3).is_empty());
+
+        report.mapping_varies_by_dest_ip = Some(false);
+        assert_eq!(report.port_allocation(), None);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_custom_stun_servers() -> Result<()> {
+        let (stun_addr, stun_stats, _cleanup_guard) =
+            stun_utils::serve("127.0.0.1".parse().unwrap()).await?;
+
+        let server: StunServer = stun_addr.to_string().parse()?;
+        assert_eq!(server, StunServer::from(stun_addr));
+        assert_eq!(server.to_string(), stun_addr.to_string());
+        let default_port: StunServer = "stun.example.com".parse()?;
+        assert_eq!(default_port.to_string(), "stun.example.com:3478");
+        assert!("stun.example.com:port".parse::<StunServer>().is_err());
+
+        let resolver = dns::tests::resolver();
+        let mut client = Client::new(None, resolver.clone(), None)?;
+        let cancel = CancellationToken::new();
+        let sock = bind_local_stun_socket(IpFamily::V4, client.addr(), cancel.clone());
+        let opts = Options::default()
+            .stun_v4(sock)
+            .stun_servers(StunServers::default().v4(vec![server.clone()]));
+
+        // Without any relay servers the custom STUN server still discovers our address.
+        let r = client.get_report(RelayMap::empty(), opts).await?;
+        assert!(r.udp, "want UDP");
+        assert!(r.global_v4.is_some(), "expected globalV4 set");
+        assert!(r.relay_latency.is_empty());
+        assert!(r.relay_v4_latency.is_empty());
+        assert!(r.preferred_relay.is_none());
+        assert!(stun_stats.total().await >= 1);
+        let public_addr = &r.public_addrs[0];
+        assert_eq!(Some(public_addr.addr), r.global_v4.map(SocketAddr::V4));
+        assert_eq!(public_addr.observer, server.node().url);
+        assert!(!public_addr.is_relay);
+        // The server does not support RFC 5780.
+        assert_eq!(r.nat_filtering, None);
+        cancel.cancel();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_fast_report() -> Result<()> {
+        let (stun_addr_1, stun_stats_1, _cleanup_guard_1) =
+            stun_utils::serve("127.0.0.1".parse().unwrap()).await?;
+        let (stun_addr_2, stun_stats_2, _cleanup_guard_2) =
+            stun_utils::serve("127.0.0.1".parse().unwrap()).await?;
+
+        let resolver = dns::tests::resolver();
+        let mut client = Client::new(None, resolver.clone(), None)?;
+        let dm = stun_utils::relay_map_of([stun_addr_1, stun_addr_2].into_iter());
+        let cancel = CancellationToken::new();
+        let sock = bind_local_stun_socket(IpFamily::V4, client.addr(), cancel.clone());
+
+        let first = client
+            .get_report(dm.clone(), Options::default().stun_v4(sock.clone()))
+            .await?;
+        assert!(first.udp, "want UDP");
+        assert_eq!(first.relay_latency.len(), 2);
+        let preferred = first.preferred_relay.clone().expect("preferred relay");
+        let (preferred_stats, other_stats) = match dm.get_node(&preferred) {
+            Some(node) if node.stun_port == stun_addr_1.port() => (stun_stats_1, stun_stats_2),
+            _ => (stun_stats_2, stun_stats_1),
+        };
+        let preferred_count = preferred_stats.total().await;
+        let other_count = other_stats.total().await;
+
+        // The fast report only probes the preferred relay and reuses the other results.
+        let second = client
+            .get_report(dm.clone(), Options::default().stun_v4(sock).fast(true))
+            .await?;
+        assert!(second.udp, "want UDP");
+        assert_eq!(second.global_v4, first.global_v4);
+        assert_eq!(second.preferred_relay, Some(preferred));
+        assert_eq!(second.relay_latency.len(), 2);
+        assert!(second.changes_since(&first).is_empty());
+        assert!(preferred_stats.total().await > preferred_count);
+        assert_eq!(other_stats.total().await, other_count);
+        cancel.cancel();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_probe_limits() -> Result<()> {
+        let (stun_addr_1, _stun_stats_1, _cleanup_guard_1) =
+            stun_utils::serve("127.0.0.1".parse().unwrap()).await?;
+        let (stun_addr_2, _stun_stats_2, _cleanup_guard_2) =
+            stun_utils::serve("127.0.0.1".parse().unwrap()).await?;
+        // Never responds, so its probes run into the probe timeout.
+        let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let dm = stun_utils::relay_map_of_opts(
+            [
+                (stun_addr_1, true),
+                (stun_addr_2, true),
+                (blackhole.local_addr()?, true),
+            ]
+            .into_iter(),
+        );
+
+        let resolver = dns::tests::resolver();
+        let mut client = Client::new(None, resolver.clone(), None)?;
+        let cancel = CancellationToken::new();
+        let sock = bind_local_stun_socket(IpFamily::V4, client.addr(), cancel.clone());
+        let opts = Options::default()
+            .stun_v4(sock)
+            .icmp_v4(false)
+            .icmp_v6(false)
+            .https(false)
+            .probe_timeout(Duration::from_millis(200))
+            .report_timeout(Duration::from_secs(10))
+            .max_concurrent_probes(Some(1));
+
+        // One probe at a time still reaches all responding servers.
+        let r = client.get_report(dm, opts).await?;
+        assert!(r.udp, "want UDP");
+        assert_eq!(r.relay_latency.len(), 2);
+        assert!(r.global_v4.is_some(), "expected globalV4 set");
+        cancel.cancel();
+
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_udp_blocked() -> Result<()> {
@@ -1144,6 +1831,7 @@ mod tests {
             preferred_relay: can_ping
                 .then_some(r.preferred_relay.clone())
                 .unwrap_or_default(),
+            preferred_relay_reason: can_ping.then_some(r.preferred_relay_reason).flatten(),
             ..Default::default()
         };
 
@@ -1182,6 +1870,8 @@ mod tests {
             steps: Vec<Step>,
             /// want PreferredRelay on final step
             want_relay: Option<RelayUrl>,
+            /// want PreferredRelayReason on final step
+            want_reason: Option<PreferredRelayReason>,
             // wanted len(c.prev)
             want_prev_len: usize,
         }
@@ -1195,6 +1885,9 @@ mod tests {
                 }],
                 want_prev_len: 1,
                 want_relay: Some(relay_url(1)),
+                want_reason: Some(PreferredRelayReason::LowestLatency {
+                    latency: Duration::from_secs(2),
+                }),
             },
             Test {
                 name: "with_two",
@@ -1210,6 +1903,9 @@ mod tests {
                 ],
                 want_prev_len: 2,
                 want_relay: Some(relay_url(1)), // t0's d1 of 2 is still best
+                want_reason: Some(PreferredRelayReason::LowestLatency {
+                    latency: Duration::from_secs(2),
+                }),
             },
             Test {
                 name: "but_now_d1_gone",
@@ -1229,6 +1925,9 @@ mod tests {
                 ],
                 want_prev_len: 3,
                 want_relay: Some(relay_url(2)), // only option
+                want_reason: Some(PreferredRelayReason::LowestLatency {
+                    latency: Duration::from_secs(3),
+                }),
             },
             Test {
                 name: "d1_is_back",
@@ -1252,6 +1951,9 @@ mod tests {
                 ],
                 want_prev_len: 4,
                 want_relay: Some(relay_url(1)), // t0's d1 of 2 is still best
+                want_reason: Some(PreferredRelayReason::LowestLatency {
+                    latency: Duration::from_secs(2),
+                }),
             },
             Test {
                 name: "things_clean_up",
@@ -1279,6 +1981,9 @@ mod tests {
                 ],
                 want_prev_len: 1, // t=[0123]s all gone. (too old, older than 10 min)
                 want_relay: Some(relay_url(3)), // only option
+                want_reason: Some(PreferredRelayReason::LowestLatency {
+                    latency: Duration::from_secs(3),
+                }),
             },
             Test {
                 name: "preferred_relay_hysteresis_no_switch",
@@ -1294,6 +1999,10 @@ mod tests {
                 ],
                 want_prev_len: 2,
                 want_relay: Some(relay_url(1)), // 2 didn't get fast enough
+                want_reason: Some(PreferredRelayReason::KeptPrevious {
+                    latency: Duration::from_secs(4),
+                    best_latency: Duration::from_secs(3),
+                }),
             },
             Test {
                 name: "preferred_relay_hysteresis_do_switch",
@@ -1309,6 +2018,9 @@ mod tests {
                 ],
                 want_prev_len: 2,
                 want_relay: Some(relay_url(2)), // 2 got fast enough
+                want_reason: Some(PreferredRelayReason::LowestLatency {
+                    latency: Duration::from_secs(1),
+                }),
             },
         ];
         let resolver = dns::tests::resolver();
@@ -1328,6 +2040,9 @@ mod tests {
             let got = &last_report.preferred_relay;
             let want = &tt.want_relay;
             assert_eq!(got, want, "preferred_relay");
+            let got = last_report.preferred_relay_reason;
+            let want = tt.want_reason;
+            assert_eq!(got, want, "preferred_relay_reason");
         }
 
         Ok(())
@@ -1389,4 +2104,22 @@ mod tests {
         task.abort();
         Ok(())
     }
+
+    #[test]
+    fn test_stun_packet_queue_overflow() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let queue = StunPacketQueue::new();
+        for port in 0..StunPacketQueue::CAPACITY as u16 {
+            assert_eq!(queue.push(Bytes::new(), addr(port)), None);
+        }
+
+        // drop newest
+        let newest = addr(u16::MAX);
+        assert_eq!(queue.push(Bytes::new(), newest), Some(newest));
+
+        // drop oldest
+        queue.drop_oldest.store(true, Ordering::Relaxed);
+        assert_eq!(queue.push(Bytes::new(), newest), Some(addr(0)));
+        assert_eq!(queue.packets.pop().unwrap().1, addr(1));
+    }
 }
@@ -12,9 +12,13 @@ pub(crate) mod timeouts {
     /// The total time we wait for all the probes.
     ///
     /// This includes the STUN, ICMP and HTTPS probes, which will all
-    /// start at different times based on the ProbePlan.
+    /// start at different times based on the ProbePlan.  When the overall report timeout
+    /// is configured this is scaled along with it.
     pub(crate) const PROBES_TIMEOUT: Duration = Duration::from_secs(3);
 
+    /// The maximum amount of time a single probe may take.
+    pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
     /// How long to await for a captive-portal result.
     ///
     /// This delay is chosen so it starts after good-working STUN probes
@@ -30,6 +34,17 @@ pub(crate) mod timeouts {
 
     pub(crate) const DNS_TIMEOUT: Duration = Duration::from_secs(3);
 
+    /// How long to wait for the DNS64 lookup discovering the NAT64 prefix.
+    pub(crate) const NAT64_DETECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// How long to wait for the NAT filtering check.
+    ///
+    /// Must be lower than [`OVERALL_REPORT_TIMEOUT`].
+    pub(crate) const NAT_FILTERING_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// How long to wait for a response of the NAT filtering check before resending a request.
+    pub(crate) const NAT_FILTERING_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(250);
+
     /// The amount of time we wait for a hairpinned packet to come back.
     pub(crate) const HAIRPIN_CHECK_TIMEOUT: Duration = Duration::from_millis(100);
 
@@ -14,6 +14,7 @@ pub struct Metrics {
     pub stun_packets_recv_ipv6: Counter,
     pub reports: Counter,
     pub reports_full: Counter,
+    pub reports_fast: Counter,
 }
 
 impl Default for Metrics {
@@ -30,6 +31,7 @@ impl Default for Metrics {
                 "Number of reports executed by net_report, including full reports",
             ),
             reports_full: Counter::new("Number of full reports executed by net_report"),
+            reports_fast: Counter::new("Number of fast reports executed by net_report"),
         }
     }
 }
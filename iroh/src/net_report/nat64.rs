@@ -0,0 +1,324 @@
+//! NAT64 prefix discovery and IPv4 address synthesis.
+//!
+//! On IPv6-only networks IPv4 hosts are reached through a NAT64 gateway, using IPv6
+//! addresses which embed the IPv4 address in the network's NAT64 prefix as described in
+//! [RFC 6052].  The prefix is discovered using DNS64, by resolving the well-known
+//! `ipv4only.arpa` name as described in [RFC 7050].
+//!
+//! [RFC 6052]: https://www.rfc-editor.org/rfc/rfc6052
+//! [RFC 7050]: https://www.rfc-editor.org/rfc/rfc7050
+
+#[cfg(not(wasm_browser))]
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+};
+
+#[cfg(not(wasm_browser))]
+use anyhow::Result;
+#[cfg(not(wasm_browser))]
+use hickory_resolver::ResolveError;
+#[cfg(not(wasm_browser))]
+use iroh_relay::dns::DnsResolver;
+#[cfg(not(wasm_browser))]
+use netwatch::interfaces;
+#[cfg(not(wasm_browser))]
+use tracing::{debug, trace};
+
+#[cfg(not(wasm_browser))]
+use super::defaults::timeouts::NAT64_DETECTION_TIMEOUT;
+
+/// The name resolved to discover the NAT64 prefix, see [RFC 7050].
+///
+/// [RFC 7050]: https://www.rfc-editor.org/rfc/rfc7050
+const IPV4_ONLY_NAME: &str = "ipv4only.arpa";
+
+/// The IPv4 addresses `ipv4only.arpa` resolves to.
+const IPV4_ONLY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// The prefix lengths allowed by RFC 6052, most common first.
+const PREFIX_LENS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+
+/// The NAT64 prefix of a network.
+///
+/// IPv4 addresses are embedded in this prefix to reach them through the NAT64 gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Nat64Prefix {
+    prefix: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64Prefix {
+    /// The well-known NAT64 prefix, `64:ff9b::/96`.
+    pub const WELL_KNOWN: Self = Self {
+        prefix: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+        len: 96,
+    };
+
+    /// Creates a NAT64 prefix.
+    ///
+    /// Returns `None` if `len` is not one of the prefix lengths allowed by RFC 6052: 32, 40,
+    /// 48, 56, 64 or 96.  Bits of `prefix` beyond `len` are ignored.
+    pub fn new(prefix: Ipv6Addr, len: u8) -> Option<Self> {
+        if !PREFIX_LENS.contains(&len) {
+            return None;
+        }
+        let mut octets = prefix.octets();
+        octets[len as usize / 8..].fill(0);
+        Some(Self {
+            prefix: octets.into(),
+            len,
+        })
+    }
+
+    /// The prefix address, with all bits beyond the prefix length zero.
+    pub fn prefix(&self) -> Ipv6Addr {
+        self.prefix
+    }
+
+    /// The length of the prefix in bits.
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// Synthesizes the IPv6 address reaching `addr` through the NAT64 gateway.
+    pub fn synthesize(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        for (i, octet) in v4_positions(self.len).zip(addr.octets()) {
+            octets[i] = octet;
+        }
+        octets.into()
+    }
+
+    /// Synthesizes the IPv6 socket address reaching `addr` through the NAT64 gateway.
+    ///
+    /// IPv6 addresses are returned unchanged.
+    pub fn synthesize_socket_addr(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(addr) => {
+                SocketAddrV6::new(self.synthesize(*addr.ip()), addr.port(), 0, 0).into()
+            }
+            SocketAddr::V6(_) => addr,
+        }
+    }
+
+    /// Extracts the IPv4 address embedded in an address synthesized with this prefix.
+    ///
+    /// Returns `None` if `addr` is not within this prefix.
+    pub fn extract(&self, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+        let octets = addr.octets();
+        let prefix_octets = self.len as usize / 8;
+        if octets[..prefix_octets] != self.prefix.octets()[..prefix_octets] {
+            return None;
+        }
+        let mut v4 = [0u8; 4];
+        for (octet, i) in v4.iter_mut().zip(v4_positions(self.len)) {
+            *octet = octets[i];
+        }
+        Some(v4.into())
+    }
+
+    /// Extracts the IPv4 socket address from a socket address synthesized with this prefix.
+    ///
+    /// Returns `None` if `addr` is not an IPv6 address within this prefix.
+    pub fn extract_socket_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        match addr {
+            SocketAddr::V6(addr) => self
+                .extract(*addr.ip())
+                .map(|ip| SocketAddr::new(ip.into(), addr.port())),
+            SocketAddr::V4(_) => None,
+        }
+    }
+
+    /// Finds the prefix from an address synthesized for `ipv4only.arpa`.
+    fn from_ipv4_only_addr(addr: Ipv6Addr) -> Option<Self> {
+        PREFIX_LENS.into_iter().find_map(|len| {
+            let prefix = Self::new(addr, len)?;
+            let v4 = prefix.extract(addr)?;
+            IPV4_ONLY_ADDRS.contains(&v4).then_some(prefix)
+        })
+    }
+}
+
+impl fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.len)
+    }
+}
+
+/// The positions of the octets of the embedded IPv4 address, skipping the reserved octet 8.
+fn v4_positions(len: u8) -> impl Iterator<Item = usize> {
+    (len as usize / 8..16).filter(|i| *i != 8).take(4)
+}
+
+/// The NAT64 prefix discovered on the current link.
+///
+/// The DNS64 lookup can take up to [`NAT64_DETECTION_TIMEOUT`], too long to repeat for
+/// every report.  The prefix belongs to the network, so the result is kept until the
+/// addresses of the local interfaces change.  A failed lookup, e.g. one which timed out, is
+/// not kept.
+#[cfg(not(wasm_browser))]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Nat64Cache(Arc<Mutex<Option<CachedPrefix>>>);
+
+#[cfg(not(wasm_browser))]
+#[derive(Debug)]
+struct CachedPrefix {
+    /// The addresses of the local interfaces when the prefix was discovered.
+    link: BTreeSet<IpAddr>,
+    prefix: Option<Nat64Prefix>,
+}
+
+#[cfg(not(wasm_browser))]
+impl Nat64Cache {
+    /// Returns the NAT64 prefix of the network, discovering it if the link changed.
+    pub(crate) async fn get(
+        &self,
+        dns_resolver: &DnsResolver,
+        if_state: &interfaces::State,
+    ) -> Option<Nat64Prefix> {
+        let link = link_addrs(if_state);
+        if let Some(cached) = self.0.lock().expect("poisoned").as_ref() {
+            if cached.link == link {
+                trace!(prefix = ?cached.prefix, "reusing NAT64 prefix");
+                return cached.prefix;
+            }
+        }
+        let prefix = detect(dns_resolver).await.ok()?;
+        *self.0.lock().expect("poisoned") = Some(CachedPrefix { link, prefix });
+        prefix
+    }
+}
+
+/// Returns the addresses of all local interfaces, which identify the link.
+#[cfg(not(wasm_browser))]
+fn link_addrs(if_state: &interfaces::State) -> BTreeSet<IpAddr> {
+    if_state
+        .interfaces
+        .values()
+        .flat_map(|iface| iface.addrs())
+        .map(|net| net.addr())
+        .collect()
+}
+
+/// Discovers the NAT64 prefix of the network using DNS64.
+///
+/// Returns `None` if the resolver does not synthesize IPv6 addresses, i.e. there is no
+/// NAT64 gateway, and an error if the resolver gave no answer.
+#[cfg(not(wasm_browser))]
+async fn detect(dns_resolver: &DnsResolver) -> Result<Option<Nat64Prefix>> {
+    let addrs = match dns_resolver
+        .lookup_ipv6(IPV4_ONLY_NAME, NAT64_DETECTION_TIMEOUT)
+        .await
+    {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            debug!("no NAT64 prefix found: {err:#}");
+            let answered = err
+                .downcast_ref::<ResolveError>()
+                .is_some_and(|err| err.is_no_records_found() || err.is_nx_domain());
+            return match answered {
+                true => Ok(None),
+                false => Err(err),
+            };
+        }
+    };
+    let prefix = addrs.into_iter().find_map(|addr| match addr {
+        IpAddr::V6(addr) => Nat64Prefix::from_ipv4_only_addr(addr),
+        IpAddr::V4(_) => None,
+    });
+    debug!(?prefix, "NAT64 prefix detection finished");
+    Ok(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(wasm_browser))]
+    #[tokio::test]
+    async fn test_cache() {
+        // A nameserver which never answers.
+        let nameserver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dns_resolver = DnsResolver::with_nameserver(nameserver.local_addr().unwrap());
+        let if_state = interfaces::State::fake();
+
+        // A timed out lookup is not cached.
+        let cache = Nat64Cache::default();
+        assert_eq!(cache.get(&dns_resolver, &if_state).await, None);
+        assert!(cache.0.lock().unwrap().is_none());
+
+        // The prefix is reused on the same link, without a lookup.
+        *cache.0.lock().unwrap() = Some(CachedPrefix {
+            link: link_addrs(&if_state),
+            prefix: Some(Nat64Prefix::WELL_KNOWN),
+        });
+        let start = std::time::Instant::now();
+        assert_eq!(
+            cache.get(&dns_resolver, &if_state).await,
+            Some(Nat64Prefix::WELL_KNOWN)
+        );
+        assert!(start.elapsed() < NAT64_DETECTION_TIMEOUT);
+
+        // Another link looks the prefix up again.
+        let mut other = interfaces::State::fake();
+        other.interfaces.clear();
+        assert_eq!(cache.get(&dns_resolver, &other).await, None);
+    }
+
+    #[test]
+    fn test_synthesize_rfc6052_examples() {
+        // The examples of RFC 6052 section 2.4.
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        let cases = [
+            ("2001:db8::", 32, "2001:db8:c000:221::"),
+            ("2001:db8:100::", 40, "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::", 48, "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::", 56, "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::", 64, "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::", 96, "2001:db8:122:344::c000:221"),
+        ];
+        for (prefix, len, expected) in cases {
+            let prefix = Nat64Prefix::new(prefix.parse().unwrap(), len).unwrap();
+            let expected: Ipv6Addr = expected.parse().unwrap();
+            assert_eq!(prefix.synthesize(v4), expected, "{prefix}");
+            assert_eq!(prefix.extract(expected), Some(v4), "{prefix}");
+        }
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert!(Nat64Prefix::new(Ipv6Addr::UNSPECIFIED, 80).is_none());
+        let prefix = Nat64Prefix::new("64:ff9b::1".parse().unwrap(), 96).unwrap();
+        assert_eq!(prefix, Nat64Prefix::WELL_KNOWN);
+        assert_eq!(prefix.to_string(), "64:ff9b::/96");
+        assert_eq!(prefix.extract("2001:db8::c000:221".parse().unwrap()), None);
+
+        let addr: SocketAddr = "192.0.2.33:7842".parse().unwrap();
+        let synthesized = prefix.synthesize_socket_addr(addr);
+        assert_eq!(synthesized, "[64:ff9b::c000:221]:7842".parse().unwrap());
+        assert_eq!(prefix.extract_socket_addr(synthesized), Some(addr));
+        assert_eq!(prefix.extract_socket_addr(addr), None);
+    }
+
+    #[test]
+    fn test_from_ipv4_only_addr() {
+        let found = Nat64Prefix::from_ipv4_only_addr("64:ff9b::c000:aa".parse().unwrap());
+        assert_eq!(found, Some(Nat64Prefix::WELL_KNOWN));
+        let found =
+            Nat64Prefix::from_ipv4_only_addr("2001:db8:122:344:c0:0:aa00:0".parse().unwrap());
+        assert_eq!(
+            found,
+            Nat64Prefix::new("2001:db8:122:344::".parse().unwrap(), 64)
+        );
+        assert_eq!(
+            Nat64Prefix::from_ipv4_only_addr("2001:db8::1".parse().unwrap()),
+            None
+        );
+    }
+}
@@ -1,14 +1,152 @@
 //! Options for creating a report gen client.
 
 pub use imp::Options;
+#[cfg(not(wasm_browser))]
+pub use imp::{StunServer, StunServerParseError, StunServers};
 
 #[cfg(not(wasm_browser))]
 mod imp {
-    use std::{collections::BTreeSet, sync::Arc};
+    use std::{
+        collections::BTreeSet,
+        fmt,
+        net::{IpAddr, SocketAddr},
+        str::FromStr,
+        sync::Arc,
+        time::Duration,
+    };
 
+    use iroh_base::RelayUrl;
+    use iroh_relay::{client::ClientTlsConfig, defaults::DEFAULT_STUN_PORT, RelayNode};
     use netwatch::UdpSocket;
 
-    use crate::net_report::{reportgen::ProbeProto, QuicConfig};
+    use crate::net_report::{
+        defaults::timeouts::{OVERALL_REPORT_TIMEOUT, PROBE_TIMEOUT},
+        reportgen::ProbeProto,
+        QuicConfig, FULL_REPORT_INTERVAL,
+    };
+
+    /// A STUN server probed by net_report besides the relay servers.
+    ///
+    /// Can be created from a [`SocketAddr`] or parsed from a `host[:port]` string, in which
+    /// case the host is resolved using DNS when probing.  If the port is omitted the
+    /// default STUN port is used.
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    pub struct StunServer {
+        /// The STUN-only node probed.
+        node: Arc<RelayNode>,
+    }
+
+    /// Error returned when parsing a [`StunServer`] fails.
+    #[derive(Debug, thiserror::Error)]
+    #[error("invalid STUN server {0:?}")]
+    pub struct StunServerParseError(String);
+
+    impl StunServer {
+        /// Creates a STUN server from a hostname, resolved using DNS, and a port.
+        pub fn from_host(hostname: &str, port: u16) -> Result<Self, StunServerParseError> {
+            let host = url::Host::parse(hostname)
+                .map_err(|_| StunServerParseError(hostname.to_string()))?;
+            let url: url::Url = format!("http://{host}:{port}")
+                .parse()
+                .map_err(|_| StunServerParseError(hostname.to_string()))?;
+            Ok(Self::from_url(url.into(), port))
+        }
+
+        fn from_url(url: RelayUrl, port: u16) -> Self {
+            Self {
+                node: Arc::new(RelayNode {
+                    url,
+                    stun_only: true,
+                    stun_port: port,
+                    quic: None,
+                }),
+            }
+        }
+
+        /// Returns the node to send the STUN probes to.
+        pub(crate) fn node(&self) -> &Arc<RelayNode> {
+            &self.node
+        }
+    }
+
+    impl From<SocketAddr> for StunServer {
+        fn from(addr: SocketAddr) -> Self {
+            let url: url::Url = format!("http://{addr}")
+                .parse()
+                .expect("socket addresses are valid URL hosts");
+            Self::from_url(url.into(), addr.port())
+        }
+    }
+
+    impl FromStr for StunServer {
+        type Err = StunServerParseError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            if let Ok(addr) = s.parse::<SocketAddr>() {
+                return Ok(addr.into());
+            }
+            if let Ok(ip) = s.parse::<IpAddr>() {
+                return Ok(SocketAddr::new(ip, DEFAULT_STUN_PORT).into());
+            }
+            match s.rsplit_once(':') {
+                Some((host, port)) => {
+                    let port = port
+                        .parse()
+                        .map_err(|_| StunServerParseError(s.to_string()))?;
+                    Self::from_host(host, port)
+                }
+                None => Self::from_host(s, DEFAULT_STUN_PORT),
+            }
+        }
+    }
+
+    impl fmt::Display for StunServer {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            // Domains of relay URLs are always absolute, which is just noise here.
+            let host = self.node.url.host_str().unwrap_or_default();
+            write!(f, "{}:{}", host.trim_end_matches('.'), self.node.stun_port)
+        }
+    }
+
+    /// Custom STUN servers to probe, per address family.
+    ///
+    /// By default the STUN servers are probed in addition to the relay servers.  Use
+    /// [`StunServers::replace_relays`] to only send STUN probes to these servers, e.g. for
+    /// private deployments whose relay servers do not serve STUN.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct StunServers {
+        /// STUN servers to probe over IPv4.
+        pub v4: Vec<StunServer>,
+        /// STUN servers to probe over IPv6.
+        pub v6: Vec<StunServer>,
+        /// Whether to stop sending STUN probes to the relay servers.
+        pub replace_relays: bool,
+    }
+
+    impl StunServers {
+        /// Whether no custom STUN servers are configured.
+        pub fn is_empty(&self) -> bool {
+            self.v4.is_empty() && self.v6.is_empty()
+        }
+
+        /// Sets the STUN servers probed over IPv4.
+        pub fn v4(mut self, servers: Vec<StunServer>) -> Self {
+            self.v4 = servers;
+            self
+        }
+
+        /// Sets the STUN servers probed over IPv6.
+        pub fn v6(mut self, servers: Vec<StunServer>) -> Self {
+            self.v6 = servers;
+            self
+        }
+
+        /// Sets whether these servers replace the relay servers for STUN probes.
+        pub fn replace_relays(mut self, replace: bool) -> Self {
+            self.replace_relays = replace;
+            self
+        }
+    }
 
     /// Options for running probes
     ///
@@ -50,6 +188,38 @@ mod imp {
         ///
         /// On by default
         pub(crate) https: bool,
+        /// Custom STUN servers to probe.
+        ///
+        /// None by default
+        pub(crate) stun_servers: StunServers,
+        /// How often a full report is run instead of an incremental one.
+        ///
+        /// Five minutes by default
+        pub(crate) full_report_interval: Duration,
+        /// Run a full report, even if the last full report is recent
+        ///
+        /// Off by default
+        pub(crate) full: bool,
+        /// Run a fast report, reusing the recent results of the previous report
+        ///
+        /// Off by default
+        pub(crate) fast: bool,
+        /// The maximum amount of time a single probe may take.
+        ///
+        /// Three seconds by default
+        pub(crate) probe_timeout: Duration,
+        /// The maximum amount of time a report may take.
+        ///
+        /// Five seconds by default
+        pub(crate) report_timeout: Duration,
+        /// The maximum number of probes running at the same time.
+        ///
+        /// Unlimited by default
+        pub(crate) max_concurrent_probes: Option<usize>,
+        /// How the certificates of relay servers are verified by HTTPS probes.
+        ///
+        /// Trusts the webpki roots by default
+        pub(crate) relay_tls_config: ClientTlsConfig,
     }
 
     impl Default for Options {
@@ -61,6 +231,14 @@ mod imp {
                 icmp_v4: true,
                 icmp_v6: true,
                 https: true,
+                stun_servers: StunServers::default(),
+                full_report_interval: FULL_REPORT_INTERVAL,
+                full: false,
+                fast: false,
+                probe_timeout: PROBE_TIMEOUT,
+                report_timeout: OVERALL_REPORT_TIMEOUT,
+                max_concurrent_probes: None,
+                relay_tls_config: ClientTlsConfig::default(),
             }
         }
     }
@@ -75,6 +253,14 @@ mod imp {
                 icmp_v4: false,
                 icmp_v6: false,
                 https: false,
+                stun_servers: StunServers::default(),
+                full_report_interval: FULL_REPORT_INTERVAL,
+                full: false,
+                fast: false,
+                probe_timeout: PROBE_TIMEOUT,
+                report_timeout: OVERALL_REPORT_TIMEOUT,
+                max_concurrent_probes: None,
+                relay_tls_config: ClientTlsConfig::default(),
             }
         }
 
@@ -96,6 +282,12 @@ mod imp {
             self
         }
 
+        /// Set how the certificates of relay servers are verified by HTTPS probes
+        pub fn relay_tls_config(mut self, config: ClientTlsConfig) -> Self {
+            self.relay_tls_config = config;
+            self
+        }
+
         /// Enable or disable icmp_v4 probe
         pub fn icmp_v4(mut self, enable: bool) -> Self {
             self.icmp_v4 = enable;
@@ -114,6 +306,72 @@ mod imp {
             self
         }
 
+        /// Set custom STUN servers to probe
+        ///
+        /// Only probed if STUN over the respective address family is enabled.
+        pub fn stun_servers(mut self, stun_servers: StunServers) -> Self {
+            self.stun_servers = stun_servers;
+            self
+        }
+
+        /// Set how often a full report is run instead of an incremental one
+        ///
+        /// Incremental reports only probe the relays which were the fastest in the
+        /// previous report.
+        pub fn full_report_interval(mut self, interval: Duration) -> Self {
+            self.full_report_interval = interval;
+            self
+        }
+
+        /// Force a full report, even if the last full report is recent
+        pub fn full(mut self, full: bool) -> Self {
+            self.full = full;
+            self
+        }
+
+        /// Run a fast report, reusing the recent results of the previous report
+        ///
+        /// A fast report only probes the preferred relay of the previous report to confirm
+        /// the public addresses, the other results are taken from the previous report.  This
+        /// takes a single round trip instead of waiting for all relays.  It is only run if
+        /// the previous report is recent and had a preferred relay, otherwise a normal report
+        /// is run.  If the fast report finds any changes the next report is a full one.
+        ///
+        /// A full report takes precedence over a fast one.
+        pub fn fast(mut self, fast: bool) -> Self {
+            self.fast = fast;
+            self
+        }
+
+        /// Set the maximum amount of time a single probe may take
+        ///
+        /// A probe taking longer is considered failed, remaining probes of the same kind
+        /// still run.  Raise this on high-latency links.
+        pub fn probe_timeout(mut self, timeout: Duration) -> Self {
+            self.probe_timeout = timeout;
+            self
+        }
+
+        /// Set the maximum amount of time a report may take
+        ///
+        /// Probes still running after three fifths of this time are aborted, leaving time
+        /// for the remaining checks to finish.  If the report does not finish in time it
+        /// fails.
+        pub fn report_timeout(mut self, timeout: Duration) -> Self {
+            self.report_timeout = timeout;
+            self
+        }
+
+        /// Set the maximum number of probes running at the same time
+        ///
+        /// Probes exceeding the limit wait until a running probe finished.  Lowering this
+        /// reduces the load on constrained devices, at the cost of slower reports.  `None`
+        /// removes the limit.
+        pub fn max_concurrent_probes(mut self, max: Option<usize>) -> Self {
+            self.max_concurrent_probes = max;
+            self
+        }
+
         /// Turn the options into set of valid protocols
         pub(crate) fn to_protocols(&self) -> BTreeSet<ProbeProto> {
             let mut protocols = BTreeSet::new();
@@ -147,9 +405,13 @@ mod imp {
 
 #[cfg(wasm_browser)]
 mod imp {
-    use std::collections::BTreeSet;
+    use std::{collections::BTreeSet, time::Duration};
 
-    use crate::net_report::reportgen::ProbeProto;
+    use crate::net_report::{
+        defaults::timeouts::{OVERALL_REPORT_TIMEOUT, PROBE_TIMEOUT},
+        reportgen::ProbeProto,
+        FULL_REPORT_INTERVAL,
+    };
 
     /// Options for running probes (in browsers).
     ///
@@ -161,18 +423,58 @@ mod imp {
         ///
         /// On by default
         pub(crate) https: bool,
+        /// How often a full report is run instead of an incremental one.
+        ///
+        /// Five minutes by default
+        pub(crate) full_report_interval: Duration,
+        /// Run a full report, even if the last full report is recent
+        ///
+        /// Off by default
+        pub(crate) full: bool,
+        /// Run a fast report, reusing the recent results of the previous report
+        ///
+        /// Off by default
+        pub(crate) fast: bool,
+        /// The maximum amount of time a single probe may take.
+        ///
+        /// Three seconds by default
+        pub(crate) probe_timeout: Duration,
+        /// The maximum amount of time a report may take.
+        ///
+        /// Five seconds by default
+        pub(crate) report_timeout: Duration,
+        /// The maximum number of probes running at the same time.
+        ///
+        /// Unlimited by default
+        pub(crate) max_concurrent_probes: Option<usize>,
     }
 
     impl Default for Options {
         fn default() -> Self {
-            Self { https: true }
+            Self {
+                https: true,
+                full_report_interval: FULL_REPORT_INTERVAL,
+                full: false,
+                fast: false,
+                probe_timeout: PROBE_TIMEOUT,
+                report_timeout: OVERALL_REPORT_TIMEOUT,
+                max_concurrent_probes: None,
+            }
         }
     }
 
     impl Options {
         /// Create an [`Options`] that disables all probes
         pub fn disabled() -> Self {
-            Self { https: false }
+            Self {
+                https: false,
+                full_report_interval: FULL_REPORT_INTERVAL,
+                full: false,
+                fast: false,
+                probe_timeout: PROBE_TIMEOUT,
+                report_timeout: OVERALL_REPORT_TIMEOUT,
+                max_concurrent_probes: None,
+            }
         }
 
         /// Enable or disable https probe
@@ -181,6 +483,58 @@ mod imp {
             self
         }
 
+        /// Set how often a full report is run instead of an incremental one
+        ///
+        /// Incremental reports only probe the relays which were the fastest in the
+        /// previous report.
+        pub fn full_report_interval(mut self, interval: Duration) -> Self {
+            self.full_report_interval = interval;
+            self
+        }
+
+        /// Force a full report, even if the last full report is recent
+        pub fn full(mut self, full: bool) -> Self {
+            self.full = full;
+            self
+        }
+
+        /// Run a fast report, reusing the recent results of the previous report
+        ///
+        /// Fast reports are not supported in browsers, a normal report is run instead.
+        pub fn fast(mut self, fast: bool) -> Self {
+            self.fast = fast;
+            self
+        }
+
+        /// Set the maximum amount of time a single probe may take
+        ///
+        /// A probe taking longer is considered failed, remaining probes of the same kind
+        /// still run.  Raise this on high-latency links.
+        pub fn probe_timeout(mut self, timeout: Duration) -> Self {
+            self.probe_timeout = timeout;
+            self
+        }
+
+        /// Set the maximum amount of time a report may take
+        ///
+        /// Probes still running after three fifths of this time are aborted, leaving time
+        /// for the remaining checks to finish.  If the report does not finish in time it
+        /// fails.
+        pub fn report_timeout(mut self, timeout: Duration) -> Self {
+            self.report_timeout = timeout;
+            self
+        }
+
+        /// Set the maximum number of probes running at the same time
+        ///
+        /// Probes exceeding the limit wait until a running probe finished.  Lowering this
+        /// reduces the load on constrained devices, at the cost of slower reports.  `None`
+        /// removes the limit.
+        pub fn max_concurrent_probes(mut self, max: Option<usize>) -> Self {
+            self.max_concurrent_probes = max;
+            self
+        }
+
         /// Turn the options into set of valid protocols
         pub(crate) fn to_protocols(&self) -> BTreeSet<ProbeProto> {
             let mut protocols = BTreeSet::new();
@@ -9,6 +9,7 @@
 //! - Creates hairpin actor.
 //! - Creates portmapper future.
 //! - Creates captive portal detection future.
+//! - Creates NAT filtering check future.
 //! - Creates Probe Set futures.
 //!   - These send messages to the reportgen actor.
 //! - Loops driving the futures and handling actor messages:
@@ -30,7 +31,7 @@ use iroh_base::RelayUrl;
 #[cfg(feature = "metrics")]
 use iroh_metrics::inc;
 #[cfg(not(wasm_browser))]
-use iroh_relay::dns::DnsResolver;
+use iroh_relay::{client::ClientTlsConfig, dns::DnsResolver};
 use iroh_relay::{
     defaults::{DEFAULT_RELAY_QUIC_PORT, DEFAULT_STUN_PORT},
     http::RELAY_PROBE_PATH,
@@ -47,7 +48,7 @@ use n0_future::{
 #[cfg(not(wasm_browser))]
 use netwatch::{interfaces, UdpSocket};
 use rand::seq::IteratorRandom;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, Semaphore};
 use tracing::{debug, debug_span, error, info_span, trace, warn, Instrument, Span};
 use url::Host;
 
@@ -55,15 +56,19 @@ use url::Host;
 use crate::net_report::portmapper; // We stub the library
 #[cfg(feature = "metrics")]
 use crate::net_report::Metrics;
-use crate::net_report::{self, Report};
+use crate::net_report::{self, NatFiltering, RelayLatencies, Report};
 #[cfg(not(wasm_browser))]
 use crate::net_report::{
-    defaults::timeouts::DNS_TIMEOUT,
+    defaults::timeouts::{DNS_TIMEOUT, NAT_FILTERING_TIMEOUT},
     dns::DNS_STAGGERING_MS,
     ip_mapped_addrs::IpMappedAddresses,
+    nat64::{Nat64Cache, Nat64Prefix},
     ping::{PingError, Pinger},
+    PublicAddr, StunServers,
 };
 
+#[cfg(not(wasm_browser))]
+mod filtering;
 #[cfg(not(wasm_browser))]
 mod hairpin;
 mod probes;
@@ -73,6 +78,7 @@ use probes::{Probe, ProbePlan};
 
 use crate::net_report::defaults::timeouts::{
     CAPTIVE_PORTAL_DELAY, CAPTIVE_PORTAL_TIMEOUT, OVERALL_REPORT_TIMEOUT, PROBES_TIMEOUT,
+    PROBE_TIMEOUT,
 };
 
 const ENOUGH_NODES: usize = 3;
@@ -100,10 +106,49 @@ pub(crate) struct SocketState {
     pub(crate) stun_sock6: Option<Arc<UdpSocket>>,
     /// QUIC configuration to do QUIC address Discovery
     pub(crate) quic_config: Option<QuicConfig>,
+    /// Custom STUN servers to probe besides, or instead of, the relay servers.
+    pub(crate) stun_servers: StunServers,
     /// The DNS resolver to use for probes that need to resolve DNS records.
     pub(crate) dns_resolver: DnsResolver,
     /// Optional [`IpMappedAddresses`] used to enable QAD in iroh
     pub(crate) ip_mapped_addrs: Option<IpMappedAddresses>,
+    /// The NAT64 prefix used to reach IPv4 servers from an IPv6-only host.
+    pub(crate) nat64_prefix: Option<Nat64Prefix>,
+    /// The NAT64 prefix found by the previous reports.
+    pub(crate) nat64_cache: Nat64Cache,
+    /// How the certificates of relay servers are verified by HTTPS probes.
+    pub(crate) relay_tls_config: ClientTlsConfig,
+}
+
+/// Limits on the time and resources a report may use.
+#[derive(Debug, Clone)]
+pub(crate) struct ProbeLimits {
+    /// The maximum amount of time a single probe may take.
+    pub(crate) probe_timeout: Duration,
+    /// The maximum amount of time the report may take.
+    pub(crate) report_timeout: Duration,
+    /// The maximum number of probes running at the same time.
+    pub(crate) max_concurrent_probes: Option<usize>,
+}
+
+impl Default for ProbeLimits {
+    fn default() -> Self {
+        Self {
+            probe_timeout: PROBE_TIMEOUT,
+            report_timeout: OVERALL_REPORT_TIMEOUT,
+            max_concurrent_probes: None,
+        }
+    }
+}
+
+impl ProbeLimits {
+    /// The time after which the remaining probes are aborted.
+    ///
+    /// This is [`PROBES_TIMEOUT`] scaled along with the report timeout.
+    fn probes_timeout(&self) -> Duration {
+        self.report_timeout
+            .mul_f64(PROBES_TIMEOUT.as_secs_f64() / OVERALL_REPORT_TIMEOUT.as_secs_f64())
+    }
 }
 
 impl Client {
@@ -114,10 +159,13 @@ impl Client {
     pub(super) fn new(
         net_report: net_report::Addr,
         last_report: Option<Arc<Report>>,
+        fast: bool,
         relay_map: RelayMap,
         protocols: BTreeSet<ProbeProto>,
+        limits: ProbeLimits,
         #[cfg(not(wasm_browser))] socket_state: SocketState,
     ) -> Self {
+        let report = initial_report(last_report.as_deref(), fast);
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let addr = Addr {
             sender: msg_tx.clone(),
@@ -127,10 +175,12 @@ impl Client {
             msg_rx,
             net_report: net_report.clone(),
             last_report,
+            fast,
             relay_map,
-            report: Report::default(),
+            report,
             outstanding_tasks: OutstandingTasks::default(),
             protocols,
+            limits,
             #[cfg(not(wasm_browser))]
             socket_state,
             #[cfg(not(wasm_browser))]
@@ -195,6 +245,10 @@ struct Actor {
     // Provided state
     /// The previous report, if it exists.
     last_report: Option<Arc<Report>>,
+    /// Whether this is a fast report, reusing the results of the previous report.
+    ///
+    /// Only the preferred relay of the previous report is probed, see [`reused_results`].
+    fast: bool,
     /// The relay configuration.
     relay_map: RelayMap,
 
@@ -208,6 +262,8 @@ struct Actor {
     /// Protocols we should attempt to create probes for, if we have the correct
     /// configuration for that protocol.
     protocols: BTreeSet<ProbeProto>,
+    /// The timeouts and concurrency limit of the probes.
+    limits: ProbeLimits,
 
     /// Any socket-related state that doesn't exist/work in browsers
     #[cfg(not(wasm_browser))]
@@ -242,6 +298,7 @@ impl Actor {
     ///
     /// - Creates a hairpin actor.
     /// - Creates a captive portal future.
+    /// - Creates a NAT filtering check future.
     /// - Creates ProbeSet futures in a group of futures.
     /// - Runs a main loop:
     ///   - Drives all the above futures.
@@ -259,11 +316,13 @@ impl Actor {
 
         let mut port_mapping = self.prepare_portmapper_task();
         let mut captive_task = self.prepare_captive_portal_task();
+        let mut filtering_task = self.prepare_nat_filtering_task();
         let mut probes = self.spawn_probes_task().await?;
 
-        let total_timer = time::sleep(OVERALL_REPORT_TIMEOUT);
+        let total_timer = time::sleep(self.limits.report_timeout);
         tokio::pin!(total_timer);
-        let probe_timer = time::sleep(PROBES_TIMEOUT);
+        let probes_timeout = self.limits.probes_timeout();
+        let probe_timer = time::sleep(probes_timeout);
         tokio::pin!(probe_timer);
 
         loop {
@@ -282,9 +341,9 @@ impl Actor {
                 _ = &mut probe_timer => {
                     warn!("tick: probes timed out");
                     // Set new timeout to not go into this branch multiple times.  We need
-                    // the abort to finish all probes normally.  PROBES_TIMEOUT is
+                    // the abort to finish all probes normally.  The probes timeout is
                     // sufficiently far in the future.
-                    probe_timer.as_mut().reset(Instant::now() + PROBES_TIMEOUT);
+                    probe_timer.as_mut().reset(Instant::now() + probes_timeout);
                     probes.abort_all();
                     self.handle_abort_probes();
                 }
@@ -321,6 +380,14 @@ impl Actor {
                     self.outstanding_tasks.captive_task = false;
                 }
 
+                // Drive the NAT filtering check.
+                filtering = &mut filtering_task, if self.outstanding_tasks.nat_filtering => {
+                    debug!(?filtering, "tick: NAT filtering check done");
+                    self.report.nat_filtering = filtering;
+                    filtering_task.inner = None;
+                    self.outstanding_tasks.nat_filtering = false;
+                }
+
                 // Handle actor messages.
                 msg = self.msg_rx.recv() => {
                     trace!("tick: msg recv: {:?}", msg);
@@ -340,6 +407,12 @@ impl Actor {
             drop(probes);
         }
 
+        if self.fast {
+            if let Some(ref last) = self.last_report {
+                finish_fast_report(&mut self.report, last);
+            }
+        }
+
         debug!("Sending report to net_report actor");
         self.net_report
             .send(net_report::Message::ReportReady {
@@ -374,12 +447,22 @@ impl Actor {
 
     fn handle_probe_report(&mut self, probe_report: ProbeReport) {
         debug!(?probe_report, "finished probe");
-        update_report(&mut self.report, probe_report);
+        // Custom STUN servers are not in the relay map, they only tell us about our own
+        // addresses.
+        let is_relay = self.relay_map.contains_node(&probe_report.probe.node().url);
+        update_report(&mut self.report, probe_report, is_relay);
 
-        // When we discover the first IPv4 address we want to start the hairpin actor.
+        // When we discover the first IPv4 address we want to start the hairpin actor.  A
+        // fast report reuses the previous result if the address did not change.
         #[cfg(not(wasm_browser))]
         if let Some(ref addr) = self.report.global_v4 {
-            if !self.hairpin_actor.has_started() {
+            let unchanged = self.fast
+                && self
+                    .last_report
+                    .as_ref()
+                    .is_some_and(|last| last.global_v4 == Some(*addr));
+            if !unchanged && !self.hairpin_actor.has_started() {
+                self.report.hair_pinning = None;
                 self.hairpin_actor.start_check(*addr);
                 self.outstanding_tasks.hairpin = true;
             }
@@ -389,8 +472,13 @@ impl Actor {
         // probes. The timer's duration is a function of whether this is our initial full
         // probe or an incremental one. For incremental ones, wait for the duration of the
         // slowest relay. For initial ones, double that.
+        //
+        // Custom STUN servers do not report relay latencies, so if there are no relay
+        // servers at all the probes simply run to completion.
+        //
+        // A fast report only probes a single relay and does not need this.
         let enough_relays = std::cmp::min(self.relay_map.len(), ENOUGH_NODES);
-        if self.report.relay_latency.len() == enough_relays {
+        if !self.fast && enough_relays > 0 && self.report.relay_latency.len() == enough_relays {
             let timeout = self.report.relay_latency.max_latency();
             let timeout = match self.last_report.is_some() {
                 true => timeout,
@@ -475,8 +563,9 @@ impl Actor {
         #[cfg(not(wasm_browser))]
         let mut port_mapping = MaybeFuture::default();
 
+        // A fast report reuses the previous portmapper probe.
         #[cfg(not(wasm_browser))]
-        if let Some(port_mapper) = self.socket_state.port_mapper.clone() {
+        if let Some(port_mapper) = self.socket_state.port_mapper.clone().filter(|_| !self.fast) {
             port_mapping.inner = Some(Box::pin(async move {
                 match port_mapper.probe().await {
                     Ok(Ok(res)) => Some(res),
@@ -554,6 +643,55 @@ impl Actor {
         captive_task
     }
 
+    /// Creates the future which will check the NAT filtering behaviour.
+    ///
+    /// This needs a STUN server supporting RFC 5780, so it only runs against the first
+    /// custom IPv4 STUN server.  Waiting for the responses which a NAT filters out takes
+    /// long, so like the captive portal check it only runs for full reports, the other
+    /// reports reuse the previous result.
+    fn prepare_nat_filtering_task(
+        &mut self,
+    ) -> MaybeFuture<Pin<Box<impl Future<Output = Option<NatFiltering>>>>> {
+        // In the browser case the compiler cannot infer the type of the future, because it's never set:
+        #[cfg(wasm_browser)]
+        let filtering_task: MaybeFuture<Pin<Box<Pending<Option<NatFiltering>>>>> =
+            MaybeFuture::default();
+
+        #[cfg(not(wasm_browser))]
+        let mut filtering_task = MaybeFuture::default();
+
+        #[cfg(not(wasm_browser))]
+        if let Some(server) = self.socket_state.stun_servers.v4.first().filter(|_| {
+            self.last_report.is_none() && self.protocols.contains(&ProbeProto::StunIpv4)
+        }) {
+            let node = server.node().clone();
+            let dns_resolver = self.socket_state.dns_resolver.clone();
+            self.outstanding_tasks.nat_filtering = true;
+            filtering_task.inner = Some(Box::pin(async move {
+                let check = async {
+                    let addr =
+                        get_relay_addr(&dns_resolver, &node, ProbeProto::StunIpv4, None).await?;
+                    filtering::run_check(addr).await
+                };
+                match time::timeout(NAT_FILTERING_TIMEOUT, check)
+                    .instrument(debug_span!("nat-filtering", url = %node.url))
+                    .await
+                {
+                    Ok(Ok(filtering)) => filtering,
+                    Ok(Err(err)) => {
+                        debug!("NAT filtering check failed: {err:#}");
+                        None
+                    }
+                    Err(_) => {
+                        debug!("NAT filtering check timed out");
+                        None
+                    }
+                }
+            }));
+        }
+        filtering_task
+    }
+
     /// Prepares the future which will run all the probes as per generated ProbePlan.
     ///
     /// Probes operate like the following:
@@ -577,21 +715,48 @@ impl Actor {
         let if_state = interfaces::State::new().await;
         #[cfg(not(wasm_browser))]
         debug!(%if_state, "Local interfaces");
-        let plan = match self.last_report {
-            Some(ref report) => ProbePlan::with_last_report(
+        #[cfg(not(wasm_browser))]
+        let fast_plan = self
+            .last_report
+            .as_ref()
+            .filter(|_| self.fast)
+            .and_then(|report| {
+                ProbePlan::fast(&self.relay_map, report, &self.protocols, &if_state)
+            });
+        #[cfg(wasm_browser)]
+        let fast_plan = None;
+        let plan = match (fast_plan, self.last_report.as_ref()) {
+            (Some(plan), _) => plan,
+            (None, Some(report)) => ProbePlan::with_last_report(
                 &self.relay_map,
                 report,
                 &self.protocols,
                 #[cfg(not(wasm_browser))]
                 &if_state,
             ),
-            None => ProbePlan::initial(
+            (None, None) => ProbePlan::initial(
                 &self.relay_map,
                 &self.protocols,
                 #[cfg(not(wasm_browser))]
                 &if_state,
             ),
         };
+        #[cfg(not(wasm_browser))]
+        if !if_state.have_v4 && if_state.have_v6 {
+            let prefix = self
+                .socket_state
+                .nat64_cache
+                .get(&self.socket_state.dns_resolver, &if_state)
+                .await;
+            self.report.nat64_prefix = prefix;
+            self.socket_state.nat64_prefix = prefix;
+        }
+        #[cfg(not(wasm_browser))]
+        let plan = plan.with_stun_servers(
+            &self.socket_state.stun_servers,
+            &if_state,
+            self.socket_state.nat64_prefix.is_some(),
+        );
         trace!(%plan, "probe plan");
 
         // The pinger is created here so that any sockets that might be bound for it are
@@ -600,6 +765,12 @@ impl Actor {
         #[cfg(not(wasm_browser))]
         let pinger = Pinger::new();
 
+        // Limits the number of probes running at once, shared by all probe sets.
+        let concurrency = self
+            .limits
+            .max_concurrent_probes
+            .map(|max| Arc::new(Semaphore::new(max)));
+
         // A collection of futures running probe sets.
         let mut probes = JoinSet::default();
         for probe_set in plan.iter() {
@@ -609,6 +780,7 @@ impl Actor {
                 let relay_node = probe.node().clone();
                 let probe = probe.clone();
                 let net_report = self.net_report.clone();
+                let concurrency = concurrency.clone();
 
                 #[cfg(not(wasm_browser))]
                 let pinger = pinger.clone();
@@ -621,6 +793,8 @@ impl Actor {
                         relay_node,
                         probe.clone(),
                         net_report,
+                        self.limits.probe_timeout,
+                        concurrency,
                         #[cfg(not(wasm_browser))]
                         pinger,
                         #[cfg(not(wasm_browser))]
@@ -676,12 +850,17 @@ struct OutstandingTasks {
     probes: bool,
     port_mapper: bool,
     captive_task: bool,
+    nat_filtering: bool,
     hairpin: bool,
 }
 
 impl OutstandingTasks {
     fn all_done(&self) -> bool {
-        !(self.probes || self.port_mapper || self.captive_task || self.hairpin)
+        !(self.probes
+            || self.port_mapper
+            || self.captive_task
+            || self.nat_filtering
+            || self.hairpin)
     }
 }
 
@@ -748,12 +927,18 @@ pub struct QuicConfig {
 
 /// Executes a particular [`Probe`], including using a delayed start if needed.
 ///
+/// After the delay the probe waits for a permit of the *concurrency* semaphore, if any.
+/// The probe fails if it takes longer than *timeout* once started.
+///
 /// If *stun_sock4* and *stun_sock6* are `None` the STUN probes are disabled.
+#[allow(clippy::too_many_arguments)]
 async fn run_probe(
     reportstate: Addr,
     relay_node: Arc<RelayNode>,
     probe: Probe,
     net_report: net_report::Addr,
+    timeout: Duration,
+    concurrency: Option<Arc<Semaphore>>,
     #[cfg(not(wasm_browser))] pinger: Pinger,
     #[cfg(not(wasm_browser))] socket_state: SocketState,
 ) -> Result<ProbeReport, ProbeError> {
@@ -761,6 +946,18 @@ async fn run_probe(
         trace!("delaying probe");
         time::sleep(probe.delay()).await;
     }
+    let _permit = match concurrency {
+        Some(ref semaphore) => {
+            trace!("waiting for probe permit");
+            Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("semaphore is never closed"),
+            )
+        }
+        None => None,
+    };
     debug!("starting probe");
 
     let (would_help_tx, would_help_rx) = oneshot::channel();
@@ -789,11 +986,38 @@ async fn run_probe(
         ));
     }
 
-    #[cfg(not(wasm_browser))]
-    let relay_addr = get_relay_addr(&socket_state.dns_resolver, &relay_node, probe.proto())
+    let probe_fut = execute_probe(
+        relay_node,
+        probe.clone(),
+        net_report,
+        #[cfg(not(wasm_browser))]
+        pinger,
+        #[cfg(not(wasm_browser))]
+        socket_state,
+    );
+    time::timeout(timeout, probe_fut)
         .await
-        .context("no relay node addr")
-        .map_err(|e| ProbeError::AbortSet(e, probe.clone()))?;
+        .map_err(|_| ProbeError::Error(anyhow!("probe timed out after {timeout:?}"), probe))?
+}
+
+/// Executes a [`Probe`] once it is started by [`run_probe`].
+async fn execute_probe(
+    relay_node: Arc<RelayNode>,
+    probe: Probe,
+    net_report: net_report::Addr,
+    #[cfg(not(wasm_browser))] pinger: Pinger,
+    #[cfg(not(wasm_browser))] socket_state: SocketState,
+) -> Result<ProbeReport, ProbeError> {
+    #[cfg(not(wasm_browser))]
+    let relay_addr = get_relay_addr(
+        &socket_state.dns_resolver,
+        &relay_node,
+        probe.proto(),
+        socket_state.nat64_prefix,
+    )
+    .await
+    .context("no relay node addr")
+    .map_err(|e| ProbeError::AbortSet(e, probe.clone()))?;
 
     let mut result = ProbeReport::new(probe.clone());
     match probe {
@@ -825,8 +1049,9 @@ async fn run_probe(
             match measure_https_latency(
                 #[cfg(not(wasm_browser))]
                 &socket_state.dns_resolver,
+                #[cfg(not(wasm_browser))]
+                &socket_state.relay_tls_config,
                 node,
-                None,
             )
             .await
             {
@@ -1028,7 +1253,7 @@ async fn check_captive_portal(
     // If we have a preferred relay node and we can use it for non-STUN requests, try that;
     // otherwise, pick a random one suitable for non-STUN requests.
     let preferred_relay = preferred_relay.and_then(|url| match dm.get_node(&url) {
-        Some(node) if node.stun_only => Some(url),
+        Some(node) if !node.stun_only => Some(url),
         _ => None,
     });
 
@@ -1141,6 +1366,7 @@ async fn get_relay_addr(
     dns_resolver: &DnsResolver,
     relay_node: &RelayNode,
     proto: ProbeProto,
+    nat64_prefix: Option<Nat64Prefix>,
 ) -> Result<SocketAddr> {
     if relay_node.stun_only && !matches!(proto, ProbeProto::StunIpv4 | ProbeProto::StunIpv6) {
         bail!("Relay node not suitable for non-STUN probes");
@@ -1153,7 +1379,7 @@ async fn get_relay_addr(
         }
 
         ProbeProto::StunIpv6 | ProbeProto::IcmpV6 | ProbeProto::QuicIpv6 => {
-            relay_lookup_ipv6_staggered(dns_resolver, relay_node, port).await
+            relay_lookup_ipv6_staggered(dns_resolver, relay_node, port, nat64_prefix).await
         }
 
         ProbeProto::Https => Err(anyhow!("Not implemented")),
@@ -1192,12 +1418,15 @@ async fn relay_lookup_ipv4_staggered(
 
 /// Do a staggared ipv6 DNS lookup based on [`RelayNode`]
 ///
-/// `port` is combined with the resolved [`std::net::Ipv6Addr`] to return a [`SocketAddr`]
+/// `port` is combined with the resolved [`std::net::Ipv6Addr`] to return a [`SocketAddr`].
+/// IPv4 addresses are synthesized into the `nat64_prefix` if there is one, DNS64 already
+/// does so for hostnames.
 #[cfg(not(wasm_browser))]
 async fn relay_lookup_ipv6_staggered(
     dns_resolver: &DnsResolver,
     relay: &RelayNode,
     port: u16,
+    nat64_prefix: Option<Nat64Prefix>,
 ) -> Result<SocketAddr> {
     match relay.url.host() {
         Some(url::Host::Domain(hostname)) => {
@@ -1214,7 +1443,10 @@ async fn relay_lookup_ipv6_staggered(
                 Err(err) => Err(err.context("No suitable relay addr found")),
             }
         }
-        Some(url::Host::Ipv4(_addr)) => Err(anyhow!("No suitable relay addr found")),
+        Some(url::Host::Ipv4(addr)) => match nat64_prefix {
+            Some(prefix) => Ok(SocketAddr::new(prefix.synthesize(addr).into(), port)),
+            None => Err(anyhow!("No suitable relay addr found")),
+        },
         Some(url::Host::Ipv6(addr)) => Ok(SocketAddr::new(addr.into(), port)),
         None => Err(anyhow!("No valid hostname in RelayUrl")),
     }
@@ -1266,13 +1498,13 @@ async fn run_icmp_probe(
 
 /// Executes an HTTPS probe.
 ///
-/// If `certs` is provided they will be added to the trusted root certificates, allowing the
-/// use of self-signed certificates for servers.  Currently this is used for testing.
+/// The certificate of the relay server is verified as configured by `tls_config`, like
+/// the relay client does.
 #[allow(clippy::unused_async)]
 async fn measure_https_latency(
     #[cfg(not(wasm_browser))] dns_resolver: &DnsResolver,
+    #[cfg(not(wasm_browser))] tls_config: &ClientTlsConfig,
     node: &RelayNode,
-    certs: Option<Vec<rustls::pki_types::CertificateDer<'static>>>,
 ) -> Result<(Duration, IpAddr)> {
     let url = node.url.join(RELAY_PROBE_PATH)?;
 
@@ -1304,11 +1536,8 @@ async fn measure_https_latency(
     }
 
     #[cfg(not(wasm_browser))]
-    if let Some(certs) = certs {
-        for cert in certs {
-            let cert = reqwest::Certificate::from_der(&cert)?;
-            builder = builder.add_root_certificate(cert);
-        }
+    {
+        builder = builder.use_preconfigured_tls((*tls_config.client_config()).clone());
     }
     let client = builder.build()?;
 
@@ -1346,13 +1575,83 @@ async fn measure_https_latency(
     }
 }
 
+/// The report a report run starts out with.
+///
+/// Full reports, without a previous report, start empty.  Fast reports start with the
+/// [`reused_results`], other reports only keep the NAT filtering behaviour, which is only
+/// checked by full reports.
+fn initial_report(last: Option<&Report>, fast: bool) -> Report {
+    match last {
+        Some(last) if fast => reused_results(last),
+        Some(last) => Report {
+            nat_filtering: last.nat_filtering,
+            ..Default::default()
+        },
+        None => Report::default(),
+    }
+}
+
+/// The results of the previous report a fast report starts out with.
+///
+/// These are the results which are not confirmed by probing the preferred relay: the
+/// latencies of the other relays and the results of the checks which are not re-run.  The
+/// latencies of the preferred relay are measured again.
+fn reused_results(last: &Report) -> Report {
+    let mut report = Report {
+        icmpv4: last.icmpv4,
+        icmpv6: last.icmpv6,
+        hair_pinning: last.hair_pinning,
+        nat_filtering: last.nat_filtering,
+        portmap_probe: last.portmap_probe.clone(),
+        relay_latency: last.relay_latency.clone(),
+        relay_v4_latency: last.relay_v4_latency.clone(),
+        relay_v6_latency: last.relay_v6_latency.clone(),
+        captive_portal: last.captive_portal,
+        ..Default::default()
+    };
+    if let Some(ref url) = last.preferred_relay {
+        report.relay_latency.remove(url);
+        report.relay_v4_latency.remove(url);
+        report.relay_v6_latency.remove(url);
+    }
+    report
+}
+
+/// Completes a fast report once the preferred relay was probed.
+///
+/// The reused latencies of an address family which no longer works are dropped.  Whether
+/// the mapping varies by destination can not be determined from a single relay, it is kept
+/// from the previous report if the public address did not change.
+fn finish_fast_report(report: &mut Report, last: &Report) {
+    if !report.ipv4 {
+        report.relay_v4_latency = RelayLatencies::default();
+    }
+    if !report.ipv6 {
+        report.relay_v6_latency = RelayLatencies::default();
+    }
+    if !report.udp {
+        report.relay_latency = RelayLatencies::default();
+    }
+    report.mapping_varies_by_dest_ip = (report.global_v4 == last.global_v4)
+        .then_some(last.mapping_varies_by_dest_ip)
+        .flatten();
+    report.mapping_varies_by_dest_ipv6 = (report.global_v6 == last.global_v6)
+        .then_some(last.mapping_varies_by_dest_ipv6)
+        .flatten();
+}
+
 /// Updates a net_report [`Report`] with a new [`ProbeReport`].
-fn update_report(report: &mut Report, probe_report: ProbeReport) {
+///
+/// Only probes to relay servers, as opposed to custom STUN servers, update the relay
+/// latencies.
+fn update_report(report: &mut Report, probe_report: ProbeReport, is_relay: bool) {
     let relay_node = probe_report.probe.node();
     if let Some(latency) = probe_report.latency {
-        report
-            .relay_latency
-            .update_relay(relay_node.url.clone(), latency);
+        if is_relay {
+            report
+                .relay_latency
+                .update_relay(relay_node.url.clone(), latency);
+        }
 
         #[cfg(not(wasm_browser))]
         if matches!(
@@ -1364,12 +1663,25 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
         ) {
             report.udp = true;
 
+            if let Some(addr) = probe_report.addr {
+                let public_addr = PublicAddr {
+                    addr,
+                    observer: relay_node.url.clone(),
+                    is_relay,
+                };
+                if !report.public_addrs.contains(&public_addr) {
+                    report.public_addrs.push(public_addr);
+                }
+            }
+
             match probe_report.addr {
                 Some(SocketAddr::V4(ipp)) => {
                     report.ipv4 = true;
-                    report
-                        .relay_v4_latency
-                        .update_relay(relay_node.url.clone(), latency);
+                    if is_relay {
+                        report
+                            .relay_v4_latency
+                            .update_relay(relay_node.url.clone(), latency);
+                    }
                     if report.global_v4.is_none() {
                         report.global_v4 = Some(ipp);
                     } else if report.global_v4 != Some(ipp) {
@@ -1380,9 +1692,11 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
                 }
                 Some(SocketAddr::V6(ipp)) => {
                     report.ipv6 = true;
-                    report
-                        .relay_v6_latency
-                        .update_relay(relay_node.url.clone(), latency);
+                    if is_relay {
+                        report
+                            .relay_v6_latency
+                            .update_relay(relay_node.url.clone(), latency);
+                    }
                     if report.global_v6.is_none() {
                         report.global_v6 = Some(ipp);
                     } else if report.global_v6 != Some(ipp) {
@@ -1445,7 +1759,7 @@ mod tests {
     use tracing_test::traced_test;
 
     use super::{super::test_utils, *};
-    use crate::net_report::dns;
+    use crate::net_report::{dns, StunServer};
 
     #[tokio::test]
     #[traced_test]
@@ -1468,7 +1782,7 @@ mod tests {
             },
             addr: Some((Ipv4Addr::new(203, 0, 113, 1), 1234).into()),
         };
-        update_report(&mut report, probe_report_a.clone());
+        update_report(&mut report, probe_report_a.clone(), true);
 
         assert!(report.udp);
         assert_eq!(
@@ -1491,7 +1805,7 @@ mod tests {
             },
             ..probe_report_a
         };
-        update_report(&mut report, probe_report_b);
+        update_report(&mut report, probe_report_b, true);
 
         assert!(report.udp);
         assert_eq!(
@@ -1518,7 +1832,7 @@ mod tests {
             },
             addr: Some((Ipv6Addr::new(2001, 0xdb8, 0, 0, 0, 0, 0, 1), 1234).into()),
         };
-        update_report(&mut report, probe_report_a_ipv6);
+        update_report(&mut report, probe_report_a_ipv6, true);
 
         assert!(report.udp);
         assert_eq!(
@@ -1554,7 +1868,7 @@ mod tests {
             },
             addr: Some((Ipv4Addr::new(203, 0, 113, 1), 1234).into()),
         };
-        update_report(&mut report, probe_report_eu.clone());
+        update_report(&mut report, probe_report_eu.clone(), true);
 
         assert!(!report.udp);
         assert!(report.ipv4_can_send);
@@ -1573,7 +1887,7 @@ mod tests {
             },
             addr: None,
         };
-        update_report(&mut report, probe_report_na);
+        update_report(&mut report, probe_report_na, true);
 
         assert_eq!(report.icmpv4, Some(true));
 
@@ -1590,12 +1904,33 @@ mod tests {
             },
             addr: Some((Ipv4Addr::new(203, 0, 113, 1), 1234).into()),
         };
-        update_report(&mut report, probe_report_eu_stun);
+        update_report(&mut report, probe_report_eu_stun, true);
 
         assert!(report.udp);
         assert_eq!(report.icmpv4, Some(true));
     }
 
+    #[test]
+    fn test_initial_report_nat_filtering() {
+        let last = Report {
+            udp: true,
+            hair_pinning: Some(true),
+            nat_filtering: Some(NatFiltering::AddressDependent),
+            ..Default::default()
+        };
+
+        let full = initial_report(None, false);
+        assert_eq!(full.nat_filtering, None);
+
+        let incremental = initial_report(Some(&last), false);
+        assert_eq!(incremental.nat_filtering, last.nat_filtering);
+        assert_eq!(incremental.hair_pinning, None);
+
+        let fast = initial_report(Some(&last), true);
+        assert_eq!(fast.nat_filtering, last.nat_filtering);
+        assert_eq!(fast.hair_pinning, last.hair_pinning);
+    }
+
     // # ICMP permissions on Linux
     //
     // ## Using capabilities: CAP_NET_RAW
@@ -1693,13 +2028,35 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_relay_lookup_ipv6_nat64() -> TestResult {
+        let dns_resolver = dns::tests::resolver();
+        let server = StunServer::from("192.0.2.33:3478".parse::<SocketAddr>()?);
+        assert!(
+            relay_lookup_ipv6_staggered(&dns_resolver, server.node(), 3478, None)
+                .await
+                .is_err()
+        );
+        let addr = relay_lookup_ipv6_staggered(
+            &dns_resolver,
+            server.node(),
+            3478,
+            Some(Nat64Prefix::WELL_KNOWN),
+        )
+        .await?;
+        assert_eq!(addr, "[64:ff9b::c000:221]:3478".parse()?);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_measure_https_latency() -> TestResult {
         let (server, relay) = test_utils::relay().await;
         let dns_resolver = dns::tests::resolver();
         tracing::info!(relay_url = ?relay.url , "RELAY_URL");
-        let (latency, ip) =
-            measure_https_latency(&dns_resolver, &relay, server.certificates()).await?;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(server.certificates().context("certificates")?);
+        let tls_config = ClientTlsConfig::Roots(Arc::new(roots));
+        let (latency, ip) = measure_https_latency(&dns_resolver, &tls_config, &relay).await?;
 
         assert!(latency > Duration::ZERO);
 
@@ -0,0 +1,239 @@
+//! Checks the filtering behaviour of the NAT.
+//!
+//! This uses the tests of [RFC 5780] section 4.4, which need a STUN server that can respond
+//! from an alternate IP address and port:
+//!
+//! - Test I: a plain binding request, the response tells us the alternate address of the
+//!   server in the OTHER-ADDRESS attribute.  Without it the server does not support
+//!   [RFC 5780] and the filtering behaviour stays unknown.
+//! - Test II: asks the server to respond from its alternate IP address and port.  If this
+//!   response arrives the filtering is endpoint independent.
+//! - Test III: asks the server to respond from its alternate port only.  If this response
+//!   arrives the filtering is address dependent, otherwise address and port dependent.
+//!
+//! All the tests are sent from the same socket, so they use the same NAT mapping.
+//!
+//! [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780#section-4.4
+
+use std::net::SocketAddr;
+
+use anyhow::{ensure, Context, Result};
+use iroh_relay::protos::stun::{self, methods, ChangeRequest, MessageBuilder, MessageClass};
+use n0_future::time;
+use netwatch::UdpSocket;
+use tracing::{debug, trace};
+
+use crate::net_report::{defaults::timeouts::NAT_FILTERING_RETRANSMIT_TIMEOUT, NatFiltering};
+
+/// How often a binding request is sent before giving up on a response.
+const ATTEMPTS: usize = 3;
+
+/// Runs the filtering tests against the STUN server.
+///
+/// Returns `None` if the server does not support [RFC 5780] or responds unexpectedly.
+///
+/// [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780#section-4.4
+pub(super) async fn run_check(server: SocketAddr) -> Result<Option<NatFiltering>> {
+    let socket =
+        UdpSocket::bind_v4(0).context("Failed to bind NAT filtering socket on 0.0.0.0:0")?;
+
+    let Some((_, response)) = binding(&socket, server, None).await? else {
+        debug!(%server, "no response from STUN server");
+        return Ok(None);
+    };
+    let Some(other) = response.other_address()? else {
+        debug!(%server, "STUN server does not support RFC 5780");
+        return Ok(None);
+    };
+    if other.ip() == server.ip() || other.port() == server.port() {
+        debug!(%server, %other, "STUN server has no usable alternate address");
+        return Ok(None);
+    }
+
+    let change_all = ChangeRequest {
+        change_ip: true,
+        change_port: true,
+    };
+    match binding(&socket, server, Some(change_all)).await? {
+        Some((from, _)) if from == other => return Ok(Some(NatFiltering::EndpointIndependent)),
+        Some((from, _)) => {
+            debug!(%from, expected = %other, "response from unexpected address");
+            return Ok(None);
+        }
+        None => (),
+    }
+
+    let change_port = ChangeRequest {
+        change_ip: false,
+        change_port: true,
+    };
+    let expected = SocketAddr::new(server.ip(), other.port());
+    match binding(&socket, server, Some(change_port)).await? {
+        Some((from, _)) if from == expected => Ok(Some(NatFiltering::AddressDependent)),
+        Some((from, _)) => {
+            debug!(%from, %expected, "response from unexpected address");
+            Ok(None)
+        }
+        None => Ok(Some(NatFiltering::AddressAndPortDependent)),
+    }
+}
+
+/// Sends a binding request to the server, returning the response and where it came from.
+///
+/// Returns `None` if no response arrived, and an error for error responses.
+async fn binding(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: Option<ChangeRequest>,
+) -> Result<Option<(SocketAddr, stun::Message)>> {
+    let txid = stun::TransactionId::default();
+    let request = match change {
+        Some(change) => MessageBuilder::new(methods::BINDING, MessageClass::Request, txid)
+            .attribute(&change)
+            .fingerprint()
+            .build()?,
+        None => stun::request(txid),
+    };
+    let mut buf = vec![0u8; 1500];
+    for _ in 0..ATTEMPTS {
+        trace!(%server, ?change, %txid, "sending binding request");
+        socket.send_to(&request, server).await?;
+        let recv = async {
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                match stun::Message::parse(&buf[..len]) {
+                    Ok(msg) if msg.transaction_id() == txid => {
+                        return anyhow::Ok((from, msg));
+                    }
+                    _ => trace!(%from, "ignoring unrelated packet"),
+                }
+            }
+        };
+        if let Ok(res) = time::timeout(NAT_FILTERING_RETRANSMIT_TIMEOUT, recv).await {
+            let (from, msg) = res?;
+            ensure!(
+                msg.class() == MessageClass::SuccessResponse,
+                "error response from {from}"
+            );
+            return Ok(Some((from, msg)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use n0_future::task::{self, AbortOnDropHandle};
+    use testresult::TestResult;
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    /// The responses the [`TestServer`] does not send.
+    #[derive(Debug, Clone, Copy)]
+    enum Dropped {
+        /// Sends all responses.
+        Nothing,
+        /// Drops the responses from the alternate IP address.
+        ChangeIp,
+        /// Drops all responses from an alternate address.
+        AllChanges,
+    }
+
+    /// A STUN server supporting [RFC 5780] on 127.0.0.1 and 127.0.0.2.
+    ///
+    /// Dropping responses simulates the filtering of a NAT.
+    ///
+    /// [RFC 5780]: https://www.rfc-editor.org/rfc/rfc5780
+    struct TestServer {
+        addr: SocketAddr,
+        _task: AbortOnDropHandle<()>,
+    }
+
+    impl TestServer {
+        async fn spawn(dropped: Dropped, other_address: bool) -> Result<Self> {
+            let ips: [IpAddr; 2] = ["127.0.0.1".parse()?, "127.0.0.2".parse()?];
+            let primary = tokio::net::UdpSocket::bind((ips[0], 0)).await?;
+            let addr = primary.local_addr()?;
+            let alt_port = tokio::net::UdpSocket::bind((ips[0], 0)).await?;
+            let port = alt_port.local_addr()?.port();
+            let alt_ip = tokio::net::UdpSocket::bind((ips[1], addr.port())).await?;
+            let alt_all = tokio::net::UdpSocket::bind((ips[1], port)).await?;
+            let other = SocketAddr::new(ips[1], port);
+
+            let task = task::spawn(async move {
+                let mut buf = vec![0u8; 1500];
+                while let Ok((len, from)) = primary.recv_from(&mut buf).await {
+                    let Ok(msg) = stun::Message::parse(&buf[..len]) else {
+                        continue;
+                    };
+                    let change = msg
+                        .get::<ChangeRequest>()
+                        .ok()
+                        .flatten()
+                        .unwrap_or_default();
+                    let socket = match (change.change_ip, change.change_port) {
+                        (false, false) => &primary,
+                        (false, true) => &alt_port,
+                        (true, false) => &alt_ip,
+                        (true, true) => &alt_all,
+                    };
+                    let skip = match dropped {
+                        Dropped::Nothing => false,
+                        Dropped::ChangeIp => change.change_ip,
+                        Dropped::AllChanges => change.change_ip || change.change_port,
+                    };
+                    if skip {
+                        continue;
+                    }
+                    let mut response = MessageBuilder::new(
+                        methods::BINDING,
+                        MessageClass::SuccessResponse,
+                        msg.transaction_id(),
+                    )
+                    .xor_mapped_address(from);
+                    if other_address {
+                        response = response.other_address(other);
+                    }
+                    let response = response.fingerprint().build().expect("valid response");
+                    socket.send_to(&response, from).await.ok();
+                }
+            });
+            Ok(Self {
+                addr,
+                _task: AbortOnDropHandle::new(task),
+            })
+        }
+    }
+
+    // Other loopback addresses than 127.0.0.1 only work without configuration on Linux.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[traced_test]
+    async fn test_nat_filtering() -> TestResult {
+        let cases = [
+            (Dropped::Nothing, Some(NatFiltering::EndpointIndependent)),
+            (Dropped::ChangeIp, Some(NatFiltering::AddressDependent)),
+            (
+                Dropped::AllChanges,
+                Some(NatFiltering::AddressAndPortDependent),
+            ),
+        ];
+        for (dropped, expected) in cases {
+            let server = TestServer::spawn(dropped, true).await?;
+            assert_eq!(run_check(server.addr).await?, expected, "{dropped:?}");
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[traced_test]
+    async fn test_nat_filtering_unsupported() -> TestResult {
+        let server = TestServer::spawn(Dropped::Nothing, false).await?;
+        assert_eq!(run_check(server.addr).await?, None);
+        Ok(())
+    }
+}
@@ -14,6 +14,8 @@ use n0_future::time::Duration;
 use netwatch::interfaces;
 
 use crate::net_report::Report;
+#[cfg(not(wasm_browser))]
+use crate::net_report::StunServers;
 
 /// The retransmit interval used when net_report first runs.
 ///
@@ -522,6 +524,74 @@ impl ProbePlan {
         plan
     }
 
+    /// Creates a fast probe plan, only probing the preferred relay of a previous report.
+    ///
+    /// Only the STUN and QUIC address discovery probes are scheduled, for each address
+    /// family available, with one retry.  They confirm the public addresses, everything else
+    /// is reused from the previous report.
+    ///
+    /// Returns `None` if the previous report had no preferred relay in the `relay_map`.
+    #[cfg(not(wasm_browser))]
+    pub(super) fn fast(
+        relay_map: &RelayMap,
+        last_report: &Report,
+        protocols: &BTreeSet<ProbeProto>,
+        if_state: &interfaces::State,
+    ) -> Option<Self> {
+        let url = last_report.preferred_relay.as_ref()?;
+        let relay_node = relay_map.get_node(url)?;
+        let mut plan = Self {
+            set: Default::default(),
+            protocols: protocols.clone(),
+        };
+        let retransmit_delay = last_report
+            .relay_latency
+            .get(url)
+            .map(|l| l * 120 / 100)
+            .unwrap_or(DEFAULT_ACTIVE_RETRANSMIT_DELAY);
+
+        let mut stun_ipv4_probes = ProbeSet::new(ProbeProto::StunIpv4);
+        let mut stun_ipv6_probes = ProbeSet::new(ProbeProto::StunIpv6);
+        let mut quic_ipv4_probes = ProbeSet::new(ProbeProto::QuicIpv4);
+        let mut quic_ipv6_probes = ProbeSet::new(ProbeProto::QuicIpv6);
+        for attempt in 0..2u32 {
+            let delay = (retransmit_delay + ACTIVE_RETRANSMIT_EXTRA_DELAY) * attempt;
+            if if_state.have_v4 {
+                stun_ipv4_probes
+                    .push(Probe::StunIpv4 {
+                        delay,
+                        node: relay_node.clone(),
+                    })
+                    .expect("Pushing StunIpv4 Probe to StunIpv4 ProbeSet");
+                quic_ipv4_probes
+                    .push(Probe::QuicIpv4 {
+                        delay,
+                        node: relay_node.clone(),
+                    })
+                    .expect("adding QuicIpv4 probe to a QuicAddrIpv4 probe set");
+            }
+            if if_state.have_v6 {
+                stun_ipv6_probes
+                    .push(Probe::StunIpv6 {
+                        delay,
+                        node: relay_node.clone(),
+                    })
+                    .expect("Pushing StunIpv6 Probe to StunIpv6 ProbeSet");
+                quic_ipv6_probes
+                    .push(Probe::QuicIpv6 {
+                        delay,
+                        node: relay_node.clone(),
+                    })
+                    .expect("adding QuicIpv6 probe to a QuicAddrIpv6 probe set");
+            }
+        }
+        plan.add_if_enabled(stun_ipv4_probes);
+        plan.add_if_enabled(stun_ipv6_probes);
+        plan.add_if_enabled(quic_ipv4_probes);
+        plan.add_if_enabled(quic_ipv6_probes);
+        Some(plan)
+    }
+
     #[cfg(wasm_browser)]
     pub(super) fn with_last_report(
         relay_map: &RelayMap,
@@ -576,6 +646,58 @@ impl ProbePlan {
         plan
     }
 
+    /// Adds STUN probes to the custom [`StunServers`].
+    ///
+    /// When the servers replace the relay servers the STUN probes to the relay servers are
+    /// removed from the plan.  Every custom server is probed like in an initial plan, as
+    /// they do not show up in the relay latencies of previous reports.
+    #[cfg(not(wasm_browser))]
+    pub(super) fn with_stun_servers(
+        mut self,
+        stun_servers: &StunServers,
+        if_state: &interfaces::State,
+        nat64: bool,
+    ) -> Self {
+        if stun_servers.replace_relays {
+            self.set
+                .retain(|set| !matches!(set.proto, ProbeProto::StunIpv4 | ProbeProto::StunIpv6));
+        }
+        if if_state.have_v4 {
+            for server in &stun_servers.v4 {
+                let mut stun_ipv4_probes = ProbeSet::new(ProbeProto::StunIpv4);
+                for attempt in 0..3 {
+                    stun_ipv4_probes
+                        .push(Probe::StunIpv4 {
+                            delay: DEFAULT_INITIAL_RETRANSMIT * attempt,
+                            node: server.node().clone(),
+                        })
+                        .expect("adding StunIpv4 probe to a StunIpv4 probe set");
+                }
+                self.add_if_enabled(stun_ipv4_probes);
+            }
+        }
+        // On IPv6-only hosts the IPv4 servers are reached through the NAT64 gateway.
+        let nat64_servers = stun_servers
+            .v4
+            .iter()
+            .filter(|_| nat64 && !if_state.have_v4);
+        if if_state.have_v6 {
+            for server in stun_servers.v6.iter().chain(nat64_servers) {
+                let mut stun_ipv6_probes = ProbeSet::new(ProbeProto::StunIpv6);
+                for attempt in 0..3 {
+                    stun_ipv6_probes
+                        .push(Probe::StunIpv6 {
+                            delay: DEFAULT_INITIAL_RETRANSMIT * attempt,
+                            node: server.node().clone(),
+                        })
+                        .expect("adding StunIpv6 probe to a StunIpv6 probe set");
+                }
+                self.add_if_enabled(stun_ipv6_probes);
+            }
+        }
+        self
+    }
+
     /// Returns an iterator over the [`ProbeSet`]s in this plan.
     pub(super) fn iter(&self) -> impl Iterator<Item = &ProbeSet> {
         self.set.iter()
@@ -681,7 +803,7 @@ mod tests {
     use tracing_test::traced_test;
 
     use super::*;
-    use crate::net_report::{test_utils, RelayLatencies};
+    use crate::net_report::{test_utils, RelayLatencies, StunServer};
 
     /// Shorthand which declares a new ProbeSet.
     ///
@@ -908,6 +1030,85 @@ mod tests {
         assert_eq!(plan, expected_plan);
     }
 
+    #[tokio::test]
+    async fn test_plan_with_stun_servers() {
+        let (_servers, relay_map) = test_utils::relay_map(1).await;
+        let relay_node = relay_map.nodes().next().unwrap();
+        let stun_v4: StunServer = "192.0.2.1:3478".parse().unwrap();
+        let stun_v6: StunServer = "[2001:db8::1]:3478".parse().unwrap();
+        let stun_servers = StunServers::default()
+            .v4(vec![stun_v4.clone()])
+            .v6(vec![stun_v6.clone()])
+            .replace_relays(true);
+        let if_state = interfaces::State::fake();
+        let protocols = BTreeSet::from([ProbeProto::StunIpv4, ProbeProto::StunIpv6]);
+        let plan = ProbePlan::initial(&relay_map, &protocols, &if_state).with_stun_servers(
+            &stun_servers,
+            &if_state,
+            false,
+        );
+
+        let delays = [
+            Duration::ZERO,
+            Duration::from_millis(100),
+            Duration::from_millis(200),
+        ];
+        let mut expected_plan: ProbePlan = [
+            probeset! {
+                proto: ProbeProto::StunIpv4,
+                relay: stun_v4.node().clone(),
+                delays: delays,
+            },
+            probeset! {
+                proto: ProbeProto::StunIpv6,
+                relay: stun_v6.node().clone(),
+                delays: delays,
+            },
+        ]
+        .into_iter()
+        .collect();
+        expected_plan.protocols = protocols.clone();
+        assert_eq!(plan.to_string(), expected_plan.to_string());
+        assert_eq!(plan, expected_plan);
+
+        // Without replacing them the relay servers are still probed.
+        let plan = ProbePlan::initial(&relay_map, &protocols, &if_state).with_stun_servers(
+            &stun_servers.clone().replace_relays(false),
+            &if_state,
+            false,
+        );
+        assert_eq!(plan.iter().count(), 4);
+        assert!(plan
+            .iter()
+            .any(|set| set.into_iter().all(|probe| probe.node() == relay_node)));
+
+        // IPv6-only hosts probe the IPv4 servers through the NAT64 gateway.
+        let mut if_state = interfaces::State::fake();
+        if_state.have_v4 = false;
+        let plan = ProbePlan::initial(&relay_map, &protocols, &if_state).with_stun_servers(
+            &stun_servers,
+            &if_state,
+            true,
+        );
+        let mut expected_plan: ProbePlan = [
+            probeset! {
+                proto: ProbeProto::StunIpv6,
+                relay: stun_v6.node().clone(),
+                delays: delays,
+            },
+            probeset! {
+                proto: ProbeProto::StunIpv6,
+                relay: stun_v4.node().clone(),
+                delays: delays,
+            },
+        ]
+        .into_iter()
+        .collect();
+        expected_plan.protocols = protocols;
+        assert_eq!(plan.to_string(), expected_plan.to_string());
+        assert_eq!(plan, expected_plan);
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_plan_with_report() {
@@ -933,14 +1134,18 @@ mod tests {
                 mapping_varies_by_dest_ip: Some(false),
                 mapping_varies_by_dest_ipv6: Some(false),
                 hair_pinning: Some(true),
+                nat_filtering: None,
                 portmap_probe: None,
                 preferred_relay: Some(relay_node_1.url.clone()),
+                preferred_relay_reason: None,
                 relay_latency: latencies.clone(),
                 relay_v4_latency: latencies.clone(),
                 relay_v6_latency: latencies.clone(),
                 global_v4: None,
                 global_v6: None,
+                public_addrs: Vec::new(),
                 captive_portal: None,
+                nat64_prefix: None,
             };
             let plan = ProbePlan::with_last_report(
                 &relay_map,
@@ -1064,6 +1269,57 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_plan_fast() {
+        let (_servers, relay_map) = test_utils::relay_map(2).await;
+        let relay_node_1 = relay_map.nodes().next().unwrap().clone();
+        let relay_node_2 = relay_map.nodes().nth(1).unwrap().clone();
+        let if_state = interfaces::State::fake();
+        let mut last_report = create_last_report(
+            &relay_node_1.url,
+            Some(Duration::from_millis(2)),
+            &relay_node_2.url,
+            Some(Duration::from_millis(2)),
+        );
+
+        // Only the preferred relay is probed, without HTTPS and ICMP probes.
+        let plan = ProbePlan::fast(&relay_map, &last_report, &default_protocols(), &if_state)
+            .expect("preferred relay");
+        let delays = [Duration::ZERO, Duration::from_micros(52_400)];
+        let mut expected_plan: ProbePlan = [
+            probeset! {
+                proto: ProbeProto::StunIpv4,
+                relay: relay_node_1.clone(),
+                delays: delays,
+            },
+            probeset! {
+                proto: ProbeProto::StunIpv6,
+                relay: relay_node_1.clone(),
+                delays: delays,
+            },
+            probeset! {
+                proto: ProbeProto::QuicIpv4,
+                relay: relay_node_1.clone(),
+                delays: delays,
+            },
+            probeset! {
+                proto: ProbeProto::QuicIpv6,
+                relay: relay_node_1.clone(),
+                delays: delays,
+            },
+        ]
+        .into_iter()
+        .collect();
+        expected_plan.protocols = default_protocols();
+        assert_eq!(plan.to_string(), expected_plan.to_string());
+        assert_eq!(plan, expected_plan);
+
+        last_report.preferred_relay = None;
+        assert!(
+            ProbePlan::fast(&relay_map, &last_report, &default_protocols(), &if_state).is_none()
+        );
+    }
+
     fn create_last_report(
         url_1: &RelayUrl,
         latency_1: Option<Duration>,
@@ -1089,14 +1345,18 @@ mod tests {
             mapping_varies_by_dest_ip: Some(false),
             mapping_varies_by_dest_ipv6: Some(false),
             hair_pinning: Some(true),
+            nat_filtering: None,
             portmap_probe: None,
             preferred_relay: Some(url_1.clone()),
+            preferred_relay_reason: None,
             relay_latency: latencies.clone(),
             relay_v4_latency: latencies.clone(),
             relay_v6_latency: latencies.clone(),
             global_v4: None,
             global_v6: None,
+            public_addrs: Vec::new(),
             captive_portal: None,
+            nat64_prefix: None,
         }
     }
 
@@ -33,6 +33,7 @@ pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, Server)> {
     run_relay_server_with(
         Some(StunConfig {
             bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            share_relay_addr: false,
         }),
         true,
     )
@@ -47,6 +48,7 @@ pub async fn run_relay_server_with_stun() -> Result<(RelayMap, RelayUrl, Server)
     run_relay_server_with(
         Some(StunConfig {
             bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            share_relay_addr: false,
         }),
         false,
     )
@@ -89,6 +91,8 @@ pub async fn run_relay_server_with(
             limits: Default::default(),
             key_cache_capacity: Some(1024),
             access: AccessConfig::Everyone,
+            mesh: None,
+            diagnostics: None,
         }),
         quic,
         stun,